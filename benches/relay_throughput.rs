@@ -0,0 +1,183 @@
+//! Relay Throughput Benchmark
+//!
+//! Moves data over loopback TCP through the relay's `copy_bidirectional`,
+//! which batches every read the client side has ready and writes wrapped
+//! buffers with one vectored write, and through the read, write, flush loop
+//! it replaced. The client side is either a plain socket, where one read
+//! usually drains whatever has arrived, or a framed stream that hands out at
+//! most one small frame per read, as tunnel streams and TLS records do.
+//! Everything runs on one thread, so the time measured is the work done.
+//!
+//! Run with `cargo bench --bench relay_throughput`.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rustproxy::relay::buffer::copy_bidirectional;
+use rustproxy::relay::{BufferSettings, RelayHandle};
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime::Builder;
+
+const PAYLOAD: usize = 16 * 1024 * 1024;
+const SOURCE_WRITE: usize = 64 * 1024;
+const FRAME: usize = 1024;
+
+#[derive(Clone, Copy)]
+enum Relay {
+    Batched,
+    ReadWriteFlush,
+}
+
+impl Relay {
+    fn name(&self) -> &'static str {
+        match self {
+            Relay::Batched => "batched",
+            Relay::ReadWriteFlush => "read_write_flush",
+        }
+    }
+}
+
+/// A client side that returns at most one frame per read
+struct Framed<S> {
+    inner: S,
+    frame: Option<usize>,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Framed<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let Some(frame) = self.frame else {
+            return Pin::new(&mut self.inner).poll_read(cx, buf);
+        };
+        let mut chunk = [0u8; FRAME];
+        let mut limited = ReadBuf::new(&mut chunk[..frame.min(FRAME).min(buf.remaining())]);
+        let result = Pin::new(&mut self.inner).poll_read(cx, &mut limited);
+        buf.put_slice(limited.filled());
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Framed<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// The relay loop before batching: one read, then write it all and flush
+async fn read_write_flush<R, W>(reader: &mut R, writer: &mut W, size: usize) -> io::Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0u8; size];
+    let mut copied = 0;
+    loop {
+        let read = reader.read(&mut buf).await?;
+        if read == 0 {
+            writer.shutdown().await?;
+            return Ok(copied);
+        }
+        writer.write_all(&buf[..read]).await?;
+        writer.flush().await?;
+        copied += read as u64;
+    }
+}
+
+/// A connected pair of loopback sockets
+async fn socket_pair() -> (TcpStream, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let connect = TcpStream::connect(listener.local_addr().unwrap());
+    let (connected, accepted) = tokio::join!(connect, listener.accept());
+    let (connected, accepted) = (connected.unwrap(), accepted.unwrap().0);
+    connected.set_nodelay(true).unwrap();
+    accepted.set_nodelay(true).unwrap();
+    (connected, accepted)
+}
+
+/// Time sending `PAYLOAD` bytes from a client, through `relay`, to a target,
+/// reading the client side `frame` bytes at a time if set
+async fn transfer(relay: Relay, frame: Option<usize>) -> Duration {
+    let (mut source, client_side) = socket_pair().await;
+    let mut client_side = Framed { inner: client_side, frame };
+    let (mut target_side, mut sink) = socket_pair().await;
+    let settings = BufferSettings::default();
+
+    let start = Instant::now();
+    let relaying = tokio::spawn(async move {
+        match relay {
+            Relay::Batched => {
+                copy_bidirectional(&mut client_side, &mut target_side, settings, &RelayHandle::new()).await.unwrap();
+            }
+            Relay::ReadWriteFlush => {
+                let (mut client_read, mut client_write) = tokio::io::split(client_side);
+                let (mut target_read, mut target_write) = target_side.split();
+                let (up, down) = tokio::join!(
+                    read_write_flush(&mut client_read, &mut target_write, settings.max),
+                    read_write_flush(&mut target_read, &mut client_write, settings.max),
+                );
+                up.unwrap();
+                down.unwrap();
+            }
+        }
+    });
+    let sending = tokio::spawn(async move {
+        let block = vec![7u8; SOURCE_WRITE];
+        for _ in 0..PAYLOAD / SOURCE_WRITE {
+            source.write_all(&block).await.unwrap();
+        }
+        source.shutdown().await.unwrap();
+        // Hold the client open until the target closes its side
+        let _ = source.read(&mut [0u8; 1]).await;
+    });
+    let mut received = 0;
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let read = sink.read(&mut buf).await.unwrap();
+        if read == 0 {
+            break;
+        }
+        received += read;
+    }
+    let elapsed = start.elapsed();
+    assert_eq!(received, PAYLOAD);
+    drop(sink);
+    sending.await.unwrap();
+    relaying.await.unwrap();
+    elapsed
+}
+
+fn relay_throughput(c: &mut Criterion) {
+    let runtime = Builder::new_current_thread().enable_all().build().unwrap();
+    let mut group = c.benchmark_group("relay_throughput");
+    group.throughput(Throughput::Bytes(PAYLOAD as u64));
+    group.sample_size(20);
+    for (client, frame) in [("tcp", None), ("framed", Some(FRAME))] {
+        for relay in [Relay::Batched, Relay::ReadWriteFlush] {
+            group.bench_function(BenchmarkId::new(relay.name(), client), |b| {
+                b.iter_custom(|iters| {
+                    runtime.block_on(async {
+                        let mut total = Duration::ZERO;
+                        for _ in 0..iters {
+                            total += transfer(relay, frame).await;
+                        }
+                        total
+                    })
+                });
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, relay_throughput);
+criterion_main!(benches);
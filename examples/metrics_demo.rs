@@ -4,6 +4,7 @@
 
 use rustproxy::metrics::{MetricsManager, export_report_json};
 use rustproxy::config::MonitoringConfig;
+use rustproxy::security::BlockReason;
 use std::net::SocketAddr;
 use std::time::Duration;
 use tokio::time::sleep;
@@ -93,7 +94,7 @@ async fn simulate_connections(metrics_manager: &MetricsManager) -> anyhow::Resul
         
         // Simulate some blocked requests
         if i % 5 == 0 {
-            metrics_manager.record_blocked_request("ACL rule violation");
+            metrics_manager.record_blocked_request(BlockReason::Acl, "ACL rule violation");
         }
     }
     
@@ -0,0 +1,116 @@
+//! Relay Task Benchmark
+//!
+//! Compares the relay's `copy_bidirectional`, which drives both directions of
+//! a connection from one task, with tokio's own single-task
+//! `copy_bidirectional` and with spawning a task per direction. The relay
+//! does more per read than tokio (idle buffer release, activity and
+//! throughput accounting), so the two single-task modes separate that cost
+//! from the cost of the extra task. Every connection runs a number of small request/response round
+//! trips over in-memory pipes, so the cost measured is scheduling and wakeups
+//! rather than the network.
+//!
+//! Run with `cargo run --release --example relay_bench -- [connections] [round_trips]`.
+
+use rustproxy::relay::buffer::copy_bidirectional;
+use rustproxy::relay::{BufferSettings, RelayHandle};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+const MESSAGE: usize = 512;
+const PIPE_CAPACITY: usize = 16 * 1024;
+
+#[derive(Clone, Copy)]
+enum Mode {
+    Relay,
+    TokioSingleTask,
+    TaskPerDirection,
+}
+
+impl Mode {
+    fn name(&self) -> &'static str {
+        match self {
+            Mode::Relay => "relay",
+            Mode::TokioSingleTask => "tokio single task",
+            Mode::TaskPerDirection => "task per direction",
+        }
+    }
+
+    fn tasks_per_connection(&self) -> usize {
+        match self {
+            Mode::Relay | Mode::TokioSingleTask => 1,
+            Mode::TaskPerDirection => 2,
+        }
+    }
+}
+
+/// Relay `client` and `target` the way `mode` does, returning once both directions end
+async fn relay(mode: Mode, mut client: DuplexStream, mut target: DuplexStream) {
+    match mode {
+        Mode::Relay => {
+            let settings = BufferSettings { initial: 8192, max: 8192, shrink_after: Duration::from_secs(5) };
+            let _ = copy_bidirectional(&mut client, &mut target, settings, &RelayHandle::new()).await;
+        }
+        Mode::TokioSingleTask => {
+            let _ = tokio::io::copy_bidirectional(&mut client, &mut target).await;
+        }
+        Mode::TaskPerDirection => {
+            let (mut client_read, mut client_write) = tokio::io::split(client);
+            let (mut target_read, mut target_write) = tokio::io::split(target);
+            let up = tokio::spawn(async move {
+                let _ = tokio::io::copy(&mut client_read, &mut target_write).await;
+                let _ = target_write.shutdown().await;
+            });
+            let down = tokio::spawn(async move {
+                let _ = tokio::io::copy(&mut target_read, &mut client_write).await;
+                let _ = client_write.shutdown().await;
+            });
+            let _ = tokio::join!(up, down);
+        }
+    }
+}
+
+/// Time `connections` relays that each carry `round_trips` exchanges
+async fn run(mode: Mode, connections: usize, round_trips: usize) -> Duration {
+    let start = Instant::now();
+    let mut drivers = Vec::with_capacity(connections);
+    for _ in 0..connections {
+        let (client, mut client_peer) = tokio::io::duplex(PIPE_CAPACITY);
+        let (target, mut target_peer) = tokio::io::duplex(PIPE_CAPACITY);
+        tokio::spawn(relay(mode, client, target));
+        drivers.push(tokio::spawn(async move {
+            let mut buf = [0u8; MESSAGE];
+            for _ in 0..round_trips {
+                client_peer.write_all(&buf).await.unwrap();
+                target_peer.read_exact(&mut buf).await.unwrap();
+                target_peer.write_all(&buf).await.unwrap();
+                client_peer.read_exact(&mut buf).await.unwrap();
+            }
+        }));
+    }
+    for driver in drivers {
+        driver.await.unwrap();
+    }
+    start.elapsed()
+}
+
+#[tokio::main]
+async fn main() {
+    let mut args = std::env::args().skip(1);
+    let connections: usize = args.next().and_then(|arg| arg.parse().ok()).unwrap_or(10_000);
+    let round_trips: usize = args.next().and_then(|arg| arg.parse().ok()).unwrap_or(20);
+
+    println!("{} connections, {} round trips of {} bytes each", connections, round_trips, MESSAGE);
+    for mode in [Mode::Relay, Mode::TokioSingleTask, Mode::TaskPerDirection] {
+        // Warm up the allocator and worker threads before timing
+        run(mode, connections / 10, round_trips).await;
+        let elapsed = run(mode, connections, round_trips).await;
+        let exchanges = (connections * round_trips) as f64;
+        println!(
+            "{:>20}: {:>5} relay tasks, {:>8.1} ms, {:>10.0} round trips/s",
+            mode.name(),
+            connections * mode.tasks_per_connection(),
+            elapsed.as_secs_f64() * 1000.0,
+            exchanges / elapsed.as_secs_f64(),
+        );
+    }
+}
@@ -0,0 +1,87 @@
+//! Anonymous Pseudo-Identities
+//!
+//! With authentication disabled every client would otherwise share the user
+//! id "anonymous", which makes per-user metrics, quotas, and routing rules
+//! useless. Depending on `auth.anonymous_identity`, clients instead get a
+//! stable id derived from their source address, optionally hashed with a
+//! secret salt so logs and reports never carry the address itself.
+
+use crate::config::{AnonymousIdentity, AuthConfig};
+use ring::digest;
+use std::net::IpAddr;
+
+/// User id shared by all clients in `shared` mode
+pub const SHARED_ANONYMOUS_ID: &str = "anonymous";
+
+/// User id for an unauthenticated client connecting from `ip`
+pub fn anonymous_user_id(config: &AuthConfig, ip: IpAddr) -> String {
+    match config.anonymous_identity {
+        AnonymousIdentity::Shared => SHARED_ANONYMOUS_ID.to_string(),
+        AnonymousIdentity::SourceIp => format!("anon-{}", client_key(ip)),
+        AnonymousIdentity::HashedSourceIp => {
+            let salt = config.anonymous_identity_salt.as_deref().unwrap_or_default();
+            let mut context = digest::Context::new(&digest::SHA256);
+            context.update(salt.as_bytes());
+            context.update(client_key(ip).as_bytes());
+            let hash = context.finish();
+            let hex: String = hash.as_ref()[..8].iter().map(|byte| format!("{:02x}", byte)).collect();
+            format!("anon-{}", hex)
+        }
+    }
+}
+
+/// The part of the address that identifies a client
+///
+/// IPv6 hosts rotate through temporary addresses inside their /64, so only
+/// the prefix stays stable.
+fn client_key(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(v4) => v4.to_string(),
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => v4.to_string(),
+            None => {
+                let segments = v6.segments();
+                format!("{:x}:{:x}:{:x}:{:x}::/64", segments[0], segments[1], segments[2], segments[3])
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    fn auth_config(mode: AnonymousIdentity, salt: Option<&str>) -> AuthConfig {
+        let mut auth = Config::default().auth;
+        auth.anonymous_identity = mode;
+        auth.anonymous_identity_salt = salt.map(str::to_string);
+        auth
+    }
+
+    #[test]
+    fn test_source_ip_identity_groups_ipv6_by_prefix() {
+        let config = auth_config(AnonymousIdentity::SourceIp, None);
+        assert_eq!(anonymous_user_id(&config, "192.0.2.7".parse().unwrap()), "anon-192.0.2.7");
+        assert_eq!(
+            anonymous_user_id(&config, "2001:db8:1:2::10".parse().unwrap()),
+            anonymous_user_id(&config, "2001:db8:1:2:abcd::1".parse().unwrap())
+        );
+        assert_eq!(
+            anonymous_user_id(&auth_config(AnonymousIdentity::Shared, None), "192.0.2.7".parse().unwrap()),
+            SHARED_ANONYMOUS_ID
+        );
+    }
+
+    #[test]
+    fn test_hashed_identity_is_stable_and_salted() {
+        let ip: IpAddr = "192.0.2.7".parse().unwrap();
+        let config = auth_config(AnonymousIdentity::HashedSourceIp, Some("pepper"));
+        let id = anonymous_user_id(&config, ip);
+
+        assert_eq!(id, anonymous_user_id(&config, ip));
+        assert!(id.starts_with("anon-") && !id.contains("192.0.2.7"));
+        assert_ne!(id, anonymous_user_id(&config, "192.0.2.8".parse().unwrap()));
+        assert_ne!(id, anonymous_user_id(&auth_config(AnonymousIdentity::HashedSourceIp, Some("salt")), ip));
+    }
+}
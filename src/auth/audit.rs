@@ -0,0 +1,233 @@
+//! Authentication Audit Trail
+//!
+//! Every authentication attempt is appended to a dedicated file as one JSON
+//! object per line, independent of the tracing log and its level. The file is
+//! only ever appended to; once it reaches the configured size it is renamed
+//! to `<path>.1` (shifting older files up) and a fresh file is started.
+
+use crate::config::AuthAuditConfig;
+use crate::protocol::AuthMethod;
+use crate::Result;
+use anyhow::Context;
+use serde::Serialize;
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use super::{AuthRejection, AuthResult};
+
+/// Outcome of an attempt as written to the audit file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditOutcome {
+    Success,
+    /// Bad, missing, or rate-limited credentials
+    Failure,
+    /// Valid credentials refused by a user restriction
+    Rejected,
+    /// The attempt could not be decided, e.g. the introspection endpoint failed
+    Error,
+}
+
+/// One line of the audit file
+#[derive(Debug, Clone, Serialize)]
+pub struct AuthAuditRecord {
+    /// RFC 3339 time of the attempt
+    pub timestamp: String,
+    pub user: Option<String>,
+    pub ip: IpAddr,
+    pub method: &'static str,
+    pub result: AuditOutcome,
+    pub reason: Option<String>,
+    pub session_id: Option<String>,
+}
+
+/// What is known about an attempt while it is being decided
+#[derive(Debug, Clone)]
+pub(super) struct AuthAttempt {
+    pub user: Option<String>,
+    pub method: &'static str,
+    pub reason: Option<&'static str>,
+}
+
+impl AuthAttempt {
+    pub fn new(method: &AuthMethod) -> Self {
+        let method = match method {
+            AuthMethod::NoAuth => "none",
+            AuthMethod::UserPass => "userpass",
+            AuthMethod::Unsupported => "unsupported",
+        };
+        Self { user: None, method, reason: None }
+    }
+
+    /// Record a failure; the first reason given is kept
+    pub fn fail(&mut self, reason: &'static str) {
+        self.reason.get_or_insert(reason);
+    }
+
+    /// Audit record for the attempt's final result
+    pub fn into_record(self, client_ip: IpAddr, result: &Result<AuthResult>) -> AuthAuditRecord {
+        let (user, result, reason, session_id) = match result {
+            Ok(auth) if auth.success => {
+                (auth.user_id.clone().or(self.user), AuditOutcome::Success, None, Some(auth.session_id.clone()))
+            }
+            Ok(auth) => match auth.rejection {
+                Some(rejection) => (self.user, AuditOutcome::Rejected, Some(rejection_reason(rejection).to_string()), None),
+                None => (self.user, AuditOutcome::Failure, self.reason.map(str::to_string), None),
+            },
+            Err(e) => (self.user, AuditOutcome::Error, Some(format!("{:#}", e)), None),
+        };
+        AuthAuditRecord {
+            timestamp: humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+            user,
+            ip: client_ip,
+            method: self.method,
+            result,
+            reason,
+            session_id,
+        }
+    }
+}
+
+fn rejection_reason(rejection: AuthRejection) -> &'static str {
+    match rejection {
+        AuthRejection::SourceNetwork => "source_network",
+        AuthRejection::OutsideAccessWindow => "outside_access_window",
+        AuthRejection::PasswordExpired => "password_expired",
+        AuthRejection::Busy => "server_busy",
+    }
+}
+
+struct AuditFile {
+    file: File,
+    size: u64,
+}
+
+/// Append-only, size-rotated JSON lines file of authentication attempts
+pub struct AuthAuditLog {
+    path: PathBuf,
+    max_bytes: u64,
+    max_files: usize,
+    file: Mutex<AuditFile>,
+}
+
+impl AuthAuditLog {
+    /// Open (or create) the audit file named in `config`
+    pub fn open(config: &AuthAuditConfig) -> Result<Self> {
+        let file = open_append(&config.path)?;
+        Ok(Self {
+            path: config.path.clone(),
+            max_bytes: config.max_bytes,
+            max_files: config.max_files,
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Append one record, rotating first if it would overflow the file
+    pub fn record(&self, record: &AuthAuditRecord) -> Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+
+        let mut current = self.file.lock().unwrap();
+        if current.size > 0 && current.size + line.len() as u64 > self.max_bytes {
+            self.rotate()?;
+            *current = open_append(&self.path)?;
+        }
+        current.file.write_all(&line)
+            .with_context(|| format!("Failed to write audit record to {}", self.path.display()))?;
+        current.size += line.len() as u64;
+        Ok(())
+    }
+
+    /// Shift `path.N` to `path.N+1`, dropping the oldest, and move `path` to `path.1`
+    fn rotate(&self) -> Result<()> {
+        if self.max_files == 0 {
+            return ignore_missing(fs::remove_file(&self.path));
+        }
+        for index in (1..self.max_files).rev() {
+            ignore_missing(fs::rename(self.rotated_path(index), self.rotated_path(index + 1)))?;
+        }
+        ignore_missing(fs::rename(&self.path, self.rotated_path(1)))
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut name = OsString::from(self.path.as_os_str());
+        name.push(format!(".{}", index));
+        PathBuf::from(name)
+    }
+}
+
+fn open_append(path: &Path) -> Result<AuditFile> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Failed to open audit file {}", path.display()))?;
+    let size = file.metadata()?.len();
+    Ok(AuditFile { file, size })
+}
+
+fn ignore_missing(result: io::Result<()>) -> Result<()> {
+    match result {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(dir: &Path, max_bytes: u64, max_files: usize) -> AuthAuditConfig {
+        AuthAuditConfig { path: dir.join("auth-audit.log"), max_bytes, max_files }
+    }
+
+    fn attempt_record(user: &str) -> AuthAuditRecord {
+        let mut attempt = AuthAttempt::new(&AuthMethod::UserPass);
+        attempt.user = Some(user.to_string());
+        attempt.fail("invalid_credentials");
+        attempt.into_record("192.0.2.1".parse().unwrap(), &Ok(AuthResult::failed()))
+    }
+
+    #[test]
+    fn test_records_are_json_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path(), 1024 * 1024, 2);
+        let audit = AuthAuditLog::open(&config).unwrap();
+
+        audit.record(&attempt_record("alice")).unwrap();
+        let expired = AuthAttempt::new(&AuthMethod::UserPass)
+            .into_record("192.0.2.2".parse().unwrap(), &Ok(AuthResult::rejected(AuthRejection::PasswordExpired)));
+        audit.record(&expired).unwrap();
+
+        let contents = fs::read_to_string(&config.path).unwrap();
+        let lines: Vec<serde_json::Value> = contents.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["user"], "alice");
+        assert_eq!(lines[0]["result"], "failure");
+        assert_eq!(lines[0]["reason"], "invalid_credentials");
+        assert_eq!(lines[1]["result"], "rejected");
+        assert_eq!(lines[1]["reason"], "password_expired");
+    }
+
+    #[test]
+    fn test_rotation_keeps_max_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path(), 1, 2);
+        let audit = AuthAuditLog::open(&config).unwrap();
+
+        for user in ["u1", "u2", "u3", "u4"] {
+            audit.record(&attempt_record(user)).unwrap();
+        }
+
+        let read = |path: &Path| fs::read_to_string(path).unwrap();
+        assert!(read(&config.path).contains("\"u4\""));
+        assert!(read(&audit.rotated_path(1)).contains("\"u3\""));
+        assert!(read(&audit.rotated_path(2)).contains("\"u2\""));
+        assert!(!audit.rotated_path(3).exists());
+    }
+}
@@ -0,0 +1,144 @@
+//! Authentication Result Cache
+//!
+//! Remembers recent successful username/password checks so clients that open
+//! many short connections with the same credentials skip repeated password
+//! verification. Entries are keyed by client IP, username, and a keyed hash of
+//! the password, so a changed password or a different client always goes
+//! through full verification. The hash key is random per process and the
+//! password itself is never stored. Expired entries are swept by an
+//! `ExpiringMap`, so pruning only visits entries that are due.
+
+use crate::expiring::ExpiringMap;
+use crate::Result;
+use anyhow::anyhow;
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct CacheKey {
+    client_ip: IpAddr,
+    username: String,
+    password_hash: [u8; 32],
+}
+
+/// Short-lived cache of successful credential checks
+pub struct AuthCache {
+    max_entries: usize,
+    key: hmac::Key,
+    entries: Mutex<ExpiringMap<CacheKey, ()>>,
+}
+
+impl AuthCache {
+    /// Create a cache whose entries expire after `ttl`
+    pub fn new(ttl: Duration, max_entries: usize) -> Result<Self> {
+        let mut secret = [0u8; 32];
+        SystemRandom::new()
+            .fill(&mut secret)
+            .map_err(|_| anyhow!("System random number generator failed"))?;
+        Ok(Self {
+            max_entries,
+            key: hmac::Key::new(hmac::HMAC_SHA256, &secret),
+            entries: Mutex::new(ExpiringMap::new(ttl)),
+        })
+    }
+
+    /// Whether these credentials were verified for this client within the TTL
+    pub fn contains(&self, client_ip: IpAddr, username: &str, password: &str) -> bool {
+        let key = self.cache_key(client_ip, username, password);
+        let mut entries = self.entries.lock().unwrap();
+        match entries.expires_at(&key) {
+            Some(expires) if expires > Instant::now() => true,
+            Some(_) => {
+                entries.remove(&key);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Remember a successful verification
+    pub fn insert(&self, client_ip: IpAddr, username: &str, password: &str) {
+        let key = self.cache_key(client_ip, username, password);
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= self.max_entries && entries.get(&key).is_none() {
+            entries.expire(Instant::now(), |_, _| false);
+            if entries.len() >= self.max_entries {
+                return;
+            }
+        }
+        entries.insert(key, ());
+    }
+
+    /// Forget every entry for a user, e.g. after their password or status changes
+    pub fn invalidate_user(&self, username: &str) {
+        self.entries.lock().unwrap().retain(|key, _| key.username != username);
+    }
+
+    /// Forget every entry
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+
+    /// Drop expired entries and return how many remain
+    pub fn prune(&self) -> usize {
+        let mut entries = self.entries.lock().unwrap();
+        entries.expire(Instant::now(), |_, _| false);
+        entries.len()
+    }
+
+    /// Number of cached entries, including any not yet pruned
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn cache_key(&self, client_ip: IpAddr, username: &str, password: &str) -> CacheKey {
+        let mut password_hash = [0u8; 32];
+        password_hash.copy_from_slice(hmac::sign(&self.key, password.as_bytes()).as_ref());
+        CacheKey {
+            client_ip,
+            username: username.to_string(),
+            password_hash,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cache_matches_ip_user_and_password() {
+        let cache = AuthCache::new(Duration::from_secs(60), 100).unwrap();
+        let ip: IpAddr = "192.0.2.10".parse().unwrap();
+        cache.insert(ip, "alice", "secret");
+
+        assert!(cache.contains(ip, "alice", "secret"));
+        assert!(!cache.contains(ip, "alice", "guess"));
+        assert!(!cache.contains("192.0.2.11".parse().unwrap(), "alice", "secret"));
+
+        cache.invalidate_user("alice");
+        assert!(!cache.contains(ip, "alice", "secret"));
+    }
+
+    #[test]
+    fn test_entries_expire_and_respect_capacity() {
+        let cache = AuthCache::new(Duration::from_millis(20), 1).unwrap();
+        let ip: IpAddr = "192.0.2.10".parse().unwrap();
+        cache.insert(ip, "alice", "secret");
+        cache.insert(ip, "bob", "secret");
+        assert!(!cache.contains(ip, "bob", "secret"));
+
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(!cache.contains(ip, "alice", "secret"));
+        cache.insert(ip, "bob", "secret");
+        assert!(cache.contains(ip, "bob", "secret"));
+        assert_eq!(cache.prune(), 1);
+    }
+}
@@ -0,0 +1,217 @@
+//! OAuth2 Token Introspection
+//!
+//! Validates opaque access tokens by asking an RFC 7662 introspection
+//! endpoint whether they are active. Active tokens are cached until they
+//! expire (bounded by `max_cache_ttl`) so the identity provider is not called
+//! on every connection.
+
+use crate::config::IntrospectionConfig;
+use crate::Result;
+use anyhow::{anyhow, Context};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::debug;
+
+/// Introspection endpoint response (the fields we use)
+#[derive(Debug, Clone, Deserialize)]
+pub struct IntrospectionResponse {
+    pub active: bool,
+    pub sub: Option<String>,
+    pub username: Option<String>,
+    pub scope: Option<String>,
+    pub exp: Option<u64>,
+}
+
+/// Identity of an active token
+#[derive(Debug, Clone)]
+pub struct IntrospectedToken {
+    pub subject: String,
+    pub scopes: Vec<String>,
+}
+
+struct CachedToken {
+    token: IntrospectedToken,
+    expires_at: Instant,
+}
+
+/// Validates tokens against an OAuth2 introspection endpoint
+pub struct TokenIntrospector {
+    client: reqwest::Client,
+    config: IntrospectionConfig,
+    cache: Arc<Mutex<HashMap<String, CachedToken>>>,
+}
+
+impl TokenIntrospector {
+    /// Create an introspector from configuration
+    pub fn from_config(config: &IntrospectionConfig) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(config.timeout)
+            .build()
+            .context("Failed to build introspection HTTP client")?;
+
+        Ok(Self {
+            client,
+            config: config.clone(),
+            cache: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+
+    /// SOCKS5 username that marks the password as a token
+    pub fn token_username(&self) -> &str {
+        &self.config.token_username
+    }
+
+    /// Introspect a token, returning `None` if it is not active
+    ///
+    /// Errors mean the endpoint could not give an answer, which callers
+    /// should treat as a backend failure rather than a rejected token.
+    pub async fn introspect(&self, token: &str) -> Result<Option<IntrospectedToken>> {
+        if let Some(cached) = self.cached(token) {
+            debug!("Token introspection cache hit for '{}'", cached.subject);
+            return Ok(Some(cached));
+        }
+
+        let mut request = self.client
+            .post(&self.config.endpoint)
+            .form(&[("token", token), ("token_type_hint", "access_token")]);
+        if let (Some(id), Some(secret)) = (&self.config.client_id, &self.config.client_secret) {
+            request = request.basic_auth(id, Some(secret));
+        }
+
+        let response = request.send().await
+            .with_context(|| format!("Introspection request to {} failed", self.config.endpoint))?;
+        if !response.status().is_success() {
+            return Err(anyhow!("Introspection endpoint returned {}", response.status()));
+        }
+        let body: IntrospectionResponse = response.json().await
+            .context("Invalid introspection response")?;
+
+        if !body.active {
+            return Ok(None);
+        }
+
+        let subject = body.sub.or(body.username)
+            .ok_or_else(|| anyhow!("Active token has neither sub nor username"))?;
+        let token_info = IntrospectedToken {
+            subject,
+            scopes: body.scope
+                .map(|s| s.split_whitespace().map(str::to_string).collect())
+                .unwrap_or_default(),
+        };
+
+        if let Some(ttl) = self.cache_ttl(body.exp) {
+            let mut cache = self.cache.lock().unwrap();
+            let now = Instant::now();
+            cache.retain(|_, entry| entry.expires_at > now);
+            cache.insert(token.to_string(), CachedToken {
+                token: token_info.clone(),
+                expires_at: now + ttl,
+            });
+        }
+
+        Ok(Some(token_info))
+    }
+
+    /// Drop all cached tokens
+    pub fn clear_cache(&self) {
+        self.cache.lock().unwrap().clear();
+    }
+
+    fn cached(&self, token: &str) -> Option<IntrospectedToken> {
+        let cache = self.cache.lock().unwrap();
+        cache.get(token)
+            .filter(|entry| entry.expires_at > Instant::now())
+            .map(|entry| entry.token.clone())
+    }
+
+    /// How long to cache a token given its `exp` claim
+    fn cache_ttl(&self, exp: Option<u64>) -> Option<Duration> {
+        let max = self.config.max_cache_ttl;
+        match exp {
+            Some(exp) => {
+                let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+                let remaining = Duration::from_secs(exp.checked_sub(now)?);
+                Some(remaining.min(max))
+            }
+            None => Some(max),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Serve a fixed JSON body to every request, counting hits
+    async fn mock_endpoint(body: &'static str) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counter = hits.clone();
+
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                counter.fetch_add(1, Ordering::SeqCst);
+                let mut buf = [0u8; 4096];
+                let _ = stream.read(&mut buf).await;
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+
+        (format!("http://{}/introspect", addr), hits)
+    }
+
+    fn test_config(endpoint: String) -> IntrospectionConfig {
+        IntrospectionConfig {
+            endpoint,
+            client_id: Some("proxy".to_string()),
+            client_secret: Some("secret".to_string()),
+            token_username: "oauth2".to_string(),
+            timeout: Duration::from_secs(2),
+            max_cache_ttl: Duration::from_secs(60),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_active_token_is_cached() {
+        let (endpoint, hits) = mock_endpoint(r#"{"active":true,"sub":"alice","scope":"read write"}"#).await;
+        let introspector = TokenIntrospector::from_config(&test_config(endpoint)).unwrap();
+
+        let token = introspector.introspect("opaque-token").await.unwrap().unwrap();
+        assert_eq!(token.subject, "alice");
+        assert_eq!(token.scopes, vec!["read".to_string(), "write".to_string()]);
+
+        introspector.introspect("opaque-token").await.unwrap().unwrap();
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_inactive_token_is_rejected() {
+        let (endpoint, hits) = mock_endpoint(r#"{"active":false}"#).await;
+        let introspector = TokenIntrospector::from_config(&test_config(endpoint)).unwrap();
+
+        assert!(introspector.introspect("revoked").await.unwrap().is_none());
+        assert!(introspector.introspect("revoked").await.unwrap().is_none());
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_cache_ttl_bounded_by_expiry() {
+        let introspector = TokenIntrospector::from_config(&test_config("http://localhost".to_string())).unwrap();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+
+        assert!(introspector.cache_ttl(Some(now + 10)).unwrap() <= Duration::from_secs(10));
+        assert_eq!(introspector.cache_ttl(Some(now + 3600)), Some(Duration::from_secs(60)));
+        assert_eq!(introspector.cache_ttl(Some(now - 10)), None);
+    }
+}
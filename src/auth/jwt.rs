@@ -0,0 +1,159 @@
+//! JWT Bearer Token Validation
+//!
+//! Lets clients present a signed JWT as the SOCKS5 password. Tokens are
+//! verified against a shared HS256 secret or the public keys in a JWKS file,
+//! and the subject, roles, and quota are taken from the claims.
+
+use crate::config::JwtAuthConfig;
+use crate::Result;
+use anyhow::{anyhow, Context};
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use serde::{Deserialize, Serialize};
+
+/// Claims extracted from a validated token
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TokenClaims {
+    pub sub: String,
+    pub exp: u64,
+    #[serde(default)]
+    pub roles: Vec<String>,
+    #[serde(default)]
+    pub quota_bytes: Option<u64>,
+}
+
+/// Validates JWTs against a shared secret or JWKS
+pub struct JwtValidator {
+    secret_key: Option<DecodingKey>,
+    jwks: Option<JwkSet>,
+    issuer: Option<String>,
+    audience: Option<String>,
+    leeway_secs: u64,
+}
+
+impl JwtValidator {
+    /// Create a validator from configuration, loading the JWKS file if set
+    pub fn from_config(config: &JwtAuthConfig) -> Result<Self> {
+        let secret_key = config.secret.as_ref()
+            .map(|secret| DecodingKey::from_secret(secret.as_bytes()));
+
+        let jwks = match &config.jwks_path {
+            Some(path) => {
+                let content = std::fs::read_to_string(path)
+                    .with_context(|| format!("Failed to read JWKS file: {}", path.display()))?;
+                let jwks: JwkSet = serde_json::from_str(&content)
+                    .with_context(|| format!("Failed to parse JWKS file: {}", path.display()))?;
+                Some(jwks)
+            }
+            None => None,
+        };
+
+        Ok(Self {
+            secret_key,
+            jwks,
+            issuer: config.issuer.clone(),
+            audience: config.audience.clone(),
+            leeway_secs: config.leeway.as_secs(),
+        })
+    }
+
+    /// Validate a token and return its claims
+    pub fn validate(&self, token: &str) -> Result<TokenClaims> {
+        let header = decode_header(token).context("Malformed JWT header")?;
+
+        let key = match header.alg {
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => self.secret_key.clone()
+                .ok_or_else(|| anyhow!("HMAC-signed token but no shared secret is configured"))?,
+            _ => {
+                let jwks = self.jwks.as_ref()
+                    .ok_or_else(|| anyhow!("Asymmetric token but no JWKS is configured"))?;
+                let jwk = match &header.kid {
+                    Some(kid) => jwks.find(kid)
+                        .ok_or_else(|| anyhow!("No JWKS key with kid '{}'", kid))?,
+                    None if jwks.keys.len() == 1 => &jwks.keys[0],
+                    None => return Err(anyhow!("Token has no kid and JWKS holds multiple keys")),
+                };
+                DecodingKey::from_jwk(jwk).context("Unusable JWKS key")?
+            }
+        };
+
+        let mut validation = Validation::new(header.alg);
+        validation.leeway = self.leeway_secs;
+        validation.set_required_spec_claims(&["exp", "sub"]);
+        if let Some(issuer) = &self.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        match &self.audience {
+            Some(audience) => validation.set_audience(&[audience]),
+            None => validation.validate_aud = false,
+        }
+
+        let data = decode::<TokenClaims>(token, &key, &validation)
+            .context("JWT validation failed")?;
+        Ok(data.claims)
+    }
+}
+
+/// Check whether a password looks like a compact-serialized JWT
+pub fn looks_like_jwt(password: &str) -> bool {
+    password.starts_with("eyJ") && password.split('.').count() == 3
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    fn test_config() -> JwtAuthConfig {
+        JwtAuthConfig {
+            secret: Some("test-secret".to_string()),
+            jwks_path: None,
+            issuer: Some("rustproxy-test".to_string()),
+            audience: None,
+            leeway: Duration::from_secs(0),
+        }
+    }
+
+    fn make_token(secret: &str, exp_offset: i64, issuer: &str) -> String {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+        let claims = serde_json::json!({
+            "sub": "alice",
+            "exp": now + exp_offset,
+            "iss": issuer,
+            "roles": ["admin"],
+            "quota_bytes": 1024,
+        });
+        encode(&Header::default(), &claims, &EncodingKey::from_secret(secret.as_bytes())).unwrap()
+    }
+
+    #[test]
+    fn test_valid_token() {
+        let validator = JwtValidator::from_config(&test_config()).unwrap();
+        let token = make_token("test-secret", 300, "rustproxy-test");
+
+        assert!(looks_like_jwt(&token));
+        let claims = validator.validate(&token).unwrap();
+        assert_eq!(claims.sub, "alice");
+        assert_eq!(claims.roles, vec!["admin".to_string()]);
+        assert_eq!(claims.quota_bytes, Some(1024));
+    }
+
+    #[test]
+    fn test_rejects_bad_tokens() {
+        let validator = JwtValidator::from_config(&test_config()).unwrap();
+
+        // Wrong secret
+        assert!(validator.validate(&make_token("other-secret", 300, "rustproxy-test")).is_err());
+        // Expired
+        assert!(validator.validate(&make_token("test-secret", -300, "rustproxy-test")).is_err());
+        // Wrong issuer
+        assert!(validator.validate(&make_token("test-secret", 300, "someone-else")).is_err());
+    }
+
+    #[test]
+    fn test_looks_like_jwt() {
+        assert!(!looks_like_jwt("password123"));
+        assert!(!looks_like_jwt("a.b.c"));
+    }
+}
@@ -1,13 +1,21 @@
 //! Authentication Manager
 
 use crate::Result;
-use super::{AuthResult, UserStore, SessionTracker, RateLimitInfo};
+use super::{AuthRejection, AuthResult, PasswordExpiry, User, UserChange, UserStore, SessionTracker, RateLimitInfo};
+use super::audit::{AuthAttempt, AuthAuditLog};
+use super::cache::AuthCache;
+use super::introspection::TokenIntrospector;
+use super::jwt::{self, JwtValidator};
+use super::webhook::WebhookAuthenticator;
 use crate::protocol::AuthMethod;
-use crate::config::Config;
+use crate::config::{AuthProvider, Config, UserConfig};
+use anyhow::anyhow;
 use std::collections::HashMap;
 use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
+use tokio::sync::broadcast;
 use tracing::{debug, warn, info};
 
 /// Manages user authentication and sessions
@@ -16,6 +24,14 @@ pub struct AuthManager {
     session_tracker: Arc<Mutex<SessionTracker>>,
     ip_rate_limits: Arc<Mutex<HashMap<IpAddr, RateLimitInfo>>>,
     user_rate_limits: Arc<Mutex<HashMap<String, RateLimitInfo>>>,
+    jwt_validator: Option<JwtValidator>,
+    introspector: Option<TokenIntrospector>,
+    webhook: Option<WebhookAuthenticator>,
+    providers: Vec<(AuthProvider, ProviderCounters)>,
+    cache: Option<AuthCache>,
+    audit_log: Option<AuthAuditLog>,
+    expired_password_rejections: AtomicU64,
+    user_changes: broadcast::Sender<UserChange>,
     config: Arc<Config>,
 }
 
@@ -23,108 +39,414 @@ impl AuthManager {
     /// Create a new authentication manager
     pub fn new(config: Arc<Config>) -> Self {
         let mut user_store = UserStore::new();
+        user_store.set_totp_window(config.auth.totp_window);
         user_store.load_from_config(&config.auth.users);
         
+        let jwt_validator = config.auth.jwt.as_ref().and_then(|jwt_config| {
+            match JwtValidator::from_config(jwt_config) {
+                Ok(validator) => Some(validator),
+                Err(e) => {
+                    warn!("JWT authentication disabled, failed to load validator: {}", e);
+                    None
+                }
+            }
+        });
+        
+        let introspector = config.auth.introspection.as_ref().and_then(|introspection_config| {
+            match TokenIntrospector::from_config(introspection_config) {
+                Ok(introspector) => Some(introspector),
+                Err(e) => {
+                    warn!("Token introspection disabled, failed to create client: {}", e);
+                    None
+                }
+            }
+        });
+        
+        let webhook = config.auth.webhook.as_ref().and_then(|webhook_config| {
+            match WebhookAuthenticator::from_config(webhook_config) {
+                Ok(webhook) => Some(webhook),
+                Err(e) => {
+                    warn!("Auth webhook disabled, failed to create client: {}", e);
+                    None
+                }
+            }
+        });
+        
+        let providers = config.auth.providers.iter()
+            .map(|provider| (*provider, ProviderCounters::default()))
+            .collect();
+        
+        let cache = config.auth.cache_ttl.and_then(|ttl| {
+            match AuthCache::new(ttl, config.auth.cache_max_entries) {
+                Ok(cache) => Some(cache),
+                Err(e) => {
+                    warn!("Authentication cache disabled: {:#}", e);
+                    None
+                }
+            }
+        });
+        
+        let audit_log = config.auth.audit_log.as_ref().and_then(|audit_config| {
+            match AuthAuditLog::open(audit_config) {
+                Ok(audit_log) => Some(audit_log),
+                Err(e) => {
+                    warn!("Authentication audit log disabled: {:#}", e);
+                    None
+                }
+            }
+        });
+        
+        let mut session_tracker = SessionTracker::new();
+        if let Some(path) = config.auth.session_state_path.as_deref().filter(|path| path.exists()) {
+            match SessionTracker::load(path) {
+                Ok(records) => {
+                    let restored = session_tracker.import(records);
+                    info!("Restored {} session(s) from {}", restored, path.display());
+                }
+                Err(e) => warn!("Ignoring unreadable session state {}: {:#}", path.display(), e),
+            }
+        }
+        
+        let (user_changes, _) = broadcast::channel(64);
+        
         Self {
             user_store: Arc::new(Mutex::new(user_store)),
-            session_tracker: Arc::new(Mutex::new(SessionTracker::new())),
+            session_tracker: Arc::new(Mutex::new(session_tracker)),
             ip_rate_limits: Arc::new(Mutex::new(HashMap::new())),
             user_rate_limits: Arc::new(Mutex::new(HashMap::new())),
+            jwt_validator,
+            introspector,
+            webhook,
+            providers,
+            cache,
+            audit_log,
+            expired_password_rejections: AtomicU64::new(0),
+            user_changes,
             config,
         }
     }
 
     /// Authenticate a user with the given method and credentials
     pub async fn authenticate(&self, method: AuthMethod, credentials: &[u8], client_ip: IpAddr) -> Result<AuthResult> {
+        let mut attempt = AuthAttempt::new(&method);
+        let result = self.authenticate_attempt(method, credentials, client_ip, &mut attempt).await;
+        if let Some(audit_log) = &self.audit_log {
+            if let Err(e) = audit_log.record(&attempt.into_record(client_ip, &result)) {
+                warn!("Failed to write authentication audit record: {:#}", e);
+            }
+        }
+        result
+    }
+
+    async fn authenticate_attempt(
+        &self,
+        method: AuthMethod,
+        credentials: &[u8],
+        client_ip: IpAddr,
+        attempt: &mut AuthAttempt,
+    ) -> Result<AuthResult> {
         debug!("Authentication attempt from {}: method={:?}", client_ip, method);
 
         // Check rate limiting first
         if self.is_rate_limited(client_ip) {
             warn!("Rate limited authentication attempt from {}", client_ip);
-            return Ok(AuthResult {
-                success: false,
-                user_id: None,
-                session_id: String::new(),
-            });
+            attempt.fail("ip_rate_limited");
+            return Ok(AuthResult::failed());
         }
 
         match method {
             AuthMethod::NoAuth => {
                 if !self.config.auth.enabled {
                     debug!("No authentication required, allowing connection from {}", client_ip);
-                    let session_id = self.create_session("anonymous".to_string(), client_ip);
-                    Ok(AuthResult {
-                        success: true,
-                        user_id: Some("anonymous".to_string()),
-                        session_id,
-                    })
+                    let user_id = super::anonymous::anonymous_user_id(&self.config.auth, client_ip);
+                    let session_id = self.create_session(user_id.clone(), client_ip);
+                    Ok(AuthResult::authenticated(user_id, session_id))
                 } else {
                     warn!("No authentication attempted but authentication is required from {}", client_ip);
                     self.record_auth_failure(client_ip);
-                    Ok(AuthResult {
-                        success: false,
-                        user_id: None,
-                        session_id: String::new(),
-                    })
+                    attempt.fail("authentication_required");
+                    Ok(AuthResult::failed())
                 }
             }
             AuthMethod::UserPass => {
                 if let Some((username, password)) = self.parse_userpass_credentials(credentials) {
+                    attempt.user = Some(username.clone());
+                    
                     // Check user-specific rate limiting
                     if self.is_user_rate_limited(&username) {
                         warn!("User '{}' is rate limited from {}", username, client_ip);
-                        return Ok(AuthResult {
-                            success: false,
-                            user_id: None,
-                            session_id: String::new(),
-                        });
+                        attempt.fail("user_rate_limited");
+                        return Ok(AuthResult::failed());
                     }
 
-                    if self.validate_user(&username, &password) {
-                        info!("Successful authentication for user '{}' from {}", username, client_ip);
-                        self.reset_rate_limit(client_ip);
-                        self.reset_user_rate_limit(&username);
-                        let session_id = self.create_session(username.clone(), client_ip);
-                        Ok(AuthResult {
-                            success: true,
-                            user_id: Some(username),
-                            session_id,
-                        })
-                    } else {
-                        warn!("Failed authentication for user '{}' from {}", username, client_ip);
-                        self.record_auth_failure(client_ip);
-                        self.record_user_auth_failure(&username);
-                        Ok(AuthResult {
-                            success: false,
-                            user_id: None,
-                            session_id: String::new(),
-                        })
-                    }
+                    self.authenticate_with_providers(&username, &password, client_ip, attempt).await
                 } else {
                     warn!("Invalid username/password credentials format from {}", client_ip);
                     self.record_auth_failure(client_ip);
-                    Ok(AuthResult {
-                        success: false,
-                        user_id: None,
-                        session_id: String::new(),
-                    })
+                    attempt.fail("malformed_credentials");
+                    Ok(AuthResult::failed())
                 }
             }
             AuthMethod::Unsupported => {
                 warn!("Unsupported authentication method from {}", client_ip);
-                Ok(AuthResult {
-                    success: false,
-                    user_id: None,
-                    session_id: String::new(),
-                })
+                attempt.fail("unsupported_method");
+                Ok(AuthResult::failed())
             }
         }
     }
 
+    /// Try each credential provider in order until one accepts the login
+    ///
+    /// A provider that cannot give an answer (e.g. its endpoint is down) is
+    /// passed over, so local accounts keep working while a directory is
+    /// unavailable. If no provider accepts, that error is returned so the auth
+    /// backend failure policy decides whether the client is let through,
+    /// unless the user file refused the credentials or was too busy to check
+    /// them: neither is a backend outage, so neither may fail open.
+    async fn authenticate_with_providers(
+        &self,
+        username: &str,
+        password: &str,
+        client_ip: IpAddr,
+        attempt: &mut AuthAttempt,
+    ) -> Result<AuthResult> {
+        let mut backend_error = None;
+        let mut refused_by_file = false;
+        let mut file_busy = false;
+        for (provider, counters) in &self.providers {
+            let outcome = match provider {
+                AuthProvider::File => self.authenticate_file_user(username, password, client_ip).await,
+                AuthProvider::Jwt => match &self.jwt_validator {
+                    // Signed tokens in the password field bypass the user list
+                    Some(validator) if jwt::looks_like_jwt(password) => {
+                        Ok(self.authenticate_token(validator, password, client_ip))
+                    }
+                    _ => Ok(ProviderOutcome::Skipped),
+                },
+                AuthProvider::Introspection => match &self.introspector {
+                    // The reserved token username hands the password to the introspection endpoint
+                    Some(introspector) if username == introspector.token_username() => {
+                        self.authenticate_introspected(introspector, password, client_ip).await
+                    }
+                    _ => Ok(ProviderOutcome::Skipped),
+                },
+                AuthProvider::Webhook => match &self.webhook {
+                    Some(webhook) => self.authenticate_webhook(webhook, username, password, client_ip).await,
+                    None => Ok(ProviderOutcome::Skipped),
+                },
+            };
+            match outcome {
+                Ok(ProviderOutcome::Skipped) => {}
+                Ok(ProviderOutcome::Busy) => {
+                    counters.record(&counters.errors);
+                    file_busy = true;
+                }
+                Ok(ProviderOutcome::Refused(reason)) => {
+                    counters.record(&counters.refused);
+                    refused_by_file |= *provider == AuthProvider::File;
+                    attempt.fail(reason);
+                }
+                Ok(ProviderOutcome::Decided(result)) => {
+                    counters.record(if result.success { &counters.accepted } else { &counters.refused });
+                    attempt.method = match provider {
+                        AuthProvider::File => "userpass",
+                        other => other.as_str(),
+                    };
+                    return Ok(result);
+                }
+                Err(e) => {
+                    counters.record(&counters.errors);
+                    warn!("Auth provider '{}' could not check login for '{}': {:#}", provider.as_str(), username, e);
+                    backend_error.get_or_insert(e);
+                }
+            }
+        }
+        
+        if file_busy {
+            warn!("Refused login for user '{}' from {}: too busy to check credentials", username, client_ip);
+            attempt.fail("server_busy");
+            return Ok(AuthResult::rejected(AuthRejection::Busy));
+        }
+        if let Some(e) = backend_error.filter(|_| !refused_by_file) {
+            return Err(e);
+        }
+        warn!("Failed authentication for user '{}' from {}", username, client_ip);
+        self.record_auth_failure(client_ip);
+        if refused_by_file {
+            self.record_user_auth_failure(username);
+        }
+        attempt.fail("invalid_credentials");
+        Ok(AuthResult::failed())
+    }
+
+    /// Check a login against the configured users and their restrictions
+    async fn authenticate_file_user(&self, username: &str, password: &str, client_ip: IpAddr) -> Result<ProviderOutcome> {
+        match self.validate_user_cached(username, password, client_ip).await {
+            Ok(true) => {}
+            Ok(false) => return Ok(ProviderOutcome::Refused("invalid_credentials")),
+            // A full blocking pool says nothing about the credentials or any backend
+            Err(e) => {
+                warn!("Could not check credentials for user '{}': {:#}", username, e);
+                return Ok(ProviderOutcome::Busy);
+            }
+        }
+        if !self.is_source_allowed(username, client_ip) {
+            warn!("Rejected valid credentials for user '{}' from {}: source network not allowed", username, client_ip);
+            self.record_auth_failure(client_ip);
+            return Ok(ProviderOutcome::Decided(AuthResult::rejected(AuthRejection::SourceNetwork)));
+        }
+        if self.access_remaining(username) == Some(Duration::ZERO) {
+            warn!("Rejected valid credentials for user '{}' from {}: outside access window", username, client_ip);
+            return Ok(ProviderOutcome::Decided(AuthResult::rejected(AuthRejection::OutsideAccessWindow)));
+        }
+        match self.password_expiry(username) {
+            PasswordExpiry::Valid => {}
+            PasswordExpiry::InGrace { remaining } => {
+                warn!("Password for user '{}' has expired; logins are refused in {:?}",
+                      username, Duration::from_secs(remaining.as_secs()));
+            }
+            PasswordExpiry::Expired => {
+                warn!("Rejected valid credentials for user '{}' from {}: password expired", username, client_ip);
+                self.expired_password_rejections.fetch_add(1, Ordering::Relaxed);
+                return Ok(ProviderOutcome::Decided(AuthResult::rejected(AuthRejection::PasswordExpired)));
+            }
+        }
+        info!("Successful authentication for user '{}' from {}", username, client_ip);
+        self.reset_rate_limit(client_ip);
+        self.reset_user_rate_limit(username);
+        let session_id = self.create_session(username.to_string(), client_ip);
+        Ok(ProviderOutcome::Decided(AuthResult::authenticated(username.to_string(), session_id)))
+    }
+
+    /// Authenticate with a JWT presented as the password
+    fn authenticate_token(&self, validator: &JwtValidator, token: &str, client_ip: IpAddr) -> ProviderOutcome {
+        match validator.validate(token) {
+            Ok(claims) => {
+                info!("Successful token authentication for user '{}' from {}", claims.sub, client_ip);
+                self.reset_rate_limit(client_ip);
+                let session_id = self.create_session(claims.sub.clone(), client_ip);
+                let mut result = AuthResult::authenticated(claims.sub, session_id);
+                result.roles = claims.roles;
+                result.quota_bytes = claims.quota_bytes;
+                ProviderOutcome::Decided(result)
+            }
+            Err(e) => {
+                warn!("Token authentication failed from {}: {:#}", client_ip, e);
+                ProviderOutcome::Refused("invalid_token")
+            }
+        }
+    }
+
+    /// Authenticate with an opaque token checked by the introspection endpoint
+    ///
+    /// Endpoint failures are returned as errors and leave the decision to
+    /// the rest of the provider chain.
+    async fn authenticate_introspected(
+        &self,
+        introspector: &TokenIntrospector,
+        token: &str,
+        client_ip: IpAddr,
+    ) -> Result<ProviderOutcome> {
+        match introspector.introspect(token).await? {
+            Some(token_info) => {
+                info!("Successful introspection authentication for user '{}' from {}", token_info.subject, client_ip);
+                self.reset_rate_limit(client_ip);
+                let session_id = self.create_session(token_info.subject.clone(), client_ip);
+                let mut result = AuthResult::authenticated(token_info.subject, session_id);
+                result.roles = token_info.scopes;
+                Ok(ProviderOutcome::Decided(result))
+            }
+            None => {
+                warn!("Inactive token presented from {}", client_ip);
+                Ok(ProviderOutcome::Refused("inactive_token"))
+            }
+        }
+    }
+
+    /// Authenticate with the auth webhook
+    async fn authenticate_webhook(
+        &self,
+        webhook: &WebhookAuthenticator,
+        username: &str,
+        password: &str,
+        client_ip: IpAddr,
+    ) -> Result<ProviderOutcome> {
+        match webhook.check(username, password, client_ip).await? {
+            Some(identity) => {
+                info!("Successful webhook authentication for user '{}' from {}", identity.user_id, client_ip);
+                self.reset_rate_limit(client_ip);
+                let session_id = self.create_session(identity.user_id.clone(), client_ip);
+                let mut result = AuthResult::authenticated(identity.user_id, session_id);
+                result.roles = identity.roles;
+                result.quota_bytes = identity.quota_bytes;
+                Ok(ProviderOutcome::Decided(result))
+            }
+            None => Ok(ProviderOutcome::Refused("webhook_refused")),
+        }
+    }
+
+    /// Check whether the user store has a user by this name
+    pub fn user_exists(&self, username: &str) -> bool {
+        self.user_store.lock().unwrap().user_exists(username)
+    }
+
     /// Validate user credentials
     pub fn validate_user(&self, username: &str, password: &str) -> bool {
+        self.credentials_for(username)
+            .is_some_and(|(user, totp_window)| user.verify_credentials(password, totp_window))
+    }
+
+    /// Copy a user out of the store, so the slow hash check runs without the lock held
+    fn credentials_for(&self, username: &str) -> Option<(User, u64)> {
+        let user_store = self.user_store.lock().unwrap();
+        let user = user_store.get_user(username)?.clone();
+        Some((user, user_store.totp_window()))
+    }
+
+    /// Validate credentials, reusing a recent successful check from the same client
+    async fn validate_user_cached(&self, username: &str, password: &str, client_ip: IpAddr) -> Result<bool> {
+        let Some(cache) = &self.cache else {
+            return self.validate_user_blocking(username, password).await;
+        };
+        if cache.contains(client_ip, username, password) {
+            debug!("Using cached credential check for user '{}' from {}", username, client_ip);
+            return Ok(true);
+        }
+        let valid = self.validate_user_blocking(username, password).await?;
+        if valid {
+            cache.insert(client_ip, username, password);
+        }
+        Ok(valid)
+    }
+
+    /// Validate credentials on the blocking pool, keeping password hashing off the async threads
+    async fn validate_user_blocking(&self, username: &str, password: &str) -> Result<bool> {
+        let Some((user, totp_window)) = self.credentials_for(username) else {
+            return Ok(false);
+        };
+        let password = password.to_string();
+        crate::blocking::pool()
+            .run(move || user.verify_credentials(&password, totp_window))
+            .await
+    }
+
+    /// Check whether a user may authenticate from the given address
+    pub fn is_source_allowed(&self, username: &str, client_ip: IpAddr) -> bool {
+        let user_store = self.user_store.lock().unwrap();
+        user_store.is_source_allowed(username, client_ip)
+    }
+
+    /// Check the user's password expiry against the configured grace period
+    pub fn password_expiry(&self, username: &str) -> PasswordExpiry {
+        let grace = self.config.auth.password_expiry_grace.unwrap_or_default();
+        let user_store = self.user_store.lock().unwrap();
+        user_store.password_expiry(username, SystemTime::now(), grace)
+    }
+
+    /// How long a user may keep relaying before their access window closes
+    pub fn access_remaining(&self, username: &str) -> Option<Duration> {
         let user_store = self.user_store.lock().unwrap();
-        user_store.validate_credentials(username, password)
+        user_store.access_remaining(username, SystemTime::now())
     }
 
     /// Create a new session for a user
@@ -230,6 +552,23 @@ impl AuthManager {
         session_tracker.update_session_activity(session_id)
     }
 
+    /// Add bytes transferred by a finished relay to its session
+    pub fn record_session_bytes(&self, session_id: &str, bytes_up: u64, bytes_down: u64) -> bool {
+        let mut session_tracker = self.session_tracker.lock().unwrap();
+        session_tracker.record_bytes(session_id, bytes_up, bytes_down)
+    }
+
+    /// Save active sessions to `auth.session_state_path`, if configured
+    pub fn save_sessions(&self) -> Result<()> {
+        let Some(path) = &self.config.auth.session_state_path else {
+            return Ok(());
+        };
+        let session_tracker = self.session_tracker.lock().unwrap();
+        session_tracker.save(path)?;
+        debug!("Saved {} session(s) to {}", session_tracker.active_session_count(), path.display());
+        Ok(())
+    }
+
     /// Remove a session
     pub fn remove_session(&self, session_id: &str) -> bool {
         let mut session_tracker = self.session_tracker.lock().unwrap();
@@ -246,6 +585,10 @@ impl AuthManager {
             debug!("Cleaned up {} expired sessions", expired_count);
         }
 
+        if let Some(cache) = &self.cache {
+            cache.prune();
+        }
+
         // Clean up old IP rate limit entries
         let mut ip_rate_limits = self.ip_rate_limits.lock().unwrap();
         let cutoff = std::time::Instant::now() - Duration::from_secs(3600); // 1 hour
@@ -270,6 +613,17 @@ impl AuthManager {
             active_sessions: session_tracker.active_session_count(),
             rate_limited_ips: ip_rate_limits.len(),
             rate_limited_users: user_rate_limits.len(),
+            cached_credentials: self.cache.as_ref().map_or(0, AuthCache::len),
+            expired_password_rejections: self.expired_password_rejections.load(Ordering::Relaxed),
+            providers: self.providers.iter()
+                .map(|(provider, counters)| ProviderStats {
+                    provider: *provider,
+                    attempts: counters.attempts.load(Ordering::Relaxed),
+                    accepted: counters.accepted.load(Ordering::Relaxed),
+                    refused: counters.refused.load(Ordering::Relaxed),
+                    errors: counters.errors.load(Ordering::Relaxed),
+                })
+                .collect(),
         }
     }
 
@@ -277,7 +631,80 @@ impl AuthManager {
     pub fn reload_users(&self, config: &Config) {
         let mut user_store = self.user_store.lock().unwrap();
         user_store.load_from_config(&config.auth.users);
+        if let Some(cache) = &self.cache {
+            cache.clear();
+        }
         info!("Reloaded {} users from configuration", config.auth.users.len());
+        self.notify(UserChange::Reloaded);
+    }
+
+    /// Add a user; fails if the username is taken
+    pub fn add_user(&self, user_config: &UserConfig) -> Result<()> {
+        let mut user_store = self.user_store.lock().unwrap();
+        if user_store.user_exists(&user_config.username) {
+            return Err(anyhow!("User '{}' already exists", user_config.username));
+        }
+        user_store.insert_user(User::from_config(user_config));
+        drop(user_store);
+        
+        info!("Added user '{}'", user_config.username);
+        self.notify(UserChange::Added(user_config.username.clone()));
+        Ok(())
+    }
+
+    /// Replace an existing user's settings, including the password
+    pub fn update_user(&self, user_config: &UserConfig) -> Result<()> {
+        let mut user_store = self.user_store.lock().unwrap();
+        if !user_store.user_exists(&user_config.username) {
+            return Err(anyhow!("User '{}' not found", user_config.username));
+        }
+        user_store.insert_user(User::from_config(user_config));
+        drop(user_store);
+        
+        self.invalidate_cached(&user_config.username);
+        info!("Updated user '{}'", user_config.username);
+        self.notify(UserChange::Updated(user_config.username.clone()));
+        Ok(())
+    }
+
+    /// Stop a user from authenticating, keeping their settings
+    pub fn disable_user(&self, username: &str) -> Result<()> {
+        if !self.user_store.lock().unwrap().set_enabled(username, false) {
+            return Err(anyhow!("User '{}' not found", username));
+        }
+        
+        self.invalidate_cached(username);
+        info!("Disabled user '{}'", username);
+        self.notify(UserChange::Disabled(username.to_string()));
+        Ok(())
+    }
+
+    /// Remove a user
+    pub fn remove_user(&self, username: &str) -> Result<()> {
+        if !self.user_store.lock().unwrap().remove_user(username) {
+            return Err(anyhow!("User '{}' not found", username));
+        }
+        
+        self.invalidate_cached(username);
+        info!("Removed user '{}'", username);
+        self.notify(UserChange::Removed(username.to_string()));
+        Ok(())
+    }
+
+    /// Receive a notification for every runtime change to the users
+    pub fn subscribe_user_changes(&self) -> broadcast::Receiver<UserChange> {
+        self.user_changes.subscribe()
+    }
+
+    fn invalidate_cached(&self, username: &str) {
+        if let Some(cache) = &self.cache {
+            cache.invalidate_user(username);
+        }
+    }
+
+    fn notify(&self, change: UserChange) {
+        // Nobody listening is fine
+        let _ = self.user_changes.send(change);
     }
 }
 
@@ -287,4 +714,184 @@ pub struct AuthStats {
     pub active_sessions: usize,
     pub rate_limited_ips: usize,
     pub rate_limited_users: usize,
-}
\ No newline at end of file
+    pub cached_credentials: usize,
+    /// Logins refused because the password expired
+    pub expired_password_rejections: u64,
+    /// Outcomes per credential provider, in chain order
+    pub providers: Vec<ProviderStats>,
+}
+
+/// How often a credential provider was asked and what it answered
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProviderStats {
+    pub provider: AuthProvider,
+    pub attempts: u64,
+    pub accepted: u64,
+    /// Bad credentials, or valid ones refused by a user restriction
+    pub refused: u64,
+    /// The provider could not give an answer
+    pub errors: u64,
+}
+
+/// Answer from one credential provider in the chain
+enum ProviderOutcome {
+    /// The provider does not handle these credentials
+    Skipped,
+    /// The provider does not accept the credentials; try the next one
+    Refused(&'static str),
+    /// The provider could not check the credentials for lack of capacity
+    Busy,
+    /// The provider accepted the credentials, or accepted them and then
+    /// refused the user for a restriction
+    Decided(AuthResult),
+}
+
+#[derive(Default)]
+struct ProviderCounters {
+    attempts: AtomicU64,
+    accepted: AtomicU64,
+    refused: AtomicU64,
+    errors: AtomicU64,
+}
+
+impl ProviderCounters {
+    fn record(&self, outcome: &AtomicU64) {
+        self.attempts.fetch_add(1, Ordering::Relaxed);
+        outcome.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AuthWebhookConfig;
+
+    fn manager() -> AuthManager {
+        let mut config = Config::default();
+        config.auth.users = vec![UserConfig::new("alice", "secret")];
+        AuthManager::new(Arc::new(config))
+    }
+
+    #[test]
+    fn test_runtime_changes_apply_immediately() {
+        let auth = manager();
+        let mut changes = auth.subscribe_user_changes();
+
+        auth.add_user(&UserConfig::new("bob", "hunter2")).unwrap();
+        assert!(auth.validate_user("bob", "hunter2"));
+        assert!(auth.add_user(&UserConfig::new("bob", "other")).is_err());
+
+        auth.update_user(&UserConfig::new("alice", "rotated")).unwrap();
+        assert!(!auth.validate_user("alice", "secret"));
+        assert!(auth.validate_user("alice", "rotated"));
+
+        auth.disable_user("bob").unwrap();
+        assert!(!auth.validate_user("bob", "hunter2"));
+        auth.remove_user("bob").unwrap();
+        assert!(auth.remove_user("bob").is_err());
+
+        let received: Vec<UserChange> = std::iter::from_fn(|| changes.try_recv().ok()).collect();
+        assert_eq!(received, vec![
+            UserChange::Added("bob".to_string()),
+            UserChange::Updated("alice".to_string()),
+            UserChange::Disabled("bob".to_string()),
+            UserChange::Removed("bob".to_string()),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_disable_drops_cached_credentials() {
+        let mut config = Config::default();
+        config.auth.users = vec![UserConfig::new("alice", "secret")];
+        config.auth.cache_ttl = Some(Duration::from_secs(60));
+        let auth = AuthManager::new(Arc::new(config));
+        let ip: IpAddr = "192.0.2.20".parse().unwrap();
+
+        assert!(auth.validate_user_cached("alice", "secret", ip).await.unwrap());
+        assert_eq!(auth.get_stats().cached_credentials, 1);
+
+        auth.disable_user("alice").unwrap();
+        assert_eq!(auth.get_stats().cached_credentials, 0);
+        assert!(!auth.validate_user_cached("alice", "secret", ip).await.unwrap());
+    }
+
+    fn userpass(username: &str, password: &str) -> Vec<u8> {
+        let mut credentials = vec![0x01, username.len() as u8];
+        credentials.extend_from_slice(username.as_bytes());
+        credentials.push(password.len() as u8);
+        credentials.extend_from_slice(password.as_bytes());
+        credentials
+    }
+
+    /// Auth webhook that accepts only "bob"
+    async fn webhook_endpoint() -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut request = Vec::new();
+                let mut buf = [0u8; 4096];
+                while !String::from_utf8_lossy(&request).ends_with('}') {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    if n == 0 {
+                        break;
+                    }
+                    request.extend_from_slice(&buf[..n]);
+                }
+                let allow = String::from_utf8_lossy(&request).contains(r#""username":"bob""#);
+                let body = format!(r#"{{"allow":{}}}"#, allow);
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+        format!("http://{}/login", addr)
+    }
+
+    fn chained_manager(webhook_url: String) -> AuthManager {
+        let mut config = Config::default();
+        config.auth.users = vec![UserConfig::new("alice", "secret")];
+        config.auth.webhook = Some(AuthWebhookConfig {
+            url: webhook_url,
+            bearer_token: None,
+            timeout: Duration::from_secs(2),
+        });
+        config.auth.providers = vec![AuthProvider::File, AuthProvider::Webhook];
+        AuthManager::new(Arc::new(config))
+    }
+
+    #[tokio::test]
+    async fn test_providers_are_tried_in_order() {
+        let auth = chained_manager(webhook_endpoint().await);
+        let ip: IpAddr = "192.0.2.30".parse().unwrap();
+
+        let alice = auth.authenticate(AuthMethod::UserPass, &userpass("alice", "secret"), ip).await.unwrap();
+        let bob = auth.authenticate(AuthMethod::UserPass, &userpass("bob", "directory"), ip).await.unwrap();
+        let carol = auth.authenticate(AuthMethod::UserPass, &userpass("carol", "guess"), ip).await.unwrap();
+        assert!(alice.success && bob.success && !carol.success);
+        assert_eq!(bob.user_id.as_deref(), Some("bob"));
+
+        let stats = auth.get_stats().providers;
+        assert_eq!(stats[0], ProviderStats { provider: AuthProvider::File, attempts: 3, accepted: 1, refused: 2, errors: 0 });
+        assert_eq!(stats[1], ProviderStats { provider: AuthProvider::Webhook, attempts: 2, accepted: 1, refused: 1, errors: 0 });
+    }
+
+    #[tokio::test]
+    async fn test_local_accounts_work_while_webhook_is_down() {
+        let closed = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let auth = chained_manager(format!("http://{}/login", closed));
+        let ip: IpAddr = "192.0.2.31".parse().unwrap();
+
+        let alice = auth.authenticate(AuthMethod::UserPass, &userpass("alice", "secret"), ip).await.unwrap();
+        assert!(alice.success);
+        // The user file refused bob, so the webhook outage must not let him in under fail-open
+        let bob = auth.authenticate(AuthMethod::UserPass, &userpass("bob", "directory"), ip).await.unwrap();
+        assert!(!bob.success);
+        assert_eq!(auth.get_stats().providers[1].errors, 1);
+    }
+}
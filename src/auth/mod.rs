@@ -2,8 +2,20 @@
 //! 
 //! Handles user authentication and session management.
 
+pub mod anonymous;
+pub mod audit;
+pub mod cache;
+pub mod introspection;
+pub mod jwt;
 pub mod manager;
+pub mod totp;
 pub mod types;
+pub mod webhook;
 
-pub use manager::{AuthManager, AuthStats};
-pub use types::{AuthResult, UserSession, User, UserStore, SessionTracker, RateLimitInfo};
\ No newline at end of file
+pub use audit::{AuditOutcome, AuthAuditLog, AuthAuditRecord};
+pub use cache::AuthCache;
+pub use introspection::{IntrospectedToken, TokenIntrospector};
+pub use jwt::{JwtValidator, TokenClaims};
+pub use webhook::{WebhookAuthenticator, WebhookIdentity};
+pub use manager::{AuthManager, AuthStats, ProviderStats};
+pub use types::{AuthRejection, AuthResult, PasswordExpiry, UserChange, UserSession, User, UserStore, SessionTracker, RateLimitInfo};
\ No newline at end of file
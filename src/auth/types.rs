@@ -1,8 +1,14 @@
 //! Authentication Types
 
+use crate::Result;
+use anyhow::Context;
+use argon2::password_hash::{PasswordHash, PasswordVerifier};
+use argon2::Argon2;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::net::IpAddr;
-use std::time::{Duration, Instant};
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use uuid::Uuid;
 
 /// Authentication result
@@ -11,6 +17,82 @@ pub struct AuthResult {
     pub success: bool,
     pub user_id: Option<String>,
     pub session_id: String,
+    /// Roles granted to the user (from token claims)
+    pub roles: Vec<String>,
+    /// Data quota in bytes (from token claims)
+    pub quota_bytes: Option<u64>,
+    /// Why otherwise valid credentials were refused
+    pub rejection: Option<AuthRejection>,
+}
+
+/// Reason a login was refused other than wrong credentials
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthRejection {
+    /// The client's network is not allowed for the user
+    SourceNetwork,
+    /// The user is outside their access windows
+    OutsideAccessWindow,
+    /// The user's password expired and the grace period is over
+    PasswordExpired,
+    /// The credentials could not be checked because the server is overloaded;
+    /// the client may retry
+    Busy,
+}
+
+/// A change made to the user store at runtime
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UserChange {
+    Added(String),
+    Updated(String),
+    Disabled(String),
+    Removed(String),
+    /// All users were reloaded from configuration
+    Reloaded,
+}
+
+/// Where a user's password stands against its expiry date
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PasswordExpiry {
+    /// No expiry date, or not reached yet
+    Valid,
+    /// Expired, but still accepted for `remaining`
+    InGrace { remaining: Duration },
+    /// Expired and no longer accepted
+    Expired,
+}
+
+impl AuthResult {
+    /// A failed authentication
+    pub fn failed() -> Self {
+        Self {
+            success: false,
+            user_id: None,
+            session_id: String::new(),
+            roles: Vec::new(),
+            quota_bytes: None,
+            rejection: None,
+        }
+    }
+
+    /// A failed authentication for valid credentials refused by a user restriction
+    pub fn rejected(reason: AuthRejection) -> Self {
+        Self {
+            rejection: Some(reason),
+            ..Self::failed()
+        }
+    }
+
+    /// A successful authentication for the given user and session
+    pub fn authenticated(user_id: String, session_id: String) -> Self {
+        Self {
+            success: true,
+            user_id: Some(user_id),
+            session_id,
+            roles: Vec::new(),
+            quota_bytes: None,
+            rejection: None,
+        }
+    }
 }
 
 /// User session information
@@ -21,6 +103,10 @@ pub struct UserSession {
     pub created_at: Instant,
     pub last_activity: Instant,
     pub client_ip: IpAddr,
+    /// Wall-clock start time, kept so the session can be persisted
+    pub started_at: SystemTime,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
 }
 
 impl UserSession {
@@ -33,9 +119,39 @@ impl UserSession {
             created_at: now,
             last_activity: now,
             client_ip,
+            started_at: SystemTime::now(),
+            bytes_up: 0,
+            bytes_down: 0,
         }
     }
 
+    /// Rebuild a session saved by a previous run
+    ///
+    /// The idle timer starts over, since the session could not have been
+    /// used while the proxy was down.
+    pub fn from_record(record: SessionRecord) -> Self {
+        let now = Instant::now();
+        let started_at = UNIX_EPOCH + Duration::from_secs(record.started_at);
+        let age = SystemTime::now().duration_since(started_at).unwrap_or_default();
+        Self {
+            session_id: record.session_id,
+            user_id: record.user_id,
+            created_at: now.checked_sub(age).unwrap_or(now),
+            last_activity: now,
+            client_ip: record.client_ip,
+            started_at,
+            bytes_up: record.bytes_up,
+            bytes_down: record.bytes_down,
+        }
+    }
+
+    /// Add transferred bytes to the session totals
+    pub fn record_bytes(&mut self, bytes_up: u64, bytes_down: u64) {
+        self.bytes_up = self.bytes_up.saturating_add(bytes_up);
+        self.bytes_down = self.bytes_down.saturating_add(bytes_down);
+        self.update_activity();
+    }
+
     /// Update the last activity timestamp
     pub fn update_activity(&mut self) {
         self.last_activity = Instant::now();
@@ -47,16 +163,69 @@ impl UserSession {
     }
 }
 
+/// Session metadata as written to the session state file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionRecord {
+    pub session_id: String,
+    pub user_id: String,
+    pub client_ip: IpAddr,
+    /// Start time in seconds since the Unix epoch
+    pub started_at: u64,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+}
+
+impl From<&UserSession> for SessionRecord {
+    fn from(session: &UserSession) -> Self {
+        Self {
+            session_id: session.session_id.clone(),
+            user_id: session.user_id.clone(),
+            client_ip: session.client_ip,
+            started_at: session.started_at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+            bytes_up: session.bytes_up,
+            bytes_down: session.bytes_down,
+        }
+    }
+}
+
 /// User information stored in the user store
 #[derive(Debug, Clone)]
 pub struct User {
     pub username: String,
+    /// Argon2 PHC string from the configuration, or an in-memory hash of a
+    /// plaintext password
     pub password_hash: String,
     pub enabled: bool,
     pub created_at: Instant,
+    /// Decoded TOTP secret; when set, a code must follow the password
+    pub totp_secret: Option<Vec<u8>>,
+    /// Networks the user may connect from (empty allows any)
+    pub allowed_sources: Vec<ipnet::IpNet>,
+    /// Times the user may connect (empty allows any time)
+    pub access_windows: Vec<crate::schedule::Schedule>,
+    /// When the password stops being accepted
+    pub password_expires: Option<SystemTime>,
 }
 
 impl User {
+    /// Check a password (followed by a TOTP code when the user has a secret)
+    /// for an enabled user
+    pub fn verify_credentials(&self, password: &str, totp_window: u64) -> bool {
+        if !self.enabled {
+            return false;
+        }
+        match &self.totp_secret {
+            Some(secret) => match super::totp::split_code(password) {
+                Some((password, code)) => {
+                    self.verify_password(password)
+                        && super::totp::verify(secret, code, super::totp::now(), totp_window)
+                }
+                None => false,
+            },
+            None => self.verify_password(password),
+        }
+    }
+
     /// Create a new user with hashed password
     pub fn new(username: String, password: String, enabled: bool) -> Self {
         Self {
@@ -64,6 +233,68 @@ impl User {
             password_hash: Self::hash_password(&password),
             enabled,
             created_at: Instant::now(),
+            totp_secret: None,
+            allowed_sources: Vec::new(),
+            access_windows: Vec::new(),
+            password_expires: None,
+        }
+    }
+
+    /// Build a user from its configuration entry
+    ///
+    /// Users whose source restrictions or TOTP secret cannot be parsed are
+    /// disabled rather than loaded with a weaker login.
+    pub fn from_config(user_config: &crate::config::UserConfig) -> Self {
+        let mut user = match &user_config.password_hash {
+            Some(hash) => User {
+                password_hash: hash.clone(),
+                ..User::new(user_config.username.clone(), String::new(), user_config.enabled)
+            },
+            None => User::new(
+                user_config.username.clone(),
+                user_config.password.clone(),
+                user_config.enabled,
+            ),
+        };
+        user.access_windows = user_config.access_windows.clone();
+        user.password_expires = user_config.password_expires;
+        for cidr in &user_config.allowed_source_cidrs {
+            match crate::config::parse_source_cidr(cidr) {
+                Ok(net) => user.allowed_sources.push(net),
+                Err(e) => {
+                    // An unparsable restriction must not turn into "allow from anywhere"
+                    tracing::warn!("Disabling user '{}': {:#}", user_config.username, e);
+                    user.enabled = false;
+                }
+            }
+        }
+        if let Some(secret) = &user_config.totp_secret {
+            match super::totp::decode_base32(secret) {
+                Ok(secret) => user.totp_secret = Some(secret),
+                Err(e) => {
+                    // Never fall back to password-only login for a user meant to have 2FA
+                    tracing::warn!("Disabling user '{}': invalid TOTP secret: {:#}", user_config.username, e);
+                    user.enabled = false;
+                }
+            }
+        }
+        user
+    }
+
+    /// Check whether the user may authenticate from the given address
+    pub fn allows_source(&self, ip: IpAddr) -> bool {
+        self.allowed_sources.is_empty() || self.allowed_sources.iter().any(|net| net.contains(&ip))
+    }
+
+    /// Check the password's expiry date, allowing `grace` past it
+    pub fn password_expiry(&self, now: SystemTime, grace: Duration) -> PasswordExpiry {
+        let Some(expires) = self.password_expires else {
+            return PasswordExpiry::Valid;
+        };
+        match now.duration_since(expires) {
+            Err(_) => PasswordExpiry::Valid,
+            Ok(overdue) if overdue < grace => PasswordExpiry::InGrace { remaining: grace - overdue },
+            Ok(_) => PasswordExpiry::Expired,
         }
     }
 
@@ -80,8 +311,20 @@ impl User {
     }
 
     /// Verify a password against the stored hash
+    ///
+    /// Argon2 hashes carry their own cost and salt; verifying one takes
+    /// milliseconds, which is why logins are checked on the blocking pool.
     pub fn verify_password(&self, password: &str) -> bool {
-        self.password_hash == Self::hash_password(password)
+        if !self.password_hash.starts_with("$argon2") {
+            return self.password_hash == Self::hash_password(password);
+        }
+        match PasswordHash::new(&self.password_hash) {
+            Ok(parsed) => Argon2::default().verify_password(password.as_bytes(), &parsed).is_ok(),
+            Err(e) => {
+                tracing::warn!("Unparsable password hash for user '{}': {}", self.username, e);
+                false
+            }
+        }
     }
 }
 
@@ -153,6 +396,8 @@ impl RateLimitInfo {
 #[derive(Debug)]
 pub struct UserStore {
     users: HashMap<String, User>,
+    /// TOTP steps accepted either side of the current one
+    totp_window: u64,
 }
 
 impl UserStore {
@@ -160,9 +405,15 @@ impl UserStore {
     pub fn new() -> Self {
         Self {
             users: HashMap::new(),
+            totp_window: 1,
         }
     }
 
+    /// Set how many TOTP steps of clock drift to tolerate
+    pub fn set_totp_window(&mut self, window: u64) {
+        self.totp_window = window;
+    }
+
     /// Add a user to the store
     pub fn add_user(&mut self, username: String, password: String, enabled: bool) {
         let user = User::new(username.clone(), password, enabled);
@@ -175,26 +426,67 @@ impl UserStore {
     }
 
     /// Validate user credentials
+    ///
+    /// Users with a TOTP secret must append the current code to their password.
     pub fn validate_credentials(&self, username: &str, password: &str) -> bool {
-        if let Some(user) = self.get_user(username) {
-            user.enabled && user.verify_password(password)
-        } else {
-            false
-        }
+        self.get_user(username)
+            .is_some_and(|user| user.verify_credentials(password, self.totp_window))
+    }
+
+    /// TOTP steps accepted either side of the current one
+    pub fn totp_window(&self) -> u64 {
+        self.totp_window
     }
 
     /// Load users from configuration
     pub fn load_from_config(&mut self, users: &[crate::config::UserConfig]) {
         self.users.clear();
         for user_config in users {
-            self.add_user(
-                user_config.username.clone(),
-                user_config.password.clone(),
-                user_config.enabled,
-            );
+            self.insert_user(User::from_config(user_config));
         }
     }
 
+    /// Add a user, replacing any existing user with the same name
+    pub fn insert_user(&mut self, user: User) {
+        self.users.insert(user.username.clone(), user);
+    }
+
+    /// Remove a user, returning whether it existed
+    pub fn remove_user(&mut self, username: &str) -> bool {
+        self.users.remove(username).is_some()
+    }
+
+    /// Enable or disable a user, returning whether it exists
+    pub fn set_enabled(&mut self, username: &str, enabled: bool) -> bool {
+        match self.users.get_mut(username) {
+            Some(user) => {
+                user.enabled = enabled;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Check whether a user may authenticate from the given address
+    pub fn is_source_allowed(&self, username: &str, ip: IpAddr) -> bool {
+        self.get_user(username).is_some_and(|user| user.allows_source(ip))
+    }
+
+    /// How long a user may keep connecting from `now`
+    ///
+    /// `Some(Duration::ZERO)` means the user is outside their access windows;
+    /// `None` means the user is not time-restricted (or does not exist).
+    pub fn access_remaining(&self, username: &str, now: SystemTime) -> Option<Duration> {
+        self.get_user(username)
+            .and_then(|user| crate::schedule::remaining(&user.access_windows, now))
+    }
+
+    /// Check a user's password expiry (unknown users count as valid)
+    pub fn password_expiry(&self, username: &str, now: SystemTime, grace: Duration) -> PasswordExpiry {
+        self.get_user(username)
+            .map_or(PasswordExpiry::Valid, |user| user.password_expiry(now, grace))
+    }
+
     /// Get all usernames
     pub fn get_usernames(&self) -> Vec<String> {
         self.users.keys().cloned().collect()
@@ -288,6 +580,63 @@ impl SessionTracker {
         count
     }
 
+    /// Add transferred bytes to a session
+    pub fn record_bytes(&mut self, session_id: &str, bytes_up: u64, bytes_down: u64) -> bool {
+        if let Some(session) = self.sessions.get_mut(session_id) {
+            session.record_bytes(bytes_up, bytes_down);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Total bytes (up, down) across a user's active sessions
+    pub fn user_bytes(&self, user_id: &str) -> (u64, u64) {
+        self.get_user_sessions(user_id).iter()
+            .fold((0, 0), |(up, down), session| (up + session.bytes_up, down + session.bytes_down))
+    }
+
+    /// Metadata for all active sessions
+    pub fn export(&self) -> Vec<SessionRecord> {
+        self.sessions.values().map(SessionRecord::from).collect()
+    }
+
+    /// Add sessions saved by a previous run, returning how many were restored
+    ///
+    /// Sessions whose id is already tracked are skipped.
+    pub fn import(&mut self, records: Vec<SessionRecord>) -> usize {
+        let mut restored = 0;
+        for record in records {
+            if self.sessions.contains_key(&record.session_id) {
+                continue;
+            }
+            let session = UserSession::from_record(record);
+            self.user_sessions
+                .entry(session.user_id.clone())
+                .or_default()
+                .push(session.session_id.clone());
+            self.sessions.insert(session.session_id.clone(), session);
+            restored += 1;
+        }
+        restored
+    }
+
+    /// Write active sessions to a JSON state file
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut records = self.export();
+        records.sort_by(|a, b| a.session_id.cmp(&b.session_id));
+        let content = serde_json::to_string_pretty(&records)?;
+        crate::security::quota::write_atomically(path, &content)
+    }
+
+    /// Read sessions from a JSON state file written by [`SessionTracker::save`]
+    pub fn load(path: &Path) -> Result<Vec<SessionRecord>> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        serde_json::from_str(&content)
+            .with_context(|| format!("Failed to parse {}", path.display()))
+    }
+
     /// Get active session count
     pub fn active_session_count(&self) -> usize {
         self.sessions.len()
@@ -304,4 +653,152 @@ impl SessionTracker {
             Vec::new()
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_session_bytes_accumulate_per_user() {
+        let mut tracker = SessionTracker::new();
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let first = tracker.create_session("alice".to_string(), ip);
+        let second = tracker.create_session("alice".to_string(), ip);
+
+        assert!(tracker.record_bytes(&first, 100, 1000));
+        assert!(tracker.record_bytes(&second, 50, 500));
+        assert!(tracker.record_bytes(&first, 1, 1));
+        assert!(!tracker.record_bytes("missing", 1, 1));
+
+        assert_eq!(tracker.user_bytes("alice"), (151, 1501));
+        assert_eq!(tracker.user_bytes("bob"), (0, 0));
+    }
+
+    #[test]
+    fn test_sessions_survive_save_and_load() {
+        let path = std::env::temp_dir().join(format!("rustproxy-sessions-{}.json", Uuid::new_v4()));
+        let ip = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 20));
+
+        let mut tracker = SessionTracker::new();
+        let session_id = tracker.create_session("alice".to_string(), ip);
+        tracker.record_bytes(&session_id, 4096, 65536);
+        tracker.save(&path).unwrap();
+
+        let mut restored = SessionTracker::new();
+        let records = SessionTracker::load(&path).unwrap();
+        assert_eq!(restored.import(records.clone()), 1);
+        assert_eq!(restored.import(records), 0);
+        std::fs::remove_file(&path).unwrap();
+
+        let session = restored.get_session(&session_id).unwrap();
+        assert_eq!(session.user_id, "alice");
+        assert_eq!(session.client_ip, ip);
+        assert_eq!(restored.user_bytes("alice"), (4096, 65536));
+        assert_eq!(
+            SessionRecord::from(session).started_at,
+            SessionRecord::from(tracker.get_session(&session_id).unwrap()).started_at
+        );
+    }
+
+    #[test]
+    fn test_totp_user_needs_code_after_password() {
+        let secret = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+        let mut store = UserStore::new();
+        store.load_from_config(&[crate::config::UserConfig {
+            totp_secret: Some(secret.to_string()),
+            ..crate::config::UserConfig::new("alice", "hunter2")
+        }]);
+
+        let key = crate::auth::totp::decode_base32(secret).unwrap();
+        let code = crate::auth::totp::code_for(&key, crate::auth::totp::now());
+
+        assert!(store.validate_credentials("alice", &format!("hunter2{}", code)));
+        assert!(!store.validate_credentials("alice", "hunter2"));
+        assert!(!store.validate_credentials("alice", &format!("wrong{}", code)));
+    }
+
+    #[test]
+    fn test_argon2_password_hash_from_config() {
+        use argon2::password_hash::{PasswordHasher, SaltString};
+        use argon2::{Algorithm, Params, Version};
+
+        // Cheap parameters keep debug builds fast; verification reads them from the hash
+        let hash = Argon2::new(Algorithm::Argon2id, Version::V0x13, Params::new(64, 1, 1, None).unwrap())
+            .hash_password(b"correct horse", &SaltString::encode_b64(b"fixed test salt").unwrap())
+            .unwrap()
+            .to_string();
+        let mut config = crate::config::Config::default();
+        config.auth.users = vec![crate::config::UserConfig {
+            password_hash: Some(hash.clone()),
+            ..crate::config::UserConfig::new("alice", "")
+        }];
+        config.validate().unwrap();
+
+        let mut store = UserStore::new();
+        store.load_from_config(&config.auth.users);
+        assert!(store.validate_credentials("alice", "correct horse"));
+        assert!(!store.validate_credentials("alice", "correct horse "));
+        assert!(!store.validate_credentials("alice", ""));
+
+        // A plaintext password next to the hash, or a hash that is not Argon2, is refused
+        config.auth.users[0].password = "correct horse".to_string();
+        assert!(config.validate().is_err());
+        config.auth.users[0].password.clear();
+        config.auth.users[0].password_hash = Some("$2b$12$notargon".to_string());
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_user_bound_to_source_networks() {
+        let mut store = UserStore::new();
+        let mut alice = crate::config::UserConfig::new("alice", "secret");
+        alice.allowed_source_cidrs = vec!["10.0.0.0/8".to_string(), "192.168.1.5".to_string()];
+        store.load_from_config(&[alice, crate::config::UserConfig::new("bob", "secret")]);
+
+        assert!(store.is_source_allowed("alice", "10.20.30.40".parse().unwrap()));
+        assert!(store.is_source_allowed("alice", "192.168.1.5".parse().unwrap()));
+        assert!(!store.is_source_allowed("alice", "192.168.1.6".parse().unwrap()));
+        assert!(store.is_source_allowed("bob", "203.0.113.1".parse().unwrap()));
+        assert!(!store.is_source_allowed("nobody", "10.0.0.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_access_windows_limit_user() {
+        let mut contractor = crate::config::UserConfig::new("contractor", "secret");
+        contractor.access_windows = vec![toml::from_str("days = [\"mon\"]\nstart = \"09:00\"\nend = \"17:00\"").unwrap()];
+        let mut store = UserStore::new();
+        store.load_from_config(&[contractor, crate::config::UserConfig::new("staff", "secret")]);
+
+        // 2024-01-08 was a Monday
+        let monday = UNIX_EPOCH + Duration::from_secs(1_704_672_000);
+        let hour = |h: u64| monday + Duration::from_secs(h * 3600);
+
+        assert_eq!(store.access_remaining("contractor", hour(16)), Some(Duration::from_secs(3600)));
+        assert_eq!(store.access_remaining("contractor", hour(18)), Some(Duration::ZERO));
+        assert_eq!(store.access_remaining("contractor", hour(24 + 10)), Some(Duration::ZERO));
+        assert_eq!(store.access_remaining("staff", hour(18)), None);
+    }
+
+    #[test]
+    fn test_password_expiry_with_grace() {
+        let alice: crate::config::UserConfig = toml::from_str(
+            "username = \"alice\"\npassword = \"secret\"\nenabled = true\npassword_expires = \"2024-01-08T00:00:00Z\""
+        ).unwrap();
+        let mut store = UserStore::new();
+        store.load_from_config(&[alice, crate::config::UserConfig::new("bob", "secret")]);
+
+        let expires = UNIX_EPOCH + Duration::from_secs(1_704_672_000);
+        let day = Duration::from_secs(24 * 3600);
+
+        assert_eq!(store.password_expiry("alice", expires - day, 3 * day), PasswordExpiry::Valid);
+        assert_eq!(
+            store.password_expiry("alice", expires + day, 3 * day),
+            PasswordExpiry::InGrace { remaining: 2 * day }
+        );
+        assert_eq!(store.password_expiry("alice", expires + 3 * day, 3 * day), PasswordExpiry::Expired);
+        assert_eq!(store.password_expiry("alice", expires, Duration::ZERO), PasswordExpiry::Expired);
+        assert_eq!(store.password_expiry("bob", expires + day, Duration::ZERO), PasswordExpiry::Valid);
+    }
+}
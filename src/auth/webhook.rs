@@ -0,0 +1,87 @@
+//! Webhook Credential Provider
+//!
+//! Hands username/password logins to an HTTP endpoint, for directories and
+//! identity systems the proxy has no native client for. The endpoint gets a
+//! JSON POST of `{"username", "password", "client_ip"}` and answers with
+//! `{"allow": true|false}`, optionally adding `user_id`, `roles`, and
+//! `quota_bytes` for the session.
+
+use crate::config::AuthWebhookConfig;
+use crate::Result;
+use anyhow::{anyhow, Context};
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+
+#[derive(Serialize)]
+struct WebhookRequest<'a> {
+    username: &'a str,
+    password: &'a str,
+    client_ip: IpAddr,
+}
+
+#[derive(Debug, Deserialize)]
+struct WebhookResponse {
+    allow: bool,
+    #[serde(default)]
+    user_id: Option<String>,
+    #[serde(default)]
+    roles: Vec<String>,
+    #[serde(default)]
+    quota_bytes: Option<u64>,
+}
+
+/// Identity returned by the webhook for an accepted login
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WebhookIdentity {
+    pub user_id: String,
+    pub roles: Vec<String>,
+    pub quota_bytes: Option<u64>,
+}
+
+/// Checks logins against an HTTP endpoint
+pub struct WebhookAuthenticator {
+    client: reqwest::Client,
+    config: AuthWebhookConfig,
+}
+
+impl WebhookAuthenticator {
+    /// Create an authenticator from configuration
+    pub fn from_config(config: &AuthWebhookConfig) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(config.timeout)
+            .build()
+            .context("Failed to build auth webhook HTTP client")?;
+
+        Ok(Self {
+            client,
+            config: config.clone(),
+        })
+    }
+
+    /// Ask the endpoint about a login, returning `None` if it refuses it
+    ///
+    /// Errors mean the endpoint could not give an answer, which callers
+    /// should treat as a backend failure rather than a refused login.
+    pub async fn check(&self, username: &str, password: &str, client_ip: IpAddr) -> Result<Option<WebhookIdentity>> {
+        let mut request = self.client
+            .post(&self.config.url)
+            .json(&WebhookRequest { username, password, client_ip });
+        if let Some(token) = &self.config.bearer_token {
+            request = request.bearer_auth(token);
+        }
+
+        let response = request.send().await
+            .with_context(|| format!("Auth webhook request to {} failed", self.config.url))?;
+        if !response.status().is_success() {
+            return Err(anyhow!("Auth webhook returned {}", response.status()));
+        }
+        let body: WebhookResponse = response.json().await
+            .context("Invalid auth webhook response")?;
+
+        Ok(body.allow.then(|| WebhookIdentity {
+            user_id: body.user_id.unwrap_or_else(|| username.to_string()),
+            roles: body.roles,
+            quota_bytes: body.quota_bytes,
+        }))
+    }
+}
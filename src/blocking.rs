@@ -0,0 +1,223 @@
+//! Blocking Work Pool
+//!
+//! Password checks, GeoIP lookups that touch cold database pages and state
+//! file writes can each block a thread for milliseconds. Done on the async
+//! worker threads they stall every connection scheduled there, so they are
+//! handed to a process-wide pool instead. Tasks run on tokio's blocking
+//! threads, at most `threads` at a time, and at most `queue_capacity` more
+//! may wait for a turn; beyond that new work is refused, so an
+//! authentication burst fails fast rather than piling up behind itself.
+//! Queue depth, running tasks and time spent waiting are exported as
+//! Prometheus metrics.
+
+use anyhow::{anyhow, bail};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::Instant;
+use tokio::sync::Semaphore;
+
+use crate::Result;
+
+/// Blocking pool configuration
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct BlockingPoolConfig {
+    /// Blocking tasks allowed to run at the same time
+    #[serde(default = "default_threads")]
+    pub threads: usize,
+    /// Tasks allowed to wait for a free thread before new work is refused
+    #[serde(default = "default_queue_capacity")]
+    pub queue_capacity: usize,
+}
+
+fn default_threads() -> usize {
+    8
+}
+
+fn default_queue_capacity() -> usize {
+    1024
+}
+
+impl Default for BlockingPoolConfig {
+    fn default() -> Self {
+        Self {
+            threads: default_threads(),
+            queue_capacity: default_queue_capacity(),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct PoolCounters {
+    queued: AtomicUsize,
+    running: AtomicUsize,
+    completed: AtomicU64,
+    rejected: AtomicU64,
+    wait_micros: AtomicU64,
+}
+
+/// Snapshot of a [`BlockingPool`]
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct BlockingPoolStats {
+    pub threads: usize,
+    /// Tasks waiting for a free thread
+    pub queued: usize,
+    pub running: usize,
+    pub completed: u64,
+    /// Tasks refused because the queue was full
+    pub rejected: u64,
+    /// Total time tasks spent waiting for a thread
+    pub wait_seconds: f64,
+}
+
+/// Runs blocking closures off the async worker threads
+#[derive(Debug)]
+pub struct BlockingPool {
+    threads: usize,
+    queue_capacity: usize,
+    permits: Arc<Semaphore>,
+    counters: Arc<PoolCounters>,
+}
+
+/// Leaves the queue when dropped, including when the caller gives up waiting
+struct QueueSlot<'a>(&'a AtomicUsize);
+
+impl Drop for QueueSlot<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Counts a task as running until it finishes or panics
+struct RunningTask(Arc<PoolCounters>);
+
+impl Drop for RunningTask {
+    fn drop(&mut self) {
+        self.0.running.fetch_sub(1, Ordering::Relaxed);
+        self.0.completed.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl BlockingPool {
+    pub fn new(config: &BlockingPoolConfig) -> Self {
+        let threads = config.threads.max(1);
+        Self {
+            threads,
+            queue_capacity: config.queue_capacity,
+            permits: Arc::new(Semaphore::new(threads)),
+            counters: Arc::new(PoolCounters::default()),
+        }
+    }
+
+    /// Run `task` on a blocking thread and wait for its result
+    ///
+    /// Fails when the queue is full or the task panics. The thread is only
+    /// given back once the task finishes, even if the caller stops waiting.
+    pub async fn run<F, T>(&self, task: F) -> Result<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let started = Instant::now();
+        let permit = match Arc::clone(&self.permits).try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                if self.counters.queued.fetch_add(1, Ordering::Relaxed) >= self.queue_capacity {
+                    self.counters.queued.fetch_sub(1, Ordering::Relaxed);
+                    self.counters.rejected.fetch_add(1, Ordering::Relaxed);
+                    bail!("Blocking pool queue is full ({} tasks waiting)", self.queue_capacity);
+                }
+                let _slot = QueueSlot(&self.counters.queued);
+                Arc::clone(&self.permits).acquire_owned().await
+                    .map_err(|_| anyhow!("Blocking pool is closed"))?
+            }
+        };
+        self.counters.wait_micros.fetch_add(started.elapsed().as_micros() as u64, Ordering::Relaxed);
+
+        self.counters.running.fetch_add(1, Ordering::Relaxed);
+        let running = RunningTask(Arc::clone(&self.counters));
+        tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            let _running = running;
+            task()
+        })
+        .await
+        .map_err(|e| anyhow!("Blocking task failed: {}", e))
+    }
+
+    pub fn stats(&self) -> BlockingPoolStats {
+        BlockingPoolStats {
+            threads: self.threads,
+            queued: self.counters.queued.load(Ordering::Relaxed),
+            running: self.counters.running.load(Ordering::Relaxed),
+            completed: self.counters.completed.load(Ordering::Relaxed),
+            rejected: self.counters.rejected.load(Ordering::Relaxed),
+            wait_seconds: self.counters.wait_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0,
+        }
+    }
+}
+
+static POOL: OnceLock<BlockingPool> = OnceLock::new();
+
+/// Size the process-wide pool; returns false if it was already in use
+pub fn configure(config: &BlockingPoolConfig) -> bool {
+    POOL.set(BlockingPool::new(config)).is_ok()
+}
+
+/// Process-wide pool, with the default size unless [`configure`]d first
+pub fn pool() -> &'static BlockingPool {
+    POOL.get_or_init(|| BlockingPool::new(&BlockingPoolConfig::default()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    async fn wait_for(pool: &BlockingPool, check: impl Fn(&BlockingPoolStats) -> bool) {
+        for _ in 0..200 {
+            if check(&pool.stats()) {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        panic!("pool never reached expected state: {:?}", pool.stats());
+    }
+
+    #[tokio::test]
+    async fn test_bounds_running_and_queued_tasks() {
+        let pool = Arc::new(BlockingPool::new(&BlockingPoolConfig { threads: 1, queue_capacity: 1 }));
+        let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+
+        let first = tokio::spawn({
+            let pool = Arc::clone(&pool);
+            async move { pool.run(move || release_rx.recv().is_ok()).await }
+        });
+        wait_for(&pool, |stats| stats.running == 1).await;
+
+        let second = tokio::spawn({
+            let pool = Arc::clone(&pool);
+            async move { pool.run(|| 2).await }
+        });
+        wait_for(&pool, |stats| stats.queued == 1).await;
+
+        // One running and one waiting: the queue is full
+        assert!(pool.run(|| 3).await.is_err());
+
+        release_tx.send(()).unwrap();
+        assert!(first.await.unwrap().unwrap());
+        assert_eq!(second.await.unwrap().unwrap(), 2);
+
+        let stats = pool.stats();
+        assert_eq!((stats.queued, stats.running, stats.completed, stats.rejected), (0, 0, 2, 1));
+        assert!(stats.wait_seconds > 0.0);
+    }
+
+    #[tokio::test]
+    async fn test_panicking_task_frees_its_thread() {
+        let pool = BlockingPool::new(&BlockingPoolConfig { threads: 1, queue_capacity: 0 });
+        assert!(pool.run(|| panic!("boom")).await.is_err());
+        assert_eq!(pool.run(|| 7).await.unwrap(), 7);
+        assert_eq!(pool.stats().running, 0);
+    }
+}
@@ -0,0 +1,166 @@
+//! Command-line Output
+//!
+//! Output formats, exit codes, and report types shared by the one-shot CLI
+//! operations (`--validate-config`, `preflight`, `status`) so CI jobs and
+//! config-management tools can gate on them without scraping log lines.
+
+use crate::config::Config;
+use clap::ValueEnum;
+use serde::Serialize;
+use std::net::SocketAddr;
+use std::path::Path;
+
+/// Process exit codes for CLI operations
+///
+/// These values are part of the CLI contract and must not be renumbered.
+pub mod exit_code {
+    /// Operation completed and everything checked out
+    pub const SUCCESS: i32 = 0;
+    /// Operation ran but one or more checks failed
+    pub const CHECKS_FAILED: i32 = 1;
+    /// Invalid command-line arguments (reported by clap)
+    pub const USAGE: i32 = 2;
+    /// The configuration could not be loaded or failed validation
+    pub const INVALID_CONFIG: i32 = 3;
+    /// A running proxy could not be reached or queried
+    pub const UNAVAILABLE: i32 = 4;
+}
+
+/// Output format for CLI operations
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// Human-readable text
+    #[default]
+    Text,
+    /// A single JSON document on stdout
+    Json,
+}
+
+impl OutputFormat {
+    pub fn is_json(self) -> bool {
+        self == OutputFormat::Json
+    }
+}
+
+/// Result of `--validate-config`
+#[derive(Debug, Clone, Serialize)]
+pub struct ValidationReport {
+    pub valid: bool,
+    pub config_path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub summary: Option<ConfigSummary>,
+}
+
+impl ValidationReport {
+    /// Report for a configuration that loaded and validated
+    pub fn valid(path: &Path, config: &Config) -> Self {
+        Self {
+            valid: true,
+            config_path: path.display().to_string(),
+            error: None,
+            summary: Some(ConfigSummary::from(config)),
+        }
+    }
+
+    /// Report for a configuration that failed to load or validate
+    pub fn invalid(path: &Path, error: &anyhow::Error) -> Self {
+        Self {
+            valid: false,
+            config_path: path.display().to_string(),
+            error: Some(format!("{:#}", error)),
+            summary: None,
+        }
+    }
+}
+
+/// Key settings of a validated configuration
+#[derive(Debug, Clone, Serialize)]
+pub struct ConfigSummary {
+    pub bind_addr: SocketAddr,
+    pub max_connections: usize,
+    pub connection_timeout_secs: u64,
+    pub buffer_size: usize,
+    pub auth_enabled: bool,
+    pub users: usize,
+    pub access_control_enabled: bool,
+    pub access_rules: usize,
+    pub routing_enabled: bool,
+    pub routing_rules: usize,
+    pub upstream_proxies: usize,
+    pub monitoring_enabled: bool,
+    pub management_api_enabled: bool,
+}
+
+impl From<&Config> for ConfigSummary {
+    fn from(config: &Config) -> Self {
+        Self {
+            bind_addr: config.server.bind_addr,
+            max_connections: config.server.max_connections,
+            connection_timeout_secs: config.server.connection_timeout.as_secs(),
+            buffer_size: config.server.buffer_size,
+            auth_enabled: config.auth.enabled,
+            users: config.auth.users.len(),
+            access_control_enabled: config.access_control.enabled,
+            access_rules: config.access_control.rules.len(),
+            routing_enabled: config.routing.enabled,
+            routing_rules: config.routing.rules.len(),
+            upstream_proxies: config.routing.upstream_proxies.len(),
+            monitoring_enabled: config.monitoring.enabled,
+            management_api_enabled: config.monitoring.management_api.enabled,
+        }
+    }
+}
+
+/// Error document printed in JSON mode when an operation cannot run
+#[derive(Debug, Clone, Serialize)]
+pub struct ErrorReport {
+    pub error: String,
+    pub exit_code: i32,
+}
+
+impl ErrorReport {
+    pub fn new(error: &anyhow::Error, exit_code: i32) -> Self {
+        Self {
+            error: format!("{:#}", error),
+            exit_code,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn test_valid_report_includes_summary() {
+        let mut config = Config::default();
+        config.auth.enabled = true;
+        config.auth.users.push(crate::config::UserConfig::new("alice", "secret"));
+
+        let report = ValidationReport::valid(Path::new("config.toml"), &config);
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["valid"], true);
+        assert_eq!(json["summary"]["users"], 1);
+        assert_eq!(json["summary"]["auth_enabled"], true);
+        assert!(json.get("error").is_none());
+    }
+
+    #[test]
+    fn test_invalid_report_keeps_error_chain() {
+        let error = Err::<(), _>(anyhow::anyhow!("max_connections must be greater than 0"))
+            .context("Server configuration validation failed")
+            .unwrap_err();
+
+        let report = ValidationReport::invalid(Path::new("bad.toml"), &error);
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["valid"], false);
+        assert_eq!(
+            json["error"],
+            "Server configuration validation failed: max_connections must be greater than 0"
+        );
+        assert!(json.get("summary").is_none());
+    }
+}
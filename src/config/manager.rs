@@ -1,6 +1,6 @@
 //! Configuration Manager
 
-use super::Config;
+use super::{Config, RoutingActionConfig};
 use crate::Result;
 use anyhow::{Context, bail};
 use std::path::Path;
@@ -33,6 +33,22 @@ impl ConfigManager {
         }
     }
 
+    /// Load configuration by rendering a template with a variables file
+    pub fn load_from_template(template_path: &Path, vars_path: &Path) -> Result<Config> {
+        tracing::info!("Rendering configuration template {} with variables from {}",
+                       template_path.display(), vars_path.display());
+        let content = super::template::render_file(template_path, vars_path)?;
+        
+        let config: Config = toml::from_str(&content)
+            .with_context(|| format!("Failed to parse rendered config template: {}", template_path.display()))?;
+        
+        config.validate()
+            .with_context(|| "Configuration validation failed")?;
+        
+        tracing::info!("Configuration template rendered and validated successfully");
+        Ok(config)
+    }
+
     /// Load configuration from environment variables
     pub fn load_from_env() -> Result<Config> {
         let mut config = Config::default();
@@ -95,6 +111,10 @@ impl Config {
         self.validate_monitoring_config()
             .with_context(|| "Monitoring configuration validation failed")?;
         
+        // Validate security configuration
+        self.validate_security_config()
+            .with_context(|| "Security configuration validation failed")?;
+        
         Ok(())
     }
     
@@ -116,6 +136,39 @@ impl Config {
             bail!("connection_timeout cannot exceed 1 hour");
         }
         
+        if self.server.idle_timeout.is_zero() {
+            bail!("idle_timeout must be greater than 0");
+        }
+        
+        if let Some(tunnel) = &self.server.tunnel {
+            if tunnel.token.is_empty() {
+                bail!("server.tunnel.token must not be empty");
+            }
+            if tunnel.bind_addr == self.server.bind_addr {
+                bail!("server.tunnel.bind_addr must differ from server.bind_addr");
+            }
+            validate_tls_files("server.tunnel", &tunnel.tls_cert, &tunnel.tls_key, &tunnel.tls_sni_certs)?;
+        }
+        
+        if self.server.max_connection_lifetime.is_some_and(|lifetime| lifetime.is_zero()) {
+            bail!("max_connection_lifetime must be greater than 0");
+        }
+        
+        if self.server.enable_keepalive {
+            if self.server.keepalive_interval.as_secs() == 0 {
+                bail!("keepalive_interval must be at least 1 second");
+            }
+            if self.server.keepalive_probes == 0 {
+                bail!("keepalive_probes must be greater than 0");
+            }
+        }
+        
+        for (name, options) in [("listener_socket", &self.server.listener_socket), ("egress_socket", &self.server.egress_socket)] {
+            if options.send_buffer_size == Some(0) || options.recv_buffer_size == Some(0) {
+                bail!("server.{} buffer sizes must be greater than 0", name);
+            }
+        }
+        
         if self.server.buffer_size < 1024 {
             bail!("buffer_size must be at least 1024 bytes");
         }
@@ -124,6 +177,22 @@ impl Config {
             bail!("buffer_size cannot exceed 1MB");
         }
         
+        if self.server.initial_buffer_size < 512 || self.server.initial_buffer_size > self.server.buffer_size {
+            bail!("initial_buffer_size must be between 512 bytes and buffer_size");
+        }
+        
+        if self.server.buffer_shrink_after.is_zero() {
+            bail!("buffer_shrink_after must be greater than 0");
+        }
+        
+        if self.server.connect_deadline.is_some_and(|deadline| deadline.is_zero()) {
+            bail!("connect_deadline must be greater than 0");
+        }
+        
+        if !(1..=512).contains(&self.server.blocking_pool.threads) {
+            bail!("blocking_pool.threads must be between 1 and 512");
+        }
+        
         Ok(())
     }
     
@@ -133,8 +202,81 @@ impl Config {
             bail!("auth.method must be 'none' or 'userpass'");
         }
         
-        if self.auth.enabled && self.auth.method == "userpass" && self.auth.users.is_empty() {
-            bail!("When userpass authentication is enabled, at least one user must be configured");
+        if self.auth.enabled && self.auth.method == "userpass" && self.auth.users.is_empty()
+            && self.auth.jwt.is_none() && self.auth.introspection.is_none() && self.auth.webhook.is_none() {
+            bail!("When userpass authentication is enabled, at least one user, JWT validation, token introspection, or an auth webhook must be configured");
+        }
+        
+        if self.auth.providers.is_empty() {
+            bail!("auth.providers must list at least one provider");
+        }
+        
+        if let Some(webhook) = &self.auth.webhook {
+            if !webhook.url.starts_with("https://") && !webhook.url.starts_with("http://") {
+                bail!("auth.webhook.url must be an http:// or https:// URL");
+            }
+        }
+        
+        for user in &self.auth.users {
+            for cidr in &user.allowed_source_cidrs {
+                super::parse_source_cidr(cidr)
+                    .with_context(|| format!("Invalid allowed_source_cidrs for user '{}'", user.username))?;
+            }
+            if let Some(secret) = &user.totp_secret {
+                crate::auth::totp::decode_base32(secret)
+                    .with_context(|| format!("Invalid totp_secret for user '{}'", user.username))?;
+            }
+            if let Some(country) = &user.egress_country {
+                if country.len() != 2 || !country.chars().all(|c| c.is_ascii_alphabetic()) {
+                    bail!("User '{}' egress_country must be a two-letter country code", user.username);
+                }
+            }
+        }
+        
+        if self.auth.session_max_lifetime.is_some_and(|lifetime| lifetime.is_zero()) {
+            bail!("auth.session_max_lifetime must be greater than 0");
+        }
+        
+        if let Some(ttl) = self.auth.cache_ttl {
+            if ttl.is_zero() || self.auth.cache_max_entries == 0 {
+                bail!("auth.cache_ttl and auth.cache_max_entries must be greater than 0 when caching is enabled");
+            }
+        }
+        
+        if self.auth.anonymous_identity == super::AnonymousIdentity::HashedSourceIp
+            && self.auth.anonymous_identity_salt.as_deref().filter(|salt| !salt.is_empty()).is_none()
+        {
+            bail!("auth.anonymous_identity_salt is required for hashed_source_ip identities");
+        }
+        
+        if self.auth.audit_log.as_ref().is_some_and(|audit| audit.max_bytes == 0) {
+            bail!("auth.audit_log.max_bytes must be greater than 0");
+        }
+
+        if let Some(jwt) = &self.auth.jwt {
+            if jwt.secret.is_none() && jwt.jwks_path.is_none() {
+                bail!("auth.jwt requires either a secret or a jwks_path");
+            }
+            
+            if let Some(jwks_path) = &jwt.jwks_path {
+                if !jwks_path.exists() {
+                    bail!("auth.jwt.jwks_path does not exist: {}", jwks_path.display());
+                }
+            }
+        }
+        
+        if let Some(introspection) = &self.auth.introspection {
+            if !introspection.endpoint.starts_with("https://") && !introspection.endpoint.starts_with("http://") {
+                bail!("auth.introspection.endpoint must be an http:// or https:// URL");
+            }
+            
+            if introspection.client_id.is_some() != introspection.client_secret.is_some() {
+                bail!("auth.introspection requires both client_id and client_secret, or neither");
+            }
+            
+            if introspection.token_username.is_empty() {
+                bail!("auth.introspection.token_username cannot be empty");
+            }
         }
         
         // Validate user configurations
@@ -147,15 +289,38 @@ impl Config {
                 bail!("User {} username exceeds 255 characters", i);
             }
             
-            if user.password.is_empty() {
-                bail!("User {} has empty password", i);
+            match &user.password_hash {
+                Some(hash) => {
+                    if !user.password.is_empty() {
+                        bail!("User {} has both password and password_hash; keep only one", i);
+                    }
+                    let parsed = argon2::password_hash::PasswordHash::new(hash)
+                        .map_err(|e| anyhow::anyhow!("User {} password_hash is not a valid PHC string: {}", i, e))?;
+                    if !parsed.algorithm.as_str().starts_with("argon2") {
+                        bail!("User {} password_hash must be an Argon2 hash, not {}", i, parsed.algorithm);
+                    }
+                }
+                None => {
+                    if user.password.is_empty() {
+                        bail!("User {} has empty password", i);
+                    }
+
+                    if user.password.len() > 255 {
+                        bail!("User {} password exceeds 255 characters", i);
+                    }
+                }
             }
             
-            if user.password.len() > 255 {
-                bail!("User {} password exceeds 255 characters", i);
+            if user.total_bandwidth.is_some_and(|limit| limit.upload_bytes_per_second == Some(0) || limit.download_bytes_per_second == Some(0)) {
+                bail!("User '{}' total_bandwidth rates must be greater than 0", user.username);
             }
         }
         
+        let total = self.auth.default_total_bandwidth;
+        if total.upload_bytes_per_second == Some(0) || total.download_bytes_per_second == Some(0) {
+            bail!("auth.default_total_bandwidth rates must be greater than 0");
+        }
+        
         Ok(())
     }
     
@@ -175,12 +340,8 @@ impl Config {
                 bail!("Access rule {} action must be 'allow', 'block', or 'redirect'", i);
             }
             
-            if let Some(ports) = &rule.ports {
-                for &port in ports {
-                    if port == 0 {
-                        bail!("Access rule {} contains invalid port 0", i);
-                    }
-                }
+            if rule.ports.iter().flatten().any(|range| range.start == 0) {
+                bail!("Access rule {} contains invalid port 0", i);
             }
         }
         
@@ -189,14 +350,45 @@ impl Config {
     
     /// Validate routing configuration
     fn validate_routing_config(&self) -> Result<()> {
+        let dns_cache = &self.routing.dns_cache;
+        if dns_cache.min_ttl > dns_cache.max_ttl {
+            bail!("routing.dns_cache.min_ttl cannot exceed max_ttl");
+        }
+        for server in &self.routing.secure_dns.servers {
+            crate::routing::DnsServer::parse(server)
+                .with_context(|| format!("Invalid routing.secure_dns server '{}'", server))?;
+        }
+        if !self.routing.secure_dns.servers.is_empty() && self.routing.secure_dns.timeout.is_zero() {
+            bail!("routing.secure_dns.timeout must be greater than 0");
+        }
+        for blocklist in &self.routing.blocklists {
+            if !blocklist.path.exists() {
+                bail!("routing.blocklists path does not exist: {}", blocklist.path.display());
+            }
+        }
+        
         // Validate upstream proxy configurations
         for (i, proxy) in self.routing.upstream_proxies.iter().enumerate() {
             if proxy.name.is_empty() {
                 bail!("Upstream proxy {} has empty name", i);
             }
             
-            if !["socks5", "http", "https"].contains(&proxy.protocol.as_str()) {
-                bail!("Upstream proxy {} protocol must be 'socks5', 'http', or 'https'", i);
+            if !["socks5", "http", "https", "tunnel"].contains(&proxy.protocol.as_str()) {
+                bail!("Upstream proxy {} protocol must be 'socks5', 'http', 'https', or 'tunnel'", i);
+            }
+            
+            match (&proxy.tunnel, proxy.protocol.as_str()) {
+                (Some(tunnel), "tunnel") => {
+                    if tunnel.token.is_empty() {
+                        bail!("Upstream proxy '{}' tunnel token must not be empty", proxy.name);
+                    }
+                    if let Some(ca_cert) = tunnel.ca_cert.as_ref().filter(|path| !path.exists()) {
+                        bail!("Upstream proxy '{}' tunnel ca_cert does not exist: {}", proxy.name, ca_cert.display());
+                    }
+                }
+                (None, "tunnel") => bail!("Upstream proxy '{}' uses protocol 'tunnel' without tunnel settings", proxy.name),
+                (Some(_), _) => bail!("Upstream proxy '{}' has tunnel settings but protocol '{}'", proxy.name, proxy.protocol),
+                (None, _) => {}
             }
             
             if let Some(auth) = &proxy.auth {
@@ -208,6 +400,105 @@ impl Config {
                     bail!("Upstream proxy {} has empty auth password", i);
                 }
             }
+            
+            if proxy.weight == 0 {
+                bail!("Upstream proxy '{}' weight must be greater than 0", proxy.name);
+            }
+            if proxy.monthly_cap_bytes == Some(0) {
+                bail!("Upstream proxy '{}' monthly_cap_bytes must be greater than 0", proxy.name);
+            }
+            if proxy.cost_per_gb.is_some_and(|cost| !cost.is_finite() || cost < 0.0) {
+                bail!("Upstream proxy '{}' cost_per_gb must be a non-negative number", proxy.name);
+            }
+            if let Some(budget) = proxy.monthly_budget {
+                if proxy.cost_per_gb.is_none() {
+                    bail!("Upstream proxy '{}' sets monthly_budget without cost_per_gb", proxy.name);
+                }
+                if !budget.is_finite() || budget <= 0.0 {
+                    bail!("Upstream proxy '{}' monthly_budget must be greater than 0", proxy.name);
+                }
+            }
+        }
+        
+        let warn_ratio = self.routing.upstream_usage.warn_ratio;
+        if !(warn_ratio > 0.0 && warn_ratio <= 1.0) {
+            bail!("routing.upstream_usage.warn_ratio must be greater than 0 and at most 1");
+        }
+        
+        // Validate DSCP marking
+        for (name, dscp) in &self.routing.dscp_classes {
+            if *dscp > crate::routing::MAX_DSCP {
+                bail!("routing.dscp_classes.{} must be between 0 and {}", name, crate::routing::MAX_DSCP);
+            }
+        }
+        
+        for (name, limit) in &self.routing.bandwidth_classes {
+            if limit.is_unlimited() {
+                bail!("routing.bandwidth_classes.{} must cap upload or download", name);
+            }
+            if limit.upload_bytes_per_second == Some(0) || limit.download_bytes_per_second == Some(0) {
+                bail!("routing.bandwidth_classes.{} rates must be greater than 0", name);
+            }
+        }
+        
+        for (name, calendar) in &self.routing.calendars {
+            calendar.validate().with_context(|| format!("Invalid routing.calendars.{}", name))?;
+        }
+        
+        for rule in &self.routing.rules {
+            if let Some(dscp) = rule.dscp {
+                if dscp > crate::routing::MAX_DSCP {
+                    bail!("Routing rule '{}' dscp must be between 0 and {}", rule.id, crate::routing::MAX_DSCP);
+                }
+            }
+            
+            if let Some(class) = &rule.dscp_class {
+                if rule.dscp.is_some() {
+                    bail!("Routing rule '{}' cannot set both dscp and dscp_class", rule.id);
+                }
+                if !self.routing.dscp_classes.contains_key(class) {
+                    bail!("Routing rule '{}' refers to unknown dscp_class '{}'", rule.id, class);
+                }
+            }
+            
+            if let RoutingActionConfig::Throttle { class } = &rule.action {
+                if !self.routing.bandwidth_classes.contains_key(class) {
+                    bail!("Routing rule '{}' refers to unknown bandwidth class '{}'", rule.id, class);
+                }
+            }
+            
+            for country in rule.countries.iter().flatten() {
+                if country.len() != 2 || !country.chars().all(|c| c.is_ascii_alphabetic()) {
+                    bail!("Routing rule '{}' countries must be two-letter country codes", rule.id);
+                }
+            }
+            
+            if let Some(calendar) = rule.time_restrictions.as_ref().and_then(|r| r.calendar.as_ref()) {
+                if !self.routing.calendars.contains_key(calendar) {
+                    bail!("Routing rule '{}' refers to unknown calendar '{}'", rule.id, calendar);
+                }
+            }
+        }
+        
+        let sticky = &self.routing.sticky_sessions;
+        if sticky.enabled && sticky.ttl.is_zero() {
+            bail!("routing.sticky_sessions.ttl must be greater than 0");
+        }
+
+        let qos = &self.routing.qos;
+        if qos.enabled && !(qos.min_share > 0.0 && qos.min_share <= 1.0) {
+            bail!("routing.qos.min_share must be greater than 0 and at most 1");
+        }
+        if qos.enabled && (qos.capacity.upload_bytes_per_second == Some(0) || qos.capacity.download_bytes_per_second == Some(0)) {
+            bail!("routing.qos capacity rates must be greater than 0");
+        }
+        
+        let pool = &self.routing.upstream_pool;
+        if pool.enabled && !(1..=64).contains(&pool.max_pool_size) {
+            bail!("routing.upstream_pool.max_pool_size must be between 1 and 64");
+        }
+        if pool.enabled && pool.idle_timeout.is_zero() {
+            bail!("routing.upstream_pool.idle_timeout must be greater than 0");
         }
         
         Ok(())
@@ -220,6 +511,192 @@ impl Config {
             bail!("monitoring.log_level must be one of: {}", valid_log_levels.join(", "));
         }
         
+        let retention = &self.monitoring.retention;
+        if retention.enabled {
+            if retention.anonymize_after.is_none() && retention.delete_after.is_none() {
+                bail!("monitoring.retention requires anonymize_after or delete_after when enabled");
+            }
+            if retention.salt_rotation.is_zero() || retention.check_interval.is_zero() {
+                bail!("monitoring.retention salt_rotation and check_interval must be greater than 0");
+            }
+            if let (Some(anonymize), Some(delete)) = (retention.anonymize_after, retention.delete_after) {
+                if anonymize >= delete {
+                    bail!("monitoring.retention.anonymize_after must be shorter than delete_after");
+                }
+            }
+        }
+        
+        if let Some(webhooks) = &self.monitoring.webhooks {
+            if !webhooks.url.starts_with("http://") && !webhooks.url.starts_with("https://") {
+                bail!("monitoring.webhooks.url must be an http:// or https:// URL");
+            }
+            if webhooks.batch_size == 0 || webhooks.queue_capacity == 0 || webhooks.flush_interval.is_zero() {
+                bail!("monitoring.webhooks batch_size, queue_capacity, and flush_interval must be greater than 0");
+            }
+        }
+
+        if let Some(update_check) = &self.monitoring.update_check {
+            if !update_check.manifest_url.starts_with("http://") && !update_check.manifest_url.starts_with("https://") {
+                bail!("monitoring.update_check.manifest_url must be an http:// or https:// URL");
+            }
+            if update_check.interval.is_zero() || update_check.timeout.is_zero() {
+                bail!("monitoring.update_check interval and timeout must be greater than 0");
+            }
+        }
+
+        let crash_reports = &self.monitoring.crash_reports;
+        if crash_reports.enabled && crash_reports.dir.as_os_str().is_empty() {
+            bail!("monitoring.crash_reports.dir must not be empty when crash reports are enabled");
+        }
+        
+        Ok(())
+    }
+    
+    /// Validate security configuration
+    fn validate_security_config(&self) -> Result<()> {
+        let quotas = &self.security.quotas;
+        if quotas.on_exceeded == crate::security::quota::QuotaAction::Throttle
+            && quotas.throttle_bytes_per_second == 0
+        {
+            bail!("security.quotas.throttle_bytes_per_second must be greater than 0 when on_exceeded = \"throttle\"");
+        }
+        
+        let udp_relay = &self.security.udp_relay;
+        if udp_relay.sequence_validation
+            && !(1..=crate::security::udp_guard::MAX_REPLAY_WINDOW).contains(&udp_relay.replay_window)
+        {
+            bail!("security.udp_relay.replay_window must be between 1 and {}", crate::security::udp_guard::MAX_REPLAY_WINDOW);
+        }
+        
+        let whitelists = [
+            ("security.fail2ban.whitelist_ips", &self.security.fail2ban.whitelist_ips),
+            ("security.ddos_protection.whitelist_ips", &self.security.ddos_protection.whitelist_ips),
+        ];
+        for (setting, entries) in whitelists {
+            for entry in entries {
+                super::parse_source_cidr(entry)
+                    .with_context(|| format!("Invalid {} entry '{}'", setting, entry))?;
+            }
+        }
+        
+        if let Some(per_user) = &self.security.rate_limiting.per_user {
+            if per_user.connections_burst == 0 || per_user.auth_attempts_burst == 0 {
+                bail!("security.rate_limiting.per_user connections_burst and auth_attempts_burst must be greater than 0");
+            }
+        }
+        
+        if let Some(udp) = &self.security.rate_limiting.udp {
+            let ceilings = [
+                udp.association_packets_per_second,
+                udp.association_bytes_per_second,
+                udp.ip_packets_per_second,
+                udp.ip_bytes_per_second,
+            ];
+            if ceilings.contains(&Some(0)) {
+                bail!("security.rate_limiting.udp ceilings must be greater than 0");
+            }
+        }
+        
+        if let Some(siem) = &self.security.siem {
+            if siem.batch_size == 0 || siem.queue_capacity == 0 {
+                bail!("security.siem.batch_size and queue_capacity must be greater than 0");
+            }
+            match &siem.target {
+                crate::security::siem::SiemTarget::Syslog { address, .. } if address.rsplit_once(':').is_none() => {
+                    bail!("security.siem.address must be host:port");
+                }
+                crate::security::siem::SiemTarget::Http { url, .. }
+                    if !url.starts_with("http://") && !url.starts_with("https://") =>
+                {
+                    bail!("security.siem.url must be an http:// or https:// URL");
+                }
+                _ => {}
+            }
+        }
+        
+        let reputation = &self.security.reputation;
+        if reputation.enabled {
+            if reputation.refresh_interval.is_zero() {
+                bail!("security.reputation.refresh_interval must be greater than 0");
+            }
+            let mut names = std::collections::HashSet::new();
+            for (index, feed) in reputation.feeds.iter().enumerate() {
+                let (name, _) = feed.source()
+                    .with_context(|| format!("Invalid security.reputation.feeds[{}]", index))?;
+                if !names.insert(name.clone()) {
+                    bail!("security.reputation.feeds has more than one feed named '{}'", name);
+                }
+            }
+            for cidr in &reputation.exempt_cidrs {
+                super::parse_source_cidr(cidr)
+                    .with_context(|| format!("Invalid security.reputation.exempt_cidrs entry '{}'", cidr))?;
+            }
+        }
+        
+        let client_geo = &self.security.client_geo;
+        if client_geo.enabled {
+            if client_geo.database.is_none() {
+                bail!("security.client_geo.database is required when the client country policy is enabled");
+            }
+            for country in client_geo.allow_countries.iter().chain(&client_geo.deny_countries) {
+                if country.len() != 2 || !country.chars().all(|c| c.is_ascii_alphabetic()) {
+                    bail!("security.client_geo country '{}' must be a two-letter country code", country);
+                }
+            }
+            for cidr in &client_geo.exempt_cidrs {
+                super::parse_source_cidr(cidr)
+                    .with_context(|| format!("Invalid security.client_geo.exempt_cidrs entry '{}'", cidr))?;
+            }
+        }
+        
+        let honeypot = &self.security.honeypot;
+        if honeypot.enabled {
+            if honeypot.divert.is_empty() {
+                bail!("security.honeypot.divert must list at least one block reason");
+            }
+            if honeypot.max_sessions == 0 {
+                bail!("security.honeypot.max_sessions must be greater than 0");
+            }
+            if honeypot.session_timeout.is_zero() {
+                bail!("security.honeypot.session_timeout must be greater than 0");
+            }
+        }
+        
+        let exfiltration = &self.security.exfiltration;
+        if exfiltration.enabled {
+            if exfiltration.connection_upload_bytes.is_none() && exfiltration.user_upload_bytes.is_none() {
+                bail!("security.exfiltration needs connection_upload_bytes or user_upload_bytes");
+            }
+            if exfiltration.connection_upload_bytes == Some(0) || exfiltration.user_upload_bytes == Some(0) {
+                bail!("security.exfiltration upload thresholds must be greater than 0");
+            }
+            if exfiltration.window.is_zero() {
+                bail!("security.exfiltration.window must be greater than 0");
+            }
+            if let Some(webhook) = &exfiltration.webhook {
+                if !webhook.url.starts_with("http://") && !webhook.url.starts_with("https://") {
+                    bail!("security.exfiltration.webhook.url must be an http:// or https:// URL");
+                }
+            }
+        }
+        
+        let rejection = &self.security.rejection;
+        let replies = rejection.default == crate::security::RejectAction::Reply
+            || rejection.by_reason.values().any(|action| *action == crate::security::RejectAction::Reply);
+        if replies && (rejection.max_replies == 0 || rejection.reply_timeout.is_zero()) {
+            bail!("security.rejection max_replies and reply_timeout must be greater than 0 when rejections reply");
+        }
+        
+        let management_api = &self.monitoring.management_api;
+        if management_api.enabled {
+            validate_tls_files(
+                "monitoring.management_api",
+                &management_api.tls_cert,
+                &management_api.tls_key,
+                &management_api.tls_sni_certs,
+            )?;
+        }
+        
         Ok(())
     }
 
@@ -274,3 +751,33 @@ impl Config {
         }
     }
 }
+
+/// Check a TLS listener's certificate settings: cert and key together, SNI
+/// certificates only alongside them, and every file present
+fn validate_tls_files(
+    section: &str,
+    cert: &Option<std::path::PathBuf>,
+    key: &Option<std::path::PathBuf>,
+    sni_certs: &[crate::tls::SniCertConfig],
+) -> Result<()> {
+    match (cert, key) {
+        (Some(cert), Some(key)) => {
+            let mut paths = vec![cert, key];
+            for (index, entry) in sni_certs.iter().enumerate() {
+                if entry.server_names.iter().all(|name| name.trim().is_empty()) {
+                    bail!("{}.tls_sni_certs[{}] needs at least one server name", section, index);
+                }
+                paths.extend([&entry.cert, &entry.key]);
+            }
+            for path in paths {
+                if !path.exists() {
+                    bail!("{} TLS file does not exist: {}", section, path.display());
+                }
+            }
+        }
+        (None, None) if sni_certs.is_empty() => {}
+        (None, None) => bail!("{}.tls_sni_certs needs tls_cert and tls_key", section),
+        _ => bail!("{}.tls_cert and tls_key must be set together", section),
+    }
+    Ok(())
+}
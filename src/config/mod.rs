@@ -3,6 +3,7 @@
 //! Handles configuration loading, validation, and management.
 
 pub mod manager;
+pub mod template;
 pub mod types;
 pub mod watcher;
 
@@ -0,0 +1,132 @@
+//! Configuration Templates
+//!
+//! Renders a config file from a template and a per-host variables file, so a
+//! fleet can share one template and vary only bind addresses, upstream pools,
+//! and the like. Placeholders use shell-style syntax:
+//!
+//! - `${name}` inserts the variable `name` and fails if it is not defined
+//! - `${name:-fallback}` inserts `fallback` when `name` is not defined
+//! - `$${` produces a literal `${`
+//!
+//! The variables file is TOML. Strings are inserted as-is; numbers, booleans,
+//! arrays, and tables are inserted as TOML values, so `ports = ${ports}` works
+//! with `ports = [80, 443]` in the variables file.
+
+use crate::Result;
+use anyhow::{bail, Context};
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Load template variables from a TOML file
+pub fn load_vars(path: &Path) -> Result<HashMap<String, String>> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read template variables: {}", path.display()))?;
+    let table: toml::Table = toml::from_str(&content)
+        .with_context(|| format!("Failed to parse template variables: {}", path.display()))?;
+
+    Ok(table.into_iter()
+        .map(|(name, value)| {
+            let text = match value {
+                toml::Value::String(s) => s,
+                other => other.to_string(),
+            };
+            (name, text)
+        })
+        .collect())
+}
+
+/// Substitute variables into a template
+///
+/// All undefined variables are reported together so a broken variables file
+/// can be fixed in one pass.
+pub fn render(template: &str, vars: &HashMap<String, String>) -> Result<String> {
+    let mut output = String::with_capacity(template.len());
+    let mut missing = Vec::new();
+
+    for (index, line) in template.split_inclusive('\n').enumerate() {
+        let mut rest = line;
+        while let Some(start) = rest.find('$') {
+            output.push_str(&rest[..start]);
+            let after = &rest[start + 1..];
+
+            if let Some(escaped) = after.strip_prefix("${") {
+                output.push_str("${");
+                rest = escaped;
+            } else if let Some(body) = after.strip_prefix('{') {
+                let Some(end) = body.find('}') else {
+                    bail!("Unterminated placeholder on line {}", index + 1);
+                };
+                let (name, fallback) = match body[..end].split_once(":-") {
+                    Some((name, fallback)) => (name.trim(), Some(fallback)),
+                    None => (body[..end].trim(), None),
+                };
+                if name.is_empty() {
+                    bail!("Empty placeholder on line {}", index + 1);
+                }
+                match (vars.get(name), fallback) {
+                    (Some(value), _) => output.push_str(value),
+                    (None, Some(fallback)) => output.push_str(fallback),
+                    (None, None) => missing.push(format!("'{}' (line {})", name, index + 1)),
+                }
+                rest = &body[end + 1..];
+            } else {
+                output.push('$');
+                rest = after;
+            }
+        }
+        output.push_str(rest);
+    }
+
+    if !missing.is_empty() {
+        bail!("Undefined template variables: {}", missing.join(", "));
+    }
+    Ok(output)
+}
+
+/// Render a template file with variables from a TOML file
+pub fn render_file(template_path: &Path, vars_path: &Path) -> Result<String> {
+    let template = std::fs::read_to_string(template_path)
+        .with_context(|| format!("Failed to read config template: {}", template_path.display()))?;
+    let vars = load_vars(vars_path)?;
+    render(&template, &vars)
+        .with_context(|| format!("Failed to render config template: {}", template_path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn test_render_substitutes_and_defaults() {
+        let template = "bind_addr = \"${host}:${port:-1080}\"\nmax_connections = ${max}\nnote = \"$${literal} and $5\"\n";
+        let rendered = render(template, &vars(&[("host", "10.0.0.7"), ("max", "500")])).unwrap();
+        assert_eq!(
+            rendered,
+            "bind_addr = \"10.0.0.7:1080\"\nmax_connections = 500\nnote = \"${literal} and $5\"\n"
+        );
+    }
+
+    #[test]
+    fn test_render_reports_all_missing_variables() {
+        let err = render("a = ${one}\nb = ${two}\n", &HashMap::new()).unwrap_err();
+        assert_eq!(err.to_string(), "Undefined template variables: 'one' (line 1), 'two' (line 2)");
+
+        assert!(render("a = ${unterminated\n", &HashMap::new()).is_err());
+    }
+
+    #[test]
+    fn test_vars_file_values_render_as_toml() {
+        let path = std::env::temp_dir().join(format!("rustproxy-vars-{}.toml", uuid::Uuid::new_v4()));
+        std::fs::write(&path, "host = \"edge-1\"\nports = [80, 443]\nenabled = true\n").unwrap();
+        let loaded = load_vars(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded["host"], "edge-1");
+        assert_eq!(loaded["ports"], "[80, 443]");
+        assert_eq!(loaded["enabled"], "true");
+    }
+}
@@ -1,8 +1,10 @@
 //! Configuration Types
 
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::time::Duration;
+use crate::relay::{BandwidthLimit, TrafficClass};
 use crate::security::SecurityConfig;
 
 /// Main configuration structure
@@ -24,17 +26,66 @@ pub struct ServerConfig {
     #[serde(with = "humantime_serde")]
     pub connection_timeout: Duration,
     pub buffer_size: usize,
+    /// Relay buffer size each connection starts with, growing to `buffer_size` under load
+    #[serde(default = "default_initial_buffer_size")]
+    pub initial_buffer_size: usize,
+    /// Idle time after which a grown relay buffer shrinks back
+    #[serde(default = "default_buffer_shrink_after", with = "humantime_serde")]
+    pub buffer_shrink_after: Duration,
     #[serde(with = "humantime_serde")]
     pub shutdown_timeout: Duration,
     #[serde(with = "humantime_serde")]
     pub idle_timeout: Duration,
     #[serde(with = "humantime_serde")]
     pub handshake_timeout: Duration,
+    /// Age after which a connection is closed however busy it is; relays
+    /// then run this long instead of `connection_timeout`
+    #[serde(default, with = "humantime_serde")]
+    pub max_connection_lifetime: Option<Duration>,
+    /// How long before `max_connection_lifetime` a connection is warned about
+    #[serde(default = "default_connection_lifetime_warning", with = "humantime_serde")]
+    pub connection_lifetime_warning: Duration,
+    /// Total time allowed from the start of the handshake to a connected target,
+    /// across authentication, routing, DNS, and connect attempts
+    #[serde(default, with = "humantime_serde")]
+    pub connect_deadline: Option<Duration>,
     pub max_memory_mb: usize,
     pub connection_pool_size: usize,
     pub enable_keepalive: bool,
+    /// Idle time before the first keepalive probe, and time between probes
     #[serde(with = "humantime_serde")]
     pub keepalive_interval: Duration,
+    /// Unanswered probes after which a peer is considered dead
+    #[serde(default = "default_keepalive_probes")]
+    pub keepalive_probes: u32,
+    /// Options for connections accepted from clients
+    #[serde(default)]
+    pub listener_socket: crate::connection::SocketOptions,
+    /// Options for outbound connections to targets and upstream proxies
+    #[serde(default)]
+    pub egress_socket: crate::connection::SocketOptions,
+    /// Threads and queue for password checks, GeoIP lookups and state file writes
+    #[serde(default)]
+    pub blocking_pool: crate::blocking::BlockingPoolConfig,
+    /// Listener for tunnels from other RustProxy nodes
+    #[serde(default)]
+    pub tunnel: Option<crate::tunnel::TunnelServerConfig>,
+}
+
+fn default_initial_buffer_size() -> usize {
+    4096
+}
+
+fn default_buffer_shrink_after() -> Duration {
+    Duration::from_secs(5)
+}
+
+fn default_connection_lifetime_warning() -> Duration {
+    Duration::from_secs(300)
+}
+
+fn default_keepalive_probes() -> u32 {
+    3
 }
 
 /// Authentication configuration
@@ -43,14 +94,363 @@ pub struct AuthConfig {
     pub enabled: bool,
     pub method: String,
     pub users: Vec<UserConfig>,
+    /// Accept signed JWTs in the password field
+    #[serde(default)]
+    pub jwt: Option<JwtAuthConfig>,
+    /// Validate opaque tokens against an OAuth2 introspection endpoint
+    #[serde(default)]
+    pub introspection: Option<IntrospectionConfig>,
+    /// Ask an HTTP endpoint to check username/password logins
+    #[serde(default)]
+    pub webhook: Option<AuthWebhookConfig>,
+    /// Credential backends tried in order until one accepts the login;
+    /// backends that are not configured are skipped
+    #[serde(default = "default_auth_providers")]
+    pub providers: Vec<AuthProvider>,
+    /// Settings shared by every member of a group, keyed by group name
+    #[serde(default)]
+    pub groups: HashMap<String, GroupConfig>,
+    /// File where active sessions are saved on shutdown and restored on startup
+    #[serde(default)]
+    pub session_state_path: Option<std::path::PathBuf>,
+    /// How long an authenticated session may keep relaying before it must re-authenticate
+    #[serde(default, with = "humantime_serde")]
+    pub session_max_lifetime: Option<Duration>,
+    /// What happens to relays that outlive `session_max_lifetime`
+    #[serde(default)]
+    pub on_session_expired: SessionExpiryAction,
+    /// TOTP steps (30 s each) of clock drift accepted either side of now
+    #[serde(default = "default_totp_window")]
+    pub totp_window: u64,
+    /// Reuse a successful password check for the same client, user, and password this long
+    #[serde(default, with = "humantime_serde")]
+    pub cache_ttl: Option<Duration>,
+    /// Upper bound on cached credential checks
+    #[serde(default = "default_auth_cache_entries")]
+    pub cache_max_entries: usize,
+    /// How long an expired password keeps working, with a warning, before logins are refused
+    #[serde(default, with = "humantime_serde")]
+    pub password_expiry_grace: Option<Duration>,
+    /// How clients are told apart when authentication is disabled
+    #[serde(default)]
+    pub anonymous_identity: AnonymousIdentity,
+    /// Secret mixed into `hashed_source_ip` identities
+    #[serde(default)]
+    pub anonymous_identity_salt: Option<String>,
+    /// Append every authentication attempt to a JSON lines audit file
+    #[serde(default)]
+    pub audit_log: Option<AuthAuditConfig>,
+    /// Per-direction rate caps shared by all of a user's concurrent relays,
+    /// for users that do not set `total_bandwidth` themselves
+    #[serde(default)]
+    pub default_total_bandwidth: BandwidthLimit,
+}
+
+fn default_auth_providers() -> Vec<AuthProvider> {
+    vec![AuthProvider::Introspection, AuthProvider::Jwt, AuthProvider::File, AuthProvider::Webhook]
+}
+
+/// Backend that can accept a username/password login
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthProvider {
+    /// Users listed in `auth.users`
+    File,
+    /// JWTs sent as the password
+    Jwt,
+    /// Opaque tokens sent as the password of `introspection.token_username`
+    Introspection,
+    /// The `auth.webhook` endpoint
+    Webhook,
+}
+
+impl AuthProvider {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuthProvider::File => "file",
+            AuthProvider::Jwt => "jwt",
+            AuthProvider::Introspection => "introspection",
+            AuthProvider::Webhook => "webhook",
+        }
+    }
+}
+
+fn default_totp_window() -> u64 {
+    1
+}
+
+fn default_auth_cache_entries() -> usize {
+    10_000
+}
+
+/// Authentication audit file configuration
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct AuthAuditConfig {
+    /// File that attempts are appended to
+    pub path: std::path::PathBuf,
+    /// Size at which the file is rotated
+    #[serde(default = "default_audit_max_bytes")]
+    pub max_bytes: u64,
+    /// Rotated files to keep (`path.1` is the newest)
+    #[serde(default = "default_audit_max_files")]
+    pub max_files: usize,
+}
+
+fn default_audit_max_bytes() -> u64 {
+    10 * 1024 * 1024
+}
+
+fn default_audit_max_files() -> usize {
+    5
+}
+
+/// User id given to clients when authentication is disabled
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AnonymousIdentity {
+    /// Every client is "anonymous"
+    #[default]
+    Shared,
+    /// One id per source address (per /64 for IPv6)
+    SourceIp,
+    /// Like `source_ip`, but the address is replaced by a salted hash
+    HashedSourceIp,
+}
+
+/// Action taken when a session reaches its maximum lifetime
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SessionExpiryAction {
+    /// Close the relay so the client has to authenticate again
+    #[default]
+    Terminate,
+    /// Keep relaying but log that the session is overdue
+    Flag,
+}
+
+impl AuthConfig {
+    /// Groups a user belongs to: those listed on the user plus any token roles
+    pub fn resolve_groups(&self, user_id: Option<&str>, roles: &[String]) -> Vec<String> {
+        let mut groups: Vec<String> = user_id
+            .and_then(|id| self.users.iter().find(|u| u.username == id))
+            .map(|user| user.groups.clone())
+            .unwrap_or_default();
+        for role in roles {
+            if !groups.contains(role) {
+                groups.push(role.clone());
+            }
+        }
+        groups
+    }
+
+    /// Combined rate caps for a user: their own limit and those of all their groups
+    pub fn bandwidth_for(&self, user_id: Option<&str>, groups: &[String]) -> BandwidthLimit {
+        let user_limit = user_id
+            .and_then(|id| self.users.iter().find(|u| u.username == id))
+            .map(|user| user.bandwidth)
+            .unwrap_or_default();
+        groups.iter()
+            .filter_map(|group| self.groups.get(group))
+            .fold(user_limit, |limit, group| limit.tighter(group.bandwidth))
+    }
+
+    /// Highest QoS traffic class any of a user's groups places them in
+    pub fn traffic_class_for(&self, groups: &[String]) -> Option<TrafficClass> {
+        groups.iter()
+            .filter_map(|group| self.groups.get(group))
+            .filter_map(|group| group.traffic_class)
+            .min()
+    }
+
+    /// Rate caps shared by all of a user's relays: their own, or the default
+    pub fn total_bandwidth_for(&self, user_id: &str) -> BandwidthLimit {
+        self.users.iter()
+            .find(|user| user.username == user_id)
+            .and_then(|user| user.total_bandwidth)
+            .unwrap_or(self.default_total_bandwidth)
+    }
+
+    /// Egress country requested for a user, if any
+    pub fn egress_country_for(&self, user_id: Option<&str>) -> Option<&str> {
+        user_id
+            .and_then(|id| self.users.iter().find(|u| u.username == id))
+            .and_then(|user| user.egress_country.as_deref())
+    }
+
+    /// Local address or interface requested for a user's direct connections, if any
+    pub fn egress_bind_addr_for(&self, user_id: Option<&str>) -> Option<&crate::connection::EgressBind> {
+        user_id
+            .and_then(|id| self.users.iter().find(|u| u.username == id))
+            .and_then(|user| user.egress_bind_addr.as_ref())
+    }
+}
+
+/// Settings applied to all members of a user group
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct GroupConfig {
+    /// Per-direction rate caps for each relay of a group member
+    #[serde(default, flatten)]
+    pub bandwidth: BandwidthLimit,
+    /// QoS traffic class of group members' connections
+    #[serde(default)]
+    pub traffic_class: Option<TrafficClass>,
+}
+
+/// JWT bearer token authentication configuration
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct JwtAuthConfig {
+    /// Shared secret for HS256 tokens
+    pub secret: Option<String>,
+    /// Path to a JWKS file with RSA/EC public keys
+    pub jwks_path: Option<std::path::PathBuf>,
+    /// Required `iss` claim
+    pub issuer: Option<String>,
+    /// Required `aud` claim
+    pub audience: Option<String>,
+    /// Allowed clock skew when checking `exp` and `nbf`
+    #[serde(default = "default_jwt_leeway", with = "humantime_serde")]
+    pub leeway: Duration,
+}
+
+fn default_jwt_leeway() -> Duration {
+    Duration::from_secs(30)
+}
+
+/// OAuth2 token introspection (RFC 7662) configuration
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct IntrospectionConfig {
+    /// Introspection endpoint URL
+    pub endpoint: String,
+    /// Client credentials sent with HTTP basic auth
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+    /// SOCKS5 username that marks the password as a token to introspect
+    #[serde(default = "default_introspection_username")]
+    pub token_username: String,
+    /// Timeout for each introspection request
+    #[serde(default = "default_introspection_timeout", with = "humantime_serde")]
+    pub timeout: Duration,
+    /// Upper bound on how long an active token is cached, even if `exp` is later
+    #[serde(default = "default_introspection_cache_ttl", with = "humantime_serde")]
+    pub max_cache_ttl: Duration,
+}
+
+fn default_introspection_username() -> String {
+    "oauth2".to_string()
+}
+
+fn default_introspection_timeout() -> Duration {
+    Duration::from_secs(5)
+}
+
+fn default_introspection_cache_ttl() -> Duration {
+    Duration::from_secs(300)
+}
+
+/// HTTP endpoint that checks username/password logins
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct AuthWebhookConfig {
+    /// Receives a JSON POST with the username, password, and client IP
+    pub url: String,
+    /// Sent as `Authorization: Bearer <token>`
+    #[serde(default)]
+    pub bearer_token: Option<String>,
+    #[serde(default = "default_auth_webhook_timeout", with = "humantime_serde")]
+    pub timeout: Duration,
+}
+
+fn default_auth_webhook_timeout() -> Duration {
+    Duration::from_secs(5)
 }
 
 /// User configuration
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct UserConfig {
     pub username: String,
+    /// Plaintext password; leave out when `password_hash` is given
+    #[serde(default)]
     pub password: String,
+    /// Argon2 PHC string (`$argon2id$v=19$...`) checked instead of `password`
+    #[serde(default)]
+    pub password_hash: Option<String>,
     pub enabled: bool,
+    /// Overrides `security.quotas.default_daily_bytes` for this user
+    #[serde(default)]
+    pub daily_quota_bytes: Option<u64>,
+    /// Overrides `security.quotas.default_monthly_bytes` for this user
+    #[serde(default)]
+    pub monthly_quota_bytes: Option<u64>,
+    #[serde(default)]
+    pub daily_upload_quota_bytes: Option<u64>,
+    #[serde(default)]
+    pub monthly_upload_quota_bytes: Option<u64>,
+    #[serde(default)]
+    pub daily_download_quota_bytes: Option<u64>,
+    #[serde(default)]
+    pub monthly_download_quota_bytes: Option<u64>,
+    /// Per-direction rate caps applied to every relay of this user
+    #[serde(default, flatten)]
+    pub bandwidth: BandwidthLimit,
+    /// Overrides `auth.default_total_bandwidth`: caps shared by all of this
+    /// user's concurrent relays together
+    #[serde(default)]
+    pub total_bandwidth: Option<BandwidthLimit>,
+    /// Groups that routing rules, ACLs, and group limits can refer to
+    #[serde(default)]
+    pub groups: Vec<String>,
+    /// Base32 TOTP secret; the user must append the current code to their password
+    #[serde(default)]
+    pub totp_secret: Option<String>,
+    /// Networks (CIDRs or single addresses) the user may log in from; empty allows any
+    #[serde(default)]
+    pub allowed_source_cidrs: Vec<String>,
+    /// Weekly windows the user may connect in; empty allows any time
+    #[serde(default)]
+    pub access_windows: Vec<crate::schedule::Schedule>,
+    /// When the password stops being accepted (RFC 3339, e.g. "2025-06-30T00:00:00Z")
+    #[serde(default, with = "humantime_serde")]
+    pub password_expires: Option<std::time::SystemTime>,
+    /// Two-letter country code requested for this user's egress, available
+    /// to upstream credential templates as `{cc}`
+    #[serde(default)]
+    pub egress_country: Option<String>,
+    /// Local address or interface this user's direct connections leave from
+    #[serde(default)]
+    pub egress_bind_addr: Option<crate::connection::EgressBind>,
+}
+
+impl UserConfig {
+    /// Create an enabled user with no quotas or bandwidth caps
+    pub fn new(username: impl Into<String>, password: impl Into<String>) -> Self {
+        Self {
+            username: username.into(),
+            password: password.into(),
+            password_hash: None,
+            enabled: true,
+            daily_quota_bytes: None,
+            monthly_quota_bytes: None,
+            daily_upload_quota_bytes: None,
+            monthly_upload_quota_bytes: None,
+            daily_download_quota_bytes: None,
+            monthly_download_quota_bytes: None,
+            bandwidth: BandwidthLimit::default(),
+            total_bandwidth: None,
+            groups: Vec::new(),
+            totp_secret: None,
+            allowed_source_cidrs: Vec::new(),
+            access_windows: Vec::new(),
+            password_expires: None,
+            egress_country: None,
+            egress_bind_addr: None,
+        }
+    }
+}
+
+/// Parse a source restriction, accepting a CIDR or a single address
+pub fn parse_source_cidr(value: &str) -> crate::Result<ipnet::IpNet> {
+    value.parse::<ipnet::IpNet>()
+        .or_else(|_| value.parse::<std::net::IpAddr>().map(ipnet::IpNet::from))
+        .map_err(|_| anyhow::anyhow!("Invalid source network '{}'", value))
 }
 
 /// Access control configuration
@@ -66,8 +466,12 @@ pub struct AccessControlConfig {
 pub struct AccessRule {
     pub pattern: String,
     pub action: String,
-    pub ports: Option<Vec<u16>>,
+    /// Ports, ranges ("1024-65535") or service names ("https")
+    pub ports: Option<Vec<crate::routing::PortRange>>,
     pub countries: Option<Vec<String>>,
+    /// Only apply the rule to users in one of these groups
+    #[serde(default)]
+    pub groups: Option<Vec<String>>,
 }
 
 /// Routing configuration
@@ -77,6 +481,376 @@ pub struct RoutingConfig {
     pub upstream_proxies: Vec<UpstreamProxyConfig>,
     pub rules: Vec<RoutingRuleConfig>,
     pub smart_routing: SmartRoutingConfigToml,
+    /// Named DSCP traffic classes that rules can refer to (e.g. bulk = 8)
+    #[serde(default)]
+    pub dscp_classes: HashMap<String, u8>,
+    /// Named bandwidth classes that throttle actions place connections in;
+    /// each class's caps are shared by all of its connections
+    #[serde(default)]
+    pub bandwidth_classes: HashMap<String, BandwidthLimit>,
+    /// Keep sending a client's connections through the same upstream
+    #[serde(default)]
+    pub sticky_sessions: StickySessionConfig,
+    /// Tracking of upstream usage against caps and budgets
+    #[serde(default)]
+    pub upstream_usage: UpstreamUsageConfig,
+    /// How an upstream is picked when no rule names one
+    #[serde(default)]
+    pub load_balancing: LoadBalancingStrategy,
+    /// Named business-hours and holiday calendars that rules can refer to
+    #[serde(default)]
+    pub calendars: HashMap<String, crate::schedule::Calendar>,
+    /// Time zone of rule time windows that do not name their own
+    #[serde(default)]
+    pub time_zone: crate::schedule::TimeZone,
+    /// Who resolves domain targets sent through upstream proxies, unless a rule says otherwise
+    #[serde(default)]
+    pub dns_resolution: DnsResolution,
+    /// Caching of domain lookups made by the proxy itself
+    #[serde(default)]
+    pub dns_cache: DnsCacheConfig,
+    /// Encrypted DNS servers to make those lookups with instead of the system resolver
+    #[serde(default)]
+    pub secure_dns: SecureDnsConfig,
+    /// Domain blocklist files, checked before the rules
+    #[serde(default)]
+    pub blocklists: Vec<BlocklistConfig>,
+    /// Routing CONNECTs to IP addresses by the server name their TLS hello asks for
+    #[serde(default)]
+    pub sni_sniffing: SniSniffingConfig,
+    /// Connections to upstream proxies opened ahead of the requests that need them
+    #[serde(default)]
+    pub upstream_pool: UpstreamPoolConfig,
+    /// Prioritizing traffic classes against each other on the proxy's link
+    #[serde(default)]
+    pub qos: QosConfig,
+    /// Local address or interface direct connections leave from, unless a
+    /// rule or the user picks another
+    #[serde(default)]
+    pub egress_bind_addr: Option<crate::connection::EgressBind>,
+}
+
+/// QoS traffic classes
+///
+/// Connections are placed in the interactive, default or bulk class by
+/// routing rules or their user's groups. With a link capacity set, higher
+/// classes are served first when the link is busy.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct QosConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Capacity of the proxy's link per direction; a direction without one
+    /// is not scheduled
+    #[serde(default, flatten)]
+    pub capacity: BandwidthLimit,
+    /// Share of the capacity each class keeps however busy higher classes are
+    #[serde(default = "default_qos_min_share")]
+    pub min_share: f64,
+    /// Mark outbound sockets with their class's DSCP value (EF for
+    /// interactive, CS1 for bulk) unless a rule sets one
+    #[serde(default)]
+    pub mark_dscp: bool,
+}
+
+fn default_qos_min_share() -> f64 {
+    0.05
+}
+
+impl Default for QosConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            capacity: BandwidthLimit::default(),
+            min_share: default_qos_min_share(),
+            mark_dscp: false,
+        }
+    }
+}
+
+/// Pool of pre-established upstream connections
+///
+/// Connections to upstreams that chained requests used recently are kept
+/// ready, past the SOCKS5 greeting and authentication, so the next request
+/// only has to send its CONNECT.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct UpstreamPoolConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Ready connections kept per upstream
+    #[serde(default = "default_upstream_pool_size")]
+    pub max_pool_size: usize,
+    /// How long a ready connection is kept unused, and how long an upstream
+    /// keeps being refilled after its last request
+    #[serde(default = "default_upstream_pool_idle_timeout", with = "humantime_serde")]
+    pub idle_timeout: Duration,
+}
+
+fn default_upstream_pool_size() -> usize {
+    2
+}
+
+fn default_upstream_pool_idle_timeout() -> Duration {
+    Duration::from_secs(30)
+}
+
+impl Default for UpstreamPoolConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_pool_size: default_upstream_pool_size(),
+            idle_timeout: default_upstream_pool_idle_timeout(),
+        }
+    }
+}
+
+/// Server name sniffing for CONNECTs to IP addresses
+///
+/// The client's TLS ClientHello is peeked at and its server name checked
+/// against access control and the routing rules as well, so pre-resolving a
+/// blocked domain does not get around them.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SniSniffingConfig {
+    #[serde(default = "default_sni_sniffing_enabled")]
+    pub enabled: bool,
+    /// Destination ports to sniff on
+    #[serde(default = "default_sni_sniffing_ports")]
+    pub ports: Vec<crate::routing::PortRange>,
+    /// How long to wait for the client's hello before relaying without it
+    #[serde(default = "default_sni_sniffing_timeout", with = "humantime_serde")]
+    pub timeout: Duration,
+}
+
+fn default_sni_sniffing_enabled() -> bool {
+    true
+}
+
+fn default_sni_sniffing_ports() -> Vec<crate::routing::PortRange> {
+    vec![443.into()]
+}
+
+fn default_sni_sniffing_timeout() -> Duration {
+    Duration::from_secs(1)
+}
+
+impl Default for SniSniffingConfig {
+    fn default() -> Self {
+        Self {
+            enabled: default_sni_sniffing_enabled(),
+            ports: default_sni_sniffing_ports(),
+            timeout: default_sni_sniffing_timeout(),
+        }
+    }
+}
+
+/// A domain blocklist file
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct BlocklistConfig {
+    pub path: std::path::PathBuf,
+    #[serde(default)]
+    pub format: BlocklistFormat,
+}
+
+/// Syntax of a blocklist file
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BlocklistFormat {
+    /// Hosts file lines such as `0.0.0.0 ads.example.com`; each name is blocked exactly
+    Hosts,
+    /// AdBlock network rules such as `||ads.example.com^`; the domain and its subdomains are blocked
+    Adblock,
+    /// One domain per line; the domain and its subdomains are blocked
+    #[default]
+    Domains,
+}
+
+/// Where domain targets are resolved when a connection goes through an upstream proxy
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DnsResolution {
+    /// Pass the domain name to the upstream proxy unresolved
+    #[default]
+    Remote,
+    /// Resolve the domain here and send the upstream an IP address
+    Local,
+}
+
+impl DnsResolution {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DnsResolution::Remote => "remote",
+            DnsResolution::Local => "local",
+        }
+    }
+}
+
+/// DNS answer cache configuration
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct DnsCacheConfig {
+    #[serde(default = "default_dns_cache_enabled")]
+    pub enabled: bool,
+    #[serde(default = "default_dns_cache_entries")]
+    pub max_entries: usize,
+    /// Lifetime of answers whose lookup reports no TTL
+    #[serde(default = "default_dns_default_ttl", with = "humantime_serde")]
+    pub default_ttl: Duration,
+    /// Shortest time an answer is kept, whatever its TTL
+    #[serde(default = "default_dns_min_ttl", with = "humantime_serde")]
+    pub min_ttl: Duration,
+    /// Longest time an answer is kept, whatever its TTL
+    #[serde(default = "default_dns_max_ttl", with = "humantime_serde")]
+    pub max_ttl: Duration,
+    /// How long a failed lookup is remembered
+    #[serde(default = "default_dns_negative_ttl", with = "humantime_serde")]
+    pub negative_ttl: Duration,
+}
+
+fn default_dns_cache_enabled() -> bool {
+    true
+}
+
+fn default_dns_cache_entries() -> usize {
+    10_000
+}
+
+fn default_dns_default_ttl() -> Duration {
+    Duration::from_secs(60)
+}
+
+fn default_dns_min_ttl() -> Duration {
+    Duration::from_secs(5)
+}
+
+fn default_dns_max_ttl() -> Duration {
+    Duration::from_secs(3600)
+}
+
+fn default_dns_negative_ttl() -> Duration {
+    Duration::from_secs(10)
+}
+
+impl Default for DnsCacheConfig {
+    fn default() -> Self {
+        Self {
+            enabled: default_dns_cache_enabled(),
+            max_entries: default_dns_cache_entries(),
+            default_ttl: default_dns_default_ttl(),
+            min_ttl: default_dns_min_ttl(),
+            max_ttl: default_dns_max_ttl(),
+            negative_ttl: default_dns_negative_ttl(),
+        }
+    }
+}
+
+/// DNS-over-HTTPS / DNS-over-TLS configuration
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SecureDnsConfig {
+    /// Servers tried in order: `https://host/dns-query` for DNS over HTTPS,
+    /// `tls://host[:853]` for DNS over TLS; empty uses the system resolver
+    #[serde(default)]
+    pub servers: Vec<String>,
+    /// Time allowed for each server to answer
+    #[serde(default = "default_secure_dns_timeout", with = "humantime_serde")]
+    pub timeout: Duration,
+}
+
+fn default_secure_dns_timeout() -> Duration {
+    Duration::from_secs(5)
+}
+
+impl Default for SecureDnsConfig {
+    fn default() -> Self {
+        Self {
+            servers: Vec::new(),
+            timeout: default_secure_dns_timeout(),
+        }
+    }
+}
+
+/// Upstream load-balancing strategy
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LoadBalancingStrategy {
+    /// The first configured upstream with room (failover order)
+    #[default]
+    First,
+    /// Each upstream in turn
+    RoundRobin,
+    /// The upstream carrying the fewest active relays
+    LeastConnections,
+    /// Each upstream in turn, as often as its `weight`
+    Weighted,
+    /// The upstream with the lowest recent connect latency
+    Latency,
+}
+
+/// Upstream usage tracking configuration
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct UpstreamUsageConfig {
+    /// Share of a cap or budget (0-1] after which an upstream is only used
+    /// when no other upstream has room
+    #[serde(default = "default_upstream_warn_ratio")]
+    pub warn_ratio: f64,
+    /// Where monthly usage is persisted (in-memory only if unset)
+    #[serde(default)]
+    pub state_path: Option<std::path::PathBuf>,
+}
+
+fn default_upstream_warn_ratio() -> f64 {
+    0.8
+}
+
+impl Default for UpstreamUsageConfig {
+    fn default() -> Self {
+        Self {
+            warn_ratio: default_upstream_warn_ratio(),
+            state_path: None,
+        }
+    }
+}
+
+/// Sticky upstream configuration
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct StickySessionConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// What a pin is tied to
+    #[serde(default)]
+    pub key: StickyKey,
+    /// How long a pin lasts after the last connection that used it
+    #[serde(default = "default_sticky_ttl", with = "humantime_serde")]
+    pub ttl: Duration,
+    /// Where pins are persisted (in-memory only if unset)
+    #[serde(default)]
+    pub state_path: Option<std::path::PathBuf>,
+}
+
+fn default_sticky_ttl() -> Duration {
+    Duration::from_secs(30 * 60)
+}
+
+impl Default for StickySessionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            key: StickyKey::default(),
+            ttl: default_sticky_ttl(),
+            state_path: None,
+        }
+    }
+}
+
+/// What a sticky upstream pin is tied to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StickyKey {
+    /// The client's IP address
+    #[default]
+    Client,
+    /// The authenticated user, or the client's IP address for anonymous connections
+    User,
+    /// The user (or client IP) together with the destination host, so each
+    /// site keeps its own exit
+    UserDestination,
 }
 
 /// Smart routing configuration for TOML
@@ -99,10 +873,50 @@ pub struct RoutingRuleConfig {
     pub priority: u32,
     pub pattern: String,
     pub action: RoutingActionConfig,
-    pub ports: Option<Vec<u16>>,
+    /// Ports, ranges ("1024-65535") or service names ("https")
+    pub ports: Option<Vec<crate::routing::PortRange>>,
     pub source_ips: Option<Vec<String>>,
     pub users: Option<Vec<String>>,
+    /// Match users belonging to any of these groups
+    #[serde(default)]
+    pub groups: Option<Vec<String>>,
+    /// Match destinations located in any of these countries (ISO 3166 codes, via GeoIP)
+    #[serde(default)]
+    pub countries: Option<Vec<String>>,
+    /// Only match at certain times, directly or through a named calendar
+    #[serde(default)]
+    pub time_restrictions: Option<crate::routing::TimeRestriction>,
     pub enabled: bool,
+    /// DSCP value (0-63) to mark outbound traffic with
+    #[serde(default)]
+    pub dscp: Option<u8>,
+    /// Name of a class in `routing.dscp_classes`, instead of a raw value
+    #[serde(default)]
+    pub dscp_class: Option<String>,
+    /// Upload and download rate caps for connections this rule allows
+    #[serde(default, flatten)]
+    pub bandwidth: BandwidthLimit,
+    /// Relay transformers (registered by name) to run on connections this rule allows
+    #[serde(default)]
+    pub transformers: Vec<String>,
+    /// Override `routing.dns_resolution` for connections this rule allows
+    #[serde(default)]
+    pub dns_resolution: Option<DnsResolution>,
+    /// Let connections this rule allows reach private, loopback, link-local
+    /// and cloud metadata addresses despite `security.private_ranges`
+    #[serde(default)]
+    pub allow_private: bool,
+    /// QoS traffic class of connections this rule allows, over any set by
+    /// the user's groups
+    #[serde(default)]
+    pub traffic_class: Option<TrafficClass>,
+    /// Local address or interface direct connections the rule allows leave from
+    #[serde(default)]
+    pub egress_bind_addr: Option<crate::connection::EgressBind>,
+    /// Firewall mark (SO_MARK) set on direct connections the rule allows, for
+    /// policy routing (Linux only)
+    #[serde(default)]
+    pub fwmark: Option<u32>,
 }
 
 /// Routing action configuration
@@ -114,6 +928,8 @@ pub enum RoutingActionConfig {
     Redirect { target: SocketAddr },
     Proxy { upstream_id: String },
     ProxyChain { upstream_ids: Vec<String> },
+    Throttle { class: String },
+    Rewrite { host: String },
 }
 
 /// Upstream proxy configuration
@@ -123,9 +939,31 @@ pub struct UpstreamProxyConfig {
     pub addr: SocketAddr,
     pub protocol: String,
     pub auth: Option<ProxyAuthConfig>,
+    /// Bytes this upstream may carry per calendar month (UTC)
+    #[serde(default)]
+    pub monthly_cap_bytes: Option<u64>,
+    /// Price of one GB (10^9 bytes) relayed through this upstream
+    #[serde(default)]
+    pub cost_per_gb: Option<f64>,
+    /// Spend allowed per calendar month, in the currency of `cost_per_gb`
+    #[serde(default)]
+    pub monthly_budget: Option<f64>,
+    /// Share of connections under `weighted` load balancing
+    #[serde(default = "default_upstream_weight")]
+    pub weight: u32,
+    /// How to reach the far node of a `tunnel` upstream
+    #[serde(default)]
+    pub tunnel: Option<crate::tunnel::TunnelClientConfig>,
+}
+
+fn default_upstream_weight() -> u32 {
+    1
 }
 
 /// Proxy authentication configuration
+///
+/// Both fields may use `{user}`, `{session}` and `{cc}` placeholders, filled
+/// in per connection (e.g. `cust-{user}-country-{cc}`).
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct ProxyAuthConfig {
     pub username: String,
@@ -141,15 +979,263 @@ pub struct MonitoringConfig {
     pub prometheus_enabled: bool,
     pub collect_connection_stats: bool,
     pub max_historical_connections: usize,
+    /// How often active relays report their byte counts
+    #[serde(default = "default_stats_update_interval", with = "humantime_serde")]
+    pub stats_update_interval: Duration,
+    /// Report early once this many bytes are unreported (0 disables)
+    #[serde(default = "default_stats_update_bytes")]
+    pub stats_update_bytes: u64,
+    /// Connections kept out of detailed logs and per-connection metrics
+    #[serde(default)]
+    pub privacy: PrivacyConfig,
+    /// How long connection history keeps identifying data
+    #[serde(default)]
+    pub retention: RetentionConfig,
+    /// Post connection usage to an external HTTP endpoint
+    #[serde(default)]
+    pub webhooks: Option<WebhookConfig>,
+    /// Diagnostic bundles written when the process panics
+    #[serde(default)]
+    pub crash_reports: CrashReportConfig,
+    /// Check a release manifest for new versions and security advisories
+    #[serde(default)]
+    pub update_check: Option<UpdateCheckConfig>,
     pub management_api: ManagementApiConfig,
 }
 
+fn default_stats_update_interval() -> Duration {
+    Duration::from_secs(5)
+}
+
+fn default_stats_update_bytes() -> u64 {
+    1024 * 1024
+}
+
+/// Privacy controls for connection metadata
+///
+/// Matching connections are only counted in aggregate: their destination and
+/// user are left out of log lines, connection history, and top-N reports.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct PrivacyConfig {
+    /// Destination patterns: exact hosts, `*.example.com`, `.example.com`, IPs, or CIDRs
+    #[serde(default)]
+    pub exclude_destinations: Vec<String>,
+    /// Users whose connections are never recorded in detail
+    #[serde(default)]
+    pub exclude_users: Vec<String>,
+}
+
+impl PrivacyConfig {
+    /// Placeholder logged instead of excluded destinations and users
+    pub const REDACTED: &'static str = "[redacted]";
+
+    /// Whether any exclusions are configured
+    pub fn is_enabled(&self) -> bool {
+        !self.exclude_destinations.is_empty() || !self.exclude_users.is_empty()
+    }
+
+    /// Whether the user's connections are excluded from detailed records
+    pub fn excludes_user(&self, user: Option<&str>) -> bool {
+        user.is_some_and(|user| self.exclude_users.iter().any(|excluded| excluded == user))
+    }
+
+    /// Whether a connection is excluded from detailed records
+    pub fn excludes(&self, target: &crate::protocol::TargetAddr, user: Option<&str>) -> bool {
+        use crate::protocol::TargetAddr;
+
+        if self.excludes_user(user) {
+            return true;
+        }
+        self.exclude_destinations.iter().any(|pattern| match target {
+            TargetAddr::Ipv4(ip) => ip_matches(pattern, (*ip).into()),
+            TargetAddr::Ipv6(ip) => ip_matches(pattern, (*ip).into()),
+            TargetAddr::Domain(domain) => domain_matches(pattern, domain),
+        })
+    }
+}
+
+fn ip_matches(pattern: &str, ip: std::net::IpAddr) -> bool {
+    parse_source_cidr(pattern).is_ok_and(|net| net.contains(&ip))
+}
+
+fn domain_matches(pattern: &str, domain: &str) -> bool {
+    let domain = domain.trim_end_matches('.');
+    if pattern.eq_ignore_ascii_case(domain) {
+        return true;
+    }
+    let suffix = pattern.strip_prefix("*.").or_else(|| pattern.strip_prefix('.'));
+    suffix.is_some_and(|suffix| {
+        let domain = domain.to_ascii_lowercase();
+        let suffix = suffix.to_ascii_lowercase();
+        domain == suffix || domain.ends_with(&format!(".{}", suffix))
+    })
+}
+
+/// Retention policy for connection history
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RetentionConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Replace client IPs and usernames with salted hashes once records reach this age
+    #[serde(default, with = "humantime_serde")]
+    pub anonymize_after: Option<Duration>,
+    /// Drop records entirely once they reach this age
+    #[serde(default, with = "humantime_serde")]
+    pub delete_after: Option<Duration>,
+    /// How often the anonymization salt is replaced
+    #[serde(default = "default_salt_rotation", with = "humantime_serde")]
+    pub salt_rotation: Duration,
+    /// How often the retention job runs
+    #[serde(default = "default_retention_interval", with = "humantime_serde")]
+    pub check_interval: Duration,
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            anonymize_after: None,
+            delete_after: None,
+            salt_rotation: default_salt_rotation(),
+            check_interval: default_retention_interval(),
+        }
+    }
+}
+
+/// Crash report configuration
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CrashReportConfig {
+    #[serde(default = "default_crash_reports_enabled")]
+    pub enabled: bool,
+    /// Directory the bundles are written to
+    #[serde(default = "default_crash_dir")]
+    pub dir: std::path::PathBuf,
+    /// Log events kept in memory for the bundle
+    #[serde(default = "default_crash_recent_events")]
+    pub recent_events: usize,
+}
+
+impl Default for CrashReportConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            dir: default_crash_dir(),
+            recent_events: default_crash_recent_events(),
+        }
+    }
+}
+
+fn default_crash_reports_enabled() -> bool {
+    true
+}
+
+fn default_crash_dir() -> std::path::PathBuf {
+    std::path::PathBuf::from("crash")
+}
+
+fn default_crash_recent_events() -> usize {
+    200
+}
+
+/// Release update check configuration
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct UpdateCheckConfig {
+    /// JSON release manifest listing the latest version and advisories
+    pub manifest_url: String,
+    #[serde(default = "default_update_check_interval", with = "humantime_serde")]
+    pub interval: Duration,
+    #[serde(default = "default_update_check_timeout", with = "humantime_serde")]
+    pub timeout: Duration,
+}
+
+fn default_update_check_interval() -> Duration {
+    Duration::from_secs(24 * 60 * 60)
+}
+
+fn default_update_check_timeout() -> Duration {
+    Duration::from_secs(10)
+}
+
+/// Connection usage webhook configuration
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct WebhookConfig {
+    /// Endpoint that receives batches of events as JSON
+    pub url: String,
+    /// Sent as `Authorization: Bearer <token>`
+    #[serde(default)]
+    pub bearer_token: Option<String>,
+    /// Also send an event when a connection opens, not only when it closes
+    #[serde(default)]
+    pub include_open: bool,
+    /// Most events sent in one request
+    #[serde(default = "default_webhook_batch_size")]
+    pub batch_size: usize,
+    /// Longest an event waits for its batch to fill
+    #[serde(default = "default_webhook_flush_interval", with = "humantime_serde")]
+    pub flush_interval: Duration,
+    /// Further attempts after a failed delivery before the batch is dropped
+    #[serde(default = "default_webhook_max_retries")]
+    pub max_retries: u32,
+    /// Wait before the first retry, doubled for each one after
+    #[serde(default = "default_webhook_retry_backoff", with = "humantime_serde")]
+    pub retry_backoff: Duration,
+    #[serde(default = "default_webhook_timeout", with = "humantime_serde")]
+    pub timeout: Duration,
+    /// Events held while deliveries are slow; new events are dropped beyond this
+    #[serde(default = "default_webhook_queue_capacity")]
+    pub queue_capacity: usize,
+}
+
+fn default_webhook_batch_size() -> usize {
+    100
+}
+
+fn default_webhook_flush_interval() -> Duration {
+    Duration::from_secs(5)
+}
+
+fn default_webhook_max_retries() -> u32 {
+    3
+}
+
+fn default_webhook_retry_backoff() -> Duration {
+    Duration::from_secs(1)
+}
+
+fn default_webhook_timeout() -> Duration {
+    Duration::from_secs(10)
+}
+
+fn default_webhook_queue_capacity() -> usize {
+    10_000
+}
+
+fn default_salt_rotation() -> Duration {
+    Duration::from_secs(24 * 60 * 60)
+}
+
+fn default_retention_interval() -> Duration {
+    Duration::from_secs(60 * 60)
+}
+
 /// Management API configuration
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct ManagementApiConfig {
     pub enabled: bool,
     pub bind_addr: SocketAddr,
     pub auth: crate::management::types::ApiAuthConfig,
+    /// Local-only management channel (named pipe on Windows)
+    #[serde(default)]
+    pub local_channel: crate::management::types::LocalChannelConfig,
+    /// PEM certificate chain; with `tls_key`, the API is served over HTTPS only
+    #[serde(default)]
+    pub tls_cert: Option<std::path::PathBuf>,
+    /// PEM private key for `tls_cert`
+    #[serde(default)]
+    pub tls_key: Option<std::path::PathBuf>,
+    /// Further certificates, served to clients that ask for one of their names
+    #[serde(default)]
+    pub tls_sni_certs: Vec<crate::tls::SniCertConfig>,
 }
 
 impl Default for Config {
@@ -160,18 +1246,44 @@ impl Default for Config {
                 max_connections: 1000,
                 connection_timeout: Duration::from_secs(300),
                 buffer_size: 8192,
+                initial_buffer_size: default_initial_buffer_size(),
+                buffer_shrink_after: default_buffer_shrink_after(),
                 shutdown_timeout: Duration::from_secs(30),
                 idle_timeout: Duration::from_secs(60),
                 handshake_timeout: Duration::from_secs(10),
+                max_connection_lifetime: None,
+                connection_lifetime_warning: default_connection_lifetime_warning(),
+                connect_deadline: None,
                 max_memory_mb: 512,
                 connection_pool_size: 10,
                 enable_keepalive: true,
                 keepalive_interval: Duration::from_secs(30),
+                keepalive_probes: default_keepalive_probes(),
+                listener_socket: Default::default(),
+                egress_socket: Default::default(),
+                blocking_pool: crate::blocking::BlockingPoolConfig::default(),
+                tunnel: None,
             },
             auth: AuthConfig {
                 enabled: false,
                 method: "none".to_string(),
                 users: vec![],
+                jwt: None,
+                introspection: None,
+                webhook: None,
+                providers: default_auth_providers(),
+                groups: HashMap::new(),
+                session_state_path: None,
+                session_max_lifetime: None,
+                on_session_expired: SessionExpiryAction::default(),
+                totp_window: default_totp_window(),
+                cache_ttl: None,
+                password_expiry_grace: None,
+                cache_max_entries: default_auth_cache_entries(),
+                anonymous_identity: AnonymousIdentity::default(),
+                anonymous_identity_salt: None,
+                audit_log: None,
+                default_total_bandwidth: BandwidthLimit::default(),
             },
             access_control: AccessControlConfig {
                 enabled: false,
@@ -190,6 +1302,21 @@ impl Default for Config {
                     enable_latency_routing: true,
                     enable_health_routing: true,
                 },
+                dscp_classes: HashMap::new(),
+                bandwidth_classes: HashMap::new(),
+                sticky_sessions: StickySessionConfig::default(),
+                upstream_usage: UpstreamUsageConfig::default(),
+                load_balancing: LoadBalancingStrategy::default(),
+                calendars: HashMap::new(),
+                time_zone: Default::default(),
+                dns_resolution: DnsResolution::default(),
+                dns_cache: DnsCacheConfig::default(),
+                secure_dns: SecureDnsConfig::default(),
+                blocklists: Vec::new(),
+                sni_sniffing: SniSniffingConfig::default(),
+                upstream_pool: UpstreamPoolConfig::default(),
+                qos: QosConfig::default(),
+                egress_bind_addr: None,
             },
             monitoring: MonitoringConfig {
                 enabled: true,
@@ -198,13 +1325,69 @@ impl Default for Config {
                 prometheus_enabled: true,
                 collect_connection_stats: true,
                 max_historical_connections: 10000,
+                stats_update_interval: default_stats_update_interval(),
+                stats_update_bytes: default_stats_update_bytes(),
+                privacy: PrivacyConfig::default(),
+                retention: RetentionConfig::default(),
+                webhooks: None,
+                crash_reports: CrashReportConfig::default(),
+                update_check: None,
                 management_api: ManagementApiConfig {
                     enabled: true,
                     bind_addr: "127.0.0.1:8080".parse().unwrap(),
                     auth: crate::management::types::ApiAuthConfig::default(),
+                    local_channel: crate::management::types::LocalChannelConfig::default(),
+                    tls_cert: None,
+                    tls_key: None,
+                    tls_sni_certs: Vec::new(),
                 },
             },
             security: SecurityConfig::default(),
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::TargetAddr;
+
+    #[test]
+    fn test_privacy_exclusions() {
+        let privacy = PrivacyConfig {
+            exclude_destinations: vec!["*.clinic.example".to_string(), "10.1.0.0/16".to_string()],
+            exclude_users: vec!["counsel".to_string()],
+        };
+
+        assert!(privacy.excludes(&TargetAddr::Domain("portal.clinic.example".to_string()), None));
+        assert!(privacy.excludes(&TargetAddr::Domain("Clinic.Example".to_string()), None));
+        assert!(!privacy.excludes(&TargetAddr::Domain("notclinic.example".to_string()), None));
+        assert!(privacy.excludes(&TargetAddr::Ipv4("10.1.2.3".parse().unwrap()), Some("alice")));
+        assert!(!privacy.excludes(&TargetAddr::Ipv4("10.2.0.1".parse().unwrap()), Some("alice")));
+        assert!(privacy.excludes(&TargetAddr::Domain("example.com".to_string()), Some("counsel")));
+    }
+
+    #[test]
+    fn test_total_bandwidth_defaults_per_user() {
+        let auth: AuthConfig = toml::from_str(r#"
+            enabled = true
+            method = "userpass"
+            default_total_bandwidth = { download_bytes_per_second = 1000000 }
+
+            [[users]]
+            username = "alice"
+            password = "secret"
+            enabled = true
+            total_bandwidth = { upload_bytes_per_second = 50000 }
+
+            [[users]]
+            username = "bob"
+            password = "secret"
+            enabled = true
+        "#).unwrap();
+
+        assert_eq!(auth.total_bandwidth_for("alice"), BandwidthLimit { upload_bytes_per_second: Some(50_000), download_bytes_per_second: None });
+        assert_eq!(auth.total_bandwidth_for("bob").download_bytes_per_second, Some(1_000_000));
+        assert_eq!(auth.total_bandwidth_for("anon-192.0.2.7"), auth.default_total_bandwidth);
+    }
+}
@@ -157,13 +157,9 @@ impl ConfigWatcher {
                     Ok(new_config) => {
                         let config_arc = Arc::new(new_config);
                         
-                        // Update current config (blocking is OK here since it's in a callback)
-                        tokio::task::block_in_place(|| {
-                            tokio::runtime::Handle::current().block_on(async {
-                                let mut current = current_config.write().await;
-                                *current = (*config_arc).clone();
-                            })
-                        });
+                        // Update current config; the callback runs on the watcher's
+                        // own thread, outside the runtime, so it can block
+                        *current_config.blocking_write() = (*config_arc).clone();
                         
                         // Notify subscribers
                         let event = ConfigChangeEvent {
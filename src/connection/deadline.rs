@@ -0,0 +1,116 @@
+//! Connection Deadlines
+//!
+//! A single time budget for getting a client from the start of the SOCKS5
+//! handshake to a connected target. Each phase (handshake, authentication,
+//! routing, DNS, connect) runs against whatever is left of the budget, so
+//! retries and fallbacks inside a phase cannot stretch the total.
+
+use std::fmt;
+use std::future::Future;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Point in time by which a connection must reach its target
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Deadline {
+    expires_at: Option<Instant>,
+}
+
+/// A phase ran out of the connection's time budget
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeadlineExceeded {
+    pub phase: &'static str,
+}
+
+impl fmt::Display for DeadlineExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Connection deadline exceeded during {}", self.phase)
+    }
+}
+
+impl std::error::Error for DeadlineExceeded {}
+
+impl Deadline {
+    /// Deadline `budget` from now, or no deadline when `budget` is `None`
+    pub fn after(budget: Option<Duration>) -> Self {
+        Self {
+            expires_at: budget.map(|budget| Instant::now() + budget),
+        }
+    }
+
+    /// No deadline
+    pub fn unbounded() -> Self {
+        Self::default()
+    }
+
+    /// Time left, or `None` when there is no deadline
+    pub fn remaining(&self) -> Option<Duration> {
+        self.expires_at.map(|at| at.saturating_duration_since(Instant::now()))
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.remaining() == Some(Duration::ZERO)
+    }
+
+    /// The shorter of `limit` and the time left
+    pub fn cap(&self, limit: Duration) -> Duration {
+        self.remaining().map_or(limit, |remaining| remaining.min(limit))
+    }
+
+    /// Fail with `DeadlineExceeded` if the deadline has already passed
+    pub fn check(&self, phase: &'static str) -> Result<(), DeadlineExceeded> {
+        if self.is_expired() {
+            Err(DeadlineExceeded { phase })
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Run a phase, giving up when the deadline passes
+    pub async fn run<F: Future>(&self, phase: &'static str, future: F) -> Result<F::Output, DeadlineExceeded> {
+        match self.expires_at {
+            Some(at) => tokio::time::timeout_at(at, future).await.map_err(|_| DeadlineExceeded { phase }),
+            None => Ok(future.await),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_phases_share_one_budget() {
+        let deadline = Deadline::after(Some(Duration::from_secs(5)));
+
+        let first = deadline.run("handshake", tokio::time::sleep(Duration::from_secs(3))).await;
+        assert!(first.is_ok());
+        assert_eq!(deadline.cap(Duration::from_secs(10)), Duration::from_secs(2));
+
+        let second = deadline.run("connect", tokio::time::sleep(Duration::from_secs(3))).await;
+        assert_eq!(second, Err(DeadlineExceeded { phase: "connect" }));
+        assert!(deadline.is_expired());
+        assert!(deadline.check("routing").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_unbounded_never_expires() {
+        let deadline = Deadline::unbounded();
+        assert_eq!(deadline.remaining(), None);
+        assert_eq!(deadline.cap(Duration::from_secs(7)), Duration::from_secs(7));
+        assert_eq!(deadline.run("dns", async { 42 }).await, Ok(42));
+    }
+
+    #[tokio::test]
+    async fn test_expired_deadline_stops_connect() {
+        let engine = crate::relay::RelayEngine::new().with_deadline(Deadline::after(Some(Duration::ZERO)));
+        let target = crate::protocol::TargetAddr::Ipv4(std::net::Ipv4Addr::LOCALHOST);
+
+        let err = engine.connect_to_target(&target, 9).await.unwrap_err();
+        assert_eq!(err.downcast_ref::<DeadlineExceeded>(), Some(&DeadlineExceeded { phase: "connect" }));
+        assert_eq!(
+            engine.connection_error_to_socks5_code(&err),
+            crate::protocol::constants::SOCKS5_REPLY_TTL_EXPIRED
+        );
+    }
+}
@@ -0,0 +1,124 @@
+//! Listener Error Handling
+//!
+//! Errors from `accept()` fall into three groups. Most concern a single
+//! connection that went away before it was accepted and can be ignored.
+//! Running out of file descriptors or kernel memory affects every accept
+//! until something is released, so the loop backs off instead of spinning.
+//! Anything else means the listening socket itself is unusable (for example
+//! its interface went away), and the only way forward is to bind a new one.
+
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// How the accept loop should react to an `accept()` error
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcceptErrorClass {
+    /// Only the pending connection failed; keep accepting
+    PerConnection,
+    /// The process or system is out of descriptors or memory; back off briefly
+    ResourceExhausted,
+    /// The listening socket is broken; bind a new one
+    Fatal,
+}
+
+/// Classify an error returned by `accept()`
+pub fn classify_accept_error(error: &io::Error) -> AcceptErrorClass {
+    #[cfg(unix)]
+    if let Some(code) = error.raw_os_error() {
+        match code {
+            libc::EMFILE | libc::ENFILE | libc::ENOBUFS | libc::ENOMEM => {
+                return AcceptErrorClass::ResourceExhausted;
+            }
+            // Linux passes pending network errors of the new socket to accept()
+            libc::ENETDOWN | libc::EPROTO | libc::ENOPROTOOPT | libc::EHOSTDOWN | libc::EHOSTUNREACH
+            | libc::EOPNOTSUPP | libc::ENETUNREACH => {
+                return AcceptErrorClass::PerConnection;
+            }
+            _ => {}
+        }
+    }
+
+    match error.kind() {
+        io::ErrorKind::ConnectionAborted
+        | io::ErrorKind::ConnectionReset
+        | io::ErrorKind::ConnectionRefused
+        | io::ErrorKind::Interrupted
+        | io::ErrorKind::WouldBlock
+        | io::ErrorKind::TimedOut => AcceptErrorClass::PerConnection,
+        io::ErrorKind::OutOfMemory => AcceptErrorClass::ResourceExhausted,
+        _ => AcceptErrorClass::Fatal,
+    }
+}
+
+/// Alerts raised by the accept loop about the health of the listener
+#[derive(Debug, Clone)]
+pub enum ListenerEvent {
+    /// Accepting paused because descriptors or memory ran out
+    AcceptBackoff { error: String, delay: Duration },
+    /// The listening socket failed and is being replaced
+    ListenerLost { addr: SocketAddr, error: String },
+    /// Binding a replacement listener failed; another attempt follows after `retry_in`
+    RebindFailed { addr: SocketAddr, attempt: u32, error: String, retry_in: Duration },
+    /// A replacement listener is accepting connections again
+    Rebound { addr: SocketAddr, attempts: u32 },
+}
+
+/// Exponential backoff between `initial` and `max`
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    next: Duration,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Self { initial, max, next: initial }
+    }
+
+    /// Delay to wait now; each call doubles the following one up to `max`
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.next;
+        self.next = (delay * 2).min(self.max);
+        delay
+    }
+
+    /// Start again from the initial delay
+    pub fn reset(&mut self) {
+        self.next = self.initial;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify_accept_errors() {
+        let aborted = io::Error::from(io::ErrorKind::ConnectionAborted);
+        assert_eq!(classify_accept_error(&aborted), AcceptErrorClass::PerConnection);
+
+        #[cfg(unix)]
+        {
+            let emfile = io::Error::from_raw_os_error(libc::EMFILE);
+            let enfile = io::Error::from_raw_os_error(libc::ENFILE);
+            let ebadf = io::Error::from_raw_os_error(libc::EBADF);
+            assert_eq!(classify_accept_error(&emfile), AcceptErrorClass::ResourceExhausted);
+            assert_eq!(classify_accept_error(&enfile), AcceptErrorClass::ResourceExhausted);
+            assert_eq!(classify_accept_error(&ebadf), AcceptErrorClass::Fatal);
+        }
+    }
+
+    #[test]
+    fn test_backoff_doubles_up_to_max_and_resets() {
+        let mut backoff = Backoff::new(Duration::from_millis(10), Duration::from_millis(35));
+        assert_eq!(backoff.next_delay(), Duration::from_millis(10));
+        assert_eq!(backoff.next_delay(), Duration::from_millis(20));
+        assert_eq!(backoff.next_delay(), Duration::from_millis(35));
+        assert_eq!(backoff.next_delay(), Duration::from_millis(35));
+
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), Duration::from_millis(10));
+    }
+}
@@ -0,0 +1,125 @@
+//! Proxy Loop Prevention
+//!
+//! A redirect rule pointing at the proxy's own listen address, or an upstream
+//! chain that leads back into the proxy, makes every connection open another
+//! one until sockets run out. Targets are checked after DNS resolution, so a
+//! name resolving to the proxy is caught as well as a literal address.
+
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+
+/// A connection would reach this proxy again instead of leaving it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProxyLoop {
+    /// The address is one the proxy itself listens on
+    Listener { addr: SocketAddr },
+    /// The address is a proxy already earlier in the upstream chain
+    Hop { addr: SocketAddr },
+}
+
+impl fmt::Display for ProxyLoop {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProxyLoop::Listener { addr } => write!(f, "Proxy loop: {} is this proxy's own listen address", addr),
+            ProxyLoop::Hop { addr } => write!(f, "Proxy loop: {} is already a hop in the proxy chain", addr),
+        }
+    }
+}
+
+impl std::error::Error for ProxyLoop {}
+
+/// Addresses a connection must not be sent back to
+#[derive(Debug, Clone, Default)]
+pub struct LoopGuard {
+    listen: Vec<SocketAddr>,
+}
+
+impl LoopGuard {
+    /// Guard against connecting to any of the proxy's `listen` addresses
+    pub fn new(listen: Vec<SocketAddr>) -> Self {
+        Self { listen }
+    }
+
+    /// Guard for the listeners in `config`
+    pub fn from_config(config: &crate::config::Config) -> Self {
+        Self::new(vec![config.server.bind_addr])
+    }
+
+    /// Whether connecting to `addr` would land on one of the proxy's listeners
+    ///
+    /// A listener on a wildcard address is reached through any address of
+    /// this host on its port.
+    pub fn is_own_listener(&self, addr: SocketAddr) -> bool {
+        let ip = addr.ip().to_canonical();
+        self.listen.iter().any(|listen| {
+            let listen_ip = listen.ip().to_canonical();
+            listen.port() == addr.port()
+                && (listen_ip == ip || ip.is_unspecified() || (listen_ip.is_unspecified() && is_local_ip(ip)))
+        })
+    }
+
+    /// Fail if `addr` leads back into this proxy or to one of the upstream `hops`
+    pub fn check(&self, addr: SocketAddr, hops: &[SocketAddr]) -> Result<(), ProxyLoop> {
+        if self.is_own_listener(addr) {
+            return Err(ProxyLoop::Listener { addr });
+        }
+        if hops.iter().any(|hop| same_addr(*hop, addr)) {
+            return Err(ProxyLoop::Hop { addr });
+        }
+        Ok(())
+    }
+
+    /// Fail if a chain of upstream proxies passes through this proxy or
+    /// visits the same proxy twice
+    pub fn check_chain(&self, hops: &[SocketAddr]) -> Result<(), ProxyLoop> {
+        for (i, hop) in hops.iter().enumerate() {
+            self.check(*hop, &hops[..i])?;
+        }
+        Ok(())
+    }
+}
+
+fn same_addr(a: SocketAddr, b: SocketAddr) -> bool {
+    a.port() == b.port() && a.ip().to_canonical() == b.ip().to_canonical()
+}
+
+/// Whether `ip` belongs to this host
+///
+/// Only local addresses can be bound, which saves enumerating interfaces.
+fn is_local_ip(ip: IpAddr) -> bool {
+    ip.is_loopback() || ip.is_unspecified() || std::net::UdpSocket::bind((ip, 0)).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_detects_own_listeners() {
+        let guard = LoopGuard::new(vec![addr("127.0.0.1:1080")]);
+        assert_eq!(guard.check(addr("127.0.0.1:1080"), &[]), Err(ProxyLoop::Listener { addr: addr("127.0.0.1:1080") }));
+        assert!(guard.check(addr("[::ffff:127.0.0.1]:1080"), &[]).is_err());
+        assert!(guard.check(addr("0.0.0.0:1080"), &[]).is_err());
+        assert!(guard.check(addr("127.0.0.1:1081"), &[]).is_ok());
+        assert!(guard.check(addr("192.0.2.1:1080"), &[]).is_ok());
+
+        // A wildcard listener is reached through every local address
+        let guard = LoopGuard::new(vec![addr("0.0.0.0:1080")]);
+        assert!(guard.check(addr("127.0.0.2:1080"), &[]).is_err());
+        assert!(guard.check(addr("192.0.2.1:1080"), &[]).is_ok());
+    }
+
+    #[test]
+    fn test_detects_repeated_chain_hops() {
+        let guard = LoopGuard::new(vec![addr("127.0.0.1:1080")]);
+        let hops = [addr("192.0.2.1:1080"), addr("192.0.2.2:3128")];
+        assert!(guard.check_chain(&hops).is_ok());
+        assert_eq!(guard.check(addr("192.0.2.2:3128"), &hops), Err(ProxyLoop::Hop { addr: addr("192.0.2.2:3128") }));
+        assert!(guard.check_chain(&[hops[0], hops[1], hops[0]]).is_err());
+        assert!(guard.check_chain(&[hops[0], addr("127.0.0.1:1080")]).is_err());
+    }
+}
@@ -8,18 +8,28 @@ use std::time::Instant;
 
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::{RwLock, broadcast};
-use tokio::time::{timeout, Duration};
+use tokio::task::JoinHandle;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio::time::Duration;
 use tracing::{info, warn, error, debug, instrument};
-use crate::config::Config;
-use crate::auth::AuthManager;
+use crate::config::{Config, ConfigChangeEvent, DnsResolution};
+use crate::auth::{AuthManager, AuthRejection};
 use crate::protocol::{Socks5Handler, AuthMethod};
 use crate::resource::ResourceManager;
-use crate::security::{RateLimiter, DdosProtection, Fail2BanManager};
-use crate::security::ddos_protection::DdosDecision;
-use crate::security::fail2ban::Fail2BanDecision;
-use crate::routing::{Router, RouteDecision};
-use crate::relay::RelayEngine;
+use crate::security::{RateLimiter, BanStore, ClientGeoPolicy, CountryCounts, DdosProtection, ExfiltrationMonitor, Fail2BanLog, Fail2BanManager, Honeypot, IpSecurityTable, QuotaManager, BlockReason, PrivateAddress, Rejector, ReputationFilter, SecurityEvent, SecurityPrefilter, SiemExporter, PrefilterDecision, UdpAssociationGuard, UdpGuardCounters, UdpGuardStats};
+use crate::routing::{ChainHop, DnsCacheStats, EgressContext, ProxyChain, ProxyChainConnector, ProxyProtocol, Resolver, Router, RouteDecision, SmartRoutingManager, StickySessionTable, UpstreamBalancer, UpstreamError, UpstreamPool, UpstreamUsageTracker};
+use crate::relay::{ConnectionContext, RelayEngine, RelayHandle, RelayObserver, RelayTransformer, TargetStream, ThroughputRates, TransformerRegistry, QosScheduler, UdpRelay, UdpRouteContext, UserBandwidth, CONTEXT_VERSION};
+use crate::relay::context::{ClientContext, GeoContext, PhaseTimer, RoutingContext, TargetContext, UserContext};
+use crate::metrics::Metrics;
+use crate::shutdown::{ShutdownCoordinator, ShutdownPhase};
+use crate::tunnel::TunnelConnector;
 use crate::Result;
+use super::deadline::Deadline;
+use super::socket::Keepalive;
+use super::listener::{classify_accept_error, AcceptErrorClass, Backoff, ListenerEvent};
+
+/// Time relays cancelled at the shutdown timeout get to close their sockets
+const FORCE_CLOSE_GRACE: Duration = Duration::from_secs(2);
 
 /// Connection information for tracking
 #[derive(Debug, Clone)]
@@ -27,6 +37,52 @@ pub struct ConnectionInfo {
     pub id: String,
     pub addr: SocketAddr,
     pub start_time: Instant,
+    /// Activity and cancellation of the connection's relay
+    pub relay: RelayHandle,
+}
+
+impl ConnectionInfo {
+    /// Current transfer rates of the connection's relay
+    pub fn throughput(&self) -> ThroughputRates {
+        self.relay.throughput().rates()
+    }
+}
+
+/// Observers and transformers made available to every relay, and the
+/// routing state shared between connections
+#[derive(Clone, Default)]
+struct RelayExtensions {
+    observers: Vec<Arc<dyn RelayObserver>>,
+    transformers: TransformerRegistry,
+    sticky_sessions: Option<Arc<StickySessionTable>>,
+    upstream_usage: Option<Arc<UpstreamUsageTracker>>,
+    balancer: Option<Arc<UpstreamBalancer>>,
+    smart_routing: Option<Arc<SmartRoutingManager>>,
+    /// Connections to upstreams kept ready for chained requests
+    upstream_pool: Option<Arc<UpstreamPool>>,
+    /// Rate caps shared by each user's concurrent relays
+    user_bandwidth: Arc<UserBandwidth>,
+    /// Link capacity shared between traffic classes by priority
+    qos: Option<Arc<QosScheduler>>,
+    metrics: Option<Arc<Metrics>>,
+    udp_guard: Arc<UdpGuardCounters>,
+    resolver: Arc<Resolver>,
+    /// Routing shared by every connection, rebuilt when the resolver changes
+    router: Option<Arc<Router>>,
+    /// Tunnels to other nodes, shared by the connections routed through them
+    tunnels: Arc<TunnelConnector>,
+    /// Per-user and UDP limits, when rate limiting is on
+    rate_limiter: Option<Arc<RateLimiter>>,
+}
+
+/// What each connection task needs from the manager
+#[derive(Clone)]
+struct HandlerContext {
+    config: Arc<Config>,
+    auth_manager: Arc<AuthManager>,
+    fail2ban_manager: Arc<Fail2BanManager>,
+    quota_manager: Arc<QuotaManager>,
+    relay_extensions: RelayExtensions,
 }
 
 /// Manages TCP connections and their lifecycle
@@ -38,11 +94,28 @@ pub struct ConnectionManager {
     rate_limiter: Arc<RateLimiter>,
     ddos_protection: Arc<DdosProtection>,
     fail2ban_manager: Arc<Fail2BanManager>,
+    ban_store: Arc<BanStore>,
+    /// Downloaded lists of known-bad sources, refreshed in the background
+    reputation: Option<Arc<ReputationFilter>>,
+    /// Allowed and refused client countries
+    client_geo: Option<Arc<ClientGeoPolicy>>,
+    /// Fake sessions for refused clients
+    honeypot: Option<Arc<Honeypot>>,
+    /// Closes refused connections the way each block reason calls for
+    rejector: Rejector,
+    /// Security events forwarded to a remote collector
+    siem: Option<Arc<SiemExporter>>,
+    quota_manager: Arc<QuotaManager>,
+    sticky_sessions: Arc<StickySessionTable>,
+    upstream_usage: Arc<UpstreamUsageTracker>,
+    prefilter: SecurityPrefilter,
     active_connections: Arc<AtomicUsize>,
     connection_tracker: Arc<RwLock<HashMap<String, ConnectionInfo>>>,
     next_connection_id: Arc<AtomicUsize>,
     shutdown_flag: Arc<AtomicBool>,
     shutdown_tx: broadcast::Sender<()>,
+    listener_events: broadcast::Sender<ListenerEvent>,
+    relay_extensions: RelayExtensions,
 }
 
 impl ConnectionManager {
@@ -50,10 +123,108 @@ impl ConnectionManager {
     pub fn new(config: Arc<Config>) -> Self {
         let auth_manager = Arc::new(AuthManager::new(Arc::clone(&config)));
         let resource_manager = Arc::new(ResourceManager::new(Arc::clone(&config)));
-        let rate_limiter = Arc::new(RateLimiter::new(config.security.rate_limiting.clone()));
-        let ddos_protection = Arc::new(DdosProtection::new(config.security.ddos_protection.clone()));
-        let fail2ban_manager = Arc::new(Fail2BanManager::new(config.security.fail2ban.clone()));
+        let security = &config.security;
+        let idle_cleanup_interval = security.rate_limiting.cleanup_interval_seconds
+            .max(security.ddos_protection.cleanup_interval_seconds)
+            .max(security.fail2ban.cleanup_interval_seconds);
+        let ip_table = Arc::new(IpSecurityTable::new(Duration::from_secs(idle_cleanup_interval * 2)));
+        let rate_limiter = Arc::new(
+            RateLimiter::new(config.security.rate_limiting.clone()).with_ip_table(Arc::clone(&ip_table)),
+        );
+        let mut ddos_protection = DdosProtection::new(config.security.ddos_protection.clone())
+            .with_ip_table(Arc::clone(&ip_table));
+        let mut fail2ban_manager = Fail2BanManager::new(config.security.fail2ban.clone())
+            .with_ip_table(Arc::clone(&ip_table));
+        if let Some(log_config) = &security.fail2ban_log {
+            match Fail2BanLog::open(log_config) {
+                Ok(event_log) => {
+                    let event_log = Arc::new(event_log);
+                    ddos_protection = ddos_protection.with_event_log(Arc::clone(&event_log));
+                    fail2ban_manager = fail2ban_manager.with_event_log(event_log);
+                }
+                Err(e) => warn!("Fail2ban log disabled: {:#}", e),
+            }
+        }
+        let siem = security.siem.clone().and_then(|siem_config| match SiemExporter::spawn(siem_config) {
+            Ok(siem) => Some(Arc::new(siem)),
+            Err(e) => {
+                warn!("SIEM export disabled: {:#}", e);
+                None
+            }
+        });
+        if let Some(siem) = &siem {
+            ddos_protection = ddos_protection.with_siem(Arc::clone(siem));
+            fail2ban_manager = fail2ban_manager.with_siem(Arc::clone(siem));
+        }
+        let ddos_protection = Arc::new(ddos_protection);
+        let fail2ban_manager = Arc::new(fail2ban_manager);
+        let ban_store = Arc::new(
+            BanStore::new(security.ban_state_path.clone(), ip_table)
+                .with_network_blocks(&ddos_protection, &fail2ban_manager),
+        );
+        if let Err(e) = ban_store.restore(&rate_limiter, &ddos_protection, &fail2ban_manager) {
+            warn!("Ignoring unreadable ban state: {:#}", e);
+        }
+        let quota_manager = Arc::new(QuotaManager::new(config.security.quotas.clone(), &config.auth.users));
+        let sticky_sessions = Arc::new(StickySessionTable::new(config.routing.sticky_sessions.clone()));
+        let upstream_usage = Arc::new(UpstreamUsageTracker::new(&config.routing));
         let (shutdown_tx, _) = broadcast::channel(1);
+        let (listener_events, _) = broadcast::channel(16);
+        let reputation = Self::build_reputation(&config, None);
+        let client_geo = Self::build_client_geo(&config, None);
+        let honeypot = config.security.honeypot.enabled
+            .then(|| Arc::new(Honeypot::new(config.security.honeypot.clone())));
+        let rejector = Rejector::new(config.security.rejection.clone());
+        let prefilter = SecurityPrefilter::new(
+            Arc::clone(&rate_limiter),
+            Arc::clone(&ddos_protection),
+            Arc::clone(&fail2ban_manager),
+        )
+        .with_reputation(reputation.clone())
+        .with_client_geo(client_geo.clone());
+        
+        let mut relay_extensions = RelayExtensions::default();
+        if config.security.rate_limiting.enabled {
+            relay_extensions.rate_limiter = Some(rate_limiter.clone());
+        }
+        if quota_manager.is_enabled() {
+            relay_extensions.observers.push(quota_manager.clone());
+        }
+        if config.security.exfiltration.enabled {
+            match ExfiltrationMonitor::new(config.security.exfiltration.clone(), siem.clone()) {
+                Ok(monitor) => relay_extensions.observers.push(Arc::new(monitor)),
+                Err(e) => warn!("Exfiltration alerts disabled: {:#}", e),
+            }
+        }
+        if sticky_sessions.is_enabled() {
+            relay_extensions.sticky_sessions = Some(sticky_sessions.clone());
+        }
+        if upstream_usage.is_enabled() {
+            relay_extensions.observers.push(upstream_usage.clone());
+            relay_extensions.upstream_usage = Some(upstream_usage.clone());
+        }
+        let balancer = Arc::new(UpstreamBalancer::new(&config.routing));
+        if balancer.tracks_connections() {
+            relay_extensions.observers.push(balancer.clone());
+        }
+        relay_extensions.balancer = Some(balancer);
+        if config.routing.smart_routing.enabled {
+            relay_extensions.smart_routing = Some(Arc::new(SmartRoutingManager::new((&config.routing.smart_routing).into())));
+        }
+        if config.routing.qos.enabled {
+            let qos = &config.routing.qos;
+            relay_extensions.qos = Some(Arc::new(QosScheduler::new(qos.capacity, qos.min_share)));
+        }
+        if config.routing.upstream_pool.enabled {
+            let pool = UpstreamPool::new(config.routing.upstream_pool.clone(), config.server.connection_timeout);
+            relay_extensions.upstream_pool = Some(Arc::new(pool));
+        }
+        relay_extensions.tunnels = Arc::new(TunnelConnector::new(
+            config.server.egress_socket,
+            Keepalive::from_config(&config.server),
+        ));
+        relay_extensions.resolver = Arc::new(Resolver::from_config(&config.routing));
+        relay_extensions.router = Some(Self::build_router(&config, &relay_extensions));
         
         Self {
             listener: None,
@@ -63,14 +234,109 @@ impl ConnectionManager {
             rate_limiter,
             ddos_protection,
             fail2ban_manager,
+            ban_store,
+            reputation,
+            client_geo,
+            honeypot,
+            rejector,
+            siem,
+            quota_manager,
+            sticky_sessions,
+            upstream_usage,
+            prefilter,
             active_connections: Arc::new(AtomicUsize::new(0)),
             connection_tracker: Arc::new(RwLock::new(HashMap::new())),
             next_connection_id: Arc::new(AtomicUsize::new(1)),
             shutdown_flag: Arc::new(AtomicBool::new(false)),
             shutdown_tx,
+            listener_events,
+            relay_extensions,
         }
     }
 
+    /// Report live relay statistics to the shared metrics collector
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        if self.config.monitoring.collect_connection_stats {
+            self.relay_extensions.observers.push(metrics.clone());
+        }
+        self.relay_extensions.resolver = Arc::new(
+            Resolver::from_config(&self.config.routing).with_metrics(Some(metrics.clone()))
+        );
+        self.reputation = Self::build_reputation(&self.config, Some(metrics.clone()));
+        self.client_geo = Self::build_client_geo(&self.config, Some(metrics.clone()));
+        self.prefilter = self.prefilter.clone()
+            .with_reputation(self.reputation.clone())
+            .with_client_geo(self.client_geo.clone());
+        self.relay_extensions.metrics = Some(metrics);
+        self.relay_extensions.router = Some(Self::build_router(&self.config, &self.relay_extensions));
+        self
+    }
+
+    /// Reputation feed filter, if enabled and usable
+    fn build_reputation(config: &Config, metrics: Option<Arc<Metrics>>) -> Option<Arc<ReputationFilter>> {
+        let reputation = &config.security.reputation;
+        if !reputation.enabled || reputation.feeds.is_empty() {
+            return None;
+        }
+        match ReputationFilter::new(reputation.clone()) {
+            Ok(filter) => Some(Arc::new(filter.with_metrics(metrics))),
+            Err(e) => {
+                warn!("Reputation feeds disabled: {:#}", e);
+                None
+            }
+        }
+    }
+
+    /// Client country policy, if enabled
+    fn build_client_geo(config: &Config, metrics: Option<Arc<Metrics>>) -> Option<Arc<ClientGeoPolicy>> {
+        let client_geo = &config.security.client_geo;
+        if !client_geo.enabled {
+            return None;
+        }
+        let policy = ClientGeoPolicy::new(client_geo, config.security.failure_policies.geoip);
+        Some(Arc::new(policy.with_metrics(metrics)))
+    }
+
+    /// Reload routing rules and upstream proxies when the configuration changes
+    ///
+    /// Connections already routed keep their decision; new ones use the
+    /// reloaded rules.
+    pub fn watch_config(&self, changes: BroadcastStream<ConfigChangeEvent>) -> Option<JoinHandle<()>> {
+        self.relay_extensions.router.as_ref().map(|router| router.watch(changes))
+    }
+
+    /// Router sharing the resolver and the sticky, usage, balancing and smart routing state
+    fn build_router(config: &Arc<Config>, extensions: &RelayExtensions) -> Arc<Router> {
+        let mut router = Router::new(Arc::clone(config))
+            .with_resolver(Arc::clone(&extensions.resolver))
+            .with_metrics(extensions.metrics.clone());
+        if let Some(sticky_sessions) = extensions.sticky_sessions.clone() {
+            router = router.with_sticky_sessions(sticky_sessions);
+        }
+        if let Some(upstream_usage) = extensions.upstream_usage.clone() {
+            router = router.with_upstream_usage(upstream_usage);
+        }
+        if let Some(balancer) = extensions.balancer.clone() {
+            router = router.with_load_balancer(balancer);
+        }
+        if let Some(smart_routing) = extensions.smart_routing.clone() {
+            router = router.with_smart_routing(smart_routing);
+        }
+        Arc::new(router)
+    }
+
+    /// Report relay lifecycle and byte counts to another observer
+    pub fn with_observer(mut self, observer: Arc<dyn RelayObserver>) -> Self {
+        self.relay_extensions.observers.push(observer);
+        self
+    }
+
+    /// Make a relay transformer available to routing rules that name it
+    pub fn with_transformer(mut self, transformer: Arc<dyn RelayTransformer>) -> Self {
+        self.relay_extensions.transformers.register(transformer);
+        self
+    }
+
     /// Get the authentication manager
     pub fn auth_manager(&self) -> &Arc<AuthManager> {
         &self.auth_manager
@@ -83,8 +349,10 @@ impl ConnectionManager {
         let rate_limiter = Arc::clone(&self.rate_limiter);
         let ddos_protection = Arc::clone(&self.ddos_protection);
         let fail2ban_manager = Arc::clone(&self.fail2ban_manager);
-        let connection_tracker = Arc::clone(&self.connection_tracker);
-        let idle_timeout = self.config.server.idle_timeout;
+        let quota_manager = Arc::clone(&self.quota_manager);
+        let sticky_sessions = Arc::clone(&self.sticky_sessions);
+        let upstream_usage = Arc::clone(&self.upstream_usage);
+        let ban_store = Arc::clone(&self.ban_store);
         
         tokio::spawn(async move {
             let mut interval = tokio::time::interval(Duration::from_secs(60)); // Check every minute
@@ -92,7 +360,7 @@ impl ConnectionManager {
             loop {
                 interval.tick().await;
                 
-                debug!("Running periodic cleanup of expired sessions, rate limits, and idle connections");
+                debug!("Running periodic cleanup of expired sessions and rate limits");
                 
                 // Cleanup authentication data
                 auth_manager.cleanup_expired();
@@ -105,22 +373,26 @@ impl ConnectionManager {
                 ddos_protection.cleanup_old_entries();
                 fail2ban_manager.cleanup_old_entries();
                 
-                // Check for idle connections that should be closed
-                let mut idle_connections = Vec::new();
-                {
-                    let tracker = connection_tracker.read().await;
-                    for (conn_id, conn_info) in tracker.iter() {
-                        if conn_info.start_time.elapsed() > idle_timeout {
-                            idle_connections.push(conn_id.clone());
-                        }
+                // Persist quota usage and bans so a restart does not reset them; the
+                // file writes run on the blocking pool
+                let (quota, sticky, usage) = (quota_manager.clone(), sticky_sessions.clone(), upstream_usage.clone());
+                let bans = ban_store.clone();
+                let saved = crate::blocking::pool().run(move || {
+                    if let Err(e) = quota.save() {
+                        warn!("Failed to save quota usage: {:#}", e);
                     }
-                }
-                
-                if !idle_connections.is_empty() {
-                    warn!("Found {} idle connections exceeding timeout of {:?}", 
-                          idle_connections.len(), idle_timeout);
-                    // Note: In a real implementation, we would need a way to actually close these connections
-                    // For now, we just log them as they will be cleaned up when they naturally close
+                    if let Err(e) = sticky.save() {
+                        warn!("Failed to save sticky sessions: {:#}", e);
+                    }
+                    if let Err(e) = usage.save() {
+                        warn!("Failed to save upstream usage: {:#}", e);
+                    }
+                    if let Err(e) = bans.save() {
+                        warn!("Failed to save bans: {:#}", e);
+                    }
+                }).await;
+                if let Err(e) = saved {
+                    warn!("Failed to save proxy state: {:#}", e);
                 }
                 
                 let auth_stats = auth_manager.get_stats();
@@ -131,7 +403,37 @@ impl ConnectionManager {
             }
         });
         
-        info!("Started background cleanup task for authentication, resources, and idle connections");
+        info!("Started background cleanup task for authentication and resources");
+    }
+
+    /// Close connections that have moved no data for `idle_timeout`
+    ///
+    /// The sweep runs often enough that a connection outlives the timeout by
+    /// at most half of it.
+    fn start_idle_sweep(&self) {
+        let connection_tracker = Arc::clone(&self.connection_tracker);
+        let idle_timeout = self.config.server.idle_timeout;
+        
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval((idle_timeout / 2).clamp(Duration::from_millis(100), Duration::from_secs(60)));
+            
+            loop {
+                interval.tick().await;
+                
+                let tracker = connection_tracker.read().await;
+                let mut closed = 0;
+                for conn_info in tracker.values() {
+                    let idle = conn_info.relay.idle_for();
+                    if idle >= idle_timeout && conn_info.relay.cancel(format!("idle for {:?}", idle)) {
+                        debug!("Closing connection {} from {}: no data for {:?}", conn_info.id, conn_info.addr, idle);
+                        closed += 1;
+                    }
+                }
+                if closed > 0 {
+                    info!("Closed {} connections idle for longer than {:?}", closed, idle_timeout);
+                }
+            }
+        });
     }
 
     /// Start the connection manager and begin accepting connections
@@ -142,21 +444,46 @@ impl ConnectionManager {
         let listener = TcpListener::bind(bind_addr).await?;
         
         info!("Successfully bound to {}", bind_addr);
+        // Accepted sockets start with the listener's buffer sizes, so the
+        // handshake already offers a window to match
+        if let Err(e) = self.config.server.listener_socket.apply(socket2::SockRef::from(&listener)) {
+            warn!("Failed to set socket options on listener {}: {}", bind_addr, e);
+        }
         self.listener = Some(listener);
         
         // Start background cleanup task
         self.start_cleanup_task();
+        self.start_idle_sweep();
         
         // Start resource manager cleanup task
         Arc::clone(&self.resource_manager).start_cleanup_task();
         
+        if let Some(reputation) = &self.reputation {
+            reputation.spawn();
+            info!("Started reputation feed refresh every {:?}", self.config.security.reputation.refresh_interval);
+        }
+        
+        // Probe upstream proxies for smart routing; the checks end with the router
+        if let Some(router) = &self.relay_extensions.router {
+            if router.start_smart_routing_health_checks().await.is_some() {
+                info!("Started smart routing health checks");
+            }
+        }
+        if let Some(pool) = &self.relay_extensions.upstream_pool {
+            pool.start_pruning();
+            info!("Started upstream connection pool");
+        }
+        
         self.accept_connections().await
     }
 
     /// Main connection acceptance loop
-    async fn accept_connections(&self) -> Result<()> {
-        let listener = self.listener.as_ref()
+    async fn accept_connections(&mut self) -> Result<()> {
+        let mut listener = self.listener.take()
             .ok_or_else(|| anyhow::anyhow!("Listener not initialized"))?;
+        // Rebind to the port actually in use, even if the configured one was 0
+        let bind_addr = listener.local_addr().unwrap_or(self.config.server.bind_addr);
+        let mut exhaustion_backoff = Backoff::new(Duration::from_millis(10), Duration::from_secs(1));
 
         info!("Starting connection acceptance loop");
         let mut shutdown_rx = self.shutdown_tx.subscribe();
@@ -174,6 +501,7 @@ impl ConnectionManager {
                     match accept_result {
                         Ok((stream, addr)) => {
                             debug!("Accepted connection from {}", addr);
+                            exhaustion_backoff.reset();
                             
                             // Check if we're shutting down
                             if self.shutdown_flag.load(Ordering::Relaxed) {
@@ -181,49 +509,40 @@ impl ConnectionManager {
                                 continue;
                             }
 
-                            // Security checks: Rate limiting
-                            if !self.rate_limiter.check_connection_rate(addr.ip()) {
-                                warn!("Connection from {} blocked by rate limiter", addr);
-                                continue;
-                            }
-
-                            // Security checks: DDoS protection
-                            match self.ddos_protection.check_connection(addr.ip()) {
-                                DdosDecision::Allow => {
-                                    debug!("Connection from {} allowed by DDoS protection", addr);
-                                }
-                                DdosDecision::Block { reason, delay } => {
-                                    warn!("Connection from {} blocked by DDoS protection: {} (delay: {:?})", 
-                                          addr, reason, delay);
-                                    
-                                    // Apply delay if configured
-                                    if delay > Duration::from_millis(0) {
+                            // Security checks: fail2ban, rate limiting, and DDoS protection in one pass
+                            let queue_timeout = match self.prefilter.check(addr.ip()) {
+                                PrefilterDecision::Allow { delay, queue_timeout } => {
+                                    if delay > Duration::ZERO {
+                                        debug!("Applying delay of {:?} for connection from {}", delay, addr);
                                         tokio::time::sleep(delay).await;
                                     }
-                                    continue;
+                                    queue_timeout
                                 }
-                            }
-
-                            // Security checks: Fail2Ban
-                            match self.fail2ban_manager.check_auth_attempt(addr.ip()) {
-                                Fail2BanDecision::Allow => {
-                                    debug!("Connection from {} allowed by fail2ban", addr);
-                                }
-                                Fail2BanDecision::Block { reason, delay, .. } => {
-                                    warn!("Connection from {} blocked by fail2ban: {}", addr, reason);
+                                PrefilterDecision::Reject { reason, detail, delay } => {
+                                    let stream = match &self.honeypot {
+                                        Some(honeypot) if honeypot.diverts(reason) => {
+                                            honeypot.divert(stream, addr, reason).err()
+                                        }
+                                        _ => Some(stream),
+                                    };
+                                    if let Some(stream) = stream {
+                                        warn!("Connection from {} blocked [{}]: {}", addr, reason, detail);
+                                        self.rejector.reject(stream, reason);
+                                    } else {
+                                        warn!("Connection from {} diverted to honeypot [{}]: {}", addr, reason, detail);
+                                    }
+                                    Self::count_blocked(self.relay_extensions.metrics.as_ref(), reason, &detail);
+                                    if let Some(siem) = &self.siem {
+                                        siem.record(&SecurityEvent::ConnectionRejected { ip: addr.ip(), reason, detail });
+                                    }
                                     
                                     // Apply delay if configured
-                                    if delay > Duration::from_millis(0) {
+                                    if delay > Duration::ZERO {
                                         tokio::time::sleep(delay).await;
                                     }
                                     continue;
                                 }
-                                Fail2BanDecision::Delay { delay, reason } => {
-                                    debug!("Applying delay for connection from {}: {} ({:?})", 
-                                           addr, reason, delay);
-                                    tokio::time::sleep(delay).await;
-                                }
-                            }
+                            };
                             
                             // Try to acquire a connection slot from resource manager
                             let connection_slot = match self.resource_manager.acquire_connection_slot().await {
@@ -244,6 +563,7 @@ impl ConnectionManager {
                                 id: connection_id.clone(),
                                 addr,
                                 start_time: Instant::now(),
+                                relay: RelayHandle::new(),
                             };
 
                             // Spawn task to handle the connection
@@ -253,15 +573,27 @@ impl ConnectionManager {
                             let fail2ban_manager = Arc::clone(&self.fail2ban_manager);
                             let active_connections = Arc::clone(&self.active_connections);
                             let connection_tracker = Arc::clone(&self.connection_tracker);
-                            let shutdown_flag = Arc::clone(&self.shutdown_flag);
                             let shutdown_rx = self.shutdown_tx.subscribe();
+                            let quota_manager = Arc::clone(&self.quota_manager);
+                            let relay_extensions = self.relay_extensions.clone();
                             
                             tokio::spawn(async move {
                                 // Keep the connection slot alive for the duration of the connection
                                 let _connection_slot = connection_slot;
                                 
-                                // Record connection start for DDoS tracking
-                                ddos_protection.connection_started(addr.ip());
+                                // Wait for a per-IP slot without holding up the accept loop;
+                                // a slot that comes free is claimed as the connection's start
+                                if let Some(wait) = queue_timeout {
+                                    if !ddos_protection.wait_for_slot(addr.ip(), wait).await {
+                                        warn!("Connection from {} dropped [{}]: per-IP queue wait timed out",
+                                              addr, BlockReason::Ddos);
+                                        Self::count_blocked(relay_extensions.metrics.as_ref(), BlockReason::Ddos, "Per-IP queue wait timed out");
+                                        return;
+                                    }
+                                } else {
+                                    // Record connection start for DDoS tracking
+                                    ddos_protection.connection_started(addr.ip());
+                                }
                                 
                                 // Increment active connection count and track connection
                                 active_connections.fetch_add(1, Ordering::Relaxed);
@@ -272,15 +604,28 @@ impl ConnectionManager {
                                 
                                 info!("Started handling connection {} from {}", connection_id, addr);
                                 
-                                // Handle the connection with timeout and shutdown awareness
+                                // Handle the connection with shutdown awareness; the handshake
+                                // timeout stops applying once the relay starts
                                 let handshake_timeout = config.server.handshake_timeout;
-                                let result = timeout(
-                                    handshake_timeout,
-                                    Self::handle_connection_with_shutdown(
-                                        stream, addr, config, auth_manager, fail2ban_manager.clone(),
-                                        connection_id.clone(), shutdown_flag, shutdown_rx
-                                    )
-                                ).await;
+                                let handshake_expired = async {
+                                    tokio::time::sleep(handshake_timeout).await;
+                                    if conn_info.relay.is_relaying() {
+                                        std::future::pending::<()>().await;
+                                    }
+                                };
+                                let context = HandlerContext {
+                                    config,
+                                    auth_manager,
+                                    fail2ban_manager: fail2ban_manager.clone(),
+                                    quota_manager,
+                                    relay_extensions,
+                                };
+                                let result = tokio::select! {
+                                    result = Self::handle_connection_with_shutdown(
+                                        stream, addr, context, connection_id.clone(), conn_info.relay.clone(), shutdown_rx
+                                    ) => Ok(result),
+                                    () = handshake_expired => Err(()),
+                                };
                                 
                                 match result {
                                     Ok(Ok(())) => {
@@ -310,10 +655,37 @@ impl ConnectionManager {
                                 active_connections.fetch_sub(1, Ordering::Relaxed);
                             });
                         }
-                        Err(e) => {
-                            error!("Error accepting connection: {}", e);
-                            // Continue accepting connections even if one fails
-                        }
+                        Err(e) => match classify_accept_error(&e) {
+                            AcceptErrorClass::PerConnection => {
+                                debug!("Error accepting connection: {}", e);
+                            }
+                            AcceptErrorClass::ResourceExhausted => {
+                                let delay = exhaustion_backoff.next_delay();
+                                warn!("Cannot accept connections ({}), pausing for {:?}", e, delay);
+                                let _ = self.listener_events.send(ListenerEvent::AcceptBackoff {
+                                    error: e.to_string(),
+                                    delay,
+                                });
+                                tokio::time::sleep(delay).await;
+                            }
+                            AcceptErrorClass::Fatal => {
+                                error!("Listener on {} failed: {}", bind_addr, e);
+                                let _ = self.listener_events.send(ListenerEvent::ListenerLost {
+                                    addr: bind_addr,
+                                    error: e.to_string(),
+                                });
+                                // Release the broken socket so its address can be bound again
+                                drop(listener);
+                                match self.rebind(bind_addr, &mut shutdown_rx).await {
+                                    Some(rebound) => listener = rebound,
+                                    None => {
+                                        self.shutdown_flag.store(true, Ordering::Relaxed);
+                                        info!("Connection acceptance loop stopped while rebinding");
+                                        return Ok(());
+                                    }
+                                }
+                            }
+                        },
                     }
                 }
                 // Listen for shutdown signal
@@ -325,54 +697,109 @@ impl ConnectionManager {
             }
         }
         
+        self.listener = Some(listener);
         info!("Connection acceptance loop stopped");
         Ok(())
     }
 
+    /// Bind a replacement listener, retrying with backoff until it works or
+    /// shutdown begins
+    async fn rebind(&self, addr: SocketAddr, shutdown_rx: &mut broadcast::Receiver<()>) -> Option<TcpListener> {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(30));
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            match TcpListener::bind(addr).await {
+                Ok(listener) => {
+                    info!("Listener rebound to {} after {} attempt(s)", addr, attempt);
+                    let _ = self.listener_events.send(ListenerEvent::Rebound { addr, attempts: attempt });
+                    return Some(listener);
+                }
+                Err(e) => {
+                    let retry_in = backoff.next_delay();
+                    warn!("Rebinding listener to {} failed (attempt {}): {}, retrying in {:?}",
+                          addr, attempt, e, retry_in);
+                    let _ = self.listener_events.send(ListenerEvent::RebindFailed {
+                        addr,
+                        attempt,
+                        error: e.to_string(),
+                        retry_in,
+                    });
+                    tokio::select! {
+                        _ = tokio::time::sleep(retry_in) => {}
+                        _ = shutdown_rx.recv() => return None,
+                    }
+                }
+            }
+            if self.shutdown_flag.load(Ordering::Relaxed) {
+                return None;
+            }
+        }
+    }
+
     /// Handle a single connection with shutdown awareness
-    #[instrument(skip(stream, _config, auth_manager, fail2ban_manager, _shutdown_flag, shutdown_rx), fields(connection_id = %connection_id, addr = %addr))]
+    #[instrument(skip(stream, context, relay_handle, shutdown_rx), fields(connection_id = %connection_id, addr = %addr))]
     async fn handle_connection_with_shutdown(
         stream: TcpStream, 
         addr: SocketAddr, 
-        _config: Arc<Config>,
-        auth_manager: Arc<AuthManager>,
-        fail2ban_manager: Arc<Fail2BanManager>,
+        context: HandlerContext,
         connection_id: String,
-        _shutdown_flag: Arc<AtomicBool>,
+        relay_handle: RelayHandle,
         mut shutdown_rx: broadcast::Receiver<()>,
     ) -> Result<()> {
+        let connection = Self::handle_connection_static(stream, addr, context, connection_id.clone(), relay_handle.clone());
+        tokio::pin!(connection);
         tokio::select! {
-            result = Self::handle_connection_static(stream, addr, _config, auth_manager, fail2ban_manager, connection_id.clone()) => {
-                result
-            }
-            _ = shutdown_rx.recv() => {
-                info!("Connection {} received shutdown signal, closing gracefully", connection_id);
-                Ok(())
-            }
+            result = &mut connection => return result,
+            _ = shutdown_rx.recv() => {}
+        }
+        // A relay in flight may finish during the drain; the manager cancels
+        // it if it outlasts the shutdown timeout
+        if relay_handle.is_relaying() {
+            debug!("Connection {} draining for shutdown", connection_id);
+            connection.await
+        } else {
+            info!("Connection {} received shutdown signal before relaying, closing", connection_id);
+            Ok(())
         }
     }
 
     /// Handle a single connection (static method for use in spawned tasks)
-    #[instrument(skip(stream, config, auth_manager, fail2ban_manager), fields(connection_id = %connection_id, addr = %addr))]
+    #[instrument(skip(stream, context, relay_handle), fields(connection_id = %connection_id, addr = %addr))]
     async fn handle_connection_static(
         stream: TcpStream, 
         addr: SocketAddr, 
-        config: Arc<Config>,
-        auth_manager: Arc<AuthManager>,
-        fail2ban_manager: Arc<Fail2BanManager>,
+        context: HandlerContext,
         connection_id: String,
+        relay_handle: RelayHandle,
     ) -> Result<()> {
+        let HandlerContext { config, auth_manager, fail2ban_manager, quota_manager, relay_extensions } = context;
         debug!("Processing SOCKS5 connection {} from {}", connection_id, addr);
         
-        // Note: TCP keepalive configuration would require additional dependencies
-        // For now, we rely on OS defaults and connection timeouts
+        if let Err(e) = config.server.listener_socket.apply(socket2::SockRef::from(&stream)) {
+            debug!("Failed to set socket options for {}: {}", addr, e);
+        }
+        
+        // Probe the client once it goes quiet, so a vanished one is noticed
+        let keepalive = Keepalive::from_config(&config.server);
+        if let Some(keepalive) = keepalive {
+            if let Err(e) = keepalive.apply(&stream) {
+                debug!("Failed to enable keepalive for {}: {}", addr, e);
+            }
+        }
         
         let mut handler = Socks5Handler::new(stream);
+        let mut phases = PhaseTimer::start();
+        let mut timings = phases.timings();
+        
+        // One time budget covers every phase up to a connected target
+        let deadline = Deadline::after(config.server.connect_deadline);
         
         // Step 1: Handle SOCKS5 handshake
-        let auth_method = match handler.handle_handshake().await {
+        let auth_method = match deadline.run("handshake", handler.handle_handshake()).await? {
             Ok(method) => {
                 debug!("SOCKS5 handshake completed for {}, selected auth method: {:?}", addr, method);
+                timings.handshake_ms = phases.lap();
                 method
             }
             Err(e) => {
@@ -385,13 +812,13 @@ impl ConnectionManager {
         let auth_result = match auth_method {
             AuthMethod::NoAuth => {
                 // No authentication required
-                auth_manager.authenticate(AuthMethod::NoAuth, &[], addr.ip()).await?
+                deadline.run("authentication", auth_manager.authenticate(AuthMethod::NoAuth, &[], addr.ip())).await??
             }
             AuthMethod::UserPass => {
                 // Username/password authentication required
                 debug!("Performing username/password authentication for {}", addr);
                 
-                let credentials = match handler.handle_userpass_auth().await {
+                let credentials = match deadline.run("authentication", handler.handle_userpass_auth()).await? {
                     Ok(creds) => creds,
                     Err(e) => {
                         error!("Failed to read username/password credentials from {}: {}", addr, e);
@@ -399,8 +826,41 @@ impl ConnectionManager {
                         return Err(e);
                     }
                 };
+                
+                // Login attempts for one user are limited wherever they come from
+                if let (Some(limiter), Some(user)) = (&relay_extensions.rate_limiter, Self::claimed_username(&credentials)) {
+                    if !limiter.check_user_auth_rate(&user) {
+                        warn!("Authentication attempt from {} blocked [{}]: too many attempts for user '{}'",
+                              addr, BlockReason::RateLimit, Self::user_label(&config, Some(&user)));
+                        Self::count_blocked(relay_extensions.metrics.as_ref(), BlockReason::RateLimit, "Too many failed logins for user");
+                        handler.send_userpass_auth_response(false).await?;
+                        return Ok(());
+                    }
+                }
 
-                let auth_result = auth_manager.authenticate(AuthMethod::UserPass, &credentials, addr.ip()).await?;
+                let authenticated = deadline.run(
+                    "authentication",
+                    auth_manager.authenticate(AuthMethod::UserPass, &credentials, addr.ip()),
+                ).await?;
+                let auth_result = match authenticated {
+                    Ok(result) => result,
+                    Err(e) if config.security.failure_policies.auth_backend.allows() => {
+                        warn!("Auth backend unavailable for {}, allowing connection (fail-open): {}", addr, e);
+                        crate::auth::AuthResult {
+                            success: true,
+                            user_id: None,
+                            session_id: String::new(),
+                            roles: Vec::new(),
+                            quota_bytes: None,
+                            rejection: None,
+                        }
+                    }
+                    Err(e) => {
+                        error!("Auth backend unavailable for {}, rejecting connection (fail-closed): {}", addr, e);
+                        handler.send_userpass_auth_response(false).await?;
+                        return Err(e);
+                    }
+                };
                 
                 // Send authentication response
                 handler.send_userpass_auth_response(auth_result.success).await?;
@@ -409,7 +869,24 @@ impl ConnectionManager {
                     warn!("Authentication failed for connection from {}", addr);
                     
                     // Record authentication failure for fail2ban
-                    fail2ban_manager.record_auth_failure(addr.ip());
+                    if matches!(auth_result.rejection, None | Some(AuthRejection::SourceNetwork)) {
+                        let user = Self::claimed_username(&credentials)
+                            .filter(|user| !config.monitoring.privacy.excludes_user(Some(user)));
+                        fail2ban_manager.log_auth_failure(addr.ip(), user.as_deref());
+                    }
+                    // Only wrong passwords for configured users use up the user's login budget
+                    if let (Some(limiter), Some(user), None) = (&relay_extensions.rate_limiter, Self::claimed_username(&credentials), auth_result.rejection) {
+                        if auth_manager.user_exists(&user) {
+                            limiter.record_user_auth_failure(&user);
+                        }
+                    }
+                    match auth_result.rejection {
+                        Some(AuthRejection::SourceNetwork) => fail2ban_manager.record_source_rejection(addr.ip()),
+                        // Right credentials at the wrong time, or past their expiry, are not a
+                        // brute-force signal, and neither are logins nobody could check
+                        Some(AuthRejection::OutsideAccessWindow | AuthRejection::PasswordExpired | AuthRejection::Busy) => {}
+                        None => fail2ban_manager.record_auth_failure(addr.ip()),
+                    }
                     
                     return Ok(()); // Close connection
                 } else {
@@ -418,7 +895,7 @@ impl ConnectionManager {
                 }
                 
                 info!("Authentication successful for user '{}' from {}", 
-                      auth_result.user_id.as_deref().unwrap_or("unknown"), addr);
+                      Self::user_label(&config, auth_result.user_id.as_deref()), addr);
                 
                 auth_result
             }
@@ -428,10 +905,22 @@ impl ConnectionManager {
             }
         };
 
+        timings.auth_ms = phases.lap();
+
+        // Groups used by routing rules, ACLs, and group bandwidth limits; token roles count as groups
+        let groups = config.auth.resolve_groups(auth_result.user_id.as_deref(), &auth_result.roles);
+
+        // A quota granted by the identity provider applies from this login on
+        if let (Some(user), Some(bytes)) = (auth_result.user_id.as_deref(), auth_result.quota_bytes) {
+            quota_manager.set_claimed_quota(user, bytes);
+        }
+
         // Step 3: Handle SOCKS5 request
-        let command = match handler.handle_request().await {
+        let command = match deadline.run("request", handler.handle_request()).await? {
             Ok(cmd) => {
                 debug!("SOCKS5 request received from {}: {:?}", addr, cmd);
+                // Reading the request completes the SOCKS5 handshake
+                timings.handshake_ms += phases.lap();
                 cmd
             }
             Err(e) => {
@@ -440,47 +929,288 @@ impl ConnectionManager {
             }
         };
 
+        // Each user gets their own connection budget, apart from their address's
+        if let (Some(limiter), Some(user)) = (&relay_extensions.rate_limiter, auth_result.user_id.as_deref()) {
+            if !limiter.check_user_connection_rate(user) {
+                warn!("Request from {} blocked [{}]: connection rate limit exceeded for user '{}'",
+                      addr, BlockReason::RateLimit, Self::user_label(&config, Some(user)));
+                Self::count_blocked(relay_extensions.metrics.as_ref(), BlockReason::RateLimit, "User connection rate limit exceeded");
+                let response = crate::protocol::Socks5Response::error(
+                    crate::protocol::constants::SOCKS5_REPLY_CONNECTION_NOT_ALLOWED
+                );
+                let _ = handler.send_response(response).await;
+                return Ok(());
+            }
+        }
+
         // Step 4: Process the command (only CONNECT is supported for now)
         match command {
             crate::protocol::Socks5Command::Connect { addr: target_addr, port } => {
-                // Create router for access control and routing decisions
-                let router = Router::new(Arc::clone(&config));
+                // Destinations and users excluded for privacy are only counted in aggregate
+                let redacted = config.monitoring.privacy.excludes(&target_addr, auth_result.user_id.as_deref());
+                let target_label = Self::target_label(&target_addr, port, redacted);
+                
+                // Refuse new connections for users who have used up their quota
+                if let Some(user) = auth_result.user_id.as_deref() {
+                    if let Some(period) = quota_manager.check(user) {
+                        warn!("Connection to {} blocked [{}] for {}: {} quota exceeded for user '{}'",
+                              target_label, BlockReason::Quota, addr, period, Self::user_label(&config, Some(user)));
+                        Self::count_blocked(relay_extensions.metrics.as_ref(), BlockReason::Quota, &format!("{} quota exceeded", period));
+                        let response = crate::protocol::Socks5Response::error(
+                            crate::protocol::constants::SOCKS5_REPLY_CONNECTION_NOT_ALLOWED
+                        );
+                        let _ = handler.send_response(response).await;
+                        return Ok(());
+                    }
+                }
+                
+                // Shared router for access control and routing decisions
+                let router = Self::connection_router(&config, &relay_extensions);
                 
                 // Make routing decision
-                let route_decision = router.route_request(
+                let route_decision = deadline.run("routing", router.route_request(
                     &target_addr, 
                     port, 
                     addr.ip(), 
-                    auth_result.user_id.as_deref()
-                ).await;
+                    auth_result.user_id.as_deref(),
+                    &groups
+                )).await?;
+                timings.routing_ms = phases.lap();
+                
+                // Redirects and rewrites connect directly to the rule's target in place of the requested one
+                let direct = |rule, allow_private| RouteDecision::Allow {
+                    rule,
+                    upstream: None,
+                    chain: Vec::new(),
+                    dscp: None,
+                    bandwidth: crate::relay::BandwidthLimit::default(),
+                    bandwidth_class: None,
+                    transformers: Vec::new(),
+                    dns_resolution: None,
+                    allow_private,
+                    traffic_class: None,
+                    egress_bind_addr: None,
+                    fwmark: None,
+                };
+                let mut redirect = None;
+                let mut rewrite = None;
+                let route_decision = match route_decision {
+                    RouteDecision::Redirect { target: redirect_addr, rule } => {
+                        let redirect_port = match redirect_addr.port() {
+                            0 => port,
+                            redirect_port => redirect_port,
+                        };
+                        let redirect_addr = SocketAddr::new(redirect_addr.ip(), redirect_port);
+                        info!("Connection to {} redirected to {} for {}", 
+                              target_label, Self::addr_label(redirect_addr, redacted), addr);
+                        if let Some(metrics) = &relay_extensions.metrics {
+                            metrics.record_redirect();
+                        }
+                        redirect = Some(redirect_addr);
+                        // The rule names the address outright, so it may be a private one
+                        direct(rule, true)
+                    }
+                    RouteDecision::Rewrite { target: rewritten, rule, allow_private } => {
+                        info!("Connection to {} rewritten to {} for {}", 
+                              target_label, Self::target_label(&rewritten, port, redacted), addr);
+                        if let Some(metrics) = &relay_extensions.metrics {
+                            metrics.record_rewrite();
+                        }
+                        rewrite = Some(rewritten);
+                        direct(rule, allow_private)
+                    }
+                    decision => decision,
+                };
+                let (connect_addr, connect_port) = match (redirect, &rewrite) {
+                    (Some(redirect_addr), _) => (crate::protocol::TargetAddr::from_socket_addr(&redirect_addr), redirect_addr.port()),
+                    (None, Some(rewritten)) => (rewritten.clone(), port),
+                    (None, None) => (target_addr.clone(), port),
+                };
                 
                 match route_decision {
-                    RouteDecision::Allow { upstream } => {
+                    RouteDecision::Allow { rule, upstream, chain, dscp, bandwidth, bandwidth_class, transformers, dns_resolution, allow_private, traffic_class, egress_bind_addr, fwmark } => {
                         // Connection is allowed, proceed with establishing target connection
-                        debug!("Connection to {}:{} allowed for {}", 
-                               Self::target_to_string(&target_addr), port, addr);
+                        debug!("Connection to {} allowed for {}", target_label, addr);
+                        
+                        // Apply the tightest of the rule's, the user's, and their groups' rate caps,
+                        // and share the user's total cap with their other relays
+                        let user_bandwidth = config.auth.bandwidth_for(auth_result.user_id.as_deref(), &groups);
+                        let user_class = auth_result.user_id.as_deref().and_then(|user| {
+                            relay_extensions.user_bandwidth.class_for(user, config.auth.total_bandwidth_for(user))
+                        });
+                        
+                        // Place the connection in the rule's traffic class, or else its groups'
+                        let qos_lane = relay_extensions.qos.as_ref().map(|qos| {
+                            qos.lane(traffic_class.or_else(|| config.auth.traffic_class_for(&groups)).unwrap_or_default())
+                        });
+                        let dscp = dscp.or_else(|| {
+                            qos_lane.as_ref().filter(|_| config.routing.qos.mark_dscp).and_then(|lane| lane.class().dscp())
+                        });
                         
-                        // Create relay engine
-                        let relay_engine = RelayEngine::from_config(&config);
+                        // Create relay engine, marking outbound traffic if the route asks for it
+                        let mut relay_engine = RelayEngine::from_config(&config)
+                            .with_dscp(dscp)
+                            .with_bandwidth_limit(bandwidth.tighter(user_bandwidth))
+                            .with_bandwidth_class(bandwidth_class.clone())
+                            .with_bandwidth_class(user_class)
+                            .with_traffic_class(qos_lane.clone())
+                            .with_redaction(redacted)
+                            .with_private_range_protection(config.security.private_ranges.enabled && !allow_private)
+                            .with_upstreams(upstream.iter().chain(&chain).map(|proxy| proxy.addr).collect())
+                            .with_resolver(Arc::clone(&relay_extensions.resolver))
+                            .with_deadline(deadline)
+                            .with_handle(relay_handle)
+                            .with_egress_bind(egress_bind_addr
+                                .or_else(|| config.auth.egress_bind_addr_for(auth_result.user_id.as_deref()).cloned())
+                                .or_else(|| config.routing.egress_bind_addr.clone()))
+                            .with_fwmark(fwmark);
+                        for observer in relay_extensions.observers {
+                            relay_engine = relay_engine.with_observer(observer);
+                        }
+                        for transformer in relay_extensions.transformers.resolve(&transformers) {
+                            relay_engine = relay_engine.with_transformer(transformer);
+                        }
+                        
+                        // Force re-authentication once the session reaches its maximum lifetime
+                        if let (Some(max_lifetime), Some(session)) = (
+                            config.auth.session_max_lifetime,
+                            auth_manager.get_session(&auth_result.session_id),
+                        ) {
+                            let remaining = max_lifetime.saturating_sub(session.created_at.elapsed());
+                            relay_engine = relay_engine.with_max_lifetime(remaining, config.auth.on_session_expired);
+                        }
+                        
+                        // Close the relay when a time-restricted user's access window ends
+                        if let Some(remaining) = auth_result.user_id.as_deref()
+                            .and_then(|user| auth_manager.access_remaining(user))
+                        {
+                            relay_engine = relay_engine.with_access_window_end(remaining);
+                        }
+                        
+                        let mut target = TargetContext::new(&target_addr, port);
+                        target.upstreams = upstream.iter().chain(&chain).map(|proxy| proxy.addr).collect();
+                        target.redirect = redirect;
+                        target.rewrite = rewrite.as_ref().map(Self::target_to_string);
+                        // Direct connections always resolve here; upstreams may do it themselves
+                        let dns_resolution = match upstream {
+                            Some(_) => dns_resolution.unwrap_or(config.routing.dns_resolution),
+                            None => DnsResolution::Local,
+                        };
+                        let routing = RoutingContext {
+                            rule,
+                            dscp,
+                            transformers: transformers.clone(),
+                            dns_resolution,
+                            bandwidth_class: bandwidth_class.as_ref().map(|class| class.name().to_string()),
+                            traffic_class: qos_lane.as_ref().map(|lane| lane.class()),
+                        };
                         
                         // Establish connection to target (either direct or through upstream proxy)
                         let target_stream = match upstream {
                             Some(upstream_proxy) => {
-                                // Connect through upstream proxy
-                                debug!("Connecting to {}:{} through upstream proxy {:?}", 
-                                       Self::target_to_string(&target_addr), port, upstream_proxy.addr);
+                                // Connect through the upstream proxy and any further chain hops
+                                debug!("Connecting to {} through upstream proxy {:?} ({} further hop(s))", 
+                                       target_label, upstream_proxy.addr, chain.len());
                                 
-                                // For now, implement direct connection
-                                // TODO: Implement upstream proxy chaining in future enhancement
-                                match relay_engine.connect_to_target(&target_addr, port).await {
-                                    Ok((stream, resolved_addr)) => {
-                                        info!("Connected to target {} (resolved to {})", 
-                                              Self::target_to_string(&target_addr), resolved_addr);
+                                // Fill per-connection options into upstream credentials
+                                let egress = EgressContext {
+                                    user: auth_result.user_id.as_deref(),
+                                    session: Some(&auth_result.session_id),
+                                    country: config.auth.egress_country_for(auth_result.user_id.as_deref()),
+                                };
+                                let proxy_addr = upstream_proxy.addr;
+                                // A tunnel carries the connection on its own; chained ones are refused by the connector
+                                let tunnel = match &upstream_proxy.protocol {
+                                    ProxyProtocol::Tunnel(settings) if chain.is_empty() => Some(settings.clone()),
+                                    _ => None,
+                                };
+                                let mut connector = ProxyChainConnector::new(ProxyChain {
+                                    proxies: std::iter::once(upstream_proxy).chain(chain)
+                                        .map(|proxy| proxy.for_egress(&egress))
+                                        .collect(),
+                                    connection_timeout: deadline.cap(config.server.connection_timeout),
+                                });
+                                if let Some(pool) = &relay_extensions.upstream_pool {
+                                    connector = connector.with_pool(pool.clone());
+                                }
+                                // With local DNS the upstream only ever sees an IP address
+                                let chain_target = match &target_addr {
+                                    crate::protocol::TargetAddr::Domain(_) if dns_resolution == DnsResolution::Local => {
+                                        match relay_engine.resolve_target_address(&target_addr, port).await {
+                                            Ok(addrs) => {
+                                                target.resolved = addrs.first().copied();
+                                                crate::protocol::TargetAddr::from_socket_addr(&addrs[0])
+                                            }
+                                            Err(e) => {
+                                                error!("Failed to resolve target {} for upstream proxy {}: {:#}", target_label, proxy_addr, e);
+                                                let error_code = relay_engine.connection_error_to_socks5_code(&e);
+                                                let _ = handler.send_response(crate::protocol::Socks5Response::error(error_code)).await;
+                                                return Err(e);
+                                            }
+                                        }
+                                    }
+                                    _ => target_addr.clone(),
+                                };
+                                // The chain must not pass through this proxy, and the target must not lead back into it
+                                let looped = relay_engine.check_chain_loop().and_then(|()| match &chain_target {
+                                    crate::protocol::TargetAddr::Ipv4(ip) => relay_engine.check_loop(SocketAddr::new((*ip).into(), port)),
+                                    crate::protocol::TargetAddr::Ipv6(ip) => relay_engine.check_loop(SocketAddr::new((*ip).into(), port)),
+                                    crate::protocol::TargetAddr::Domain(_) => Ok(()),
+                                });
+                                if let Err(proxy_loop) = looped {
+                                    let e = anyhow::Error::new(proxy_loop);
+                                    Self::report_refused_target(relay_extensions.metrics.as_ref(), &e, &target_label, addr);
+                                    let error_code = relay_engine.connection_error_to_socks5_code(&e);
+                                    let _ = handler.send_response(crate::protocol::Socks5Response::error(error_code)).await;
+                                    return Ok(());
+                                }
+                                let started = Instant::now();
+                                let connecting = async {
+                                    match &tunnel {
+                                        Some(settings) => relay_extensions.tunnels
+                                            .open(proxy_addr, settings, &chain_target, port, deadline.cap(config.server.connection_timeout))
+                                            .await
+                                            .map(TargetStream::Tunnel),
+                                        None => connector.connect_through_chain(&chain_target, port).await.map(TargetStream::Tcp),
+                                    }
+                                };
+                                let connected = match deadline.run("connect", connecting).await {
+                                    Ok(connected) => connected,
+                                    Err(exceeded) => Err(exceeded.into()),
+                                };
+                                if let (Some(balancer), Ok(_)) = (&relay_extensions.balancer, &connected) {
+                                    balancer.record_latency(proxy_addr, started.elapsed());
+                                }
+                                // Score the first upstream for smart routing; a refusal to reach
+                                // the target is still an answer, and later hops are not its fault
+                                let upstream_ok = match &connected {
+                                    Ok(_) => Some(true),
+                                    Err(e) if e.downcast_ref::<ChainHop>().is_some_and(|at| at.hop > 1) => None,
+                                    Err(e) => Some(matches!(
+                                        e.downcast_ref::<UpstreamError>(),
+                                        Some(UpstreamError::Refused { .. } | UpstreamError::HttpRefused { .. })
+                                    )),
+                                };
+                                if let Some(success) = upstream_ok {
+                                    router.record_upstream_result(proxy_addr, started.elapsed(), success).await;
+                                }
+                                if let Some(metrics) = &relay_extensions.metrics {
+                                    let failed_hop = connected.as_ref().err()
+                                        .map(|e| e.downcast_ref::<ChainHop>().map_or(1, |at| at.hop));
+                                    metrics.record_upstream_connect(connector.hops(), failed_hop, started.elapsed());
+                                }
+                                match connected {
+                                    Ok(stream) => {
+                                        info!("Connected to target {} through upstream proxy {}", target_label, proxy_addr);
+                                        if let Some(tcp) = stream.as_tcp() {
+                                            if let Err(e) = config.server.egress_socket.apply(socket2::SockRef::from(tcp)) {
+                                                debug!("Failed to set socket options on connection to {}: {}", proxy_addr, e);
+                                            }
+                                        }
                                         stream
                                     }
                                     Err(e) => {
-                                        error!("Failed to connect to target {}:{}: {}", 
-                                               Self::target_to_string(&target_addr), port, e);
+                                        error!("Failed to connect to target {} through upstream proxy {}: {:#}", target_label, proxy_addr, e);
                                         
                                         // Send appropriate SOCKS5 error response
                                         let error_code = relay_engine.connection_error_to_socks5_code(&e);
@@ -492,29 +1222,65 @@ impl ConnectionManager {
                             }
                             None => {
                                 // Direct connection
-                                debug!("Connecting directly to {}:{}", 
-                                       Self::target_to_string(&target_addr), port);
+                                debug!("Connecting directly to {}", target_label);
                                 
-                                match relay_engine.connect_to_target(&target_addr, port).await {
+                                match relay_engine.connect_to_target(&connect_addr, connect_port).await {
                                     Ok((stream, resolved_addr)) => {
                                         info!("Connected to target {} (resolved to {})", 
-                                              Self::target_to_string(&target_addr), resolved_addr);
-                                        stream
+                                              target_label, Self::addr_label(resolved_addr, redacted));
+                                        target.resolved = Some(resolved_addr);
+                                        TargetStream::Tcp(stream)
                                     }
                                     Err(e) => {
-                                        error!("Failed to connect to target {}:{}: {}", 
-                                               Self::target_to_string(&target_addr), port, e);
+                                        let refused = Self::report_refused_target(relay_extensions.metrics.as_ref(), &e, &target_label, addr);
+                                        if !refused {
+                                            error!("Failed to connect to target {}: {}", target_label, e);
+                                        }
                                         
                                         // Send appropriate SOCKS5 error response
                                         let error_code = relay_engine.connection_error_to_socks5_code(&e);
                                         let response = crate::protocol::Socks5Response::error(error_code);
                                         let _ = handler.send_response(response).await;
-                                        return Err(e);
+                                        return if refused { Ok(()) } else { Err(e) };
                                     }
                                 }
                             }
                         };
                         
+                        timings.connect_ms = phases.lap();
+                        
+                        // Describe the connection to observers, transformers and the session log
+                        let target_ip = target.resolved.map(|resolved| resolved.ip()).or(match &connect_addr {
+                            crate::protocol::TargetAddr::Ipv4(ip) => Some((*ip).into()),
+                            crate::protocol::TargetAddr::Ipv6(ip) => Some((*ip).into()),
+                            crate::protocol::TargetAddr::Domain(_) => None,
+                        });
+                        // Database reads may fault in cold pages, so they run on the blocking pool
+                        let geo = match router.geoip_handle().cloned() {
+                            Some(geoip) => {
+                                let client_ip = addr.ip();
+                                crate::blocking::pool()
+                                    .run(move || GeoContext::lookup(Some(&geoip), client_ip, target_ip))
+                                    .await
+                                    .unwrap_or_else(|e| {
+                                        debug!("Skipping GeoIP lookup for connection context: {:#}", e);
+                                        GeoContext::default()
+                                    })
+                            }
+                            None => GeoContext::default(),
+                        };
+                        relay_engine = relay_engine.with_context(ConnectionContext {
+                            version: CONTEXT_VERSION,
+                            connection_id: connection_id.clone(),
+                            client: ClientContext { addr },
+                            user: auth_result.user_id.clone().map(|id| UserContext { id, groups: groups.clone() }),
+                            geo,
+                            target,
+                            routing,
+                            timings,
+                            redacted,
+                        });
+                        
                         // Send success response to client
                         let response = crate::protocol::Socks5Response::success(
                             crate::protocol::TargetAddr::Ipv4(std::net::Ipv4Addr::new(0, 0, 0, 0)),
@@ -528,10 +1294,42 @@ impl ConnectionManager {
                         
                         // Get the client stream back from the handler
                         let client_stream = handler.into_stream();
+                        // Tunnels have keepalive of their own
+                        if let (Some(keepalive), Some(tcp)) = (keepalive, target_stream.as_tcp()) {
+                            if let Err(e) = keepalive.apply(tcp) {
+                                debug!("Failed to enable keepalive for connection {} to {}: {}", connection_id, target_label, e);
+                            }
+                        }
+                        
+                        // A CONNECT to an IP address can still name a blocked site in its TLS hello
+                        let sni = &config.routing.sni_sniffing;
+                        if sni.enabled
+                            && !matches!(target_addr, crate::protocol::TargetAddr::Domain(_))
+                            && crate::routing::ports::ports_contain(&sni.ports, port)
+                        {
+                            if let Some(server_name) = crate::protocol::sni::peek_server_name(&client_stream, sni.timeout).await {
+                                let sniffed = crate::protocol::TargetAddr::Domain(server_name);
+                                debug!("Connection {} asks for TLS server name {}", 
+                                       connection_id, Self::target_label(&sniffed, port, redacted));
+                                let decision = router.route_request(
+                                    &sniffed,
+                                    port,
+                                    addr.ip(),
+                                    auth_result.user_id.as_deref(),
+                                    &groups
+                                ).await;
+                                if let RouteDecision::Block { reason, code } = decision {
+                                    warn!("Connection to {} blocked [{}] for {} by its TLS server name {}: {}", 
+                                          target_label, code, addr, Self::target_label(&sniffed, port, redacted), reason);
+                                    Self::count_blocked(relay_extensions.metrics.as_ref(), code, &reason);
+                                    return Ok(());
+                                }
+                            }
+                        }
                         
                         // Start complete data relay with bidirectional transfer
-                        info!("Starting complete data relay for connection {} from {} to {}:{}", 
-                              connection_id, addr, Self::target_to_string(&target_addr), port);
+                        info!("Starting complete data relay for connection {} from {} to {}", 
+                              connection_id, addr, target_label);
                         
                         // Start the complete relay session with immediate data transfer
                         match relay_engine.start_complete_relay_with_user(
@@ -543,6 +1341,7 @@ impl ConnectionManager {
                                 info!("SOCKS5 connection {} relay completed successfully: {} bytes up, {} bytes down in {:?}", 
                                       connection_id, stats.bytes_up, stats.bytes_down, 
                                       std::time::Duration::from_millis(stats.duration_ms));
+                                auth_manager.record_session_bytes(&auth_result.session_id, stats.bytes_up, stats.bytes_down);
                             }
                             Err(e) => {
                                 error!("SOCKS5 connection {} relay failed: {}", connection_id, e);
@@ -550,9 +1349,10 @@ impl ConnectionManager {
                             }
                         }
                     }
-                    RouteDecision::Block { reason } => {
-                        warn!("Connection to {}:{} blocked for {}: {}", 
-                              Self::target_to_string(&target_addr), port, addr, reason);
+                    RouteDecision::Block { reason, code } => {
+                        warn!("Connection to {} blocked [{}] for {}: {}", 
+                              target_label, code, addr, reason);
+                        Self::count_blocked(relay_extensions.metrics.as_ref(), code, &reason);
                         
                         // Send connection not allowed response
                         let response = crate::protocol::Socks5Response::error(
@@ -561,33 +1361,26 @@ impl ConnectionManager {
                         let _ = handler.send_response(response).await;
                         return Ok(());
                     }
-                    RouteDecision::Redirect { target: redirect_addr } => {
-                        info!("Connection to {}:{} redirected to {} for {}", 
-                              Self::target_to_string(&target_addr), port, redirect_addr, addr);
-                        
-                        // For redirect, we would need to establish connection to redirect target
-                        // For now, treat as block
-                        let response = crate::protocol::Socks5Response::error(
-                            crate::protocol::constants::SOCKS5_REPLY_GENERAL_FAILURE
-                        );
-                        let _ = handler.send_response(response).await;
-                        return Ok(());
+                    RouteDecision::Redirect { .. } | RouteDecision::Rewrite { .. } => {
+                        unreachable!("redirects and rewrites are connected as allowed routes")
                     }
                 }
             }
             crate::protocol::Socks5Command::Bind { addr: bind_addr, port: bind_port } => {
-                info!("BIND command requested by {} for {}:{}", addr, 
-                      Self::target_to_string(&bind_addr), bind_port);
+                let redacted = config.monitoring.privacy.excludes(&bind_addr, auth_result.user_id.as_deref());
+                let bind_label = Self::target_label(&bind_addr, bind_port, redacted);
+                info!("BIND command requested by {} for {}", addr, bind_label);
                 
-                // Create router for access control
-                let router = Router::new(Arc::clone(&config));
+                // Shared router for access control
+                let router = Self::connection_router(&config, &relay_extensions);
                 
                 // Check if BIND is allowed
                 let route_decision = router.route_request(
                     &bind_addr, 
                     bind_port, 
                     addr.ip(), 
-                    auth_result.user_id.as_deref()
+                    auth_result.user_id.as_deref(),
+                    &groups
                 ).await;
                 
                 match route_decision {
@@ -607,17 +1400,17 @@ impl ConnectionManager {
                             }
                         }
                     }
-                    RouteDecision::Block { reason } => {
-                        warn!("BIND to {}:{} blocked for {}: {}", 
-                              Self::target_to_string(&bind_addr), bind_port, addr, reason);
+                    RouteDecision::Block { reason, code } => {
+                        warn!("BIND to {} blocked [{}] for {}: {}", bind_label, code, addr, reason);
+                        Self::count_blocked(relay_extensions.metrics.as_ref(), code, &reason);
                         let response = crate::protocol::Socks5Response::error(
                             crate::protocol::constants::SOCKS5_REPLY_CONNECTION_NOT_ALLOWED
                         );
                         let _ = handler.send_response(response).await;
                         return Ok(());
                     }
-                    RouteDecision::Redirect { .. } => {
-                        warn!("BIND redirect not supported for {}", addr);
+                    RouteDecision::Redirect { .. } | RouteDecision::Rewrite { .. } => {
+                        warn!("BIND redirect or rewrite not supported for {}", addr);
                         let response = crate::protocol::Socks5Response::error(
                             crate::protocol::constants::SOCKS5_REPLY_GENERAL_FAILURE
                         );
@@ -627,24 +1420,33 @@ impl ConnectionManager {
                 }
             }
             crate::protocol::Socks5Command::UdpAssociate { addr: udp_addr, port: udp_port } => {
-                info!("UDP ASSOCIATE command requested by {} for {}:{}", addr, 
-                      Self::target_to_string(&udp_addr), udp_port);
+                let redacted = config.monitoring.privacy.excludes(&udp_addr, auth_result.user_id.as_deref());
+                let udp_label = Self::target_label(&udp_addr, udp_port, redacted);
+                info!("UDP ASSOCIATE command requested by {} for {}", addr, udp_label);
                 
-                // Create router for access control
-                let router = Router::new(Arc::clone(&config));
+                // Shared router for access control
+                let router = Self::connection_router(&config, &relay_extensions);
                 
                 // Check if UDP ASSOCIATE is allowed
                 let route_decision = router.route_request(
                     &udp_addr, 
                     udp_port, 
                     addr.ip(), 
-                    auth_result.user_id.as_deref()
+                    auth_result.user_id.as_deref(),
+                    &groups
                 ).await;
                 
                 match route_decision {
                     RouteDecision::Allow { .. } => {
-                        // Implement UDP ASSOCIATE command
-                        match Self::handle_udp_associate_command(&udp_addr, udp_port, &mut handler).await {
+                        // Each datagram's target is routed again for this client
+                        let route = UdpRouteContext {
+                            router,
+                            client_ip: addr.ip(),
+                            user: auth_result.user_id.clone(),
+                            groups: groups.clone(),
+                        };
+                        let udp_relay = Self::handle_udp_associate_command(&udp_addr, udp_port, route, &config, &relay_extensions, relay_handle, &mut handler);
+                        match udp_relay.await {
                             Ok(()) => {
                                 info!("UDP ASSOCIATE command completed successfully for {}", addr);
                             }
@@ -658,17 +1460,17 @@ impl ConnectionManager {
                             }
                         }
                     }
-                    RouteDecision::Block { reason } => {
-                        warn!("UDP ASSOCIATE to {}:{} blocked for {}: {}", 
-                              Self::target_to_string(&udp_addr), udp_port, addr, reason);
+                    RouteDecision::Block { reason, code } => {
+                        warn!("UDP ASSOCIATE to {} blocked [{}] for {}: {}", udp_label, code, addr, reason);
+                        Self::count_blocked(relay_extensions.metrics.as_ref(), code, &reason);
                         let response = crate::protocol::Socks5Response::error(
                             crate::protocol::constants::SOCKS5_REPLY_CONNECTION_NOT_ALLOWED
                         );
                         let _ = handler.send_response(response).await;
                         return Ok(());
                     }
-                    RouteDecision::Redirect { .. } => {
-                        warn!("UDP ASSOCIATE redirect not supported for {}", addr);
+                    RouteDecision::Redirect { .. } | RouteDecision::Rewrite { .. } => {
+                        warn!("UDP ASSOCIATE redirect or rewrite not supported for {}", addr);
                         let response = crate::protocol::Socks5Response::error(
                             crate::protocol::constants::SOCKS5_REPLY_GENERAL_FAILURE
                         );
@@ -686,12 +1488,54 @@ impl ConnectionManager {
 
         info!("SOCKS5 connection {} from {} completed successfully (user: {}, session: {})", 
               connection_id, addr, 
-              auth_result.user_id.as_deref().unwrap_or("anonymous"),
+              Self::user_label(&config, auth_result.user_id.as_deref()),
               auth_result.session_id);
         
         Ok(())
     }
 
+    /// The shared router, or a fresh one if the extensions carry none
+    fn connection_router(config: &Arc<Config>, extensions: &RelayExtensions) -> Arc<Router> {
+        match &extensions.router {
+            Some(router) => Arc::clone(router),
+            None => Self::build_router(config, extensions),
+        }
+    }
+
+    /// Destination for log lines, hidden when privacy settings exclude the connection
+    fn target_label(target: &crate::protocol::TargetAddr, port: u16, redacted: bool) -> String {
+        if redacted {
+            crate::config::PrivacyConfig::REDACTED.to_string()
+        } else {
+            format!("{}:{}", Self::target_to_string(target), port)
+        }
+    }
+
+    /// Resolved address for log lines, hidden when privacy settings exclude the connection
+    fn addr_label(addr: SocketAddr, redacted: bool) -> String {
+        if redacted {
+            crate::config::PrivacyConfig::REDACTED.to_string()
+        } else {
+            addr.to_string()
+        }
+    }
+
+    /// Username for log lines, hidden for users excluded by privacy settings
+    fn user_label<'a>(config: &Config, user: Option<&'a str>) -> &'a str {
+        match user {
+            Some(_) if config.monitoring.privacy.excludes_user(user) => crate::config::PrivacyConfig::REDACTED,
+            Some(user) => user,
+            None => "anonymous",
+        }
+    }
+
+    /// Username a client sent in its RFC 1929 request, whether or not it exists
+    fn claimed_username(credentials: &[u8]) -> Option<String> {
+        let len = *credentials.get(1)? as usize;
+        let username = credentials.get(2..2 + len)?;
+        Some(String::from_utf8_lossy(username).into_owned())
+    }
+
     /// Convert TargetAddr to string for logging
     fn target_to_string(target: &crate::protocol::TargetAddr) -> String {
         match target {
@@ -791,37 +1635,49 @@ impl ConnectionManager {
         }
     }
 
+    /// Log and count a connection refused for leading back into the proxy or
+    /// into a private range
+    ///
+    /// Returns `false`, doing nothing, for any other connect error.
+    fn report_refused_target(metrics: Option<&Arc<Metrics>>, error: &anyhow::Error, target_label: &str, client: SocketAddr) -> bool {
+        let (reason, detail) = if let Some(proxy_loop) = error.downcast_ref::<super::ProxyLoop>() {
+            (BlockReason::Loop, proxy_loop.to_string())
+        } else if let Some(private) = error.downcast_ref::<PrivateAddress>() {
+            // The resolved address is left out, as the target label may be redacted
+            (BlockReason::PrivateRange, format!("Target resolves to a {} address", private.range))
+        } else {
+            return false;
+        };
+        warn!("Connection to {} blocked [{}] for {}: {}", target_label, reason, client, detail);
+        Self::count_blocked(metrics, reason, &detail);
+        true
+    }
+
+    /// Count a refused request by its block reason, if metrics are on
+    fn count_blocked(metrics: Option<&Arc<Metrics>>, reason: BlockReason, detail: &str) {
+        if let Some(metrics) = metrics {
+            metrics.record_blocked_request(reason, detail);
+        }
+    }
+
     /// Handle SOCKS5 UDP ASSOCIATE command
     async fn handle_udp_associate_command(
         udp_addr: &crate::protocol::TargetAddr,
-        _udp_port: u16,
+        udp_port: u16,
+        route: UdpRouteContext,
+        config: &Config,
+        relay_extensions: &RelayExtensions,
+        relay_handle: RelayHandle,
         handler: &mut crate::protocol::Socks5Handler,
     ) -> Result<()> {
         use tokio::net::UdpSocket;
         use std::net::IpAddr;
         use anyhow::Context;
         
-        // For UDP ASSOCIATE, we need to create a UDP socket for relaying UDP packets
-        // The client will send UDP packets to this socket, and we'll relay them to the target
-        
-        // Create a UDP socket on an available port
-        let socket = match udp_addr {
-            crate::protocol::TargetAddr::Ipv4(_) => {
-                // Bind to IPv4
-                UdpSocket::bind("0.0.0.0:0").await
-                    .context("Failed to bind IPv4 UDP socket")?
-            }
-            crate::protocol::TargetAddr::Ipv6(_) => {
-                // Bind to IPv6
-                UdpSocket::bind("[::]:0").await
-                    .context("Failed to bind IPv6 UDP socket")?
-            }
-            crate::protocol::TargetAddr::Domain(_) => {
-                // For domain names, default to IPv4
-                UdpSocket::bind("0.0.0.0:0").await
-                    .context("Failed to bind UDP socket for domain")?
-            }
-        };
+        // Relay on the address the client reached us at, so replies route back the same way
+        let local_ip = handler.local_addr()?.ip();
+        let socket = UdpSocket::bind(SocketAddr::new(local_ip, 0)).await
+            .context("Failed to bind UDP relay socket")?;
         
         let local_addr = socket.local_addr()
             .context("Failed to get UDP socket local address")?;
@@ -842,32 +1698,64 @@ impl ConnectionManager {
         handler.send_response(response).await
             .context("Failed to send UDP ASSOCIATE response")?;
         
-        // Keep the TCP connection alive and relay UDP packets
-        // For a full implementation, we would:
-        // 1. Keep the TCP connection open (client closes it to end UDP association)
-        // 2. Listen for UDP packets on the socket
-        // 3. Parse SOCKS5 UDP request format
-        // 4. Relay packets to/from the target
-        
-        // For now, we'll just keep the connection alive for a short time
-        info!("UDP ASSOCIATE established, keeping connection alive");
-        
-        // Wait for the client to close the TCP connection or timeout
-        let timeout_duration = std::time::Duration::from_secs(300); // 5 minutes
+        // Pin the association to the address the client announced, if it gave one
+        let announced = match udp_addr {
+            crate::protocol::TargetAddr::Ipv4(ip) => Some(SocketAddr::new((*ip).into(), udp_port)),
+            crate::protocol::TargetAddr::Ipv6(ip) => Some(SocketAddr::new((*ip).into(), udp_port)),
+            crate::protocol::TargetAddr::Domain(_) => None,
+        };
+        let guard = UdpAssociationGuard::new(&config.security.udp_relay, route.client_ip, announced);
         
-        // In a real implementation, we would monitor the TCP connection and relay UDP packets
-        // For now, we'll just wait and then close
-        tokio::time::sleep(timeout_duration).await;
+        // The association lives until the client closes the TCP connection
+        let rate_limit = relay_extensions.rate_limiter.as_ref()
+            .and_then(|limiter| limiter.udp_association_limit(route.client_ip));
+        let stats = UdpRelay::new(socket, guard, Arc::clone(&relay_extensions.udp_guard))
+            .with_rate_limit(rate_limit)
+            .with_resolver(Arc::clone(&relay_extensions.resolver))
+            .with_router(route)
+            .with_private_range_protection(config.security.private_ranges.enabled)
+            .with_metrics(relay_extensions.metrics.clone())
+            .with_idle_timeout(config.server.idle_timeout)
+            .with_connection_lifetime(config.server.max_connection_lifetime, config.server.connection_lifetime_warning)
+            .with_handle(relay_handle)
+            .run(handler.wait_for_close())
+            .await?;
         
-        info!("UDP ASSOCIATE command completed");
+        info!("UDP ASSOCIATE command completed: {} datagram(s) up, {} down",
+              stats.datagrams_up, stats.datagrams_down);
         Ok(())
     }
 
+    /// Datagrams UDP relays dropped as spoofed, replayed, or malformed
+    pub fn get_udp_guard_stats(&self) -> UdpGuardStats {
+        self.relay_extensions.udp_guard.stats()
+    }
+
+    /// Connections let in and refused per client country, empty if the policy is off
+    pub fn get_client_geo_counts(&self) -> Vec<(String, CountryCounts)> {
+        self.client_geo.as_ref().map(|client_geo| client_geo.counts()).unwrap_or_default()
+    }
+
+    /// Honeypot that refused connections are diverted to, if enabled
+    pub fn honeypot(&self) -> Option<&Arc<Honeypot>> {
+        self.honeypot.as_ref()
+    }
+
+    /// DNS cache size and hit/miss counts
+    pub fn get_dns_cache_stats(&self) -> DnsCacheStats {
+        self.relay_extensions.resolver.stats()
+    }
+
     /// Get the number of active connections
     pub fn get_active_connections(&self) -> usize {
         self.active_connections.load(Ordering::Relaxed)
     }
 
+    /// Get the live active connection counter
+    pub fn active_connections_counter(&self) -> &Arc<AtomicUsize> {
+        &self.active_connections
+    }
+
     /// Get the bind address if listener is initialized
     pub fn get_bind_addr(&self) -> Option<SocketAddr> {
         self.listener.as_ref()
@@ -926,6 +1814,26 @@ impl ConnectionManager {
         &self.fail2ban_manager
     }
 
+    /// Get the per-user quota manager
+    pub fn quota_manager(&self) -> &Arc<QuotaManager> {
+        &self.quota_manager
+    }
+
+    /// Get the table of sticky upstream pins
+    pub fn sticky_sessions(&self) -> &Arc<StickySessionTable> {
+        &self.sticky_sessions
+    }
+
+    /// Get the router new connections are routed through
+    pub fn router(&self) -> Arc<Router> {
+        Self::connection_router(&self.config, &self.relay_extensions)
+    }
+
+    /// Get the monthly usage of each upstream proxy
+    pub fn upstream_usage(&self) -> &Arc<UpstreamUsageTracker> {
+        &self.upstream_usage
+    }
+
     /// Force cleanup of expired sessions and rate limits
     pub fn cleanup_auth_data(&self) {
         self.auth_manager.cleanup_expired();
@@ -947,12 +1855,20 @@ impl ConnectionManager {
         self.shutdown_tx.subscribe()
     }
 
+    /// Receive alerts about accept backoffs and listener rebinds
+    pub fn subscribe_listener_events(&self) -> broadcast::Receiver<ListenerEvent> {
+        self.listener_events.subscribe()
+    }
+
     /// Check if shutdown has been initiated
     pub fn is_shutting_down(&self) -> bool {
         self.shutdown_flag.load(Ordering::Relaxed)
     }
 
-    /// Wait for all connections to close gracefully
+    /// Wait for active relays to drain, then close whatever outlasts the shutdown timeout
+    ///
+    /// Relays still running at the timeout are cancelled, which shuts down
+    /// both of their sockets, and get [`FORCE_CLOSE_GRACE`] to wind down.
     pub async fn wait_for_connections_to_close(&self) -> Result<()> {
         let shutdown_timeout = self.config.server.shutdown_timeout;
         let start_time = Instant::now();
@@ -962,7 +1878,7 @@ impl ConnectionManager {
         
         while self.get_active_connections() > 0 && start_time.elapsed() < shutdown_timeout {
             debug!("Waiting for {} active connections to close", self.get_active_connections());
-            tokio::time::sleep(Duration::from_millis(500)).await;
+            tokio::time::sleep(Duration::from_millis(100)).await;
         }
         
         let remaining = self.get_active_connections();
@@ -970,20 +1886,99 @@ impl ConnectionManager {
         
         if remaining == 0 {
             info!("All connections closed gracefully in {:?}", elapsed);
-        } else {
-            warn!("Shutdown timeout reached after {:?} with {} connections still active", 
-                  elapsed, remaining);
+            return Ok(());
+        }
+        
+        warn!("Shutdown timeout reached after {:?} with {} connections still active, closing them", 
+              elapsed, remaining);
+        let closed = {
+            let tracker = self.connection_tracker.read().await;
+            tracker.values().filter(|conn_info| conn_info.relay.cancel("server shutting down")).count()
+        };
+        debug!("Cancelled {} relays still running at shutdown", closed);
+        
+        let force_start = Instant::now();
+        while self.get_active_connections() > 0 && force_start.elapsed() < FORCE_CLOSE_GRACE {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        let remaining = self.get_active_connections();
+        if remaining > 0 {
+            warn!("{} connections still open after being closed for shutdown", remaining);
         }
         
         Ok(())
     }
 
+    /// Save quota usage, sticky pins, upstream usage, bans and auth sessions
+    /// during the persist phase of a coordinated shutdown
+    pub fn register_shutdown_hooks(&self, coordinator: &ShutdownCoordinator) {
+        let state = self.persisted_state();
+        coordinator.register(ShutdownPhase::Persist, "connection state", move || async move {
+            state.save()
+        });
+        if let Some(siem) = self.siem.clone() {
+            coordinator.register(ShutdownPhase::Flush, "SIEM export", move || async move {
+                siem.close().await
+            });
+        }
+    }
 
+    fn persisted_state(&self) -> PersistedState {
+        PersistedState {
+            quota_manager: Arc::clone(&self.quota_manager),
+            sticky_sessions: Arc::clone(&self.sticky_sessions),
+            upstream_usage: Arc::clone(&self.upstream_usage),
+            ban_store: Arc::clone(&self.ban_store),
+            auth_manager: Arc::clone(&self.auth_manager),
+        }
+    }
 
-    /// Gracefully shutdown the connection manager
+    /// Gracefully shutdown the connection manager, saving its state once drained
     pub async fn shutdown(&self) -> Result<()> {
         self.initiate_shutdown();
-        self.wait_for_connections_to_close().await
+        self.wait_for_connections_to_close().await?;
+        self.persisted_state().save()
+    }
+}
+
+/// State written on shutdown so it survives a restart
+struct PersistedState {
+    quota_manager: Arc<QuotaManager>,
+    sticky_sessions: Arc<StickySessionTable>,
+    upstream_usage: Arc<UpstreamUsageTracker>,
+    ban_store: Arc<BanStore>,
+    auth_manager: Arc<AuthManager>,
+}
+
+impl PersistedState {
+    /// Save everything, failing if any part could not be saved
+    fn save(&self) -> Result<()> {
+        let mut failed = Vec::new();
+        if let Err(e) = self.quota_manager.save() {
+            warn!("Failed to save quota usage: {:#}", e);
+            failed.push("quota usage");
+        }
+        if let Err(e) = self.sticky_sessions.save() {
+            warn!("Failed to save sticky sessions: {:#}", e);
+            failed.push("sticky sessions");
+        }
+        if let Err(e) = self.upstream_usage.save() {
+            warn!("Failed to save upstream usage: {:#}", e);
+            failed.push("upstream usage");
+        }
+        if let Err(e) = self.ban_store.save() {
+            warn!("Failed to save bans: {:#}", e);
+            failed.push("bans");
+        }
+        if let Err(e) = self.auth_manager.save_sessions() {
+            warn!("Failed to save sessions: {:#}", e);
+            failed.push("sessions");
+        }
+        if failed.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow::anyhow!("Failed to save {}", failed.join(", ")))
+        }
     }
 }
 
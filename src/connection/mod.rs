@@ -2,6 +2,14 @@
 //! 
 //! Handles TCP connection acceptance, management, and lifecycle.
 
+pub mod deadline;
+pub mod listener;
+pub mod loop_guard;
 pub mod manager;
+pub mod socket;
 
-pub use manager::{ConnectionManager, ConnectionInfo, ConnectionStats};
\ No newline at end of file
+pub use deadline::{Deadline, DeadlineExceeded};
+pub use listener::{AcceptErrorClass, ListenerEvent};
+pub use loop_guard::{LoopGuard, ProxyLoop};
+pub use manager::{ConnectionManager, ConnectionInfo, ConnectionStats};
+pub use socket::{set_fwmark, EgressBind, Keepalive, SocketOptions};
\ No newline at end of file
@@ -0,0 +1,207 @@
+//! Socket Options
+//!
+//! A peer that vanishes without closing (a crashed host, a NAT that dropped
+//! the mapping) leaves an idle relay waiting forever. TCP keepalive probes
+//! both sides of every relay once it has been quiet for a while, so such
+//! connections fail and are cleaned up.
+//!
+//! Client and target sockets can also be tuned separately: latency-sensitive
+//! deployments turn off Nagle's algorithm, while bulk ones want large kernel
+//! buffers to keep long fat links busy. On servers with several addresses,
+//! outbound connections can leave from a chosen address or interface, or
+//! carry a firewall mark for the OS's policy routing to steer them.
+
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use socket2::{SockRef, TcpKeepalive};
+use tokio::net::{TcpSocket, TcpStream};
+
+use crate::config::ServerConfig;
+
+/// TCP keepalive settings for client and target connections
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Keepalive {
+    /// Idle time before the first probe
+    pub time: Duration,
+    /// Time between unanswered probes
+    pub interval: Duration,
+    /// Unanswered probes after which the connection is dropped
+    pub probes: u32,
+}
+
+impl Keepalive {
+    /// Keepalive from the server config, or `None` when it is disabled
+    pub fn from_config(server: &ServerConfig) -> Option<Self> {
+        server.enable_keepalive.then_some(Self {
+            time: server.keepalive_interval,
+            interval: server.keepalive_interval,
+            probes: server.keepalive_probes,
+        })
+    }
+
+    /// Enable keepalive on `stream`
+    ///
+    /// Platforms that cannot set the probe interval or count keep their
+    /// defaults for them.
+    pub fn apply(&self, stream: &TcpStream) -> io::Result<()> {
+        let keepalive = TcpKeepalive::new().with_time(self.time);
+        #[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd", target_os = "macos", target_os = "windows"))]
+        let keepalive = keepalive.with_interval(self.interval);
+        #[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd", target_os = "macos"))]
+        let keepalive = keepalive.with_retries(self.probes);
+        SockRef::from(stream).set_tcp_keepalive(&keepalive)
+    }
+}
+
+/// Options set on client or target sockets; unset ones keep the OS default
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct SocketOptions {
+    /// Send small writes at once instead of coalescing them (TCP_NODELAY)
+    #[serde(default)]
+    pub nodelay: Option<bool>,
+    /// Kernel send buffer size in bytes (SO_SNDBUF)
+    #[serde(default)]
+    pub send_buffer_size: Option<usize>,
+    /// Kernel receive buffer size in bytes (SO_RCVBUF)
+    #[serde(default)]
+    pub recv_buffer_size: Option<usize>,
+}
+
+impl SocketOptions {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Set the options on `socket`
+    ///
+    /// Buffer sizes are best set before connecting, since the receive buffer
+    /// decides the window scale offered in the handshake.
+    pub fn apply(&self, socket: SockRef<'_>) -> io::Result<()> {
+        if let Some(nodelay) = self.nodelay {
+            socket.set_tcp_nodelay(nodelay)?;
+        }
+        if let Some(size) = self.send_buffer_size {
+            socket.set_send_buffer_size(size)?;
+        }
+        if let Some(size) = self.recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+        }
+        Ok(())
+    }
+}
+
+/// Local address or network interface outbound connections leave from
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum EgressBind {
+    Address(IpAddr),
+    /// Interface name, e.g. `eth1` (Linux only)
+    Interface(String),
+}
+
+impl EgressBind {
+    /// Bind `socket`, which is about to connect to `target`
+    ///
+    /// An address of the other IP family than `target` cannot reach it, so
+    /// this fails and the caller moves on to the target's next address.
+    pub fn bind(&self, socket: &TcpSocket, target: SocketAddr) -> io::Result<()> {
+        match self {
+            EgressBind::Address(ip) if ip.is_ipv4() != target.is_ipv4() => Err(io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                format!("egress address {} cannot reach {}", ip, target),
+            )),
+            EgressBind::Address(ip) => socket.bind(SocketAddr::new(*ip, 0)),
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            EgressBind::Interface(name) => SockRef::from(socket).bind_device(Some(name.as_bytes())),
+            #[cfg(not(any(target_os = "linux", target_os = "android")))]
+            EgressBind::Interface(name) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("binding to interface {} is only supported on Linux", name),
+            )),
+        }
+    }
+}
+
+impl fmt::Display for EgressBind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EgressBind::Address(ip) => write!(f, "{}", ip),
+            EgressBind::Interface(name) => write!(f, "interface {}", name),
+        }
+    }
+}
+
+/// Set the firewall mark (SO_MARK) on `socket`, which needs CAP_NET_ADMIN
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn set_fwmark(socket: &TcpSocket, mark: u32) -> io::Result<()> {
+    SockRef::from(socket).set_mark(mark)
+}
+
+/// Firewall marks only exist on Linux
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn set_fwmark(_socket: &TcpSocket, _mark: u32) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "firewall marks are only supported on Linux"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_applies_keepalive() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let keepalive = Keepalive { time: Duration::from_secs(45), interval: Duration::from_secs(15), probes: 4 };
+        keepalive.apply(&stream).unwrap();
+
+        let socket = SockRef::from(&stream);
+        assert!(socket.keepalive().unwrap());
+        #[cfg(target_os = "linux")]
+        {
+            assert_eq!(socket.tcp_keepalive_time().unwrap(), Duration::from_secs(45));
+            assert_eq!(socket.tcp_keepalive_interval().unwrap(), Duration::from_secs(15));
+            assert_eq!(socket.tcp_keepalive_retries().unwrap(), 4);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_applies_socket_options() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let socket = SockRef::from(&stream);
+        let default_send_buffer = socket.send_buffer_size().unwrap();
+
+        SocketOptions { nodelay: Some(true), ..Default::default() }.apply(SockRef::from(&stream)).unwrap();
+        assert!(stream.nodelay().unwrap());
+        assert_eq!(socket.send_buffer_size().unwrap(), default_send_buffer);
+
+        // Linux doubles the requested size for bookkeeping
+        let options = SocketOptions { nodelay: Some(false), send_buffer_size: Some(256 * 1024), recv_buffer_size: Some(128 * 1024) };
+        options.apply(SockRef::from(&stream)).unwrap();
+        assert!(!stream.nodelay().unwrap());
+        assert!(socket.send_buffer_size().unwrap() >= 256 * 1024);
+        assert!(socket.recv_buffer_size().unwrap() >= 128 * 1024);
+    }
+
+    #[tokio::test]
+    async fn test_binds_egress_address() {
+        let bind: EgressBind = serde_json::from_str(r#""127.0.0.2""#).unwrap();
+        assert_eq!(bind, EgressBind::Address("127.0.0.2".parse().unwrap()));
+        let interface: EgressBind = serde_json::from_str(r#""eth1""#).unwrap();
+        assert_eq!(interface, EgressBind::Interface("eth1".to_string()));
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap();
+        let socket = TcpSocket::new_v4().unwrap();
+        bind.bind(&socket, target).unwrap();
+        let _stream = socket.connect(target).await.unwrap();
+        let (_, peer) = listener.accept().await.unwrap();
+        assert_eq!(peer.ip(), "127.0.0.2".parse::<IpAddr>().unwrap());
+
+        let err = bind.bind(&TcpSocket::new_v6().unwrap(), "[::1]:80".parse().unwrap()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AddrNotAvailable);
+    }
+}
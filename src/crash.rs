@@ -0,0 +1,275 @@
+//! Crash Reports
+//!
+//! When the process panics, a diagnostic bundle is written to
+//! `monitoring.crash_reports.dir` before it exits: the version, a summary
+//! of the configuration with secrets redacted, the most recent log events,
+//! active connection counts and a backtrace. Attaching the bundle to a bug
+//! report usually says more than the last screen of logs.
+
+use crate::config::{Config, CrashReportConfig};
+use crate::Result;
+use anyhow::Context;
+use serde::Serialize;
+use serde_json::Value;
+use std::backtrace::Backtrace;
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::panic::PanicHookInfo;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::field::{Field, Visit};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::layer::{Context as LayerContext, Layer};
+
+/// Placeholder written instead of secret configuration values
+const REDACTED: &str = "[redacted]";
+
+/// Configuration keys whose values never leave the process
+const SECRET_KEYS: &[&str] = &["password", "secret", "token", "salt", "credential", "private_key", "api_key"];
+
+/// Ring buffer of the most recent log events
+pub struct RecentEvents {
+    capacity: AtomicUsize,
+    events: Mutex<VecDeque<String>>,
+}
+
+impl RecentEvents {
+    /// Create a buffer keeping the last `capacity` events
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: AtomicUsize::new(capacity),
+            events: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    /// Change how many events are kept, e.g. once the configuration is loaded
+    pub fn set_capacity(&self, capacity: usize) {
+        self.capacity.store(capacity, Ordering::Relaxed);
+        let mut events = self.lock();
+        while events.len() > capacity {
+            events.pop_front();
+        }
+    }
+
+    /// Append an event, dropping the oldest when full
+    pub fn push(&self, event: String) {
+        let capacity = self.capacity.load(Ordering::Relaxed);
+        if capacity == 0 {
+            return;
+        }
+        let mut events = self.lock();
+        while events.len() >= capacity {
+            events.pop_front();
+        }
+        events.push_back(event);
+    }
+
+    /// Events currently held, oldest first
+    pub fn snapshot(&self) -> Vec<String> {
+        self.lock().iter().cloned().collect()
+    }
+
+    /// Tracing layer that feeds this buffer
+    pub fn layer(self: &Arc<Self>) -> RecentEventsLayer {
+        RecentEventsLayer { events: Arc::clone(self) }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, VecDeque<String>> {
+        // A panic while logging must not keep the crash report from reading the buffer
+        self.events.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Tracing layer recording events into [`RecentEvents`]
+pub struct RecentEventsLayer {
+    events: Arc<RecentEvents>,
+}
+
+impl<S: Subscriber> Layer<S> for RecentEventsLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: LayerContext<'_, S>) {
+        let metadata = event.metadata();
+        let mut line = format!("{} {} {}:",
+                               humantime::format_rfc3339_millis(SystemTime::now()),
+                               metadata.level(),
+                               metadata.target());
+        event.record(&mut EventFormatter(&mut line));
+        self.events.push(line);
+    }
+}
+
+struct EventFormatter<'a>(&'a mut String);
+
+impl Visit for EventFormatter<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "message" {
+            let _ = write!(self.0, " {:?}", value);
+        } else {
+            let _ = write!(self.0, " {}={:?}", field.name(), value);
+        }
+    }
+}
+
+/// Process-wide event buffer, shared by the tracing layer and the crash hook
+pub fn recent_events() -> &'static Arc<RecentEvents> {
+    static EVENTS: OnceLock<Arc<RecentEvents>> = OnceLock::new();
+    EVENTS.get_or_init(|| Arc::new(RecentEvents::new(CrashReportConfig::default().recent_events)))
+}
+
+/// Contents of a crash report
+#[derive(Debug, Serialize)]
+pub struct CrashBundle {
+    pub version: String,
+    pub time: String,
+    pub pid: u32,
+    pub thread: String,
+    pub message: String,
+    pub location: Option<String>,
+    pub active_connections: Option<usize>,
+    pub max_connections: usize,
+    pub config: Value,
+    pub recent_events: Vec<String>,
+    pub backtrace: String,
+}
+
+/// Writes a diagnostic bundle when the process panics
+pub struct CrashReporter {
+    dir: PathBuf,
+    config: Value,
+    max_connections: usize,
+    events: Arc<RecentEvents>,
+    active_connections: Option<Arc<AtomicUsize>>,
+}
+
+impl CrashReporter {
+    /// Create a reporter for `config`, capturing its redacted summary now
+    pub fn new(config: &Config, events: Arc<RecentEvents>) -> Self {
+        let settings: &CrashReportConfig = &config.monitoring.crash_reports;
+        events.set_capacity(settings.recent_events);
+        Self {
+            dir: settings.dir.clone(),
+            config: config_summary(config),
+            max_connections: config.server.max_connections,
+            events,
+            active_connections: None,
+        }
+    }
+
+    /// Include the live connection count in reports
+    pub fn with_active_connections(mut self, active_connections: Arc<AtomicUsize>) -> Self {
+        self.active_connections = Some(active_connections);
+        self
+    }
+
+    /// Install as the panic hook, running the previous hook afterwards
+    pub fn install(self) {
+        let reporter = Arc::new(self);
+        let previous = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            match reporter.write(&reporter.bundle(info)) {
+                Ok(path) => eprintln!("Crash report written to {}", path.display()),
+                Err(e) => eprintln!("Failed to write crash report: {:#}", e),
+            }
+            previous(info);
+        }));
+    }
+
+    /// Assemble the report for a panic
+    pub fn bundle(&self, info: &PanicHookInfo<'_>) -> CrashBundle {
+        let payload = info.payload();
+        let message = payload.downcast_ref::<&str>().map(|s| s.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "Box<dyn Any>".to_string());
+        let thread = std::thread::current();
+
+        CrashBundle {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            time: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+            pid: std::process::id(),
+            thread: thread.name().unwrap_or("<unnamed>").to_string(),
+            message,
+            location: info.location().map(|location| location.to_string()),
+            active_connections: self.active_connections.as_ref().map(|count| count.load(Ordering::Relaxed)),
+            max_connections: self.max_connections,
+            config: self.config.clone(),
+            recent_events: self.events.snapshot(),
+            backtrace: Backtrace::force_capture().to_string(),
+        }
+    }
+
+    /// Write a bundle into the crash directory, returning its path
+    pub fn write(&self, bundle: &CrashBundle) -> Result<PathBuf> {
+        std::fs::create_dir_all(&self.dir)
+            .with_context(|| format!("Failed to create {}", self.dir.display()))?;
+        let secs = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let path = self.dir.join(format!("crash-{}-{}.json", secs, bundle.pid));
+        std::fs::write(&path, serde_json::to_string_pretty(bundle)?)
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        Ok(path)
+    }
+}
+
+/// Configuration as JSON, with secrets redacted and user lists reduced to counts
+pub fn config_summary(config: &Config) -> Value {
+    let mut value = serde_json::to_value(config).unwrap_or(Value::Null);
+    if let Some(users) = value.pointer_mut("/auth/users") {
+        let count = users.as_array().map_or(0, Vec::len);
+        *users = Value::String(format!("{} user(s)", count));
+    }
+    redact(&mut value);
+    value
+}
+
+fn redact(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                let key = key.to_ascii_lowercase();
+                if SECRET_KEYS.iter().any(|secret| key.contains(secret)) && !value.is_null() {
+                    *value = Value::String(REDACTED.to_string());
+                } else {
+                    redact(value);
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(redact),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::UserConfig;
+
+    #[test]
+    fn test_config_summary_redacts_secrets() {
+        let mut config = Config::default();
+        let alice: UserConfig = toml::from_str(
+            "username = \"alice\"\npassword = \"hunter2\"\nenabled = true\ntotp_secret = \"JBSWY3DPEHPK3PXP\"",
+        ).unwrap();
+        config.auth.users = vec![alice];
+        config.monitoring.management_api.auth.api_key = Some("api-token".to_string());
+
+        let summary = config_summary(&config);
+        let text = summary.to_string();
+        assert!(!text.contains("hunter2"));
+        assert!(!text.contains("alice"));
+        assert!(!text.contains("api-token"));
+        assert!(!text.contains("JBSWY3DPEHPK3PXP"));
+        assert_eq!(summary["auth"]["users"], "1 user(s)");
+        assert_eq!(summary["server"]["max_connections"], config.server.max_connections);
+    }
+
+    #[test]
+    fn test_recent_events_keeps_newest() {
+        let events = RecentEvents::new(2);
+        for event in ["one", "two", "three"] {
+            events.push(event.to_string());
+        }
+        assert_eq!(events.snapshot(), ["two", "three"]);
+        events.set_capacity(1);
+        assert_eq!(events.snapshot(), ["three"]);
+    }
+}
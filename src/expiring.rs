@@ -0,0 +1,325 @@
+//! Expiring Maps
+//!
+//! A map whose entries expire after a period without use. Expiry is driven by
+//! a hierarchical timing wheel: each entry sits in exactly one wheel slot, and
+//! advancing the wheel only touches the slots whose time has come, so an
+//! expiry pass costs O(expired) instead of a scan of the whole map. Refreshing
+//! an entry just moves its deadline; the wheel notices when the old slot fires
+//! and reschedules it.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+const SLOT_BITS: u32 = 6;
+const SLOTS: usize = 1 << SLOT_BITS;
+const SLOT_MASK: u64 = SLOTS as u64 - 1;
+const LEVELS: usize = 4;
+
+/// Hierarchical timing wheel of items due at a tick
+///
+/// Level `n` has 64 slots of 64^n ticks each. Items are placed by how far
+/// their tick is from the current one and move down a level each time the
+/// wheel reaches their slot, until they fire from level 0. Items further out
+/// than the top level wait in an overflow list.
+#[derive(Debug)]
+pub struct TimingWheel<T> {
+    origin: Instant,
+    resolution: Duration,
+    /// Next tick to process; everything before it has fired
+    current: u64,
+    levels: Vec<Vec<Vec<(u64, T)>>>,
+    overflow: Vec<(u64, T)>,
+    len: usize,
+}
+
+impl<T> TimingWheel<T> {
+    /// Create a wheel whose ticks are `resolution` long
+    pub fn new(resolution: Duration) -> Self {
+        Self {
+            origin: Instant::now(),
+            resolution: resolution.max(Duration::from_millis(1)),
+            current: 0,
+            levels: (0..LEVELS).map(|_| (0..SLOTS).map(|_| Vec::new()).collect()).collect(),
+            overflow: Vec::new(),
+            len: 0,
+        }
+    }
+
+    /// Tick containing `at`, rounded up so items never fire early
+    pub fn tick_for(&self, at: Instant) -> u64 {
+        let offset = at.saturating_duration_since(self.origin).as_nanos();
+        let resolution = self.resolution.as_nanos();
+        offset.div_ceil(resolution) as u64
+    }
+
+    /// Schedule `item` to fire at `tick` (or on the next advance, if that has passed)
+    pub fn schedule(&mut self, tick: u64, item: T) {
+        let tick = tick.max(self.current);
+        self.len += 1;
+        self.place(tick, item);
+    }
+
+    /// Fire everything due up to and including `now`
+    pub fn advance(&mut self, now: Instant, mut fire: impl FnMut(u64, T)) {
+        let elapsed = now.saturating_duration_since(self.origin).as_nanos();
+        let target = (elapsed / self.resolution.as_nanos()) as u64;
+        if self.len == 0 {
+            self.current = self.current.max(target + 1);
+            return;
+        }
+        while self.current <= target {
+            let tick = self.current;
+            self.cascade(tick);
+            let due = std::mem::take(&mut self.levels[0][(tick & SLOT_MASK) as usize]);
+            self.len -= due.len();
+            for (at, item) in due {
+                fire(at, item);
+            }
+            self.current += 1;
+            if self.len == 0 {
+                self.current = self.current.max(target + 1);
+            }
+        }
+    }
+
+    /// Number of scheduled items
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn place(&mut self, tick: u64, item: T) {
+        let differing = (tick ^ self.current) | SLOT_MASK;
+        let level = ((63 - differing.leading_zeros()) / SLOT_BITS) as usize;
+        if level >= LEVELS {
+            self.overflow.push((tick, item));
+        } else {
+            let slot = ((tick >> (level as u32 * SLOT_BITS)) & SLOT_MASK) as usize;
+            self.levels[level][slot].push((tick, item));
+        }
+    }
+
+    /// Move items whose higher-level slot starts at `tick` down the wheel
+    fn cascade(&mut self, tick: u64) {
+        if tick & ((1 << (LEVELS as u32 * SLOT_BITS)) - 1) == 0 {
+            for (at, item) in std::mem::take(&mut self.overflow) {
+                self.place(at, item);
+            }
+        }
+        for level in (1..LEVELS).rev() {
+            let shift = level as u32 * SLOT_BITS;
+            if tick & ((1 << shift) - 1) != 0 {
+                continue;
+            }
+            let slot = ((tick >> shift) & SLOT_MASK) as usize;
+            for (at, item) in std::mem::take(&mut self.levels[level][slot]) {
+                self.place(at, item);
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Entry<V> {
+    value: V,
+    expires_at: Instant,
+    /// Tick of this entry's live wheel slot; older slots are stale
+    scheduled: u64,
+}
+
+/// Map whose entries expire after `ttl` without being refreshed
+#[derive(Debug)]
+pub struct ExpiringMap<K, V> {
+    entries: HashMap<K, Entry<V>>,
+    wheel: TimingWheel<K>,
+    ttl: Duration,
+}
+
+impl<K: Eq + Hash + Clone, V> ExpiringMap<K, V> {
+    /// Create a map whose expiry is accurate to a small fraction of `ttl`,
+    /// and never coarser than a second
+    pub fn new(ttl: Duration) -> Self {
+        Self::with_resolution(ttl, (ttl / 64).clamp(Duration::from_millis(1), Duration::from_secs(1)))
+    }
+
+    /// Create a map whose expiry is accurate to `resolution`
+    pub fn with_resolution(ttl: Duration, resolution: Duration) -> Self {
+        Self {
+            entries: HashMap::new(),
+            wheel: TimingWheel::new(resolution),
+            ttl,
+        }
+    }
+
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    /// Change the lifetime given to entries from now on
+    pub fn set_ttl(&mut self, ttl: Duration) {
+        self.ttl = ttl;
+    }
+
+    /// Look up an entry without refreshing it, even if it is due to expire
+    pub fn get(&self, key: &K) -> Option<&V> {
+        self.entries.get(key).map(|entry| &entry.value)
+    }
+
+    /// Look up an entry for modification and refresh it
+    pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+        let expires_at = Instant::now() + self.ttl;
+        self.entries.get_mut(key).map(|entry| {
+            entry.expires_at = expires_at;
+            &mut entry.value
+        })
+    }
+
+    /// Refreshed entry for `key`, inserting `default()` if there is none
+    pub fn get_or_insert_with(&mut self, key: K, default: impl FnOnce() -> V) -> &mut V {
+        let expires_at = Instant::now() + self.ttl;
+        let wheel = &mut self.wheel;
+        let entry = self.entries.entry(key).or_insert_with_key(|key| {
+            let scheduled = wheel.tick_for(expires_at);
+            wheel.schedule(scheduled, key.clone());
+            Entry { value: default(), expires_at, scheduled }
+        });
+        entry.expires_at = expires_at;
+        &mut entry.value
+    }
+
+    /// Insert or replace an entry, giving it a full lifetime
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        let mut value = Some(value);
+        let entry = self.get_or_insert_with(key, || value.take().unwrap());
+        value.map(|value| std::mem::replace(entry, value))
+    }
+
+    /// Insert or replace an entry that expires at `expires_at` rather than
+    /// after a full lifetime
+    pub fn insert_until(&mut self, key: K, value: V, expires_at: Instant) -> Option<V> {
+        let previous = self.insert(key.clone(), value);
+        let entry = self.entries.get_mut(&key).expect("entry was just inserted");
+        entry.expires_at = expires_at;
+        let tick = self.wheel.tick_for(expires_at);
+        if tick < entry.scheduled {
+            entry.scheduled = tick;
+            self.wheel.schedule(tick, key);
+        }
+        previous
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        // The entry's wheel slot goes stale and is skipped when it fires
+        self.entries.remove(key).map(|entry| entry.value)
+    }
+
+    /// When an entry expires unless refreshed
+    pub fn expires_at(&self, key: &K) -> Option<Instant> {
+        self.entries.get(key).map(|entry| entry.expires_at)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.entries.iter().map(|(key, entry)| (key, &entry.value))
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &V> {
+        self.entries.values().map(|entry| &entry.value)
+    }
+
+    /// Keep only the entries for which `keep` returns true, whatever their expiry
+    pub fn retain(&mut self, mut keep: impl FnMut(&K, &mut V) -> bool) {
+        self.entries.retain(|key, entry| keep(key, &mut entry.value));
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Remove entries that expired by `now`, returning how many went
+    ///
+    /// `keep` gets a last say over each expired entry; returning true gives it
+    /// another full lifetime.
+    pub fn expire(&mut self, now: Instant, mut keep: impl FnMut(&K, &mut V) -> bool) -> usize {
+        let mut due = Vec::new();
+        self.wheel.advance(now, |tick, key| due.push((tick, key)));
+
+        let mut removed = 0;
+        for (tick, key) in due {
+            let Some(entry) = self.entries.get_mut(&key) else {
+                continue;
+            };
+            if entry.scheduled != tick {
+                continue;
+            }
+            if entry.expires_at <= now {
+                if !keep(&key, &mut entry.value) {
+                    self.entries.remove(&key);
+                    removed += 1;
+                    continue;
+                }
+                entry.expires_at = now + self.ttl;
+            }
+            entry.scheduled = self.wheel.tick_for(entry.expires_at);
+            self.wheel.schedule(entry.scheduled, key);
+        }
+        removed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wheel_fires_each_item_once_at_its_tick() {
+        let mut wheel = TimingWheel::new(Duration::from_secs(1));
+        let origin = wheel.origin;
+        for tick in [0, 1, 63, 64, 65, 4095, 4096, 300_000] {
+            wheel.schedule(tick, tick);
+        }
+
+        let mut fired = Vec::new();
+        wheel.advance(origin + Duration::from_secs(64), |at, item| fired.push((at, item)));
+        assert_eq!(fired, vec![(0, 0), (1, 1), (63, 63), (64, 64)]);
+
+        fired.clear();
+        wheel.advance(origin + Duration::from_secs(400_000), |at, item| fired.push((at, item)));
+        assert_eq!(fired, vec![(65, 65), (4095, 4095), (4096, 4096), (300_000, 300_000)]);
+        assert!(wheel.is_empty());
+    }
+
+    #[test]
+    fn test_entries_expire_unless_refreshed_or_kept() {
+        let mut map = ExpiringMap::with_resolution(Duration::from_millis(50), Duration::from_millis(5));
+        map.insert("idle", 1);
+        map.insert("busy", 2);
+        map.insert("sticky", 3);
+
+        std::thread::sleep(Duration::from_millis(30));
+        assert_eq!(map.expire(Instant::now(), |_, _| false), 0);
+        *map.get_mut(&"busy").unwrap() += 1;
+
+        let removed = map.expire(Instant::now() + Duration::from_millis(30), |key, _| *key == "sticky");
+        assert_eq!(removed, 1);
+        assert_eq!(map.get(&"idle"), None);
+        assert_eq!(map.get(&"busy"), Some(&3));
+        assert_eq!(map.get(&"sticky"), Some(&3));
+
+        map.remove(&"sticky");
+        map.insert("sticky", 4);
+        assert_eq!(map.expire(Instant::now() + Duration::from_secs(1), |_, _| false), 2);
+        assert!(map.is_empty());
+    }
+}
@@ -7,21 +7,31 @@
 //! for maximum security, reliability, and performance.
 
 pub mod auth;
+pub mod blocking;
+pub mod cli;
 pub mod config;
 pub mod connection;
+pub mod crash;
+pub mod expiring;
 pub mod management;
 pub mod metrics;
+pub mod preflight;
 pub mod protocol;
 pub mod relay;
 pub mod resource;
 pub mod routing;
+pub mod schedule;
 pub mod security;
 pub mod shutdown;
+pub mod status;
+pub mod tls;
+pub mod tunnel;
+pub mod update;
 
 pub use config::Config;
 pub use connection::ConnectionManager;
 pub use resource::ResourceManager;
-pub use shutdown::ShutdownCoordinator;
+pub use shutdown::{ShutdownCoordinator, ShutdownPhase};
 
 /// Common error type for the proxy server
 pub type Result<T> = anyhow::Result<T>;
\ No newline at end of file
@@ -6,15 +6,28 @@
 //! for maximum security, reliability, and performance.
 
 use anyhow::{Context, Result};
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use std::path::PathBuf;
 
 use tracing::{error, info, warn};
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+use tracing_subscriber::{fmt::writer::BoxMakeWriter, layer::SubscriberExt, util::SubscriberInitExt};
 
 use rustproxy::{
-    config::ConfigManager, management::ManagementServer, metrics::Metrics, ConnectionManager,
-    ShutdownCoordinator,
+    auth::totp,
+    blocking,
+    cli::{exit_code, ErrorReport, OutputFormat, ValidationReport},
+    config::{Config, ConfigManager, ConfigWatcher},
+    crash::{self, CrashReporter},
+    management::ManagementServer,
+    metrics::{Metrics, RetentionEnforcer, WebhookNotifier},
+    preflight::{self, CheckStatus},
+    security::secrets::SecureConfigSettings as SecretsSettings,
+    security::{fail2ban_log, secrets, MigrationOutcome, SecretsManager},
+    status::{self, Palette, StatusClient},
+    tls::CertificateFiles,
+    tunnel::TunnelServer,
+    update::UpdateChecker,
+    ConnectionManager, ShutdownCoordinator, ShutdownPhase,
 };
 
 /// CLI arguments for RustProxy
@@ -45,6 +58,13 @@ Environment variables:
   RUSTPROXY_AUTH_ENABLED       - Enable authentication (true/false)
   RUSTPROXY_LOG_LEVEL          - Log level (trace, debug, info, warn, error)
 
+Exit codes for --validate-config, preflight, and status:
+  0 - Success
+  1 - One or more checks failed
+  2 - Invalid command-line arguments
+  3 - Configuration could not be loaded or is invalid
+  4 - The running proxy could not be reached
+
 For complete documentation, see: USER_MANUAL.md
 ")]
 pub struct CliArgs {
@@ -57,6 +77,10 @@ pub struct CliArgs {
     )]
     pub config: PathBuf,
 
+    /// Variables file for rendering the config file as a template
+    #[arg(long, help = "Render the config file as a template with variables from this TOML file")]
+    pub vars: Option<PathBuf>,
+
     /// Bind address (overrides config file)
     #[arg(short, long, help = "Bind address (e.g., 127.0.0.1:1080)")]
     pub bind: Option<String>,
@@ -92,6 +116,71 @@ pub struct CliArgs {
     /// Validate configuration and exit
     #[arg(long, help = "Validate configuration and exit")]
     pub validate_config: bool,
+
+    /// Output format for --validate-config and subcommands
+    #[arg(long, value_enum, global = true, default_value_t = OutputFormat::Text, help = "Output format (text or json)")]
+    pub output: OutputFormat,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// Subcommands that run instead of starting the proxy
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Verify bind addresses, upstreams, auth backend, certificates, GeoIP, and OS limits, then exit
+    Preflight {
+        /// Print the report as JSON (same as --output json)
+        #[arg(long, help = "Print the report as JSON")]
+        json: bool,
+        /// Secrets file whose TLS certificates are checked as well
+        #[arg(long, help = "Secrets file whose TLS certificates are checked as well")]
+        secrets: Option<PathBuf>,
+        /// Read the secrets key material from this file instead of the environment
+        #[arg(long, help = "Read the secrets key material from this file instead of the environment")]
+        key_file: Option<PathBuf>,
+        /// Environment variable holding the secrets key material
+        #[arg(long, default_value = "SOCKS5_CONFIG_KEY", help = "Environment variable holding the secrets key material")]
+        key_env: String,
+    },
+    /// Show a status dashboard for the running proxy via its management API
+    Status {
+        /// Management API base URL (defaults to the configured bind address)
+        #[arg(long, help = "Management API base URL, e.g. http://127.0.0.1:8080/api/v1")]
+        url: Option<String>,
+        /// Print the snapshot as JSON (same as --output json)
+        #[arg(long, help = "Print the snapshot as JSON")]
+        json: bool,
+        /// Disable colored output
+        #[arg(long, help = "Disable colored output")]
+        no_color: bool,
+    },
+    /// Generate a TOTP secret and enrollment URI for a user
+    Totp {
+        /// Username the secret is for
+        #[arg(long, help = "Username the secret is for")]
+        user: String,
+        /// Issuer name shown in authenticator apps
+        #[arg(long, default_value = "RustProxy", help = "Issuer name shown in authenticator apps")]
+        issuer: String,
+    },
+    /// Re-encrypt secrets files written in the old XOR format with AES-256-GCM
+    MigrateSecrets {
+        /// Encrypted secrets files to migrate in place
+        #[arg(required = true, help = "Encrypted secrets files to migrate in place")]
+        files: Vec<PathBuf>,
+        /// Read the key material from this file instead of the environment
+        #[arg(long, help = "Read the key material from this file instead of the environment")]
+        key_file: Option<PathBuf>,
+        /// Environment variable holding the key material
+        #[arg(long, default_value = "SOCKS5_CONFIG_KEY", help = "Environment variable holding the key material")]
+        key_env: String,
+    },
+    /// Hash a password read from stdin for a user's `password_hash` field
+    HashPassword,
+    /// Print a fail2ban filter for the lines written to `security.fail2ban_log`
+    #[command(name = "fail2ban-filter")]
+    Fail2banFilter,
 }
 
 #[tokio::main]
@@ -107,31 +196,118 @@ async fn main() -> Result<()> {
     );
     info!("Created by Ryan M. - Professional Network Solutions");
 
-    // Load configuration with priority: CLI args > config file > environment > defaults
-    let mut config = if args.config.exists() {
-        ConfigManager::load_from_file(&args.config)?
-    } else {
-        info!("Config file not found, checking environment variables");
-        ConfigManager::load_from_env()?
+    let output = match &args.command {
+        Some(Command::Preflight { json: true, .. }) | Some(Command::Status { json: true, .. }) => OutputFormat::Json,
+        _ => args.output,
     };
 
-    // Apply CLI argument overrides (highest priority)
-    config.merge_with_cli_args(
-        args.bind.as_deref(),
-        args.port,
-        args.max_connections,
-        args.no_auth,
-        args.timeout,
-        args.buffer_size,
-    );
+    // Generating a TOTP secret does not need a configuration
+    if let Some(Command::Totp { user, issuer }) = &args.command {
+        let secret = match totp::generate_secret() {
+            Ok(secret) => secret,
+            Err(e) => fail(output, &e, exit_code::CHECKS_FAILED),
+        };
+        let uri = totp::provisioning_uri(&secret, user, issuer);
+        if output.is_json() {
+            let enrollment = serde_json::json!({ "user": user, "secret": secret, "uri": uri });
+            println!("{}", serde_json::to_string_pretty(&enrollment)?);
+        } else {
+            println!("Secret: {}", secret);
+            println!("URI:    {}", uri);
+            println!();
+            println!("Add to the user's [[auth.users]] entry:");
+            println!("totp_secret = \"{}\"", secret);
+            println!();
+            println!("Enroll by scanning the URI as a QR code (e.g. `qrencode -t ansiutf8 '<URI>'`),");
+            println!("then log in with the password followed by the current 6-digit code.");
+        }
+        return Ok(());
+    }
 
-    // Final validation after all overrides
-    config
-        .validate()
-        .context("Final configuration validation failed")?;
+    // Neither does hashing a password
+    if let Some(Command::HashPassword) = &args.command {
+        let password = match read_password() {
+            Ok(password) => password,
+            Err(e) => fail(output, &e, exit_code::CHECKS_FAILED),
+        };
+        let hash = match SecretsManager::new(SecretsSettings::default()).hash_password(&password) {
+            Ok(hash) => hash,
+            Err(e) => fail(output, &e, exit_code::CHECKS_FAILED),
+        };
+        if output.is_json() {
+            println!("{}", serde_json::to_string_pretty(&serde_json::json!({ "password_hash": hash }))?);
+        } else {
+            println!("Replace `password` in the user's [[auth_users]] entry with:");
+            println!("password_hash = \"{}\"", hash);
+        }
+        return Ok(());
+    }
+
+    // Nor does printing the fail2ban filter
+    if let Some(Command::Fail2banFilter) = &args.command {
+        let filter = fail2ban_log::filter_definition();
+        if output.is_json() {
+            println!("{}", serde_json::to_string_pretty(&serde_json::json!({ "filter": filter }))?);
+        } else {
+            print!("{}", filter);
+        }
+        return Ok(());
+    }
+
+    // Migrating secrets files does not need a configuration either
+    if let Some(Command::MigrateSecrets { files, key_file, key_env }) = &args.command {
+        let secrets = SecretsManager::new(SecretsSettings {
+            encrypt_config: true,
+            config_encryption_key_env: key_env.clone(),
+            config_encryption_key_file: key_file.clone(),
+            ..SecretsSettings::default()
+        });
+        let mut results = Vec::new();
+        let mut failed = false;
+        for file in files {
+            let (status, error) = match secrets.migrate_file(file) {
+                Ok(MigrationOutcome::Migrated) => ("migrated", None),
+                Ok(MigrationOutcome::AlreadyCurrent) => ("current", None),
+                Err(e) => {
+                    failed = true;
+                    ("failed", Some(format!("{:#}", e)))
+                }
+            };
+            if !output.is_json() {
+                match &error {
+                    Some(error) => println!("{}: {}", file.display(), error),
+                    None => println!("{}: {}", file.display(), status),
+                }
+            }
+            results.push(serde_json::json!({ "file": file, "status": status, "error": error }));
+        }
+        if output.is_json() {
+            println!("{}", serde_json::to_string_pretty(&results)?);
+        }
+        std::process::exit(if failed { exit_code::CHECKS_FAILED } else { exit_code::SUCCESS });
+    }
+
+    let config = match load_config(&args) {
+        Ok(config) => config,
+        Err(e) if args.validate_config => {
+            let report = ValidationReport::invalid(&args.config, &e);
+            if output.is_json() {
+                println!("{}", serde_json::to_string_pretty(&report)?);
+            } else {
+                error!("❌ Configuration is invalid: {:#}", e);
+            }
+            std::process::exit(exit_code::INVALID_CONFIG);
+        }
+        Err(e) => fail(output, &e, exit_code::INVALID_CONFIG),
+    };
 
     // If validate-config flag is set, just validate and exit
     if args.validate_config {
+        if output.is_json() {
+            let report = ValidationReport::valid(&args.config, &config);
+            println!("{}", serde_json::to_string_pretty(&report)?);
+            return Ok(());
+        }
         info!("✅ Configuration is valid");
         info!("Configuration summary:");
         info!("  Bind address: {}", config.server.bind_addr);
@@ -176,6 +352,68 @@ async fn main() -> Result<()> {
         return Ok(());
     }
 
+    if let Some(Command::Preflight { secrets, key_file, key_env, .. }) = &args.command {
+        let secure_config = match secrets {
+            Some(path) => {
+                let encrypted = std::fs::read_to_string(path)
+                    .map(|content| secrets::is_encrypted(&content))
+                    .unwrap_or(false);
+                let mut manager = SecretsManager::new(SecretsSettings {
+                    encrypt_config: encrypted,
+                    config_encryption_key_env: key_env.clone(),
+                    config_encryption_key_file: key_file.clone(),
+                    ..SecretsSettings::default()
+                });
+                match manager.load_secure_config(Some(path)) {
+                    Ok(secure_config) => Some(secure_config),
+                    Err(e) => {
+                        let e = e.context(format!("Failed to load secrets file {}", path.display()));
+                        fail(output, &e, exit_code::INVALID_CONFIG)
+                    }
+                }
+            }
+            None => None,
+        };
+        let report = preflight::run_preflight(&config, secure_config.as_ref()).await;
+        if output.is_json() {
+            println!("{}", serde_json::to_string_pretty(&report)?);
+        } else {
+            for check in &report.checks {
+                let label = match check.status {
+                    CheckStatus::Pass => "PASS",
+                    CheckStatus::Warn => "WARN",
+                    CheckStatus::Fail => "FAIL",
+                };
+                println!("[{}] {}: {}", label, check.name, check.message);
+            }
+            println!(
+                "{} passed, {} warnings, {} failed",
+                report.count(CheckStatus::Pass),
+                report.count(CheckStatus::Warn),
+                report.count(CheckStatus::Fail)
+            );
+        }
+        std::process::exit(if report.passed { exit_code::SUCCESS } else { exit_code::CHECKS_FAILED });
+    }
+
+    if let Some(Command::Status { url, no_color, .. }) = &args.command {
+        let client = match StatusClient::from_config(&config, url.as_deref()) {
+            Ok(client) => client,
+            Err(e) => fail(output, &e, exit_code::INVALID_CONFIG),
+        };
+        let snapshot = match client.snapshot(&config).await {
+            Ok(snapshot) => snapshot,
+            Err(e) => fail(output, &e, exit_code::UNAVAILABLE),
+        };
+        if output.is_json() {
+            println!("{}", serde_json::to_string_pretty(&snapshot)?);
+        } else {
+            let palette = if *no_color { Palette::new(false) } else { Palette::detect() };
+            print!("{}", status::render(&snapshot, palette));
+        }
+        return Ok(());
+    }
+
     info!("Configuration loaded successfully");
     info!("Bind address: {}", config.server.bind_addr);
     info!("Max connections: {}", config.server.max_connections);
@@ -188,6 +426,11 @@ async fn main() -> Result<()> {
         }
     );
 
+    // Size the pool for password checks, GeoIP lookups and state writes
+    if !blocking::configure(&config.server.blocking_pool) {
+        warn!("Blocking pool was already in use; keeping its default size");
+    }
+
     // Create shutdown coordinator
     let shutdown_timeout = config.server.shutdown_timeout;
     let shutdown_coordinator = ShutdownCoordinator::new(shutdown_timeout);
@@ -195,11 +438,72 @@ async fn main() -> Result<()> {
     // Create metrics
     let metrics = std::sync::Arc::new(Metrics::new());
 
+    // Anonymize and expire connection history per the retention policy
+    if config.monitoring.retention.enabled {
+        RetentionEnforcer::new(config.monitoring.retention.clone())
+            .context("Failed to start metrics retention job")?
+            .spawn(metrics.clone());
+    }
+
     // Create shared config for management API
     let config_arc = std::sync::Arc::new(tokio::sync::RwLock::new(config.clone()));
 
     // Start the connection manager
-    let connection_manager = ConnectionManager::new(std::sync::Arc::new(config.clone()));
+    let mut connection_manager = ConnectionManager::new(std::sync::Arc::new(config.clone()))
+        .with_metrics(metrics.clone());
+
+    // Post connection usage to the billing webhook, delivering what is
+    // queued once the relays are gone
+    if let Some(webhooks) = &config.monitoring.webhooks {
+        let (notifier, delivery) = WebhookNotifier::spawn(webhooks.clone())
+            .context("Failed to start connection webhooks")?;
+        connection_manager = connection_manager.with_observer(notifier);
+        shutdown_coordinator.register(ShutdownPhase::Flush, "connection webhooks", move || async move {
+            delivery.await.context("Webhook delivery task failed")
+        });
+    }
+    connection_manager.register_shutdown_hooks(&shutdown_coordinator);
+
+    // Pick up routing rule and upstream proxy edits without a restart; the
+    // watcher stops when it is dropped at the end of main
+    let _config_watcher = if args.vars.is_none() && args.config.exists() {
+        match ConfigWatcher::new(args.config.clone()) {
+            Ok(watcher) => {
+                connection_manager.watch_config(watcher.subscribe());
+                Some(watcher)
+            }
+            Err(e) => {
+                warn!("Routing will not reload on config changes: {:#}", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    // Leave a diagnostic bundle behind if the process panics
+    if config.monitoring.crash_reports.enabled {
+        CrashReporter::new(&config, crash::recent_events().clone())
+            .with_active_connections(connection_manager.active_connections_counter().clone())
+            .install();
+        info!("Crash reports will be written to {}", config.monitoring.crash_reports.dir.display());
+    }
+
+    // Watch the release manifest for new versions and advisories; nothing is installed
+    let update_checker = match &config.monitoring.update_check {
+        Some(update_check) => {
+            let checker = std::sync::Arc::new(
+                UpdateChecker::new(update_check.clone()).context("Failed to start update checks")?,
+            );
+            let handle = checker.spawn();
+            shutdown_coordinator.register(ShutdownPhase::Exit, "update checks", move || async move {
+                handle.abort();
+                Ok(())
+            });
+            Some(checker)
+        }
+        None => None,
+    };
 
     // Start management API server if enabled
     let management_handle = if config.monitoring.management_api.enabled {
@@ -213,7 +517,33 @@ async fn main() -> Result<()> {
             config_arc.clone(),
             metrics.clone(),
             config.monitoring.management_api.auth.clone(),
-        );
+        )
+        .with_auth_manager(connection_manager.auth_manager().clone())
+        .with_sticky_sessions(connection_manager.sticky_sessions().clone())
+        .with_router(connection_manager.router())
+        .with_network_blocking(
+            connection_manager.fail2ban_manager().clone(),
+            connection_manager.ddos_protection().clone(),
+        )
+        .with_local_channel(config.monitoring.management_api.local_channel.clone());
+        let management_server = match &update_checker {
+            Some(checker) => management_server.with_update_checker(checker.clone()),
+            None => management_server,
+        };
+        let management_server = match connection_manager.honeypot() {
+            Some(honeypot) => management_server.with_honeypot(honeypot.clone()),
+            None => management_server,
+        };
+        let management_api = &config.monitoring.management_api;
+        let management_server = match CertificateFiles::from_settings(
+            management_api.tls_cert.as_deref(),
+            management_api.tls_key.as_deref(),
+            &management_api.tls_sni_certs,
+            "Management API",
+        )? {
+            Some(files) => management_server.with_tls(files),
+            None => management_server,
+        };
 
         Some(tokio::spawn(async move {
             if let Err(e) = management_server.start().await {
@@ -225,6 +555,20 @@ async fn main() -> Result<()> {
         None
     };
 
+    // Accept tunnels from other nodes alongside SOCKS5 clients
+    if config.server.tunnel.is_some() {
+        let tunnel_server = TunnelServer::new(&config).context("Failed to set up tunnel listener")?;
+        let handle = tokio::spawn(async move {
+            if let Err(e) = tunnel_server.start().await {
+                error!("Tunnel listener error: {:#}", e);
+            }
+        });
+        shutdown_coordinator.register(ShutdownPhase::StopAccepting, "tunnel listener", move || async move {
+            handle.abort();
+            Ok(())
+        });
+    }
+
     // Create a channel to communicate with the server task
     let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel();
 
@@ -248,6 +592,26 @@ async fn main() -> Result<()> {
         }
     });
 
+    // Stop accepting, then wait for the server task to drain its relays
+    shutdown_coordinator.register(ShutdownPhase::StopAccepting, "server", move || async move {
+        shutdown_tx.send(()).map_err(|_| anyhow::anyhow!("Server task already stopped"))
+    });
+    shutdown_coordinator.register(ShutdownPhase::DrainRelays, "server", move || async move {
+        match server_handle.await {
+            Err(e) if !e.is_cancelled() => Err(anyhow::anyhow!("Server task failed: {}", e)),
+            _ => Ok(()),
+        }
+    });
+
+    // Keep the management API up for status queries until everything else is done
+    if let Some(handle) = management_handle {
+        shutdown_coordinator.register(ShutdownPhase::Exit, "management API", move || async move {
+            handle.abort();
+            info!("Management API server shutdown");
+            Ok(())
+        });
+    }
+
     info!("🚀 RustProxy started successfully!");
     info!("✅ Enterprise SOCKS5 proxy with authentication, access control, and advanced routing");
     info!("📖 For help and documentation, see USER_MANUAL.md");
@@ -259,30 +623,74 @@ async fn main() -> Result<()> {
         error!("Error setting up signal handlers: {}", e);
     }
 
-    // Initiate graceful shutdown
+    // Run the shutdown phases in order
     info!("Initiating graceful shutdown...");
-
-    // Send shutdown signal to server task
-    if let Err(_) = shutdown_tx.send(()) {
-        warn!("Failed to send shutdown signal to server task");
+    let failed = shutdown_coordinator.run_phases().await;
+    if !failed.is_empty() {
+        warn!("{} shutdown hook(s) did not complete cleanly", failed.len());
     }
 
-    // Wait for server task to complete
-    if let Err(e) = server_handle.await {
-        if !e.is_cancelled() {
-            error!("Server task failed: {}", e);
-        }
-    }
+    info!("Server shutdown complete");
 
-    // Shutdown management API server if it was started
-    if let Some(handle) = management_handle {
-        handle.abort();
-        info!("Management API server shutdown");
+    Ok(())
+}
+
+/// Load configuration with priority: CLI args > config file > environment > defaults
+fn load_config(args: &CliArgs) -> Result<Config> {
+    let mut config = if let Some(vars) = &args.vars {
+        ConfigManager::load_from_template(&args.config, vars)?
+    } else if args.config.exists() {
+        ConfigManager::load_from_file(&args.config)?
+    } else {
+        info!("Config file not found, checking environment variables");
+        ConfigManager::load_from_env()?
+    };
+
+    // Apply CLI argument overrides (highest priority)
+    config.merge_with_cli_args(
+        args.bind.as_deref(),
+        args.port,
+        args.max_connections,
+        args.no_auth,
+        args.timeout,
+        args.buffer_size,
+    );
+
+    // Final validation after all overrides
+    config
+        .validate()
+        .context("Final configuration validation failed")?;
+
+    Ok(config)
+}
+
+/// Report an error in the requested format and exit with the given code
+fn fail(output: OutputFormat, error: &anyhow::Error, code: i32) -> ! {
+    if output.is_json() {
+        let report = ErrorReport::new(error, code);
+        println!("{}", serde_json::to_string_pretty(&report).unwrap_or_default());
+    } else {
+        eprintln!("Error: {:?}", error);
     }
+    std::process::exit(code);
+}
 
-    info!("Server shutdown complete");
+/// Read one line from stdin as a password, so it stays out of shell history
+fn read_password() -> Result<String> {
+    use std::io::{BufRead, IsTerminal, Write};
 
-    Ok(())
+    if std::io::stdin().is_terminal() {
+        eprint!("Password (input is echoed): ");
+        std::io::stderr().flush()?;
+    }
+    let mut line = String::new();
+    std::io::stdin().lock().read_line(&mut line).context("Failed to read password from stdin")?;
+    let password = line.strip_suffix('\n').unwrap_or(&line);
+    let password = password.strip_suffix('\r').unwrap_or(password);
+    if password.is_empty() {
+        anyhow::bail!("No password given on stdin");
+    }
+    Ok(password.to_string())
 }
 
 /// Initialize tracing/logging
@@ -296,14 +704,23 @@ fn init_tracing(args: &CliArgs) -> Result<()> {
     let env_filter = tracing_subscriber::EnvFilter::try_from_default_env()
         .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new(log_level));
 
+    // Keep stdout clean for subcommand and JSON output
+    let writer = if args.command.is_some() || args.output.is_json() {
+        BoxMakeWriter::new(std::io::stderr)
+    } else {
+        BoxMakeWriter::new(std::io::stdout)
+    };
+
     tracing_subscriber::registry()
         .with(
             tracing_subscriber::fmt::layer()
+                .with_writer(writer)
                 .with_target(false)
                 .with_thread_ids(true)
                 .with_level(true)
                 .with_ansi(true),
         )
+        .with(crash::recent_events().layer())
         .with(env_filter)
         .init();
 
@@ -32,18 +32,36 @@ impl ManagementApi {
             .route("/config", get(get_config))
             .route("/config", put(update_config))
             .route("/config/reload", post(reload_config))
+            .route("/capabilities", get(get_capabilities))
+            .route("/version", get(get_version))
             
             // Connection management
             .route("/connections", get(get_connections))
+            .route("/connections/export", get(export_connections))
             
             // Statistics and metrics
             .route("/stats", get(get_stats))
             .route("/metrics/export", post(export_metrics))
+            .route("/compliance/retention", get(get_retention_compliance))
+            
+            // Security
+            .route("/security/honeypot", get(get_honeypot_report))
+            .route("/security/networks", get(get_network_blocks))
+            .route("/security/networks", post(block_network))
+            .route("/security/networks", delete(unblock_network))
+            
+            // Sticky upstream sessions
+            .route("/routing/test", post(test_routing))
+            .route("/routing/sessions", get(get_sticky_sessions))
+            .route("/routing/sessions/:key", delete(delete_sticky_session))
+            .route("/routing/sessions/:key/rotate", post(rotate_sticky_session))
             
             // User management
             .route("/users", post(create_user))
             .route("/users/:username", get(get_user))
             .route("/users/:username", delete(delete_user))
+            .route("/users/:username/password", post(rotate_password))
+            .route("/users/:username/disable", post(disable_user))
             
             // Add authentication middleware to protected routes
             .layer(middleware::from_fn_with_state(auth.clone(), auth_middleware))
@@ -75,6 +93,13 @@ mod tests {
             config: Arc::new(RwLock::new(Config::default())),
             metrics: Arc::new(Metrics::new()),
             start_time: SystemTime::now(),
+            auth_manager: None,
+            sticky_sessions: None,
+            router: None,
+            update_checker: None,
+            honeypot: None,
+            fail2ban: None,
+            ddos: None,
         }
     }
     
@@ -137,4 +162,119 @@ mod tests {
         let response = app.oneshot(request).await.unwrap();
         assert_eq!(response.status(), StatusCode::OK);
     }
-}
\ No newline at end of file
+    
+    #[tokio::test]
+    async fn test_connection_export_streams_csv() {
+        let state = create_test_state();
+        state.metrics.record_connection(&crate::metrics::ConnectionStats {
+            session_id: "conn_1".to_string(),
+            client_addr: "198.51.100.7:50000".parse().unwrap(),
+            target_addr: "93.184.216.34:443".parse().unwrap(),
+            start_time: SystemTime::now(),
+            duration: std::time::Duration::from_secs(2),
+            bytes_up: 10,
+            bytes_down: 20,
+            user_id: Some("alice".to_string()),
+            client_token: None,
+            result: Default::default(),
+        });
+        let auth_config = ApiAuthConfig { enabled: false, ..Default::default() };
+        let app = ManagementApi::create_router(state, auth_config);
+        
+        let request = Request::builder()
+            .uri("/api/v1/connections/export?format=csv&since=1h")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["content-type"], "text/csv; charset=utf-8");
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let lines: Vec<&str> = std::str::from_utf8(&body).unwrap().lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[1].starts_with("conn_1,") && lines[1].ends_with(",alice,93.184.216.34:443,10,20,completed"));
+        
+        let request = Request::builder()
+            .uri("/api/v1/connections/export?since=2999-01-01T00:00:00Z")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(std::str::from_utf8(&body).unwrap().lines().count(), 1);
+        
+        for uri in ["/api/v1/connections/export?format=json", "/api/v1/connections/export?since=yesterday"] {
+            let request = Request::builder().uri(uri).body(Body::empty()).unwrap();
+            let response = app.clone().oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_routing_dry_run() {
+        let state = create_test_state();
+        {
+            let mut config = state.config.write().await;
+            config.routing.enabled = true;
+            config.routing.rules.push(toml::from_str(r#"
+                id = "no-ads"
+                priority = 100
+                pattern = "ads.*"
+                action = { type = "Block", config = { reason = "Ads" } }
+                enabled = true
+            "#).unwrap());
+        }
+        let auth_config = ApiAuthConfig { enabled: false, ..Default::default() };
+        let app = ManagementApi::create_router(state, auth_config);
+        
+        let request = Request::builder()
+            .method("POST")
+            .uri("/api/v1/routing/test")
+            .header("content-type", "application/json")
+            .body(Body::from(r#"{"target": "ads.example.com", "port": 443}"#))
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let result: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(result["data"]["matched_rule"], "no-ads");
+        assert_eq!(result["data"]["decision"]["action"], "block");
+        assert_eq!(result["data"]["rules"][0]["matched"], true);
+    }
+    
+    #[tokio::test]
+    async fn test_network_blocks() {
+        let mut state = create_test_state();
+        let ddos = Arc::new(crate::security::DdosProtection::new(Default::default()));
+        state.fail2ban = Some(Arc::new(crate::security::Fail2BanManager::new(Default::default())));
+        state.ddos = Some(ddos.clone());
+        let auth_config = ApiAuthConfig { enabled: false, ..Default::default() };
+        let app = ManagementApi::create_router(state, auth_config);
+        
+        let request = Request::builder()
+            .method("POST")
+            .uri("/api/v1/security/networks")
+            .header("content-type", "application/json")
+            .body(Body::from(r#"{"network": "198.51.100.0/24", "source": "ddos", "duration": "1h"}"#))
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let result: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(result["success"], true);
+        assert!(ddos.is_ip_blocked("198.51.100.7".parse().unwrap()));
+        
+        let request = Request::builder().uri("/api/v1/security/networks").body(Body::empty()).unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let result: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(result["data"][0]["network"], "198.51.100.0/24");
+        assert_eq!(result["data"][0]["source"], "ddos");
+        
+        let request = Request::builder()
+            .method("DELETE")
+            .uri("/api/v1/security/networks?network=198.51.100.0/24&source=ddos")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(!ddos.is_ip_blocked("198.51.100.7".parse().unwrap()));
+    }
+}
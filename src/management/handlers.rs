@@ -1,17 +1,25 @@
 //! Management API Handlers
 
 use super::types::*;
+use crate::auth::AuthManager;
 use crate::config::{Config, UserConfig};
 use crate::metrics::Metrics;
+use crate::protocol::TargetAddr;
+use crate::routing::{Router, StickySession, StickySessionTable};
+use crate::security::{DdosProtection, Fail2BanManager, Honeypot, HoneypotReport};
+use crate::update::{UpdateChecker, VersionReport};
 use axum::{
+    body::Body,
     extract::{Path, Query, State},
-    http::StatusCode,
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
     Json,
 };
 use serde::Deserialize;
 use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr};
 use std::sync::Arc;
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 use tokio::sync::RwLock;
 use tracing::{error, info};
 
@@ -21,6 +29,20 @@ pub struct AppState {
     pub config: Arc<RwLock<Config>>,
     pub metrics: Arc<Metrics>,
     pub start_time: SystemTime,
+    /// Running authentication manager that user changes are applied to
+    pub auth_manager: Option<Arc<AuthManager>>,
+    /// Sticky upstream pins of the running proxy
+    pub sticky_sessions: Option<Arc<StickySessionTable>>,
+    /// Router of the running proxy, for routing rule dry runs
+    pub router: Option<Arc<Router>>,
+    /// Background release check, when configured
+    pub update_checker: Option<Arc<UpdateChecker>>,
+    /// Honeypot of the running proxy, for the attacker behaviour report
+    pub honeypot: Option<Arc<Honeypot>>,
+    /// Fail2ban of the running proxy, for network bans
+    pub fail2ban: Option<Arc<Fail2BanManager>>,
+    /// DDoS protection of the running proxy, for network blocks
+    pub ddos: Option<Arc<DdosProtection>>,
 }
 
 /// Query parameters for pagination
@@ -30,6 +52,25 @@ pub struct PaginationQuery {
     pub limit: Option<usize>,
 }
 
+/// Query parameters for the honeypot report
+#[derive(Debug, Deserialize)]
+pub struct HoneypotQuery {
+    /// Entries in each top list and recent sessions kept in the report (default 20)
+    pub limit: Option<usize>,
+}
+
+/// Query parameters for the connection history export
+#[derive(Debug, Deserialize)]
+pub struct ExportQuery {
+    /// Only `csv` is supported
+    pub format: Option<String>,
+    /// RFC 3339 time, or a duration back from now such as `24h`
+    pub since: Option<String>,
+}
+
+/// Rows written per chunk of a streamed export
+const EXPORT_CHUNK_ROWS: usize = 256;
+
 /// Health check handler
 pub async fn health_check() -> Json<ApiResponse<HealthStatus>> {
     let mut checks = HashMap::new();
@@ -98,6 +139,37 @@ pub async fn get_server_status(State(state): State<AppState>) -> Json<ApiRespons
     Json(ApiResponse::success(status))
 }
 
+/// Get enabled features and the failure policy of each security subsystem
+pub async fn get_capabilities(State(state): State<AppState>) -> Json<ApiResponse<CapabilitiesReport>> {
+    let config = state.config.read().await;
+    
+    let mut features = HashMap::new();
+    features.insert("auth".to_string(), config.auth.enabled);
+    features.insert("access_control".to_string(), config.access_control.enabled);
+    features.insert("routing".to_string(), config.routing.enabled);
+    features.insert("smart_routing".to_string(), config.routing.smart_routing.enabled);
+    features.insert("geoip".to_string(), cfg!(feature = "geoip"));
+    features.insert("rate_limiting".to_string(), config.security.rate_limiting.enabled);
+    features.insert("ddos_protection".to_string(), config.security.ddos_protection.enabled);
+    features.insert("fail2ban".to_string(), config.security.fail2ban.enabled);
+    features.insert("prometheus".to_string(), config.monitoring.prometheus_enabled);
+    
+    let report = CapabilitiesReport {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        features,
+        failure_policies: config.security.failure_policies.clone(),
+    };
+    
+    Json(ApiResponse::success(report))
+}
+
+/// Get the running version and what the last release check found
+pub async fn get_version(State(state): State<AppState>) -> Json<ApiResponse<VersionReport>> {
+    let report = state.update_checker.as_ref()
+        .map_or_else(VersionReport::unchecked, |checker| checker.report());
+    Json(ApiResponse::success(report))
+}
+
 /// Get current configuration
 pub async fn get_config(State(state): State<AppState>) -> Json<ApiResponse<Config>> {
     let config = state.config.read().await;
@@ -143,7 +215,9 @@ pub async fn get_connections(
     State(state): State<AppState>,
     Query(pagination): Query<PaginationQuery>,
 ) -> Json<ApiResponse<Vec<ConnectionInfo>>> {
-    let connections = state.metrics.get_active_connection_info();
+    let mut connections = state.metrics.get_active_connection_info();
+    // Busiest first, so bandwidth hogs top the first page
+    connections.sort_by_key(|connection| std::cmp::Reverse(connection.throughput.total_1s()));
     
     let page = pagination.page.unwrap_or(1);
     let limit = pagination.limit.unwrap_or(50).min(1000); // Cap at 1000
@@ -173,6 +247,7 @@ pub async fn get_stats(State(state): State<AppState>) -> Json<ApiResponse<StatsS
         auth_attempts: state.metrics.get_auth_attempts(),
         auth_failures: state.metrics.get_auth_failures(),
         blocked_requests: state.metrics.get_blocked_requests(),
+        blocked_by_reason: state.metrics.get_blocked_requests_by_reason(),
         uptime_seconds: uptime,
         top_destinations: state.metrics.get_top_destinations(10),
         top_users: state.metrics.get_top_users(10),
@@ -181,6 +256,75 @@ pub async fn get_stats(State(state): State<AppState>) -> Json<ApiResponse<StatsS
     Json(ApiResponse::success(stats))
 }
 
+/// Report connection history against the retention policy
+pub async fn get_retention_compliance(
+    State(state): State<AppState>,
+) -> Json<ApiResponse<crate::metrics::ComplianceReport>> {
+    let retention = state.config.read().await.monitoring.retention.clone();
+    
+    match crate::metrics::retention::compliance_report(&retention, &state.metrics, SystemTime::now()) {
+        Ok(report) => Json(ApiResponse::success(report)),
+        Err(e) => Json(ApiResponse::error(format!("Failed to build compliance report: {:#}", e))),
+    }
+}
+
+/// Stream completed connections as CSV
+pub async fn export_connections(
+    State(state): State<AppState>,
+    Query(query): Query<ExportQuery>,
+) -> Response {
+    let format = query.format.as_deref().unwrap_or("csv");
+    if format != "csv" {
+        return bad_request(format!("Unsupported export format: {}", format));
+    }
+    let since = match query.since.as_deref().map(parse_since).transpose() {
+        Ok(since) => since,
+        Err(e) => return bad_request(e),
+    };
+    
+    let records = match crate::metrics::export::connections_since(&state.metrics, since) {
+        Ok(records) => records,
+        Err(e) => {
+            error!("Failed to read connection history: {:#}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    
+    // Rows are formatted as the client reads them, a chunk at a time
+    let len = records.len();
+    let rows = (0..len).step_by(EXPORT_CHUNK_ROWS).map(move |start| {
+        records[start..(start + EXPORT_CHUNK_ROWS).min(len)].iter()
+            .map(crate::metrics::export::csv_row)
+            .collect::<String>()
+    });
+    let chunks = std::iter::once(crate::metrics::export::CSV_HEADER.to_string())
+        .chain(rows)
+        .map(Ok::<_, std::convert::Infallible>);
+    
+    (
+        [
+            (header::CONTENT_TYPE, "text/csv; charset=utf-8"),
+            (header::CONTENT_DISPOSITION, "attachment; filename=\"connections.csv\""),
+        ],
+        Body::from_stream(tokio_stream::iter(chunks)),
+    )
+        .into_response()
+}
+
+/// Parse `since` as an RFC 3339 time or a duration back from now
+fn parse_since(since: &str) -> Result<SystemTime, String> {
+    if let Ok(time) = humantime::parse_rfc3339_weak(since) {
+        return Ok(time);
+    }
+    let ago: Duration = humantime::parse_duration(since)
+        .map_err(|_| format!("Invalid since '{}': expected an RFC 3339 time or a duration", since))?;
+    Ok(SystemTime::now().checked_sub(ago).unwrap_or(SystemTime::UNIX_EPOCH))
+}
+
+fn bad_request(message: String) -> Response {
+    (StatusCode::BAD_REQUEST, Json(ApiResponse::<()>::error(message))).into_response()
+}
+
 /// Create a new user
 pub async fn create_user(
     State(state): State<AppState>,
@@ -211,10 +355,12 @@ pub async fn create_user(
     
     // Add new user
     let new_user = UserConfig {
-        username: request.username.clone(),
-        password: request.password,
         enabled: request.enabled,
+        ..UserConfig::new(request.username.clone(), request.password)
     };
+    if let Err(message) = apply_to_auth(&state, |auth| auth.add_user(&new_user)) {
+        return Ok(Json(ApiResponse::error(message)));
+    }
     
     config.auth.users.push(new_user);
     
@@ -224,6 +370,7 @@ pub async fn create_user(
         created_at: SystemTime::now(),
         last_login: None,
         connection_count: 0,
+        password_expires: None,
     };
     
     info!("User created via management API: {}", user_info.username);
@@ -244,6 +391,7 @@ pub async fn get_user(
             created_at: SystemTime::now(), // TODO: Track actual creation time
             last_login: None,               // TODO: Track last login
             connection_count: 0,            // TODO: Get from metrics
+            password_expires: user.password_expires,
         };
         Json(ApiResponse::success(user_info))
     } else {
@@ -251,6 +399,52 @@ pub async fn get_user(
     }
 }
 
+/// Replace a user's password and set when the new one expires
+pub async fn rotate_password(
+    State(state): State<AppState>,
+    Path(username): Path<String>,
+    Json(request): Json<RotatePasswordRequest>,
+) -> Json<ApiResponse<UserInfo>> {
+    if request.password.is_empty() || request.password.len() > 255 {
+        return Json(ApiResponse::error(
+            "Password must be between 1 and 255 characters".to_string(),
+        ));
+    }
+    
+    let mut config = state.config.write().await;
+    let Some(user) = config.auth.users.iter_mut().find(|u| u.username == username) else {
+        return Json(ApiResponse::error("User not found".to_string()));
+    };
+    if user.password == request.password
+        || user.password_hash.is_some() && crate::auth::User::from_config(user).verify_password(&request.password)
+    {
+        return Json(ApiResponse::error(
+            "New password must differ from the current one".to_string(),
+        ));
+    }
+    
+    let updated = UserConfig {
+        password: request.password,
+        password_hash: None,
+        password_expires: request.expires,
+        ..user.clone()
+    };
+    if let Err(message) = apply_to_auth(&state, |auth| auth.update_user(&updated)) {
+        return Json(ApiResponse::error(message));
+    }
+    *user = updated;
+    
+    info!("Password rotated via management API for user: {}", username);
+    Json(ApiResponse::success(UserInfo {
+        username: user.username.clone(),
+        enabled: user.enabled,
+        created_at: SystemTime::now(), // TODO: Track actual creation time
+        last_login: None,
+        connection_count: 0,
+        password_expires: user.password_expires,
+    }))
+}
+
 /// Delete a user
 pub async fn delete_user(
     State(state): State<AppState>,
@@ -258,17 +452,223 @@ pub async fn delete_user(
 ) -> Json<ApiResponse<()>> {
     let mut config = state.config.write().await;
     
-    let initial_len = config.auth.users.len();
-    config.auth.users.retain(|u| u.username != username);
+    let Some(index) = config.auth.users.iter().position(|u| u.username == username) else {
+        return Json(ApiResponse::error("User not found".to_string()));
+    };
+    if let Err(message) = apply_to_auth(&state, |auth| auth.remove_user(&username)) {
+        return Json(ApiResponse::error(message));
+    }
     
-    if config.auth.users.len() < initial_len {
-        info!("User deleted via management API: {}", username);
-        Json(ApiResponse::success(()))
-    } else {
-        Json(ApiResponse::error("User not found".to_string()))
+    config.auth.users.remove(index);
+    info!("User deleted via management API: {}", username);
+    Json(ApiResponse::success(()))
+}
+
+/// Disable a user without deleting it
+pub async fn disable_user(
+    State(state): State<AppState>,
+    Path(username): Path<String>,
+) -> Json<ApiResponse<UserInfo>> {
+    let mut config = state.config.write().await;
+    let Some(user) = config.auth.users.iter_mut().find(|u| u.username == username) else {
+        return Json(ApiResponse::error("User not found".to_string()));
+    };
+    if let Err(message) = apply_to_auth(&state, |auth| auth.disable_user(&username)) {
+        return Json(ApiResponse::error(message));
+    }
+    
+    user.enabled = false;
+    info!("User disabled via management API: {}", username);
+    Json(ApiResponse::success(UserInfo {
+        username: user.username.clone(),
+        enabled: user.enabled,
+        created_at: SystemTime::now(), // TODO: Track actual creation time
+        last_login: None,
+        connection_count: 0,
+        password_expires: user.password_expires,
+    }))
+}
+
+/// Apply a user change to the running proxy, when the API is attached to one
+fn apply_to_auth(
+    state: &AppState,
+    apply: impl FnOnce(&AuthManager) -> crate::Result<()>,
+) -> std::result::Result<(), String> {
+    match &state.auth_manager {
+        Some(auth_manager) => apply(auth_manager).map_err(|e| format!("Failed to apply user change: {:#}", e)),
+        None => Ok(()),
+    }
+}
+
+/// What clients diverted to the honeypot tried to do
+pub async fn get_honeypot_report(
+    State(state): State<AppState>,
+    Query(query): Query<HoneypotQuery>,
+) -> Json<ApiResponse<HoneypotReport>> {
+    match &state.honeypot {
+        Some(honeypot) => Json(ApiResponse::success(honeypot.report(query.limit.unwrap_or(20)))),
+        None => Json(ApiResponse::error("Honeypot is not enabled".to_string())),
     }
 }
 
+/// List the fail2ban network bans and DDoS network blocks in force
+pub async fn get_network_blocks(State(state): State<AppState>) -> Json<ApiResponse<Vec<NetworkBlockInfo>>> {
+    let (Some(fail2ban), Some(ddos)) = (&state.fail2ban, &state.ddos) else {
+        return Json(ApiResponse::error("Network blocks are not available".to_string()));
+    };
+    let info = |source| move |(network, remaining): (ipnet::IpNet, Duration)| NetworkBlockInfo {
+        network,
+        source,
+        remaining_secs: remaining.as_secs(),
+    };
+    let blocks = fail2ban.get_banned_networks().into_iter().map(info(NetworkBlockSource::Fail2ban))
+        .chain(ddos.get_blocked_networks().into_iter().map(info(NetworkBlockSource::Ddos)))
+        .collect();
+    Json(ApiResponse::success(blocks))
+}
+
+/// Ban a network through fail2ban or block it through DDoS protection
+pub async fn block_network(
+    State(state): State<AppState>,
+    Json(request): Json<NetworkBlockRequest>,
+) -> Json<ApiResponse<NetworkBlockInfo>> {
+    let (Some(fail2ban), Some(ddos)) = (&state.fail2ban, &state.ddos) else {
+        return Json(ApiResponse::error("Network blocks are not available".to_string()));
+    };
+    if request.duration.is_zero() {
+        return Json(ApiResponse::error("Duration must not be zero".to_string()));
+    }
+    let network = request.network.trunc();
+    let reason = request.reason.as_deref().unwrap_or("management API");
+    let single_address = (network.prefix_len() == network.max_prefix_len()).then(|| network.addr());
+    let blocked = match request.source {
+        NetworkBlockSource::Fail2ban => {
+            fail2ban.ban_network(network, request.duration, reason);
+            match single_address {
+                Some(ip) => fail2ban.is_ip_banned(ip),
+                None => fail2ban.get_banned_networks().iter().any(|(banned, _)| *banned == network),
+            }
+        }
+        NetworkBlockSource::Ddos => {
+            ddos.block_network(network, request.duration, reason);
+            true
+        }
+    };
+    if !blocked {
+        return Json(ApiResponse::error(format!("{} is whitelisted", network)));
+    }
+    info!("Network {} blocked via management API ({:?}) for {:?}", network, request.source, request.duration);
+    Json(ApiResponse::success(NetworkBlockInfo {
+        network,
+        source: request.source,
+        remaining_secs: request.duration.as_secs(),
+    }))
+}
+
+/// Lift a fail2ban network ban or DDoS network block
+pub async fn unblock_network(
+    State(state): State<AppState>,
+    Query(query): Query<NetworkUnblockQuery>,
+) -> Json<ApiResponse<()>> {
+    let (Some(fail2ban), Some(ddos)) = (&state.fail2ban, &state.ddos) else {
+        return Json(ApiResponse::error("Network blocks are not available".to_string()));
+    };
+    let lifted = match query.source {
+        NetworkBlockSource::Fail2ban => fail2ban.unban_network(query.network),
+        NetworkBlockSource::Ddos => ddos.unblock_network(query.network),
+    };
+    if !lifted {
+        return Json(ApiResponse::error("Network block not found".to_string()));
+    }
+    info!("Network {} unblocked via management API ({:?})", query.network, query.source);
+    Json(ApiResponse::success(()))
+}
+
+/// List sticky upstream pins
+pub async fn get_sticky_sessions(State(state): State<AppState>) -> Json<ApiResponse<Vec<StickySession>>> {
+    match sticky_sessions(&state) {
+        Some(table) => Json(ApiResponse::success(table.sessions())),
+        None => Json(ApiResponse::error("Sticky sessions are not enabled".to_string())),
+    }
+}
+
+/// Drop a sticky pin, so the session's next connection picks an upstream afresh
+pub async fn delete_sticky_session(
+    State(state): State<AppState>,
+    Path(key): Path<String>,
+) -> Json<ApiResponse<()>> {
+    let Some(table) = sticky_sessions(&state) else {
+        return Json(ApiResponse::error("Sticky sessions are not enabled".to_string()));
+    };
+    if !table.remove(&key) {
+        return Json(ApiResponse::error("Sticky session not found".to_string()));
+    }
+    info!("Sticky session removed via management API: {}", key);
+    Json(ApiResponse::success(()))
+}
+
+/// Move a sticky session to the next configured upstream
+pub async fn rotate_sticky_session(
+    State(state): State<AppState>,
+    Path(key): Path<String>,
+) -> Json<ApiResponse<StickySession>> {
+    let Some(table) = sticky_sessions(&state) else {
+        return Json(ApiResponse::error("Sticky sessions are not enabled".to_string()));
+    };
+    let config = state.config.read().await;
+    let upstreams: Vec<&str> = config.routing.upstream_proxies.iter().map(|u| u.name.as_str()).collect();
+    match table.rotate(&key, &upstreams) {
+        Ok(session) => Json(ApiResponse::success(session)),
+        Err(e) => Json(ApiResponse::error(format!("{:#}", e))),
+    }
+}
+
+/// Sticky session table of the running proxy, if pinning is turned on
+fn sticky_sessions(state: &AppState) -> Option<&StickySessionTable> {
+    state.sticky_sessions.as_deref().filter(|table| table.is_enabled())
+}
+
+/// Check a request against the routing rules without connecting anywhere
+pub async fn test_routing(
+    State(state): State<AppState>,
+    Json(request): Json<RoutingTestRequest>,
+) -> Json<ApiResponse<RoutingTestResult>> {
+    if request.target.is_empty() {
+        return Json(ApiResponse::error("Target must not be empty".to_string()));
+    }
+    let config = state.config.read().await;
+    let groups = match &request.groups {
+        Some(groups) => groups.clone(),
+        None => config.auth.resolve_groups(request.user.as_deref(), &[]),
+    };
+    // Without a running proxy, try the rules as currently configured
+    let router = match &state.router {
+        Some(router) => Arc::clone(router),
+        None => Arc::new(Router::new(Arc::new(config.clone()))),
+    };
+    drop(config);
+
+    let target = match request.target.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => TargetAddr::Ipv4(ip),
+        Ok(IpAddr::V6(ip)) => TargetAddr::Ipv6(ip),
+        Err(_) => TargetAddr::Domain(request.target.clone()),
+    };
+    let explanation = router.explain_request(
+        &target,
+        request.port,
+        request.source_ip.unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+        request.user.as_deref(),
+        &groups,
+    ).await;
+    Json(ApiResponse::success(RoutingTestResult {
+        routing_enabled: router.is_routing_enabled(),
+        blocklist: explanation.blocklist,
+        matched_rule: explanation.matched_rule,
+        decision: RoutingTestDecision::from(&explanation.decision),
+        rules: explanation.rules,
+    }))
+}
+
 /// Export metrics in various formats
 pub async fn export_metrics(
     State(state): State<AppState>,
@@ -287,6 +687,7 @@ pub async fn export_metrics(
                 auth_attempts: state.metrics.get_auth_attempts(),
                 auth_failures: state.metrics.get_auth_failures(),
                 blocked_requests: state.metrics.get_blocked_requests(),
+                blocked_by_reason: state.metrics.get_blocked_requests_by_reason(),
                 uptime_seconds: SystemTime::now()
                     .duration_since(state.start_time)
                     .unwrap_or_default()
@@ -341,6 +742,13 @@ mod tests {
             config: Arc::new(RwLock::new(Config::default())),
             metrics: Arc::new(Metrics::new()),
             start_time: SystemTime::now(),
+            auth_manager: None,
+            sticky_sessions: None,
+            router: None,
+            update_checker: None,
+            honeypot: None,
+            fail2ban: None,
+            ddos: None,
         }
     }
     
@@ -359,6 +767,15 @@ mod tests {
         assert!(response.0.data.is_some());
     }
     
+    #[tokio::test]
+    async fn test_get_capabilities() {
+        let state = create_test_state();
+        let response = get_capabilities(State(state)).await;
+        let report = response.0.data.unwrap();
+        assert_eq!(report.features.get("geoip"), Some(&cfg!(feature = "geoip")));
+        assert!(!report.failure_policies.auth_backend.allows());
+    }
+    
     #[tokio::test]
     async fn test_create_user() {
         let state = create_test_state();
@@ -383,11 +800,7 @@ mod tests {
         // Add initial user
         {
             let mut config = state.config.write().await;
-            config.auth.users.push(UserConfig {
-                username: "existing".to_string(),
-                password: "pass".to_string(),
-                enabled: true,
-            });
+            config.auth.users.push(UserConfig::new("existing", "pass"));
         }
         
         // Try to create duplicate
@@ -401,4 +814,83 @@ mod tests {
         assert!(!response.0.success);
         assert!(response.0.error.is_some());
     }
+    
+    #[tokio::test]
+    async fn test_sticky_session_rotation() {
+        use crate::config::{StickySessionConfig, UpstreamProxyConfig};
+        
+        let mut config = Config::default();
+        for (name, addr) in [("eu-1", "192.0.2.10:1080"), ("eu-2", "192.0.2.11:1080")] {
+            config.routing.upstream_proxies.push(UpstreamProxyConfig {
+                name: name.to_string(),
+                addr: addr.parse().unwrap(),
+                protocol: "socks5".to_string(),
+                auth: None,
+                monthly_cap_bytes: None,
+                cost_per_gb: None,
+                monthly_budget: None,
+                weight: 1,
+                tunnel: None,
+            });
+        }
+        let table = Arc::new(StickySessionTable::new(StickySessionConfig {
+            enabled: true,
+            ..Default::default()
+        }));
+        table.pin("client:192.0.2.1".to_string(), "eu-1");
+        let state = AppState {
+            config: Arc::new(RwLock::new(config)),
+            sticky_sessions: Some(table.clone()),
+            ..create_test_state()
+        };
+        
+        let rotated = rotate_sticky_session(State(state.clone()), Path("client:192.0.2.1".to_string())).await;
+        assert_eq!(rotated.0.data.unwrap().upstream, "eu-2");
+        let listed = get_sticky_sessions(State(state.clone())).await.0.data.unwrap();
+        assert_eq!(listed.len(), 1);
+        
+        assert!(delete_sticky_session(State(state.clone()), Path("client:192.0.2.1".to_string())).await.0.success);
+        assert!(!delete_sticky_session(State(state), Path("client:192.0.2.1".to_string())).await.0.success);
+        assert!(table.sessions().is_empty());
+    }
+    
+    #[tokio::test]
+    async fn test_honeypot_report() {
+        let query = || Query(HoneypotQuery { limit: None });
+        assert!(!get_honeypot_report(State(create_test_state()), query()).await.0.success);
+        
+        let honeypot = Honeypot::new(crate::security::HoneypotConfig { enabled: true, ..Default::default() });
+        let state = AppState {
+            honeypot: Some(Arc::new(honeypot)),
+            ..create_test_state()
+        };
+        let report = get_honeypot_report(State(state), query()).await.0.data.unwrap();
+        assert_eq!(report.total_sessions, 0);
+    }
+    
+    #[tokio::test]
+    async fn test_user_changes_reach_auth_manager() {
+        let config = Config::default();
+        let auth_manager = Arc::new(AuthManager::new(Arc::new(config.clone())));
+        let state = AppState {
+            auth_manager: Some(auth_manager.clone()),
+            ..create_test_state()
+        };
+        
+        let request = CreateUserRequest {
+            username: "runtime".to_string(),
+            password: "pass".to_string(),
+            enabled: true,
+        };
+        assert!(create_user(State(state.clone()), Json(request)).await.unwrap().0.success);
+        assert!(auth_manager.validate_user("runtime", "pass"));
+        
+        let response = disable_user(State(state.clone()), Path("runtime".to_string())).await;
+        assert!(response.0.success);
+        assert!(!auth_manager.validate_user("runtime", "pass"));
+        
+        assert!(delete_user(State(state.clone()), Path("runtime".to_string())).await.0.success);
+        assert!(auth_manager.remove_user("runtime").is_err());
+        assert!(state.config.read().await.auth.users.is_empty());
+    }
 }
\ No newline at end of file
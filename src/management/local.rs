@@ -0,0 +1,235 @@
+//! Local Management Channel
+//!
+//! A line-oriented control channel for admin tooling on the same host. Each
+//! request is a single command word and each response is one line of JSON
+//! carrying the same `ApiResponse` payloads as the REST API. On Windows the
+//! channel is a named pipe that rejects remote clients and is guarded by a
+//! configurable security descriptor, so no TCP port has to be opened.
+
+use super::handlers::{self, AppState};
+use super::types::{ApiResponse, LocalChannelConfig};
+use crate::Result;
+use axum::extract::State;
+use serde::Serialize;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tracing::{debug, info};
+
+/// Commands understood by the local channel
+pub const COMMANDS: [&str; 7] = ["health", "status", "stats", "capabilities", "version", "reload", "help"];
+
+/// Execute a single command and return its JSON response line
+pub async fn handle_command(state: &AppState, line: &str) -> String {
+    let command = line.trim().to_ascii_lowercase();
+    match command.as_str() {
+        "health" => to_json(&handlers::health_check().await.0),
+        "status" => to_json(&handlers::get_server_status(State(state.clone())).await.0),
+        "stats" => to_json(&handlers::get_stats(State(state.clone())).await.0),
+        "capabilities" => to_json(&handlers::get_capabilities(State(state.clone())).await.0),
+        "version" => to_json(&handlers::get_version(State(state.clone())).await.0),
+        "reload" => to_json(&handlers::reload_config(State(state.clone())).await.0),
+        "help" => to_json(&ApiResponse::success(COMMANDS)),
+        other => to_json(&ApiResponse::<()>::error(format!("Unknown command: {}", other))),
+    }
+}
+
+/// Serve commands over a connected stream until the client disconnects
+pub async fn serve_stream<S>(stream: S, state: AppState) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut lines = BufReader::new(reader).lines();
+
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        debug!("Local management command: {}", line.trim());
+        let mut response = handle_command(&state, &line).await;
+        response.push('\n');
+        writer.write_all(response.as_bytes()).await?;
+        writer.flush().await?;
+    }
+
+    Ok(())
+}
+
+fn to_json<T: Serialize>(response: &ApiResponse<T>) -> String {
+    serde_json::to_string(response)
+        .unwrap_or_else(|e| format!(r#"{{"success":false,"error":"Serialization failed: {}"}}"#, e))
+}
+
+/// Run the local management channel
+#[cfg(windows)]
+pub async fn start(config: LocalChannelConfig, state: AppState) -> Result<()> {
+    windows::serve_pipe(config, state).await
+}
+
+/// Run the local management channel
+#[cfg(not(windows))]
+pub async fn start(config: LocalChannelConfig, _state: AppState) -> Result<()> {
+    info!(
+        "Local management channel {} requested but named pipes are only supported on Windows",
+        config.pipe_name
+    );
+    Ok(())
+}
+
+#[cfg(windows)]
+mod windows {
+    use super::*;
+    use anyhow::{anyhow, Context};
+    use std::ffi::c_void;
+    use std::os::windows::ffi::OsStrExt;
+    use tokio::net::windows::named_pipe::{NamedPipeServer, ServerOptions};
+    use tracing::warn;
+    use windows_sys::Win32::Foundation::LocalFree;
+    use windows_sys::Win32::Security::Authorization::{
+        ConvertStringSecurityDescriptorToSecurityDescriptorW, SDDL_REVISION_1,
+    };
+    use windows_sys::Win32::Security::SECURITY_ATTRIBUTES;
+
+    /// Security descriptor parsed from SDDL, freed with `LocalFree`
+    struct SecurityDescriptor(*mut c_void);
+
+    // SAFETY: the descriptor is immutable after creation and only read by the OS
+    unsafe impl Send for SecurityDescriptor {}
+    unsafe impl Sync for SecurityDescriptor {}
+
+    impl SecurityDescriptor {
+        fn from_sddl(sddl: &str) -> Result<Self> {
+            let wide: Vec<u16> = std::ffi::OsStr::new(sddl)
+                .encode_wide()
+                .chain(std::iter::once(0))
+                .collect();
+            let mut descriptor: *mut c_void = std::ptr::null_mut();
+            // SAFETY: `wide` is NUL-terminated and outlives the call
+            let ok = unsafe {
+                ConvertStringSecurityDescriptorToSecurityDescriptorW(
+                    wide.as_ptr(),
+                    SDDL_REVISION_1,
+                    &mut descriptor,
+                    std::ptr::null_mut(),
+                )
+            };
+            if ok == 0 {
+                return Err(anyhow!(
+                    "Invalid security descriptor '{}': {}",
+                    sddl,
+                    std::io::Error::last_os_error()
+                ));
+            }
+            Ok(Self(descriptor))
+        }
+
+        fn create_pipe(&self, name: &str, first: bool) -> Result<NamedPipeServer> {
+            let mut attributes = SECURITY_ATTRIBUTES {
+                nLength: std::mem::size_of::<SECURITY_ATTRIBUTES>() as u32,
+                lpSecurityDescriptor: self.0,
+                bInheritHandle: 0,
+            };
+            // SAFETY: `attributes` points at a valid descriptor for the duration of the call
+            let server = unsafe {
+                ServerOptions::new()
+                    .first_pipe_instance(first)
+                    .reject_remote_clients(true)
+                    .create_with_security_attributes_raw(
+                        name,
+                        &mut attributes as *mut SECURITY_ATTRIBUTES as *mut c_void,
+                    )
+            }
+            .with_context(|| format!("Failed to create named pipe {}", name))?;
+            Ok(server)
+        }
+    }
+
+    impl Drop for SecurityDescriptor {
+        fn drop(&mut self) {
+            // SAFETY: the pointer was allocated by ConvertStringSecurityDescriptorToSecurityDescriptorW
+            unsafe {
+                LocalFree(self.0 as _);
+            }
+        }
+    }
+
+    pub(super) async fn serve_pipe(config: LocalChannelConfig, state: AppState) -> Result<()> {
+        let descriptor = SecurityDescriptor::from_sddl(&config.security_descriptor)?;
+        let mut server = descriptor.create_pipe(&config.pipe_name, true)?;
+        info!("Local management channel listening on {}", config.pipe_name);
+
+        loop {
+            server.connect().await
+                .with_context(|| format!("Failed to accept on {}", config.pipe_name))?;
+
+            // Open the next instance before handing this one off so clients never see the pipe missing
+            let client = std::mem::replace(
+                &mut server,
+                descriptor.create_pipe(&config.pipe_name, false)?,
+            );
+
+            let state = state.clone();
+            tokio::spawn(async move {
+                if let Err(e) = serve_stream(client, state).await {
+                    warn!("Local management client error: {}", e);
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::metrics::Metrics;
+    use std::sync::Arc;
+    use std::time::SystemTime;
+    use tokio::sync::RwLock;
+
+    fn create_test_state() -> AppState {
+        AppState {
+            config: Arc::new(RwLock::new(Config::default())),
+            metrics: Arc::new(Metrics::new()),
+            start_time: SystemTime::now(),
+            auth_manager: None,
+            sticky_sessions: None,
+            router: None,
+            update_checker: None,
+            honeypot: None,
+            fail2ban: None,
+            ddos: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_handle_command() {
+        let state = create_test_state();
+
+        let response: serde_json::Value =
+            serde_json::from_str(&handle_command(&state, "status\r\n").await).unwrap();
+        assert_eq!(response["success"], true);
+
+        let response: serde_json::Value =
+            serde_json::from_str(&handle_command(&state, "shutdown-everything").await).unwrap();
+        assert_eq!(response["success"], false);
+    }
+
+    #[tokio::test]
+    async fn test_serve_stream() {
+        let (client, server) = tokio::io::duplex(4096);
+        let handle = tokio::spawn(serve_stream(server, create_test_state()));
+
+        let (reader, mut writer) = tokio::io::split(client);
+        writer.write_all(b"health\n\nstats\n").await.unwrap();
+        writer.shutdown().await.unwrap();
+
+        let mut lines = BufReader::new(reader).lines();
+        let mut responses = Vec::new();
+        while let Some(line) = lines.next_line().await.unwrap() {
+            responses.push(line);
+        }
+
+        assert_eq!(responses.len(), 2);
+        handle.await.unwrap().unwrap();
+    }
+}
@@ -5,6 +5,7 @@
 pub mod api;
 pub mod auth;
 pub mod handlers;
+pub mod local;
 pub mod server;
 pub mod types;
 
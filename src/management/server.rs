@@ -3,22 +3,29 @@
 use super::{
     api::ManagementApi,
     handlers::AppState,
-    types::ApiAuthConfig,
+    local,
+    types::{ApiAuthConfig, LocalChannelConfig},
 };
-use crate::{config::Config, metrics::Metrics, Result};
+use crate::{auth::AuthManager, config::Config, metrics::Metrics, routing::StickySessionTable, security::{DdosProtection, Fail2BanManager, Honeypot}, update::UpdateChecker, Result};
+use crate::tls::{CertificateFiles, ReloadingCertResolver};
 use anyhow::Context;
 use axum::Router;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto::Builder;
+use hyper_util::service::TowerToHyperService;
 use std::net::SocketAddr;
 use std::sync::Arc;
 use std::time::SystemTime;
 use tokio::{net::TcpListener, sync::RwLock};
-use tracing::{error, info};
+use tracing::{debug, error, info, warn};
 
 /// Management API server
 pub struct ManagementServer {
     bind_addr: SocketAddr,
     app_state: AppState,
     auth_config: ApiAuthConfig,
+    local_channel: LocalChannelConfig,
+    tls: Option<CertificateFiles>,
 }
 
 impl ManagementServer {
@@ -33,19 +40,87 @@ impl ManagementServer {
             config,
             metrics,
             start_time: SystemTime::now(),
+            auth_manager: None,
+            sticky_sessions: None,
+            router: None,
+            update_checker: None,
+            honeypot: None,
+            fail2ban: None,
+            ddos: None,
         };
         
         Self {
             bind_addr,
             app_state,
             auth_config,
+            local_channel: LocalChannelConfig::default(),
+            tls: None,
         }
     }
     
+    /// Apply user changes made through the API to a running authentication manager
+    pub fn with_auth_manager(mut self, auth_manager: Arc<AuthManager>) -> Self {
+        self.app_state.auth_manager = Some(auth_manager);
+        self
+    }
+    
+    /// Dry-run requests against the routing rules of a running connection manager
+    pub fn with_router(mut self, router: Arc<crate::routing::Router>) -> Self {
+        self.app_state.router = Some(router);
+        self
+    }
+    
+    /// List and manage the sticky upstream pins of a running connection manager
+    pub fn with_sticky_sessions(mut self, sticky_sessions: Arc<StickySessionTable>) -> Self {
+        self.app_state.sticky_sessions = Some(sticky_sessions);
+        self
+    }
+    
+    /// Report what clients diverted to a running honeypot tried to do
+    pub fn with_honeypot(mut self, honeypot: Arc<Honeypot>) -> Self {
+        self.app_state.honeypot = Some(honeypot);
+        self
+    }
+    
+    /// Ban and block networks through the fail2ban and DDoS protection of a running proxy
+    pub fn with_network_blocking(mut self, fail2ban: Arc<Fail2BanManager>, ddos: Arc<DdosProtection>) -> Self {
+        self.app_state.fail2ban = Some(fail2ban);
+        self.app_state.ddos = Some(ddos);
+        self
+    }
+    
+    /// Report the findings of a running release check
+    pub fn with_update_checker(mut self, update_checker: Arc<UpdateChecker>) -> Self {
+        self.app_state.update_checker = Some(update_checker);
+        self
+    }
+    
+    /// Also serve the local management channel alongside the HTTP API
+    pub fn with_local_channel(mut self, local_channel: LocalChannelConfig) -> Self {
+        self.local_channel = local_channel;
+        self
+    }
+    
+    /// Serve the API over HTTPS with these certificates, reloading them when the files change
+    pub fn with_tls(mut self, tls: CertificateFiles) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+    
     /// Start the management API server
     pub async fn start(self) -> Result<()> {
         info!("Starting management API server on {}", self.bind_addr);
         
+        if self.local_channel.enabled {
+            let local_channel = self.local_channel.clone();
+            let state = self.app_state.clone();
+            tokio::spawn(async move {
+                if let Err(e) = local::start(local_channel, state).await {
+                    error!("Local management channel error: {:#}", e);
+                }
+            });
+        }
+        
         // Create the router
         let app = ManagementApi::create_router(self.app_state, self.auth_config);
         
@@ -54,6 +129,10 @@ impl ManagementServer {
             .await
             .with_context(|| format!("Failed to bind management API server to {}", self.bind_addr))?;
         
+        if let Some(files) = self.tls {
+            return serve_tls(listener, app, files).await;
+        }
+        
         info!("Management API server listening on {}", self.bind_addr);
         
         // Start serving
@@ -71,6 +150,57 @@ impl ManagementServer {
     }
 }
 
+const TLS_HANDSHAKE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// Serve the API over TLS; the certificates are picked per handshake, so a
+/// reload applies to new connections while open ones carry on
+async fn serve_tls(listener: TcpListener, app: Router, files: CertificateFiles) -> Result<()> {
+    let resolver = Arc::new(
+        ReloadingCertResolver::new(files).context("Failed to load management API certificates")?,
+    );
+    let acceptor = resolver.acceptor()?;
+    let _watcher = match resolver.watch() {
+        Ok(watcher) => Some(watcher),
+        Err(e) => {
+            warn!("Management API certificates will not reload on change: {:#}", e);
+            None
+        }
+    };
+    
+    info!("Management API server listening on {} (https)", listener.local_addr()?);
+    
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!("Failed to accept management API connection: {}", e);
+                continue;
+            }
+        };
+        let acceptor = acceptor.clone();
+        let service = TowerToHyperService::new(app.clone());
+        tokio::spawn(async move {
+            let stream = match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(e)) => {
+                    debug!("TLS handshake with management client {} failed: {}", peer, e);
+                    return;
+                }
+                Err(_) => {
+                    debug!("TLS handshake with management client {} timed out", peer);
+                    return;
+                }
+            };
+            if let Err(e) = Builder::new(TokioExecutor::new())
+                .serve_connection_with_upgrades(TokioIo::new(stream), service)
+                .await
+            {
+                debug!("Management API connection from {} ended: {}", peer, e);
+            }
+        });
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -2,12 +2,16 @@
 
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::net::SocketAddr;
-use std::time::SystemTime;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, SystemTime};
+use ipnet::IpNet;
 use crate::config::Config;
+use crate::routing::{PortRange, RouteDecision, RuleCheck};
+use crate::relay::TrafficClass;
+use crate::security::{BlockReason, FailurePolicyConfig};
 
 /// API response wrapper
-#[derive(Debug, Serialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct ApiResponse<T> {
     pub success: bool,
     pub data: Option<T>,
@@ -36,7 +40,7 @@ impl<T> ApiResponse<T> {
 }
 
 /// Server status information
-#[derive(Debug, Serialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct ServerStatus {
     pub uptime_seconds: u64,
     pub active_connections: usize,
@@ -49,7 +53,7 @@ pub struct ServerStatus {
 }
 
 /// Connection information
-#[derive(Debug, Serialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct ConnectionInfo {
     pub id: String,
     pub client_addr: SocketAddr,
@@ -58,6 +62,8 @@ pub struct ConnectionInfo {
     pub start_time: SystemTime,
     pub bytes_up: u64,
     pub bytes_down: u64,
+    /// Live transfer rates in bytes per second
+    pub throughput: crate::relay::ThroughputRates,
     pub status: String,
 }
 
@@ -69,6 +75,15 @@ pub struct CreateUserRequest {
     pub enabled: bool,
 }
 
+/// Password rotation request
+#[derive(Debug, Deserialize)]
+pub struct RotatePasswordRequest {
+    pub password: String,
+    /// When the new password expires (RFC 3339); omit for no expiry
+    #[serde(default, with = "humantime_serde")]
+    pub expires: Option<SystemTime>,
+}
+
 /// User management response
 #[derive(Debug, Serialize)]
 pub struct UserInfo {
@@ -77,6 +92,7 @@ pub struct UserInfo {
     pub created_at: SystemTime,
     pub last_login: Option<SystemTime>,
     pub connection_count: u64,
+    pub password_expires: Option<SystemTime>,
 }
 
 /// Configuration update request
@@ -87,7 +103,7 @@ pub struct ConfigUpdateRequest {
 }
 
 /// Statistics summary
-#[derive(Debug, Serialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct StatsSummary {
     pub total_connections: u64,
     pub active_connections: usize,
@@ -95,13 +111,22 @@ pub struct StatsSummary {
     pub auth_attempts: u64,
     pub auth_failures: u64,
     pub blocked_requests: u64,
+    pub blocked_by_reason: HashMap<BlockReason, u64>,
     pub uptime_seconds: u64,
     pub top_destinations: Vec<DestinationStats>,
     pub top_users: Vec<UserStats>,
 }
 
-/// Destination statistics
+/// Capabilities report describing enabled features and active failure policies
 #[derive(Debug, Serialize)]
+pub struct CapabilitiesReport {
+    pub version: String,
+    pub features: HashMap<String, bool>,
+    pub failure_policies: FailurePolicyConfig,
+}
+
+/// Destination statistics
+#[derive(Debug, Deserialize, Serialize)]
 pub struct DestinationStats {
     pub destination: String,
     pub connection_count: u64,
@@ -109,7 +134,7 @@ pub struct DestinationStats {
 }
 
 /// User statistics
-#[derive(Debug, Serialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct UserStats {
     pub username: String,
     pub connection_count: u64,
@@ -122,7 +147,7 @@ pub struct UserStats {
 pub struct CreateRuleRequest {
     pub pattern: String,
     pub action: String,
-    pub ports: Option<Vec<u16>>,
+    pub ports: Option<Vec<PortRange>>,
     pub countries: Option<Vec<String>>,
     pub enabled: bool,
 }
@@ -133,13 +158,112 @@ pub struct RuleInfo {
     pub id: String,
     pub pattern: String,
     pub action: String,
-    pub ports: Option<Vec<u16>>,
+    pub ports: Option<Vec<PortRange>>,
     pub countries: Option<Vec<String>>,
     pub enabled: bool,
     pub created_at: SystemTime,
     pub hit_count: u64,
 }
 
+/// Routing rule dry-run request
+#[derive(Debug, Deserialize)]
+pub struct RoutingTestRequest {
+    /// Domain name or IP address
+    pub target: String,
+    pub port: u16,
+    /// Client address; rules restricted to source IPs see `0.0.0.0` when omitted
+    pub source_ip: Option<IpAddr>,
+    pub user: Option<String>,
+    /// Groups to test with; when omitted, the user's configured groups
+    pub groups: Option<Vec<String>>,
+}
+
+/// Routing rule dry-run result
+#[derive(Debug, Serialize)]
+pub struct RoutingTestResult {
+    pub routing_enabled: bool,
+    /// Every rule in priority order, with the condition that failed
+    pub rules: Vec<RuleCheck>,
+    /// Blocklist the target is on, which decides before any rule
+    pub blocklist: Option<String>,
+    pub matched_rule: Option<String>,
+    pub decision: RoutingTestDecision,
+}
+
+/// What the routing rules decided in a dry run
+#[derive(Debug, Serialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum RoutingTestDecision {
+    Allow {
+        upstream: Option<SocketAddr>,
+        chain: Vec<SocketAddr>,
+        dscp: Option<u8>,
+        bandwidth_class: Option<String>,
+        /// Traffic class set by the rule; the user's groups may set one otherwise
+        traffic_class: Option<TrafficClass>,
+        transformers: Vec<String>,
+    },
+    Block { reason: String, code: BlockReason },
+    Redirect { target: SocketAddr },
+    Rewrite { target: String },
+}
+
+impl From<&RouteDecision> for RoutingTestDecision {
+    fn from(decision: &RouteDecision) -> Self {
+        match decision {
+            RouteDecision::Allow { upstream, chain, dscp, bandwidth_class, traffic_class, transformers, .. } => RoutingTestDecision::Allow {
+                upstream: upstream.as_ref().map(|proxy| proxy.addr),
+                chain: chain.iter().map(|proxy| proxy.addr).collect(),
+                dscp: *dscp,
+                bandwidth_class: bandwidth_class.as_ref().map(|class| class.name().to_string()),
+                traffic_class: *traffic_class,
+                transformers: transformers.clone(),
+            },
+            RouteDecision::Block { reason, code } => RoutingTestDecision::Block { reason: reason.clone(), code: *code },
+            RouteDecision::Redirect { target, .. } => RoutingTestDecision::Redirect { target: *target },
+            RouteDecision::Rewrite { target, .. } => RoutingTestDecision::Rewrite { target: target.to_string() },
+        }
+    }
+}
+
+/// Which module enforces a network block
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NetworkBlockSource {
+    /// A fail2ban ban, refusing authentication from the network
+    Fail2ban,
+    /// A DDoS block, refusing connections from the network
+    Ddos,
+}
+
+/// Network ban or block request
+#[derive(Debug, Deserialize)]
+pub struct NetworkBlockRequest {
+    /// CIDR range, or a single address as `/32` or `/128`
+    pub network: IpNet,
+    pub source: NetworkBlockSource,
+    /// How long the block lasts, such as `1h`
+    #[serde(with = "humantime_serde")]
+    pub duration: Duration,
+    #[serde(default)]
+    pub reason: Option<String>,
+}
+
+/// Network whose block is lifted
+#[derive(Debug, Deserialize)]
+pub struct NetworkUnblockQuery {
+    pub network: IpNet,
+    pub source: NetworkBlockSource,
+}
+
+/// Network block in force
+#[derive(Debug, Serialize)]
+pub struct NetworkBlockInfo {
+    pub network: IpNet,
+    pub source: NetworkBlockSource,
+    pub remaining_secs: u64,
+}
+
 /// Health check response
 #[derive(Debug, Serialize)]
 pub struct HealthStatus {
@@ -215,4 +339,37 @@ impl Default for ApiAuthConfig {
             jwt: None,
         }
     }
-}
\ No newline at end of file
+}
+
+/// Local management channel configuration
+///
+/// On Windows this is a named pipe that never accepts remote clients; access
+/// is controlled by the pipe's security descriptor instead of API keys.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct LocalChannelConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_pipe_name")]
+    pub pipe_name: String,
+    /// SDDL string applied to the pipe (defaults to SYSTEM and Administrators only)
+    #[serde(default = "default_pipe_sddl")]
+    pub security_descriptor: String,
+}
+
+fn default_pipe_name() -> String {
+    r"\\.\pipe\rustproxy-mgmt".to_string()
+}
+
+fn default_pipe_sddl() -> String {
+    "D:P(A;;GA;;;SY)(A;;GA;;;BA)".to_string()
+}
+
+impl Default for LocalChannelConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            pipe_name: default_pipe_name(),
+            security_descriptor: default_pipe_sddl(),
+        }
+    }
+}
@@ -1,11 +1,13 @@
 //! Metrics Collector
 
-use super::{ConnectionStats, ActiveConnection, MetricsRegistry, HistoricalStats, ActivitySummary};
+use super::{ConnectionStats, ConnectionResult, ActiveConnection, MetricsRegistry, HistoricalStats, ActivitySummary};
+use super::retention::{RetentionRun, RetentionStatus};
 use std::collections::HashMap;
 use std::sync::{Arc, RwLock};
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::time::{Duration, SystemTime};
-use prometheus::{Counter, Gauge, Histogram, Registry, TextEncoder};
+use prometheus::{Counter, CounterVec, Gauge, GaugeVec, Histogram, Opts, Registry, TextEncoder};
+use crate::security::BlockReason;
 use tracing::{info, warn, error, debug};
 
 /// Collects and exports metrics
@@ -21,6 +23,24 @@ pub struct Metrics {
     auth_attempts_total: Counter,
     auth_success_total: Counter,
     blocked_requests_total: Counter,
+    blocked_requests_by_reason: CounterVec,
+    redacted_connections_total: Counter,
+    redirected_connections_total: Counter,
+    rewritten_connections_total: Counter,
+    upstream_connects_total: CounterVec,
+    upstream_hop_failures_total: CounterVec,
+    upstream_connect_duration: Histogram,
+    udp_datagrams_rejected_total: CounterVec,
+    blocking_pool_queued: Gauge,
+    blocking_pool_running: Gauge,
+    blocking_pool_tasks_total: CounterVec,
+    blocking_pool_wait_seconds_total: Counter,
+    dns_cache_lookups_total: CounterVec,
+    rule_matches_total: CounterVec,
+    connection_lifetime_warnings_total: Counter,
+    reputation_feed_entries: GaugeVec,
+    reputation_hits_total: CounterVec,
+    client_geo_decisions_total: CounterVec,
     
     // Internal counters
     total_connections: AtomicU64,
@@ -28,6 +48,7 @@ pub struct Metrics {
     auth_attempts: AtomicU64,
     auth_successes: AtomicU64,
     blocked_requests: AtomicU64,
+    retention: RwLock<RetentionStatus>,
 }
 
 impl Metrics {
@@ -73,6 +94,128 @@ impl Metrics {
             "Total blocked requests"
         ).expect("Failed to create blocked_requests_total counter");
         
+        let blocked_requests_by_reason = CounterVec::new(
+            Opts::new(
+                "socks5_blocked_requests_by_reason_total",
+                "Total blocked requests by reason code"
+            ),
+            &["reason"]
+        ).expect("Failed to create blocked_requests_by_reason counter");
+        
+        let redacted_connections_total = Counter::new(
+            "socks5_redacted_connections_total",
+            "Connections counted only in aggregate for privacy"
+        ).expect("Failed to create redacted_connections_total counter");
+        
+        let redirected_connections_total = Counter::new(
+            "socks5_redirected_connections_total",
+            "Connections sent to a routing rule's redirect target instead of the requested destination"
+        ).expect("Failed to create redirected_connections_total counter");
+        
+        let rewritten_connections_total = Counter::new(
+            "socks5_rewritten_connections_total",
+            "Connections sent to the host a routing rule rewrote the requested domain to"
+        ).expect("Failed to create rewritten_connections_total counter");
+        
+        let upstream_connects_total = CounterVec::new(
+            Opts::new(
+                "socks5_upstream_connects_total",
+                "Connections dialed through upstream proxies by chain length and result"
+            ),
+            &["hops", "result"]
+        ).expect("Failed to create upstream_connects_total counter");
+        
+        let upstream_hop_failures_total = CounterVec::new(
+            Opts::new(
+                "socks5_upstream_hop_failures_total",
+                "Failed upstream connections by the chain hop that failed"
+            ),
+            &["hop"]
+        ).expect("Failed to create upstream_hop_failures_total counter");
+        
+        let upstream_connect_duration = Histogram::with_opts(
+            prometheus::HistogramOpts::new(
+                "socks5_upstream_connect_duration_seconds",
+                "Time to establish a connection through an upstream proxy chain"
+            ).buckets(vec![0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0])
+        ).expect("Failed to create upstream_connect_duration histogram");
+        
+        let udp_datagrams_rejected_total = CounterVec::new(
+            Opts::new(
+                "socks5_udp_datagrams_rejected_total",
+                "UDP relay datagrams dropped as spoofed, replayed, malformed, or over a rate limit"
+            ),
+            &["reason"]
+        ).expect("Failed to create udp_datagrams_rejected_total counter");
+        
+        let blocking_pool_queued = Gauge::new(
+            "socks5_blocking_pool_queued",
+            "Blocking tasks waiting for a free blocking pool thread"
+        ).expect("Failed to create blocking_pool_queued gauge");
+        
+        let blocking_pool_running = Gauge::new(
+            "socks5_blocking_pool_running",
+            "Blocking tasks currently running on the blocking pool"
+        ).expect("Failed to create blocking_pool_running gauge");
+        
+        let blocking_pool_tasks_total = CounterVec::new(
+            Opts::new(
+                "socks5_blocking_pool_tasks_total",
+                "Blocking pool tasks that completed or were refused because the queue was full"
+            ),
+            &["result"]
+        ).expect("Failed to create blocking_pool_tasks_total counter");
+        
+        let blocking_pool_wait_seconds_total = Counter::new(
+            "socks5_blocking_pool_wait_seconds_total",
+            "Total time blocking tasks waited for a blocking pool thread"
+        ).expect("Failed to create blocking_pool_wait_seconds_total counter");
+        
+        let dns_cache_lookups_total = CounterVec::new(
+            Opts::new(
+                "socks5_dns_cache_lookups_total",
+                "Domain lookups answered from the DNS cache (hit, negative_hit) or resolved (miss)"
+            ),
+            &["result"]
+        ).expect("Failed to create dns_cache_lookups_total counter");
+        
+        let rule_matches_total = CounterVec::new(
+            Opts::new(
+                "socks5_rule_matches_total",
+                "Requests decided by each routing rule"
+            ),
+            &["rule_id"]
+        ).expect("Failed to create rule_matches_total counter");
+        
+        let connection_lifetime_warnings_total = Counter::new(
+            "socks5_connection_lifetime_warnings_total",
+            "Connections that entered the warning period before reaching the maximum connection lifetime"
+        ).expect("Failed to create connection_lifetime_warnings_total counter");
+        
+        let reputation_feed_entries = GaugeVec::new(
+            Opts::new(
+                "socks5_reputation_feed_entries",
+                "Networks listed in each IP reputation feed"
+            ),
+            &["feed"]
+        ).expect("Failed to create reputation_feed_entries gauge");
+        
+        let reputation_hits_total = CounterVec::new(
+            Opts::new(
+                "socks5_reputation_hits_total",
+                "Connections refused because their source is listed in an IP reputation feed"
+            ),
+            &["feed"]
+        ).expect("Failed to create reputation_hits_total counter");
+        
+        let client_geo_decisions_total = CounterVec::new(
+            Opts::new(
+                "socks5_client_geo_decisions_total",
+                "Connections let in or refused by the client country policy, by client country"
+            ),
+            &["country", "decision"]
+        ).expect("Failed to create client_geo_decisions_total counter");
+        
         // Register metrics
         prometheus_registry.register(Box::new(connections_total.clone()))
             .expect("Failed to register connections_total");
@@ -88,6 +231,42 @@ impl Metrics {
             .expect("Failed to register auth_success_total");
         prometheus_registry.register(Box::new(blocked_requests_total.clone()))
             .expect("Failed to register blocked_requests_total");
+        prometheus_registry.register(Box::new(blocked_requests_by_reason.clone()))
+            .expect("Failed to register blocked_requests_by_reason");
+        prometheus_registry.register(Box::new(redacted_connections_total.clone()))
+            .expect("Failed to register redacted_connections_total");
+        prometheus_registry.register(Box::new(redirected_connections_total.clone()))
+            .expect("Failed to register redirected_connections_total");
+        prometheus_registry.register(Box::new(rewritten_connections_total.clone()))
+            .expect("Failed to register rewritten_connections_total");
+        prometheus_registry.register(Box::new(upstream_connects_total.clone()))
+            .expect("Failed to register upstream_connects_total");
+        prometheus_registry.register(Box::new(upstream_hop_failures_total.clone()))
+            .expect("Failed to register upstream_hop_failures_total");
+        prometheus_registry.register(Box::new(upstream_connect_duration.clone()))
+            .expect("Failed to register upstream_connect_duration");
+        prometheus_registry.register(Box::new(udp_datagrams_rejected_total.clone()))
+            .expect("Failed to register udp_datagrams_rejected_total");
+        prometheus_registry.register(Box::new(blocking_pool_queued.clone()))
+            .expect("Failed to register blocking_pool_queued");
+        prometheus_registry.register(Box::new(blocking_pool_running.clone()))
+            .expect("Failed to register blocking_pool_running");
+        prometheus_registry.register(Box::new(blocking_pool_tasks_total.clone()))
+            .expect("Failed to register blocking_pool_tasks_total");
+        prometheus_registry.register(Box::new(blocking_pool_wait_seconds_total.clone()))
+            .expect("Failed to register blocking_pool_wait_seconds_total");
+        prometheus_registry.register(Box::new(dns_cache_lookups_total.clone()))
+            .expect("Failed to register dns_cache_lookups_total");
+        prometheus_registry.register(Box::new(rule_matches_total.clone()))
+            .expect("Failed to register rule_matches_total");
+        prometheus_registry.register(Box::new(reputation_feed_entries.clone()))
+            .expect("Failed to register reputation_feed_entries");
+        prometheus_registry.register(Box::new(reputation_hits_total.clone()))
+            .expect("Failed to register reputation_hits_total");
+        prometheus_registry.register(Box::new(client_geo_decisions_total.clone()))
+            .expect("Failed to register client_geo_decisions_total");
+        prometheus_registry.register(Box::new(connection_lifetime_warnings_total.clone()))
+            .expect("Failed to register connection_lifetime_warnings_total");
         
         let registry = Arc::new(MetricsRegistry {
             active_connections: RwLock::new(HashMap::new()),
@@ -105,11 +284,30 @@ impl Metrics {
             auth_attempts_total,
             auth_success_total,
             blocked_requests_total,
+            blocked_requests_by_reason,
+            redacted_connections_total,
+            redirected_connections_total,
+            rewritten_connections_total,
+            upstream_connects_total,
+            upstream_hop_failures_total,
+            upstream_connect_duration,
+            udp_datagrams_rejected_total,
+            blocking_pool_queued,
+            blocking_pool_running,
+            blocking_pool_tasks_total,
+            blocking_pool_wait_seconds_total,
+            dns_cache_lookups_total,
+            rule_matches_total,
+            connection_lifetime_warnings_total,
+            reputation_feed_entries,
+            reputation_hits_total,
+            client_geo_decisions_total,
             total_connections: AtomicU64::new(0),
             total_bytes: AtomicU64::new(0),
             auth_attempts: AtomicU64::new(0),
             auth_successes: AtomicU64::new(0),
             blocked_requests: AtomicU64::new(0),
+            retention: RwLock::new(RetentionStatus::default()),
         }
     }
     
@@ -147,6 +345,11 @@ impl Metrics {
     
     /// Stop tracking a connection and record final statistics
     pub fn end_connection(&self, session_id: &str) -> anyhow::Result<()> {
+        self.end_connection_with_result(session_id, ConnectionResult::Completed)
+    }
+
+    /// Stop tracking a connection, recording how it ended
+    pub fn end_connection_with_result(&self, session_id: &str, result: ConnectionResult) -> anyhow::Result<()> {
         let connection = {
             let mut active = self.registry.active_connections.write()
                 .map_err(|_| anyhow::anyhow!("Failed to acquire write lock on active connections"))?;
@@ -154,7 +357,7 @@ impl Metrics {
         };
         
         if let Some(connection) = connection {
-            let stats = connection.to_stats();
+            let stats = ConnectionStats { result, ..connection.to_stats() };
             
             // Update metrics
             self.active_connections.dec();
@@ -179,6 +382,7 @@ impl Metrics {
                 duration_secs = stats.duration.as_secs(),
                 bytes_up = stats.bytes_up,
                 bytes_down = stats.bytes_down,
+                result = stats.result.as_str(),
                 "Ended connection tracking"
             );
         } else {
@@ -188,6 +392,37 @@ impl Metrics {
         Ok(())
     }
     
+    /// Count a connection excluded from detailed tracking by privacy settings
+    ///
+    /// Only the aggregate counters move; nothing about the connection is stored.
+    pub fn start_redacted_connection(&self) {
+        self.connections_total.inc();
+        self.active_connections.inc();
+        self.redacted_connections_total.inc();
+        self.total_connections.fetch_add(1, Ordering::Relaxed);
+    }
+    
+    /// Add bytes transferred by a redacted connection to the aggregate totals
+    pub fn add_redacted_bytes(&self, bytes_up: u64, bytes_down: u64) {
+        self.bytes_transferred_total.inc_by((bytes_up + bytes_down) as f64);
+        self.total_bytes.fetch_add(bytes_up + bytes_down, Ordering::Relaxed);
+    }
+    
+    /// Finish a redacted connection
+    pub fn end_redacted_connection(&self, duration: Duration) {
+        self.active_connections.dec();
+        self.connection_duration.observe(duration.as_secs_f64());
+    }
+    
+    /// Report the live transfer rates of a tracked connection from `throughput`
+    pub fn track_throughput(&self, session_id: &str, throughput: crate::relay::Throughput) {
+        if let Ok(mut active) = self.registry.active_connections.write() {
+            if let Some(connection) = active.get_mut(session_id) {
+                connection.throughput = throughput;
+            }
+        }
+    }
+
     /// Update bytes transferred for an active connection
     pub fn update_connection_bytes(&self, session_id: &str, bytes_up: u64, bytes_down: u64) -> anyhow::Result<()> {
         let active = self.registry.active_connections.read()
@@ -247,11 +482,75 @@ impl Metrics {
     }
 
     /// Record blocked request
-    pub fn record_blocked_request(&self, reason: &str) {
+    pub fn record_blocked_request(&self, reason: BlockReason, detail: &str) {
         self.blocked_requests_total.inc();
+        self.blocked_requests_by_reason.with_label_values(&[reason.as_str()]).inc();
         self.blocked_requests.fetch_add(1, Ordering::Relaxed);
         
-        info!(reason = %reason, "Recorded blocked request");
+        info!(reason = %reason, detail = %detail, "Recorded blocked request");
+    }
+
+    /// Record a connection attempt through a chain of `hops` upstream proxies
+    ///
+    /// `failed_hop` is the 1-based hop that failed, if the attempt did.
+    pub fn record_upstream_connect(&self, hops: usize, failed_hop: Option<usize>, duration: Duration) {
+        let hops_label = hops.to_string();
+        match failed_hop {
+            Some(hop) => {
+                self.upstream_connects_total.with_label_values(&[hops_label.as_str(), "failed"]).inc();
+                self.upstream_hop_failures_total.with_label_values(&[hop.to_string().as_str()]).inc();
+            }
+            None => {
+                self.upstream_connects_total.with_label_values(&[hops_label.as_str(), "connected"]).inc();
+                self.upstream_connect_duration.observe(duration.as_secs_f64());
+            }
+        }
+    }
+    
+    /// Count a datagram dropped by a UDP relay's spoofing, replay, or rate checks
+    pub fn record_udp_rejection(&self, rejection: crate::security::udp_guard::UdpRejection) {
+        self.udp_datagrams_rejected_total.with_label_values(&[rejection.as_str()]).inc();
+    }
+    
+    /// Count a domain lookup by how the DNS cache answered it
+    pub fn record_dns_lookup(&self, outcome: crate::routing::resolver::CacheOutcome) {
+        self.dns_cache_lookups_total.with_label_values(&[outcome.as_str()]).inc();
+    }
+    
+    /// Count a connection a routing rule redirected
+    pub fn record_redirect(&self) {
+        self.redirected_connections_total.inc();
+    }
+    
+    /// Count a connection a routing rule rewrote to another host
+    pub fn record_rewrite(&self) {
+        self.rewritten_connections_total.inc();
+    }
+    
+    /// Count a request decided by a routing rule
+    pub fn record_rule_match(&self, rule_id: &str) {
+        self.rule_matches_total.with_label_values(&[rule_id]).inc();
+    }
+    
+    /// Count a connection that will soon be closed for reaching the maximum connection lifetime
+    pub fn record_lifetime_warning(&self) {
+        self.connection_lifetime_warnings_total.inc();
+    }
+    
+    /// Set how many networks a reputation feed lists
+    pub fn set_reputation_feed_entries(&self, feed: &str, entries: u64) {
+        self.reputation_feed_entries.with_label_values(&[feed]).set(entries as f64);
+    }
+    
+    /// Count a connection refused because its source is listed in a reputation feed
+    pub fn record_reputation_hit(&self, feed: &str) {
+        self.reputation_hits_total.with_label_values(&[feed]).inc();
+    }
+    
+    /// Count a connection let in or refused by the client country policy
+    pub fn record_client_geo_decision(&self, country: &str, allowed: bool) {
+        let decision = if allowed { "allowed" } else { "denied" };
+        self.client_geo_decisions_total.with_label_values(&[country, decision]).inc();
     }
     
     /// Get current activity summary
@@ -349,8 +648,50 @@ impl Metrics {
         })
     }
 
+    /// Inspect the stored connection history
+    pub(crate) fn read_history<R>(&self, f: impl FnOnce(&[ConnectionStats]) -> R) -> anyhow::Result<R> {
+        let historical = self.registry.historical_connections.read()
+            .map_err(|_| anyhow::anyhow!("Failed to acquire read lock on historical connections"))?;
+        Ok(f(&historical))
+    }
+    
+    /// Rewrite or prune the stored connection history
+    pub(crate) fn update_history<R>(&self, f: impl FnOnce(&mut Vec<ConnectionStats>) -> R) -> anyhow::Result<R> {
+        let mut historical = self.registry.historical_connections.write()
+            .map_err(|_| anyhow::anyhow!("Failed to acquire write lock on historical connections"))?;
+        Ok(f(&mut historical))
+    }
+    
+    /// Totals from the retention job
+    pub fn retention_status(&self) -> RetentionStatus {
+        self.retention.read().map(|status| status.clone()).unwrap_or_default()
+    }
+    
+    /// Fold a retention run into the totals
+    pub(crate) fn record_retention_run(&self, run: RetentionRun, salt_rotated_at: SystemTime, now: SystemTime) {
+        if let Ok(mut status) = self.retention.write() {
+            status.last_run = Some(now);
+            status.salt_rotated_at = Some(salt_rotated_at);
+            status.total_anonymized += run.anonymized;
+            status.total_deleted += run.deleted;
+        }
+    }
+
+    /// Copy a blocking pool snapshot into the Prometheus metrics
+    pub fn observe_blocking_pool(&self, stats: &crate::blocking::BlockingPoolStats) {
+        self.blocking_pool_queued.set(stats.queued as f64);
+        self.blocking_pool_running.set(stats.running as f64);
+        for (result, total) in [("completed", stats.completed), ("rejected", stats.rejected)] {
+            let counter = self.blocking_pool_tasks_total.with_label_values(&[result]);
+            counter.inc_by((total as f64 - counter.get()).max(0.0));
+        }
+        let wait = &self.blocking_pool_wait_seconds_total;
+        wait.inc_by((stats.wait_seconds - wait.get()).max(0.0));
+    }
+    
     /// Export metrics in Prometheus format
     pub fn export_prometheus(&self) -> String {
+        self.observe_blocking_pool(&crate::blocking::pool().stats());
         let encoder = TextEncoder::new();
         let metric_families = self.prometheus_registry.gather();
         
@@ -397,6 +738,18 @@ impl Metrics {
         self.blocked_requests.load(Ordering::Relaxed)
     }
     
+    /// Get blocked requests count broken down by reason code
+    pub fn get_blocked_requests_by_reason(&self) -> HashMap<BlockReason, u64> {
+        BlockReason::ALL.iter()
+            .map(|reason| {
+                let count = self.blocked_requests_by_reason
+                    .with_label_values(&[reason.as_str()])
+                    .get() as u64;
+                (*reason, count)
+            })
+            .collect()
+    }
+    
     /// Get active connection information for management API
     pub fn get_active_connection_info(&self) -> Vec<crate::management::types::ConnectionInfo> {
         use crate::management::types::ConnectionInfo;
@@ -412,6 +765,7 @@ impl Metrics {
                         start_time: SystemTime::now() - conn.start_time.elapsed(),
                         bytes_up: conn.bytes_up.load(Ordering::Relaxed),
                         bytes_down: conn.bytes_down.load(Ordering::Relaxed),
+                        throughput: conn.throughput.rates(),
                         status: "active".to_string(),
                     }
                 }).collect()
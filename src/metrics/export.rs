@@ -0,0 +1,92 @@
+//! Connection History Export
+//!
+//! Completed connections as CSV rows for spreadsheets and billing scripts.
+//! Anonymized records export their client token in place of the address.
+//! Fields that a spreadsheet would evaluate as a formula (leading `=`, `+`,
+//! `-` or `@`) are prefixed with a quote, since user names and destinations
+//! are client-controlled.
+
+use super::{ConnectionStats, Metrics};
+use std::time::SystemTime;
+
+/// Header line of [`csv_row`] output
+pub const CSV_HEADER: &str = "session_id,start_time,duration_ms,client,user,destination,bytes_up,bytes_down,result\n";
+
+/// Completed connections that started at or after `since`, oldest first
+pub fn connections_since(metrics: &Metrics, since: Option<SystemTime>) -> anyhow::Result<Vec<ConnectionStats>> {
+    metrics.read_history(|history| {
+        history.iter()
+            .filter(|stats| since.is_none_or(|since| stats.start_time >= since))
+            .cloned()
+            .collect()
+    })
+}
+
+/// One connection as a CSV line, in [`CSV_HEADER`] order
+pub fn csv_row(stats: &ConnectionStats) -> String {
+    let client = match &stats.client_token {
+        Some(token) => token.clone(),
+        None => stats.client_addr.to_string(),
+    };
+    format!(
+        "{},{},{},{},{},{},{},{},{}\n",
+        csv_field(&stats.session_id),
+        humantime::format_rfc3339_millis(stats.start_time),
+        stats.duration.as_millis(),
+        csv_field(&client),
+        csv_field(stats.user_id.as_deref().unwrap_or("")),
+        csv_field(&stats.target_addr.to_string()),
+        stats.bytes_up,
+        stats.bytes_down,
+        stats.result.as_str(),
+    )
+}
+
+/// Quote a field when needed and defuse spreadsheet formulas
+fn csv_field(value: &str) -> String {
+    let value = if value.starts_with(['=', '+', '-', '@']) {
+        format!("'{}", value)
+    } else {
+        value.to_string()
+    };
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::ConnectionResult;
+    use std::time::Duration;
+
+    fn stats(user: &str) -> ConnectionStats {
+        ConnectionStats {
+            session_id: "conn_1".to_string(),
+            client_addr: "198.51.100.7:50000".parse().unwrap(),
+            target_addr: "93.184.216.34:443".parse().unwrap(),
+            start_time: SystemTime::UNIX_EPOCH + Duration::from_secs(1_760_000_000),
+            duration: Duration::from_millis(1500),
+            bytes_up: 10,
+            bytes_down: 20,
+            user_id: Some(user.to_string()),
+            client_token: None,
+            result: ConnectionResult::Timeout,
+        }
+    }
+
+    #[test]
+    fn test_csv_row_escapes_fields() {
+        assert_eq!(
+            csv_row(&stats("alice")),
+            "conn_1,2025-10-09T08:53:20.000Z,1500,198.51.100.7:50000,alice,93.184.216.34:443,10,20,timeout\n"
+        );
+        assert!(csv_row(&stats("a,\"b\"")).contains(",\"a,\"\"b\"\"\","));
+        assert!(csv_row(&stats("=HYPERLINK(1)")).contains(",'=HYPERLINK(1),"));
+
+        let anonymized = ConnectionStats { client_token: Some("anon-ab12".to_string()), ..stats("alice") };
+        assert!(csv_row(&anonymized).contains(",anon-ab12,"));
+    }
+}
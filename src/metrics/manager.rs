@@ -4,6 +4,7 @@
 
 use super::{Metrics, MetricsServer, ConnectionInsights};
 use crate::config::MonitoringConfig;
+use crate::security::BlockReason;
 use std::sync::Arc;
 use tokio::task::JoinHandle;
 use tracing::{info, error, warn};
@@ -169,9 +170,9 @@ impl MetricsManager {
     }
     
     /// Record blocked request
-    pub fn record_blocked_request(&self, reason: &str) {
+    pub fn record_blocked_request(&self, reason: BlockReason, detail: &str) {
         if self.config.enabled {
-            self.metrics.record_blocked_request(reason);
+            self.metrics.record_blocked_request(reason, detail);
         }
     }
 }
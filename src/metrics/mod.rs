@@ -7,15 +7,20 @@ pub mod types;
 pub mod server;
 pub mod reporter;
 pub mod manager;
+pub mod retention;
+pub mod webhook;
+pub mod export;
 
 pub use collector::Metrics;
 pub use server::MetricsServer;
 pub use manager::MetricsManager;
+pub use retention::{ComplianceReport, RetentionEnforcer, RetentionRun, RetentionStatus};
+pub use webhook::{ConnectionEvent, ConnectionEventKind, WebhookNotifier};
 pub use reporter::{
     ConnectionInsights, UsageReport, ReportSummary, UserActivity, 
     DestinationActivity, export_report_json, export_report_csv
 };
 pub use types::{
-    ConnectionStats, ActiveConnection, HistoricalStats, 
+    ConnectionStats, ConnectionResult, ActiveConnection, HistoricalStats, 
     ActivitySummary, MetricsRegistry
 };
\ No newline at end of file
@@ -0,0 +1,281 @@
+//! Metrics Retention
+//!
+//! Limits how long connection history keeps identifying data. A background
+//! job replaces client IPs and usernames with salted hashes once records reach
+//! `anonymize_after`, and drops records once they reach `delete_after`.
+//!
+//! The salt is random, held only in memory, and replaced every
+//! `salt_rotation`. Tokens from the same rotation period can still be grouped
+//! (e.g. "one client made 40 connections"), but they cannot be linked across
+//! periods or reversed by hashing every IPv4 address.
+
+use super::{ConnectionStats, Metrics};
+use crate::config::RetentionConfig;
+use crate::Result;
+use anyhow::anyhow;
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::Serialize;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+/// Prefix for anonymized client and user tokens
+pub const TOKEN_PREFIX: &str = "anon-";
+
+/// Records changed by one retention run
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct RetentionRun {
+    pub anonymized: u64,
+    pub deleted: u64,
+}
+
+/// Running totals kept by the metrics collector
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RetentionStatus {
+    pub last_run: Option<SystemTime>,
+    pub salt_rotated_at: Option<SystemTime>,
+    pub total_anonymized: u64,
+    pub total_deleted: u64,
+}
+
+/// Applies the retention policy to a metrics collector
+pub struct RetentionEnforcer {
+    config: RetentionConfig,
+    rng: SystemRandom,
+    salt: Mutex<Salt>,
+}
+
+struct Salt {
+    key: hmac::Key,
+    created_at: SystemTime,
+}
+
+impl RetentionEnforcer {
+    /// Create an enforcer with a fresh salt
+    pub fn new(config: RetentionConfig) -> Result<Self> {
+        let rng = SystemRandom::new();
+        let salt = Salt::generate(&rng, SystemTime::now())?;
+        Ok(Self {
+            config,
+            rng,
+            salt: Mutex::new(salt),
+        })
+    }
+
+    /// Anonymize and delete records that have aged past the policy
+    pub fn run(&self, metrics: &Metrics, now: SystemTime) -> Result<RetentionRun> {
+        let mut salt = self.salt.lock().unwrap();
+        if age(salt.created_at, now) >= self.config.salt_rotation {
+            *salt = Salt::generate(&self.rng, now)?;
+        }
+
+        let run = metrics.update_history(|history| {
+            let mut run = RetentionRun::default();
+            if let Some(delete_after) = self.config.delete_after {
+                let before = history.len();
+                history.retain(|record| age(record.start_time, now) < delete_after);
+                run.deleted = (before - history.len()) as u64;
+            }
+            if let Some(anonymize_after) = self.config.anonymize_after {
+                for record in history.iter_mut() {
+                    if !record.is_anonymized() && age(record.start_time, now) >= anonymize_after {
+                        salt.anonymize(record);
+                        run.anonymized += 1;
+                    }
+                }
+            }
+            run
+        })?;
+
+        metrics.record_retention_run(run, salt.created_at, now);
+        Ok(run)
+    }
+
+    /// Run the policy every `check_interval` in the background
+    pub fn spawn(self, metrics: Arc<Metrics>) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(self.config.check_interval);
+            loop {
+                interval.tick().await;
+                match self.run(&metrics, SystemTime::now()) {
+                    Ok(run) if run != RetentionRun::default() => {
+                        info!("Retention anonymized {} and deleted {} connection records", run.anonymized, run.deleted);
+                    }
+                    Ok(_) => {}
+                    Err(e) => warn!("Retention run failed: {:#}", e),
+                }
+            }
+        })
+    }
+}
+
+impl Salt {
+    fn generate(rng: &SystemRandom, created_at: SystemTime) -> Result<Self> {
+        let mut bytes = [0u8; 32];
+        rng.fill(&mut bytes)
+            .map_err(|_| anyhow!("System random number generator failed"))?;
+        Ok(Self {
+            key: hmac::Key::new(hmac::HMAC_SHA256, &bytes),
+            created_at,
+        })
+    }
+
+    fn anonymize(&self, record: &mut ConnectionStats) {
+        let unspecified = match record.client_addr.ip() {
+            IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        };
+        record.client_token = Some(self.token(record.client_addr.ip().to_string().as_bytes()));
+        record.client_addr = SocketAddr::new(unspecified, 0);
+        record.user_id = record.user_id.as_deref().map(|user| self.token(user.as_bytes()));
+    }
+
+    fn token(&self, value: &[u8]) -> String {
+        let tag = hmac::sign(&self.key, value);
+        let hex: String = tag.as_ref()[..8].iter().map(|b| format!("{:02x}", b)).collect();
+        format!("{}{}", TOKEN_PREFIX, hex)
+    }
+}
+
+/// Current state of connection history against the retention policy
+#[derive(Debug, Clone, Serialize)]
+pub struct ComplianceReport {
+    pub enabled: bool,
+    pub anonymize_after_secs: Option<u64>,
+    pub delete_after_secs: Option<u64>,
+    pub salt_rotation_secs: u64,
+    pub records: usize,
+    pub anonymized_records: usize,
+    pub oldest_record_age_secs: Option<u64>,
+    pub oldest_identifiable_age_secs: Option<u64>,
+    /// The policy is enabled and no record is overdue by more than one check interval
+    pub compliant: bool,
+    pub status: RetentionStatus,
+}
+
+/// Describe how the stored history measures up to the retention policy
+pub fn compliance_report(config: &RetentionConfig, metrics: &Metrics, now: SystemTime) -> Result<ComplianceReport> {
+    let (records, anonymized_records, oldest, oldest_identifiable) = metrics.read_history(|history| {
+        let oldest = history.iter().map(|r| age(r.start_time, now)).max();
+        let oldest_identifiable = history.iter()
+            .filter(|r| !r.is_anonymized())
+            .map(|r| age(r.start_time, now))
+            .max();
+        let anonymized = history.iter().filter(|r| r.is_anonymized()).count();
+        (history.len(), anonymized, oldest, oldest_identifiable)
+    })?;
+
+    // Records can outlive a limit by up to one interval between runs
+    let overdue = |oldest: Option<Duration>, limit: Option<Duration>| match (oldest, limit) {
+        (Some(oldest), Some(limit)) => oldest > limit + config.check_interval,
+        _ => false,
+    };
+    let compliant = config.enabled
+        && !overdue(oldest, config.delete_after)
+        && !overdue(oldest_identifiable, config.anonymize_after);
+
+    Ok(ComplianceReport {
+        enabled: config.enabled,
+        anonymize_after_secs: config.anonymize_after.map(|d| d.as_secs()),
+        delete_after_secs: config.delete_after.map(|d| d.as_secs()),
+        salt_rotation_secs: config.salt_rotation.as_secs(),
+        records,
+        anonymized_records,
+        oldest_record_age_secs: oldest.map(|d| d.as_secs()),
+        oldest_identifiable_age_secs: oldest_identifiable.map(|d| d.as_secs()),
+        compliant,
+        status: metrics.retention_status(),
+    })
+}
+
+fn age(time: SystemTime, now: SystemTime) -> Duration {
+    now.duration_since(time).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+    fn record(user: &str, client: &str, start_time: SystemTime) -> ConnectionStats {
+        ConnectionStats {
+            session_id: format!("{}-{}", user, client),
+            client_addr: client.parse().unwrap(),
+            target_addr: "93.184.216.34:443".parse().unwrap(),
+            start_time,
+            duration: Duration::from_secs(1),
+            bytes_up: 10,
+            bytes_down: 20,
+            user_id: Some(user.to_string()),
+            client_token: None,
+            result: Default::default(),
+        }
+    }
+
+    fn policy() -> RetentionConfig {
+        RetentionConfig {
+            enabled: true,
+            anonymize_after: Some(7 * DAY),
+            delete_after: Some(30 * DAY),
+            ..RetentionConfig::default()
+        }
+    }
+
+    #[test]
+    fn test_run_anonymizes_and_deletes_by_age() {
+        let metrics = Metrics::new();
+        let now = SystemTime::now();
+        metrics.record_connection(&record("alice", "198.51.100.7:5000", now - DAY));
+        metrics.record_connection(&record("bob", "198.51.100.8:5000", now - 10 * DAY));
+        metrics.record_connection(&record("carol", "198.51.100.9:5000", now - 40 * DAY));
+
+        let enforcer = RetentionEnforcer::new(policy()).unwrap();
+        let run = enforcer.run(&metrics, now).unwrap();
+        assert_eq!(run, RetentionRun { anonymized: 1, deleted: 1 });
+
+        metrics.read_history(|history| {
+            assert_eq!(history.len(), 2);
+            assert_eq!(history[0].user_id.as_deref(), Some("alice"));
+            let old = &history[1];
+            assert!(old.is_anonymized());
+            assert!(old.user_id.as_deref().unwrap().starts_with(TOKEN_PREFIX));
+            assert!(old.client_addr.ip().is_unspecified());
+        }).unwrap();
+
+        // Already-anonymized records are left alone on the next run
+        assert_eq!(enforcer.run(&metrics, now).unwrap(), RetentionRun::default());
+        assert_eq!(metrics.retention_status().total_anonymized, 1);
+    }
+
+    #[test]
+    fn test_tokens_change_when_salt_rotates() {
+        let now = SystemTime::now();
+        let rng = SystemRandom::new();
+        let first = Salt::generate(&rng, now).unwrap();
+        let second = Salt::generate(&rng, now).unwrap();
+
+        assert_eq!(first.token(b"alice"), first.token(b"alice"));
+        assert_ne!(first.token(b"alice"), second.token(b"alice"));
+    }
+
+    #[test]
+    fn test_compliance_report_flags_overdue_records() {
+        let metrics = Metrics::new();
+        let now = SystemTime::now();
+        metrics.record_connection(&record("alice", "198.51.100.7:5000", now - 9 * DAY));
+
+        let report = compliance_report(&policy(), &metrics, now).unwrap();
+        assert!(!report.compliant);
+        assert_eq!(report.oldest_identifiable_age_secs, Some((9 * DAY).as_secs()));
+
+        RetentionEnforcer::new(policy()).unwrap().run(&metrics, now).unwrap();
+        let report = compliance_report(&policy(), &metrics, now).unwrap();
+        assert!(report.compliant);
+        assert_eq!(report.anonymized_records, 1);
+        assert_eq!(report.oldest_identifiable_age_secs, None);
+    }
+}
@@ -17,6 +17,39 @@ pub struct ConnectionStats {
     pub bytes_up: u64,
     pub bytes_down: u64,
     pub user_id: Option<String>,
+    /// Salted hash of the client IP once the record has been anonymized
+    pub client_token: Option<String>,
+    pub result: ConnectionResult,
+}
+
+/// How a connection ended
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ConnectionResult {
+    #[default]
+    Completed,
+    /// The connection timeout elapsed
+    Timeout,
+    Error,
+    /// Ended by policy: lifetime, access window or an observer
+    Terminated,
+}
+
+impl ConnectionResult {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ConnectionResult::Completed => "completed",
+            ConnectionResult::Timeout => "timeout",
+            ConnectionResult::Error => "error",
+            ConnectionResult::Terminated => "terminated",
+        }
+    }
+}
+
+impl ConnectionStats {
+    /// Whether identifying fields have been replaced by retention
+    pub fn is_anonymized(&self) -> bool {
+        self.client_token.is_some()
+    }
 }
 
 /// Active connection tracking
@@ -29,6 +62,8 @@ pub struct ActiveConnection {
     pub bytes_up: AtomicU64,
     pub bytes_down: AtomicU64,
     pub user_id: Option<String>,
+    /// Live transfer rates, fed by the relay itself rather than progress reports
+    pub throughput: crate::relay::Throughput,
 }
 
 impl ActiveConnection {
@@ -46,6 +81,7 @@ impl ActiveConnection {
             bytes_up: AtomicU64::new(0),
             bytes_down: AtomicU64::new(0),
             user_id,
+            throughput: crate::relay::Throughput::new(),
         }
     }
 
@@ -79,6 +115,8 @@ impl ActiveConnection {
             bytes_up: self.get_bytes_up(),
             bytes_down: self.get_bytes_down(),
             user_id: self.user_id.clone(),
+            client_token: None,
+            result: ConnectionResult::Completed,
         }
     }
 }
@@ -0,0 +1,299 @@
+//! Connection Usage Webhooks
+//!
+//! Posts an event for each completed connection (and optionally each opened
+//! one) to an external endpoint, so billing or CRM systems see usage within
+//! seconds instead of polling reports. Events are queued by the relay observer
+//! and delivered from a background task in batches of up to `batch_size`, or
+//! whatever has arrived after `flush_interval`. A failed batch is retried with
+//! exponential backoff and dropped after `max_retries`; when the queue is full,
+//! new events are dropped and counted rather than slowing down relays.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use anyhow::{anyhow, Context};
+use serde::Serialize;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use tracing::{debug, warn};
+
+use crate::config::WebhookConfig;
+use crate::relay::{RelayControl, RelayObserver, RelaySession};
+use crate::Result;
+
+/// Point in a connection's life an event describes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectionEventKind {
+    Open,
+    Close,
+}
+
+/// One connection event as posted to the webhook
+#[derive(Debug, Clone, Serialize)]
+pub struct ConnectionEvent {
+    pub event: ConnectionEventKind,
+    pub session_id: String,
+    /// Left out for connections excluded by the privacy settings
+    pub user: Option<String>,
+    pub destination: Option<SocketAddr>,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+    pub duration_ms: u64,
+    /// RFC 3339 time of the event
+    pub timestamp: String,
+}
+
+#[derive(Serialize)]
+struct WebhookBatch<'a> {
+    events: &'a [ConnectionEvent],
+}
+
+/// Relay observer that queues connection events for webhook delivery
+pub struct WebhookNotifier {
+    include_open: bool,
+    queue: mpsc::Sender<ConnectionEvent>,
+    /// User of each running relay, which `on_end` is not given
+    users: Mutex<HashMap<String, Option<String>>>,
+    dropped: AtomicU64,
+}
+
+impl WebhookNotifier {
+    /// Start delivering events to the configured endpoint
+    pub fn spawn(config: WebhookConfig) -> Result<(Arc<Self>, JoinHandle<()>)> {
+        let client = reqwest::Client::builder()
+            .timeout(config.timeout)
+            .build()
+            .context("Failed to build webhook HTTP client")?;
+        let (notifier, events) = Self::with_queue(config.include_open, config.queue_capacity);
+        let handle = tokio::spawn(deliver(client, config, events));
+        Ok((Arc::new(notifier), handle))
+    }
+
+    fn with_queue(include_open: bool, capacity: usize) -> (Self, mpsc::Receiver<ConnectionEvent>) {
+        let (queue, events) = mpsc::channel(capacity);
+        let notifier = Self {
+            include_open,
+            queue,
+            users: Mutex::new(HashMap::new()),
+            dropped: AtomicU64::new(0),
+        };
+        (notifier, events)
+    }
+
+    /// Events dropped because the queue was full
+    pub fn dropped_events(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    fn event(&self, kind: ConnectionEventKind, session: &RelaySession, user: Option<String>) -> ConnectionEvent {
+        let (user, destination) = if session.redacted { (None, None) } else { (user, Some(session.target_addr)) };
+        ConnectionEvent {
+            event: kind,
+            session_id: session.session_id.clone(),
+            user,
+            destination,
+            bytes_up: session.bytes_up(),
+            bytes_down: session.bytes_down(),
+            duration_ms: session.duration().as_millis() as u64,
+            timestamp: humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+        }
+    }
+
+    fn enqueue(&self, event: ConnectionEvent) {
+        if self.queue.try_send(event).is_err() {
+            let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+            if dropped.is_power_of_two() {
+                warn!("Webhook queue full, {} connection event(s) dropped so far", dropped);
+            }
+        }
+    }
+}
+
+impl RelayObserver for WebhookNotifier {
+    fn on_start(&self, session: &RelaySession, user_id: Option<&str>) {
+        let user = user_id.map(str::to_string);
+        if self.include_open {
+            self.enqueue(self.event(ConnectionEventKind::Open, session, user.clone()));
+        }
+        self.users.lock().unwrap().insert(session.session_id.clone(), user);
+    }
+
+    fn on_progress(&self, _session: &RelaySession, _user_id: Option<&str>, _bytes_up: u64, _bytes_down: u64) -> RelayControl {
+        RelayControl::Continue
+    }
+
+    fn on_end(&self, session: &RelaySession) {
+        let user = self.users.lock().unwrap().remove(&session.session_id).flatten();
+        self.enqueue(self.event(ConnectionEventKind::Close, session, user));
+    }
+}
+
+/// Send batches until every notifier handle is gone and the queue is drained
+async fn deliver(client: reqwest::Client, config: WebhookConfig, mut events: mpsc::Receiver<ConnectionEvent>) {
+    while let Some(batch) = next_batch(&mut events, &config).await {
+        if let Err(e) = send_with_retries(&client, &config, &batch).await {
+            warn!("Dropping {} webhook event(s): {:#}", batch.len(), e);
+        }
+    }
+}
+
+/// Wait for an event, then gather more until the batch is full or
+/// `flush_interval` has passed since the first
+async fn next_batch(events: &mut mpsc::Receiver<ConnectionEvent>, config: &WebhookConfig) -> Option<Vec<ConnectionEvent>> {
+    let first = events.recv().await?;
+    let flush_at = Instant::now() + config.flush_interval;
+    let mut batch = vec![first];
+    while batch.len() < config.batch_size {
+        match tokio::time::timeout_at(flush_at, events.recv()).await {
+            Ok(Some(event)) => batch.push(event),
+            Ok(None) | Err(_) => break,
+        }
+    }
+    Some(batch)
+}
+
+async fn send_with_retries(client: &reqwest::Client, config: &WebhookConfig, batch: &[ConnectionEvent]) -> Result<()> {
+    let mut backoff = config.retry_backoff;
+    let mut attempt = 0;
+    loop {
+        match send(client, config, batch).await {
+            Ok(()) => {
+                debug!("Delivered {} webhook event(s)", batch.len());
+                return Ok(());
+            }
+            Err(e) if attempt >= config.max_retries => return Err(e),
+            Err(e) => {
+                attempt += 1;
+                warn!("Webhook delivery failed (retry {} of {} in {:?}): {:#}", attempt, config.max_retries, backoff, e);
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+            }
+        }
+    }
+}
+
+async fn send(client: &reqwest::Client, config: &WebhookConfig, batch: &[ConnectionEvent]) -> Result<()> {
+    let mut request = client.post(&config.url).json(&WebhookBatch { events: batch });
+    if let Some(token) = &config.bearer_token {
+        request = request.bearer_auth(token);
+    }
+    let response = request.send().await
+        .with_context(|| format!("Webhook request to {} failed", config.url))?;
+    if !response.status().is_success() {
+        return Err(anyhow!("Webhook endpoint returned {}", response.status()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    fn test_config(url: String) -> WebhookConfig {
+        WebhookConfig {
+            url,
+            bearer_token: None,
+            include_open: false,
+            batch_size: 10,
+            flush_interval: Duration::from_millis(20),
+            max_retries: 2,
+            retry_backoff: Duration::from_millis(10),
+            timeout: Duration::from_secs(2),
+            queue_capacity: 16,
+        }
+    }
+
+    /// Fail the first request with a 500, then accept and pass on request bodies
+    async fn flaky_endpoint() -> (String, mpsc::UnboundedReceiver<serde_json::Value>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (bodies, received) = mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            let mut requests = 0;
+            while let Ok((mut stream, _)) = listener.accept().await {
+                requests += 1;
+                let mut request = Vec::new();
+                let mut buf = [0u8; 4096];
+                let body = loop {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                    let text = String::from_utf8_lossy(&request);
+                    if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                        let length = head.lines()
+                            .find_map(|line| line.to_ascii_lowercase().strip_prefix("content-length:").map(|v| v.trim().parse::<usize>().unwrap()))
+                            .unwrap_or(0);
+                        if body.len() >= length || n == 0 {
+                            break body.to_string();
+                        }
+                    }
+                };
+                let status = if requests == 1 { "500 Internal Server Error" } else { "200 OK" };
+                let _ = stream.write_all(format!("HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status).as_bytes()).await;
+                if requests > 1 {
+                    let _ = bodies.send(serde_json::from_str(&body).unwrap());
+                }
+            }
+        });
+
+        (format!("http://{}/usage", addr), received)
+    }
+
+    fn session(id: &str, redacted: bool) -> RelaySession {
+        let session = RelaySession::new(id.to_string(), "192.0.2.1:5000".parse().unwrap(), "198.51.100.1:443".parse().unwrap())
+            .with_redaction(redacted);
+        session.add_bytes_up(100);
+        session.add_bytes_down(2000);
+        session
+    }
+
+    #[test]
+    fn test_close_events_carry_user_and_usage() {
+        let (notifier, mut events) = WebhookNotifier::with_queue(false, 1);
+
+        let billed = session("s1", false);
+        notifier.on_start(&billed, Some("alice"));
+        notifier.on_end(&billed);
+        let event = events.try_recv().unwrap();
+        assert_eq!(event.event, ConnectionEventKind::Close);
+        assert_eq!(event.user.as_deref(), Some("alice"));
+        assert_eq!(event.destination, Some("198.51.100.1:443".parse().unwrap()));
+        assert_eq!((event.bytes_up, event.bytes_down), (100, 2000));
+
+        let private = session("s2", true);
+        notifier.on_start(&private, Some("counsel"));
+        notifier.on_end(&private);
+        notifier.on_end(&session("s3", false));
+        let event = events.try_recv().unwrap();
+        assert_eq!((event.user, event.destination), (None, None));
+        assert_eq!(notifier.dropped_events(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_batches_are_retried_until_delivered() {
+        let (url, mut received) = flaky_endpoint().await;
+        let (notifier, handle) = WebhookNotifier::spawn(test_config(url)).unwrap();
+
+        for id in ["s1", "s2"] {
+            let session = session(id, false);
+            notifier.on_start(&session, Some("alice"));
+            notifier.on_end(&session);
+        }
+        drop(notifier);
+        handle.await.unwrap();
+
+        let body = received.recv().await.unwrap();
+        let events = body["events"].as_array().unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0]["session_id"], "s1");
+        assert_eq!(events[1]["event"], "close");
+    }
+}
@@ -0,0 +1,405 @@
+//! Startup Preflight Checks
+//!
+//! Verifies that the environment can support the loaded configuration before
+//! the proxy is started: listen addresses are bindable, upstream proxies are
+//! reachable, the auth backend has usable credentials and answers, TLS
+//! certificates and GeoIP databases load, and OS limits are high enough.
+//! Results are available as a structured report so deployment pipelines can
+//! gate on them.
+
+use crate::config::Config;
+use crate::routing::GeoIpReader;
+use crate::security::secrets::SecureConfig;
+use crate::tls::{CertificateFiles, ReloadingCertResolver, SniCertConfig};
+use serde::Serialize;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
+
+/// Outcome of a single preflight check
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckStatus {
+    Pass,
+    Warn,
+    Fail,
+}
+
+/// Result of a single preflight check
+#[derive(Debug, Clone, Serialize)]
+pub struct PreflightCheck {
+    pub name: String,
+    pub status: CheckStatus,
+    pub message: String,
+    pub duration_ms: u64,
+}
+
+/// Full preflight report
+#[derive(Debug, Clone, Serialize)]
+pub struct PreflightReport {
+    pub passed: bool,
+    pub checks: Vec<PreflightCheck>,
+}
+
+impl PreflightReport {
+    /// Number of checks with the given status
+    pub fn count(&self, status: CheckStatus) -> usize {
+        self.checks.iter().filter(|c| c.status == status).count()
+    }
+}
+
+/// Run all preflight checks against the given configuration and, if one is
+/// given, the TLS certificates of a secrets file
+pub async fn run_preflight(config: &Config, secrets: Option<&SecureConfig>) -> PreflightReport {
+    let mut checks = Vec::new();
+
+    // Listen addresses
+    checks.push(check_bind("bind:proxy", config.server.bind_addr).await);
+    if let Some(tunnel) = &config.server.tunnel {
+        checks.push(check_bind("bind:tunnel", tunnel.bind_addr).await);
+    }
+    if config.monitoring.enabled && config.monitoring.prometheus_enabled {
+        if let Some(metrics_addr) = config.monitoring.metrics_addr {
+            checks.push(check_bind("bind:metrics", metrics_addr).await);
+        }
+    }
+    if config.monitoring.management_api.enabled {
+        checks.push(check_bind("bind:management_api", config.monitoring.management_api.bind_addr).await);
+    }
+
+    // Upstream proxies
+    checks.extend(check_upstreams(config).await);
+
+    checks.push(check_auth_backend(config));
+    checks.extend(check_auth_endpoints(config).await);
+    checks.extend(check_certificates(config, secrets));
+    checks.push(check_geoip(config));
+    checks.push(check_file_limit(config));
+
+    let passed = checks.iter().all(|c| c.status != CheckStatus::Fail);
+    PreflightReport { passed, checks }
+}
+
+/// Check that every configured upstream proxy accepts TCP connections
+pub async fn check_upstreams(config: &Config) -> Vec<PreflightCheck> {
+    let upstream_timeout = config.routing.smart_routing.health_check_timeout;
+    let mut checks = Vec::new();
+    for upstream in &config.routing.upstream_proxies {
+        checks.push(check_upstream(&upstream.name, upstream.addr, upstream_timeout).await);
+    }
+    checks
+}
+
+/// Verify that an address can be bound
+async fn check_bind(name: &str, addr: SocketAddr) -> PreflightCheck {
+    let start = Instant::now();
+    let (status, message) = match TcpListener::bind(addr).await {
+        Ok(_) => (CheckStatus::Pass, format!("{} is bindable", addr)),
+        Err(e) => (CheckStatus::Fail, format!("Cannot bind {}: {}", addr, e)),
+    };
+    finish(name, status, message, start)
+}
+
+/// Verify that an upstream proxy accepts TCP connections
+async fn check_upstream(name: &str, addr: SocketAddr, connect_timeout: Duration) -> PreflightCheck {
+    let start = Instant::now();
+    let (status, message) = match timeout(connect_timeout, TcpStream::connect(addr)).await {
+        Ok(Ok(_)) => (CheckStatus::Pass, format!("{} is reachable", addr)),
+        Ok(Err(e)) => (CheckStatus::Fail, format!("Cannot connect to {}: {}", addr, e)),
+        Err(_) => (CheckStatus::Fail, format!("Timed out connecting to {} after {:?}", addr, connect_timeout)),
+    };
+    finish(&format!("upstream:{}", name), status, message, start)
+}
+
+/// Verify that the auth backend can authenticate someone
+fn check_auth_backend(config: &Config) -> PreflightCheck {
+    let start = Instant::now();
+    let enabled_users = config.auth.users.iter().filter(|u| u.enabled).count();
+    let (status, message) = if !config.auth.enabled {
+        (CheckStatus::Pass, "Authentication disabled".to_string())
+    } else if let Some(jwt_config) = &config.auth.jwt {
+        match crate::auth::JwtValidator::from_config(jwt_config) {
+            Ok(_) => (CheckStatus::Pass, format!("JWT validation configured, {} enabled user(s)", enabled_users)),
+            Err(e) => (CheckStatus::Fail, format!("JWT validator failed to load: {:#}", e)),
+        }
+    } else if let Some(introspection) = &config.auth.introspection {
+        match crate::auth::TokenIntrospector::from_config(introspection) {
+            Ok(_) => (CheckStatus::Pass, format!(
+                "Token introspection via {}, {} enabled user(s)", introspection.endpoint, enabled_users
+            )),
+            Err(e) => (CheckStatus::Fail, format!("Token introspection client failed to build: {:#}", e)),
+        }
+    } else if enabled_users == 0 {
+        (CheckStatus::Fail, "Authentication enabled but no enabled users are configured".to_string())
+    } else {
+        (CheckStatus::Pass, format!("{} enabled user(s) configured", enabled_users))
+    };
+    finish("auth_backend", status, message, start)
+}
+
+/// Verify that the introspection and webhook endpoints answer HTTP requests.
+/// Any response counts, since a probe carries no token or credentials.
+async fn check_auth_endpoints(config: &Config) -> Vec<PreflightCheck> {
+    let mut checks = Vec::new();
+    if !config.auth.enabled {
+        return checks;
+    }
+    if let Some(introspection) = &config.auth.introspection {
+        checks.push(check_http_endpoint("auth_endpoint:introspection", &introspection.endpoint, introspection.timeout).await);
+    }
+    if let Some(webhook) = &config.auth.webhook {
+        checks.push(check_http_endpoint("auth_endpoint:webhook", &webhook.url, webhook.timeout).await);
+    }
+    checks
+}
+
+/// Verify that an HTTP endpoint answers within `request_timeout`
+async fn check_http_endpoint(name: &str, url: &str, request_timeout: Duration) -> PreflightCheck {
+    let start = Instant::now();
+    let client = reqwest::Client::builder().timeout(request_timeout).build();
+    let (status, message) = match client {
+        Ok(client) => match client.head(url).send().await {
+            Ok(response) => (CheckStatus::Pass, format!("{} answered with {}", url, response.status())),
+            Err(e) if e.is_timeout() => {
+                (CheckStatus::Fail, format!("Timed out reaching {} after {:?}", url, request_timeout))
+            }
+            Err(e) => (CheckStatus::Fail, format!("Cannot reach {}: {}", url, e)),
+        },
+        Err(e) => (CheckStatus::Fail, format!("HTTP client failed to build: {}", e)),
+    };
+    finish(name, status, message, start)
+}
+
+/// Verify that the certificates of every TLS listener and of the secrets
+/// file exist, parse, and match their keys
+fn check_certificates(config: &Config, secrets: Option<&SecureConfig>) -> Vec<PreflightCheck> {
+    let mut checks = Vec::new();
+    if let Some(tunnel) = &config.server.tunnel {
+        checks.extend(check_listener_certificates(
+            "tls:tunnel", tunnel.tls_cert.as_deref(), tunnel.tls_key.as_deref(), &tunnel.tls_sni_certs, "Tunnel",
+        ));
+    }
+    let management_api = &config.monitoring.management_api;
+    if management_api.enabled {
+        checks.extend(check_listener_certificates(
+            "tls:management_api",
+            management_api.tls_cert.as_deref(),
+            management_api.tls_key.as_deref(),
+            &management_api.tls_sni_certs,
+            "Management API",
+        ));
+    }
+    for tls in secrets.map(|secrets| secrets.tls_certificates.as_slice()).unwrap_or_default() {
+        let start = Instant::now();
+        let name = format!("tls:secrets:{}", tls.name);
+        let (status, message) = match (&tls.cert_path, &tls.key_path) {
+            (Some(cert), Some(key)) => {
+                let files = CertificateFiles { cert: cert.into(), key: key.into(), sni: Vec::new() };
+                load_certificates(&files)
+            }
+            // Certificates given inline through the environment have no files to check
+            (None, None) => continue,
+            _ => (CheckStatus::Fail, "Needs both cert_path and key_path".to_string()),
+        };
+        checks.push(finish(&name, status, message, start));
+    }
+    checks
+}
+
+/// Load one listener's certificate set; `None` when the listener has no TLS
+fn check_listener_certificates(
+    name: &str,
+    cert: Option<&Path>,
+    key: Option<&Path>,
+    sni: &[SniCertConfig],
+    what: &str,
+) -> Option<PreflightCheck> {
+    let start = Instant::now();
+    let (status, message) = match CertificateFiles::from_settings(cert, key, sni, what) {
+        Ok(Some(files)) => load_certificates(&files),
+        Ok(None) => return None,
+        Err(e) => (CheckStatus::Fail, format!("{:#}", e)),
+    };
+    Some(finish(name, status, message, start))
+}
+
+fn load_certificates(files: &CertificateFiles) -> (CheckStatus, String) {
+    match ReloadingCertResolver::new(files.clone()) {
+        Ok(_) if files.sni.is_empty() => (CheckStatus::Pass, format!("{} loads", files.cert.display())),
+        Ok(_) => (CheckStatus::Pass, format!(
+            "{} and {} SNI certificate(s) load", files.cert.display(), files.sni.len()
+        )),
+        Err(e) => (CheckStatus::Fail, format!("{:#}", e)),
+    }
+}
+
+/// Verify that country-restricted rules can be evaluated and that the client
+/// country database opens
+fn check_geoip(config: &Config) -> PreflightCheck {
+    let start = Instant::now();
+    let acl_country_rules = if config.access_control.enabled {
+        config.access_control.rules.iter().filter(|r| r.countries.is_some()).count()
+    } else {
+        0
+    };
+    let routing_country_rules = if config.routing.enabled {
+        config.routing.rules.iter().filter(|r| r.enabled && r.countries.is_some()).count()
+    } else {
+        0
+    };
+    let client_geo = &config.security.client_geo;
+    let client_country_rules = usize::from(client_geo.enabled);
+    let country_rules = acl_country_rules + routing_country_rules + client_country_rules;
+    let policy = config.security.failure_policies.geoip;
+    let (status, message) = match client_geo.database.as_deref().filter(|_| client_geo.enabled) {
+        // The reader only opens the file when GeoIP support is compiled in
+        Some(path) if !path.is_file() => {
+            (CheckStatus::Fail, format!("Client GeoIP database {} does not exist", path.display()))
+        }
+        Some(path) => match GeoIpReader::new(path) {
+            Ok(reader) if reader.is_available() => (CheckStatus::Pass, format!(
+                "{} country-restricted rule(s), client GeoIP database {} opens", country_rules, path.display()
+            )),
+            Ok(_) => (CheckStatus::Warn, format!(
+                "{} country-restricted rule(s) but GeoIP support is not compiled in (failure policy: {})",
+                country_rules, policy
+            )),
+            Err(e) => (CheckStatus::Fail, format!(
+                "Client GeoIP database {} failed to open (failure policy: {}): {}", path.display(), policy, e
+            )),
+        },
+        None if client_geo.enabled => (CheckStatus::Fail, format!(
+            "security.client_geo is enabled without a database (failure policy: {})", policy
+        )),
+        None if country_rules == 0 => (CheckStatus::Pass, "No country-restricted rules".to_string()),
+        None if cfg!(feature = "geoip") => {
+            (CheckStatus::Pass, format!("{} country-restricted rule(s), GeoIP support compiled in", country_rules))
+        }
+        None => (CheckStatus::Warn, format!(
+            "{} country-restricted rule(s) but GeoIP support is not compiled in (failure policy: {})",
+            country_rules, policy
+        )),
+    };
+    finish("geoip", status, message, start)
+}
+
+/// Verify that the open file limit covers the configured connection count
+fn check_file_limit(config: &Config) -> PreflightCheck {
+    let start = Instant::now();
+    // Each proxied connection holds a client and a target socket
+    let required = (config.server.max_connections as u64) * 2;
+    let (status, message) = match open_file_limit() {
+        Some(limit) if limit >= required => {
+            (CheckStatus::Pass, format!("Open file limit {} covers {} sockets", limit, required))
+        }
+        Some(limit) => (CheckStatus::Warn, format!(
+            "Open file limit {} is below {} sockets needed for max_connections = {}",
+            limit, required, config.server.max_connections
+        )),
+        None => (CheckStatus::Warn, "Open file limit could not be determined".to_string()),
+    };
+    finish("ulimit:nofile", status, message, start)
+}
+
+#[cfg(unix)]
+#[allow(clippy::unnecessary_cast)] // rlim_t is not u64 on every unix target
+fn open_file_limit() -> Option<u64> {
+    let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+    // SAFETY: getrlimit only writes into the provided struct
+    let result = unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) };
+    if result == 0 {
+        Some(limit.rlim_cur as u64)
+    } else {
+        None
+    }
+}
+
+#[cfg(not(unix))]
+fn open_file_limit() -> Option<u64> {
+    None
+}
+
+fn finish(name: &str, status: CheckStatus, message: String, start: Instant) -> PreflightCheck {
+    PreflightCheck {
+        name: name.to_string(),
+        status,
+        message,
+        duration_ms: start.elapsed().as_millis() as u64,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_bind_check_detects_port_in_use() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let check = check_bind("bind:test", addr).await;
+        assert_eq!(check.status, CheckStatus::Fail);
+
+        drop(listener);
+        let check = check_bind("bind:test", addr).await;
+        assert_eq!(check.status, CheckStatus::Pass);
+    }
+
+    #[tokio::test]
+    async fn test_upstream_check_unreachable() {
+        // Bind and drop to get a port with nothing listening
+        let addr = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+
+        let check = check_upstream("dead", addr, Duration::from_secs(1)).await;
+        assert_eq!(check.name, "upstream:dead");
+        assert_eq!(check.status, CheckStatus::Fail);
+    }
+
+    #[test]
+    fn test_auth_backend_without_users_fails() {
+        let mut config = Config::default();
+        config.auth.enabled = true;
+        config.auth.users.clear();
+
+        assert_eq!(check_auth_backend(&config).status, CheckStatus::Fail);
+    }
+
+    #[tokio::test]
+    async fn test_dead_auth_webhook_fails() {
+        let addr = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let mut config = Config::default();
+        config.auth.enabled = true;
+        config.auth.webhook = Some(toml::from_str(&format!("url = \"http://{}/login\"", addr)).unwrap());
+
+        let checks = check_auth_endpoints(&config).await;
+        assert_eq!(checks.len(), 1);
+        assert_eq!(checks[0].name, "auth_endpoint:webhook");
+        assert_eq!(checks[0].status, CheckStatus::Fail);
+    }
+
+    #[test]
+    fn test_missing_certificate_fails() {
+        let data = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data/tls");
+        let check = check_listener_certificates(
+            "tls:test", Some(&data.join("a.example.com.crt")), Some(&data.join("a.example.com.key")), &[], "Test",
+        ).unwrap();
+        assert_eq!(check.status, CheckStatus::Pass);
+
+        let check = check_listener_certificates(
+            "tls:test", Some(&data.join("missing.crt")), Some(&data.join("a.example.com.key")), &[], "Test",
+        ).unwrap();
+        assert_eq!(check.status, CheckStatus::Fail);
+        assert!(check_listener_certificates("tls:test", None, None, &[], "Test").is_none());
+    }
+
+    #[test]
+    fn test_missing_client_geoip_database_fails() {
+        let mut config = Config::default();
+        config.security.client_geo.enabled = true;
+        config.security.client_geo.database = Some("/nonexistent/GeoLite2-Country.mmdb".into());
+
+        assert_eq!(check_geoip(&config).status, CheckStatus::Fail);
+    }
+}
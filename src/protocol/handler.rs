@@ -451,6 +451,21 @@ impl Socks5Handler {
         })
     }
 
+    /// Local address the client connected to
+    pub fn local_addr(&self) -> Result<std::net::SocketAddr> {
+        Ok(self.stream.local_addr()?)
+    }
+
+    /// Wait until the client closes the connection, discarding anything it sends
+    pub async fn wait_for_close(&mut self) {
+        let mut buf = [0u8; 512];
+        while let Ok(n) = self.stream.read(&mut buf).await {
+            if n == 0 {
+                break;
+            }
+        }
+    }
+
     /// Get the underlying stream (for proxy chaining)
     pub fn into_stream(self) -> TcpStream {
         self.stream
@@ -4,7 +4,9 @@
 
 pub mod constants;
 pub mod handler;
+pub mod sni;
 pub mod types;
+pub mod udp;
 
 pub use constants::*;
 pub use handler::Socks5Handler;
@@ -0,0 +1,216 @@
+//! TLS Server Name Sniffing
+//!
+//! Clients that CONNECT to an IP address can still name the site they want
+//! in the server name (SNI) extension of their TLS ClientHello. The hello is
+//! read with `peek`, so it is left in the socket for the relay to forward
+//! untouched; TLS is never terminated. Only a hello in the first TLS record
+//! is looked at, which covers every mainstream client.
+
+use std::time::Duration;
+use tokio::net::TcpStream;
+
+/// Largest TLS record: 5 header bytes and up to 16 KiB of payload
+const MAX_RECORD: usize = 5 + 16 * 1024;
+
+const CONTENT_TYPE_HANDSHAKE: u8 = 0x16;
+const HANDSHAKE_CLIENT_HELLO: u8 = 0x01;
+const EXTENSION_SERVER_NAME: u16 = 0x0000;
+const NAME_TYPE_HOST_NAME: u8 = 0x00;
+
+/// More bytes are needed before the hello can be parsed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Incomplete;
+
+/// Server name in the ClientHello at the start of `data`
+///
+/// Returns `Ok(None)` when the data is not a ClientHello or has no server
+/// name, and `Err(Incomplete)` when the hello is cut short.
+pub fn server_name(data: &[u8]) -> Result<Option<String>, Incomplete> {
+    let mut record = Reader(data);
+    match record.u8() {
+        Some(CONTENT_TYPE_HANDSHAKE) => {}
+        Some(_) => return Ok(None),
+        None => return Err(Incomplete),
+    }
+    let Some(length) = record.skip(2).and_then(|_| record.u16()) else {
+        return Err(Incomplete);
+    };
+    let Some(payload) = record.bytes(length as usize) else {
+        return if length as usize + 5 > MAX_RECORD { Ok(None) } else { Err(Incomplete) };
+    };
+
+    let mut handshake = Reader(payload);
+    if handshake.u8() != Some(HANDSHAKE_CLIENT_HELLO) {
+        return Ok(None);
+    }
+    // A hello continuing in further records is not followed
+    Ok(handshake.u24().and_then(|length| handshake.bytes(length)).and_then(hello_server_name))
+}
+
+/// Server name extension of a ClientHello body
+fn hello_server_name(body: &[u8]) -> Option<String> {
+    let mut hello = Reader(body);
+    // Version and random
+    hello.skip(2 + 32)?;
+    let session_id = hello.u8()?;
+    hello.skip(session_id as usize)?;
+    let cipher_suites = hello.u16()?;
+    hello.skip(cipher_suites as usize)?;
+    let compression = hello.u8()?;
+    hello.skip(compression as usize)?;
+
+    let extensions_length = hello.u16()?;
+    let mut extensions = Reader(hello.bytes(extensions_length as usize)?);
+    while !extensions.0.is_empty() {
+        let kind = extensions.u16()?;
+        let length = extensions.u16()?;
+        let data = extensions.bytes(length as usize)?;
+        if kind != EXTENSION_SERVER_NAME {
+            continue;
+        }
+        let mut list = Reader(data);
+        let list_length = list.u16()?;
+        let mut names = Reader(list.bytes(list_length as usize)?);
+        while !names.0.is_empty() {
+            let name_type = names.u8()?;
+            let length = names.u16()?;
+            let name = names.bytes(length as usize)?;
+            if name_type == NAME_TYPE_HOST_NAME {
+                let name = std::str::from_utf8(name).ok()?;
+                return Some(name.trim_end_matches('.').to_ascii_lowercase());
+            }
+        }
+        return None;
+    }
+    None
+}
+
+/// Wait up to `wait` for the client's ClientHello and return its server name
+///
+/// Nothing is consumed from the stream. Clients that send something other
+/// than TLS, or nothing in time, yield `None`.
+pub async fn peek_server_name(stream: &TcpStream, wait: Duration) -> Option<String> {
+    let mut buf = vec![0u8; MAX_RECORD];
+    tokio::time::timeout(wait, async {
+        loop {
+            let peeked = stream.peek(&mut buf).await.ok()?;
+            if peeked == 0 {
+                return None;
+            }
+            match server_name(&buf[..peeked]) {
+                Ok(name) => return name,
+                // Peeking again returns at once, so give the rest of the hello time to arrive
+                Err(Incomplete) => tokio::time::sleep(Duration::from_millis(5)).await,
+            }
+        }
+    })
+    .await
+    .ok()
+    .flatten()
+}
+
+/// Cursor over big-endian TLS fields
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Some(head)
+    }
+
+    fn skip(&mut self, n: usize) -> Option<()> {
+        self.bytes(n).map(|_| ())
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.bytes(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.bytes(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u24(&mut self) -> Option<usize> {
+        self.bytes(3).map(|b| usize::from(b[0]) << 16 | usize::from(b[1]) << 8 | usize::from(b[2]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+
+    /// A minimal TLS 1.2 ClientHello record, with a server name if given
+    fn client_hello(name: Option<&str>) -> Vec<u8> {
+        let mut extensions = Vec::new();
+        if let Some(name) = name {
+            let mut entry = vec![NAME_TYPE_HOST_NAME];
+            entry.extend((name.len() as u16).to_be_bytes());
+            entry.extend(name.as_bytes());
+            let mut list = (entry.len() as u16).to_be_bytes().to_vec();
+            list.extend(entry);
+            extensions.extend(EXTENSION_SERVER_NAME.to_be_bytes());
+            extensions.extend((list.len() as u16).to_be_bytes());
+            extensions.extend(list);
+        }
+        // Supported groups, to have an extension before or without the name
+        extensions.extend([0x00, 0x0a, 0x00, 0x04, 0x00, 0x02, 0x00, 0x1d]);
+
+        let mut body = vec![0x03, 0x03];
+        body.extend([0u8; 32]);
+        body.push(0);
+        body.extend([0x00, 0x02, 0x13, 0x01]);
+        body.extend([0x01, 0x00]);
+        body.extend((extensions.len() as u16).to_be_bytes());
+        body.extend(extensions);
+
+        let mut handshake = vec![HANDSHAKE_CLIENT_HELLO];
+        handshake.extend(&(body.len() as u32).to_be_bytes()[1..]);
+        handshake.extend(body);
+
+        let mut record = vec![CONTENT_TYPE_HANDSHAKE, 0x03, 0x01];
+        record.extend((handshake.len() as u16).to_be_bytes());
+        record.extend(handshake);
+        record
+    }
+
+    #[test]
+    fn test_parses_server_name() {
+        let hello = client_hello(Some("Blocked.Example."));
+        assert_eq!(server_name(&hello), Ok(Some("blocked.example".to_string())));
+        assert_eq!(server_name(&hello[..hello.len() - 10]), Err(Incomplete));
+        assert_eq!(server_name(&hello[..3]), Err(Incomplete));
+        assert_eq!(server_name(&client_hello(None)), Ok(None));
+        assert_eq!(server_name(b"GET / HTTP/1.1\r\n"), Ok(None));
+    }
+
+    #[tokio::test]
+    async fn test_peeks_without_consuming() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+
+        // The hello arrives in two segments
+        let hello = client_hello(Some("video.example"));
+        let rest = hello[20..].to_vec();
+        client.write_all(&hello[..20]).await.unwrap();
+        let sender = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            client.write_all(&rest).await.unwrap();
+            client
+        });
+        let name = peek_server_name(&server, Duration::from_secs(5)).await;
+        assert_eq!(name.as_deref(), Some("video.example"));
+
+        let mut buf = vec![0u8; hello.len()];
+        server.peek(&mut buf).await.unwrap();
+        assert_eq!(buf, hello);
+        let _client = sender.await.unwrap();
+
+        assert_eq!(peek_server_name(&server, Duration::from_millis(20)).await.as_deref(), Some("video.example"));
+    }
+}
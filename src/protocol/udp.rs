@@ -0,0 +1,107 @@
+//! SOCKS5 UDP Datagrams
+//!
+//! Datagrams exchanged with the client over a UDP ASSOCIATE relay carry a
+//! header in front of the payload (RFC 1928 section 7):
+//! `RSV(2) FRAG(1) ATYP(1) DST.ADDR DST.PORT(2) DATA`.
+
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use anyhow::anyhow;
+
+use super::TargetAddr;
+use crate::protocol::constants::*;
+use crate::Result;
+
+/// A client datagram addressed to a target
+#[derive(Debug, Clone, PartialEq)]
+pub struct UdpDatagram<'a> {
+    /// Fragment number; 0 for a complete datagram
+    pub frag: u8,
+    pub target: TargetAddr,
+    pub port: u16,
+    pub payload: &'a [u8],
+}
+
+impl<'a> UdpDatagram<'a> {
+    /// Parse a datagram received from the client
+    pub fn parse(buf: &'a [u8]) -> Result<Self> {
+        if buf.len() < 4 {
+            return Err(anyhow!("UDP datagram too short: {} bytes", buf.len()));
+        }
+        if buf[0] != SOCKS5_RESERVED || buf[1] != SOCKS5_RESERVED {
+            return Err(anyhow!("Invalid reserved field in UDP datagram"));
+        }
+        let frag = buf[2];
+
+        let (target, rest) = match buf[3] {
+            SOCKS5_ADDR_IPV4 => {
+                let octets: [u8; 4] = buf.get(4..8)
+                    .ok_or_else(|| anyhow!("Truncated IPv4 address in UDP datagram"))?
+                    .try_into()?;
+                (TargetAddr::Ipv4(Ipv4Addr::from(octets)), &buf[8..])
+            }
+            SOCKS5_ADDR_IPV6 => {
+                let octets: [u8; 16] = buf.get(4..20)
+                    .ok_or_else(|| anyhow!("Truncated IPv6 address in UDP datagram"))?
+                    .try_into()?;
+                (TargetAddr::Ipv6(Ipv6Addr::from(octets)), &buf[20..])
+            }
+            SOCKS5_ADDR_DOMAIN => {
+                let len = *buf.get(4).ok_or_else(|| anyhow!("Truncated domain in UDP datagram"))? as usize;
+                let name = buf.get(5..5 + len)
+                    .ok_or_else(|| anyhow!("Truncated domain in UDP datagram"))?;
+                let domain = String::from_utf8(name.to_vec())
+                    .map_err(|_| anyhow!("Invalid domain name in UDP datagram"))?;
+                (TargetAddr::Domain(domain), &buf[5 + len..])
+            }
+            other => return Err(anyhow!("Unsupported address type in UDP datagram: {}", other)),
+        };
+
+        let port_bytes: [u8; 2] = rest.get(..2)
+            .ok_or_else(|| anyhow!("Truncated port in UDP datagram"))?
+            .try_into()?;
+        Ok(Self { frag, target, port: u16::from_be_bytes(port_bytes), payload: &rest[2..] })
+    }
+}
+
+/// Wrap a target's reply for the client, addressed from `source`
+pub fn encode_udp_reply(source: SocketAddr, payload: &[u8]) -> Vec<u8> {
+    let mut datagram = Vec::with_capacity(22 + payload.len());
+    datagram.extend_from_slice(&[SOCKS5_RESERVED, SOCKS5_RESERVED, 0]);
+    match source {
+        SocketAddr::V4(v4) => {
+            datagram.push(SOCKS5_ADDR_IPV4);
+            datagram.extend_from_slice(&v4.ip().octets());
+        }
+        SocketAddr::V6(v6) => {
+            datagram.push(SOCKS5_ADDR_IPV6);
+            datagram.extend_from_slice(&v6.ip().octets());
+        }
+    }
+    datagram.extend_from_slice(&source.port().to_be_bytes());
+    datagram.extend_from_slice(payload);
+    datagram
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reply_round_trips() {
+        let source: SocketAddr = "192.0.2.53:53".parse().unwrap();
+        let encoded = encode_udp_reply(source, b"answer");
+        let parsed = UdpDatagram::parse(&encoded).unwrap();
+        assert_eq!(parsed.frag, 0);
+        assert_eq!(parsed.target, TargetAddr::Ipv4(Ipv4Addr::new(192, 0, 2, 53)));
+        assert_eq!(parsed.port, 53);
+        assert_eq!(parsed.payload, b"answer");
+
+        let domain = [&[0, 0, 0, SOCKS5_ADDR_DOMAIN, 11][..], b"example.com", &[0, 80], b"hi"].concat();
+        let parsed = UdpDatagram::parse(&domain).unwrap();
+        assert_eq!(parsed.target, TargetAddr::Domain("example.com".to_string()));
+        assert_eq!(parsed.payload, b"hi");
+
+        assert!(UdpDatagram::parse(&domain[..10]).is_err());
+        assert!(UdpDatagram::parse(&[1, 0, 0, SOCKS5_ADDR_IPV4, 1, 2, 3, 4, 0, 80]).is_err());
+    }
+}
@@ -0,0 +1,490 @@
+//! Adaptive Relay Buffers
+//!
+//! Each relay direction starts with a small buffer and doubles it whenever
+//! reads fill it completely, up to `server.buffer_size`, so bulk transfers
+//! reach full throughput within a few reads. A direction that sees no data
+//! for `shrink_after` drops back to the initial size, so thousands of mostly
+//! idle connections (chat, IoT) each hold a few kilobytes instead of a full
+//! buffer.
+
+use std::future::Future;
+use std::io::{self, IoSlice};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::Sleep;
+
+use crate::config::ServerConfig;
+use super::session::RelayHandle;
+use super::transform::Direction;
+
+/// Relay buffer sizing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BufferSettings {
+    /// Size each direction starts at and returns to when idle
+    pub initial: usize,
+    /// Largest size a busy direction grows to
+    pub max: usize,
+    /// Idle time before a grown buffer is released
+    pub shrink_after: Duration,
+}
+
+impl Default for BufferSettings {
+    fn default() -> Self {
+        Self {
+            initial: 4096,
+            max: 8192,
+            shrink_after: Duration::from_secs(5),
+        }
+    }
+}
+
+impl BufferSettings {
+    pub fn from_config(server: &ServerConfig) -> Self {
+        Self {
+            initial: server.initial_buffer_size.min(server.buffer_size),
+            max: server.buffer_size,
+            shrink_after: server.buffer_shrink_after,
+        }
+    }
+}
+
+/// A ring buffer that grows with throughput and shrinks when idle
+///
+/// Reads land after the buffered bytes, wrapping to the start once the end
+/// is reached, so a direction can keep reading while a slow writer drains
+/// what came before. Wrapped bytes go out in one vectored write.
+#[derive(Debug)]
+struct AdaptiveBuffer {
+    buf: Vec<u8>,
+    /// Start of the buffered bytes, and how many there are
+    head: usize,
+    buffered: usize,
+    settings: BufferSettings,
+}
+
+impl AdaptiveBuffer {
+    fn new(settings: BufferSettings) -> Self {
+        Self { buf: vec![0; settings.initial.min(settings.max).max(1)], head: 0, buffered: 0, settings }
+    }
+
+    fn len(&self) -> usize {
+        self.buf.len()
+    }
+
+    fn is_grown(&self) -> bool {
+        self.buf.len() > self.settings.initial
+    }
+
+    fn is_empty(&self) -> bool {
+        self.buffered == 0
+    }
+
+    fn is_full(&self) -> bool {
+        self.buffered == self.len()
+    }
+
+    /// Free space after the buffered bytes, up to the end of the buffer or the head
+    fn unfilled(&mut self) -> &mut [u8] {
+        let tail = (self.head + self.buffered) % self.buf.len();
+        let end = if tail < self.head || self.is_full() { self.head } else { self.buf.len() };
+        &mut self.buf[tail..end]
+    }
+
+    /// Mark `n` bytes read into [`unfilled`](Self::unfilled) as buffered
+    fn fill(&mut self, n: usize) {
+        self.buffered += n;
+    }
+
+    /// The buffered bytes, in two parts if they wrap around the end
+    fn filled(&self) -> (&[u8], &[u8]) {
+        let end = self.head + self.buffered;
+        if end <= self.buf.len() {
+            (&self.buf[self.head..end], &[])
+        } else {
+            (&self.buf[self.head..], &self.buf[..end - self.buf.len()])
+        }
+    }
+
+    /// Drop `n` written bytes from the front
+    fn consume(&mut self, n: usize) {
+        self.buffered -= n;
+        self.head = if self.buffered == 0 { 0 } else { (self.head + n) % self.buf.len() };
+    }
+
+    /// Double the buffer after reads filled it
+    fn grow(&mut self) {
+        let size = (self.buf.len() * 2).min(self.settings.max);
+        if size > self.buf.len() {
+            self.buf.rotate_left(self.head);
+            self.head = 0;
+            self.buf.resize(size, 0);
+        }
+    }
+
+    /// Release a grown buffer after the direction went idle
+    fn shrink(&mut self) {
+        if self.is_grown() && self.is_empty() {
+            self.buf = vec![0; self.settings.initial.max(1)];
+            self.head = 0;
+        }
+    }
+}
+
+/// Progress of one direction of a relay
+#[derive(Debug)]
+enum CopyState {
+    /// Waiting for data, or writing out what was read
+    Copying,
+    /// The reader reached EOF; writing out what is left, then passing the
+    /// half-close on to the writer
+    Draining,
+    /// Reading failed; telling the writer's peer nothing more will come
+    Failing(Option<io::Error>),
+}
+
+/// One direction of a relay: copies `reader` to `writer` until EOF, then
+/// shuts the writer down so its peer sees the half-close, recording each
+/// read's time and size on the relay's handle
+///
+/// Every read the reader has ready is taken before writing, so a burst of
+/// small segments leaves in one large write, and reading goes on while the
+/// writer is busy, as long as there is room.
+#[derive(Debug)]
+struct CopyOne {
+    direction: Direction,
+    buffer: AdaptiveBuffer,
+    /// Bytes were written since the last flush
+    needs_flush: bool,
+    copied: u64,
+    /// Releases a grown buffer once the reader has been quiet for `shrink_after`
+    idle: Option<Pin<Box<Sleep>>>,
+    state: CopyState,
+}
+
+impl CopyOne {
+    fn new(direction: Direction, settings: BufferSettings) -> Self {
+        Self {
+            direction,
+            buffer: AdaptiveBuffer::new(settings),
+            needs_flush: false,
+            copied: 0,
+            idle: None,
+            state: CopyState::Copying,
+        }
+    }
+
+    fn poll_copy<R, W>(&mut self, cx: &mut Context<'_>, mut reader: Pin<&mut R>, mut writer: Pin<&mut W>, activity: &RelayHandle) -> Poll<io::Result<u64>>
+    where
+        R: AsyncRead + ?Sized,
+        W: AsyncWrite + ?Sized,
+    {
+        loop {
+            let mut progressed = false;
+            if let CopyState::Failing(error) = &mut self.state {
+                let _ = ready!(writer.as_mut().poll_shutdown(cx));
+                return Poll::Ready(Err(error.take().expect("copy polled after failing")));
+            }
+
+            // Take everything the reader has ready, as far as there is room
+            while matches!(self.state, CopyState::Copying) && !self.buffer.is_full() {
+                let mut buf = ReadBuf::new(self.buffer.unfilled());
+                match reader.as_mut().poll_read(cx, &mut buf) {
+                    Poll::Ready(Ok(())) => {
+                        let read = buf.filled().len();
+                        self.idle = None;
+                        progressed = true;
+                        if read == 0 {
+                            activity.touch();
+                            self.state = CopyState::Draining;
+                        } else {
+                            activity.record(self.direction, read as u64);
+                            self.buffer.fill(read);
+                            if self.buffer.is_full() {
+                                self.buffer.grow();
+                            }
+                        }
+                    }
+                    // Nothing more will come, so tell the writer's peer
+                    Poll::Ready(Err(e)) => {
+                        self.state = CopyState::Failing(Some(e));
+                        progressed = true;
+                        break;
+                    }
+                    Poll::Pending => {
+                        // Only grown buffers need the idle timer; small ones wait as long as it takes
+                        if self.buffer.is_grown() && self.buffer.is_empty() {
+                            let shrink_after = self.buffer.settings.shrink_after;
+                            let idle = self.idle.get_or_insert_with(|| Box::pin(tokio::time::sleep(shrink_after)));
+                            if idle.as_mut().poll(cx).is_ready() {
+                                self.idle = None;
+                                self.buffer.shrink();
+                            }
+                        }
+                        break;
+                    }
+                }
+            }
+            if matches!(self.state, CopyState::Failing(_)) {
+                continue;
+            }
+
+            if !self.buffer.is_empty() {
+                let written = match self.buffer.filled() {
+                    (first, second) if !second.is_empty() && writer.is_write_vectored() => {
+                        writer.as_mut().poll_write_vectored(cx, &[IoSlice::new(first), IoSlice::new(second)])
+                    }
+                    (first, _) => writer.as_mut().poll_write(cx, first),
+                };
+                if let Poll::Ready(written) = written {
+                    let n = written?;
+                    if n == 0 {
+                        return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+                    }
+                    self.buffer.consume(n);
+                    self.copied += n as u64;
+                    self.needs_flush = true;
+                    progressed = true;
+                }
+            }
+
+            // Flush once everything read so far is written
+            if self.buffer.is_empty() && self.needs_flush {
+                ready!(writer.as_mut().poll_flush(cx))?;
+                self.needs_flush = false;
+            }
+
+            if self.buffer.is_empty() && matches!(self.state, CopyState::Draining) {
+                return match ready!(writer.as_mut().poll_shutdown(cx)) {
+                    // The peer already closed the connection and needs no FIN
+                    Err(e) if e.kind() == io::ErrorKind::NotConnected => Poll::Ready(Ok(self.copied)),
+                    result => Poll::Ready(result.map(|()| self.copied)),
+                };
+            }
+            if !progressed {
+                return Poll::Pending;
+            }
+        }
+    }
+}
+
+/// Copy data both ways between `a` and `b` until both directions finish,
+/// returning the bytes copied from `a` to `b` and from `b` to `a`
+///
+/// Each direction ends on its own: an EOF is passed on as a half-close while
+/// the other direction keeps flowing, so a client can shut down writing after
+/// its request and still read the whole response. A direction that fails
+/// does not cut the other short either; its error is returned once both end.
+/// `a` is the client side, so bytes it sends count as upstream throughput.
+///
+/// Both directions are polled from the calling task, without splitting the
+/// streams, so a relay costs one task and no locking per read or write.
+pub async fn copy_bidirectional<A, B>(a: &mut A, b: &mut B, settings: BufferSettings, activity: &RelayHandle) -> io::Result<(u64, u64)>
+where
+    A: AsyncRead + AsyncWrite + Unpin + ?Sized,
+    B: AsyncRead + AsyncWrite + Unpin + ?Sized,
+{
+    let mut a_to_b = CopyOne::new(Direction::Upstream, settings);
+    let mut b_to_a = CopyOne::new(Direction::Downstream, settings);
+    let (mut up, mut down) = (None, None);
+    std::future::poll_fn(|cx| {
+        if up.is_none() {
+            if let Poll::Ready(result) = a_to_b.poll_copy(cx, Pin::new(&mut *a), Pin::new(&mut *b), activity) {
+                up = Some(result);
+            }
+        }
+        if down.is_none() {
+            if let Poll::Ready(result) = b_to_a.poll_copy(cx, Pin::new(&mut *b), Pin::new(&mut *a), activity) {
+                down = Some(result);
+            }
+        }
+        if up.is_some() && down.is_some() { Poll::Ready(()) } else { Poll::Pending }
+    }).await;
+    Ok((up.unwrap()?, down.unwrap()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn test_buffer_grows_to_max_and_shrinks() {
+        let settings = BufferSettings { initial: 1024, max: 5000, shrink_after: Duration::from_secs(1) };
+        let mut buffer = AdaptiveBuffer::new(settings);
+        assert_eq!(buffer.len(), 1024);
+
+        buffer.grow();
+        buffer.grow();
+        assert_eq!(buffer.len(), 4096);
+        buffer.grow();
+        buffer.grow();
+        assert_eq!(buffer.len(), 5000);
+
+        buffer.shrink();
+        assert_eq!(buffer.len(), 1024);
+        assert!(!buffer.is_grown());
+    }
+
+    #[test]
+    fn test_reads_wrap_around_behind_unwritten_bytes() {
+        let settings = BufferSettings { initial: 8, max: 8, shrink_after: Duration::from_secs(1) };
+        let mut buffer = AdaptiveBuffer::new(settings);
+        buffer.unfilled()[..8].copy_from_slice(b"abcdefgh");
+        buffer.fill(8);
+        assert!(buffer.is_full());
+
+        buffer.consume(5);
+        let unfilled = buffer.unfilled();
+        assert_eq!(unfilled.len(), 5);
+        unfilled[..4].copy_from_slice(b"ijkl");
+        buffer.fill(4);
+        assert_eq!(buffer.filled(), (&b"fgh"[..], &b"ijkl"[..]));
+
+        // Growing keeps the buffered bytes in order
+        let settings = BufferSettings { max: 16, ..settings };
+        buffer.settings = settings;
+        buffer.grow();
+        assert_eq!(buffer.filled(), (&b"fghijkl"[..], &b""[..]));
+    }
+
+    /// A slow writer taking a few bytes at a time, vectored if asked
+    struct TrickleWriter {
+        received: Vec<u8>,
+        vectored_writes: usize,
+    }
+
+    impl AsyncWrite for TrickleWriter {
+        fn poll_write(mut self: std::pin::Pin<&mut Self>, _cx: &mut std::task::Context<'_>, buf: &[u8]) -> std::task::Poll<io::Result<usize>> {
+            let n = buf.len().min(3);
+            self.received.extend_from_slice(&buf[..n]);
+            std::task::Poll::Ready(Ok(n))
+        }
+
+        fn poll_write_vectored(mut self: std::pin::Pin<&mut Self>, _cx: &mut std::task::Context<'_>, bufs: &[IoSlice<'_>]) -> std::task::Poll<io::Result<usize>> {
+            if bufs.len() > 1 {
+                self.vectored_writes += 1;
+            }
+            let mut n = 0;
+            for buf in bufs {
+                self.received.extend_from_slice(buf);
+                n += buf.len();
+            }
+            std::task::Poll::Ready(Ok(n))
+        }
+
+        fn is_write_vectored(&self) -> bool {
+            true
+        }
+
+        fn poll_flush(self: std::pin::Pin<&mut Self>, _cx: &mut std::task::Context<'_>) -> std::task::Poll<io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: std::pin::Pin<&mut Self>, _cx: &mut std::task::Context<'_>) -> std::task::Poll<io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_wrapped_bytes_go_out_in_one_vectored_write() {
+        let settings = BufferSettings { initial: 16, max: 16, shrink_after: Duration::from_secs(1) };
+        let data: Vec<u8> = (0..200u8).collect();
+        let mut reader = &data[..];
+        let mut writer = TrickleWriter { received: Vec::new(), vectored_writes: 0 };
+        let mut copy = CopyOne::new(Direction::Downstream, settings);
+        let copied = std::future::poll_fn(|cx| copy.poll_copy(cx, Pin::new(&mut reader), Pin::new(&mut writer), &RelayHandle::new()))
+            .await
+            .unwrap();
+        assert_eq!(copied, 200);
+        assert_eq!(writer.received, data);
+        assert!(writer.vectored_writes > 0);
+    }
+
+    #[tokio::test]
+    async fn test_copies_both_ways_and_releases_idle_buffers() {
+        let settings = BufferSettings { initial: 16, max: 256, shrink_after: Duration::from_millis(20) };
+        let (mut client, mut client_side) = tokio::io::duplex(1024);
+        let (mut target_side, mut target) = tokio::io::duplex(1024);
+        let activity = RelayHandle::new();
+        let relay = {
+            let activity = activity.clone();
+            tokio::spawn(async move { copy_bidirectional(&mut client_side, &mut target_side, settings, &activity).await })
+        };
+
+        let upload = vec![7u8; 10_000];
+        let writer = tokio::spawn(async move {
+            client.write_all(&upload).await.unwrap();
+            // Let the grown buffer go idle before the second burst
+            tokio::time::sleep(Duration::from_millis(60)).await;
+            client.write_all(b"ping").await.unwrap();
+            client.shutdown().await.unwrap();
+            let mut reply = Vec::new();
+            client.read_to_end(&mut reply).await.unwrap();
+            reply
+        });
+
+        let mut received = Vec::new();
+        target.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received.len(), 10_004);
+        assert!(received.ends_with(b"ping"));
+        target.write_all(b"pong").await.unwrap();
+        target.shutdown().await.unwrap();
+
+        assert_eq!(writer.await.unwrap(), b"pong");
+        assert_eq!(relay.await.unwrap().unwrap(), (10_004, 4));
+        assert!(activity.idle_for() < Duration::from_millis(60));
+    }
+
+    /// A client that has stopped reading: writes to it fail, but it still sends
+    struct DeafClient(tokio::io::DuplexStream);
+
+    impl AsyncRead for DeafClient {
+        fn poll_read(mut self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>, buf: &mut tokio::io::ReadBuf<'_>) -> std::task::Poll<io::Result<()>> {
+            std::pin::Pin::new(&mut self.0).poll_read(cx, buf)
+        }
+    }
+
+    impl AsyncWrite for DeafClient {
+        fn poll_write(self: std::pin::Pin<&mut Self>, _cx: &mut std::task::Context<'_>, _buf: &[u8]) -> std::task::Poll<io::Result<usize>> {
+            std::task::Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()))
+        }
+
+        fn poll_flush(self: std::pin::Pin<&mut Self>, _cx: &mut std::task::Context<'_>) -> std::task::Poll<io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: std::pin::Pin<&mut Self>, _cx: &mut std::task::Context<'_>) -> std::task::Poll<io::Result<()>> {
+            std::task::Poll::Ready(Err(io::ErrorKind::NotConnected.into()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failed_direction_leaves_the_other_flowing() {
+        let settings = BufferSettings::default();
+        let (mut client, client_side) = tokio::io::duplex(1024);
+        let (mut target_side, mut target) = tokio::io::duplex(1024);
+
+        // The reply cannot be delivered before the upload has even started
+        target.write_all(b"pong").await.unwrap();
+        let relay = tokio::spawn(async move {
+            let mut client_side = DeafClient(client_side);
+            copy_bidirectional(&mut client_side, &mut target_side, settings, &RelayHandle::new()).await
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        let upload = vec![7u8; 10_000];
+        let writer = tokio::spawn(async move {
+            client.write_all(&upload).await.unwrap();
+            client.shutdown().await.unwrap();
+        });
+        let mut received = Vec::new();
+        target.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received.len(), 10_000);
+        writer.await.unwrap();
+
+        let error = relay.await.unwrap().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::BrokenPipe);
+    }
+}
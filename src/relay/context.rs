@@ -0,0 +1,219 @@
+//! Connection Context
+//!
+//! A versioned, serializable description of a relayed connection handed to
+//! extensions: observers and transformers read it from
+//! [`RelaySession::context`](super::RelaySession::context) instead of
+//! reaching into internal types, and it is written to the session's log
+//! line. Within a [`CONTEXT_VERSION`] fields are only ever added; renaming or
+//! removing one bumps the version, so consumers can check `version` and
+//! ignore fields they do not know.
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Instant, SystemTime};
+
+use crate::config::{DnsResolution, PrivacyConfig};
+use crate::protocol::TargetAddr;
+use crate::routing::GeoIpHandle;
+use super::qos::TrafficClass;
+
+/// Version of the [`ConnectionContext`] layout
+pub const CONTEXT_VERSION: u32 = 1;
+
+/// Everything known about a connection once its target is connected
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConnectionContext {
+    pub version: u32,
+    pub connection_id: String,
+    pub client: ClientContext,
+    /// Authenticated user, if any
+    #[serde(default)]
+    pub user: Option<UserContext>,
+    pub target: TargetContext,
+    #[serde(default)]
+    pub geo: GeoContext,
+    #[serde(default)]
+    pub routing: RoutingContext,
+    #[serde(default)]
+    pub timings: ConnectionTimings,
+    /// Privacy exclusions apply: addresses and user stay out of logs
+    #[serde(default)]
+    pub redacted: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClientContext {
+    pub addr: SocketAddr,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserContext {
+    pub id: String,
+    #[serde(default)]
+    pub groups: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TargetContext {
+    /// Domain name or IP address as the client requested it
+    pub host: String,
+    pub port: u16,
+    /// Address the target was resolved to here, when it was
+    #[serde(default)]
+    pub resolved: Option<SocketAddr>,
+    /// Upstream proxies carrying the connection in dialing order
+    #[serde(default)]
+    pub upstreams: Vec<SocketAddr>,
+    /// Address a routing rule sent the connection to instead of the target
+    #[serde(default)]
+    pub redirect: Option<SocketAddr>,
+    /// Host a routing rule rewrote the target to, connected on the requested port
+    #[serde(default)]
+    pub rewrite: Option<String>,
+}
+
+/// GeoIP countries (ISO 3166 codes), when a database is loaded
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct GeoContext {
+    pub client_country: Option<String>,
+    pub target_country: Option<String>,
+}
+
+impl GeoContext {
+    /// Look up both ends of a connection
+    pub fn lookup(geoip: Option<&GeoIpHandle>, client: IpAddr, target: Option<IpAddr>) -> Self {
+        let Some(filter) = geoip.and_then(GeoIpHandle::current) else {
+            return Self::default();
+        };
+        Self {
+            client_country: filter.get_country(client),
+            target_country: target.and_then(|ip| filter.get_country(ip)),
+        }
+    }
+}
+
+/// What routing decided for the connection
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RoutingContext {
+    /// Routing rule that matched; none when the default applied
+    pub rule: Option<String>,
+    pub dscp: Option<u8>,
+    pub transformers: Vec<String>,
+    /// Who resolved a domain target: `local`, or `remote` at the upstream proxy
+    #[serde(default)]
+    pub dns_resolution: DnsResolution,
+    /// Bandwidth class the connection shares its rate caps with
+    #[serde(default)]
+    pub bandwidth_class: Option<String>,
+    /// QoS traffic class the connection was placed in
+    #[serde(default)]
+    pub traffic_class: Option<TrafficClass>,
+}
+
+/// When the connection was accepted and how long each phase took
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ConnectionTimings {
+    /// RFC 3339 time the connection was accepted
+    pub accepted_at: String,
+    pub handshake_ms: u64,
+    pub auth_ms: u64,
+    pub routing_ms: u64,
+    pub connect_ms: u64,
+}
+
+/// Measures consecutive connection phases
+pub struct PhaseTimer {
+    accepted_at: SystemTime,
+    last: Instant,
+}
+
+impl PhaseTimer {
+    pub fn start() -> Self {
+        Self { accepted_at: SystemTime::now(), last: Instant::now() }
+    }
+
+    /// Milliseconds since the previous lap (or the start)
+    pub fn lap(&mut self) -> u64 {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_millis() as u64;
+        self.last = now;
+        elapsed
+    }
+
+    /// Timings with the accept time filled in
+    pub fn timings(&self) -> ConnectionTimings {
+        ConnectionTimings {
+            accepted_at: humantime::format_rfc3339_millis(self.accepted_at).to_string(),
+            ..Default::default()
+        }
+    }
+}
+
+impl TargetContext {
+    pub fn new(target: &TargetAddr, port: u16) -> Self {
+        let host = match target {
+            TargetAddr::Ipv4(ip) => ip.to_string(),
+            TargetAddr::Ipv6(ip) => ip.to_string(),
+            TargetAddr::Domain(domain) => domain.clone(),
+        };
+        Self { host, port, resolved: None, upstreams: Vec::new(), redirect: None, rewrite: None }
+    }
+}
+
+impl ConnectionContext {
+    /// JSON for logs, with client, user, target and geo redacted when privacy exclusions apply
+    pub fn to_log_value(&self) -> Value {
+        let mut value = serde_json::to_value(self).unwrap_or(Value::Null);
+        if self.redacted {
+            if let Value::Object(map) = &mut value {
+                for key in ["client", "user", "target", "geo"] {
+                    map.insert(key.to_string(), Value::String(PrivacyConfig::REDACTED.to_string()));
+                }
+            }
+        }
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context(redacted: bool) -> ConnectionContext {
+        ConnectionContext {
+            version: CONTEXT_VERSION,
+            connection_id: "conn_1".to_string(),
+            client: ClientContext { addr: "198.51.100.7:50000".parse().unwrap() },
+            user: Some(UserContext { id: "alice".to_string(), groups: vec!["staff".to_string()] }),
+            target: TargetContext::new(&TargetAddr::Domain("example.com".to_string()), 443),
+            geo: GeoContext::default(),
+            routing: RoutingContext { rule: Some("staff".to_string()), ..Default::default() },
+            timings: PhaseTimer::start().timings(),
+            redacted,
+        }
+    }
+
+    #[test]
+    fn test_context_round_trips_and_tolerates_new_fields() {
+        let mut value = serde_json::to_value(context(false)).unwrap();
+        assert_eq!(value["version"], CONTEXT_VERSION);
+        assert_eq!(value["target"]["host"], "example.com");
+        assert_eq!(value["routing"]["rule"], "staff");
+
+        value["future_field"] = Value::Bool(true);
+        let parsed: ConnectionContext = serde_json::from_value(value).unwrap();
+        assert_eq!(parsed.user.unwrap().groups, ["staff"]);
+    }
+
+    #[test]
+    fn test_log_value_honours_redaction() {
+        let shown = context(false).to_log_value().to_string();
+        assert!(shown.contains("alice") && shown.contains("example.com"));
+
+        let hidden = context(true).to_log_value();
+        let text = hidden.to_string();
+        assert!(!text.contains("alice") && !text.contains("example.com") && !text.contains("198.51.100.7"));
+        assert_eq!(hidden["routing"]["rule"], "staff");
+    }
+}
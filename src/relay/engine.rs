@@ -4,20 +4,53 @@ use std::collections::HashMap;
 use std::net::{IpAddr, SocketAddr};
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
-use tokio::net::{TcpStream, lookup_host};
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpSocket, TcpStream};
 use tokio::time::timeout;
 use tracing::{debug, error, info, warn};
 use anyhow::{anyhow, Context};
 
 use crate::Result;
+use crate::config::SessionExpiryAction;
+use crate::connection::{set_fwmark, Deadline, DeadlineExceeded, EgressBind, LoopGuard, ProxyLoop, SocketOptions};
+use crate::metrics::ConnectionResult;
 use crate::protocol::types::TargetAddr;
 use crate::protocol::constants::*;
-use super::{RelaySession, session::ConnectionStats};
+use crate::routing::Resolver;
+use crate::security::private_ranges::{self, PrivateAddress};
+use super::{ConnectionContext, RelayHandle, RelaySession, RelayTarget, session::ConnectionStats};
+use super::buffer::{copy_bidirectional, BufferSettings};
+use super::progress::{BandwidthClass, BandwidthLimit, CountingStream, ProgressSettings, ProgressTracker, RelayObserver};
+use super::qos::QosLane;
+use super::transform::{RelayTransformer, TransformPipeline, TransformStream};
 
 /// Handles data relay between client and target connections
 pub struct RelayEngine {
     connection_timeout: Duration,
     active_sessions: Arc<Mutex<HashMap<String, Arc<RelaySession>>>>,
+    dscp: Option<u8>,
+    socket_options: SocketOptions,
+    egress_bind: Option<EgressBind>,
+    fwmark: Option<u32>,
+    observers: Vec<Arc<dyn RelayObserver>>,
+    progress: ProgressSettings,
+    bandwidth: BandwidthLimit,
+    bandwidth_classes: Vec<Arc<BandwidthClass>>,
+    qos: Option<QosLane>,
+    max_lifetime: Option<(Duration, SessionExpiryAction)>,
+    connection_lifetime: Option<Duration>,
+    lifetime_warning: Duration,
+    access_window_end: Option<Duration>,
+    redacted: bool,
+    upstreams: Vec<SocketAddr>,
+    loop_guard: LoopGuard,
+    protect_private: bool,
+    transformers: Vec<Arc<dyn RelayTransformer>>,
+    deadline: Deadline,
+    context: Option<Arc<ConnectionContext>>,
+    buffers: BufferSettings,
+    resolver: Arc<Resolver>,
+    handle: RelayHandle,
 }
 
 impl RelayEngine {
@@ -26,6 +59,29 @@ impl RelayEngine {
         Self {
             connection_timeout: Duration::from_secs(300), // Default 5 minute timeout for data relay
             active_sessions: Arc::new(Mutex::new(HashMap::new())),
+            dscp: None,
+            socket_options: SocketOptions::default(),
+            egress_bind: None,
+            fwmark: None,
+            observers: Vec::new(),
+            progress: ProgressSettings::default(),
+            bandwidth: BandwidthLimit::default(),
+            bandwidth_classes: Vec::new(),
+            qos: None,
+            max_lifetime: None,
+            connection_lifetime: None,
+            lifetime_warning: Duration::ZERO,
+            access_window_end: None,
+            redacted: false,
+            upstreams: Vec::new(),
+            loop_guard: LoopGuard::default(),
+            protect_private: false,
+            transformers: Vec::new(),
+            deadline: Deadline::unbounded(),
+            context: None,
+            buffers: BufferSettings::default(),
+            resolver: Arc::new(Resolver::default()),
+            handle: RelayHandle::default(),
         }
     }
 
@@ -34,6 +90,29 @@ impl RelayEngine {
         Self {
             connection_timeout,
             active_sessions: Arc::new(Mutex::new(HashMap::new())),
+            dscp: None,
+            socket_options: SocketOptions::default(),
+            egress_bind: None,
+            fwmark: None,
+            observers: Vec::new(),
+            progress: ProgressSettings::default(),
+            bandwidth: BandwidthLimit::default(),
+            bandwidth_classes: Vec::new(),
+            qos: None,
+            max_lifetime: None,
+            connection_lifetime: None,
+            lifetime_warning: Duration::ZERO,
+            access_window_end: None,
+            redacted: false,
+            upstreams: Vec::new(),
+            loop_guard: LoopGuard::default(),
+            protect_private: false,
+            transformers: Vec::new(),
+            deadline: Deadline::unbounded(),
+            context: None,
+            buffers: BufferSettings::default(),
+            resolver: Arc::new(Resolver::default()),
+            handle: RelayHandle::default(),
         }
     }
 
@@ -42,6 +121,190 @@ impl RelayEngine {
         Self {
             connection_timeout: config.server.connection_timeout,
             active_sessions: Arc::new(Mutex::new(HashMap::new())),
+            dscp: None,
+            socket_options: config.server.egress_socket,
+            egress_bind: None,
+            fwmark: None,
+            observers: Vec::new(),
+            progress: ProgressSettings {
+                interval: config.monitoring.stats_update_interval,
+                threshold_bytes: config.monitoring.stats_update_bytes,
+            },
+            bandwidth: BandwidthLimit::default(),
+            bandwidth_classes: Vec::new(),
+            qos: None,
+            max_lifetime: None,
+            connection_lifetime: config.server.max_connection_lifetime,
+            lifetime_warning: config.server.connection_lifetime_warning,
+            access_window_end: None,
+            redacted: false,
+            upstreams: Vec::new(),
+            loop_guard: LoopGuard::from_config(config),
+            protect_private: config.security.private_ranges.enabled,
+            transformers: Vec::new(),
+            deadline: Deadline::unbounded(),
+            context: None,
+            buffers: BufferSettings::from_config(&config.server),
+            resolver: Arc::new(Resolver::from_config(&config.routing)),
+            handle: RelayHandle::default(),
+        }
+    }
+
+    /// Mark outbound connections with the given DSCP value
+    pub fn with_dscp(mut self, dscp: Option<u8>) -> Self {
+        self.dscp = dscp;
+        self
+    }
+
+    /// Set `options` on outbound sockets before they connect
+    pub fn with_socket_options(mut self, options: SocketOptions) -> Self {
+        self.socket_options = options;
+        self
+    }
+
+    /// Connect to targets from the given local address or interface
+    pub fn with_egress_bind(mut self, egress_bind: Option<EgressBind>) -> Self {
+        self.egress_bind = egress_bind;
+        self
+    }
+
+    /// Set a firewall mark on outbound connections for policy routing
+    pub fn with_fwmark(mut self, fwmark: Option<u32>) -> Self {
+        self.fwmark = fwmark;
+        self
+    }
+
+    /// Report live byte counts for relayed sessions to an observer
+    pub fn with_observer(mut self, observer: Arc<dyn RelayObserver>) -> Self {
+        self.observers.push(observer);
+        self
+    }
+
+    /// Cap upload and download rates for relayed sessions
+    pub fn with_bandwidth_limit(mut self, bandwidth: BandwidthLimit) -> Self {
+        self.bandwidth = bandwidth;
+        self
+    }
+
+    /// Share a bandwidth class's rate caps with the other relays placed in it
+    ///
+    /// A relay can be in several classes, e.g. a routing rule's and its
+    /// user's, and is paced to stay under all of them.
+    pub fn with_bandwidth_class(mut self, class: Option<Arc<BandwidthClass>>) -> Self {
+        self.bandwidth_classes.extend(class);
+        self
+    }
+
+    /// Pace relayed sessions to their traffic class's share of the link
+    pub fn with_traffic_class(mut self, lane: Option<QosLane>) -> Self {
+        self.qos = lane;
+        self
+    }
+
+    /// End (or flag) relays once `remaining` has passed, e.g. when the
+    /// authenticated session reaches its maximum lifetime
+    pub fn with_max_lifetime(mut self, remaining: Duration, action: SessionExpiryAction) -> Self {
+        self.max_lifetime = Some((remaining, action));
+        self
+    }
+
+    /// Close connections once they are `lifetime` old, counted from when they
+    /// were accepted, warning observers `warning` beforehand
+    pub fn with_connection_lifetime(mut self, lifetime: Option<Duration>, warning: Duration) -> Self {
+        self.connection_lifetime = lifetime;
+        self.lifetime_warning = warning;
+        self
+    }
+
+    /// End relays once the user's access window closes in `remaining`
+    pub fn with_access_window_end(mut self, remaining: Duration) -> Self {
+        self.access_window_end = Some(remaining);
+        self
+    }
+
+    /// Run relayed bytes through a transformer; transformers apply to client
+    /// traffic in the order they are added
+    pub fn with_transformer(mut self, transformer: Arc<dyn RelayTransformer>) -> Self {
+        self.transformers.push(transformer);
+        self
+    }
+
+    /// Bound DNS resolution and connect attempts by the connection's overall deadline
+    pub fn with_deadline(mut self, deadline: Deadline) -> Self {
+        self.deadline = deadline;
+        self
+    }
+
+    /// Keep target addresses and users out of logs and per-connection metrics
+    pub fn with_redaction(mut self, redacted: bool) -> Self {
+        self.redacted = redacted;
+        self
+    }
+
+    /// Tag sessions with the upstream proxies their target stream goes through
+    pub fn with_upstreams(mut self, upstreams: Vec<SocketAddr>) -> Self {
+        self.upstreams = upstreams;
+        self
+    }
+
+    /// Refuse targets that lead back into this proxy
+    pub fn with_loop_guard(mut self, loop_guard: LoopGuard) -> Self {
+        self.loop_guard = loop_guard;
+        self
+    }
+
+    /// Refuse (or stop refusing) targets at private and internal addresses
+    pub fn with_private_range_protection(mut self, enabled: bool) -> Self {
+        self.protect_private = enabled;
+        self
+    }
+
+    /// Fail if connecting to `addr` would loop back into this proxy or its upstreams
+    pub fn check_loop(&self, addr: SocketAddr) -> std::result::Result<(), ProxyLoop> {
+        self.loop_guard.check(addr, &self.upstreams)
+    }
+
+    /// Fail if the upstream chain passes through this proxy or repeats a hop
+    pub fn check_chain_loop(&self) -> std::result::Result<(), ProxyLoop> {
+        self.loop_guard.check_chain(&self.upstreams)
+    }
+
+    /// Describe the connection to observers, transformers and the session log
+    pub fn with_context(mut self, context: ConnectionContext) -> Self {
+        self.context = Some(Arc::new(context));
+        self
+    }
+
+    /// Override how often live byte counts are reported
+    pub fn with_progress_settings(mut self, progress: ProgressSettings) -> Self {
+        self.progress = progress;
+        self
+    }
+
+    /// Override how relay buffers start, grow and shrink
+    pub fn with_buffer_settings(mut self, buffers: BufferSettings) -> Self {
+        self.buffers = buffers;
+        self
+    }
+
+    /// Resolve target domains through a DNS cache shared with other connections
+    pub fn with_resolver(mut self, resolver: Arc<Resolver>) -> Self {
+        self.resolver = resolver;
+        self
+    }
+
+    /// Record the relay's activity on `handle`, and close it when the handle is cancelled
+    pub fn with_handle(mut self, handle: RelayHandle) -> Self {
+        self.handle = handle;
+        self
+    }
+
+    /// Format a value for logging, honouring redaction
+    fn shown(&self, value: impl std::fmt::Display) -> String {
+        if self.redacted {
+            crate::config::PrivacyConfig::REDACTED.to_string()
+        } else {
+            value.to_string()
         }
     }
 
@@ -53,12 +316,22 @@ impl RelayEngine {
         let socket_addrs = self.resolve_target_address(target_addr, port).await
             .context("Failed to resolve target address")?;
 
+        // A name may resolve to the proxy itself or into the internal network,
+        // so check every address before trying any
+        for addr in &socket_addrs {
+            self.check_loop(*addr)?;
+            if self.protect_private {
+                private_ranges::check(*addr)?;
+            }
+        }
+
         // Try connecting to each resolved address
         let mut last_error = None;
         for addr in socket_addrs {
+            self.deadline.check("connect")?;
             match self.try_connect_to_address(addr).await {
                 Ok(stream) => {
-                    info!("Successfully connected to target: {}", addr);
+                    info!("Successfully connected to target: {}", self.shown(addr));
                     return Ok((stream, addr));
                 }
                 Err(e) => {
@@ -69,6 +342,7 @@ impl RelayEngine {
         }
 
         // If we get here, all connection attempts failed
+        self.deadline.check("connect")?;
         let error_msg = format!("Failed to connect to target {}:{}", target_addr.to_string(), port);
         if let Some(e) = last_error {
             Err(anyhow!("{}: {}", error_msg, e))
@@ -78,7 +352,7 @@ impl RelayEngine {
     }
 
     /// Resolve target address to socket addresses
-    async fn resolve_target_address(&self, target_addr: &TargetAddr, port: u16) -> Result<Vec<SocketAddr>> {
+    pub async fn resolve_target_address(&self, target_addr: &TargetAddr, port: u16) -> Result<Vec<SocketAddr>> {
         match target_addr {
             TargetAddr::Ipv4(ip) => {
                 let addr = SocketAddr::new(IpAddr::V4(*ip), port);
@@ -91,23 +365,19 @@ impl RelayEngine {
             TargetAddr::Domain(domain) => {
                 debug!("Resolving domain: {}:{}", domain, port);
                 
-                // Use tokio's lookup_host for DNS resolution
-                let host_port = format!("{}:{}", domain, port);
-                let lookup_future = lookup_host(host_port);
-                match timeout(self.connection_timeout, lookup_future).await {
-                    Ok(Ok(addrs)) => {
-                        let resolved_addrs: Vec<SocketAddr> = addrs.collect();
-                        if resolved_addrs.is_empty() {
-                            return Err(anyhow!("DNS resolution returned no addresses for {}", domain));
-                        }
+                // Answers are cached across connections by the shared resolver
+                let lookup_future = self.resolver.resolve_socket_addrs(domain, port);
+                match timeout(self.deadline.cap(self.connection_timeout), lookup_future).await {
+                    Ok(Ok(resolved_addrs)) => {
                         debug!("Resolved {} to {} addresses", domain, resolved_addrs.len());
                         Ok(resolved_addrs)
                     }
                     Ok(Err(e)) => {
-                        error!("DNS resolution failed for {}: {}", domain, e);
-                        Err(anyhow!("DNS resolution failed for {}: {}", domain, e))
+                        error!("{:#}", e);
+                        Err(e)
                     }
                     Err(_) => {
+                        self.deadline.check("DNS resolution")?;
                         error!("DNS resolution timed out for {}", domain);
                         Err(anyhow!("DNS resolution timed out for {}", domain))
                     }
@@ -118,15 +388,51 @@ impl RelayEngine {
 
     /// Try to connect to a specific socket address
     async fn try_connect_to_address(&self, addr: SocketAddr) -> Result<TcpStream> {
-        match timeout(self.connection_timeout, TcpStream::connect(addr)).await {
+        let socket = if addr.is_ipv4() { TcpSocket::new_v4() } else { TcpSocket::new_v6() }
+            .context("Failed to create socket")?;
+        if let Some(dscp) = self.dscp {
+            // Set before connecting so the SYN already carries the marking
+            if let Err(e) = set_dscp(&socket, addr, dscp) {
+                warn!("Failed to set DSCP {} on connection to {}: {}", dscp, addr, e);
+            }
+        }
+        if let Err(e) = self.socket_options.apply(socket2::SockRef::from(&socket)) {
+            warn!("Failed to set socket options on connection to {}: {}", self.shown(addr), e);
+        }
+        if let Some(egress_bind) = &self.egress_bind {
+            egress_bind.bind(&socket, addr)
+                .with_context(|| format!("Failed to bind outbound connection to {}", egress_bind))?;
+        }
+        // Without its mark the connection could leave through the wrong uplink, so failing is safer
+        if let Some(mark) = self.fwmark {
+            set_fwmark(&socket, mark).with_context(|| format!("Failed to set firewall mark {}", mark))?;
+        }
+
+        match timeout(self.deadline.cap(self.connection_timeout), socket.connect(addr)).await {
             Ok(Ok(stream)) => Ok(stream),
             Ok(Err(e)) => Err(anyhow!("Connection failed: {}", e)),
-            Err(_) => Err(anyhow!("Connection timed out")),
+            Err(_) => {
+                self.deadline.check("connect")?;
+                Err(anyhow!("Connection timed out"))
+            }
         }
     }
 
     /// Convert connection error to appropriate SOCKS5 error code
     pub fn connection_error_to_socks5_code(&self, error: &anyhow::Error) -> u8 {
+        if error.downcast_ref::<DeadlineExceeded>().is_some() {
+            return SOCKS5_REPLY_TTL_EXPIRED;
+        }
+        // Like an expired IP TTL, the connection would otherwise circle forever
+        if error.downcast_ref::<ProxyLoop>().is_some() {
+            return SOCKS5_REPLY_TTL_EXPIRED;
+        }
+        if error.downcast_ref::<PrivateAddress>().is_some() {
+            return SOCKS5_REPLY_CONNECTION_NOT_ALLOWED;
+        }
+        if let Some(upstream) = error.downcast_ref::<crate::routing::UpstreamError>() {
+            return upstream.reply_code();
+        }
         let error_str = error.to_string().to_lowercase();
         
         if error_str.contains("timed out") || error_str.contains("timeout") {
@@ -158,7 +464,13 @@ impl RelayEngine {
             .as_millis();
         let session_id = format!("relay_{}_{}", timestamp, client_addr.port());
 
-        let session = Arc::new(RelaySession::new(session_id.clone(), client_addr, target_addr));
+        let session = Arc::new(
+            RelaySession::new(session_id.clone(), client_addr, target_addr)
+                .with_redaction(self.redacted)
+                .with_upstreams(self.upstreams.clone())
+                .with_context(self.context.clone())
+                .with_throughput(self.handle.throughput().clone())
+        );
         
         // Add to active sessions
         {
@@ -167,16 +479,16 @@ impl RelayEngine {
         }
         
         info!("Started relay session {} from {} to {}", 
-              session.session_id, client_addr, target_addr);
+              session.session_id, self.shown(client_addr), self.shown(target_addr));
         
         Ok(session)
     }
 
     /// Start a complete relay session with immediate data transfer
-    pub async fn start_complete_relay_with_user(
+    pub async fn start_complete_relay_with_user<T: RelayTarget>(
         &self,
         client: TcpStream,
-        target: TcpStream,
+        target: T,
         user_id: Option<String>,
     ) -> Result<crate::relay::session::ConnectionStats> {
         let client_addr = client.peer_addr()
@@ -191,7 +503,13 @@ impl RelayEngine {
             .as_millis();
         let session_id = format!("relay_{}_{}", timestamp, client_addr.port());
 
-        let session = Arc::new(RelaySession::new(session_id.clone(), client_addr, target_addr));
+        let session = Arc::new(
+            RelaySession::new(session_id.clone(), client_addr, target_addr)
+                .with_redaction(self.redacted)
+                .with_upstreams(self.upstreams.clone())
+                .with_context(self.context.clone())
+                .with_throughput(self.handle.throughput().clone())
+        );
         
         // Add to active sessions
         {
@@ -200,7 +518,7 @@ impl RelayEngine {
         }
         
         info!("Started complete relay session {} from {} to {}", 
-              session.session_id, client_addr, target_addr);
+              session.session_id, self.shown(client_addr), self.shown(target_addr));
         
         // Start the actual data relay immediately
         self.relay_data_with_user(&session, client, target, user_id).await
@@ -215,19 +533,15 @@ impl RelayEngine {
     }
 
     /// Relay data bidirectionally between client and target
-    pub async fn relay_data(
+    pub async fn relay_data<T: RelayTarget>(
         &self,
         session: &Arc<RelaySession>,
         mut client: TcpStream,
-        mut target: TcpStream,
+        mut target: T,
     ) -> Result<ConnectionStats> {
         info!("Starting bidirectional data relay for session {}", session.session_id);
         
-        // Use tokio's copy_bidirectional for efficient data transfer with timeout
-        let result = timeout(
-            self.connection_timeout,
-            tokio::io::copy_bidirectional(&mut client, &mut target)
-        ).await;
+        let result = self.run_relay(session, &mut client, &mut target, None).await;
         
         // Remove from active sessions when done
         self.remove_session(&session.session_id);
@@ -271,21 +585,17 @@ impl RelayEngine {
     }
 
     /// Relay data with user context for authentication tracking
-    pub async fn relay_data_with_user(
+    pub async fn relay_data_with_user<T: RelayTarget>(
         &self,
         session: &Arc<RelaySession>,
         mut client: TcpStream,
-        mut target: TcpStream,
+        mut target: T,
         user_id: Option<String>,
     ) -> Result<ConnectionStats> {
-        info!("Starting bidirectional data relay for session {} (user: {:?})", 
-              session.session_id, user_id);
+        info!("Starting bidirectional data relay for session {} (user: {})", 
+              session.session_id, self.shown(user_id.as_deref().unwrap_or("none")));
         
-        // Use tokio's copy_bidirectional for efficient data transfer with timeout
-        let result = timeout(
-            self.connection_timeout,
-            tokio::io::copy_bidirectional(&mut client, &mut target)
-        ).await;
+        let result = self.run_relay(session, &mut client, &mut target, user_id.as_deref()).await;
         
         // Remove from active sessions when done
         self.remove_session(&session.session_id);
@@ -317,8 +627,8 @@ impl RelayEngine {
                 Err(anyhow!("Data relay failed: {}", e))
             }
             Err(_) => {
-                error!("Relay session {} timed out after {:?} (user: {:?})", 
-                       session.session_id, session.duration(), user_id);
+                error!("Relay session {} timed out after {:?} (user: {})", 
+                       session.session_id, session.duration(), self.shown(user_id.as_deref().unwrap_or("none")));
                 
                 // Log partial statistics even on timeout
                 session.log_stats(user_id.as_deref());
@@ -328,6 +638,154 @@ impl RelayEngine {
         }
     }
 
+    /// Copy data in both directions under the connection timeout, reporting progress to observers
+    /// and pacing each direction to the bandwidth limit and class
+    async fn run_relay<T: RelayTarget>(
+        &self,
+        session: &Arc<RelaySession>,
+        client: &mut TcpStream,
+        target: &mut T,
+        user_id: Option<&str>,
+    ) -> std::result::Result<std::io::Result<(u64, u64)>, tokio::time::error::Elapsed> {
+        self.handle.start_relaying();
+        let result = if self.observers.is_empty()
+            && self.bandwidth.is_unlimited()
+            && self.bandwidth_classes.is_empty()
+            && self.qos.is_none()
+            && self.max_lifetime.is_none()
+            && self.access_window_end.is_none()
+            && self.transformers.is_empty()
+        {
+            // Plain copy with adaptive buffers
+            self.supervise(session, None, copy_bidirectional(client, target, self.buffers, &self.handle)).await
+        } else {
+            self.run_tracked_relay(session, client, target, user_id).await
+        };
+        
+        if session.outcome() == ConnectionResult::Terminated {
+            // Send both ends a FIN rather than leaving them to find the connection gone
+            let _ = tokio::join!(client.shutdown(), target.shutdown());
+        }
+        result
+    }
+    
+    /// Relay through a [`CountingStream`], reporting progress and pacing each direction
+    async fn run_tracked_relay<T: RelayTarget>(
+        &self,
+        session: &Arc<RelaySession>,
+        client: &mut TcpStream,
+        target: &mut T,
+        user_id: Option<&str>,
+    ) -> std::result::Result<std::io::Result<(u64, u64)>, tokio::time::error::Elapsed> {
+        let tracker = ProgressTracker::new(
+            session.clone(),
+            user_id.map(str::to_string),
+            self.observers.clone(),
+            self.progress,
+            self.bandwidth,
+        );
+        tracker.start();
+        let mut counted = CountingStream::new(client, tracker.clone()).with_traffic_class(self.qos.clone());
+        for class in &self.bandwidth_classes {
+            counted = counted.with_bandwidth_class(Some(class.clone()));
+        }
+        let result = if self.transformers.is_empty() {
+            self.supervise(session, Some(&tracker), copy_bidirectional(&mut counted, target, self.buffers, &self.handle)).await
+        } else {
+            let pipeline = TransformPipeline::new(&self.transformers, session, user_id);
+            let mut transformed = TransformStream::new(counted, pipeline);
+            self.supervise(session, Some(&tracker), copy_bidirectional(&mut transformed, target, self.buffers, &self.handle)).await
+        };
+        
+        session.set_outcome(match &result {
+            Ok(Ok(_)) => ConnectionResult::Completed,
+            Ok(Err(_)) => ConnectionResult::Error,
+            Err(_) => ConnectionResult::Timeout,
+        });
+        
+        // Report whatever is left, even if the relay failed or timed out
+        tracker.flush();
+        tracker.end();
+        result
+    }
+
+    /// Drive a copy under the connection timeout (or lifetime, if one is set) until it
+    /// finishes or the relay is terminated
+    async fn supervise(
+        &self,
+        session: &RelaySession,
+        tracker: Option<&ProgressTracker>,
+        copy: impl std::future::Future<Output = std::io::Result<(u64, u64)>>,
+    ) -> std::result::Result<std::io::Result<(u64, u64)>, tokio::time::error::Elapsed> {
+        let limit = if self.connection_lifetime.is_some() { Duration::MAX } else { self.connection_timeout };
+        timeout(limit, async {
+            let progress = async {
+                match tracker {
+                    Some(tracker) => tracker.run().await,
+                    None => std::future::pending().await,
+                }
+            };
+            let reason = tokio::select! {
+                result = copy => return result,
+                reason = progress => reason,
+                reason = self.lifetime_expired(session) => reason,
+                reason = self.connection_lifetime_reached(session, tracker) => reason,
+                reason = self.access_window_closed(session) => reason,
+                reason = self.handle.cancelled() => reason,
+            };
+            session.set_outcome(ConnectionResult::Terminated);
+            Err(std::io::Error::other(format!("relay terminated: {}", reason)))
+        }).await
+    }
+
+    /// Resolves with a termination reason once the connection reaches its maximum
+    /// lifetime, having warned the tracker's observers shortly before
+    async fn connection_lifetime_reached(&self, session: &RelaySession, tracker: Option<&ProgressTracker>) -> String {
+        let Some(lifetime) = self.connection_lifetime else {
+            return std::future::pending().await;
+        };
+        let remaining = lifetime.saturating_sub(self.handle.age());
+        tokio::time::sleep(remaining.saturating_sub(self.lifetime_warning)).await;
+        let remaining = lifetime.saturating_sub(self.handle.age());
+        if !remaining.is_zero() {
+            warn!("Relay {} will be closed in {:?} for reaching the maximum connection lifetime", session.session_id, remaining);
+            if let Some(tracker) = tracker {
+                tracker.warn_lifetime(remaining);
+            }
+            tokio::time::sleep(remaining).await;
+        }
+        info!("Relay {} reached the maximum connection lifetime of {:?}, closing", session.session_id, lifetime);
+        "connection reached its maximum lifetime".to_string()
+    }
+
+    /// Resolves with a termination reason once the relay outlives its maximum lifetime
+    async fn lifetime_expired(&self, session: &RelaySession) -> String {
+        let Some((remaining, action)) = self.max_lifetime else {
+            return std::future::pending().await;
+        };
+        tokio::time::sleep(remaining).await;
+        match action {
+            SessionExpiryAction::Terminate => {
+                info!("Relay {} reached the maximum session lifetime, closing for re-authentication", session.session_id);
+                "session reached its maximum lifetime".to_string()
+            }
+            SessionExpiryAction::Flag => {
+                warn!("Relay {} outlived the maximum session lifetime and is due for re-authentication", session.session_id);
+                std::future::pending().await
+            }
+        }
+    }
+
+    /// Resolves with a termination reason once the user's access window closes
+    async fn access_window_closed(&self, session: &RelaySession) -> String {
+        let Some(remaining) = self.access_window_end else {
+            return std::future::pending().await;
+        };
+        tokio::time::sleep(remaining).await;
+        info!("Relay {} reached the end of the user's access window", session.session_id);
+        "user's access window closed".to_string()
+    }
+
     /// Start a complete relay session (connect + relay)
     pub async fn start_complete_relay(
         &self,
@@ -350,7 +808,7 @@ impl RelayEngine {
         // For now, we'll need to restructure this to avoid consuming the streams twice
         // This is a design issue that will be addressed in the integration
         info!("Relay session {} established between {} and {}", 
-              session.session_id, client_addr, target_addr_resolved);
+              session.session_id, self.shown(client_addr), self.shown(target_addr_resolved));
         
         Ok(())
     }
@@ -380,4 +838,43 @@ impl RelayEngine {
             .map(|session| session.to_stats(None))
             .collect()
     }
-}
\ No newline at end of file
+}
+
+/// Apply a DSCP codepoint to a socket (the upper six bits of the TOS / traffic class byte)
+fn set_dscp(socket: &TcpSocket, addr: SocketAddr, dscp: u8) -> std::io::Result<()> {
+    let tos = u32::from(dscp) << 2;
+    if addr.is_ipv4() {
+        socket.set_tos_v4(tos)
+    } else {
+        set_tclass_v6(socket, tos)
+    }
+}
+
+#[cfg(any(
+    target_os = "android",
+    target_os = "dragonfly",
+    target_os = "freebsd",
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "netbsd",
+    target_os = "openbsd",
+))]
+fn set_tclass_v6(socket: &TcpSocket, tclass: u32) -> std::io::Result<()> {
+    socket.set_tclass_v6(tclass)
+}
+
+#[cfg(not(any(
+    target_os = "android",
+    target_os = "dragonfly",
+    target_os = "freebsd",
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "netbsd",
+    target_os = "openbsd",
+)))]
+fn set_tclass_v6(_socket: &TcpSocket, _tclass: u32) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "IPv6 traffic class marking is not supported on this platform",
+    ))
+}
@@ -2,8 +2,26 @@
 //! 
 //! Handles bidirectional data relay between client and target.
 
+pub mod buffer;
+pub mod context;
 pub mod engine;
+pub mod progress;
+pub mod qos;
 pub mod session;
+pub mod target;
+pub mod throughput;
+pub mod transform;
+pub mod udp;
+pub mod user_bandwidth;
 
+pub use buffer::BufferSettings;
+pub use context::{ConnectionContext, CONTEXT_VERSION};
 pub use engine::RelayEngine;
-pub use session::{RelaySession, ConnectionStats};
\ No newline at end of file
+pub use progress::{BandwidthClass, BandwidthLimit, ProgressSettings, RelayControl, RelayObserver};
+pub use qos::{QosLane, QosScheduler, TrafficClass};
+pub use session::{RelayHandle, RelaySession, ConnectionStats};
+pub use target::{RelayTarget, TargetStream};
+pub use throughput::{Throughput, ThroughputRates};
+pub use transform::{Direction, RelayTransformer, TransformSession, TransformerRegistry};
+pub use udp::{UdpRelay, UdpRelayStats, UdpRouteContext};
+pub use user_bandwidth::UserBandwidth;
\ No newline at end of file
@@ -0,0 +1,655 @@
+//! Relay Progress Reporting
+//!
+//! Counts bytes as they pass through a relay and reports them to observers
+//! while the transfer is still running, either every `interval` or as soon as
+//! `threshold_bytes` have accumulated since the last report. Observers can
+//! end or throttle the relay from a progress report (e.g. when a quota runs
+//! out). Relays placed in [`BandwidthClass`]es are also paced against each
+//! class's cap, which they share with every other relay in it, and relays in
+//! a QoS traffic class against that class's share of the link.
+
+use std::future::Future;
+use std::io::{self, IoSlice};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::Notify;
+use serde::{Deserialize, Serialize};
+use tokio::time::{Instant, Sleep};
+
+use super::qos::QosLane;
+use tracing::info;
+
+use super::RelaySession;
+
+/// What a relay should do after a progress report
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RelayControl {
+    Continue,
+    /// Limit each direction to this many bytes per second for the rest of the relay
+    Throttle(u64),
+    Terminate(String),
+}
+
+/// Receives lifecycle and byte-count updates from relay sessions
+pub trait RelayObserver: Send + Sync {
+    /// Called once when the relay starts
+    fn on_start(&self, _session: &RelaySession, _user_id: Option<&str>) {}
+
+    /// Called with the bytes transferred since the previous report
+    fn on_progress(
+        &self,
+        session: &RelaySession,
+        user_id: Option<&str>,
+        bytes_up: u64,
+        bytes_down: u64,
+    ) -> RelayControl;
+
+    /// Called once when the connection is `remaining` away from its maximum lifetime
+    fn on_lifetime_warning(&self, _session: &RelaySession, _remaining: Duration) {}
+
+    /// Called once after the final progress report
+    fn on_end(&self, _session: &RelaySession) {}
+}
+
+impl RelayObserver for crate::metrics::Metrics {
+    fn on_start(&self, session: &RelaySession, user_id: Option<&str>) {
+        if session.redacted {
+            self.start_redacted_connection();
+            return;
+        }
+        let _ = self.start_connection(
+            session.session_id.clone(),
+            session.client_addr,
+            session.target_addr,
+            user_id.map(str::to_string),
+        );
+        self.track_throughput(&session.session_id, session.throughput.clone());
+    }
+
+    fn on_progress(&self, session: &RelaySession, _user_id: Option<&str>, bytes_up: u64, bytes_down: u64) -> RelayControl {
+        if session.redacted {
+            self.add_redacted_bytes(bytes_up, bytes_down);
+        } else {
+            let _ = self.update_connection_bytes(&session.session_id, bytes_up, bytes_down);
+        }
+        RelayControl::Continue
+    }
+
+    fn on_lifetime_warning(&self, _session: &RelaySession, _remaining: Duration) {
+        self.record_lifetime_warning();
+    }
+
+    fn on_end(&self, session: &RelaySession) {
+        if session.redacted {
+            self.end_redacted_connection(session.duration());
+        } else {
+            let _ = self.end_connection_with_result(&session.session_id, session.outcome());
+        }
+    }
+}
+
+/// Per-direction byte rate caps for a relay
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct BandwidthLimit {
+    /// Client-to-target bytes per second
+    #[serde(default)]
+    pub upload_bytes_per_second: Option<u64>,
+    /// Target-to-client bytes per second
+    #[serde(default)]
+    pub download_bytes_per_second: Option<u64>,
+}
+
+impl BandwidthLimit {
+    pub fn is_unlimited(&self) -> bool {
+        self.upload_bytes_per_second.is_none() && self.download_bytes_per_second.is_none()
+    }
+
+    /// Combine two limits, keeping the tighter cap in each direction
+    pub fn tighter(self, other: BandwidthLimit) -> BandwidthLimit {
+        let min = |a: Option<u64>, b: Option<u64>| match (a, b) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        BandwidthLimit {
+            upload_bytes_per_second: min(self.upload_bytes_per_second, other.upload_bytes_per_second),
+            download_bytes_per_second: min(self.download_bytes_per_second, other.download_bytes_per_second),
+        }
+    }
+}
+
+/// Per-direction rate caps shared by every relay placed in a named class
+///
+/// Each direction keeps a single transfer schedule for all of the class's
+/// relays, so together they stay under the cap however many there are.
+#[derive(Debug)]
+pub struct BandwidthClass {
+    name: String,
+    limit: BandwidthLimit,
+    next_up: Mutex<Option<Instant>>,
+    next_down: Mutex<Option<Instant>>,
+}
+
+impl BandwidthClass {
+    pub fn new(name: impl Into<String>, limit: BandwidthLimit) -> Self {
+        Self {
+            name: name.into(),
+            limit,
+            next_up: Mutex::new(None),
+            next_down: Mutex::new(None),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn limit(&self) -> BandwidthLimit {
+        self.limit
+    }
+
+    /// Charge client-to-target bytes to the class, returning when it may send again
+    fn charge_up(&self, bytes: u64) -> Option<Instant> {
+        let rate = self.limit.upload_bytes_per_second?;
+        Some(schedule(&mut self.next_up.lock().unwrap(), bytes, rate))
+    }
+
+    /// Charge target-to-client bytes to the class, returning when it may send again
+    fn charge_down(&self, bytes: u64) -> Option<Instant> {
+        let rate = self.limit.download_bytes_per_second?;
+        Some(schedule(&mut self.next_down.lock().unwrap(), bytes, rate))
+    }
+}
+
+/// Book `bytes` at `rate` after the transfers already booked on `next`,
+/// returning when they are paid off
+pub(super) fn schedule(next: &mut Option<Instant>, bytes: u64, rate: u64) -> Instant {
+    let now = Instant::now();
+    let start = next.filter(|next| *next > now).unwrap_or(now);
+    let end = start + Duration::from_secs_f64(bytes as f64 / rate.max(1) as f64);
+    *next = Some(end);
+    end
+}
+
+/// How often in-progress byte counts are reported
+#[derive(Debug, Clone, Copy)]
+pub struct ProgressSettings {
+    pub interval: Duration,
+    /// Report early once this many unreported bytes accumulate (0 disables)
+    pub threshold_bytes: u64,
+}
+
+impl Default for ProgressSettings {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(5),
+            threshold_bytes: 1024 * 1024,
+        }
+    }
+}
+
+/// Tracks what has been reported for one session
+pub(crate) struct ProgressTracker {
+    session: Arc<RelaySession>,
+    user_id: Option<String>,
+    observers: Vec<Arc<dyn RelayObserver>>,
+    settings: ProgressSettings,
+    reported_up: AtomicU64,
+    reported_down: AtomicU64,
+    threshold_reached: Notify,
+    /// Client-to-target bytes per second, 0 when unthrottled
+    throttle_up: AtomicU64,
+    /// Target-to-client bytes per second, 0 when unthrottled
+    throttle_down: AtomicU64,
+}
+
+impl ProgressTracker {
+    pub(crate) fn new(
+        session: Arc<RelaySession>,
+        user_id: Option<String>,
+        observers: Vec<Arc<dyn RelayObserver>>,
+        settings: ProgressSettings,
+        limit: BandwidthLimit,
+    ) -> Arc<Self> {
+        Arc::new(Self {
+            session,
+            user_id,
+            observers,
+            settings,
+            reported_up: AtomicU64::new(0),
+            reported_down: AtomicU64::new(0),
+            threshold_reached: Notify::new(),
+            throttle_up: AtomicU64::new(limit.upload_bytes_per_second.unwrap_or(0)),
+            throttle_down: AtomicU64::new(limit.download_bytes_per_second.unwrap_or(0)),
+        })
+    }
+
+    /// Notify observers that the relay started
+    pub(crate) fn start(&self) {
+        for observer in &self.observers {
+            observer.on_start(&self.session, self.user_id.as_deref());
+        }
+    }
+
+    /// Warn observers that the connection is `remaining` away from its maximum lifetime
+    pub(crate) fn warn_lifetime(&self, remaining: Duration) {
+        for observer in &self.observers {
+            observer.on_lifetime_warning(&self.session, remaining);
+        }
+    }
+
+    /// Notify observers that the relay ended
+    pub(crate) fn end(&self) {
+        for observer in &self.observers {
+            observer.on_end(&self.session);
+        }
+    }
+
+    /// Report any bytes not yet passed to the observers
+    ///
+    /// Returns the reason if any observer asked for the relay to end.
+    pub(crate) fn flush(&self) -> Option<String> {
+        let up = self.session.bytes_up();
+        let down = self.session.bytes_down();
+        let delta_up = up.saturating_sub(self.reported_up.swap(up, Ordering::Relaxed));
+        let delta_down = down.saturating_sub(self.reported_down.swap(down, Ordering::Relaxed));
+        if delta_up == 0 && delta_down == 0 {
+            return None;
+        }
+
+        let mut terminate = None;
+        for observer in &self.observers {
+            match observer.on_progress(&self.session, self.user_id.as_deref(), delta_up, delta_down) {
+                RelayControl::Continue => {}
+                RelayControl::Throttle(rate) => self.throttle_to(rate),
+                RelayControl::Terminate(reason) => {
+                    terminate.get_or_insert(reason);
+                }
+            }
+        }
+        terminate
+    }
+
+    /// Throttle both directions, keeping any tighter existing cap
+    fn throttle_to(&self, rate: u64) {
+        let rate = rate.max(1);
+        let tighten = |current: u64| (current == 0 || rate < current).then_some(rate);
+        let up = self.throttle_up.fetch_update(Ordering::Relaxed, Ordering::Relaxed, tighten);
+        let down = self.throttle_down.fetch_update(Ordering::Relaxed, Ordering::Relaxed, tighten);
+        if up.is_ok() || down.is_ok() {
+            info!("Relay {} throttled to {} bytes/s", self.session.session_id, rate);
+        }
+    }
+
+    /// Report periodically and on threshold until an observer ends the relay
+    pub(crate) async fn run(&self) -> String {
+        let mut interval = tokio::time::interval(self.settings.interval);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        interval.tick().await;
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = self.threshold_reached.notified() => {}
+            }
+            if let Some(reason) = self.flush() {
+                return reason;
+            }
+        }
+    }
+
+    fn record(&self) {
+        let threshold = self.settings.threshold_bytes;
+        if threshold == 0 {
+            return;
+        }
+        let unreported = self.session.total_bytes().saturating_sub(
+            self.reported_up.load(Ordering::Relaxed) + self.reported_down.load(Ordering::Relaxed),
+        );
+        if unreported >= threshold {
+            self.threshold_reached.notify_one();
+        }
+    }
+}
+
+/// Spaces out transfers in one direction to stay under a byte rate
+#[derive(Default)]
+struct Pacer {
+    next: Option<Instant>,
+    delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl Pacer {
+    /// Wait out the delay owed for earlier transfers
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        if let Some(delay) = self.delay.as_mut() {
+            ready!(delay.as_mut().poll(cx));
+            self.delay = None;
+        }
+        Poll::Ready(())
+    }
+
+    /// Charge `bytes` against `rate`, scheduling the next transfer
+    fn consume(&mut self, bytes: u64, rate: u64) {
+        if rate == 0 {
+            return;
+        }
+        let next = schedule(&mut self.next, bytes, rate);
+        self.delay = Some(Box::pin(tokio::time::sleep_until(next)));
+    }
+
+    /// Hold the next transfer until at least `until`, e.g. for a shared class
+    fn wait_until(&mut self, until: Instant) {
+        if self.next.is_some_and(|next| next >= until) {
+            return;
+        }
+        self.next = Some(until);
+        self.delay = Some(Box::pin(tokio::time::sleep_until(until)));
+    }
+}
+
+/// Wraps the client side of a relay, counting bytes in both directions
+///
+/// Reads from the client are upstream traffic; writes to it are downstream.
+/// Once an observer throttles the relay, each direction is paced separately.
+/// In bandwidth classes, each direction also waits for every class's schedule,
+/// and in a traffic class for its share of the link.
+pub(crate) struct CountingStream<S> {
+    inner: S,
+    tracker: Arc<ProgressTracker>,
+    classes: Vec<Arc<BandwidthClass>>,
+    qos: Option<QosLane>,
+    read_pacer: Pacer,
+    write_pacer: Pacer,
+}
+
+impl<S> CountingStream<S> {
+    pub(crate) fn new(inner: S, tracker: Arc<ProgressTracker>) -> Self {
+        Self {
+            inner,
+            tracker,
+            classes: Vec::new(),
+            qos: None,
+            read_pacer: Pacer::default(),
+            write_pacer: Pacer::default(),
+        }
+    }
+
+    /// Share the class's rate caps with its other relays, on top of any
+    /// classes the stream is already in
+    pub(crate) fn with_bandwidth_class(mut self, class: Option<Arc<BandwidthClass>>) -> Self {
+        self.classes.extend(class);
+        self
+    }
+
+    /// Pace the stream to its traffic class's share of the link
+    pub(crate) fn with_traffic_class(mut self, lane: Option<QosLane>) -> Self {
+        self.qos = lane;
+        self
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for CountingStream<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.read_pacer.poll_ready(cx));
+        let before = buf.filled().len();
+        let result = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = result {
+            let n = (buf.filled().len() - before) as u64;
+            if n > 0 {
+                this.tracker.session.add_bytes_up(n);
+                this.tracker.record();
+                this.read_pacer.consume(n, this.tracker.throttle_up.load(Ordering::Relaxed));
+                let qos = this.qos.as_ref().and_then(|lane| lane.charge_up(n));
+                if let Some(until) = this.classes.iter().filter_map(|class| class.charge_up(n)).chain(qos).max() {
+                    this.read_pacer.wait_until(until);
+                }
+            }
+        }
+        result
+    }
+}
+
+impl<S> CountingStream<S> {
+    /// Slowest rate paced writes must keep to, if any
+    fn download_pace(&self) -> Option<u64> {
+        let rate = self.tracker.throttle_down.load(Ordering::Relaxed);
+        let class_rates = self.classes.iter().filter_map(|class| class.limit.download_bytes_per_second)
+            .chain(self.qos.as_ref().and_then(QosLane::download_capacity));
+        class_rates.chain([rate]).filter(|rate| *rate > 0).min()
+    }
+
+    /// Count `n` bytes written to the client and schedule the next write
+    fn wrote(&mut self, n: usize) {
+        if n == 0 {
+            return;
+        }
+        let rate = self.tracker.throttle_down.load(Ordering::Relaxed);
+        self.tracker.session.add_bytes_down(n as u64);
+        self.tracker.record();
+        self.write_pacer.consume(n as u64, rate);
+        let qos = self.qos.as_ref().and_then(|lane| lane.charge_down(n as u64));
+        if let Some(until) = self.classes.iter().filter_map(|class| class.charge_down(n as u64)).chain(qos).max() {
+            self.write_pacer.wait_until(until);
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for CountingStream<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.write_pacer.poll_ready(cx));
+        // Keep each paced write to at most a second's worth of bytes
+        let buf = match this.download_pace() {
+            None => buf,
+            Some(rate) => &buf[..buf.len().min(rate as usize)],
+        };
+        let result = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = result {
+            this.wrote(n);
+        }
+        result
+    }
+
+    fn poll_write_vectored(self: Pin<&mut Self>, cx: &mut Context<'_>, bufs: &[IoSlice<'_>]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        // Paced writes are cut to size one buffer at a time
+        if this.download_pace().is_some() {
+            let buf = bufs.iter().find(|buf| !buf.is_empty()).map_or(&[][..], |buf| &**buf);
+            return Pin::new(this).poll_write(cx, buf);
+        }
+        ready!(this.write_pacer.poll_ready(cx));
+        let result = Pin::new(&mut this.inner).poll_write_vectored(cx, bufs);
+        if let Poll::Ready(Ok(n)) = result {
+            this.wrote(n);
+        }
+        result
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[derive(Default)]
+    struct RecordingObserver {
+        reports: Mutex<Vec<(u64, u64)>>,
+    }
+
+    impl RelayObserver for RecordingObserver {
+        fn on_progress(&self, _session: &RelaySession, _user_id: Option<&str>, bytes_up: u64, bytes_down: u64) -> RelayControl {
+            self.reports.lock().unwrap().push((bytes_up, bytes_down));
+            RelayControl::Continue
+        }
+    }
+
+    fn test_session() -> Arc<RelaySession> {
+        let addr = "127.0.0.1:1080".parse().unwrap();
+        Arc::new(RelaySession::new("test".to_string(), addr, addr))
+    }
+
+    #[test]
+    fn test_redacted_session_only_counts_aggregates() {
+        let metrics = crate::metrics::Metrics::new();
+        let addr = "127.0.0.1:1080".parse().unwrap();
+        let session = RelaySession::new("private".to_string(), addr, addr).with_redaction(true);
+
+        metrics.on_start(&session, Some("alice"));
+        assert_eq!(metrics.get_active_connections(), 0);
+        metrics.on_progress(&session, Some("alice"), 100, 400);
+        metrics.on_end(&session);
+
+        assert_eq!(metrics.get_total_connections(), 1);
+        assert_eq!(metrics.get_bytes_transferred(), 500);
+        assert!(metrics.get_top_destinations(10).is_empty());
+        assert!(metrics.get_top_users(10).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_counting_stream_reports_deltas() {
+        let observer = Arc::new(RecordingObserver::default());
+        let session = test_session();
+        let tracker = ProgressTracker::new(session.clone(), None, vec![observer.clone()], ProgressSettings::default(), BandwidthLimit::default());
+
+        let (client, mut remote) = tokio::io::duplex(1024);
+        let mut counted = CountingStream::new(client, tracker.clone());
+
+        remote.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 5];
+        counted.read_exact(&mut buf).await.unwrap();
+        counted.write_all(b"response").await.unwrap();
+
+        tracker.flush();
+        tracker.flush(); // Nothing new, no report
+
+        assert_eq!(session.bytes_up(), 5);
+        assert_eq!(session.bytes_down(), 8);
+        assert_eq!(*observer.reports.lock().unwrap(), vec![(5, 8)]);
+    }
+
+    #[tokio::test]
+    async fn test_threshold_triggers_early_report() {
+        let observer = Arc::new(RecordingObserver::default());
+        let settings = ProgressSettings { interval: Duration::from_secs(3600), threshold_bytes: 4 };
+        let tracker = ProgressTracker::new(test_session(), None, vec![observer.clone()], settings, BandwidthLimit::default());
+        let reporter = {
+            let tracker = tracker.clone();
+            tokio::spawn(async move { tracker.run().await })
+        };
+
+        let (client, _remote) = tokio::io::duplex(1024);
+        let mut counted = CountingStream::new(client, tracker.clone());
+        counted.write_all(b"12345").await.unwrap();
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(*observer.reports.lock().unwrap(), vec![(0, 5)]);
+        reporter.abort();
+    }
+
+    struct ThrottlingObserver(u64);
+
+    impl RelayObserver for ThrottlingObserver {
+        fn on_progress(&self, _session: &RelaySession, _user_id: Option<&str>, _bytes_up: u64, _bytes_down: u64) -> RelayControl {
+            RelayControl::Throttle(self.0)
+        }
+    }
+
+    #[test]
+    fn test_bandwidth_limit_tighter() {
+        let rule = BandwidthLimit { upload_bytes_per_second: Some(100), download_bytes_per_second: None };
+        let user = BandwidthLimit { upload_bytes_per_second: Some(50), download_bytes_per_second: Some(1000) };
+
+        let combined = rule.tighter(user);
+        assert_eq!(combined.upload_bytes_per_second, Some(50));
+        assert_eq!(combined.download_bytes_per_second, Some(1000));
+        assert!(BandwidthLimit::default().is_unlimited());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_throttle_paces_writes() {
+        let tracker = ProgressTracker::new(
+            test_session(),
+            None,
+            vec![Arc::new(ThrottlingObserver(1000)), Arc::new(ThrottlingObserver(100))],
+            ProgressSettings::default(),
+            BandwidthLimit { download_bytes_per_second: Some(200), ..Default::default() },
+        );
+        let (client, mut remote) = tokio::io::duplex(4096);
+        let mut counted = CountingStream::new(client, tracker.clone());
+
+        counted.write_all(b"x").await.unwrap();
+        assert_eq!(tracker.flush(), None);
+        assert_eq!(tracker.throttle_up.load(Ordering::Relaxed), 100);
+        assert_eq!(tracker.throttle_down.load(Ordering::Relaxed), 100);
+
+        // 300 bytes at 100 bytes/s needs about three seconds
+        let start = Instant::now();
+        counted.write_all(&[0u8; 300]).await.unwrap();
+        counted.write_all(b"y").await.unwrap();
+        assert!(start.elapsed() >= Duration::from_secs(3));
+
+        let mut buf = vec![0u8; 302];
+        remote.read_exact(&mut buf).await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_bandwidth_class_is_shared_between_relays() {
+        let limit = BandwidthLimit { download_bytes_per_second: Some(100), ..Default::default() };
+        let class = Arc::new(BandwidthClass::new("bulk", limit));
+        let start = Instant::now();
+        let relays: Vec<_> = (0..2).map(|_| {
+            let tracker = ProgressTracker::new(test_session(), None, Vec::new(), ProgressSettings::default(), BandwidthLimit::default());
+            let (client, remote) = tokio::io::duplex(4096);
+            let mut counted = CountingStream::new(client, tracker).with_bandwidth_class(Some(class.clone()));
+            tokio::spawn(async move {
+                counted.write_all(&[0u8; 150]).await.unwrap();
+                counted.write_all(b"y").await.unwrap();
+                remote
+            })
+        }).collect();
+        for relay in relays {
+            relay.await.unwrap();
+        }
+
+        // Alone each would need 1.5 seconds; sharing 100 bytes/s, together they need 3
+        assert!(start.elapsed() >= Duration::from_secs(3));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_relay_is_paced_by_its_tightest_class() {
+        let class = |rate| Arc::new(BandwidthClass::new("class", BandwidthLimit { upload_bytes_per_second: Some(rate), ..Default::default() }));
+        let (loose, tight) = (class(1000), class(100));
+        let tracker = ProgressTracker::new(test_session(), None, Vec::new(), ProgressSettings::default(), BandwidthLimit::default());
+        let (client, mut remote) = tokio::io::duplex(4096);
+        let mut counted = CountingStream::new(client, tracker)
+            .with_bandwidth_class(Some(loose))
+            .with_bandwidth_class(Some(tight));
+        let start = Instant::now();
+
+        remote.write_all(&[0u8; 200]).await.unwrap();
+        let mut buf = [0u8; 200];
+        counted.read_exact(&mut buf).await.unwrap();
+        remote.write_all(b"y").await.unwrap();
+        counted.read_exact(&mut buf[..1]).await.unwrap();
+
+        // The looser class alone would allow the next read after 0.2 seconds
+        assert!(start.elapsed() >= Duration::from_secs(2));
+    }
+}
@@ -0,0 +1,216 @@
+//! QoS Traffic Classes
+//!
+//! Relays are placed in one of three traffic classes by the routing rule
+//! that allowed them or by their user's groups. When the proxy's link
+//! capacity is configured, every class shares one transfer schedule per
+//! direction, paced at whatever capacity the higher classes have not been
+//! using lately. Interactive traffic therefore always gets the full link,
+//! default traffic what interactive leaves, and bulk traffic the rest; a
+//! lower class never drops below `min_share` of the capacity, so it slows
+//! down under contention but does not stall. Classes can also mark outbound
+//! sockets with a DSCP value for the network to prioritize them further.
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+use super::progress::{schedule, BandwidthLimit};
+
+/// How far back a class's recent throughput is measured
+const WINDOW: Duration = Duration::from_millis(250);
+
+/// Priority class of a relay, highest first
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TrafficClass {
+    /// Latency-sensitive traffic such as remote shells, calls and games
+    Interactive,
+    #[default]
+    Default,
+    /// Transfers that can wait, such as backups and downloads
+    Bulk,
+}
+
+impl TrafficClass {
+    pub const ALL: [TrafficClass; 3] = [TrafficClass::Interactive, TrafficClass::Default, TrafficClass::Bulk];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TrafficClass::Interactive => "interactive",
+            TrafficClass::Default => "default",
+            TrafficClass::Bulk => "bulk",
+        }
+    }
+
+    /// DSCP value outbound sockets of the class are marked with, if any:
+    /// Expedited Forwarding for interactive and CS1 (lower effort) for bulk
+    pub fn dscp(&self) -> Option<u8> {
+        match self {
+            TrafficClass::Interactive => Some(46),
+            TrafficClass::Default => None,
+            TrafficClass::Bulk => Some(8),
+        }
+    }
+
+    fn index(&self) -> usize {
+        *self as usize
+    }
+}
+
+impl fmt::Display for TrafficClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Transfer schedule and recent throughput of one class in one direction
+#[derive(Debug, Default)]
+struct Lane {
+    next: Option<Instant>,
+    window_start: Option<Instant>,
+    window_bytes: u64,
+    /// Bytes per second over the last complete window
+    rate: f64,
+}
+
+impl Lane {
+    /// Bytes per second the class has been sending lately
+    fn rate(&self, now: Instant) -> f64 {
+        match self.window_start {
+            // Nothing was sent for a whole window
+            Some(start) if now - start >= 2 * WINDOW => 0.0,
+            Some(start) if now - start >= WINDOW => self.window_bytes as f64 / (now - start).as_secs_f64(),
+            Some(_) => self.rate,
+            None => 0.0,
+        }
+    }
+
+    fn record(&mut self, now: Instant, bytes: u64) {
+        if self.window_start.is_none_or(|start| now - start >= WINDOW) {
+            self.rate = self.rate(now);
+            self.window_start = Some(now);
+            self.window_bytes = 0;
+        }
+        self.window_bytes += bytes;
+    }
+}
+
+/// One direction of the link the classes compete for
+#[derive(Debug)]
+struct Link {
+    capacity: Option<u64>,
+    lanes: Mutex<[Lane; 3]>,
+}
+
+impl Link {
+    fn new(capacity: Option<u64>) -> Self {
+        Self { capacity, lanes: Mutex::new(Default::default()) }
+    }
+
+    /// Charge `bytes` to `class`, returning when the class may send again
+    fn charge(&self, class: TrafficClass, bytes: u64, min_share: f64) -> Option<Instant> {
+        let capacity = self.capacity? as f64;
+        let now = Instant::now();
+        let mut lanes = self.lanes.lock().unwrap();
+        let higher: f64 = lanes[..class.index()].iter().map(|lane| lane.rate(now)).sum();
+        let rate = (capacity - higher).max(capacity * min_share);
+        let lane = &mut lanes[class.index()];
+        lane.record(now, bytes);
+        Some(schedule(&mut lane.next, bytes, rate as u64))
+    }
+}
+
+/// Shares the proxy's link capacity between traffic classes by priority
+#[derive(Debug)]
+pub struct QosScheduler {
+    up: Link,
+    down: Link,
+    min_share: f64,
+}
+
+impl QosScheduler {
+    /// Schedule classes within `capacity`, keeping `min_share` of it for each
+    ///
+    /// A direction without a capacity is not scheduled.
+    pub fn new(capacity: BandwidthLimit, min_share: f64) -> Self {
+        Self {
+            up: Link::new(capacity.upload_bytes_per_second),
+            down: Link::new(capacity.download_bytes_per_second),
+            min_share: min_share.clamp(0.0, 1.0),
+        }
+    }
+
+    /// Handle for relays in `class` to charge their transfers to
+    pub fn lane(self: &Arc<Self>, class: TrafficClass) -> QosLane {
+        QosLane { scheduler: Arc::clone(self), class }
+    }
+}
+
+/// A traffic class's share of a [`QosScheduler`]
+#[derive(Debug, Clone)]
+pub struct QosLane {
+    scheduler: Arc<QosScheduler>,
+    class: TrafficClass,
+}
+
+impl QosLane {
+    pub fn class(&self) -> TrafficClass {
+        self.class
+    }
+
+    /// Charge client-to-target bytes, returning when the class may send again
+    pub(crate) fn charge_up(&self, bytes: u64) -> Option<Instant> {
+        self.scheduler.up.charge(self.class, bytes, self.scheduler.min_share)
+    }
+
+    /// Charge target-to-client bytes, returning when the class may send again
+    pub(crate) fn charge_down(&self, bytes: u64) -> Option<Instant> {
+        self.scheduler.down.charge(self.class, bytes, self.scheduler.min_share)
+    }
+
+    /// Download capacity of the link, if scheduled
+    pub(crate) fn download_capacity(&self) -> Option<u64> {
+        self.scheduler.down.capacity
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_lower_classes_get_what_higher_ones_leave() {
+        let capacity = BandwidthLimit { download_bytes_per_second: Some(1000), ..Default::default() };
+        let scheduler = Arc::new(QosScheduler::new(capacity, 0.1));
+        let (interactive, bulk) = (scheduler.lane(TrafficClass::Interactive), scheduler.lane(TrafficClass::Bulk));
+        assert!(bulk.charge_up(100).is_none());
+
+        // Alone, bulk has the whole link
+        let start = Instant::now();
+        assert_eq!(bulk.charge_down(500), Some(start + Duration::from_millis(500)));
+        tokio::time::advance(Duration::from_secs(1)).await;
+
+        // With interactive traffic using all of it, bulk keeps its minimum share
+        for _ in 0..4 {
+            assert!(interactive.charge_down(250).is_some());
+            tokio::time::advance(WINDOW).await;
+        }
+        let now = Instant::now();
+        assert_eq!(bulk.charge_down(100), Some(now + Duration::from_secs(1)));
+
+        // Once interactive goes quiet, bulk gets the link back
+        tokio::time::advance(Duration::from_secs(2)).await;
+        let now = Instant::now();
+        assert_eq!(bulk.charge_down(100), Some(now + Duration::from_millis(100)));
+    }
+
+    #[test]
+    fn test_classes_order_by_priority() {
+        assert!(TrafficClass::Interactive < TrafficClass::Default);
+        assert_eq!(TrafficClass::ALL.iter().min(), Some(&TrafficClass::Interactive));
+        assert_eq!(serde_json::to_string(&TrafficClass::Bulk).unwrap(), r#""bulk""#);
+        assert_eq!(TrafficClass::Bulk.dscp(), Some(8));
+    }
+}
@@ -1,11 +1,16 @@
 //! Relay Session
 
 use std::net::SocketAddr;
-use std::sync::atomic::{AtomicU64, Ordering};
-use std::time::Instant;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
 use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
 use tracing::{info, debug};
 
+use super::{ConnectionContext, Direction, Throughput};
+use crate::metrics::ConnectionResult;
+
 /// Represents an active relay session
 #[derive(Debug)]
 pub struct RelaySession {
@@ -15,6 +20,122 @@ pub struct RelaySession {
     pub start_time: Instant,
     pub bytes_up: AtomicU64,
     pub bytes_down: AtomicU64,
+    /// Keep addresses and user out of logs and per-connection metrics
+    pub redacted: bool,
+    /// Upstream proxies carrying the session in dialing order (empty if it goes direct)
+    pub upstreams: Vec<SocketAddr>,
+    /// Stable description of the connection for extensions
+    pub context: Option<Arc<ConnectionContext>>,
+    /// Live transfer rates, shared with the relay's handle
+    pub throughput: Throughput,
+    /// How the relay ended, once it has
+    outcome: OnceLock<ConnectionResult>,
+}
+
+/// Shared between a connection's relay and whoever supervises it: records
+/// when data last moved and lets the relay be terminated from outside
+#[derive(Debug, Clone, Default)]
+pub struct RelayHandle {
+    inner: Arc<HandleState>,
+}
+
+#[derive(Debug)]
+struct HandleState {
+    created: tokio::time::Instant,
+    /// Milliseconds after `created` that data last moved
+    last_activity_ms: AtomicU64,
+    relaying: AtomicBool,
+    throughput: Throughput,
+    reason: OnceLock<String>,
+    cancelled: Notify,
+}
+
+impl Default for HandleState {
+    fn default() -> Self {
+        Self {
+            created: tokio::time::Instant::now(),
+            last_activity_ms: AtomicU64::new(0),
+            relaying: AtomicBool::new(false),
+            throughput: Throughput::new(),
+            reason: OnceLock::new(),
+            cancelled: Notify::new(),
+        }
+    }
+}
+
+impl RelayHandle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record that data moved just now
+    pub fn touch(&self) {
+        self.touch_at(tokio::time::Instant::now());
+    }
+
+    /// Record that `bytes` were just read in `direction`, reading the clock once for both
+    pub fn record(&self, direction: Direction, bytes: u64) {
+        let now = tokio::time::Instant::now();
+        self.touch_at(now);
+        self.inner.throughput.record_at(direction, bytes, now);
+    }
+
+    fn touch_at(&self, now: tokio::time::Instant) {
+        let elapsed = now.saturating_duration_since(self.inner.created).as_millis() as u64;
+        self.inner.last_activity_ms.fetch_max(elapsed, Ordering::Relaxed);
+    }
+
+    /// Time since the handle was created, which is when the connection was accepted
+    pub fn age(&self) -> Duration {
+        self.inner.created.elapsed()
+    }
+
+    /// Record that the handshake is over and data is being relayed
+    pub fn start_relaying(&self) {
+        self.inner.relaying.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_relaying(&self) -> bool {
+        self.inner.relaying.load(Ordering::Relaxed)
+    }
+
+    /// Rolling transfer rates of the relay
+    pub fn throughput(&self) -> &Throughput {
+        &self.inner.throughput
+    }
+
+    /// Time since data last moved, or since the handle was created
+    pub fn idle_for(&self) -> Duration {
+        let last = Duration::from_millis(self.inner.last_activity_ms.load(Ordering::Relaxed));
+        self.inner.created.elapsed().saturating_sub(last)
+    }
+
+    /// Ask the relay to close; returns `false` if it was already asked to
+    pub fn cancel(&self, reason: impl Into<String>) -> bool {
+        let cancelled = self.inner.reason.set(reason.into()).is_ok();
+        if cancelled {
+            self.inner.cancelled.notify_waiters();
+        }
+        cancelled
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.reason.get().is_some()
+    }
+
+    /// Resolves with the reason once the relay is cancelled, even if that
+    /// happened before it started
+    pub async fn cancelled(&self) -> String {
+        loop {
+            let notified = self.inner.cancelled.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            if let Some(reason) = self.inner.reason.get() {
+                return reason.clone();
+            }
+            notified.await;
+        }
+    }
 }
 
 /// Connection statistics for completed sessions
@@ -44,9 +165,53 @@ impl RelaySession {
             start_time: Instant::now(),
             bytes_up: AtomicU64::new(0),
             bytes_down: AtomicU64::new(0),
+            redacted: false,
+            upstreams: Vec::new(),
+            context: None,
+            throughput: Throughput::new(),
+            outcome: OnceLock::new(),
         }
     }
 
+    /// Keep this session's addresses and user out of logs and per-connection metrics
+    pub fn with_redaction(mut self, redacted: bool) -> Self {
+        self.redacted = redacted;
+        self
+    }
+
+    /// Record the upstream proxies carrying this session
+    pub fn with_upstreams(mut self, upstreams: Vec<SocketAddr>) -> Self {
+        self.upstreams = upstreams;
+        self
+    }
+
+    /// Report transfer rates from `throughput`, usually the relay handle's
+    pub fn with_throughput(mut self, throughput: Throughput) -> Self {
+        self.throughput = throughput;
+        self
+    }
+
+    /// Attach the connection's context for observers, transformers and logs
+    pub fn with_context(mut self, context: Option<Arc<ConnectionContext>>) -> Self {
+        self.context = context;
+        self
+    }
+
+    /// The connection's context, when the session was started by the connection manager
+    pub fn context(&self) -> Option<&ConnectionContext> {
+        self.context.as_deref()
+    }
+
+    /// Record how the relay ended; only the first call counts
+    pub fn set_outcome(&self, outcome: ConnectionResult) {
+        let _ = self.outcome.set(outcome);
+    }
+
+    /// How the relay ended, `Completed` until told otherwise
+    pub fn outcome(&self) -> ConnectionResult {
+        self.outcome.get().copied().unwrap_or_default()
+    }
+
     /// Get bytes transferred upstream (client to target)
     pub fn bytes_up(&self) -> u64 {
         self.bytes_up.load(Ordering::Relaxed)
@@ -111,6 +276,18 @@ impl RelaySession {
         let bytes_up = self.bytes_up();
         let bytes_down = self.bytes_down();
         let total_bytes = self.total_bytes();
+        let context = self.context.as_ref().map(|context| context.to_log_value().to_string());
+        
+        if self.redacted {
+            info!(
+                session_id = %self.session_id,
+                duration_ms = duration.as_millis(),
+                total_bytes = total_bytes,
+                context = context,
+                "Relay session completed"
+            );
+            return;
+        }
         
         info!(
             session_id = %self.session_id,
@@ -121,6 +298,7 @@ impl RelaySession {
             bytes_down = bytes_down,
             total_bytes = total_bytes,
             user_id = user_id,
+            context = context,
             "Relay session completed"
         );
         
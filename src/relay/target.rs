@@ -0,0 +1,102 @@
+//! Relay Targets
+//!
+//! The far end of a relay is usually a TCP connection, to the target or an
+//! upstream proxy, but can also be a stream over a tunnel to another node.
+
+use std::io::{self, IoSlice};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+
+use crate::tunnel::TunnelStream;
+
+/// A stream a relay can carry a client's connection to
+pub trait RelayTarget: AsyncRead + AsyncWrite + Unpin + Send {
+    /// Address the stream is connected to
+    fn peer_addr(&self) -> io::Result<SocketAddr>;
+}
+
+impl RelayTarget for TcpStream {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        TcpStream::peer_addr(self)
+    }
+}
+
+impl RelayTarget for TunnelStream {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(TunnelStream::peer_addr(self))
+    }
+}
+
+/// Connection to a target, made directly or through upstreams
+pub enum TargetStream {
+    Tcp(TcpStream),
+    Tunnel(TunnelStream),
+}
+
+impl TargetStream {
+    /// The TCP connection, unless the target is reached over a tunnel
+    pub fn as_tcp(&self) -> Option<&TcpStream> {
+        match self {
+            TargetStream::Tcp(stream) => Some(stream),
+            TargetStream::Tunnel(_) => None,
+        }
+    }
+}
+
+impl RelayTarget for TargetStream {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            TargetStream::Tcp(stream) => stream.peer_addr(),
+            TargetStream::Tunnel(stream) => Ok(stream.peer_addr()),
+        }
+    }
+}
+
+impl AsyncRead for TargetStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            TargetStream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            TargetStream::Tunnel(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for TargetStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            TargetStream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            TargetStream::Tunnel(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_write_vectored(self: Pin<&mut Self>, cx: &mut Context<'_>, bufs: &[IoSlice<'_>]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            TargetStream::Tcp(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+            TargetStream::Tunnel(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match self {
+            TargetStream::Tcp(stream) => stream.is_write_vectored(),
+            TargetStream::Tunnel(stream) => stream.is_write_vectored(),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            TargetStream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            TargetStream::Tunnel(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            TargetStream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            TargetStream::Tunnel(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
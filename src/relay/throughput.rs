@@ -0,0 +1,183 @@
+//! Throughput Sampling
+//!
+//! Byte totals only show what a connection moved once it reports them or
+//! ends. Each relay also keeps exponentially weighted moving averages of its
+//! transfer rate in both directions, over about the last second and the last
+//! ten seconds, so operators can spot a connection hogging the link while it
+//! does so. Reads only add to counters; the averages are brought up to date
+//! at most every [`SAMPLE_INTERVAL`] and whenever they are read.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+
+use super::transform::Direction;
+
+/// Shortest time between folding new bytes into the averages from the relay
+pub const SAMPLE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Time constants of the short and long averages, in seconds
+const WINDOWS: [f64; 2] = [1.0, 10.0];
+
+/// Transfer rates of a connection in bytes per second
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ThroughputRates {
+    /// Client to target, averaged over about the last second
+    pub up_1s: u64,
+    /// Target to client, averaged over about the last second
+    pub down_1s: u64,
+    /// Client to target, averaged over about the last ten seconds
+    pub up_10s: u64,
+    /// Target to client, averaged over about the last ten seconds
+    pub down_10s: u64,
+}
+
+impl ThroughputRates {
+    /// Both directions over the last second, for ranking connections
+    pub fn total_1s(&self) -> u64 {
+        self.up_1s + self.down_1s
+    }
+}
+
+/// Rolling transfer rates of one connection, shared by its relay and whoever reports on it
+#[derive(Debug, Clone, Default)]
+pub struct Throughput {
+    inner: Arc<Meter>,
+}
+
+#[derive(Debug)]
+struct Meter {
+    created: Instant,
+    up: AtomicU64,
+    down: AtomicU64,
+    /// Milliseconds after `created` the averages were last brought up to date
+    sampled_ms: AtomicU64,
+    averages: Mutex<Averages>,
+}
+
+impl Default for Meter {
+    fn default() -> Self {
+        Self {
+            created: Instant::now(),
+            up: AtomicU64::new(0),
+            down: AtomicU64::new(0),
+            sampled_ms: AtomicU64::new(0),
+            averages: Mutex::new(Averages::default()),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Averages {
+    /// Time after `created` of the last sample
+    sampled: Duration,
+    /// Totals at the last sample
+    up_total: u64,
+    down_total: u64,
+    /// Bytes per second for each of [`WINDOWS`]
+    up: [f64; 2],
+    down: [f64; 2],
+}
+
+impl Throughput {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Count `bytes` moved in `direction`
+    pub fn record(&self, direction: Direction, bytes: u64) {
+        self.record_at(direction, bytes, Instant::now());
+    }
+
+    /// Count `bytes` moved in `direction` at `now`, for callers that already read the clock
+    pub(crate) fn record_at(&self, direction: Direction, bytes: u64, now: Instant) {
+        let meter = &self.inner;
+        match direction {
+            Direction::Upstream => meter.up.fetch_add(bytes, Ordering::Relaxed),
+            Direction::Downstream => meter.down.fetch_add(bytes, Ordering::Relaxed),
+        };
+        let now_ms = now.saturating_duration_since(meter.created).as_millis() as u64;
+        if now_ms.saturating_sub(meter.sampled_ms.load(Ordering::Relaxed)) >= SAMPLE_INTERVAL.as_millis() as u64 {
+            // Whoever holds the lock is sampling already
+            if let Ok(mut averages) = meter.averages.try_lock() {
+                self.sample(&mut averages);
+            }
+        }
+    }
+
+    /// Current rates, decayed for any time without traffic
+    pub fn rates(&self) -> ThroughputRates {
+        let mut averages = self.inner.averages.lock().unwrap();
+        self.sample(&mut averages);
+        ThroughputRates {
+            up_1s: averages.up[0].round() as u64,
+            down_1s: averages.down[0].round() as u64,
+            up_10s: averages.up[1].round() as u64,
+            down_10s: averages.down[1].round() as u64,
+        }
+    }
+
+    /// Fold the bytes moved since the last sample into the averages
+    ///
+    /// Samples come at uneven intervals, so each average is weighted by how
+    /// much of its window the interval covers.
+    fn sample(&self, averages: &mut Averages) {
+        let meter = &self.inner;
+        let now = meter.created.elapsed();
+        let elapsed = now.saturating_sub(averages.sampled).as_secs_f64();
+        if elapsed <= 0.0 {
+            return;
+        }
+        let up = meter.up.load(Ordering::Relaxed);
+        let down = meter.down.load(Ordering::Relaxed);
+        let up_rate = (up - averages.up_total) as f64 / elapsed;
+        let down_rate = (down - averages.down_total) as f64 / elapsed;
+        for (i, window) in WINDOWS.iter().enumerate() {
+            let weight = 1.0 - (-elapsed / window).exp();
+            averages.up[i] += weight * (up_rate - averages.up[i]);
+            averages.down[i] += weight * (down_rate - averages.down[i]);
+        }
+        averages.sampled = now;
+        averages.up_total = up;
+        averages.down_total = down;
+        meter.sampled_ms.store(now.as_millis() as u64, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_rates_follow_traffic_and_decay_when_it_stops() {
+        let throughput = Throughput::new();
+        // 100 KB/s up and 10 KB/s down for 20 seconds
+        for _ in 0..200 {
+            tokio::time::advance(Duration::from_millis(100)).await;
+            throughput.record(Direction::Upstream, 10_000);
+            throughput.record(Direction::Downstream, 1_000);
+        }
+        let busy = throughput.rates();
+        assert!((95_000..=105_000).contains(&busy.up_1s), "{:?}", busy);
+        assert!((9_500..=10_500).contains(&busy.down_1s), "{:?}", busy);
+        assert!((80_000..=105_000).contains(&busy.up_10s), "{:?}", busy);
+
+        // Two quiet seconds all but empty the short average, the long one only loses a fifth
+        tokio::time::advance(Duration::from_secs(2)).await;
+        let quiet = throughput.rates();
+        assert!(quiet.up_1s < busy.up_1s / 5, "{:?}", quiet);
+        assert!(quiet.up_10s > busy.up_10s * 3 / 4, "{:?}", quiet);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_clones_share_one_meter() {
+        let throughput = Throughput::new();
+        let reporter = throughput.clone();
+        tokio::time::advance(Duration::from_secs(1)).await;
+        throughput.record(Direction::Downstream, 50_000);
+        assert!(reporter.rates().down_1s > 0);
+        assert_eq!(reporter.rates().up_1s, 0);
+    }
+}
@@ -0,0 +1,380 @@
+//! Relay Transformers
+//!
+//! Pluggable byte-stream middleware for relayed connections. A transformer is
+//! registered with the connection manager under a name, and routing rules list
+//! the names to apply to the connections they allow. Each relayed connection
+//! gets its own [`TransformSession`], which sees every chunk of client traffic
+//! (`Upstream`) and target traffic (`Downstream`) and writes what should be
+//! forwarded in its place. A session may pass bytes through unchanged (for
+//! accounting or tagging), rewrite them, or hold them back until more arrive.
+//!
+//! Several transformers form a pipeline: upstream traffic runs through them in
+//! the order the rule lists them, and downstream traffic in reverse, so each
+//! transformer sees the other direction's bytes as it produced them.
+//!
+//! Byte counts, quotas, and bandwidth limits apply to the bytes exchanged with
+//! the client, before upstream and after downstream transformation.
+
+use std::collections::HashMap;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tracing::warn;
+
+use super::RelaySession;
+
+const READ_CHUNK: usize = 16 * 1024;
+
+/// Which way bytes are flowing through the relay
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// From the client towards the target
+    Upstream,
+    /// From the target back to the client
+    Downstream,
+}
+
+/// A named transformer that creates per-connection transform state
+pub trait RelayTransformer: Send + Sync {
+    /// Name used to reference the transformer from routing rules
+    fn name(&self) -> &str;
+
+    /// Start transforming a new relayed connection
+    fn session(&self, session: &RelaySession, user_id: Option<&str>) -> Box<dyn TransformSession>;
+}
+
+/// Transform state for a single relayed connection
+pub trait TransformSession: Send {
+    /// Transform a chunk flowing in `direction`, appending the bytes to forward to `output`
+    fn transform(&mut self, direction: Direction, input: &[u8], output: &mut Vec<u8>) -> io::Result<()>;
+
+    /// Append any bytes still held back once `direction` reaches end of stream
+    fn finish(&mut self, _direction: Direction, _output: &mut Vec<u8>) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Transformers available to routing rules, by name
+#[derive(Clone, Default)]
+pub struct TransformerRegistry {
+    transformers: HashMap<String, Arc<dyn RelayTransformer>>,
+}
+
+impl TransformerRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a transformer, replacing any existing one with the same name
+    pub fn register(&mut self, transformer: Arc<dyn RelayTransformer>) {
+        self.transformers.insert(transformer.name().to_string(), transformer);
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<dyn RelayTransformer>> {
+        self.transformers.get(name).cloned()
+    }
+
+    /// Look up the transformers a rule names, skipping (and logging) unknown names
+    pub fn resolve(&self, names: &[String]) -> Vec<Arc<dyn RelayTransformer>> {
+        names.iter()
+            .filter_map(|name| {
+                let transformer = self.get(name);
+                if transformer.is_none() {
+                    warn!("Routing rule refers to unknown relay transformer '{}', skipping it", name);
+                }
+                transformer
+            })
+            .collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.transformers.is_empty()
+    }
+}
+
+/// The transform sessions for one connection, applied in order
+pub(crate) struct TransformPipeline {
+    stages: Vec<Box<dyn TransformSession>>,
+}
+
+impl TransformPipeline {
+    pub(crate) fn new(
+        transformers: &[Arc<dyn RelayTransformer>],
+        session: &RelaySession,
+        user_id: Option<&str>,
+    ) -> Self {
+        Self {
+            stages: transformers.iter().map(|t| t.session(session, user_id)).collect(),
+        }
+    }
+
+    /// Run a chunk through every stage
+    fn apply(&mut self, direction: Direction, input: &[u8]) -> io::Result<Vec<u8>> {
+        let mut data = input.to_vec();
+        for position in 0..self.stages.len() {
+            let mut output = Vec::with_capacity(data.len());
+            let index = self.stage_index(direction, position);
+            self.stages[index].transform(direction, &data, &mut output)?;
+            data = output;
+        }
+        Ok(data)
+    }
+
+    /// Collect the held-back bytes of every stage, passing each through the stages after it
+    fn finish(&mut self, direction: Direction) -> io::Result<Vec<u8>> {
+        let mut output = Vec::new();
+        for position in 0..self.stages.len() {
+            let mut tail = Vec::new();
+            let index = self.stage_index(direction, position);
+            self.stages[index].transform(direction, &output, &mut tail)?;
+            self.stages[index].finish(direction, &mut tail)?;
+            output = tail;
+        }
+        Ok(output)
+    }
+
+    /// Upstream runs through the stages front to back, downstream back to front
+    fn stage_index(&self, direction: Direction, position: usize) -> usize {
+        match direction {
+            Direction::Upstream => position,
+            Direction::Downstream => self.stages.len() - 1 - position,
+        }
+    }
+}
+
+/// Client-side stream wrapper that runs reads and writes through a pipeline
+///
+/// Reads from the client are upstream traffic; writes to the client are
+/// downstream traffic. Transformed output that the client cannot take yet is
+/// buffered and written before the next write, flush, or shutdown.
+pub(crate) struct TransformStream<S> {
+    inner: S,
+    pipeline: TransformPipeline,
+    scratch: Vec<u8>,
+    read_buf: Vec<u8>,
+    read_pos: usize,
+    read_eof: bool,
+    write_buf: Vec<u8>,
+    write_pos: usize,
+    write_finished: bool,
+}
+
+impl<S> TransformStream<S> {
+    pub(crate) fn new(inner: S, pipeline: TransformPipeline) -> Self {
+        Self {
+            inner,
+            pipeline,
+            scratch: vec![0; READ_CHUNK],
+            read_buf: Vec::new(),
+            read_pos: 0,
+            read_eof: false,
+            write_buf: Vec::new(),
+            write_pos: 0,
+            write_finished: false,
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> TransformStream<S> {
+    /// Write out buffered downstream bytes
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.write_pos < self.write_buf.len() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.write_buf[self.write_pos..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.write_pos += n;
+        }
+        self.write_buf.clear();
+        self.write_pos = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for TransformStream<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if this.read_pos < this.read_buf.len() {
+                let n = buf.remaining().min(this.read_buf.len() - this.read_pos);
+                buf.put_slice(&this.read_buf[this.read_pos..this.read_pos + n]);
+                this.read_pos += n;
+                return Poll::Ready(Ok(()));
+            }
+            if this.read_eof {
+                return Poll::Ready(Ok(()));
+            }
+
+            let mut chunk = ReadBuf::new(&mut this.scratch);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut chunk))?;
+            let data = chunk.filled();
+            this.read_buf = if data.is_empty() {
+                this.read_eof = true;
+                this.pipeline.finish(Direction::Upstream)?
+            } else {
+                this.pipeline.apply(Direction::Upstream, data)?
+            };
+            this.read_pos = 0;
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for TransformStream<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        this.write_buf = this.pipeline.apply(Direction::Downstream, buf)?;
+        // Start writing now; whatever is left goes out on the next call
+        if let Poll::Ready(Err(e)) = this.poll_drain(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        if !this.write_finished {
+            this.write_finished = true;
+            this.write_buf = this.pipeline.finish(Direction::Downstream)?;
+            ready!(this.poll_drain(cx))?;
+        }
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Upper-cases upstream traffic and counts downstream bytes
+    #[derive(Default)]
+    struct Shout {
+        downstream: Arc<AtomicU64>,
+    }
+
+    struct ShoutSession {
+        downstream: Arc<AtomicU64>,
+    }
+
+    impl RelayTransformer for Shout {
+        fn name(&self) -> &str {
+            "shout"
+        }
+
+        fn session(&self, _session: &RelaySession, _user_id: Option<&str>) -> Box<dyn TransformSession> {
+            Box::new(ShoutSession { downstream: self.downstream.clone() })
+        }
+    }
+
+    impl TransformSession for ShoutSession {
+        fn transform(&mut self, direction: Direction, input: &[u8], output: &mut Vec<u8>) -> io::Result<()> {
+            match direction {
+                Direction::Upstream => output.extend(input.iter().map(u8::to_ascii_uppercase)),
+                Direction::Downstream => {
+                    self.downstream.fetch_add(input.len() as u64, Ordering::Relaxed);
+                    output.extend_from_slice(input);
+                }
+            }
+            Ok(())
+        }
+    }
+
+    /// Holds upstream bytes back until end of stream, then prefixes a tag
+    struct Tagger;
+
+    struct TaggerSession {
+        held: Vec<u8>,
+    }
+
+    impl RelayTransformer for Tagger {
+        fn name(&self) -> &str {
+            "tag"
+        }
+
+        fn session(&self, _session: &RelaySession, _user_id: Option<&str>) -> Box<dyn TransformSession> {
+            Box::new(TaggerSession { held: Vec::new() })
+        }
+    }
+
+    impl TransformSession for TaggerSession {
+        fn transform(&mut self, direction: Direction, input: &[u8], output: &mut Vec<u8>) -> io::Result<()> {
+            match direction {
+                Direction::Upstream => self.held.extend_from_slice(input),
+                Direction::Downstream => output.extend_from_slice(input),
+            }
+            Ok(())
+        }
+
+        fn finish(&mut self, direction: Direction, output: &mut Vec<u8>) -> io::Result<()> {
+            if direction == Direction::Upstream {
+                output.extend_from_slice(b"[tagged]");
+                output.append(&mut self.held);
+            }
+            Ok(())
+        }
+    }
+
+    fn test_session() -> RelaySession {
+        let addr = "127.0.0.1:1080".parse().unwrap();
+        RelaySession::new("test".to_string(), addr, addr)
+    }
+
+    #[tokio::test]
+    async fn test_pipeline_transforms_both_directions() {
+        let shout = Arc::new(Shout::default());
+        let mut registry = TransformerRegistry::new();
+        registry.register(shout.clone());
+        registry.register(Arc::new(Tagger));
+
+        let transformers = registry.resolve(&["shout".to_string(), "missing".to_string(), "tag".to_string()]);
+        assert_eq!(transformers.len(), 2);
+
+        let (client, mut remote) = tokio::io::duplex(1024);
+        let pipeline = TransformPipeline::new(&transformers, &test_session(), None);
+        let mut stream = TransformStream::new(client, pipeline);
+
+        remote.write_all(b"hello").await.unwrap();
+        remote.shutdown().await.unwrap();
+        let mut upstream = Vec::new();
+        stream.read_to_end(&mut upstream).await.unwrap();
+        assert_eq!(upstream, b"[tagged]HELLO");
+
+        stream.write_all(b"response").await.unwrap();
+        stream.shutdown().await.unwrap();
+        let mut downstream = Vec::new();
+        remote.read_to_end(&mut downstream).await.unwrap();
+        assert_eq!(downstream, b"response");
+        assert_eq!(shout.downstream.load(Ordering::Relaxed), 8);
+    }
+
+    #[tokio::test]
+    async fn test_buffered_writes_reach_slow_client() {
+        let transformers: Vec<Arc<dyn RelayTransformer>> = vec![Arc::new(Shout::default())];
+        // A tiny pipe forces transformed output to be buffered across writes
+        let (client, mut remote) = tokio::io::duplex(4);
+        let pipeline = TransformPipeline::new(&transformers, &test_session(), None);
+        let mut stream = TransformStream::new(client, pipeline);
+
+        let reader = tokio::spawn(async move {
+            let mut received = Vec::new();
+            remote.read_to_end(&mut received).await.unwrap();
+            received
+        });
+        let payload = vec![b'x'; 1000];
+        stream.write_all(&payload).await.unwrap();
+        stream.shutdown().await.unwrap();
+
+        assert_eq!(reader.await.unwrap(), payload);
+    }
+}
@@ -0,0 +1,439 @@
+//! UDP Relay
+//!
+//! Carries datagrams for a SOCKS5 UDP ASSOCIATE until the controlling TCP
+//! connection closes, the association sits idle or it reaches the maximum
+//! connection lifetime. Client datagrams are
+//! unwrapped and sent on to their target; target replies are wrapped with
+//! the sender's address and returned to the client. Every datagram first
+//! passes the association's [`UdpAssociationGuard`], so spoofed or replayed
+//! datagrams are dropped and counted before anything is forwarded, and
+//! client datagrams over the association's rate limits are dropped the
+//! same way. Targets are resolved with the shared resolver and held to the
+//! same routing rules and private range protection as CONNECT requests, so
+//! UDP is no way around them.
+
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tracing::{debug, info, warn};
+
+use crate::metrics::Metrics;
+use crate::protocol::udp::{encode_udp_reply, UdpDatagram};
+use crate::protocol::TargetAddr;
+use crate::routing::{Resolver, RouteDecision, Router};
+use crate::security::{private_ranges, BlockReason};
+use crate::security::rate_limiter::UdpAssociationLimit;
+use crate::security::udp_guard::{UdpAssociationGuard, UdpGuardCounters, UdpOrigin, UdpRejection};
+use crate::Result;
+use super::{Direction, RelayHandle};
+
+/// Largest datagram the relay handles
+const MAX_DATAGRAM: usize = 65_535;
+
+/// Datagrams forwarded by a finished relay
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UdpRelayStats {
+    pub datagrams_up: u64,
+    pub datagrams_down: u64,
+}
+
+/// Who an association belongs to, for routing decisions on its datagrams
+pub struct UdpRouteContext {
+    pub router: Arc<Router>,
+    pub client_ip: IpAddr,
+    pub user: Option<String>,
+    pub groups: Vec<String>,
+}
+
+/// Relays one UDP association
+pub struct UdpRelay {
+    socket: UdpSocket,
+    guard: UdpAssociationGuard,
+    counters: Arc<UdpGuardCounters>,
+    rate_limit: Option<UdpAssociationLimit>,
+    metrics: Option<Arc<Metrics>>,
+    idle_timeout: Duration,
+    lifetime: Option<Duration>,
+    lifetime_warning: Duration,
+    handle: RelayHandle,
+    resolver: Arc<Resolver>,
+    route: Option<UdpRouteContext>,
+    protect_private: bool,
+}
+
+impl UdpRelay {
+    pub fn new(socket: UdpSocket, guard: UdpAssociationGuard, counters: Arc<UdpGuardCounters>) -> Self {
+        Self {
+            socket,
+            guard,
+            counters,
+            rate_limit: None,
+            metrics: None,
+            idle_timeout: Duration::from_secs(300),
+            lifetime: None,
+            lifetime_warning: Duration::ZERO,
+            handle: RelayHandle::default(),
+            resolver: Arc::new(Resolver::default()),
+            route: None,
+            protect_private: false,
+        }
+    }
+
+    /// Drop client datagrams over the association's packet and byte ceilings
+    pub fn with_rate_limit(mut self, rate_limit: Option<UdpAssociationLimit>) -> Self {
+        self.rate_limit = rate_limit;
+        self
+    }
+
+    /// Count rejected datagrams in the Prometheus metrics as well
+    pub fn with_metrics(mut self, metrics: Option<Arc<Metrics>>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// End the association after this long without an accepted datagram
+    pub fn with_idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// End the association once the connection is `lifetime` old, counting a
+    /// warning in the metrics `warning` beforehand
+    pub fn with_connection_lifetime(mut self, lifetime: Option<Duration>, warning: Duration) -> Self {
+        self.lifetime = lifetime;
+        self.lifetime_warning = warning;
+        self
+    }
+
+    /// Resolve domain targets through a shared resolver and its cache
+    pub fn with_resolver(mut self, resolver: Arc<Resolver>) -> Self {
+        self.resolver = resolver;
+        self
+    }
+
+    /// Drop datagrams to targets the router blocks for this association's client
+    pub fn with_router(mut self, route: UdpRouteContext) -> Self {
+        self.route = Some(route);
+        self
+    }
+
+    /// Drop datagrams to private and internal addresses, unless the rule
+    /// that allowed the target sets `allow_private`
+    pub fn with_private_range_protection(mut self, enabled: bool) -> Self {
+        self.protect_private = enabled;
+        self
+    }
+
+    /// Record datagrams on `handle`, and end the association when it is cancelled
+    pub fn with_handle(mut self, handle: RelayHandle) -> Self {
+        self.handle = handle;
+        self
+    }
+
+    /// Relay until `closed` completes, the association goes idle, reaches its
+    /// lifetime or its handle is cancelled
+    pub async fn run(mut self, closed: impl Future<Output = ()>) -> Result<UdpRelayStats> {
+        self.handle.start_relaying();
+        let expired = Self::lifetime_reached(self.handle.clone(), self.lifetime, self.lifetime_warning, self.metrics.clone());
+        tokio::pin!(closed, expired);
+        let mut stats = UdpRelayStats::default();
+        let mut buf = vec![0u8; MAX_DATAGRAM];
+
+        loop {
+            let (len, source) = tokio::select! {
+                _ = &mut closed => {
+                    debug!("UDP association control connection closed");
+                    break;
+                }
+                _ = &mut expired => {
+                    info!("UDP association reached the maximum connection lifetime, closing");
+                    break;
+                }
+                reason = self.handle.cancelled() => {
+                    info!("UDP association closed: {}", reason);
+                    break;
+                }
+                received = tokio::time::timeout(self.idle_timeout, self.socket.recv_from(&mut buf)) => match received {
+                    Ok(received) => received?,
+                    Err(_) => {
+                        info!("UDP association idle for {:?}, closing", self.idle_timeout);
+                        break;
+                    }
+                },
+            };
+            self.handle.touch();
+
+            match self.guard.check(source, &buf[..len]) {
+                Ok(UdpOrigin::Client(datagram)) => {
+                    if self.rate_limit.as_mut().is_some_and(|limit| !limit.allow(len)) {
+                        self.reject(source, UdpRejection::RateLimited);
+                        continue;
+                    }
+                    self.handle.throughput().record(Direction::Upstream, datagram.len() as u64);
+                    let datagram = datagram.to_vec();
+                    if self.forward_to_target(&datagram).await {
+                        stats.datagrams_up += 1;
+                    }
+                }
+                Ok(UdpOrigin::Target) => {
+                    let Some(client) = self.guard.client() else {
+                        continue;
+                    };
+                    self.handle.throughput().record(Direction::Downstream, len as u64);
+                    self.socket.send_to(&encode_udp_reply(source, &buf[..len]), client).await?;
+                    stats.datagrams_down += 1;
+                }
+                Err(rejection) => self.reject(source, rejection),
+            }
+        }
+        Ok(stats)
+    }
+
+    /// Resolves once the connection is `lifetime` old, counting a warning shortly before
+    async fn lifetime_reached(handle: RelayHandle, lifetime: Option<Duration>, warning: Duration, metrics: Option<Arc<Metrics>>) {
+        let Some(lifetime) = lifetime else {
+            return std::future::pending().await;
+        };
+        tokio::time::sleep(lifetime.saturating_sub(handle.age()).saturating_sub(warning)).await;
+        let remaining = lifetime.saturating_sub(handle.age());
+        if !remaining.is_zero() {
+            warn!("UDP association will be closed in {:?} for reaching the maximum connection lifetime", remaining);
+            if let Some(metrics) = &metrics {
+                metrics.record_lifetime_warning();
+            }
+            tokio::time::sleep(remaining).await;
+        }
+    }
+
+    /// Send a client datagram on to its target, returning whether it went out
+    async fn forward_to_target(&mut self, datagram: &[u8]) -> bool {
+        let datagram = match UdpDatagram::parse(datagram) {
+            Ok(datagram) => datagram,
+            Err(e) => {
+                debug!("Dropping malformed UDP datagram: {}", e);
+                self.count(UdpRejection::Malformed);
+                return false;
+            }
+        };
+        if datagram.frag != 0 {
+            debug!("Dropping fragmented UDP datagram (fragment {})", datagram.frag);
+            return false;
+        }
+
+        let Some((target, port, allow_private)) = self.route_target(&datagram.target, datagram.port).await else {
+            self.count(UdpRejection::Blocked);
+            return false;
+        };
+        let target = match &target {
+            TargetAddr::Ipv4(ip) => SocketAddr::new((*ip).into(), port),
+            TargetAddr::Ipv6(ip) => SocketAddr::new((*ip).into(), port),
+            // Answers are cached across associations by the shared resolver
+            TargetAddr::Domain(domain) => match self.resolver.resolve_socket_addrs(domain, port).await {
+                Ok(addrs) => match addrs.into_iter().next() {
+                    Some(addr) => addr,
+                    None => return false,
+                },
+                Err(e) => {
+                    debug!("Failed to resolve UDP target {}: {:#}", domain, e);
+                    return false;
+                }
+            },
+        };
+        // Resolved addresses are checked, so a public name pointing inward is caught too
+        if self.protect_private && !allow_private {
+            if let Err(e) = private_ranges::check(target) {
+                debug!("Dropping UDP datagram [{}]: {}", BlockReason::PrivateRange, e);
+                self.count(UdpRejection::Blocked);
+                return false;
+            }
+        }
+
+        self.guard.record_destination(target);
+        match self.socket.send_to(datagram.payload, target).await {
+            Ok(_) => true,
+            Err(e) => {
+                debug!("Failed to send UDP datagram to {}: {}", target, e);
+                false
+            }
+        }
+    }
+
+    /// Where the routing rules send a datagram for `target`, and whether it may
+    /// reach private addresses, or `None` if they block it
+    async fn route_target(&self, target: &TargetAddr, port: u16) -> Option<(TargetAddr, u16, bool)> {
+        let Some(route) = &self.route else {
+            return Some((target.clone(), port, false));
+        };
+        match route.router.route_datagram(target, port, route.client_ip, route.user.as_deref(), &route.groups).await {
+            RouteDecision::Allow { allow_private, .. } => Some((target.clone(), port, allow_private)),
+            // Redirects keep the requested port when the rule's is 0, and name
+            // the address outright, so it may be a private one
+            RouteDecision::Redirect { target: redirect, .. } => {
+                let port = if redirect.port() == 0 { port } else { redirect.port() };
+                Some((TargetAddr::from_socket_addr(&redirect), port, true))
+            }
+            RouteDecision::Rewrite { target, allow_private, .. } => Some((target, port, allow_private)),
+            RouteDecision::Block { reason, code } => {
+                debug!("Dropping UDP datagram to {}:{} [{}]: {}", target.to_string(), port, code, reason);
+                None
+            }
+        }
+    }
+
+    fn reject(&self, source: SocketAddr, rejection: UdpRejection) {
+        debug!("Dropping {} UDP datagram from {}", rejection, source);
+        self.count(rejection);
+    }
+
+    fn count(&self, rejection: UdpRejection) {
+        self.counters.record(rejection);
+        if let Some(metrics) = &self.metrics {
+            metrics.record_udp_rejection(rejection);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::security::udp_guard::UdpGuardConfig;
+
+    #[tokio::test]
+    async fn test_relays_datagrams_and_drops_spoofed_ones() {
+        let relay_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let relay_addr = relay_socket.local_addr().unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+
+        // Pinned to the announced client address, so the same-host attacker is rejected
+        let guard = UdpAssociationGuard::new(&UdpGuardConfig::default(), client.local_addr().unwrap().ip(), Some(client.local_addr().unwrap()));
+        let counters = Arc::new(UdpGuardCounters::default());
+        let (close_tx, close_rx) = tokio::sync::oneshot::channel::<()>();
+        let relay = tokio::spawn(UdpRelay::new(relay_socket, guard, counters.clone()).run(async {
+            let _ = close_rx.await;
+        }));
+
+        let attacker = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        attacker.send_to(b"injected", relay_addr).await.unwrap();
+
+        let request = [&[0, 0, 0, 1][..], &[127, 0, 0, 1], &target_addr.port().to_be_bytes(), b"ping"].concat();
+        client.send_to(&request, relay_addr).await.unwrap();
+        let mut buf = [0u8; 64];
+        let (len, from) = target.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"ping");
+
+        target.send_to(b"pong", from).await.unwrap();
+        let (len, _) = client.recv_from(&mut buf).await.unwrap();
+        let reply = UdpDatagram::parse(&buf[..len]).unwrap();
+        assert_eq!(reply.payload, b"pong");
+        assert_eq!(reply.port, target_addr.port());
+
+        close_tx.send(()).unwrap();
+        let stats = relay.await.unwrap().unwrap();
+        assert_eq!(stats, UdpRelayStats { datagrams_up: 1, datagrams_down: 1 });
+        assert_eq!(counters.stats().spoofed, 1);
+    }
+
+    #[tokio::test]
+    async fn test_drops_datagrams_over_the_association_rate() {
+        use crate::security::{RateLimitConfig, RateLimiter, UdpRateLimitConfig};
+
+        let relay_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let relay_addr = relay_socket.local_addr().unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+
+        let limiter = Arc::new(RateLimiter::new(RateLimitConfig {
+            udp: Some(UdpRateLimitConfig { association_packets_per_second: Some(2), ..Default::default() }),
+            ..Default::default()
+        }));
+        let client_ip = client.local_addr().unwrap().ip();
+        let guard = UdpAssociationGuard::new(&UdpGuardConfig::default(), client_ip, Some(client.local_addr().unwrap()));
+        let counters = Arc::new(UdpGuardCounters::default());
+        let (close_tx, close_rx) = tokio::sync::oneshot::channel::<()>();
+        let relay = UdpRelay::new(relay_socket, guard, counters.clone())
+            .with_rate_limit(limiter.udp_association_limit(client_ip));
+        let relay = tokio::spawn(relay.run(async {
+            let _ = close_rx.await;
+        }));
+
+        let request = [&[0, 0, 0, 1][..], &[127, 0, 0, 1], &target_addr.port().to_be_bytes(), b"ping"].concat();
+        for _ in 0..5 {
+            client.send_to(&request, relay_addr).await.unwrap();
+        }
+        let mut buf = [0u8; 64];
+        for _ in 0..2 {
+            target.recv_from(&mut buf).await.unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        close_tx.send(()).unwrap();
+        let stats = relay.await.unwrap().unwrap();
+        assert_eq!(stats.datagrams_up, 2);
+        assert_eq!(counters.stats().rate_limited, 3);
+    }
+
+    #[tokio::test]
+    async fn test_never_sends_to_blocked_or_private_targets() {
+        use crate::config::{AccessRule, Config};
+        use crate::routing::PortRange;
+
+        let relay_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let relay_addr = relay_socket.local_addr().unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        let open_target = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client_ip = client.local_addr().unwrap().ip();
+
+        // A rule blocks the target's port outright
+        let mut config = Config::default();
+        config.access_control.enabled = true;
+        config.access_control.default_policy = "allow".to_string();
+        config.access_control.rules.push(AccessRule {
+            pattern: "127.0.0.1".to_string(),
+            action: "block".to_string(),
+            ports: Some(vec![PortRange::new(target_addr.port(), target_addr.port())]),
+            countries: None,
+            groups: None,
+        });
+        let route = UdpRouteContext {
+            router: Arc::new(Router::new(Arc::new(config))),
+            client_ip,
+            user: None,
+            groups: Vec::new(),
+        };
+
+        let guard = UdpAssociationGuard::new(&UdpGuardConfig::default(), client_ip, Some(client.local_addr().unwrap()));
+        let counters = Arc::new(UdpGuardCounters::default());
+        let (close_tx, close_rx) = tokio::sync::oneshot::channel::<()>();
+        let relay = UdpRelay::new(relay_socket, guard, counters.clone())
+            .with_router(route)
+            .with_private_range_protection(true);
+        let relay = tokio::spawn(relay.run(async {
+            let _ = close_rx.await;
+        }));
+
+        // One datagram to the blocked port, one to a port no rule blocks, but on loopback
+        let open_port = open_target.local_addr().unwrap().port();
+        let blocked = [&[0, 0, 0, 1][..], &[127, 0, 0, 1], &target_addr.port().to_be_bytes(), b"ping"].concat();
+        let private = [&[0, 0, 0, 1][..], &[127, 0, 0, 1], &open_port.to_be_bytes(), b"ping"].concat();
+        client.send_to(&blocked, relay_addr).await.unwrap();
+        client.send_to(&private, relay_addr).await.unwrap();
+
+        let mut buf = [0u8; 64];
+        for socket in [&target, &open_target] {
+            let received = tokio::time::timeout(Duration::from_millis(200), socket.recv_from(&mut buf)).await;
+            assert!(received.is_err(), "blocked datagram reached its target");
+        }
+
+        close_tx.send(()).unwrap();
+        let stats = relay.await.unwrap().unwrap();
+        assert_eq!(stats.datagrams_up, 0);
+        assert_eq!(counters.stats().blocked, 2);
+    }
+}
@@ -0,0 +1,76 @@
+//! Per-User Bandwidth Limits
+//!
+//! A user's total bandwidth caps all of their relays together, however many
+//! connections they open at once. While any of a capped user's relays are
+//! running, the user has a [`BandwidthClass`] of their own that each relay
+//! is paced against, just like a routing rule's bandwidth class. Classes
+//! are held by the relays only, so a user's class goes away with their last
+//! relay.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, Weak};
+
+use super::progress::{BandwidthClass, BandwidthLimit};
+
+/// Bandwidth classes of users with relays running
+#[derive(Debug, Default)]
+pub struct UserBandwidth {
+    classes: Mutex<HashMap<String, Weak<BandwidthClass>>>,
+}
+
+impl UserBandwidth {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Class shared by `user_id`'s relays under `limit`, or `None` when unlimited
+    ///
+    /// When the limit changes, e.g. on a config reload, relays started from
+    /// then on share a new class while running ones keep the old one.
+    pub fn class_for(&self, user_id: &str, limit: BandwidthLimit) -> Option<Arc<BandwidthClass>> {
+        if limit.is_unlimited() {
+            return None;
+        }
+        let mut classes = self.classes.lock().unwrap();
+        if let Some(class) = classes.get(user_id).and_then(Weak::upgrade).filter(|class| class.limit() == limit) {
+            return Some(class);
+        }
+        classes.retain(|_, class| class.strong_count() > 0);
+        let class = Arc::new(BandwidthClass::new(format!("user:{}", user_id), limit));
+        classes.insert(user_id.to_string(), Arc::downgrade(&class));
+        Some(class)
+    }
+
+    /// Number of users with capped relays running
+    pub fn active_users(&self) -> usize {
+        self.classes.lock().unwrap().values().filter(|class| class.strong_count() > 0).count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_users_share_one_class_while_relays_run() {
+        let table = UserBandwidth::new();
+        let limit = BandwidthLimit { download_bytes_per_second: Some(1000), ..Default::default() };
+        assert!(table.class_for("alice", BandwidthLimit::default()).is_none());
+
+        let first = table.class_for("alice", limit).unwrap();
+        let second = table.class_for("alice", limit).unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+        let bob = table.class_for("bob", limit).unwrap();
+        assert!(!Arc::ptr_eq(&first, &bob));
+        assert_eq!(table.active_users(), 2);
+
+        // A changed limit starts a new class
+        let raised = BandwidthLimit { download_bytes_per_second: Some(2000), ..Default::default() };
+        let third = table.class_for("alice", raised).unwrap();
+        assert!(!Arc::ptr_eq(&first, &third));
+        assert_eq!(third.limit(), raised);
+
+        drop((first, second, third));
+        assert_eq!(table.active_users(), 1);
+    }
+}
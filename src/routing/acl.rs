@@ -3,13 +3,15 @@
 use std::net::IpAddr;
 use crate::config::{AccessControlConfig, AccessRule};
 use crate::protocol::TargetAddr;
+use crate::security::{BlockReason, FailurePolicy};
 use super::types::{AccessControlList, AccessControlRule, Action, Policy};
-use super::geoip::GeoIpFilter;
+use super::geoip::{GeoIpFilter, GeoIpHandle};
 
 /// ACL manager for handling access control configuration
 pub struct AclManager {
     acl: AccessControlList,
-    geoip_filter: Option<GeoIpFilter>,
+    geoip: GeoIpHandle,
+    geoip_failure_policy: FailurePolicy,
 }
 
 impl AclManager {
@@ -20,61 +22,105 @@ impl AclManager {
 
         // Convert configuration rules to ACL rules
         for rule in &config.rules {
-            let acl_rule = AccessControlRule {
-                pattern: rule.pattern.clone(),
-                action: Action::from(rule.action.as_str()),
-                ports: rule.ports.clone(),
-                countries: rule.countries.clone(),
-            };
-            acl.add_rule(acl_rule);
+            acl.add_rule(AccessControlRule::from(rule));
         }
 
         Self { 
             acl,
-            geoip_filter: None,
+            geoip: GeoIpHandle::default(),
+            geoip_failure_policy: FailurePolicy::Open,
         }
     }
 
+    /// Set how country-restricted rules behave when no GeoIP database is loaded
+    pub fn set_geoip_failure_policy(&mut self, policy: FailurePolicy) {
+        self.geoip_failure_policy = policy;
+    }
+
     /// Create a new ACL manager with GeoIP support
     pub fn with_geoip(config: &AccessControlConfig, geoip_filter: GeoIpFilter) -> Self {
+        Self::with_geoip_handle(config, GeoIpHandle::new(Some(geoip_filter)))
+    }
+
+    /// Create a new ACL manager whose GeoIP database can be swapped through `handle`
+    pub fn with_geoip_handle(config: &AccessControlConfig, handle: GeoIpHandle) -> Self {
         let mut manager = Self::new(config);
-        manager.geoip_filter = Some(geoip_filter);
+        manager.geoip = handle;
         manager
     }
 
+    /// Handle for swapping the GeoIP database without rebuilding the ACL
+    pub fn geoip_handle(&self) -> &GeoIpHandle {
+        &self.geoip
+    }
+
     /// Check if access is allowed for the given parameters
     pub fn check_access(&self, target: &TargetAddr, port: u16, source_ip: IpAddr) -> (bool, String) {
+        let (allowed, reason, _) = self.check_access_with_code(target, port, source_ip);
+        (allowed, reason)
+    }
+
+    /// Check access and report whether a denial came from an ACL rule or a GeoIP restriction
+    pub fn check_access_with_code(&self, target: &TargetAddr, port: u16, source_ip: IpAddr) -> (bool, String, BlockReason) {
+        self.check_access_for_groups(target, port, source_ip, &[])
+    }
+
+    /// Check access for a user belonging to the given groups
+    ///
+    /// Rules limited to groups only apply when the user is in one of them.
+    pub fn check_access_for_groups(
+        &self,
+        target: &TargetAddr,
+        port: u16,
+        source_ip: IpAddr,
+        groups: &[String],
+    ) -> (bool, String, BlockReason) {
         // First check standard ACL rules
-        let (allowed, reason) = self.acl.evaluate_access(target, port, source_ip);
+        let (allowed, reason) = self.acl.evaluate_access_for_groups(target, port, source_ip, groups);
         
         if !allowed {
-            return (false, reason);
+            return (false, reason, BlockReason::Acl);
+        }
+
+        // Use one database for the whole check, even if it is swapped meanwhile
+        let geoip_filter = self.geoip.current();
+
+        // Country restrictions cannot be evaluated without a GeoIP database
+        if geoip_filter.is_none() && !self.geoip_failure_policy.allows() {
+            for rule in &self.acl.rules {
+                if rule.countries.is_some()
+                    && self.acl.applies_to_groups(rule, groups)
+                    && self.acl.matches_rule(rule, target, port, source_ip)
+                {
+                    return (false, format!("GeoIP unavailable for country-restricted rule: {}", rule.pattern), BlockReason::Geo);
+                }
+            }
         }
 
         // If standard ACL allows, check GeoIP restrictions if available
-        if let Some(geoip) = &self.geoip_filter {
+        if let Some(geoip) = &geoip_filter {
             // Check if any rules have country restrictions that apply
             for rule in &self.acl.rules {
                 if let Some(countries) = &rule.countries {
-                    if self.acl.matches_rule(rule, target, port, source_ip) {
+                    if self.acl.applies_to_groups(rule, groups) && self.acl.matches_rule(rule, target, port, source_ip) {
                         // This rule applies and has country restrictions
                         match &rule.action {
                             Action::Allow => {
                                 // Allow rule with country allowlist
                                 if !geoip.is_country_allowed(source_ip, countries) {
-                                    return (false, format!("Country not in allowlist for rule: {}", rule.pattern));
+                                    return (false, format!("Country not in allowlist for rule: {}", rule.pattern), BlockReason::Geo);
                                 }
                             }
                             Action::Block => {
                                 // Block rule with country blocklist
                                 if geoip.is_country_blocked(source_ip, countries) {
-                                    return (false, format!("Country blocked by rule: {}", rule.pattern));
+                                    return (false, format!("Country blocked by rule: {}", rule.pattern), BlockReason::Geo);
                                 }
                             }
                             Action::Redirect(_) => {
                                 // Redirect rules can also have country restrictions
                                 if !geoip.is_country_allowed(source_ip, countries) {
-                                    return (false, format!("Country not allowed for redirect rule: {}", rule.pattern));
+                                    return (false, format!("Country not allowed for redirect rule: {}", rule.pattern), BlockReason::Geo);
                                 }
                             }
                         }
@@ -83,7 +129,7 @@ impl AclManager {
             }
         }
 
-        (allowed, reason)
+        (allowed, reason, BlockReason::Acl)
     }
 
     /// Get the default policy
@@ -98,12 +144,17 @@ impl AclManager {
 
     /// Check if GeoIP filtering is available
     pub fn has_geoip(&self) -> bool {
-        self.geoip_filter.is_some()
+        self.geoip.current().is_some()
+    }
+
+    /// Build epoch of the GeoIP database in use
+    pub fn geoip_build_epoch(&self) -> Option<u64> {
+        self.geoip.build_epoch()
     }
 
     /// Get country for an IP address (if GeoIP is available)
     pub fn get_country(&self, ip: IpAddr) -> Option<String> {
-        self.geoip_filter.as_ref()?.get_country(ip)
+        self.geoip.current()?.get_country(ip)
     }
 }
 
@@ -114,6 +165,7 @@ impl From<&AccessRule> for AccessControlRule {
             action: Action::from(rule.action.as_str()),
             ports: rule.ports.clone(),
             countries: rule.countries.clone(),
+            groups: rule.groups.clone(),
         }
     }
 }
@@ -132,8 +184,9 @@ mod tests {
                 AccessRule {
                     pattern: "192.168.1.0/24".to_string(),
                     action: "block".to_string(),
-                    ports: Some(vec![80, 443]),
+                    ports: Some(vec![80.into(), 443.into()]),
                     countries: None,
+                    groups: None,
                 },
             ],
         };
@@ -154,6 +207,7 @@ mod tests {
                     action: "block".to_string(),
                     ports: None,
                     countries: None,
+                    groups: None,
                 },
             ],
         };
@@ -181,8 +235,9 @@ mod tests {
                 AccessRule {
                     pattern: "*".to_string(),
                     action: "block".to_string(),
-                    ports: Some(vec![22, 23]),
+                    ports: Some(vec![22.into(), 23.into()]),
                     countries: None,
+                    groups: None,
                 },
             ],
         };
@@ -200,6 +255,26 @@ mod tests {
         assert!(allowed);
     }
 
+    #[test]
+    fn test_port_ranges_and_services_from_config() {
+        let config: AccessControlConfig = toml::from_str(r#"
+            enabled = true
+            default_policy = "allow"
+
+            [[rules]]
+            pattern = "*"
+            action = "block"
+            ports = ["ssh", "6000-6063"]
+        "#).unwrap();
+
+        let acl_manager = AclManager::new(&config);
+        let source_ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let target = TargetAddr::Domain("example.com".to_string());
+        assert!(!acl_manager.check_access(&target, 22, source_ip).0);
+        assert!(!acl_manager.check_access(&target, 6010, source_ip).0);
+        assert!(acl_manager.check_access(&target, 6064, source_ip).0);
+    }
+
     #[test]
     fn test_domain_patterns() {
         let config = AccessControlConfig {
@@ -211,6 +286,7 @@ mod tests {
                     action: "block".to_string(),
                     ports: None,
                     countries: None,
+                    groups: None,
                 },
             ],
         };
@@ -228,4 +304,106 @@ mod tests {
         let (allowed, _reason) = acl_manager.check_access(&allowed_target, 80, source_ip);
         assert!(allowed);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_geoip_failure_policy() {
+        let config = AccessControlConfig {
+            enabled: true,
+            default_policy: "allow".to_string(),
+            rules: vec![
+                AccessRule {
+                    pattern: "*".to_string(),
+                    action: "allow".to_string(),
+                    ports: None,
+                    countries: Some(vec!["US".to_string()]),
+                    groups: None,
+                },
+            ],
+        };
+
+        let mut acl_manager = AclManager::new(&config);
+        let source_ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let target = TargetAddr::Domain("example.com".to_string());
+
+        // Fail-open: country restriction is skipped without a GeoIP database
+        let (allowed, _reason) = acl_manager.check_access(&target, 80, source_ip);
+        assert!(allowed);
+
+        // Fail-closed: country-restricted rules deny when GeoIP is missing
+        acl_manager.set_geoip_failure_policy(FailurePolicy::Closed);
+        let (allowed, _reason, code) = acl_manager.check_access_with_code(&target, 80, source_ip);
+        assert!(!allowed);
+        assert_eq!(code, BlockReason::Geo);
+    }
+
+    #[test]
+    fn test_group_restricted_rule() {
+        let config = AccessControlConfig {
+            enabled: true,
+            default_policy: "allow".to_string(),
+            rules: vec![
+                AccessRule {
+                    pattern: "10.20.0.0/16".to_string(),
+                    action: "block".to_string(),
+                    ports: None,
+                    countries: None,
+                    groups: Some(vec!["contractors".to_string()]),
+                },
+            ],
+        };
+
+        let acl_manager = AclManager::new(&config);
+        let source_ip = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 10));
+        let target = TargetAddr::Ipv4(Ipv4Addr::new(10, 20, 1, 1));
+
+        let contractors = vec!["contractors".to_string()];
+        let (allowed, _reason, code) = acl_manager.check_access_for_groups(&target, 22, source_ip, &contractors);
+        assert!(!allowed);
+        assert_eq!(code, BlockReason::Acl);
+
+        let staff = vec!["staff".to_string()];
+        let (allowed, _reason, _) = acl_manager.check_access_for_groups(&target, 22, source_ip, &staff);
+        assert!(allowed);
+
+        // Users without groups are not affected by group rules
+        let (allowed, _reason) = acl_manager.check_access(&target, 22, source_ip);
+        assert!(allowed);
+    }
+
+    #[test]
+    fn test_geoip_swap_applies_without_rebuilding() {
+        use crate::routing::GeoIpReader;
+
+        let config = AccessControlConfig {
+            enabled: true,
+            default_policy: "allow".to_string(),
+            rules: vec![
+                AccessRule {
+                    pattern: "*".to_string(),
+                    action: "allow".to_string(),
+                    ports: None,
+                    countries: Some(vec!["US".to_string()]),
+                    groups: None,
+                },
+            ],
+        };
+
+        let handle = GeoIpHandle::default();
+        let mut acl_manager = AclManager::with_geoip_handle(&config, handle.clone());
+        acl_manager.set_geoip_failure_policy(FailurePolicy::Closed);
+        let source_ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let target = TargetAddr::Domain("example.com".to_string());
+
+        let (_, reason, _) = acl_manager.check_access_with_code(&target, 80, source_ip);
+        assert!(reason.starts_with("GeoIP unavailable"));
+        assert!(!acl_manager.has_geoip());
+
+        // The swapped-in database is used by the existing manager
+        handle.swap(Some(GeoIpFilter::new(GeoIpReader::disabled())));
+        assert!(acl_manager.has_geoip());
+        let (allowed, reason, code) = acl_manager.check_access_with_code(&target, 80, source_ip);
+        assert!(!allowed);
+        assert!(reason.starts_with("Country not in allowlist"));
+        assert_eq!(code, BlockReason::Geo);
+    }
+}
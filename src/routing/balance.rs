@@ -0,0 +1,198 @@
+//! Upstream Load Balancing
+//!
+//! Picks one of the upstreams a connection may use when no routing rule
+//! names one, according to `routing.load_balancing`. State shared between
+//! connections (the rotation position, active relays per upstream and
+//! recent connect latencies) lives here, since routers are built per
+//! connection.
+
+use crate::config::{LoadBalancingStrategy, RoutingConfig, UpstreamProxyConfig};
+use crate::relay::{RelayControl, RelayObserver, RelaySession};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Weight of a new latency sample in the moving average
+const LATENCY_SMOOTHING: f64 = 0.3;
+
+/// Shared state for choosing between upstream proxies
+pub struct UpstreamBalancer {
+    strategy: LoadBalancingStrategy,
+    names: HashMap<SocketAddr, String>,
+    next: AtomicUsize,
+    active: Mutex<HashMap<String, usize>>,
+    latency: Mutex<HashMap<String, Duration>>,
+}
+
+impl UpstreamBalancer {
+    /// Create a balancer for the configured upstreams
+    pub fn new(config: &RoutingConfig) -> Self {
+        Self {
+            strategy: config.load_balancing,
+            names: config.upstream_proxies.iter()
+                .map(|upstream| (upstream.addr, upstream.name.clone()))
+                .collect(),
+            next: AtomicUsize::new(0),
+            active: Mutex::new(HashMap::new()),
+            latency: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Configured strategy
+    pub fn strategy(&self) -> LoadBalancingStrategy {
+        self.strategy
+    }
+
+    /// Whether picks depend on the relays each upstream carries
+    pub fn tracks_connections(&self) -> bool {
+        self.strategy == LoadBalancingStrategy::LeastConnections
+    }
+
+    /// Pick one of `candidates`, which are in configuration order
+    pub fn pick<'a>(&self, candidates: &[&'a UpstreamProxyConfig]) -> Option<&'a UpstreamProxyConfig> {
+        if candidates.is_empty() {
+            return None;
+        }
+        let picked = match self.strategy {
+            LoadBalancingStrategy::First => candidates[0],
+            LoadBalancingStrategy::RoundRobin => {
+                candidates[self.next.fetch_add(1, Ordering::Relaxed) % candidates.len()]
+            }
+            LoadBalancingStrategy::Weighted => {
+                let total: u64 = candidates.iter().map(|u| u64::from(u.weight)).sum();
+                let mut slot = self.next.fetch_add(1, Ordering::Relaxed) as u64 % total.max(1);
+                candidates.iter()
+                    .find(|u| {
+                        let hit = slot < u64::from(u.weight);
+                        slot = slot.saturating_sub(u64::from(u.weight));
+                        hit
+                    })
+                    .copied()
+                    .unwrap_or(candidates[0])
+            }
+            LoadBalancingStrategy::LeastConnections => {
+                let active = self.active.lock().unwrap();
+                candidates.iter()
+                    .min_by_key(|u| active.get(&u.name).copied().unwrap_or(0))
+                    .copied()
+                    .unwrap_or(candidates[0])
+            }
+            LoadBalancingStrategy::Latency => {
+                // Unmeasured upstreams go first so every one gets a sample
+                let latency = self.latency.lock().unwrap();
+                candidates.iter()
+                    .min_by_key(|u| latency.get(&u.name).copied().unwrap_or(Duration::ZERO))
+                    .copied()
+                    .unwrap_or(candidates[0])
+            }
+        };
+        Some(picked)
+    }
+
+    /// Record how long connecting through the upstream at `addr` took
+    pub fn record_latency(&self, addr: SocketAddr, elapsed: Duration) {
+        let Some(name) = self.names.get(&addr) else {
+            return;
+        };
+        let mut latency = self.latency.lock().unwrap();
+        let average = latency.entry(name.clone()).or_insert(elapsed);
+        *average = average.mul_f64(1.0 - LATENCY_SMOOTHING) + elapsed.mul_f64(LATENCY_SMOOTHING);
+    }
+
+    /// Relays currently carried by the named upstream
+    pub fn active_connections(&self, name: &str) -> usize {
+        self.active.lock().unwrap().get(name).copied().unwrap_or(0)
+    }
+}
+
+impl RelayObserver for UpstreamBalancer {
+    fn on_start(&self, session: &RelaySession, _user_id: Option<&str>) {
+        let mut active = self.active.lock().unwrap();
+        for name in session.upstreams.iter().filter_map(|addr| self.names.get(addr)) {
+            *active.entry(name.clone()).or_insert(0) += 1;
+        }
+    }
+
+    fn on_progress(&self, _session: &RelaySession, _user_id: Option<&str>, _bytes_up: u64, _bytes_down: u64) -> RelayControl {
+        RelayControl::Continue
+    }
+
+    fn on_end(&self, session: &RelaySession) {
+        let mut active = self.active.lock().unwrap();
+        for name in session.upstreams.iter().filter_map(|addr| self.names.get(addr)) {
+            if let Some(count) = active.get_mut(name) {
+                *count = count.saturating_sub(1);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    fn routing_config(strategy: LoadBalancingStrategy) -> RoutingConfig {
+        let mut routing = Config::default().routing;
+        routing.load_balancing = strategy;
+        routing.upstream_proxies = [("a", 1080, 3), ("b", 1081, 1)].into_iter()
+            .map(|(name, port, weight)| UpstreamProxyConfig {
+                name: name.to_string(),
+                addr: SocketAddr::from(([192, 0, 2, 10], port)),
+                protocol: "socks5".to_string(),
+                auth: None,
+                monthly_cap_bytes: None,
+                cost_per_gb: None,
+                monthly_budget: None,
+                weight,
+                tunnel: None,
+            })
+            .collect();
+        routing
+    }
+
+    fn picks(balancer: &UpstreamBalancer, config: &RoutingConfig, count: usize) -> Vec<String> {
+        let candidates: Vec<_> = config.upstream_proxies.iter().collect();
+        (0..count).map(|_| balancer.pick(&candidates).unwrap().name.clone()).collect()
+    }
+
+    #[test]
+    fn test_rotating_strategies() {
+        let config = routing_config(LoadBalancingStrategy::First);
+        assert_eq!(picks(&UpstreamBalancer::new(&config), &config, 3), ["a", "a", "a"]);
+
+        let config = routing_config(LoadBalancingStrategy::RoundRobin);
+        assert_eq!(picks(&UpstreamBalancer::new(&config), &config, 4), ["a", "b", "a", "b"]);
+
+        let config = routing_config(LoadBalancingStrategy::Weighted);
+        assert_eq!(picks(&UpstreamBalancer::new(&config), &config, 8), ["a", "a", "a", "b", "a", "a", "a", "b"]);
+    }
+
+    #[test]
+    fn test_least_connections_follows_relays() {
+        let config = routing_config(LoadBalancingStrategy::LeastConnections);
+        let balancer = UpstreamBalancer::new(&config);
+        let client = SocketAddr::from(([198, 51, 100, 1], 5000));
+        let session = RelaySession::new("s1".to_string(), client, client)
+            .with_upstreams(vec![SocketAddr::from(([192, 0, 2, 10], 1080))]);
+
+        balancer.on_start(&session, None);
+        assert_eq!(balancer.active_connections("a"), 1);
+        assert_eq!(picks(&balancer, &config, 1), ["b"]);
+        balancer.on_end(&session);
+        assert_eq!(picks(&balancer, &config, 1), ["a"]);
+    }
+
+    #[test]
+    fn test_latency_prefers_fastest_measured() {
+        let config = routing_config(LoadBalancingStrategy::Latency);
+        let balancer = UpstreamBalancer::new(&config);
+        balancer.record_latency(SocketAddr::from(([192, 0, 2, 10], 1080)), Duration::from_millis(200));
+        // "b" has no sample yet, so it is tried first
+        assert_eq!(picks(&balancer, &config, 1), ["b"]);
+        balancer.record_latency(SocketAddr::from(([192, 0, 2, 10], 1081)), Duration::from_millis(400));
+        assert_eq!(picks(&balancer, &config, 1), ["a"]);
+    }
+}
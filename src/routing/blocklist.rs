@@ -0,0 +1,217 @@
+//! Domain Blocklists
+//!
+//! Third-party lists with hundreds of thousands of entries (hosts files,
+//! AdBlock filter lists, plain domain lists) are loaded into one trie keyed by
+//! domain label from the right, so checking a target costs a walk over its
+//! labels however long the lists are. Only whole-domain entries are taken:
+//! AdBlock rules with paths, wildcards, options or exceptions, and hosts
+//! entries for local names, are skipped.
+
+use crate::config::{BlocklistConfig, BlocklistFormat};
+use crate::protocol::TargetAddr;
+use crate::Result;
+use anyhow::Context;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use tracing::info;
+
+/// Names hosts files map for the local machine rather than to block
+const LOCAL_HOSTS: &[&str] = &[
+    "localhost",
+    "localhost.localdomain",
+    "local",
+    "broadcasthost",
+    "ip6-localhost",
+    "ip6-loopback",
+    "ip6-localnet",
+    "ip6-mcastprefix",
+    "ip6-allnodes",
+    "ip6-allrouters",
+    "ip6-allhosts",
+];
+
+/// What a blocklist entry covers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Coverage {
+    Exact,
+    /// The domain and every name under it
+    Subdomains,
+}
+
+#[derive(Debug, Default)]
+struct Node {
+    children: HashMap<Box<str>, Node>,
+    /// Entry ending at this label, with the index of the list it came from
+    entry: Option<(Coverage, usize)>,
+}
+
+/// Domains from every configured blocklist
+#[derive(Debug, Default)]
+pub struct Blocklist {
+    root: Node,
+    /// Paths of the loaded lists, indexed by entries
+    sources: Vec<String>,
+    len: usize,
+}
+
+impl Blocklist {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Load every list in `configs`
+    pub fn load(configs: &[BlocklistConfig]) -> Result<Self> {
+        let mut blocklist = Self::new();
+        for config in configs {
+            let text = std::fs::read_to_string(&config.path)
+                .with_context(|| format!("Failed to read blocklist {}", config.path.display()))?;
+            let added = blocklist.add_list(&config.path.display().to_string(), &text, config.format);
+            info!("Loaded {} domains from blocklist {}", added, config.path.display());
+        }
+        Ok(blocklist)
+    }
+
+    /// Add the entries of one list, returning how many were taken
+    pub fn add_list(&mut self, source: &str, text: &str, format: BlocklistFormat) -> usize {
+        let index = self.sources.len();
+        self.sources.push(source.to_string());
+        let mut added = 0;
+        for line in text.lines() {
+            for (domain, coverage) in parse_line(line, format) {
+                if let Some(domain) = normalize(domain) {
+                    self.insert(&domain, coverage, index);
+                    added += 1;
+                }
+            }
+        }
+        added
+    }
+
+    /// The list blocking `target`, if any; IP address targets are never blocked
+    pub fn matches(&self, target: &TargetAddr) -> Option<&str> {
+        let TargetAddr::Domain(domain) = target else {
+            return None;
+        };
+        let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+        let mut node = &self.root;
+        let mut labels = domain.rsplit('.').peekable();
+        while let Some(label) = labels.next() {
+            node = node.children.get(label)?;
+            match node.entry {
+                Some((Coverage::Subdomains, source)) => return Some(&self.sources[source]),
+                Some((Coverage::Exact, source)) if labels.peek().is_none() => return Some(&self.sources[source]),
+                _ => {}
+            }
+        }
+        None
+    }
+
+    /// Number of domains loaded
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn insert(&mut self, domain: &str, coverage: Coverage, source: usize) {
+        let mut node = &mut self.root;
+        for label in domain.rsplit('.') {
+            node = node.children.entry(label.into()).or_default();
+        }
+        match node.entry {
+            None => self.len += 1,
+            // A list blocking the whole subtree wins over an exact entry
+            Some((Coverage::Subdomains, _)) => return,
+            Some((Coverage::Exact, _)) if coverage == Coverage::Exact => return,
+            Some((Coverage::Exact, _)) => {}
+        }
+        node.entry = Some((coverage, source));
+    }
+}
+
+/// Domains a line of a list names
+fn parse_line(line: &str, format: BlocklistFormat) -> Vec<(&str, Coverage)> {
+    let line = line.trim();
+    match format {
+        BlocklistFormat::Hosts => {
+            let line = line.split('#').next().unwrap_or_default();
+            let mut fields = line.split_whitespace();
+            match fields.next().map(str::parse::<IpAddr>) {
+                Some(Ok(_)) => fields
+                    .filter(|name| !LOCAL_HOSTS.contains(&name.to_ascii_lowercase().as_str()))
+                    .map(|name| (name, Coverage::Exact))
+                    .collect(),
+                _ => Vec::new(),
+            }
+        }
+        BlocklistFormat::Adblock => {
+            let domain = line.strip_prefix("||")
+                .and_then(|rule| rule.strip_suffix('^').or_else(|| rule.strip_suffix("^|")));
+            domain.map(|domain| vec![(domain, Coverage::Subdomains)]).unwrap_or_default()
+        }
+        BlocklistFormat::Domains => {
+            let domain = line.split('#').next().unwrap_or_default().trim();
+            let domain = domain.strip_prefix("*.").or_else(|| domain.strip_prefix('.')).unwrap_or(domain);
+            if domain.is_empty() {
+                Vec::new()
+            } else {
+                vec![(domain, Coverage::Subdomains)]
+            }
+        }
+    }
+}
+
+/// Lowercase a domain, or `None` if it is not a plain domain name
+fn normalize(domain: &str) -> Option<String> {
+    let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+    let valid = !domain.is_empty()
+        && domain.parse::<IpAddr>().is_err()
+        && domain.split('.').all(|label| {
+            !label.is_empty() && label.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+        });
+    valid.then_some(domain)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn domain(name: &str) -> TargetAddr {
+        TargetAddr::Domain(name.to_string())
+    }
+
+    #[test]
+    fn test_parses_each_format() {
+        let mut blocklist = Blocklist::new();
+        let hosts = "# comment\n127.0.0.1 localhost\n0.0.0.0 ads.example.com tracker.example.net # inline\n";
+        assert_eq!(blocklist.add_list("hosts.txt", hosts, BlocklistFormat::Hosts), 2);
+        let adblock = "! Title: ads\n[Adblock Plus 2.0]\n||doubleclick.example^\n||cdn.example/ads.js\n@@||good.example^\n||opt.example^$third-party\n";
+        assert_eq!(blocklist.add_list("easylist.txt", adblock, BlocklistFormat::Adblock), 1);
+        let domains = "malware.example\n*.phish.example  # campaign\n\nnot a domain\n";
+        assert_eq!(blocklist.add_list("domains.txt", domains, BlocklistFormat::Domains), 2);
+        assert_eq!(blocklist.len(), 5);
+
+        // Hosts entries are exact, the other formats cover subdomains
+        assert_eq!(blocklist.matches(&domain("ADS.example.com.")), Some("hosts.txt"));
+        assert_eq!(blocklist.matches(&domain("x.ads.example.com")), None);
+        assert_eq!(blocklist.matches(&domain("example.com")), None);
+        assert_eq!(blocklist.matches(&domain("ad.doubleclick.example")), Some("easylist.txt"));
+        assert_eq!(blocklist.matches(&domain("phish.example")), Some("domains.txt"));
+        assert_eq!(blocklist.matches(&domain("a.b.malware.example")), Some("domains.txt"));
+        assert_eq!(blocklist.matches(&domain("localhost")), None);
+        assert_eq!(blocklist.matches(&domain("good.example")), None);
+        assert_eq!(blocklist.matches(&TargetAddr::Ipv4("0.0.0.0".parse().unwrap())), None);
+    }
+
+    #[test]
+    fn test_subdomain_entries_win_over_exact_ones() {
+        let mut blocklist = Blocklist::new();
+        blocklist.add_list("hosts", "0.0.0.0 example.org", BlocklistFormat::Hosts);
+        blocklist.add_list("domains", "example.org", BlocklistFormat::Domains);
+        blocklist.add_list("more-hosts", "0.0.0.0 example.org", BlocklistFormat::Hosts);
+        assert_eq!(blocklist.len(), 1);
+        assert_eq!(blocklist.matches(&domain("www.example.org")), Some("domains"));
+    }
+}
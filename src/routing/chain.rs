@@ -3,17 +3,101 @@
 //! Provides functionality to chain multiple proxies together, allowing traffic
 //! to be routed through a sequence of upstream proxies.
 
+use std::fmt;
 use std::net::SocketAddr;
+use std::sync::Arc;
 use std::time::Duration;
 use tokio::net::TcpStream;
 use tokio::time::timeout;
 use tracing::debug;
+use anyhow::bail;
 use base64::Engine;
 
+use crate::protocol::constants::{
+    SOCKS5_REPLY_ADDRESS_TYPE_NOT_SUPPORTED, SOCKS5_REPLY_CONNECTION_NOT_ALLOWED, SOCKS5_REPLY_GENERAL_FAILURE,
+    SOCKS5_REPLY_TTL_EXPIRED,
+};
 use crate::protocol::Socks5Handler;
 use crate::protocol::TargetAddr;
 use crate::Result;
-use crate::routing::{UpstreamProxy, ProxyProtocol, ProxyAuth};
+use crate::routing::{UpstreamPool, UpstreamProxy, ProxyProtocol, ProxyAuth};
+
+/// Longest HTTP CONNECT response head accepted from an upstream
+const MAX_HTTP_RESPONSE_HEAD: usize = 8192;
+
+/// Why an upstream proxy could not carry a connection
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UpstreamError {
+    /// The proxy could not be reached
+    Unreachable { proxy: SocketAddr, reason: String },
+    /// The proxy did not accept a connection in time
+    Timeout { proxy: SocketAddr },
+    /// The proxy refused our authentication method or credentials
+    AuthFailed { proxy: SocketAddr },
+    /// A SOCKS5 proxy could not connect onwards and replied with this code
+    Refused { proxy: SocketAddr, reply_code: u8 },
+    /// An HTTP proxy answered CONNECT with this status line
+    HttpRefused { proxy: SocketAddr, status: String },
+}
+
+impl UpstreamError {
+    /// SOCKS5 reply code to give the client
+    ///
+    /// Failures of the upstream itself are reported as general failures,
+    /// since the client cannot do anything about them. A SOCKS5 upstream's
+    /// own answer about the target is passed through unchanged.
+    pub fn reply_code(&self) -> u8 {
+        match self {
+            UpstreamError::Refused { reply_code, .. }
+                if (SOCKS5_REPLY_GENERAL_FAILURE..=SOCKS5_REPLY_ADDRESS_TYPE_NOT_SUPPORTED).contains(reply_code) =>
+            {
+                *reply_code
+            }
+            UpstreamError::Timeout { .. } => SOCKS5_REPLY_TTL_EXPIRED,
+            UpstreamError::HttpRefused { status, .. } if status.split_whitespace().nth(1) == Some("403") => {
+                SOCKS5_REPLY_CONNECTION_NOT_ALLOWED
+            }
+            _ => SOCKS5_REPLY_GENERAL_FAILURE,
+        }
+    }
+}
+
+impl fmt::Display for UpstreamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UpstreamError::Unreachable { proxy, reason } => write!(f, "Upstream proxy {} unreachable: {}", proxy, reason),
+            UpstreamError::Timeout { proxy } => write!(f, "Upstream proxy {} did not answer in time", proxy),
+            UpstreamError::AuthFailed { proxy } => write!(f, "Upstream proxy {} rejected our credentials", proxy),
+            UpstreamError::Refused { proxy, reply_code } => {
+                write!(f, "Upstream proxy {} could not reach the target (reply code {})", proxy, reply_code)
+            }
+            UpstreamError::HttpRefused { proxy, status } => write!(f, "Upstream proxy {} refused CONNECT: {}", proxy, status),
+        }
+    }
+}
+
+impl std::error::Error for UpstreamError {}
+
+/// Where in a chain of several upstreams a connection attempt failed
+///
+/// Attached as context to the hop's error, which stays reachable with
+/// `downcast_ref::<UpstreamError>()` when the proxy itself reported it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChainHop {
+    /// 1-based position of the failing proxy
+    pub hop: usize,
+    /// Number of proxies in the chain
+    pub hops: usize,
+    /// Address of the failing proxy
+    pub proxy: SocketAddr,
+}
+
+impl fmt::Display for ChainHop {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Proxy chain failed at hop {} of {} ({}), after {} hop(s) connected",
+               self.hop, self.hops, self.proxy, self.hop - 1)
+    }
+}
 
 /// Proxy chain configuration
 #[derive(Debug, Clone)]
@@ -27,210 +111,164 @@ pub struct ProxyChain {
 /// Proxy chain connector
 pub struct ProxyChainConnector {
     chain: ProxyChain,
+    pool: Option<Arc<UpstreamPool>>,
 }
 
 impl ProxyChainConnector {
     /// Create a new proxy chain connector
     pub fn new(chain: ProxyChain) -> Self {
-        Self { chain }
+        Self { chain, pool: None }
+    }
+
+    /// Take connections to the first proxy from `pool` when it has one ready
+    pub fn with_pool(mut self, pool: Arc<UpstreamPool>) -> Self {
+        self.pool = Some(pool);
+        self
     }
 
     /// Connect through the proxy chain to reach the target
+    ///
+    /// Each proxy is asked to connect onwards to the next one, and the last
+    /// to the target. Every hop gets its own `connection_timeout`. When a
+    /// chain of several proxies fails, the error carries a [`ChainHop`]
+    /// naming the hop that failed.
     pub async fn connect_through_chain(
         &self,
         target: &TargetAddr,
         port: u16,
     ) -> Result<TcpStream> {
-        if self.chain.proxies.is_empty() {
+        let proxies = &self.chain.proxies;
+        if proxies.is_empty() {
             return Err(anyhow::anyhow!("Proxy chain is empty"));
         }
 
-        debug!("Connecting through proxy chain with {} proxies", self.chain.proxies.len());
-
-        // Connect to the first proxy directly
-        let mut stream = self.connect_to_first_proxy().await?;
+        debug!("Connecting through proxy chain with {} proxies", proxies.len());
 
-        // Chain through intermediate proxies
-        for (i, proxy) in self.chain.proxies.iter().enumerate().skip(1) {
-            debug!("Chaining through proxy {} of {}: {}", i + 1, self.chain.proxies.len(), proxy.addr);
-            stream = self.chain_through_proxy(stream, proxy).await?;
+        // Ask each proxy to connect to the next one, and the last one to the target
+        let onwards = |i: usize| match proxies.get(i + 1) {
+            Some(next_proxy) => (TargetAddr::from_socket_addr(&next_proxy.addr), next_proxy.addr.port()),
+            None => (target.clone(), port),
+        };
+        let (next, next_port) = onwards(0);
+        debug!("Hop 1 of {}: asking {} to connect to {:?}:{}", proxies.len(), proxies[0].addr, next, next_port);
+        let mut stream = self.first_hop(&next, next_port).await
+            .map_err(|e| self.at_hop(0, e))?;
+
+        for (i, proxy) in proxies.iter().enumerate().skip(1) {
+            let (next, next_port) = onwards(i);
+            debug!("Hop {} of {}: asking {} to connect to {:?}:{}", i + 1, proxies.len(), proxy.addr, next, next_port);
+
+            let negotiated = async {
+                let stream = Self::greet(stream, proxy).await?;
+                Self::request(stream, proxy, &next, next_port).await
+            };
+            stream = match timeout(self.chain.connection_timeout, negotiated).await {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(e)) => return Err(self.at_hop(i, e)),
+                Err(_) => return Err(self.at_hop(i, UpstreamError::Timeout { proxy: proxy.addr }.into())),
+            };
         }
 
-        // Finally connect to the target through the last proxy
-        debug!("Connecting to final target: {:?}:{}", target, port);
-        self.connect_to_target_through_proxy(stream, target, port).await
-    }
-
-    /// Connect to the first proxy in the chain
-    async fn connect_to_first_proxy(&self) -> Result<TcpStream> {
-        let first_proxy = &self.chain.proxies[0];
-        debug!("Connecting to first proxy: {}", first_proxy.addr);
-
-        let stream = timeout(
-            self.chain.connection_timeout,
-            TcpStream::connect(first_proxy.addr)
-        ).await??;
-
-        debug!("Connected to first proxy: {}", first_proxy.addr);
+        debug!("Connected to final target {:?}:{} through the proxy chain", target, port);
         Ok(stream)
     }
 
-    /// Chain through an intermediate proxy
-    async fn chain_through_proxy(
-        &self,
-        stream: TcpStream,
-        proxy: &UpstreamProxy,
-    ) -> Result<TcpStream> {
-        match proxy.protocol {
-            ProxyProtocol::Socks5 => {
-                self.chain_through_socks5_proxy(stream, proxy).await
-            },
-            ProxyProtocol::Http => {
-                self.chain_through_http_proxy(stream, proxy).await
-            },
-        }
+    /// Number of proxies in the chain
+    pub fn hops(&self) -> usize {
+        self.chain.proxies.len()
     }
 
-    /// Chain through a SOCKS5 proxy
-    async fn chain_through_socks5_proxy(
-        &self,
-        stream: TcpStream,
-        proxy: &UpstreamProxy,
-    ) -> Result<TcpStream> {
-        debug!("Chaining through SOCKS5 proxy: {}", proxy.addr);
-
-        let mut handler = Socks5Handler::new(stream);
-
-        // Perform SOCKS5 handshake
-        let auth_method = if proxy.auth.is_some() {
-            0x02 // Username/password authentication
-        } else {
-            0x00 // No authentication
-        };
-
-        handler.send_greeting(&[auth_method]).await?;
-        let selected_method = handler.receive_auth_method().await?;
-
-        if selected_method != auth_method {
-            return Err(anyhow::anyhow!(
-                "SOCKS5 proxy rejected authentication method: expected {}, got {}",
-                auth_method, selected_method
-            ));
-        }
-
-        // Authenticate if required
-        if let Some(auth) = &proxy.auth {
-            handler.authenticate_username_password(&auth.username, &auth.password).await?;
+    /// Ask the first proxy in the chain to connect onwards to `target:port`
+    ///
+    /// A pooled connection is used when there is one. As the upstream may
+    /// have closed it just before it was taken, a pooled connection that
+    /// fails without the upstream saying why is replaced by a fresh one.
+    async fn first_hop(&self, target: &TargetAddr, port: u16) -> Result<TcpStream> {
+        let proxy = &self.chain.proxies[0];
+        if let Some(stream) = self.pool.as_ref().and_then(|pool| pool.take(proxy)) {
+            match timeout(self.chain.connection_timeout, Self::request(stream, proxy, target, port)).await {
+                Ok(Ok(stream)) => return Ok(stream),
+                Ok(Err(e)) if e.downcast_ref::<UpstreamError>().is_none() => {
+                    debug!("Pooled connection to {} failed ({}), connecting afresh", proxy.addr, e);
+                }
+                Ok(Err(e)) => return Err(e),
+                Err(_) => return Err(UpstreamError::Timeout { proxy: proxy.addr }.into()),
+            }
         }
 
-        // Get the next proxy address (or target if this is the last proxy)
-        let next_proxy_addr = if let Some(next_proxy) = self.get_next_proxy_after(proxy) {
-            TargetAddr::from_socket_addr(&next_proxy.addr)
-        } else {
-            return Err(anyhow::anyhow!("No next proxy found in chain"));
+        let stream = Self::connect_to_proxy(proxy, self.chain.connection_timeout).await?;
+        let negotiated = async {
+            let stream = Self::greet(stream, proxy).await?;
+            Self::request(stream, proxy, target, port).await
         };
-
-        // Send CONNECT request to next proxy
-        let next_port = if let Some(next_proxy) = self.get_next_proxy_after(proxy) {
-            next_proxy.addr.port()
-        } else {
-            return Err(anyhow::anyhow!("No next proxy found in chain"));
-        };
-        handler.send_connect_request(&next_proxy_addr, next_port).await?;
-        let response = handler.receive_connect_response().await?;
-
-        if response.reply_code != 0x00 {
-            return Err(anyhow::anyhow!(
-                "SOCKS5 proxy connection failed with reply code: {}",
-                response.reply_code
-            ));
+        match timeout(self.chain.connection_timeout, negotiated).await {
+            Ok(result) => result,
+            Err(_) => Err(UpstreamError::Timeout { proxy: proxy.addr }.into()),
         }
+    }
 
-        debug!("Successfully chained through SOCKS5 proxy: {}", proxy.addr);
-        Ok(handler.into_stream())
+    /// Open a connection to `proxy` that is ready for a CONNECT request
+    ///
+    /// This is how [`UpstreamPool`] fills itself: a SOCKS5 proxy has been
+    /// greeted and authenticated to, while an HTTP proxy, which takes its
+    /// credentials with each request, is only connected to.
+    pub async fn open_ready(proxy: &UpstreamProxy, connection_timeout: Duration) -> Result<TcpStream> {
+        let stream = Self::connect_to_proxy(proxy, connection_timeout).await?;
+        match timeout(connection_timeout, Self::greet(stream, proxy)).await {
+            Ok(result) => result,
+            Err(_) => Err(UpstreamError::Timeout { proxy: proxy.addr }.into()),
+        }
     }
 
-    /// Chain through an HTTP proxy
-    async fn chain_through_http_proxy(
-        &self,
-        mut stream: TcpStream,
-        proxy: &UpstreamProxy,
-    ) -> Result<TcpStream> {
-        debug!("Chaining through HTTP proxy: {}", proxy.addr);
+    /// Open a TCP connection to `proxy`
+    async fn connect_to_proxy(proxy: &UpstreamProxy, connection_timeout: Duration) -> Result<TcpStream> {
+        debug!("Connecting to proxy: {}", proxy.addr);
 
-        // Get the next proxy address
-        let next_proxy_addr = if let Some(next_proxy) = self.get_next_proxy_after(proxy) {
-            next_proxy.addr
-        } else {
-            return Err(anyhow::anyhow!("No next proxy found in chain"));
+        let addr = proxy.addr;
+        let stream = match timeout(connection_timeout, TcpStream::connect(addr)).await {
+            Ok(Ok(stream)) => stream,
+            Ok(Err(e)) => return Err(UpstreamError::Unreachable { proxy: addr, reason: e.to_string() }.into()),
+            Err(_) => return Err(UpstreamError::Timeout { proxy: addr }.into()),
         };
 
-        // Build HTTP CONNECT request
-        let mut request = format!(
-            "CONNECT {}:{} HTTP/1.1\r\nHost: {}:{}\r\n",
-            next_proxy_addr.ip(),
-            next_proxy_addr.port(),
-            next_proxy_addr.ip(),
-            next_proxy_addr.port()
-        );
+        debug!("Connected to proxy: {}", addr);
+        Ok(stream)
+    }
 
-        // Add authentication if required
-        if let Some(auth) = &proxy.auth {
-            let credentials = base64::engine::general_purpose::STANDARD.encode(format!("{}:{}", auth.username, auth.password));
-            request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", credentials));
+    /// Tag a failure with the hop it happened at, for chains of several proxies
+    fn at_hop(&self, index: usize, error: anyhow::Error) -> anyhow::Error {
+        let hops = self.chain.proxies.len();
+        if hops == 1 {
+            return error;
         }
+        error.context(ChainHop { hop: index + 1, hops, proxy: self.chain.proxies[index].addr })
+    }
 
-        request.push_str("\r\n");
-
-        // Send CONNECT request
-        use tokio::io::{AsyncWriteExt, AsyncReadExt};
-        stream.write_all(request.as_bytes()).await?;
-
-        // Read response
-        let mut response = vec![0u8; 1024];
-        let n = stream.read(&mut response).await?;
-        let response_str = String::from_utf8_lossy(&response[..n]);
-
-        // Check if connection was successful
-        if !response_str.starts_with("HTTP/1.1 200") && !response_str.starts_with("HTTP/1.0 200") {
-            return Err(anyhow::anyhow!(
-                "HTTP proxy connection failed: {}",
-                response_str.lines().next().unwrap_or("Unknown error")
-            ));
+    /// Get `proxy`, reached over `stream`, ready to take a CONNECT request
+    async fn greet(stream: TcpStream, proxy: &UpstreamProxy) -> Result<TcpStream> {
+        match proxy.protocol {
+            ProxyProtocol::Socks5 => Self::greet_socks5(stream, proxy).await,
+            ProxyProtocol::Http => Ok(stream),
+            ProxyProtocol::Tunnel(_) => bail!("Tunnel upstream {} cannot be part of a proxy chain", proxy.addr),
         }
-
-        debug!("Successfully chained through HTTP proxy: {}", proxy.addr);
-        Ok(stream)
     }
 
-    /// Connect to the final target through the last proxy in the chain
-    async fn connect_to_target_through_proxy(
-        &self,
+    /// Ask `proxy`, reached over a greeted `stream`, to connect onwards to `target:port`
+    async fn request(
         stream: TcpStream,
+        proxy: &UpstreamProxy,
         target: &TargetAddr,
         port: u16,
     ) -> Result<TcpStream> {
-        let last_proxy = self.chain.proxies.last().unwrap();
-        debug!("Connecting to target {:?}:{} through last proxy: {}", target, port, last_proxy.addr);
-
-        match last_proxy.protocol {
-            ProxyProtocol::Socks5 => {
-                self.connect_to_target_through_socks5(stream, last_proxy, target, port).await
-            },
-            ProxyProtocol::Http => {
-                self.connect_to_target_through_http(stream, last_proxy, target, port).await
-            },
+        match proxy.protocol {
+            ProxyProtocol::Socks5 => Self::request_socks5(stream, proxy, target, port).await,
+            ProxyProtocol::Http => Self::negotiate_http(stream, proxy, target, port).await,
+            ProxyProtocol::Tunnel(_) => bail!("Tunnel upstream {} cannot be part of a proxy chain", proxy.addr),
         }
     }
 
-    /// Connect to target through SOCKS5 proxy
-    async fn connect_to_target_through_socks5(
-        &self,
-        stream: TcpStream,
-        proxy: &UpstreamProxy,
-        target: &TargetAddr,
-        port: u16,
-    ) -> Result<TcpStream> {
+    /// Greet a SOCKS5 proxy and authenticate to it
+    async fn greet_socks5(stream: TcpStream, proxy: &UpstreamProxy) -> Result<TcpStream> {
         let mut handler = Socks5Handler::new(stream);
 
         // Perform SOCKS5 handshake
@@ -239,38 +277,49 @@ impl ProxyChainConnector {
         let selected_method = handler.receive_auth_method().await?;
 
         if selected_method != auth_method {
-            return Err(anyhow::anyhow!("SOCKS5 authentication method rejected"));
+            return Err(UpstreamError::AuthFailed { proxy: proxy.addr }.into());
         }
 
         // Authenticate if required
         if let Some(auth) = &proxy.auth {
-            handler.authenticate_username_password(&auth.username, &auth.password).await?;
+            handler.authenticate_username_password(&auth.username, &auth.password).await
+                .map_err(|_| UpstreamError::AuthFailed { proxy: proxy.addr })?;
         }
 
-        // Send CONNECT request to target
+        Ok(handler.into_stream())
+    }
+
+    /// Connect onwards through a greeted SOCKS5 proxy
+    async fn request_socks5(
+        stream: TcpStream,
+        proxy: &UpstreamProxy,
+        target: &TargetAddr,
+        port: u16,
+    ) -> Result<TcpStream> {
+        let mut handler = Socks5Handler::new(stream);
+
+        // Send CONNECT request onwards
         handler.send_connect_request(target, port).await?;
         let response = handler.receive_connect_response().await?;
 
         if response.reply_code != 0x00 {
-            return Err(anyhow::anyhow!(
-                "SOCKS5 connection to target failed with reply code: {}",
-                response.reply_code
-            ));
+            return Err(UpstreamError::Refused { proxy: proxy.addr, reply_code: response.reply_code }.into());
         }
 
-        debug!("Successfully connected to target through SOCKS5 proxy chain");
+        debug!("SOCKS5 proxy {} connected onwards", proxy.addr);
         Ok(handler.into_stream())
     }
 
-    /// Connect to target through HTTP proxy
-    async fn connect_to_target_through_http(
-        &self,
+    /// Connect onwards through an HTTP proxy
+    async fn negotiate_http(
         mut stream: TcpStream,
         proxy: &UpstreamProxy,
         target: &TargetAddr,
         port: u16,
     ) -> Result<TcpStream> {
-        // Build HTTP CONNECT request for target
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // Build HTTP CONNECT request
         let target_host = match target {
             TargetAddr::Ipv4(ip) => ip.to_string(),
             TargetAddr::Ipv6(ip) => format!("[{}]", ip),
@@ -289,41 +338,30 @@ impl ProxyChainConnector {
         }
 
         request.push_str("\r\n");
-
-        // Send CONNECT request
-        use tokio::io::{AsyncWriteExt, AsyncReadExt};
         stream.write_all(request.as_bytes()).await?;
 
-        // Read response
-        let mut response = vec![0u8; 1024];
-        let n = stream.read(&mut response).await?;
-        let response_str = String::from_utf8_lossy(&response[..n]);
+        // Read the response head only, so no tunneled bytes are swallowed
+        let mut response = Vec::new();
+        while !response.ends_with(b"\r\n\r\n") {
+            if response.len() >= MAX_HTTP_RESPONSE_HEAD {
+                return Err(UpstreamError::HttpRefused { proxy: proxy.addr, status: "Oversized response".to_string() }.into());
+            }
+            let byte = stream.read_u8().await?;
+            response.push(byte);
+        }
+        let response_str = String::from_utf8_lossy(&response);
 
         // Check if connection was successful
         if !response_str.starts_with("HTTP/1.1 200") && !response_str.starts_with("HTTP/1.0 200") {
-            return Err(anyhow::anyhow!(
-                "HTTP proxy connection to target failed: {}",
-                response_str.lines().next().unwrap_or("Unknown error")
-            ));
+            let status = response_str.lines().next().unwrap_or("Unknown error").to_string();
+            return Err(UpstreamError::HttpRefused { proxy: proxy.addr, status }.into());
         }
 
-        debug!("Successfully connected to target through HTTP proxy chain");
+        debug!("HTTP proxy {} connected onwards", proxy.addr);
         Ok(stream)
     }
-
-    /// Get the next proxy in the chain after the given proxy
-    fn get_next_proxy_after(&self, current_proxy: &UpstreamProxy) -> Option<&UpstreamProxy> {
-        let current_index = self.chain.proxies.iter().position(|p| {
-            p.addr == current_proxy.addr && p.protocol == current_proxy.protocol
-        })?;
-
-        self.chain.proxies.get(current_index + 1)
-    }
 }
 
-/// Helper trait to convert TargetAddr to/from SocketAddr
-
-
 /// Proxy chain builder for easier configuration
 pub struct ProxyChainBuilder {
     proxies: Vec<UpstreamProxy>,
@@ -425,6 +463,225 @@ mod tests {
         assert!(result.is_err());
     }
 
+    /// Single-connection SOCKS5 upstream that requires `user:pass`, answers
+    /// CONNECT with `reply_code`, and then echoes whatever it receives
+    async fn mock_upstream(reply_code: u8) -> SocketAddr {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut header = [0u8; 2];
+            stream.read_exact(&mut header).await.unwrap();
+            let mut methods = vec![0u8; header[1] as usize];
+            stream.read_exact(&mut methods).await.unwrap();
+            stream.write_all(&[0x05, 0x02]).await.unwrap();
+
+            let mut field = [0u8; 2];
+            stream.read_exact(&mut field).await.unwrap();
+            let mut username = vec![0u8; field[1] as usize];
+            stream.read_exact(&mut username).await.unwrap();
+            stream.read_exact(&mut field[..1]).await.unwrap();
+            let mut password = vec![0u8; field[0] as usize];
+            stream.read_exact(&mut password).await.unwrap();
+            let accepted = username == b"user" && password == b"pass";
+            stream.write_all(&[0x01, if accepted { 0x00 } else { 0x01 }]).await.unwrap();
+            if !accepted {
+                return;
+            }
+
+            let mut request = [0u8; 10];
+            stream.read_exact(&mut request).await.unwrap();
+            stream.write_all(&[0x05, reply_code, 0x00, 0x01, 0, 0, 0, 0, 0, 0]).await.unwrap();
+            let mut buf = [0u8; 64];
+            while let Ok(n) = stream.read(&mut buf).await {
+                if n == 0 || stream.write_all(&buf[..n]).await.is_err() {
+                    break;
+                }
+            }
+        });
+        addr
+    }
+
+    fn connector(upstream: SocketAddr, password: &str) -> ProxyChainConnector {
+        let chain = ProxyChainBuilder::new()
+            .add_socks5_proxy(upstream, Some(ProxyAuth {
+                username: "user".to_string(),
+                password: password.to_string(),
+            }))
+            .with_timeout(Duration::from_secs(2))
+            .build()
+            .unwrap();
+        ProxyChainConnector::new(chain)
+    }
+
+    #[tokio::test]
+    async fn test_connect_through_authenticated_upstream() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let upstream = mock_upstream(0x00).await;
+        let target = TargetAddr::Ipv4(Ipv4Addr::new(198, 51, 100, 1));
+        let mut stream = connector(upstream, "pass").connect_through_chain(&target, 443).await.unwrap();
+
+        stream.write_all(b"ping").await.unwrap();
+        let mut echoed = [0u8; 4];
+        stream.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"ping");
+    }
+
+    #[tokio::test]
+    async fn test_upstream_failures_map_to_reply_codes() {
+        use crate::protocol::constants::SOCKS5_REPLY_CONNECTION_REFUSED;
+
+        let target = TargetAddr::Ipv4(Ipv4Addr::new(198, 51, 100, 1));
+        let upstream_error = |e: anyhow::Error| e.downcast::<UpstreamError>().unwrap();
+
+        let upstream = mock_upstream(SOCKS5_REPLY_CONNECTION_REFUSED).await;
+        let refused = upstream_error(connector(upstream, "pass").connect_through_chain(&target, 443).await.unwrap_err());
+        assert_eq!(refused, UpstreamError::Refused { proxy: upstream, reply_code: SOCKS5_REPLY_CONNECTION_REFUSED });
+        assert_eq!(refused.reply_code(), SOCKS5_REPLY_CONNECTION_REFUSED);
+
+        let upstream = mock_upstream(0x00).await;
+        let denied = upstream_error(connector(upstream, "wrong").connect_through_chain(&target, 443).await.unwrap_err());
+        assert_eq!(denied, UpstreamError::AuthFailed { proxy: upstream });
+        assert_eq!(denied.reply_code(), SOCKS5_REPLY_GENERAL_FAILURE);
+    }
+
+    /// SOCKS5 upstream without authentication that actually connects onwards
+    /// to IPv4 targets, or answers `connection refused` when it cannot
+    async fn forwarding_upstream() -> SocketAddr {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut header = [0u8; 2];
+            stream.read_exact(&mut header).await.unwrap();
+            let mut methods = vec![0u8; header[1] as usize];
+            stream.read_exact(&mut methods).await.unwrap();
+            stream.write_all(&[0x05, 0x00]).await.unwrap();
+
+            let mut request = [0u8; 10];
+            stream.read_exact(&mut request).await.unwrap();
+            let next = SocketAddr::from((
+                [request[4], request[5], request[6], request[7]],
+                u16::from_be_bytes([request[8], request[9]]),
+            ));
+            let Ok(mut onward) = TcpStream::connect(next).await else {
+                let _ = stream.write_all(&[0x05, 0x05, 0x00, 0x01, 0, 0, 0, 0, 0, 0]).await;
+                return;
+            };
+            stream.write_all(&[0x05, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0]).await.unwrap();
+            let _ = tokio::io::copy_bidirectional(&mut stream, &mut onward).await;
+        });
+        addr
+    }
+
+    async fn echo_server() -> SocketAddr {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let (mut reader, mut writer) = stream.split();
+            let _ = tokio::io::copy(&mut reader, &mut writer).await;
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_connect_through_every_hop() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let target = echo_server().await;
+        let chain = ProxyChainBuilder::new()
+            .add_socks5_proxy(forwarding_upstream().await, None)
+            .add_socks5_proxy(forwarding_upstream().await, None)
+            .with_timeout(Duration::from_secs(2))
+            .build()
+            .unwrap();
+        let connector = ProxyChainConnector::new(chain);
+        assert_eq!(connector.hops(), 2);
+
+        let mut stream = connector.connect_through_chain(&TargetAddr::from_socket_addr(&target), target.port()).await.unwrap();
+        stream.write_all(b"ping").await.unwrap();
+        let mut echoed = [0u8; 4];
+        stream.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"ping");
+    }
+
+    #[tokio::test]
+    async fn test_chain_failure_names_the_hop() {
+        use crate::protocol::constants::SOCKS5_REPLY_CONNECTION_REFUSED;
+
+        // The second hop is not listening, so the first one refuses to reach it
+        let entry = forwarding_upstream().await;
+        let closed = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let chain = ProxyChainBuilder::new()
+            .add_socks5_proxy(entry, None)
+            .add_socks5_proxy(closed, None)
+            .with_timeout(Duration::from_secs(2))
+            .build()
+            .unwrap();
+
+        let target = TargetAddr::Ipv4(Ipv4Addr::new(198, 51, 100, 1));
+        let error = ProxyChainConnector::new(chain).connect_through_chain(&target, 443).await.unwrap_err();
+        assert_eq!(error.downcast_ref::<ChainHop>(), Some(&ChainHop { hop: 1, hops: 2, proxy: entry }));
+        assert_eq!(
+            error.downcast_ref::<UpstreamError>(),
+            Some(&UpstreamError::Refused { proxy: entry, reply_code: SOCKS5_REPLY_CONNECTION_REFUSED })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_failed_pooled_connection_is_replaced() {
+        use crate::config::UpstreamPoolConfig;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // Upstream that greets every connection but drops the first at its CONNECT,
+        // as if it had closed the pooled connection just as it was taken
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            for accepted in 0usize.. {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut greeting = [0u8; 3];
+                    stream.read_exact(&mut greeting).await.unwrap();
+                    stream.write_all(&[0x05, 0x00]).await.unwrap();
+                    let mut request = [0u8; 10];
+                    if stream.read_exact(&mut request).await.is_err() || accepted == 0 {
+                        return;
+                    }
+                    stream.write_all(&[0x05, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0]).await.unwrap();
+                    let _ = stream.read(&mut [0u8; 1]).await;
+                });
+            }
+        });
+
+        let config = UpstreamPoolConfig { enabled: true, max_pool_size: 1, idle_timeout: Duration::from_secs(30) };
+        let pool = Arc::new(UpstreamPool::new(config, Duration::from_secs(2)));
+        let chain = ProxyChainBuilder::new()
+            .add_socks5_proxy(upstream, None)
+            .with_timeout(Duration::from_secs(2))
+            .build()
+            .unwrap();
+        let proxy = chain.proxies[0].clone();
+        assert!(pool.take(&proxy).is_none());
+        while pool.ready(&proxy) == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let connector = ProxyChainConnector::new(chain).with_pool(pool.clone());
+        let target = TargetAddr::Ipv4(Ipv4Addr::new(198, 51, 100, 1));
+        connector.connect_through_chain(&target, 443).await.unwrap();
+        while pool.ready(&proxy) == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        connector.connect_through_chain(&target, 443).await.unwrap();
+    }
+
     #[test]
     fn test_target_addr_from_socket_addr() {
         let ipv4_addr = SocketAddr::new(std::net::IpAddr::V4(Ipv4Addr::new(192, 168, 1, 1)), 80);
@@ -0,0 +1,73 @@
+//! Egress Identity Templates
+//!
+//! Upstream providers often read per-connection options from the proxy
+//! credentials, e.g. `cust-{user}-country-{cc}`. Upstream usernames and
+//! passwords may use `{user}`, `{session}` and `{cc}`, which are filled in
+//! for each connection from the local login. Missing values render as an
+//! empty string; other braces are left untouched.
+
+use super::{ProxyAuth, UpstreamProxy};
+
+/// Per-connection values available to upstream credential templates
+#[derive(Debug, Clone, Copy, Default)]
+pub struct EgressContext<'a> {
+    /// Authenticated SOCKS username (`{user}`)
+    pub user: Option<&'a str>,
+    /// Local session ID (`{session}`)
+    pub session: Option<&'a str>,
+    /// Requested egress country code (`{cc}`)
+    pub country: Option<&'a str>,
+}
+
+impl EgressContext<'_> {
+    /// Fill the placeholders of a credential template
+    pub fn render(&self, template: &str) -> String {
+        if !template.contains('{') {
+            return template.to_string();
+        }
+        template
+            .replace("{user}", self.user.unwrap_or_default())
+            .replace("{session}", self.session.unwrap_or_default())
+            .replace("{cc}", self.country.unwrap_or_default())
+    }
+}
+
+impl UpstreamProxy {
+    /// Render this upstream's credentials for one connection
+    pub fn for_egress(mut self, context: &EgressContext<'_>) -> Self {
+        self.auth = self.auth.map(|auth| ProxyAuth {
+            username: context.render(&auth.username),
+            password: context.render(&auth.password),
+        });
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::routing::ProxyProtocol;
+    use std::net::SocketAddr;
+
+    #[test]
+    fn test_credentials_are_rendered_per_connection() {
+        let upstream = UpstreamProxy {
+            addr: SocketAddr::from(([192, 0, 2, 10], 1080)),
+            auth: Some(ProxyAuth {
+                username: "cust-{user}-country-{cc}".to_string(),
+                password: "s3cret-{session}".to_string(),
+            }),
+            protocol: ProxyProtocol::Socks5,
+        };
+
+        let context = EgressContext { user: Some("alice"), session: Some("sess42"), country: Some("de") };
+        let auth = upstream.clone().for_egress(&context).auth.unwrap();
+        assert_eq!(auth.username, "cust-alice-country-de");
+        assert_eq!(auth.password, "s3cret-sess42");
+
+        // Unknown placeholders stay, missing values are left empty
+        let anonymous = EgressContext::default();
+        assert_eq!(anonymous.render("cust-{user}-{zone}"), "cust--{zone}");
+        assert_eq!(upstream.for_egress(&anonymous).auth.unwrap().username, "cust--country-");
+    }
+}
@@ -2,10 +2,13 @@
 
 use std::net::IpAddr;
 use std::path::Path;
-use tracing::{debug, warn};
+use std::sync::{Arc, RwLock};
+use tracing::{debug, info, warn};
 
 #[cfg(feature = "geoip")]
 use maxminddb::{Reader, geoip2};
+#[cfg(feature = "geoip")]
+use tracing::error;
 
 /// GeoIP database reader for country-based filtering
 pub struct GeoIpReader {
@@ -88,6 +91,19 @@ impl GeoIpReader {
         }
     }
 
+    /// Build time of the loaded database, in seconds since the Unix epoch
+    pub fn build_epoch(&self) -> Option<u64> {
+        #[cfg(feature = "geoip")]
+        {
+            self.reader.as_ref().map(|reader| reader.metadata.build_epoch)
+        }
+        
+        #[cfg(not(feature = "geoip"))]
+        {
+            None
+        }
+    }
+
     /// Check if GeoIP is available
     pub fn is_available(&self) -> bool {
         #[cfg(feature = "geoip")]
@@ -158,6 +174,60 @@ impl GeoIpFilter {
     pub fn is_available(&self) -> bool {
         self.reader.is_available()
     }
+
+    /// Build time of the underlying database, in seconds since the Unix epoch
+    pub fn build_epoch(&self) -> Option<u64> {
+        self.reader.build_epoch()
+    }
+}
+
+/// Shared, swappable GeoIP filter
+///
+/// Clones share the same slot, so a database reload through any clone is
+/// seen by every ACL holding the handle. Lookups take a snapshot of the
+/// current filter, so a check that is already running keeps using the
+/// database it started with.
+#[derive(Clone, Default)]
+pub struct GeoIpHandle {
+    current: Arc<RwLock<Option<Arc<GeoIpFilter>>>>,
+}
+
+impl GeoIpHandle {
+    /// Create a handle holding the given filter
+    pub fn new(filter: Option<GeoIpFilter>) -> Self {
+        Self {
+            current: Arc::new(RwLock::new(filter.map(Arc::new))),
+        }
+    }
+
+    /// The filter in use right now
+    pub fn current(&self) -> Option<Arc<GeoIpFilter>> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Replace the filter, returning the previous one
+    pub fn swap(&self, filter: Option<GeoIpFilter>) -> Option<Arc<GeoIpFilter>> {
+        let mut current = self.current.write().unwrap();
+        std::mem::replace(&mut *current, filter.map(Arc::new))
+    }
+
+    /// Load a database from disk and swap it in
+    ///
+    /// The current filter stays in place if the new database cannot be read.
+    /// Returns the build epoch of the new database.
+    pub fn reload<P: AsRef<Path>>(&self, db_path: P) -> Result<Option<u64>, Box<dyn std::error::Error + Send + Sync>> {
+        let reader = GeoIpReader::new(db_path.as_ref())?;
+        let build_epoch = reader.build_epoch();
+        let previous = self.swap(Some(GeoIpFilter::new(reader)));
+        info!("Swapped GeoIP database {} (build epoch {:?}, previous {:?})",
+              db_path.as_ref().display(), build_epoch, previous.and_then(|filter| filter.build_epoch()));
+        Ok(build_epoch)
+    }
+
+    /// Build epoch of the database in use, if any
+    pub fn build_epoch(&self) -> Option<u64> {
+        self.current()?.build_epoch()
+    }
 }
 
 #[cfg(test)]
@@ -201,4 +271,20 @@ mod tests {
         // With disabled reader, country blocklist should not block (can't verify country)
         assert!(!filter.is_country_blocked(ip, &blocked_countries));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_handle_swap_is_shared_between_clones() {
+        let handle = GeoIpHandle::default();
+        let acl_view = handle.clone();
+        assert!(acl_view.current().is_none());
+
+        let previous = handle.swap(Some(GeoIpFilter::new(GeoIpReader::disabled())));
+        assert!(previous.is_none());
+        let snapshot = acl_view.current().expect("clone should see the new filter");
+
+        // An in-flight snapshot survives the filter being removed
+        assert!(handle.swap(None).is_some());
+        assert!(acl_view.current().is_none());
+        assert!(!snapshot.is_available());
+    }
+}
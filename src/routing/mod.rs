@@ -3,17 +3,35 @@
 //! Handles connection routing and access control.
 
 pub mod acl;
+pub mod balance;
+pub mod blocklist;
 pub mod chain;
+pub mod egress;
 pub mod geoip;
+pub mod pool;
+pub mod ports;
+pub mod resolver;
 pub mod router;
 pub mod rules;
+pub mod secure_dns;
 pub mod smart;
+pub mod sticky;
 pub mod types;
+pub mod usage;
 
 pub use acl::AclManager;
-pub use chain::{ProxyChain, ProxyChainConnector, ProxyChainBuilder};
-pub use geoip::{GeoIpReader, GeoIpFilter};
+pub use balance::UpstreamBalancer;
+pub use blocklist::Blocklist;
+pub use chain::{ChainHop, ProxyChain, ProxyChainConnector, ProxyChainBuilder, UpstreamError};
+pub use egress::EgressContext;
+pub use geoip::{GeoIpReader, GeoIpFilter, GeoIpHandle};
+pub use pool::UpstreamPool;
+pub use ports::PortRange;
+pub use resolver::{DnsCacheStats, Resolver};
+pub use secure_dns::{DnsServer, SecureDnsClient};
 pub use router::{Router, RoutingStats};
-pub use rules::{RoutingRulesEngine, RoutingRule, RoutingAction, Priority};
+pub use rules::{RoutingRulesEngine, RoutingRule, RoutingAction, Priority, RuleCheck, RuleExplanation, RuleHits, RuleMismatch, TimeRestriction};
 pub use smart::{SmartRoutingManager, SmartRoutingConfig, HealthStatus, HealthSummary, ProxyMetrics};
-pub use types::*;
\ No newline at end of file
+pub use sticky::{StickySession, StickySessionTable};
+pub use types::*;
+pub use usage::{UpstreamBudget, UpstreamUsageTracker};
\ No newline at end of file
@@ -0,0 +1,211 @@
+//! Upstream Connection Pool
+//!
+//! Every chained request pays for a TCP handshake with the first upstream
+//! and, for SOCKS5, a greeting and authentication round trip before its
+//! CONNECT can even be sent. The pool keeps a few connections to recently
+//! used upstreams that have already got that far, so a request only pays for
+//! its CONNECT. A taken connection is replaced in the background for as long
+//! as the upstream keeps being used, and connections left unused for
+//! `idle_timeout` are closed before the upstream drops them itself.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+use tokio::task::JoinHandle;
+use tracing::debug;
+
+use crate::config::UpstreamPoolConfig;
+use crate::routing::{ProxyChainConnector, UpstreamProxy};
+
+/// Ready connections to one upstream, with its credentials filled in
+#[derive(Default)]
+struct Upstream {
+    ready: Vec<(TcpStream, Instant)>,
+    /// Connections being opened to refill the pool
+    opening: usize,
+    last_used: Option<Instant>,
+}
+
+/// Pool of connections to upstream proxies that are ready for a CONNECT
+pub struct UpstreamPool {
+    config: UpstreamPoolConfig,
+    connection_timeout: Duration,
+    upstreams: Mutex<HashMap<UpstreamProxy, Upstream>>,
+}
+
+impl UpstreamPool {
+    /// Create an empty pool that opens connections within `connection_timeout`
+    pub fn new(config: UpstreamPoolConfig, connection_timeout: Duration) -> Self {
+        Self { config, connection_timeout, upstreams: Mutex::new(HashMap::new()) }
+    }
+
+    /// Take a ready connection to `proxy`, and top its pool up again
+    ///
+    /// Upstreams are pooled by address and credentials, so per-session
+    /// credentials never share connections. Returns `None` when no live
+    /// connection is ready, after starting to open some for next time.
+    pub fn take(self: &Arc<Self>, proxy: &UpstreamProxy) -> Option<TcpStream> {
+        let now = Instant::now();
+        let (stream, refill) = {
+            let mut upstreams = self.upstreams.lock().unwrap();
+            let upstream = upstreams.entry(proxy.clone()).or_default();
+            upstream.last_used = Some(now);
+            let mut stream = None;
+            while let Some((candidate, opened)) = upstream.ready.pop() {
+                if now.duration_since(opened) < self.config.idle_timeout && is_open(&candidate) {
+                    stream = Some(candidate);
+                    break;
+                }
+            }
+            let refill = self.config.max_pool_size.saturating_sub(upstream.ready.len() + upstream.opening);
+            upstream.opening += refill;
+            (stream, refill)
+        };
+
+        debug!("{} pooled connection to {}, opening {} more",
+               if stream.is_some() { "Took a" } else { "No" }, proxy.addr, refill);
+        for _ in 0..refill {
+            self.open(proxy.clone());
+        }
+        stream
+    }
+
+    /// Open a connection to `proxy` in the background and add it to the pool
+    fn open(self: &Arc<Self>, proxy: UpstreamProxy) {
+        let pool = Arc::clone(self);
+        tokio::spawn(async move {
+            let opened = ProxyChainConnector::open_ready(&proxy, pool.connection_timeout).await;
+            let mut upstreams = pool.upstreams.lock().unwrap();
+            let Some(upstream) = upstreams.get_mut(&proxy) else {
+                return;
+            };
+            upstream.opening -= 1;
+            match opened {
+                Ok(stream) if upstream.ready.len() < pool.config.max_pool_size => {
+                    upstream.ready.push((stream, Instant::now()));
+                }
+                Ok(_) => {}
+                Err(e) => debug!("Failed to open pooled connection to {}: {}", proxy.addr, e),
+            }
+        });
+    }
+
+    /// Close connections left unused for `idle_timeout`, and forget upstreams
+    /// no request has used for as long
+    pub fn prune(&self) {
+        let idle_timeout = self.config.idle_timeout;
+        let mut upstreams = self.upstreams.lock().unwrap();
+        for upstream in upstreams.values_mut() {
+            upstream.ready.retain(|(stream, opened)| opened.elapsed() < idle_timeout && is_open(stream));
+        }
+        upstreams.retain(|_, upstream| {
+            upstream.opening > 0 || upstream.last_used.is_some_and(|used| used.elapsed() < idle_timeout)
+        });
+    }
+
+    /// Number of ready connections to `proxy`
+    pub fn ready(&self, proxy: &UpstreamProxy) -> usize {
+        self.upstreams.lock().unwrap().get(proxy).map_or(0, |upstream| upstream.ready.len())
+    }
+
+    /// Prune the pool in the background until it is dropped
+    pub fn start_pruning(self: &Arc<Self>) -> JoinHandle<()> {
+        let pool = Arc::downgrade(self);
+        let period = self.config.idle_timeout / 2;
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(period);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                match pool.upgrade() {
+                    Some(pool) => pool.prune(),
+                    None => break,
+                }
+            }
+        })
+    }
+}
+
+/// Whether the upstream has left an idle connection open
+///
+/// Nothing is sent on a ready connection until its CONNECT, so anything
+/// readable means the upstream closed it or has broken the protocol.
+fn is_open(stream: &TcpStream) -> bool {
+    matches!(stream.try_read(&mut [0u8; 1]), Err(e) if e.kind() == std::io::ErrorKind::WouldBlock)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::routing::ProxyProtocol;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn pool(max_pool_size: usize, idle_timeout: Duration) -> Arc<UpstreamPool> {
+        let config = UpstreamPoolConfig { enabled: true, max_pool_size, idle_timeout };
+        Arc::new(UpstreamPool::new(config, Duration::from_secs(2)))
+    }
+
+    async fn wait_for_ready(pool: &UpstreamPool, proxy: &UpstreamProxy, count: usize) {
+        for _ in 0..100 {
+            if pool.ready(proxy) == count {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("Pool never had {} ready connections", count);
+    }
+
+    #[tokio::test]
+    async fn test_pool_refills_with_greeted_connections() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = UpstreamProxy { addr: listener.local_addr().unwrap(), auth: None, protocol: ProxyProtocol::Socks5 };
+        let (greeted_tx, mut greeted) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let greeted_tx = greeted_tx.clone();
+                tokio::spawn(async move {
+                    let mut greeting = [0u8; 3];
+                    stream.read_exact(&mut greeting).await.unwrap();
+                    stream.write_all(&[0x05, 0x00]).await.unwrap();
+                    greeted_tx.send(()).unwrap();
+                    let _ = stream.read(&mut [0u8; 1]).await;
+                });
+            }
+        });
+
+        let pool = pool(2, Duration::from_secs(30));
+        assert!(pool.take(&proxy).is_none());
+        wait_for_ready(&pool, &proxy, 2).await;
+        greeted.recv().await.unwrap();
+        greeted.recv().await.unwrap();
+
+        // Taking one opens a replacement
+        let _stream = pool.take(&proxy).unwrap();
+        wait_for_ready(&pool, &proxy, 2).await;
+
+        // Other credentials get connections of their own
+        let other = UpstreamProxy { auth: Some(crate::routing::ProxyAuth { username: "u".into(), password: "p".into() }), ..proxy.clone() };
+        assert_eq!(pool.ready(&other), 0);
+    }
+
+    #[tokio::test]
+    async fn test_pool_drops_closed_and_expired_connections() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = UpstreamProxy { addr: listener.local_addr().unwrap(), auth: None, protocol: ProxyProtocol::Http };
+        let pool = pool(1, Duration::from_millis(200));
+        assert!(pool.take(&proxy).is_none());
+        wait_for_ready(&pool, &proxy, 1).await;
+
+        // The upstream closes the connection while it waits in the pool
+        drop(listener.accept().await.unwrap());
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(pool.take(&proxy).is_none());
+        wait_for_ready(&pool, &proxy, 1).await;
+
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        pool.prune();
+        assert_eq!(pool.ready(&proxy), 0);
+    }
+}
@@ -0,0 +1,177 @@
+//! Rule Port Ranges
+//!
+//! Routing rules and ACL rules restrict the destination port with a list of
+//! entries, each a port number (`443`), an inclusive range (`"1024-65535"`),
+//! or a service name (`"https"`) from the well-known ports below.
+
+use serde::de::{self, Deserializer, Visitor};
+use serde::{Deserialize, Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+
+/// Service names rules may use in place of port numbers
+const SERVICES: &[(&str, u16)] = &[
+    ("ftp", 21),
+    ("ssh", 22),
+    ("telnet", 23),
+    ("smtp", 25),
+    ("dns", 53),
+    ("domain", 53),
+    ("http", 80),
+    ("pop3", 110),
+    ("ntp", 123),
+    ("imap", 143),
+    ("ldap", 389),
+    ("https", 443),
+    ("smtps", 465),
+    ("submission", 587),
+    ("ldaps", 636),
+    ("imaps", 993),
+    ("pop3s", 995),
+    ("socks", 1080),
+    ("mssql", 1433),
+    ("mysql", 3306),
+    ("rdp", 3389),
+    ("postgres", 5432),
+    ("postgresql", 5432),
+    ("vnc", 5900),
+    ("redis", 6379),
+    ("http-alt", 8080),
+    ("https-alt", 8443),
+];
+
+/// Port of a well-known service name
+pub fn service_port(name: &str) -> Option<u16> {
+    SERVICES.iter()
+        .find(|(service, _)| service.eq_ignore_ascii_case(name))
+        .map(|&(_, port)| port)
+}
+
+/// Inclusive range of destination ports; a single port is a range of one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl PortRange {
+    pub fn new(start: u16, end: u16) -> Self {
+        Self { start, end }
+    }
+
+    pub fn contains(&self, port: u16) -> bool {
+        (self.start..=self.end).contains(&port)
+    }
+}
+
+/// Whether any of `ranges` contains `port`
+pub fn ports_contain(ranges: &[PortRange], port: u16) -> bool {
+    ranges.iter().any(|range| range.contains(port))
+}
+
+impl From<u16> for PortRange {
+    fn from(port: u16) -> Self {
+        Self::new(port, port)
+    }
+}
+
+impl FromStr for PortRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let port = |part: &str| {
+            let part = part.trim();
+            part.parse::<u16>().ok()
+                .or_else(|| service_port(part))
+                .ok_or_else(|| format!("'{}' is not a port number or known service name", part))
+        };
+        match s.split_once('-').filter(|(start, _)| start.trim().parse::<u16>().is_ok()) {
+            Some((start, end)) => {
+                let (start, end) = (port(start)?, port(end)?);
+                if start > end {
+                    return Err(format!("Port range '{}' ends before it starts", s));
+                }
+                Ok(Self::new(start, end))
+            }
+            // Service names such as "http-alt" contain a dash too
+            None => port(s).map(Self::from),
+        }
+    }
+}
+
+impl fmt::Display for PortRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.start == self.end {
+            write!(f, "{}", self.start)
+        } else {
+            write!(f, "{}-{}", self.start, self.end)
+        }
+    }
+}
+
+impl Serialize for PortRange {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if self.start == self.end {
+            serializer.serialize_u16(self.start)
+        } else {
+            serializer.collect_str(self)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for PortRange {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct PortRangeVisitor;
+
+        impl Visitor<'_> for PortRangeVisitor {
+            type Value = PortRange;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str("a port number, a range such as \"1024-65535\", or a service name")
+            }
+
+            fn visit_u64<E: de::Error>(self, port: u64) -> Result<PortRange, E> {
+                u16::try_from(port)
+                    .map(PortRange::from)
+                    .map_err(|_| E::custom(format!("port {} is out of range", port)))
+            }
+
+            fn visit_i64<E: de::Error>(self, port: i64) -> Result<PortRange, E> {
+                u64::try_from(port)
+                    .map_err(|_| E::custom(format!("port {} is out of range", port)))
+                    .and_then(|port| self.visit_u64(port))
+            }
+
+            fn visit_str<E: de::Error>(self, s: &str) -> Result<PortRange, E> {
+                s.parse().map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_any(PortRangeVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parses_ports_ranges_and_services() {
+        assert_eq!("443".parse(), Ok(PortRange::from(443)));
+        assert_eq!("1024-65535".parse(), Ok(PortRange::new(1024, 65535)));
+        assert_eq!("HTTPS".parse(), Ok(PortRange::from(443)));
+        assert_eq!("http-alt".parse(), Ok(PortRange::from(8080)));
+        assert!("2000-1000".parse::<PortRange>().is_err());
+        assert!("gopher".parse::<PortRange>().is_err());
+        assert!("70000".parse::<PortRange>().is_err());
+
+        let ranges: Vec<PortRange> = toml::from_str::<toml::Value>(r#"ports = [22, "https", "8000-8999"]"#)
+            .unwrap()["ports"].clone().try_into().unwrap();
+        assert!(ports_contain(&ranges, 22));
+        assert!(ports_contain(&ranges, 443));
+        assert!(ports_contain(&ranges, 8500));
+        assert!(!ports_contain(&ranges, 80));
+        assert_eq!(serde_json::to_string(&ranges).unwrap(), r#"[22,443,"8000-8999"]"#);
+    }
+}
@@ -0,0 +1,287 @@
+//! Caching DNS Resolver
+//!
+//! Domain targets are resolved once and the answer is shared by every
+//! connection until it expires, so repeat destinations skip the lookup.
+//! Lookups go to the system resolver, or to encrypted DNS servers when
+//! `routing.secure_dns` lists any (see [`super::secure_dns`]).
+//! Answers live for the record TTL when the lookup reports one, or
+//! `default_ttl` when it does not (the system resolver never does), clamped
+//! to `min_ttl..=max_ttl`. Failed lookups are remembered for `negative_ttl`,
+//! so clients retrying a dead name do not hammer the resolver. Expired
+//! answers are swept by an `ExpiringMap`; when the cache is full and nothing
+//! is due, new answers are simply not cached.
+
+use super::secure_dns::SecureDnsClient;
+use crate::config::{DnsCacheConfig, RoutingConfig, SecureDnsConfig};
+use crate::expiring::ExpiringMap;
+use crate::metrics::Metrics;
+use crate::Result;
+use anyhow::anyhow;
+use serde::Serialize;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::lookup_host;
+use tracing::{debug, warn};
+
+/// What a lookup returned
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DnsAnswer {
+    pub addrs: Vec<IpAddr>,
+    /// Record TTL, when the lookup reports it
+    pub ttl: Option<Duration>,
+}
+
+#[derive(Debug, Clone)]
+enum CachedAnswer {
+    Found(Arc<[IpAddr]>),
+    Failed(String),
+}
+
+/// How a lookup was answered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheOutcome {
+    Hit,
+    /// A cached failure
+    NegativeHit,
+    Miss,
+}
+
+impl CacheOutcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CacheOutcome::Hit => "hit",
+            CacheOutcome::NegativeHit => "negative_hit",
+            CacheOutcome::Miss => "miss",
+        }
+    }
+}
+
+/// Lookup counters of a [`Resolver`]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct DnsCacheStats {
+    pub entries: usize,
+    pub hits: u64,
+    pub negative_hits: u64,
+    pub misses: u64,
+}
+
+/// DNS resolver with positive and negative caching
+pub struct Resolver {
+    config: DnsCacheConfig,
+    entries: Mutex<ExpiringMap<String, CachedAnswer>>,
+    hits: AtomicU64,
+    negative_hits: AtomicU64,
+    misses: AtomicU64,
+    metrics: Option<Arc<Metrics>>,
+    /// Encrypted DNS servers; none means the system resolver
+    secure_dns: Option<SecureDnsClient>,
+}
+
+impl Resolver {
+    pub fn new(config: &DnsCacheConfig) -> Self {
+        Self {
+            config: config.clone(),
+            entries: Mutex::new(ExpiringMap::new(config.max_ttl)),
+            hits: AtomicU64::new(0),
+            negative_hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            metrics: None,
+            secure_dns: None,
+        }
+    }
+
+    /// Resolver for the `routing` section: its cache and encrypted DNS servers
+    pub fn from_config(routing: &RoutingConfig) -> Self {
+        Self::new(&routing.dns_cache).with_secure_dns(&routing.secure_dns)
+    }
+
+    /// Resolve through encrypted DNS servers instead of the system resolver
+    ///
+    /// With no servers configured, or if the client cannot be built, the
+    /// system resolver stays in use.
+    pub fn with_secure_dns(mut self, config: &SecureDnsConfig) -> Self {
+        if config.servers.is_empty() {
+            return self;
+        }
+        match SecureDnsClient::new(config) {
+            Ok(client) => self.secure_dns = Some(client),
+            Err(e) => warn!("Encrypted DNS disabled, using the system resolver: {:#}", e),
+        }
+        self
+    }
+
+    /// Count cache hits and misses in the Prometheus metrics as well
+    pub fn with_metrics(mut self, metrics: Option<Arc<Metrics>>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Resolve a domain name to IP addresses, from the cache when possible
+    pub async fn resolve(&self, domain: &str) -> Result<Vec<IpAddr>> {
+        if !self.config.enabled {
+            return Ok(self.lookup(domain).await?.addrs);
+        }
+
+        let key = cache_key(domain);
+        match self.cached(&key) {
+            Some(CachedAnswer::Found(addrs)) => {
+                self.count(CacheOutcome::Hit);
+                return Ok(addrs.to_vec());
+            }
+            Some(CachedAnswer::Failed(error)) => {
+                self.count(CacheOutcome::NegativeHit);
+                return Err(anyhow!("{} (cached)", error));
+            }
+            None => self.count(CacheOutcome::Miss),
+        }
+
+        match self.lookup(&key).await {
+            Ok(answer) => {
+                let ttl = answer.ttl.unwrap_or(self.config.default_ttl)
+                    .clamp(self.config.min_ttl, self.config.max_ttl);
+                debug!("Caching {} addresses for {} for {:?}", answer.addrs.len(), domain, ttl);
+                self.store(key, CachedAnswer::Found(answer.addrs.clone().into()), ttl);
+                Ok(answer.addrs)
+            }
+            Err(e) => {
+                self.store(key, CachedAnswer::Failed(format!("{:#}", e)), self.config.negative_ttl);
+                Err(e)
+            }
+        }
+    }
+
+    /// Resolve a domain name to socket addresses for `port`
+    pub async fn resolve_socket_addrs(&self, domain: &str, port: u16) -> Result<Vec<SocketAddr>> {
+        Ok(self.resolve(domain).await?.into_iter().map(|ip| SocketAddr::new(ip, port)).collect())
+    }
+
+    pub fn stats(&self) -> DnsCacheStats {
+        DnsCacheStats {
+            entries: self.entries.lock().unwrap().len(),
+            hits: self.hits.load(Ordering::Relaxed),
+            negative_hits: self.negative_hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Forget every cached answer
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+
+    fn cached(&self, key: &String) -> Option<CachedAnswer> {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.expires_at(key) {
+            Some(expires) if expires > Instant::now() => entries.get(key).cloned(),
+            Some(_) => {
+                entries.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn store(&self, key: String, answer: CachedAnswer, ttl: Duration) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= self.config.max_entries && entries.get(&key).is_none() {
+            entries.expire(Instant::now(), |_, _| false);
+            if entries.len() >= self.config.max_entries {
+                return;
+            }
+        }
+        entries.insert_until(key, answer, Instant::now() + ttl);
+    }
+
+    async fn lookup(&self, domain: &str) -> Result<DnsAnswer> {
+        match &self.secure_dns {
+            Some(client) => client.lookup(domain).await,
+            None => system_lookup(domain).await,
+        }
+    }
+
+    fn count(&self, outcome: CacheOutcome) {
+        let counter = match outcome {
+            CacheOutcome::Hit => &self.hits,
+            CacheOutcome::NegativeHit => &self.negative_hits,
+            CacheOutcome::Miss => &self.misses,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        if let Some(metrics) = &self.metrics {
+            metrics.record_dns_lookup(outcome);
+        }
+    }
+}
+
+impl Default for Resolver {
+    fn default() -> Self {
+        Self::new(&DnsCacheConfig::default())
+    }
+}
+
+/// Names differ only in case and a trailing dot
+fn cache_key(domain: &str) -> String {
+    domain.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// Ask the system resolver
+async fn system_lookup(domain: &str) -> Result<DnsAnswer> {
+    let addrs: Vec<IpAddr> = lookup_host((domain, 0)).await
+        .map_err(|e| anyhow!("DNS resolution failed for {}: {}", domain, e))?
+        .map(|addr| addr.ip())
+        .collect();
+    if addrs.is_empty() {
+        return Err(anyhow!("DNS resolution returned no addresses for {}", domain));
+    }
+    Ok(DnsAnswer { addrs, ttl: None })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> DnsCacheConfig {
+        DnsCacheConfig {
+            negative_ttl: Duration::from_millis(20),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_caches_answers_by_normalized_name() {
+        let resolver = Resolver::new(&config());
+        let first = resolver.resolve("LOCALHOST.").await.unwrap();
+        assert!(!first.is_empty());
+        assert_eq!(resolver.resolve("localhost").await.unwrap(), first);
+
+        let stats = resolver.stats();
+        assert_eq!((stats.entries, stats.hits, stats.misses), (1, 1, 1));
+        assert_eq!(resolver.resolve_socket_addrs("localhost", 443).await.unwrap()[0].port(), 443);
+    }
+
+    #[tokio::test]
+    async fn test_failures_are_cached_for_negative_ttl() {
+        let resolver = Resolver::new(&config());
+        assert!(resolver.resolve("does-not-exist.invalid").await.is_err());
+        let cached = resolver.resolve("does-not-exist.invalid").await.unwrap_err();
+        assert!(cached.to_string().contains("(cached)"));
+        assert_eq!(resolver.stats().negative_hits, 1);
+
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        assert!(resolver.resolve("does-not-exist.invalid").await.is_err());
+        assert_eq!(resolver.stats().misses, 2);
+    }
+
+    #[tokio::test]
+    async fn test_full_cache_skips_new_answers() {
+        let resolver = Resolver::new(&DnsCacheConfig { max_entries: 1, ..config() });
+        resolver.resolve("localhost").await.unwrap();
+        assert!(resolver.resolve("does-not-exist.invalid").await.is_err());
+        assert_eq!(resolver.stats().entries, 1);
+
+        let disabled = Resolver::new(&DnsCacheConfig { enabled: false, ..config() });
+        disabled.resolve("localhost").await.unwrap();
+        assert_eq!(disabled.stats(), DnsCacheStats::default());
+    }
+}
@@ -1,85 +1,193 @@
 //! Connection Router
 
+use std::collections::HashMap;
 use std::net::{IpAddr, SocketAddr};
-use std::sync::Arc;
-use tokio::net::lookup_host;
-use tracing::{debug, warn, error};
+use std::sync::{Arc, RwLock, Weak};
+use tokio::task::JoinHandle;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::StreamExt;
+use tracing::{debug, info, warn, error};
 
-use crate::config::{Config, UpstreamProxyConfig, RoutingRuleConfig, RoutingActionConfig};
+use crate::config::{Config, ConfigChangeEvent, UpstreamProxyConfig, RoutingRuleConfig, RoutingActionConfig};
 use crate::Result;
+use crate::metrics::Metrics;
 use crate::protocol::TargetAddr;
-use super::{RouteDecision, UpstreamProxy, ProxyAuth, ProxyProtocol, AclManager, GeoIpReader, GeoIpFilter, RoutingRulesEngine, RoutingRule, RoutingAction, SmartRoutingManager, SmartRoutingConfig};
+use crate::relay::BandwidthLimit;
+use crate::security::BlockReason;
+use super::{Blocklist, RouteDecision, UpstreamProxy, ProxyAuth, ProxyProtocol, AclManager, GeoIpReader, GeoIpFilter, GeoIpHandle, Resolver, RoutingRulesEngine, RoutingRule, RoutingAction, RuleExplanation, RuleHits, SmartRoutingManager, SmartRoutingConfig, StickySessionTable, UpstreamBalancer, UpstreamBudget, UpstreamUsageTracker};
 
 
 
+/// Routing rules and the configuration they were built from, replaced as a
+/// unit when the configuration is reloaded
+#[derive(Clone)]
+struct RoutingTable {
+    config: Arc<Config>,
+    rules_engine: RoutingRulesEngine,
+}
+
 /// Handles routing decisions and access control
 pub struct Router {
-    config: Arc<Config>,
+    table: RwLock<Arc<RoutingTable>>,
     acl_manager: Option<AclManager>,
-    rules_engine: RoutingRulesEngine,
-    smart_routing: Option<SmartRoutingManager>,
+    smart_routing: Option<Arc<SmartRoutingManager>>,
+    sticky_sessions: Option<Arc<StickySessionTable>>,
+    upstream_usage: Option<Arc<UpstreamUsageTracker>>,
+    balancer: Option<Arc<UpstreamBalancer>>,
+    resolver: Arc<Resolver>,
 }
 
 impl Router {
     /// Create a new router with configuration
     pub fn new(config: Arc<Config>) -> Self {
         let acl_manager = if config.access_control.enabled {
-            Some(AclManager::new(&config.access_control))
+            let mut acl = AclManager::new(&config.access_control);
+            acl.set_geoip_failure_policy(config.security.failure_policies.geoip);
+            Some(acl)
         } else {
             None
         };
 
-        let mut rules_engine = RoutingRulesEngine::new();
-        
-        // Load routing rules from configuration
-        for rule_config in &config.routing.rules {
-            if let Ok(rule) = Self::config_to_routing_rule(rule_config) {
-                if let Err(e) = rules_engine.add_rule(rule) {
-                    warn!("Failed to add routing rule '{}': {}", rule_config.id, e);
-                }
-            }
-        }
-        
-        // Load upstream proxies
-        for upstream_config in &config.routing.upstream_proxies {
-            let upstream = Self::config_to_upstream_proxy(upstream_config);
-            rules_engine.add_upstream_proxy(upstream_config.name.clone(), upstream);
-        }
-
+        let resolver = Arc::new(Resolver::from_config(&config.routing));
+        let rules_engine = Self::build_rules_engine(&config, GeoIpHandle::default());
         Self {
-            config,
+            table: RwLock::new(Arc::new(RoutingTable { config, rules_engine })),
             acl_manager,
-            rules_engine,
             smart_routing: None,
+            sticky_sessions: None,
+            upstream_usage: None,
+            balancer: None,
+            resolver,
         }
     }
 
+    /// Keep connections on the upstream they were pinned to in a shared table
+    pub fn with_sticky_sessions(mut self, table: Arc<StickySessionTable>) -> Self {
+        self.sticky_sessions = Some(table);
+        self
+    }
+
+    /// Steer traffic away from upstreams that are near or past their monthly limits
+    pub fn with_upstream_usage(mut self, tracker: Arc<UpstreamUsageTracker>) -> Self {
+        self.upstream_usage = Some(tracker);
+        self
+    }
+
+    /// Pick upstreams by their measured health and latency
+    ///
+    /// The manager is given the configured upstream proxies, and is kept in
+    /// step with them when the configuration is reloaded.
+    pub fn with_smart_routing(mut self, smart_routing: Arc<SmartRoutingManager>) -> Self {
+        smart_routing.set_upstream_proxies(Self::upstream_proxies(&self.table().config));
+        self.smart_routing = Some(smart_routing);
+        self
+    }
+
+    /// Spread connections over upstreams with shared load-balancing state
+    pub fn with_load_balancer(mut self, balancer: Arc<UpstreamBalancer>) -> Self {
+        self.balancer = Some(balancer);
+        self
+    }
+
+    /// Resolve domains through a DNS cache shared with other connections
+    pub fn with_resolver(mut self, resolver: Arc<Resolver>) -> Self {
+        self.resolver = resolver;
+        self
+    }
+
+    /// Count routing rule matches in the Prometheus metrics
+    pub fn with_metrics(mut self, metrics: Option<Arc<Metrics>>) -> Self {
+        self.table_mut().rules_engine.set_metrics(metrics);
+        self
+    }
+
+    /// Create a router whose ACL reads GeoIP data from a shared handle
+    ///
+    /// Reloading the database through the handle takes effect for this
+    /// router immediately, without rebuilding it.
+    pub fn with_geoip_handle(config: Arc<Config>, handle: GeoIpHandle) -> Self {
+        let mut router = Self::new(Arc::clone(&config));
+        router.table_mut().rules_engine.set_geoip_handle(handle.clone());
+        if router.acl_manager.is_some() {
+            let mut acl = AclManager::with_geoip_handle(&config.access_control, handle);
+            acl.set_geoip_failure_policy(config.security.failure_policies.geoip);
+            router.acl_manager = Some(acl);
+        }
+        router
+    }
+
+    /// Handle for swapping the ACL's GeoIP database at runtime
+    pub fn geoip_handle(&self) -> Option<&GeoIpHandle> {
+        self.acl_manager.as_ref().map(AclManager::geoip_handle)
+    }
+
     /// Create a new router with GeoIP support
     pub fn with_geoip<P: AsRef<std::path::Path>>(
         config: Arc<Config>, 
         geoip_db_path: P
     ) -> std::result::Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        // Try to load GeoIP database, shared by the ACL and country-restricted routing rules
+        let geoip = match GeoIpReader::new(geoip_db_path) {
+            Ok(reader) => Some(GeoIpHandle::new(Some(GeoIpFilter::new(reader)))),
+            Err(e) => {
+                warn!("Failed to load GeoIP database, routing without GeoIP (failure policy: {}): {}",
+                      config.security.failure_policies.geoip, e);
+                None
+            }
+        };
         let acl_manager = if config.access_control.enabled {
-            // Try to load GeoIP database
-            match GeoIpReader::new(geoip_db_path) {
-                Ok(reader) => {
-                    let geoip_filter = GeoIpFilter::new(reader);
-                    Some(AclManager::with_geoip(&config.access_control, geoip_filter))
-                }
-                Err(e) => {
-                    warn!("Failed to load GeoIP database, using ACL without GeoIP: {}", e);
-                    Some(AclManager::new(&config.access_control))
+            match &geoip {
+                Some(handle) => Some(AclManager::with_geoip_handle(&config.access_control, handle.clone())),
+                None => {
+                    let mut acl = AclManager::new(&config.access_control);
+                    acl.set_geoip_failure_policy(config.security.failure_policies.geoip);
+                    Some(acl)
                 }
             }
         } else {
             None
         };
 
+        let resolver = Arc::new(Resolver::from_config(&config.routing));
+        let rules_engine = Self::build_rules_engine(&config, geoip.unwrap_or_default());
+        Ok(Self {
+            table: RwLock::new(Arc::new(RoutingTable { config, rules_engine })),
+            acl_manager,
+            smart_routing: None,
+            sticky_sessions: None,
+            upstream_usage: None,
+            balancer: None,
+            resolver,
+        })
+    }
+
+    /// Build the rules engine for a configuration's calendars, rules and upstream proxies
+    fn build_rules_engine(config: &Config, geoip: GeoIpHandle) -> RoutingRulesEngine {
         let mut rules_engine = RoutingRulesEngine::new();
+        rules_engine.set_geoip_handle(geoip);
+        
+        // Calendars first, so rules can refer to them
+        for (name, calendar) in &config.routing.calendars {
+            rules_engine.add_calendar(name.clone(), calendar.clone());
+        }
+        rules_engine.set_time_zone(config.routing.time_zone);
+        
+        // Bandwidth classes too, for throttle actions
+        for (name, limit) in &config.routing.bandwidth_classes {
+            rules_engine.add_bandwidth_class(name.clone(), *limit);
+        }
+        
+        // Blocklists are checked before any rule
+        if !config.routing.blocklists.is_empty() {
+            match Blocklist::load(&config.routing.blocklists) {
+                Ok(blocklist) => rules_engine.set_blocklist(Arc::new(blocklist)),
+                Err(e) => warn!("Routing blocklists not loaded: {:#}", e),
+            }
+        }
         
         // Load routing rules from configuration
         for rule_config in &config.routing.rules {
-            if let Ok(rule) = Self::config_to_routing_rule(rule_config) {
+            if let Ok(rule) = Self::config_to_routing_rule(rule_config, &config.routing.dscp_classes) {
                 if let Err(e) = rules_engine.add_rule(rule) {
                     warn!("Failed to add routing rule '{}': {}", rule_config.id, e);
                 }
@@ -91,15 +199,79 @@ impl Router {
             let upstream = Self::config_to_upstream_proxy(upstream_config);
             rules_engine.add_upstream_proxy(upstream_config.name.clone(), upstream);
         }
+        rules_engine
+    }
 
-        Ok(Self {
-            config,
-            acl_manager,
-            rules_engine,
-            smart_routing: None,
+    /// The routing rules in effect
+    fn table(&self) -> Arc<RoutingTable> {
+        Arc::clone(&self.table.read().unwrap())
+    }
+
+    fn table_mut(&mut self) -> &mut RoutingTable {
+        Arc::make_mut(self.table.get_mut().unwrap())
+    }
+
+    /// Replace the routing rules and upstream proxies with those of `config`
+    ///
+    /// Requests already being routed finish under the rules they started
+    /// with. Access control, smart routing, the resolver and the sticky,
+    /// usage and load-balancing state are kept, as are bandwidth classes
+    /// whose caps did not change.
+    pub fn reload(&self, config: Arc<Config>) {
+        let previous = self.table();
+        let mut rules_engine = Self::build_rules_engine(&config, previous.rules_engine.geoip_handle().clone());
+        rules_engine.carry_over_hits(&previous.rules_engine);
+        rules_engine.carry_over_bandwidth_classes(&previous.rules_engine);
+        if let Some(smart_routing) = &self.smart_routing {
+            smart_routing.set_upstream_proxies(Self::upstream_proxies(&config));
+        }
+        info!("Routing reloaded: {} rules, {} upstream proxies",
+              rules_engine.rule_count(), config.routing.upstream_proxies.len());
+        *self.table.write().unwrap() = Arc::new(RoutingTable { config, rules_engine });
+    }
+
+    /// Reload the routing rules whenever the configuration changes
+    ///
+    /// The task ends when the router is dropped or the changes stop.
+    pub fn watch(self: &Arc<Self>, mut changes: BroadcastStream<ConfigChangeEvent>) -> JoinHandle<()> {
+        let router: Weak<Self> = Arc::downgrade(self);
+        tokio::spawn(async move {
+            while let Some(change) = changes.next().await {
+                let Some(router) = router.upgrade() else {
+                    break;
+                };
+                match change {
+                    Ok(event) => router.reload(event.config),
+                    // Each event carries the whole configuration, so the next one catches up
+                    Err(e) => warn!("Missed configuration changes: {}", e),
+                }
+            }
         })
     }
 
+    /// Check a request against the routing rules in effect without routing it
+    ///
+    /// Only the rules engine is consulted: access control, upstream
+    /// selection and usage caps are not, and the rules apply only while
+    /// routing is enabled.
+    pub async fn explain_request(
+        &self,
+        target: &TargetAddr,
+        port: u16,
+        source_ip: IpAddr,
+        user: Option<&str>,
+        groups: &[String],
+    ) -> RuleExplanation {
+        let table = self.table();
+        let resolved_ip = match target {
+            TargetAddr::Domain(domain) if table.rules_engine.has_country_rules() => {
+                self.resolve_domain(domain).await.ok().and_then(|addrs| addrs.first().map(SocketAddr::ip))
+            }
+            _ => None,
+        };
+        table.rules_engine.explain_for_destination(target, resolved_ip, port, source_ip, user, groups)
+    }
+
     /// Make a routing decision for the given request
     pub async fn route_request(
         &self,
@@ -107,31 +279,108 @@ impl Router {
         port: u16,
         source_ip: IpAddr,
         user: Option<&str>,
+        groups: &[String],
+    ) -> RouteDecision {
+        self.decide(target, port, source_ip, user, groups, true).await
+    }
+
+    /// Make a routing decision for a single UDP datagram. Refusals are logged
+    /// at debug level only, since a client can trigger them at packet rate;
+    /// callers count them instead.
+    pub async fn route_datagram(
+        &self,
+        target: &TargetAddr,
+        port: u16,
+        source_ip: IpAddr,
+        user: Option<&str>,
+        groups: &[String],
+    ) -> RouteDecision {
+        self.decide(target, port, source_ip, user, groups, false).await
+    }
+
+    async fn decide(
+        &self,
+        target: &TargetAddr,
+        port: u16,
+        source_ip: IpAddr,
+        user: Option<&str>,
+        groups: &[String],
+        warn_on_refusal: bool,
     ) -> RouteDecision {
         debug!("Making routing decision for target: {:?}, port: {}, source: {}", target, port, source_ip);
 
         // Step 1: Check access control
         if let Some(acl) = &self.acl_manager {
-            let (allowed, reason) = acl.check_access(target, port, source_ip);
+            let (allowed, reason, code) = acl.check_access_for_groups(target, port, source_ip, groups);
             if !allowed {
-                warn!("Access denied for {}:{} from {}: {}", 
-                      self.target_to_string(target), port, source_ip, reason);
-                return RouteDecision::Block { reason };
+                if warn_on_refusal {
+                    warn!("Access denied [{}] for {}:{} from {}: {}", 
+                          code, self.target_to_string(target), port, source_ip, reason);
+                } else {
+                    debug!("Access denied [{}] for {}:{} from {}: {}", 
+                           code, self.target_to_string(target), port, source_ip, reason);
+                }
+                return RouteDecision::Block { reason, code };
             }
             debug!("Access allowed for {}:{} from {}: {}", 
                    self.target_to_string(target), port, source_ip, reason);
         }
 
         // Step 2: Apply custom routing rules (if routing is enabled)
-        if self.config.routing.enabled {
-            let rules_decision = self.rules_engine.evaluate_rules(target, port, source_ip, user);
+        let table = self.table();
+        if table.config.routing.enabled {
+            // Country-restricted rules need to know where a domain points
+            let resolved_ip = match target {
+                TargetAddr::Domain(domain) if table.rules_engine.has_country_rules() => {
+                    self.resolve_domain(domain).await.ok().and_then(|addrs| addrs.first().map(SocketAddr::ip))
+                }
+                _ => None,
+            };
+            let rules_decision = table.rules_engine.evaluate_rules_for_destination(target, resolved_ip, port, source_ip, user, groups);
             
             // If rules engine made a decision other than default allow, use it
             match &rules_decision {
-                RouteDecision::Allow { upstream: None } => {
-                    // No specific rule matched, fall back to legacy upstream selection
-                    let upstream = self.select_upstream_proxy(target, port).await;
-                    RouteDecision::Allow { upstream }
+                RouteDecision::Allow { rule, upstream: None, dscp, bandwidth, bandwidth_class, transformers, dns_resolution, allow_private, traffic_class, egress_bind_addr, fwmark, .. } => {
+                    // No specific upstream chosen, fall back to legacy upstream selection
+                    let upstream = self.select_upstream_proxy(&table.config, target, port, source_ip, user).await;
+                    if upstream.is_none() && self.all_upstreams_exhausted(&table.config) {
+                        if warn_on_refusal {
+                            warn!("No upstream proxy with usage left for {}:{} from {}", self.target_to_string(target), port, source_ip);
+                        } else {
+                            debug!("No upstream proxy with usage left for {}:{} from {}", self.target_to_string(target), port, source_ip);
+                        }
+                        return RouteDecision::Block {
+                            reason: "All upstream proxies have used up their monthly limits".to_string(),
+                            code: BlockReason::Quota,
+                        };
+                    }
+                    RouteDecision::Allow {
+                        rule: rule.clone(),
+                        upstream,
+                        chain: Vec::new(),
+                        dscp: *dscp,
+                        bandwidth: *bandwidth,
+                        bandwidth_class: bandwidth_class.clone(),
+                        transformers: transformers.clone(),
+                        dns_resolution: *dns_resolution,
+                        allow_private: *allow_private,
+                        traffic_class: *traffic_class,
+                        egress_bind_addr: egress_bind_addr.clone(),
+                        fwmark: *fwmark,
+                    }
+                },
+                RouteDecision::Allow { upstream: Some(proxy), chain, .. }
+                    if std::iter::once(proxy).chain(chain).any(|hop| self.upstream_budget_at(hop.addr) == UpstreamBudget::Exhausted) =>
+                {
+                    if warn_on_refusal {
+                        warn!("An upstream proxy chosen by a routing rule has used up its monthly limit");
+                    } else {
+                        debug!("An upstream proxy chosen by a routing rule has used up its monthly limit");
+                    }
+                    RouteDecision::Block {
+                        reason: "An upstream proxy on the route has used up its monthly limit".to_string(),
+                        code: BlockReason::Quota,
+                    }
                 },
                 _ => {
                     // Rules engine made a specific decision (block, redirect, or proxy)
@@ -142,7 +391,7 @@ impl Router {
         } else {
             // Routing disabled, allow direct connection
             debug!("Routing disabled, allowing direct connection");
-            RouteDecision::Allow { upstream: None }
+            RouteDecision::Allow { rule: None, upstream: None, chain: Vec::new(), dscp: None, bandwidth: BandwidthLimit::default(), bandwidth_class: None, transformers: Vec::new(), dns_resolution: None, allow_private: false, traffic_class: None, egress_bind_addr: None, fwmark: None }
         }
     }
 
@@ -182,44 +431,125 @@ impl Router {
         debug!("Resolving domain: {}", domain);
         
         // Use a dummy port for resolution, caller should replace with actual port
-        let host_port = format!("{}:80", domain);
-        
-        match lookup_host(host_port).await {
-            Ok(addrs) => {
-                let resolved: Vec<SocketAddr> = addrs.collect();
+        match self.resolver.resolve_socket_addrs(domain, 80).await {
+            Ok(resolved) => {
                 debug!("Resolved {} to {} addresses", domain, resolved.len());
                 Ok(resolved)
             }
             Err(e) => {
-                error!("Failed to resolve domain {}: {}", domain, e);
-                Err(anyhow::anyhow!("DNS resolution failed for {}: {}", domain, e))
+                error!("Failed to resolve domain {}: {:#}", domain, e);
+                Err(e)
             }
         }
     }
 
     /// Select an upstream proxy for the given target (if any)
-    async fn select_upstream_proxy(&self, _target: &TargetAddr, _port: u16) -> Option<UpstreamProxy> {
+    async fn select_upstream_proxy(
+        &self,
+        config: &Config,
+        target: &TargetAddr,
+        port: u16,
+        source_ip: IpAddr,
+        user: Option<&str>,
+    ) -> Option<UpstreamProxy> {
+        let Some(sticky) = self.sticky_sessions.as_ref().filter(|table| table.is_enabled()) else {
+            return self.choose_upstream_proxy(config, target, port).await.map(|(_, proxy)| proxy);
+        };
+
+        // Keep pinned sessions on their upstream while it is still configured
+        let key = sticky.key_for(source_ip, user, target);
+        if let Some(name) = sticky.lookup(&key).filter(|name| self.upstream_budget(name) != UpstreamBudget::Exhausted) {
+            if let Some(upstream_config) = config.routing.upstream_proxies.iter().find(|u| u.name == name) {
+                debug!("Sticky session {} uses upstream proxy: {}", key, name);
+                return Some(Self::config_to_upstream_proxy(upstream_config));
+            }
+        }
+
+        let (name, proxy) = self.choose_upstream_proxy(config, target, port).await?;
+        sticky.pin(key, &name);
+        Some(proxy)
+    }
+
+    /// Pick an upstream proxy, returning its name along with it
+    ///
+    /// Upstreams near their monthly limit are only picked when no other
+    /// upstream is available, and exhausted ones never are. Among the rest,
+    /// `routing.load_balancing` decides.
+    async fn choose_upstream_proxy(&self, config: &Config, _target: &TargetAddr, _port: u16) -> Option<(String, UpstreamProxy)> {
+        let upstreams = &config.routing.upstream_proxies;
+        let names_at = |level: UpstreamBudget| -> Vec<String> {
+            upstreams.iter()
+                .filter(|u| self.upstream_budget(&u.name) >= level)
+                .map(|u| u.name.clone())
+                .collect()
+        };
+
         // Use smart routing if available
         if let Some(smart_routing) = &self.smart_routing {
-            if let Some((proxy_id, proxy)) = smart_routing.select_best_proxy(&[]).await {
+            let exhausted = names_at(UpstreamBudget::Exhausted);
+            let mut choice = smart_routing.select_best_proxy(&names_at(UpstreamBudget::Approaching)).await;
+            if choice.is_none() {
+                choice = smart_routing.select_best_proxy(&exhausted).await;
+            }
+            if let Some((proxy_id, proxy)) = choice {
                 debug!("Smart routing selected upstream proxy: {}", proxy_id);
-                return Some(proxy);
+                return Some((proxy_id, proxy));
             }
         }
         
-        // Fallback to simple selection
-        if let Some(upstream_config) = self.config.routing.upstream_proxies.first() {
-            debug!("Selected upstream proxy (fallback): {}", upstream_config.name);
-            Some(Self::config_to_upstream_proxy(upstream_config))
+        // Balance over the upstreams with the most room left
+        let with_room = |level: UpstreamBudget| -> Vec<&UpstreamProxyConfig> {
+            upstreams.iter().filter(|u| self.upstream_budget(&u.name) == level).collect()
+        };
+        let mut candidates = with_room(UpstreamBudget::Available);
+        if candidates.is_empty() {
+            candidates = with_room(UpstreamBudget::Approaching);
+        }
+        let picked = match &self.balancer {
+            Some(balancer) => balancer.pick(&candidates),
+            None => candidates.first().copied(),
+        };
+        if let Some(upstream_config) = picked {
+            debug!("Selected upstream proxy (load balancing): {}", upstream_config.name);
+            Some((upstream_config.name.clone(), Self::config_to_upstream_proxy(upstream_config)))
         } else {
             debug!("No upstream proxies configured");
             None
         }
     }
 
+    /// Room an upstream has left this month (always available without usage tracking)
+    fn upstream_budget(&self, name: &str) -> UpstreamBudget {
+        match &self.upstream_usage {
+            Some(tracker) => tracker.budget(name),
+            None => UpstreamBudget::Available,
+        }
+    }
+
+    fn upstream_budget_at(&self, addr: SocketAddr) -> UpstreamBudget {
+        match self.upstream_usage.as_ref().and_then(|tracker| tracker.name_at(addr)) {
+            Some(name) => self.upstream_budget(name),
+            None => UpstreamBudget::Available,
+        }
+    }
+
+    /// Whether upstreams are configured but none of them may be used
+    fn all_upstreams_exhausted(&self, config: &Config) -> bool {
+        let upstreams = &config.routing.upstream_proxies;
+        !upstreams.is_empty() && upstreams.iter().all(|u| self.upstream_budget(&u.name) == UpstreamBudget::Exhausted)
+    }
+
     /// Convert routing rule configuration to RoutingRule
-    fn config_to_routing_rule(config: &RoutingRuleConfig) -> std::result::Result<RoutingRule, String> {
+    fn config_to_routing_rule(
+        config: &RoutingRuleConfig,
+        dscp_classes: &HashMap<String, u8>,
+    ) -> std::result::Result<RoutingRule, String> {
         let action = Self::config_to_routing_action(&config.action)?;
+        let dscp = match &config.dscp_class {
+            Some(class) => Some(*dscp_classes.get(class)
+                .ok_or_else(|| format!("Unknown DSCP class '{}'", class))?),
+            None => config.dscp,
+        };
         
         Ok(RoutingRule {
             id: config.id.clone(),
@@ -229,8 +559,18 @@ impl Router {
             ports: config.ports.clone(),
             source_ips: config.source_ips.clone(),
             users: config.users.clone(),
-            time_restrictions: None, // Not implemented yet
+            groups: config.groups.clone(),
+            countries: config.countries.clone(),
+            time_restrictions: config.time_restrictions.clone(),
             enabled: config.enabled,
+            dscp,
+            bandwidth: config.bandwidth,
+            transformers: config.transformers.clone(),
+            dns_resolution: config.dns_resolution,
+            allow_private: config.allow_private,
+            traffic_class: config.traffic_class,
+            egress_bind_addr: config.egress_bind_addr.clone(),
+            fwmark: config.fwmark,
         })
     }
 
@@ -250,9 +590,22 @@ impl Router {
             RoutingActionConfig::ProxyChain { upstream_ids } => Ok(RoutingAction::ProxyChain { 
                 upstream_ids: upstream_ids.clone() 
             }),
+            RoutingActionConfig::Throttle { class } => Ok(RoutingAction::Throttle { 
+                class: class.clone() 
+            }),
+            RoutingActionConfig::Rewrite { host } => Ok(RoutingAction::Rewrite { 
+                host: host.clone() 
+            }),
         }
     }
 
+    /// The configured upstream proxies by name
+    fn upstream_proxies(config: &Config) -> HashMap<String, UpstreamProxy> {
+        config.routing.upstream_proxies.iter()
+            .map(|upstream| (upstream.name.clone(), Self::config_to_upstream_proxy(upstream)))
+            .collect()
+    }
+
     /// Convert upstream proxy configuration to UpstreamProxy
     fn config_to_upstream_proxy(config: &UpstreamProxyConfig) -> UpstreamProxy {
         let auth = config.auth.as_ref().map(|auth_config| ProxyAuth {
@@ -263,6 +616,13 @@ impl Router {
         let protocol = match config.protocol.to_lowercase().as_str() {
             "socks5" => ProxyProtocol::Socks5,
             "http" => ProxyProtocol::Http,
+            "tunnel" => match &config.tunnel {
+                Some(tunnel) => ProxyProtocol::Tunnel(Box::new(tunnel.clone())),
+                None => {
+                    warn!("Tunnel upstream '{}' has no tunnel settings, defaulting to SOCKS5", config.name);
+                    ProxyProtocol::Socks5
+                }
+            },
             _ => {
                 warn!("Unknown proxy protocol '{}', defaulting to SOCKS5", config.protocol);
                 ProxyProtocol::Socks5
@@ -294,37 +654,43 @@ impl Router {
             default_policy: format!("{:?}", acl.get_default_policy()),
             rule_count: acl.get_rule_count(),
             geoip_enabled: acl.has_geoip(),
+            geoip_build_epoch: acl.geoip_build_epoch(),
         })
     }
 
     /// Check if routing is enabled
     pub fn is_routing_enabled(&self) -> bool {
-        self.config.routing.enabled
+        self.table().config.routing.enabled
     }
 
     /// Get the number of configured upstream proxies
     pub fn get_upstream_proxy_count(&self) -> usize {
-        self.config.routing.upstream_proxies.len()
+        self.table().config.routing.upstream_proxies.len()
     }
 
     /// Add a routing rule at runtime
     pub fn add_routing_rule(&mut self, rule: RoutingRule) -> std::result::Result<(), String> {
-        self.rules_engine.add_rule(rule)
+        self.table_mut().rules_engine.add_rule(rule)
     }
 
     /// Remove a routing rule by ID
     pub fn remove_routing_rule(&mut self, rule_id: &str) -> bool {
-        self.rules_engine.remove_rule(rule_id)
+        self.table_mut().rules_engine.remove_rule(rule_id)
     }
 
     /// Update a routing rule
     pub fn update_routing_rule(&mut self, rule: RoutingRule) -> std::result::Result<(), String> {
-        self.rules_engine.update_rule(rule)
+        self.table_mut().rules_engine.update_rule(rule)
     }
 
     /// Get all routing rules
-    pub fn get_routing_rules(&self) -> &[RoutingRule] {
-        self.rules_engine.get_rules()
+    pub fn get_routing_rules(&self) -> Vec<RoutingRule> {
+        self.table().rules_engine.get_rules().to_vec()
+    }
+
+    /// Match counts and last match times of the routing rules
+    pub fn get_rule_hits(&self) -> Vec<RuleHits> {
+        self.table().rules_engine.rule_hits()
     }
 
     /// Get routing rules statistics
@@ -335,11 +701,12 @@ impl Router {
             None
         };
 
+        let table = self.table();
         RoutingStats {
-            enabled: self.config.routing.enabled,
-            total_rules: self.rules_engine.rule_count(),
-            enabled_rules: self.rules_engine.enabled_rule_count(),
-            upstream_proxies: self.get_upstream_proxy_count(),
+            enabled: table.config.routing.enabled,
+            total_rules: table.rules_engine.rule_count(),
+            enabled_rules: table.rules_engine.enabled_rule_count(),
+            upstream_proxies: table.config.routing.upstream_proxies.len(),
             smart_routing_enabled: self.smart_routing.is_some(),
             health_summary,
         }
@@ -347,31 +714,35 @@ impl Router {
 
     /// Add an upstream proxy at runtime
     pub async fn add_upstream_proxy(&mut self, id: String, proxy: UpstreamProxy) {
-        self.rules_engine.add_upstream_proxy(id.clone(), proxy.clone());
+        self.table_mut().rules_engine.add_upstream_proxy(id.clone(), proxy.clone());
         
         // Also add to smart routing if enabled
-        if let Some(smart_routing) = &mut self.smart_routing {
+        if let Some(smart_routing) = &self.smart_routing {
             smart_routing.add_upstream_proxy(id, proxy).await;
         }
     }
 
     /// Enable smart routing with the given configuration
     pub async fn enable_smart_routing(&mut self, config: SmartRoutingConfig) {
-        let mut smart_routing = SmartRoutingManager::new(config);
+        let smart_routing = SmartRoutingManager::new(config);
         
         // Add existing upstream proxies to smart routing
-        for upstream_config in &self.config.routing.upstream_proxies {
+        for upstream_config in &self.table().config.routing.upstream_proxies {
             let upstream = Self::config_to_upstream_proxy(upstream_config);
             smart_routing.add_upstream_proxy(upstream_config.name.clone(), upstream).await;
         }
         
-        self.smart_routing = Some(smart_routing);
+        self.smart_routing = Some(Arc::new(smart_routing));
     }
 
     /// Start smart routing health checks (if enabled)
-    pub async fn start_smart_routing_health_checks(&self) {
-        if let Some(smart_routing) = &self.smart_routing {
-            smart_routing.start_health_checking().await;
+    ///
+    /// The checks stop once the router and everything sharing its smart
+    /// routing manager are dropped.
+    pub async fn start_smart_routing_health_checks(&self) -> Option<JoinHandle<()>> {
+        match &self.smart_routing {
+            Some(smart_routing) => Some(smart_routing.start_health_checking().await),
+            None => None,
         }
     }
 
@@ -382,6 +753,16 @@ impl Router {
         }
     }
 
+    /// Record how a connection through the upstream proxy at `addr` went, for smart routing
+    pub async fn record_upstream_result(&self, addr: SocketAddr, latency: std::time::Duration, success: bool) {
+        let Some(smart_routing) = &self.smart_routing else {
+            return;
+        };
+        if let Some(proxy_id) = smart_routing.proxy_id_at(addr) {
+            smart_routing.record_connection_result(&proxy_id, latency, success).await;
+        }
+    }
+
     /// Get smart routing health summary
     pub async fn get_smart_routing_health(&self) -> Option<super::HealthSummary> {
         if let Some(smart_routing) = &self.smart_routing {
@@ -422,4 +803,6 @@ pub struct AclStats {
     pub default_policy: String,
     pub rule_count: usize,
     pub geoip_enabled: bool,
+    /// Build time of the GeoIP database in use (seconds since the Unix epoch)
+    pub geoip_build_epoch: Option<u64>,
 }
\ No newline at end of file
@@ -4,12 +4,22 @@
 //! and support for domain-based blocking, allowing, and redirection.
 
 use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::collections::HashMap;
 use serde::{Deserialize, Serialize};
 use tracing::{debug, warn};
 
+use crate::config::DnsResolution;
+use crate::connection::EgressBind;
+use crate::metrics::Metrics;
 use crate::protocol::TargetAddr;
-use super::{RouteDecision, UpstreamProxy};
+use crate::relay::{BandwidthClass, BandwidthLimit, TrafficClass};
+use crate::schedule::{Calendar, Schedule, TimeOfDay, TimeZone, Weekday};
+use crate::security::BlockReason;
+use super::{Blocklist, GeoIpHandle, PortRange, RouteDecision, UpstreamProxy, MAX_DSCP};
+use super::ports::ports_contain;
 
 /// Priority level for routing rules (higher number = higher priority)
 pub type Priority = u32;
@@ -25,16 +35,47 @@ pub struct RoutingRule {
     pub pattern: String,
     /// Action to take when rule matches
     pub action: RoutingAction,
-    /// Optional port restrictions
-    pub ports: Option<Vec<u16>>,
+    /// Optional port restrictions: ports, ranges or service names
+    pub ports: Option<Vec<PortRange>>,
     /// Optional source IP restrictions
     pub source_ips: Option<Vec<String>>,
     /// Optional user restrictions
     pub users: Option<Vec<String>>,
-    /// Optional time-based restrictions (future enhancement)
+    /// Optional group restrictions; the rule matches members of any listed group
+    #[serde(default)]
+    pub groups: Option<Vec<String>>,
+    /// Optional destination country restrictions (ISO 3166 codes, via GeoIP)
+    #[serde(default)]
+    pub countries: Option<Vec<String>>,
+    /// Optional time-based restrictions
+    #[serde(default)]
     pub time_restrictions: Option<TimeRestriction>,
     /// Whether the rule is enabled
     pub enabled: bool,
+    /// DSCP value to mark outbound traffic with when the rule allows the connection
+    #[serde(default)]
+    pub dscp: Option<u8>,
+    /// Upload and download rate caps when the rule allows the connection
+    #[serde(default, flatten)]
+    pub bandwidth: BandwidthLimit,
+    /// Names of relay transformers to run on connections the rule allows
+    #[serde(default)]
+    pub transformers: Vec<String>,
+    /// Where domain targets are resolved when the rule sends the connection through an upstream
+    #[serde(default)]
+    pub dns_resolution: Option<DnsResolution>,
+    /// Whether connections the rule allows or rewrites may reach private and internal addresses
+    #[serde(default)]
+    pub allow_private: bool,
+    /// QoS traffic class of connections the rule allows
+    #[serde(default)]
+    pub traffic_class: Option<TrafficClass>,
+    /// Local address or interface direct connections the rule allows leave from
+    #[serde(default)]
+    pub egress_bind_addr: Option<EgressBind>,
+    /// Firewall mark set on direct connections the rule allows
+    #[serde(default)]
+    pub fwmark: Option<u32>,
 }
 
 /// Actions that can be taken when a routing rule matches
@@ -51,17 +92,66 @@ pub enum RoutingAction {
     Proxy { upstream_id: String },
     /// Route through multiple proxies in sequence (proxy chaining)
     ProxyChain { upstream_ids: Vec<String> },
+    /// Allow direct connection in a named bandwidth class, sharing its rate caps
+    Throttle { class: String },
+    /// Connect directly to another host name on the requested port; with a
+    /// regex pattern, `$1` or `${name}` in `host` expand to its captures
+    Rewrite { host: String },
 }
 
-/// Time-based restrictions for rules (future enhancement)
-#[derive(Debug, Clone, Deserialize, Serialize)]
+/// Time-based restrictions for rules
+///
+/// A rule with restrictions only matches while all of them hold: the raw
+/// window (on the listed days, in `time_zone` or else `routing.time_zone`)
+/// and the named calendar, if given.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
 pub struct TimeRestriction {
     /// Days of week (0=Sunday, 6=Saturday)
+    #[serde(default)]
     pub days: Option<Vec<u8>>,
     /// Start time (HH:MM format)
+    #[serde(default)]
     pub start_time: Option<String>,
     /// End time (HH:MM format)
+    #[serde(default)]
     pub end_time: Option<String>,
+    /// Time zone of the days and times, instead of `routing.time_zone`
+    #[serde(default)]
+    pub time_zone: Option<TimeZone>,
+    /// Name of a calendar in `routing.calendars`
+    #[serde(default)]
+    pub calendar: Option<String>,
+    /// Match while the calendar is closed instead of open
+    #[serde(default)]
+    pub outside_calendar: bool,
+}
+
+impl TimeRestriction {
+    /// The raw days and times as a weekly window in its time zone, if any are set
+    fn window(&self, default_zone: TimeZone) -> Result<Option<Calendar>, String> {
+        if self.days.is_none() && self.start_time.is_none() && self.end_time.is_none() {
+            return Ok(None);
+        }
+        let parse = |time: &Option<String>, default: &str| {
+            TimeOfDay::try_from(time.clone().unwrap_or_else(|| default.to_string()))
+                .map_err(|e| e.to_string())
+        };
+        const SUNDAY_FIRST: [Weekday; 7] = [
+            Weekday::Sun, Weekday::Mon, Weekday::Tue, Weekday::Wed,
+            Weekday::Thu, Weekday::Fri, Weekday::Sat,
+        ];
+        let days = self.days.iter().flatten()
+            .map(|day| SUNDAY_FIRST.get(*day as usize).copied()
+                .ok_or_else(|| format!("Day {} is out of range (0=Sunday to 6=Saturday)", day)))
+            .collect::<Result<Vec<_>, _>>()?;
+        let window = Schedule {
+            days,
+            start: parse(&self.start_time, "00:00")?,
+            end: parse(&self.end_time, "00:00")?,
+            utc_offset: Default::default(),
+        };
+        Ok(Some(self.time_zone.unwrap_or(default_zone).calendar(vec![window])))
+    }
 }
 
 /// Pattern matching types
@@ -81,7 +171,78 @@ pub enum PatternType {
     SubdomainWildcard(String),
 }
 
+/// Condition that kept a rule from matching a request
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleMismatch {
+    Disabled,
+    Port,
+    SourceIp,
+    User,
+    Time,
+    Pattern,
+    Country,
+}
+
+/// How one rule fared against a request
+#[derive(Debug, Clone, Serialize)]
+pub struct RuleCheck {
+    pub rule_id: String,
+    pub priority: Priority,
+    pub matched: bool,
+    /// First condition that failed, checked in the order the engine does
+    pub mismatch: Option<RuleMismatch>,
+}
+
+/// Every rule checked against a request, in priority order, and the decision
+///
+/// Rules after the deciding one are still checked, so rules shadowed by a
+/// higher-priority match show up as matched.
+#[derive(Debug, Clone)]
+pub struct RuleExplanation {
+    pub rules: Vec<RuleCheck>,
+    /// Blocklist the target is on, which decides before any rule
+    pub blocklist: Option<String>,
+    /// The first rule that matched, which made the decision
+    pub matched_rule: Option<String>,
+    pub decision: RouteDecision,
+}
+
+/// How often a rule has decided a request, and when it last did
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RuleHits {
+    pub rule_id: String,
+    pub matches: u64,
+    pub last_match: Option<SystemTime>,
+}
+
+/// Match counter of one rule, shared by copies of the engine
+#[derive(Debug, Default)]
+struct RuleHitCounter {
+    matches: AtomicU64,
+    /// Milliseconds since the Unix epoch; zero until the first match
+    last_match_ms: AtomicU64,
+}
+
+impl RuleHitCounter {
+    fn record(&self, now: SystemTime) {
+        self.matches.fetch_add(1, Ordering::Relaxed);
+        let ms = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+        self.last_match_ms.fetch_max(ms, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self, rule_id: &str) -> RuleHits {
+        let ms = self.last_match_ms.load(Ordering::Relaxed);
+        RuleHits {
+            rule_id: rule_id.to_string(),
+            matches: self.matches.load(Ordering::Relaxed),
+            last_match: (ms > 0).then(|| UNIX_EPOCH + Duration::from_millis(ms)),
+        }
+    }
+}
+
 /// Custom routing rules engine
+#[derive(Clone)]
 pub struct RoutingRulesEngine {
     /// Ordered list of rules (sorted by priority)
     rules: Vec<RoutingRule>,
@@ -89,6 +250,20 @@ pub struct RoutingRulesEngine {
     compiled_patterns: HashMap<String, PatternType>,
     /// Upstream proxy configurations
     upstream_proxies: HashMap<String, UpstreamProxy>,
+    /// GeoIP database for rules restricted to destination countries
+    geoip: GeoIpHandle,
+    /// Named calendars that time restrictions refer to
+    calendars: HashMap<String, Calendar>,
+    /// Time zone of raw time windows that do not name their own
+    time_zone: TimeZone,
+    /// Match counters by rule ID
+    hits: HashMap<String, Arc<RuleHitCounter>>,
+    /// Collector rule matches are also counted in
+    metrics: Option<Arc<Metrics>>,
+    /// Domains blocked before any rule is checked
+    blocklist: Option<Arc<Blocklist>>,
+    /// Bandwidth classes that throttle actions refer to
+    bandwidth_classes: HashMap<String, Arc<BandwidthClass>>,
 }
 
 impl RoutingRulesEngine {
@@ -98,9 +273,94 @@ impl RoutingRulesEngine {
             rules: Vec::new(),
             compiled_patterns: HashMap::new(),
             upstream_proxies: HashMap::new(),
+            geoip: GeoIpHandle::default(),
+            calendars: HashMap::new(),
+            time_zone: TimeZone::default(),
+            hits: HashMap::new(),
+            metrics: None,
+            blocklist: None,
+            bandwidth_classes: HashMap::new(),
+        }
+    }
+
+    /// Block domains on `blocklist` before checking any rule
+    pub fn set_blocklist(&mut self, blocklist: Arc<Blocklist>) {
+        self.blocklist = Some(blocklist);
+    }
+
+    /// Count rule matches in the Prometheus metrics as well
+    pub fn set_metrics(&mut self, metrics: Option<Arc<Metrics>>) {
+        self.metrics = metrics;
+    }
+
+    /// Keep counting where `previous` left off for rules both engines have
+    ///
+    /// Used when rules are reloaded, so unchanged rules keep their history.
+    pub fn carry_over_hits(&mut self, previous: &RoutingRulesEngine) {
+        for (rule_id, counter) in &mut self.hits {
+            if let Some(previous) = previous.hits.get(rule_id) {
+                *counter = Arc::clone(previous);
+            }
+        }
+        self.metrics = previous.metrics.clone();
+    }
+
+    /// Keep sharing the schedules of `previous`'s classes whose caps did not change
+    ///
+    /// Used when rules are reloaded, so connections still running in a class
+    /// and new ones keep sharing its cap.
+    pub fn carry_over_bandwidth_classes(&mut self, previous: &RoutingRulesEngine) {
+        for (name, class) in &mut self.bandwidth_classes {
+            if let Some(previous) = previous.bandwidth_classes.get(name).filter(|p| p.limit() == class.limit()) {
+                *class = Arc::clone(previous);
+            }
         }
     }
 
+    /// Match counts and last match times of every rule, in priority order
+    pub fn rule_hits(&self) -> Vec<RuleHits> {
+        self.rules.iter()
+            .map(|rule| match self.hits.get(&rule.id) {
+                Some(counter) => counter.snapshot(&rule.id),
+                None => RuleHits { rule_id: rule.id.clone(), matches: 0, last_match: None },
+            })
+            .collect()
+    }
+
+    /// Set the time zone of raw time windows that do not name their own
+    pub fn set_time_zone(&mut self, time_zone: TimeZone) {
+        self.time_zone = time_zone;
+    }
+
+    /// Add a named bandwidth class for throttle actions to refer to
+    pub fn add_bandwidth_class(&mut self, name: String, limit: BandwidthLimit) {
+        self.bandwidth_classes.insert(name.clone(), Arc::new(BandwidthClass::new(name.clone(), limit)));
+        debug!("Added bandwidth class: {}", name);
+    }
+
+    /// Add a named calendar for rules' time restrictions to refer to
+    pub fn add_calendar(&mut self, name: String, calendar: Calendar) {
+        self.calendars.insert(name.clone(), calendar);
+        debug!("Added routing calendar: {}", name);
+    }
+
+    /// Look up destination countries in the database behind `handle`
+    pub fn set_geoip_handle(&mut self, handle: GeoIpHandle) {
+        self.geoip = handle;
+    }
+
+    /// Handle of the database destination countries are looked up in
+    pub fn geoip_handle(&self) -> &GeoIpHandle {
+        &self.geoip
+    }
+
+    /// Whether any enabled rule is restricted to destination countries
+    ///
+    /// Domain targets have to be resolved before such rules can match.
+    pub fn has_country_rules(&self) -> bool {
+        self.rules.iter().any(|rule| rule.enabled && rule.countries.is_some())
+    }
+
     /// Add a routing rule
     pub fn add_rule(&mut self, rule: RoutingRule) -> Result<(), String> {
         // Validate the rule
@@ -109,6 +369,7 @@ impl RoutingRulesEngine {
         // Compile the pattern
         let pattern = self.compile_pattern(&rule.pattern)?;
         self.compiled_patterns.insert(rule.id.clone(), pattern);
+        self.hits.entry(rule.id.clone()).or_default();
         
         // Add the rule and maintain priority order
         self.rules.push(rule);
@@ -123,6 +384,7 @@ impl RoutingRulesEngine {
         if let Some(pos) = self.rules.iter().position(|r| r.id == rule_id) {
             self.rules.remove(pos);
             self.compiled_patterns.remove(rule_id);
+            self.hits.remove(rule_id);
             debug!("Removed routing rule: {}", rule_id);
             true
         } else {
@@ -132,8 +394,12 @@ impl RoutingRulesEngine {
 
     /// Update a routing rule
     pub fn update_rule(&mut self, rule: RoutingRule) -> Result<(), String> {
-        // Remove existing rule if it exists
+        // Remove existing rule if it exists, keeping its match count
+        let counter = self.hits.get(&rule.id).cloned();
         self.remove_rule(&rule.id);
+        if let Some(counter) = counter {
+            self.hits.insert(rule.id.clone(), counter);
+        }
         
         // Add the updated rule
         self.add_rule(rule)
@@ -152,72 +418,243 @@ impl RoutingRulesEngine {
         port: u16,
         source_ip: IpAddr,
         user: Option<&str>,
+    ) -> RouteDecision {
+        self.evaluate_rules_for_groups(target, port, source_ip, user, &[])
+    }
+
+    /// Evaluate routing rules for a user belonging to the given groups
+    pub fn evaluate_rules_for_groups(
+        &self,
+        target: &TargetAddr,
+        port: u16,
+        source_ip: IpAddr,
+        user: Option<&str>,
+        groups: &[String],
+    ) -> RouteDecision {
+        self.evaluate_rules_for_destination(target, None, port, source_ip, user, groups)
+    }
+
+    /// Evaluate routing rules, with the address a domain target resolved to
+    ///
+    /// Country-restricted rules look up the target itself when it is an IP
+    /// address and `resolved_ip` otherwise; without either they never match.
+    pub fn evaluate_rules_for_destination(
+        &self,
+        target: &TargetAddr,
+        resolved_ip: Option<IpAddr>,
+        port: u16,
+        source_ip: IpAddr,
+        user: Option<&str>,
+        groups: &[String],
     ) -> RouteDecision {
         debug!("Evaluating routing rules for target: {:?}, port: {}, source: {}", 
                target, port, source_ip);
 
+        if let Some(source) = self.blocklisted(target) {
+            debug!("Target {:?} is on blocklist {}", target, source);
+            return Self::blocked_by_list(source);
+        }
+
+        let country = if self.has_country_rules() {
+            self.destination_country(target, resolved_ip)
+        } else {
+            None
+        };
+
         // Check each rule in priority order
         for rule in &self.rules {
-            if !rule.enabled {
-                continue;
-            }
-
-            if self.matches_rule(rule, target, port, source_ip, user) {
+            let checked = self.check_rule(rule, target, port, source_ip, user, groups)
+                .and_then(|()| Self::check_country(rule, country.as_deref()));
+            if checked.is_ok() {
                 debug!("Rule '{}' matched, applying action: {:?}", rule.id, rule.action);
-                return self.apply_action(&rule.action, target, port);
+                self.record_match(rule);
+                return self.apply_action(rule, target, port);
             }
         }
 
         // No rules matched, allow direct connection
         debug!("No routing rules matched, allowing direct connection");
-        RouteDecision::Allow { upstream: None }
+        Self::direct()
+    }
+
+    /// Check every rule against a request without connecting anywhere
+    pub fn explain(
+        &self,
+        target: &TargetAddr,
+        port: u16,
+        source_ip: IpAddr,
+        user: Option<&str>,
+    ) -> RuleExplanation {
+        self.explain_for_destination(target, None, port, source_ip, user, &[])
+    }
+
+    /// Check every rule against a request, with the user's groups and the
+    /// address a domain target resolved to, as [`Self::evaluate_rules_for_destination`] would
+    pub fn explain_for_destination(
+        &self,
+        target: &TargetAddr,
+        resolved_ip: Option<IpAddr>,
+        port: u16,
+        source_ip: IpAddr,
+        user: Option<&str>,
+        groups: &[String],
+    ) -> RuleExplanation {
+        let country = if self.has_country_rules() {
+            self.destination_country(target, resolved_ip)
+        } else {
+            None
+        };
+
+        let mut decision = None;
+        let rules = self.rules.iter().map(|rule| {
+            let mismatch = self.check_rule(rule, target, port, source_ip, user, groups)
+                .and_then(|()| Self::check_country(rule, country.as_deref()))
+                .err();
+            if mismatch.is_none() && decision.is_none() {
+                decision = Some((rule.id.clone(), self.apply_action(rule, target, port)));
+            }
+            RuleCheck { rule_id: rule.id.clone(), priority: rule.priority, matched: mismatch.is_none(), mismatch }
+        }).collect();
+
+        let blocklist = self.blocklisted(target).map(str::to_string);
+        let (matched_rule, decision) = match (&blocklist, decision) {
+            (Some(source), _) => (None, Self::blocked_by_list(source)),
+            (None, Some((rule_id, decision))) => (Some(rule_id), decision),
+            (None, None) => (None, Self::direct()),
+        };
+        RuleExplanation { rules, blocklist, matched_rule, decision }
+    }
+
+    /// The blocklist `target` is on, if any
+    fn blocklisted(&self, target: &TargetAddr) -> Option<&str> {
+        self.blocklist.as_ref()?.matches(target)
+    }
+
+    fn blocked_by_list(source: &str) -> RouteDecision {
+        RouteDecision::Block { reason: format!("Domain is on blocklist {}", source), code: BlockReason::Acl }
     }
 
-    /// Check if a rule matches the given parameters
-    fn matches_rule(
+    fn record_match(&self, rule: &RoutingRule) {
+        if let Some(counter) = self.hits.get(&rule.id) {
+            counter.record(SystemTime::now());
+        }
+        if let Some(metrics) = &self.metrics {
+            metrics.record_rule_match(&rule.id);
+        }
+    }
+
+    /// Decision when no rule matches
+    fn direct() -> RouteDecision {
+        RouteDecision::Allow { rule: None, upstream: None, chain: Vec::new(), dscp: None, bandwidth: BandwidthLimit::default(), bandwidth_class: None, transformers: Vec::new(), dns_resolution: None, allow_private: false, traffic_class: None, egress_bind_addr: None, fwmark: None }
+    }
+
+    /// Country of the destination according to the GeoIP database
+    fn destination_country(&self, target: &TargetAddr, resolved_ip: Option<IpAddr>) -> Option<String> {
+        let ip = match target {
+            TargetAddr::Ipv4(ip) => IpAddr::V4(*ip),
+            TargetAddr::Ipv6(ip) => IpAddr::V6(*ip),
+            TargetAddr::Domain(_) => resolved_ip?,
+        };
+        let Some(geoip) = self.geoip.current() else {
+            debug!("No GeoIP database loaded, country-restricted routing rules cannot match");
+            return None;
+        };
+        geoip.get_country(ip)
+    }
+
+    /// Check if a rule matches the given parameters, or which condition failed
+    fn check_rule(
         &self,
         rule: &RoutingRule,
         target: &TargetAddr,
         port: u16,
         source_ip: IpAddr,
         user: Option<&str>,
-    ) -> bool {
+        groups: &[String],
+    ) -> Result<(), RuleMismatch> {
+        if !rule.enabled {
+            return Err(RuleMismatch::Disabled);
+        }
+
         // Check port restrictions
         if let Some(allowed_ports) = &rule.ports {
-            if !allowed_ports.contains(&port) {
-                return false;
+            if !ports_contain(allowed_ports, port) {
+                return Err(RuleMismatch::Port);
             }
         }
 
         // Check source IP restrictions
         if let Some(source_patterns) = &rule.source_ips {
             if !self.matches_source_ip(source_patterns, source_ip) {
-                return false;
+                return Err(RuleMismatch::SourceIp);
             }
         }
 
-        // Check user restrictions
-        if let Some(allowed_users) = &rule.users {
-            match user {
-                Some(u) if allowed_users.contains(&u.to_string()) => {},
-                Some(_) => return false, // User not in allowed list
-                None if !allowed_users.is_empty() => return false, // No user but rule requires one
-                None => {}, // No user and rule doesn't require one
-            }
+        // Check user and group restrictions; a rule listing both matches either
+        let user_allowed = rule.users.as_ref().map(|allowed_users| match user {
+            Some(u) => allowed_users.iter().any(|allowed| allowed == u),
+            None => allowed_users.is_empty(), // No user only passes if the rule doesn't require one
+        });
+        let group_allowed = rule.groups.as_ref()
+            .map(|allowed_groups| allowed_groups.iter().any(|group| groups.contains(group)));
+        match (user_allowed, group_allowed) {
+            (Some(false), Some(false)) | (Some(false), None) | (None, Some(false)) => return Err(RuleMismatch::User),
+            _ => {}
         }
 
-        // Check time restrictions (if implemented)
-        if let Some(_time_restriction) = &rule.time_restrictions {
-            // TODO: Implement time-based matching
-            // For now, assume time restrictions pass
+        // Check time restrictions
+        if let Some(restriction) = &rule.time_restrictions {
+            if !self.matches_time(restriction, SystemTime::now()) {
+                return Err(RuleMismatch::Time);
+            }
         }
 
         // Check pattern match
-        if let Some(pattern) = self.compiled_patterns.get(&rule.id) {
-            self.matches_pattern(pattern, target)
+        match self.compiled_patterns.get(&rule.id) {
+            Some(pattern) if self.matches_pattern(pattern, target) => {}
+            Some(_) => return Err(RuleMismatch::Pattern),
+            None => {
+                warn!("No compiled pattern found for rule: {}", rule.id);
+                return Err(RuleMismatch::Pattern);
+            }
+        }
+        Ok(())
+    }
+
+    /// Check whether time restrictions hold at the given time
+    fn matches_time(&self, restriction: &TimeRestriction, now: SystemTime) -> bool {
+        if let Ok(Some(window)) = restriction.window(self.time_zone) {
+            if !window.is_open(now) {
+                return false;
+            }
+        }
+        match &restriction.calendar {
+            Some(name) => match self.calendars.get(name) {
+                Some(calendar) => calendar.is_open(now) != restriction.outside_calendar,
+                None => {
+                    warn!("Routing calendar '{}' not found, time restriction does not match", name);
+                    false
+                }
+            },
+            None => true,
+        }
+    }
+
+    fn check_country(rule: &RoutingRule, country: Option<&str>) -> Result<(), RuleMismatch> {
+        if Self::matches_country(rule, country) {
+            Ok(())
         } else {
-            warn!("No compiled pattern found for rule: {}", rule.id);
-            false
+            Err(RuleMismatch::Country)
+        }
+    }
+
+    /// Check a rule's destination country restrictions; unknown countries never match
+    fn matches_country(rule: &RoutingRule, country: Option<&str>) -> bool {
+        match &rule.countries {
+            Some(countries) => country.is_some_and(|country| {
+                countries.iter().any(|allowed| allowed.eq_ignore_ascii_case(country))
+            }),
+            None => true,
         }
     }
 
@@ -300,43 +737,83 @@ impl RoutingRulesEngine {
     }
 
     /// Apply the action specified by a matching rule
-    fn apply_action(&self, action: &RoutingAction, _target: &TargetAddr, _port: u16) -> RouteDecision {
-        match action {
-            RoutingAction::Allow => RouteDecision::Allow { upstream: None },
+    fn apply_action(&self, rule: &RoutingRule, target: &TargetAddr, _port: u16) -> RouteDecision {
+        let allow = |upstream: Option<UpstreamProxy>, chain: Vec<UpstreamProxy>| RouteDecision::Allow {
+            rule: Some(rule.id.clone()),
+            upstream,
+            chain,
+            dscp: rule.dscp,
+            bandwidth: rule.bandwidth,
+            bandwidth_class: None,
+            transformers: rule.transformers.clone(),
+            dns_resolution: rule.dns_resolution,
+            allow_private: rule.allow_private,
+            traffic_class: rule.traffic_class,
+            egress_bind_addr: rule.egress_bind_addr.clone(),
+            fwmark: rule.fwmark,
+        };
+        match &rule.action {
+            RoutingAction::Allow => allow(None, Vec::new()),
             RoutingAction::Block { reason } => {
                 let block_reason = reason.clone().unwrap_or_else(|| "Blocked by routing rule".to_string());
-                RouteDecision::Block { reason: block_reason }
+                RouteDecision::Block { reason: block_reason, code: BlockReason::Acl }
             },
-            RoutingAction::Redirect { target } => RouteDecision::Redirect { target: *target },
+            RoutingAction::Redirect { target } => RouteDecision::Redirect { target: *target, rule: Some(rule.id.clone()) },
             RoutingAction::Proxy { upstream_id } => {
                 if let Some(upstream) = self.upstream_proxies.get(upstream_id) {
-                    RouteDecision::Allow { upstream: Some(upstream.clone()) }
+                    allow(Some(upstream.clone()), Vec::new())
                 } else {
                     warn!("Upstream proxy '{}' not found, allowing direct connection", upstream_id);
-                    RouteDecision::Allow { upstream: None }
+                    allow(None, Vec::new())
                 }
             },
             RoutingAction::ProxyChain { upstream_ids } => {
-                // Create a proxy chain from the upstream IDs
-                if upstream_ids.is_empty() {
-                    RouteDecision::Allow { upstream: None }
-                } else {
-                    // For now, we'll use the first proxy in the chain as the upstream
-                    // Full proxy chaining will be handled by the relay engine
-                    if let Some(first_id) = upstream_ids.first() {
-                        if let Some(upstream) = self.upstream_proxies.get(first_id) {
-                            // TODO: Store the full chain information for the relay engine
-                            RouteDecision::Allow { upstream: Some(upstream.clone()) }
-                        } else {
-                            warn!("First upstream proxy '{}' in chain not found", first_id);
-                            RouteDecision::Allow { upstream: None }
+                // Dial the first proxy, then each following one through the previous
+                let mut hops = Vec::with_capacity(upstream_ids.len());
+                for upstream_id in upstream_ids {
+                    match self.upstream_proxies.get(upstream_id) {
+                        Some(upstream) => hops.push(upstream.clone()),
+                        None => {
+                            warn!("Upstream proxy '{}' in chain not found, allowing direct connection", upstream_id);
+                            return allow(None, Vec::new());
                         }
-                    } else {
-                        RouteDecision::Allow { upstream: None }
                     }
                 }
+                if hops.is_empty() {
+                    return allow(None, Vec::new());
+                }
+                let first = hops.remove(0);
+                allow(Some(first), hops)
             },
+            RoutingAction::Throttle { class } => {
+                let mut decision = allow(None, Vec::new());
+                if let RouteDecision::Allow { bandwidth_class, .. } = &mut decision {
+                    *bandwidth_class = self.bandwidth_classes.get(class).cloned();
+                    if bandwidth_class.is_none() {
+                        warn!("Bandwidth class '{}' not found, allowing the connection unthrottled", class);
+                    }
+                }
+                decision
+            },
+            RoutingAction::Rewrite { host } => RouteDecision::Rewrite {
+                target: host_target(&self.rewrite_host(rule, host, target)),
+                rule: Some(rule.id.clone()),
+                allow_private: rule.allow_private,
+            },
+        }
+    }
+
+    /// Expand a rewrite host with the captures of the rule's regex pattern
+    fn rewrite_host(&self, rule: &RoutingRule, host: &str, target: &TargetAddr) -> String {
+        let Some(PatternType::Regex(regex)) = self.compiled_patterns.get(&rule.id) else {
+            return host.to_string();
+        };
+        let mut expanded = String::new();
+        match regex.captures(&target.to_string()) {
+            Some(captures) => captures.expand(host, &mut expanded),
+            None => expanded.push_str(host),
         }
+        expanded
     }
 
     /// Compile a pattern string into a PatternType for efficient matching
@@ -391,6 +868,27 @@ impl RoutingRulesEngine {
         // Validate pattern
         self.compile_pattern(&rule.pattern)?;
 
+        if let Some(countries) = &rule.countries {
+            if let Some(code) = countries.iter().find(|code| code.len() != 2 || !code.chars().all(|c| c.is_ascii_alphabetic())) {
+                return Err(format!("Country '{}' is not a two-letter ISO 3166 code", code));
+            }
+        }
+
+        if let Some(restriction) = &rule.time_restrictions {
+            restriction.window(self.time_zone)?;
+            if let Some(name) = &restriction.calendar {
+                if !self.calendars.contains_key(name) {
+                    return Err(format!("Unknown calendar '{}'", name));
+                }
+            }
+        }
+
+        if let Some(dscp) = rule.dscp {
+            if dscp > MAX_DSCP {
+                return Err(format!("DSCP value {} is out of range (0-{})", dscp, MAX_DSCP));
+            }
+        }
+
         // Validate action-specific requirements
         match &rule.action {
             RoutingAction::Proxy { upstream_id } => {
@@ -403,6 +901,17 @@ impl RoutingRulesEngine {
                     return Err("ProxyChain action requires at least one upstream_id".to_string());
                 }
             },
+            RoutingAction::Rewrite { host } => {
+                if host.is_empty() {
+                    return Err("Rewrite action requires a non-empty host".to_string());
+                }
+                if host.contains('$') && !rule.pattern.starts_with('^') {
+                    return Err("Rewrite host refers to captures, which need a regex pattern".to_string());
+                }
+            },
+            RoutingAction::Throttle { class } if !self.bandwidth_classes.contains_key(class) => {
+                return Err(format!("Unknown bandwidth class '{}'", class));
+            },
             _ => {}, // Other actions don't need validation
         }
 
@@ -433,6 +942,7 @@ impl RoutingRulesEngine {
     pub fn clear_rules(&mut self) {
         self.rules.clear();
         self.compiled_patterns.clear();
+        self.hits.clear();
         debug!("Cleared all routing rules");
     }
 }
@@ -443,10 +953,20 @@ impl Default for RoutingRulesEngine {
     }
 }
 
+/// Target for a rewritten host, which may also be an IP address
+fn host_target(host: &str) -> TargetAddr {
+    match host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => TargetAddr::Ipv4(ip),
+        Ok(IpAddr::V6(ip)) => TargetAddr::Ipv6(ip),
+        Err(_) => TargetAddr::Domain(host.to_string()),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use std::net::Ipv4Addr;
+    use crate::schedule::DstRule;
 
     #[test]
     fn test_exact_pattern_matching() {
@@ -460,8 +980,18 @@ mod tests {
             ports: None,
             source_ips: None,
             users: None,
+            groups: None,
+            countries: None,
+            dns_resolution: None,
+            allow_private: false,
+            traffic_class: None,
+            egress_bind_addr: None,
+            fwmark: None,
             time_restrictions: None,
             enabled: true,
+            dscp: None,
+            bandwidth: BandwidthLimit::default(),
+            transformers: Vec::new(),
         };
         
         engine.add_rule(rule).unwrap();
@@ -487,8 +1017,18 @@ mod tests {
             ports: None,
             source_ips: None,
             users: None,
+            groups: None,
+            countries: None,
+            dns_resolution: None,
+            allow_private: false,
+            traffic_class: None,
+            egress_bind_addr: None,
+            fwmark: None,
             time_restrictions: None,
             enabled: true,
+            dscp: None,
+            bandwidth: BandwidthLimit::default(),
+            transformers: Vec::new(),
         };
         
         engine.add_rule(rule).unwrap();
@@ -515,8 +1055,18 @@ mod tests {
             ports: None,
             source_ips: None,
             users: None,
+            groups: None,
+            countries: None,
+            dns_resolution: None,
+            allow_private: false,
+            traffic_class: None,
+            egress_bind_addr: None,
+            fwmark: None,
             time_restrictions: None,
             enabled: true,
+            dscp: None,
+            bandwidth: BandwidthLimit::default(),
+            transformers: Vec::new(),
         };
         
         // Add higher priority rule
@@ -528,8 +1078,18 @@ mod tests {
             ports: None,
             source_ips: None,
             users: None,
+            groups: None,
+            countries: None,
+            dns_resolution: None,
+            allow_private: false,
+            traffic_class: None,
+            egress_bind_addr: None,
+            fwmark: None,
             time_restrictions: None,
             enabled: true,
+            dscp: None,
+            bandwidth: BandwidthLimit::default(),
+            transformers: Vec::new(),
         };
         
         engine.add_rule(rule1).unwrap();
@@ -539,10 +1099,492 @@ mod tests {
         let decision = engine.evaluate_rules(&target, 80, IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), None);
         
         match decision {
-            RouteDecision::Block { reason } => {
+            RouteDecision::Block { reason, code } => {
                 assert_eq!(reason, "High priority block");
+                assert_eq!(code, BlockReason::Acl);
             },
             _ => panic!("Expected block decision from high priority rule"),
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_dscp_marking() {
+        let mut engine = RoutingRulesEngine::new();
+        
+        let rule = RoutingRule {
+            id: "bulk".to_string(),
+            priority: 100,
+            pattern: "*.example.com".to_string(),
+            action: RoutingAction::Allow,
+            ports: None,
+            source_ips: None,
+            users: None,
+            groups: None,
+            countries: None,
+            dns_resolution: None,
+            allow_private: false,
+            traffic_class: None,
+            egress_bind_addr: None,
+            fwmark: None,
+            time_restrictions: None,
+            enabled: true,
+            dscp: Some(8),
+            bandwidth: BandwidthLimit::default(),
+            transformers: Vec::new(),
+        };
+        engine.add_rule(rule.clone()).unwrap();
+        
+        let target = TargetAddr::Domain("cdn.example.com".to_string());
+        match engine.evaluate_rules(&target, 443, IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), None) {
+            RouteDecision::Allow { dscp, .. } => assert_eq!(dscp, Some(8)),
+            _ => panic!("Expected allow decision"),
+        }
+        
+        // Out-of-range codepoints are rejected
+        let invalid = RoutingRule { id: "invalid".to_string(), dscp: Some(64), ..rule };
+        assert!(engine.add_rule(invalid).is_err());
+    }
+
+    #[test]
+    fn test_rule_bandwidth_limit() {
+        let mut engine = RoutingRulesEngine::new();
+        let limit = BandwidthLimit {
+            upload_bytes_per_second: Some(1024),
+            download_bytes_per_second: None,
+        };
+        
+        engine.add_rule(RoutingRule {
+            id: "uploads".to_string(),
+            priority: 100,
+            pattern: "*".to_string(),
+            action: RoutingAction::Allow,
+            ports: None,
+            source_ips: None,
+            users: None,
+            groups: None,
+            countries: None,
+            dns_resolution: None,
+            allow_private: false,
+            traffic_class: None,
+            egress_bind_addr: None,
+            fwmark: None,
+            time_restrictions: None,
+            enabled: true,
+            dscp: None,
+            bandwidth: limit,
+            transformers: Vec::new(),
+        }).unwrap();
+        
+        let target = TargetAddr::Domain("files.example.com".to_string());
+        match engine.evaluate_rules(&target, 443, IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), None) {
+            RouteDecision::Allow { bandwidth, .. } => assert_eq!(bandwidth, limit),
+            _ => panic!("Expected allow decision"),
+        }
+    }
+
+    #[test]
+    fn test_throttle_action_uses_bandwidth_class() {
+        let mut engine = RoutingRulesEngine::new();
+        let bulk = BandwidthLimit { upload_bytes_per_second: None, download_bytes_per_second: Some(625_000) };
+        engine.add_bandwidth_class("bulk".to_string(), bulk);
+        let rule = |id: &str, class: &str| RoutingRule {
+            id: id.to_string(),
+            priority: 100,
+            pattern: "*.video.example".to_string(),
+            action: RoutingAction::Throttle { class: class.to_string() },
+            ports: None,
+            source_ips: None,
+            users: None,
+            groups: None,
+            countries: None,
+            dns_resolution: None,
+            allow_private: false,
+            traffic_class: None,
+            egress_bind_addr: None,
+            fwmark: None,
+            time_restrictions: None,
+            enabled: true,
+            dscp: None,
+            bandwidth: BandwidthLimit::default(),
+            transformers: Vec::new(),
+        };
+        assert!(engine.add_rule(rule("unknown", "gold")).unwrap_err().contains("Unknown bandwidth class"));
+        engine.add_rule(rule("video", "bulk")).unwrap();
+        
+        let target = TargetAddr::Domain("cdn.video.example".to_string());
+        let class = match engine.evaluate_rules(&target, 443, IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), None) {
+            RouteDecision::Allow { rule, bandwidth_class: Some(class), .. } => {
+                assert_eq!(rule.as_deref(), Some("video"));
+                class
+            }
+            decision => panic!("Expected throttled allow, got {:?}", decision),
+        };
+        assert_eq!((class.name(), class.limit()), ("bulk", bulk));
+        
+        // Reloaded engines keep sharing unchanged classes, but not changed ones
+        let mut reloaded = RoutingRulesEngine::new();
+        reloaded.add_bandwidth_class("bulk".to_string(), bulk);
+        reloaded.carry_over_bandwidth_classes(&engine);
+        assert!(Arc::ptr_eq(&reloaded.bandwidth_classes["bulk"], &class));
+        let mut changed = RoutingRulesEngine::new();
+        changed.add_bandwidth_class("bulk".to_string(), BandwidthLimit::default());
+        changed.carry_over_bandwidth_classes(&engine);
+        assert!(!Arc::ptr_eq(&changed.bandwidth_classes["bulk"], &class));
+    }
+
+    #[test]
+    fn test_rewrite_action_expands_regex_captures() {
+        let mut engine = RoutingRulesEngine::new();
+        let rule = |id: &str, priority: Priority, pattern: &str, host: &str| RoutingRule {
+            id: id.to_string(),
+            priority,
+            pattern: pattern.to_string(),
+            action: RoutingAction::Rewrite { host: host.to_string() },
+            ports: None,
+            source_ips: None,
+            users: None,
+            groups: None,
+            countries: None,
+            dns_resolution: None,
+            allow_private: false,
+            traffic_class: None,
+            egress_bind_addr: None,
+            fwmark: None,
+            time_restrictions: None,
+            enabled: true,
+            dscp: None,
+            bandwidth: BandwidthLimit::default(),
+            transformers: Vec::new(),
+        };
+        assert!(engine.add_rule(rule("bad", 1, "api.example.com", "$1.internal")).is_err());
+        assert!(engine.add_rule(rule("empty", 1, "api.example.com", "")).is_err());
+        engine.add_rule(rule("api", 200, "api.example.com", "api.staging.example.com")).unwrap();
+        engine.add_rule(rule("services", 100, r"^(?P<service>[a-z]+)\.svc\.example$", "${service}.internal.example")).unwrap();
+        engine.add_rule(rule("pinned", 50, "db.example.com", "10.0.0.5")).unwrap();
+        
+        let source = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let rewrite = |domain: &str| match engine.evaluate_rules(&TargetAddr::Domain(domain.to_string()), 443, source, None) {
+            RouteDecision::Rewrite { target, rule, .. } => (target, rule.unwrap()),
+            decision => panic!("Expected rewrite, got {:?}", decision),
+        };
+        assert_eq!(rewrite("api.example.com"), (TargetAddr::Domain("api.staging.example.com".to_string()), "api".to_string()));
+        assert_eq!(rewrite("billing.svc.example").0, TargetAddr::Domain("billing.internal.example".to_string()));
+        assert_eq!(rewrite("db.example.com").0, TargetAddr::Ipv4(Ipv4Addr::new(10, 0, 0, 5)));
+    }
+
+    #[test]
+    fn test_rule_matches_user_groups() {
+        let mut engine = RoutingRulesEngine::new();
+        engine.add_rule(RoutingRule {
+            id: "contractors".to_string(),
+            priority: 100,
+            pattern: "*.internal.example.com".to_string(),
+            action: RoutingAction::Block { reason: Some("Contractors only reach public hosts".to_string()) },
+            ports: None,
+            source_ips: None,
+            users: Some(vec!["mallory".to_string()]),
+            groups: Some(vec!["contractors".to_string()]),
+            countries: None,
+            dns_resolution: None,
+            allow_private: false,
+            traffic_class: None,
+            egress_bind_addr: None,
+            fwmark: None,
+            time_restrictions: None,
+            enabled: true,
+            dscp: None,
+            bandwidth: BandwidthLimit::default(),
+            transformers: Vec::new(),
+        }).unwrap();
+
+        let target = TargetAddr::Domain("wiki.internal.example.com".to_string());
+        let source = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 5));
+        let contractors = vec!["contractors".to_string()];
+        let staff = vec!["staff".to_string()];
+
+        assert!(matches!(
+            engine.evaluate_rules_for_groups(&target, 443, source, Some("bob"), &contractors),
+            RouteDecision::Block { .. }
+        ));
+        assert!(matches!(
+            engine.evaluate_rules_for_groups(&target, 443, source, Some("alice"), &staff),
+            RouteDecision::Allow { .. }
+        ));
+        // Listed users still match without being in a group
+        assert!(matches!(
+            engine.evaluate_rules(&target, 443, source, Some("mallory")),
+            RouteDecision::Block { .. }
+        ));
+    }
+
+    #[test]
+    fn test_proxy_chain_resolves_every_hop() {
+        let mut engine = RoutingRulesEngine::new();
+        for (id, port) in [("entry", 1080), ("exit", 1081)] {
+            engine.add_upstream_proxy(id.to_string(), UpstreamProxy {
+                addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 10)), port),
+                auth: None,
+                protocol: crate::routing::ProxyProtocol::Socks5,
+            });
+        }
+        let rule = RoutingRule {
+            id: "chained".to_string(),
+            priority: 100,
+            pattern: "*.example.com".to_string(),
+            action: RoutingAction::ProxyChain { upstream_ids: vec!["entry".to_string(), "exit".to_string()] },
+            ports: None,
+            source_ips: None,
+            users: None,
+            groups: None,
+            countries: None,
+            dns_resolution: None,
+            allow_private: false,
+            traffic_class: None,
+            egress_bind_addr: None,
+            fwmark: None,
+            time_restrictions: None,
+            enabled: true,
+            dscp: None,
+            bandwidth: BandwidthLimit::default(),
+            transformers: Vec::new(),
+        };
+        engine.add_rule(rule.clone()).unwrap();
+
+        let target = TargetAddr::Domain("www.example.com".to_string());
+        let source = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        match engine.evaluate_rules(&target, 443, source, None) {
+            RouteDecision::Allow { upstream: Some(first), chain, .. } => {
+                assert_eq!(first.addr.port(), 1080);
+                assert_eq!(chain.iter().map(|hop| hop.addr.port()).collect::<Vec<_>>(), vec![1081]);
+            }
+            other => panic!("Expected a chained allow decision, got {:?}", other),
+        }
+
+        // A chain with an unknown hop is not dialed partially
+        engine.update_rule(RoutingRule {
+            action: RoutingAction::ProxyChain { upstream_ids: vec!["entry".to_string(), "missing".to_string()] },
+            ..rule
+        }).unwrap();
+        assert!(matches!(
+            engine.evaluate_rules(&target, 443, source, None),
+            RouteDecision::Allow { upstream: None, .. }
+        ));
+    }
+
+    #[test]
+    fn test_country_rules_need_a_known_country() {
+        let mut engine = RoutingRulesEngine::new();
+        let rule = RoutingRule {
+            id: "eu".to_string(),
+            priority: 100,
+            pattern: "*".to_string(),
+            action: RoutingAction::Block { reason: None },
+            ports: None,
+            source_ips: None,
+            users: None,
+            groups: None,
+            countries: Some(vec!["de".to_string(), "FR".to_string()]),
+            dns_resolution: None,
+            allow_private: false,
+            traffic_class: None,
+            egress_bind_addr: None,
+            fwmark: None,
+            time_restrictions: None,
+            enabled: true,
+            dscp: None,
+            bandwidth: BandwidthLimit::default(),
+            transformers: Vec::new(),
+        };
+        assert!(RoutingRulesEngine::matches_country(&rule, Some("DE")));
+        assert!(!RoutingRulesEngine::matches_country(&rule, Some("US")));
+        assert!(!RoutingRulesEngine::matches_country(&rule, None));
+
+        engine.add_rule(rule.clone()).unwrap();
+        assert!(engine.has_country_rules());
+
+        // Without a GeoIP database the destination country is unknown
+        let target = TargetAddr::Ipv4(Ipv4Addr::new(192, 0, 2, 80));
+        let source = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        assert!(matches!(
+            engine.evaluate_rules_for_destination(&target, None, 443, source, None, &[]),
+            RouteDecision::Allow { upstream: None, .. }
+        ));
+
+        let invalid = RoutingRule { id: "invalid".to_string(), countries: Some(vec!["EUR".to_string()]), ..rule };
+        assert!(engine.add_rule(invalid).is_err());
+    }
+
+    #[test]
+    fn test_time_restrictions_use_named_calendars() {
+        let mut engine = RoutingRulesEngine::new();
+        let business: Calendar = toml::from_str(r#"utc_offset = "+01:00"
+dst = "eu"
+holidays = ["12-25"]
+
+[[hours]]
+days = ["mon", "tue", "wed", "thu", "fri"]
+start = "09:00"
+end = "17:00""#).unwrap();
+        engine.add_calendar("business".to_string(), business);
+
+        let during = TimeRestriction { calendar: Some("business".to_string()), ..Default::default() };
+        let after = TimeRestriction { outside_calendar: true, ..during.clone() };
+        // 2024-07-08 was a Monday, 2024-12-25 a Wednesday
+        let monday_noon = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_720_432_800);
+        let christmas_noon = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_735_124_400);
+        assert!(engine.matches_time(&during, monday_noon));
+        assert!(!engine.matches_time(&after, monday_noon));
+        assert!(!engine.matches_time(&during, christmas_noon));
+        assert!(engine.matches_time(&after, christmas_noon));
+
+        // Raw windows are in UTC, days counted from Sunday
+        let sunday_only = TimeRestriction { days: Some(vec![0]), ..Default::default() };
+        assert!(!engine.matches_time(&sunday_only, monday_noon));
+        let weekday_lunch = TimeRestriction {
+            days: Some(vec![1]),
+            start_time: Some("10:00".to_string()),
+            end_time: Some("11:00".to_string()),
+            ..during
+        };
+        assert!(engine.matches_time(&weekday_lunch, monday_noon));
+
+        // Unless routing.time_zone or the restriction says otherwise
+        let berlin = TimeZone { utc_offset: "+01:00".to_string().try_into().unwrap(), dst: DstRule::Eu };
+        let berlin_noon = TimeRestriction {
+            days: Some(vec![1]),
+            start_time: Some("12:00".to_string()),
+            end_time: Some("13:00".to_string()),
+            ..Default::default()
+        };
+        assert!(!engine.matches_time(&berlin_noon, monday_noon));
+        engine.set_time_zone(berlin);
+        assert!(engine.matches_time(&berlin_noon, monday_noon));
+        assert!(!engine.matches_time(&weekday_lunch, monday_noon));
+        let utc_lunch = TimeRestriction { time_zone: Some(TimeZone::default()), ..weekday_lunch };
+        assert!(engine.matches_time(&utc_lunch, monday_noon));
+
+        let rule = RoutingRule {
+            id: "after-hours".to_string(),
+            priority: 100,
+            pattern: "*".to_string(),
+            action: RoutingAction::Allow,
+            ports: None,
+            source_ips: None,
+            users: None,
+            groups: None,
+            countries: None,
+            dns_resolution: None,
+            allow_private: false,
+            traffic_class: None,
+            egress_bind_addr: None,
+            fwmark: None,
+            time_restrictions: Some(TimeRestriction { calendar: Some("holidays".to_string()), ..Default::default() }),
+            enabled: true,
+            dscp: None,
+            bandwidth: BandwidthLimit::default(),
+            transformers: Vec::new(),
+        };
+        assert!(engine.add_rule(rule.clone()).is_err());
+        let bad_day = RoutingRule { time_restrictions: Some(TimeRestriction { days: Some(vec![7]), ..Default::default() }), ..rule };
+        assert!(engine.add_rule(bad_day).is_err());
+    }
+
+    #[test]
+    fn test_explain_reports_every_rule() {
+        let mut engine = RoutingRulesEngine::new();
+        let rule = |id: &str, priority: Priority, pattern: &str| RoutingRule {
+            id: id.to_string(),
+            priority,
+            pattern: pattern.to_string(),
+            action: RoutingAction::Block { reason: Some(id.to_string()) },
+            ports: None,
+            source_ips: None,
+            users: None,
+            groups: None,
+            countries: None,
+            dns_resolution: None,
+            allow_private: false,
+            traffic_class: None,
+            egress_bind_addr: None,
+            fwmark: None,
+            time_restrictions: None,
+            enabled: true,
+            dscp: None,
+            bandwidth: BandwidthLimit::default(),
+            transformers: Vec::new(),
+        };
+        engine.add_rule(RoutingRule { ports: Some(vec![22.into()]), ..rule("ssh", 400, "*") }).unwrap();
+        engine.add_rule(RoutingRule { enabled: false, ..rule("off", 300, "*") }).unwrap();
+        engine.add_rule(rule("ads", 200, "ads.*")).unwrap();
+        engine.add_rule(rule("catch-all", 100, "*")).unwrap();
+
+        let target = TargetAddr::Domain("ads.example.com".to_string());
+        let source = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let explanation = engine.explain(&target, 443, source, None);
+        let checks: Vec<_> = explanation.rules.iter().map(|check| (check.rule_id.as_str(), check.mismatch)).collect();
+        assert_eq!(checks, [
+            ("ssh", Some(RuleMismatch::Port)),
+            ("off", Some(RuleMismatch::Disabled)),
+            ("ads", None),
+            ("catch-all", None),
+        ]);
+        assert_eq!(explanation.matched_rule.as_deref(), Some("ads"));
+        assert!(matches!(explanation.decision, RouteDecision::Block { ref reason, .. } if reason == "ads"));
+
+        let other = engine.explain(&TargetAddr::Domain("example.com".to_string()), 443, source, None);
+        assert_eq!(other.rules[2].mismatch, Some(RuleMismatch::Pattern));
+        assert_eq!(other.matched_rule.as_deref(), Some("catch-all"));
+    }
+
+    #[test]
+    fn test_rule_hits_count_decisions() {
+        let rule = |id: &str, pattern: &str| RoutingRule {
+            id: id.to_string(),
+            priority: 100,
+            pattern: pattern.to_string(),
+            action: RoutingAction::Allow,
+            ports: None,
+            source_ips: None,
+            users: None,
+            groups: None,
+            countries: None,
+            dns_resolution: None,
+            allow_private: false,
+            traffic_class: None,
+            egress_bind_addr: None,
+            fwmark: None,
+            time_restrictions: None,
+            enabled: true,
+            dscp: None,
+            bandwidth: BandwidthLimit::default(),
+            transformers: Vec::new(),
+        };
+        let metrics = Arc::new(Metrics::new());
+        let mut engine = RoutingRulesEngine::new();
+        engine.set_metrics(Some(metrics.clone()));
+        engine.add_rule(rule("ads", "ads.*")).unwrap();
+        engine.add_rule(rule("idle", "never.example")).unwrap();
+
+        let target = TargetAddr::Domain("ads.example.com".to_string());
+        let source = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        engine.evaluate_rules(&target, 443, source, None);
+        engine.evaluate_rules(&target, 443, source, None);
+        // Dry runs are not counted
+        engine.explain(&target, 443, source, None);
+
+        let hits = engine.rule_hits();
+        assert_eq!((hits[0].rule_id.as_str(), hits[0].matches), ("ads", 2));
+        assert!(hits[0].last_match.is_some());
+        assert_eq!(hits[1], RuleHits { rule_id: "idle".to_string(), matches: 0, last_match: None });
+        assert!(metrics.export_prometheus().contains("socks5_rule_matches_total{rule_id=\"ads\"} 2"));
+
+        // Edited and reloaded rules keep counting
+        engine.update_rule(RoutingRule { ports: Some(vec![443.into()]), ..rule("ads", "ads.*") }).unwrap();
+        let mut reloaded = RoutingRulesEngine::new();
+        reloaded.add_rule(rule("ads", "ads.*")).unwrap();
+        reloaded.carry_over_hits(&engine);
+        reloaded.evaluate_rules(&target, 443, source, None);
+        assert_eq!(reloaded.rule_hits()[0].matches, 3);
+        assert!(engine.rule_hits().iter().any(|hits| hits.rule_id == "ads" && hits.matches == 3));
+    }
+}
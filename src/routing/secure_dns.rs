@@ -0,0 +1,394 @@
+//! DNS over HTTPS and DNS over TLS
+//!
+//! With `routing.secure_dns.servers` set, domain targets are resolved by
+//! sending DNS queries to those servers over HTTPS (RFC 8484) or TLS
+//! (RFC 7858) instead of through the system resolver, so the local network
+//! can neither read nor forge the answers. Servers are tried in order until
+//! one gives a usable answer; "no such domain" from any of them is final.
+//! A and AAAA are asked for together, and the answer lives as long as its
+//! shortest record TTL. A DoT server given by name is itself located with
+//! the system resolver, so list it by IP address to avoid that lookup.
+
+use super::resolver::DnsAnswer;
+use crate::config::SecureDnsConfig;
+use crate::Result;
+use anyhow::{anyhow, bail, Context};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use tokio_rustls::rustls::{self, pki_types::ServerName, ClientConfig, RootCertStore};
+use tokio_rustls::TlsConnector;
+use tracing::debug;
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+const RCODE_NOERROR: u8 = 0;
+const RCODE_NXDOMAIN: u8 = 3;
+const DOT_PORT: u16 = 853;
+
+/// An encrypted DNS server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DnsServer {
+    /// DNS over HTTPS endpoint URL
+    Https(String),
+    /// DNS over TLS server; the certificate must be valid for `host`
+    Tls { host: String, port: u16 },
+}
+
+impl DnsServer {
+    /// Parse `https://host/path` or `tls://host[:port]`
+    pub fn parse(server: &str) -> Result<Self> {
+        if server.starts_with("https://") {
+            let url = reqwest::Url::parse(server).context("invalid URL")?;
+            if url.host_str().is_none_or(str::is_empty) {
+                bail!("missing host in {}", server);
+            }
+            return Ok(DnsServer::Https(server.to_string()));
+        }
+        let Some(authority) = server.strip_prefix("tls://") else {
+            bail!("expected an https:// or tls:// server, got {}", server);
+        };
+        let (host, port) = split_host_port(authority.trim_end_matches('/'))?;
+        ServerName::try_from(host.clone()).map_err(|_| anyhow!("invalid TLS server name {}", host))?;
+        Ok(DnsServer::Tls { host, port })
+    }
+}
+
+impl fmt::Display for DnsServer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DnsServer::Https(url) => write!(f, "{}", url),
+            DnsServer::Tls { host, port } if host.contains(':') => write!(f, "tls://[{}]:{}", host, port),
+            DnsServer::Tls { host, port } => write!(f, "tls://{}:{}", host, port),
+        }
+    }
+}
+
+/// Host and port of `host`, `host:port`, `[v6]` or `[v6]:port`
+fn split_host_port(authority: &str) -> Result<(String, u16)> {
+    let (host, port) = match authority.strip_prefix('[') {
+        Some(rest) => {
+            let (host, after) = rest.split_once(']').ok_or_else(|| anyhow!("unclosed '[' in {}", authority))?;
+            match after {
+                "" => (host, None),
+                _ => (host, Some(after.strip_prefix(':').ok_or_else(|| anyhow!("invalid server {}", authority))?)),
+            }
+        }
+        None => match authority.split_once(':') {
+            Some((_, port)) if port.contains(':') => bail!("IPv6 server {} must be written in brackets", authority),
+            Some((host, port)) => (host, Some(port)),
+            None => (authority, None),
+        },
+    };
+    if host.is_empty() {
+        bail!("missing host in {}", authority);
+    }
+    let port = match port {
+        Some(port) => port.parse().with_context(|| format!("invalid port in {}", authority))?,
+        None => DOT_PORT,
+    };
+    Ok((host.to_string(), port))
+}
+
+/// Address records of one DNS response
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Records {
+    rcode: u8,
+    addrs: Vec<IpAddr>,
+    /// Smallest TTL among the address records
+    ttl: Option<u32>,
+}
+
+/// Resolves names through encrypted DNS servers
+pub struct SecureDnsClient {
+    servers: Vec<DnsServer>,
+    timeout: Duration,
+    http: reqwest::Client,
+    tls: TlsConnector,
+    rng: SystemRandom,
+}
+
+impl SecureDnsClient {
+    pub fn new(config: &SecureDnsConfig) -> Result<Self> {
+        let servers = config.servers.iter()
+            .map(|server| DnsServer::parse(server))
+            .collect::<Result<Vec<_>>>()?;
+        let http = reqwest::Client::builder()
+            .timeout(config.timeout)
+            .build()
+            .context("Failed to build DNS-over-HTTPS client")?;
+        let roots = RootCertStore { roots: webpki_roots::TLS_SERVER_ROOTS.to_vec() };
+        let tls = ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .context("Failed to build DNS-over-TLS client")?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        Ok(Self {
+            servers,
+            timeout: config.timeout,
+            http,
+            tls: TlsConnector::from(Arc::new(tls)),
+            rng: SystemRandom::new(),
+        })
+    }
+
+    /// Resolve `domain` with the first server that gives a usable answer
+    pub async fn lookup(&self, domain: &str) -> Result<DnsAnswer> {
+        let mut last_error = anyhow!("no DNS servers configured");
+        for server in &self.servers {
+            let (v4, v6) = match timeout(self.timeout, self.query(server, domain)).await {
+                Ok(Ok(records)) => records,
+                Ok(Err(e)) => {
+                    debug!("DNS server {} failed for {}: {:#}", server, domain, e);
+                    last_error = e.context(format!("DNS server {}", server));
+                    continue;
+                }
+                Err(_) => {
+                    debug!("DNS server {} did not answer for {} within {:?}", server, domain, self.timeout);
+                    last_error = anyhow!("DNS server {} did not answer within {:?}", server, self.timeout);
+                    continue;
+                }
+            };
+            if v4.rcode == RCODE_NXDOMAIN || v6.rcode == RCODE_NXDOMAIN {
+                bail!("DNS resolution failed for {}: no such domain", domain);
+            }
+            if v4.rcode != RCODE_NOERROR && v6.rcode != RCODE_NOERROR {
+                last_error = anyhow!("DNS server {} answered with response code {}", server, v4.rcode);
+                continue;
+            }
+
+            let ttl = v4.ttl.into_iter().chain(v6.ttl).min();
+            let addrs: Vec<IpAddr> = v4.addrs.into_iter().chain(v6.addrs).collect();
+            if addrs.is_empty() {
+                bail!("DNS resolution returned no addresses for {}", domain);
+            }
+            return Ok(DnsAnswer { addrs, ttl: ttl.map(|secs| Duration::from_secs(secs.into())) });
+        }
+        Err(anyhow!("DNS resolution failed for {}: {:#}", domain, last_error))
+    }
+
+    /// Ask one server for the A and AAAA records of `domain`
+    async fn query(&self, server: &DnsServer, domain: &str) -> Result<(Records, Records)> {
+        match server {
+            DnsServer::Https(url) => {
+                // RFC 8484 asks for ID 0 so identical queries can be cached by HTTP
+                let (v4, v6) = tokio::try_join!(
+                    self.https_exchange(url, encode_query(0, domain, TYPE_A)?),
+                    self.https_exchange(url, encode_query(0, domain, TYPE_AAAA)?),
+                )?;
+                Ok((decode_response(&v4, 0)?, decode_response(&v6, 0)?))
+            }
+            DnsServer::Tls { host, port } => {
+                let mut id = [0u8; 2];
+                self.rng.fill(&mut id).map_err(|_| anyhow!("Failed to generate DNS query ID"))?;
+                let v4_id = u16::from_be_bytes(id);
+                let v6_id = v4_id.wrapping_add(1);
+                let queries = [encode_query(v4_id, domain, TYPE_A)?, encode_query(v6_id, domain, TYPE_AAAA)?];
+                let mut responses = self.tls_exchange(host, *port, &queries).await?;
+                // Servers may answer pipelined queries in any order
+                responses.sort_by_key(|response| response.get(..2) != Some(&v4_id.to_be_bytes()[..]));
+                Ok((decode_response(&responses[0], v4_id)?, decode_response(&responses[1], v6_id)?))
+            }
+        }
+    }
+
+    async fn https_exchange(&self, url: &str, query: Vec<u8>) -> Result<Vec<u8>> {
+        let response = self.http.post(url)
+            .header(reqwest::header::CONTENT_TYPE, "application/dns-message")
+            .header(reqwest::header::ACCEPT, "application/dns-message")
+            .body(query)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(response.bytes().await?.to_vec())
+    }
+
+    /// Send length-prefixed queries over one TLS connection and read as many responses
+    async fn tls_exchange(&self, host: &str, port: u16, queries: &[Vec<u8>]) -> Result<Vec<Vec<u8>>> {
+        let name = ServerName::try_from(host.to_string())?;
+        let stream = TcpStream::connect((host, port)).await?;
+        let mut stream = self.tls.connect(name, stream).await?;
+
+        let mut request = Vec::new();
+        for query in queries {
+            request.extend_from_slice(&(query.len() as u16).to_be_bytes());
+            request.extend_from_slice(query);
+        }
+        stream.write_all(&request).await?;
+        stream.flush().await?;
+
+        let mut responses = Vec::with_capacity(queries.len());
+        for _ in queries {
+            let len = stream.read_u16().await?;
+            let mut response = vec![0; len as usize];
+            stream.read_exact(&mut response).await?;
+            responses.push(response);
+        }
+        Ok(responses)
+    }
+}
+
+/// Build a recursive query for one record type of `domain`
+fn encode_query(id: u16, domain: &str, qtype: u16) -> Result<Vec<u8>> {
+    let mut query = Vec::with_capacity(domain.len() + 18);
+    query.extend_from_slice(&id.to_be_bytes());
+    // Standard query with recursion desired, one question
+    query.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in domain.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            bail!("invalid domain name {}", domain);
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    if query.len() - 12 > 255 {
+        bail!("domain name {} is too long", domain);
+    }
+    query.extend_from_slice(&qtype.to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(query)
+}
+
+/// Read the address records out of a response to query `id`
+fn decode_response(response: &[u8], id: u16) -> Result<Records> {
+    let mut reader = Reader { data: response, pos: 0 };
+    if reader.u16()? != id {
+        bail!("DNS response does not match its query");
+    }
+    let flags = reader.u16()?;
+    if flags & 0x8000 == 0 {
+        bail!("DNS message is not a response");
+    }
+    if flags & 0x0200 != 0 {
+        bail!("DNS response was truncated");
+    }
+    let questions = reader.u16()?;
+    let answers = reader.u16()?;
+    // Authority and additional sections are not needed
+    reader.take(4)?;
+    for _ in 0..questions {
+        reader.skip_name()?;
+        reader.take(4)?;
+    }
+
+    let mut records = Records { rcode: (flags & 0x000f) as u8, ..Default::default() };
+    for _ in 0..answers {
+        reader.skip_name()?;
+        let rtype = reader.u16()?;
+        let class = reader.u16()?;
+        let ttl = reader.u32()?;
+        let len = reader.u16()?;
+        let data = reader.take(len.into())?;
+        // CNAMEs in the chain are skipped; the resolver includes the records they lead to
+        let addr = match (rtype, class) {
+            (TYPE_A, CLASS_IN) => IpAddr::from(<[u8; 4]>::try_from(data)?),
+            (TYPE_AAAA, CLASS_IN) => IpAddr::from(<[u8; 16]>::try_from(data)?),
+            _ => continue,
+        };
+        records.addrs.push(addr);
+        records.ttl = Some(records.ttl.map_or(ttl, |min| min.min(ttl)));
+    }
+    Ok(records)
+}
+
+/// Bounds-checked cursor over a DNS message
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let bytes = self.data.get(self.pos..self.pos + len)
+            .ok_or_else(|| anyhow!("DNS response is truncated"))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_be_bytes(self.take(2)?.try_into()?))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into()?))
+    }
+
+    /// Step over a name, which ends at a zero label or a compression pointer
+    fn skip_name(&mut self) -> Result<()> {
+        loop {
+            match self.take(1)?[0] {
+                0 => return Ok(()),
+                len if len & 0xc0 == 0xc0 => {
+                    self.take(1)?;
+                    return Ok(());
+                }
+                len => {
+                    self.take(len.into())?;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parses_servers() {
+        assert_eq!(
+            DnsServer::parse("https://cloudflare-dns.com/dns-query").unwrap(),
+            DnsServer::Https("https://cloudflare-dns.com/dns-query".to_string())
+        );
+        assert_eq!(DnsServer::parse("tls://1.1.1.1").unwrap(), DnsServer::Tls { host: "1.1.1.1".to_string(), port: 853 });
+        assert_eq!(DnsServer::parse("tls://dns.quad9.net:8853").unwrap().to_string(), "tls://dns.quad9.net:8853");
+        assert_eq!(DnsServer::parse("tls://[2606:4700::1111]").unwrap().to_string(), "tls://[2606:4700::1111]:853");
+
+        for invalid in ["http://1.1.1.1/dns-query", "udp://1.1.1.1", "tls://", "tls://2606:4700::1111", "tls://1.1.1.1:dns"] {
+            assert!(DnsServer::parse(invalid).is_err(), "{} should be rejected", invalid);
+        }
+    }
+
+    #[test]
+    fn test_encodes_queries() {
+        let query = encode_query(0x1234, "Example.com.", TYPE_AAAA).unwrap();
+        assert_eq!(&query[..12], &[0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(&query[12..], b"\x07Example\x03com\x00\x00\x1c\x00\x01");
+
+        assert!(encode_query(0, "bad..name", TYPE_A).is_err());
+        assert!(encode_query(0, &vec!["a".repeat(63); 5].join("."), TYPE_A).is_err());
+    }
+
+    #[test]
+    fn test_decodes_address_records() {
+        let mut response = encode_query(7, "www.example.com", TYPE_A).unwrap();
+        response[2] = 0x81;
+        response[3] = 0x80;
+        response[7] = 3;
+        // CNAME to a compressed name, then two A records under pointers
+        response.extend_from_slice(&[0xc0, 12, 0, 5, 0, 1, 0, 0, 1, 0, 0, 2, 0xc0, 16]);
+        response.extend_from_slice(&[0xc0, 16, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 93, 184, 216, 34]);
+        response.extend_from_slice(&[0xc0, 16, 0, 1, 0, 1, 0, 0, 0, 30, 0, 4, 93, 184, 216, 35]);
+
+        let records = decode_response(&response, 7).unwrap();
+        assert_eq!(records.rcode, RCODE_NOERROR);
+        assert_eq!(records.addrs, ["93.184.216.34".parse::<IpAddr>().unwrap(), "93.184.216.35".parse().unwrap()]);
+        assert_eq!(records.ttl, Some(30));
+
+        assert!(decode_response(&response, 8).is_err());
+        assert!(decode_response(&response[..response.len() - 2], 7).is_err());
+
+        let mut nxdomain = encode_query(9, "missing.example", TYPE_A).unwrap();
+        nxdomain[2] = 0x81;
+        nxdomain[3] = 0x83;
+        assert_eq!(decode_response(&nxdomain, 9).unwrap().rcode, RCODE_NXDOMAIN);
+    }
+}
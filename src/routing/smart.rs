@@ -5,10 +5,11 @@
 
 use std::collections::HashMap;
 use std::net::SocketAddr;
-use std::sync::Arc;
+use std::sync::{Arc, Weak};
 use std::time::{Duration, Instant};
 use tokio::net::TcpStream;
 use tokio::sync::RwLock;
+use tokio::task::JoinHandle;
 use tokio::time::timeout;
 use tracing::{debug, warn, info};
 
@@ -163,11 +164,30 @@ impl Default for SmartRoutingConfig {
     }
 }
 
+impl From<&crate::config::SmartRoutingConfigToml> for SmartRoutingConfig {
+    fn from(config: &crate::config::SmartRoutingConfigToml) -> Self {
+        Self {
+            health_check_interval: config.health_check_interval,
+            health_check_timeout: config.health_check_timeout,
+            min_measurements: config.min_measurements,
+            enable_latency_routing: config.enable_latency_routing,
+            enable_health_routing: config.enable_health_routing,
+        }
+    }
+}
+
+/// Upstream proxies by ID
+type ProxyTable = std::sync::RwLock<HashMap<String, UpstreamProxy>>;
+
 /// Smart routing manager
+///
+/// Shared by every connection: the router asks it for upstreams, the
+/// connection manager reports how connections through them went, and the
+/// health-check task probes them in the background.
 pub struct SmartRoutingManager {
     config: SmartRoutingConfig,
     metrics: Arc<RwLock<HashMap<String, ProxyMetrics>>>,
-    upstream_proxies: HashMap<String, UpstreamProxy>,
+    upstream_proxies: Arc<ProxyTable>,
 }
 
 impl SmartRoutingManager {
@@ -176,13 +196,13 @@ impl SmartRoutingManager {
         Self {
             config,
             metrics: Arc::new(RwLock::new(HashMap::new())),
-            upstream_proxies: HashMap::new(),
+            upstream_proxies: Arc::new(std::sync::RwLock::new(HashMap::new())),
         }
     }
 
     /// Add an upstream proxy to be managed
-    pub async fn add_upstream_proxy(&mut self, id: String, proxy: UpstreamProxy) {
-        self.upstream_proxies.insert(id.clone(), proxy);
+    pub async fn add_upstream_proxy(&self, id: String, proxy: UpstreamProxy) {
+        self.upstream_proxies.write().unwrap().insert(id.clone(), proxy);
         
         // Initialize metrics for this proxy
         let mut metrics_guard = self.metrics.write().await;
@@ -190,27 +210,46 @@ impl SmartRoutingManager {
     }
 
     /// Remove an upstream proxy
-    pub async fn remove_upstream_proxy(&mut self, id: &str) {
-        self.upstream_proxies.remove(id);
+    pub async fn remove_upstream_proxy(&self, id: &str) {
+        self.upstream_proxies.write().unwrap().remove(id);
         
         // Remove metrics for this proxy
         let mut metrics_guard = self.metrics.write().await;
         metrics_guard.remove(id);
     }
 
+    /// Replace the managed upstream proxies, e.g. after a configuration reload
+    ///
+    /// Measurements of proxies that stay are kept; those of removed proxies
+    /// are no longer reported and are dropped by the next health check.
+    pub fn set_upstream_proxies(&self, proxies: HashMap<String, UpstreamProxy>) {
+        *self.upstream_proxies.write().unwrap() = proxies;
+    }
+
+    /// The upstream proxy with the given address
+    pub fn proxy_id_at(&self, addr: SocketAddr) -> Option<String> {
+        self.upstream_proxies.read().unwrap().iter()
+            .find(|(_, proxy)| proxy.addr == addr)
+            .map(|(id, _)| id.clone())
+    }
+
+    fn proxies(&self) -> HashMap<String, UpstreamProxy> {
+        self.upstream_proxies.read().unwrap().clone()
+    }
+
     /// Select the best upstream proxy based on current metrics
     pub async fn select_best_proxy(&self, exclude_ids: &[String]) -> Option<(String, UpstreamProxy)> {
         let metrics_guard = self.metrics.read().await;
         
         let mut best_proxy: Option<(String, UpstreamProxy, f64)> = None;
         
-        for (id, proxy) in &self.upstream_proxies {
+        for (id, proxy) in self.proxies() {
             // Skip excluded proxies
-            if exclude_ids.contains(id) {
+            if exclude_ids.contains(&id) {
                 continue;
             }
             
-            let score = if let Some(metrics) = metrics_guard.get(id) {
+            let score = if let Some(metrics) = metrics_guard.get(&id) {
                 // Skip unhealthy proxies if health routing is enabled
                 if self.config.enable_health_routing && metrics.health_status == HealthStatus::Unhealthy {
                     continue;
@@ -229,11 +268,11 @@ impl SmartRoutingManager {
             
             match &best_proxy {
                 None => {
-                    best_proxy = Some((id.clone(), proxy.clone(), score));
+                    best_proxy = Some((id, proxy, score));
                 },
                 Some((_, _, best_score)) => {
                     if score > *best_score {
-                        best_proxy = Some((id.clone(), proxy.clone(), score));
+                        best_proxy = Some((id, proxy, score));
                     }
                 },
             }
@@ -250,19 +289,23 @@ impl SmartRoutingManager {
 
     /// Record a connection attempt result
     pub async fn record_connection_result(&self, proxy_id: &str, latency: Duration, success: bool) {
-        let mut metrics_guard = self.metrics.write().await;
-        
-        if let Some(metrics) = metrics_guard.get_mut(proxy_id) {
-            metrics.record_latency(latency, success);
-            debug!("Recorded connection result for '{}': latency={:?}, success={}, score={:.3}", 
-                   proxy_id, latency, success, metrics.get_score());
+        if !self.upstream_proxies.read().unwrap().contains_key(proxy_id) {
+            return;
         }
+        let mut metrics_guard = self.metrics.write().await;
+        let metrics = metrics_guard.entry(proxy_id.to_string()).or_default();
+        metrics.record_latency(latency, success);
+        debug!("Recorded connection result for '{}': latency={:?}, success={}, score={:.3}", 
+               proxy_id, latency, success, metrics.get_score());
     }
 
     /// Start background health checking
-    pub async fn start_health_checking(&self) {
+    ///
+    /// Each round probes the proxies managed at that time. The task ends
+    /// once the manager is dropped.
+    pub async fn start_health_checking(&self) -> JoinHandle<()> {
         let metrics = Arc::clone(&self.metrics);
-        let proxies = self.upstream_proxies.clone();
+        let proxies = Arc::downgrade(&self.upstream_proxies);
         let config = self.config.clone();
         
         tokio::spawn(async move {
@@ -270,37 +313,52 @@ impl SmartRoutingManager {
             
             loop {
                 interval.tick().await;
+                let Some(current) = Weak::upgrade(&proxies) else {
+                    break;
+                };
+                let current = current.read().unwrap().clone();
                 
-                for (id, proxy) in &proxies {
-                    let proxy_id = id.clone();
-                    let proxy_addr = proxy.addr;
-                    let timeout_duration = config.health_check_timeout;
-                    let metrics_clone = Arc::clone(&metrics);
-                    
-                    tokio::spawn(async move {
-                        let start_time = Instant::now();
-                        let result = Self::health_check_proxy(proxy_addr, timeout_duration).await;
-                        let latency = start_time.elapsed();
-                        
-                        let mut metrics_guard = metrics_clone.write().await;
-                        if let Some(proxy_metrics) = metrics_guard.get_mut(&proxy_id) {
-                            proxy_metrics.last_health_check = Instant::now();
-                            
-                            match result {
-                                Ok(()) => {
-                                    debug!("Health check passed for '{}': {:?}", proxy_id, latency);
-                                    proxy_metrics.record_latency(latency, true);
-                                },
-                                Err(e) => {
-                                    warn!("Health check failed for '{}': {}", proxy_id, e);
-                                    proxy_metrics.record_latency(latency, false);
-                                },
-                            }
-                        }
-                    });
+                // Forget measurements of proxies no longer managed
+                metrics.write().await.retain(|id, _| current.contains_key(id));
+                
+                for (id, proxy) in current {
+                    tokio::spawn(Self::check_and_record(Arc::clone(&metrics), id, proxy.addr, config.health_check_timeout, false));
                 }
             }
-        });
+        })
+    }
+
+    /// Probe one proxy and record the outcome in its metrics
+    async fn check_and_record(
+        metrics: Arc<RwLock<HashMap<String, ProxyMetrics>>>,
+        proxy_id: String,
+        proxy_addr: SocketAddr,
+        timeout_duration: Duration,
+        forced: bool,
+    ) {
+        let start_time = Instant::now();
+        let result = Self::health_check_proxy(proxy_addr, timeout_duration).await;
+        let latency = start_time.elapsed();
+        
+        let mut metrics_guard = metrics.write().await;
+        let proxy_metrics = metrics_guard.entry(proxy_id.clone()).or_default();
+        proxy_metrics.last_health_check = Instant::now();
+        
+        let kind = if forced { "Forced health check" } else { "Health check" };
+        match result {
+            Ok(()) => {
+                if forced {
+                    info!("{} passed for '{}': {:?}", kind, proxy_id, latency);
+                } else {
+                    debug!("{} passed for '{}': {:?}", kind, proxy_id, latency);
+                }
+                proxy_metrics.record_latency(latency, true);
+            },
+            Err(e) => {
+                warn!("{} failed for '{}': {}", kind, proxy_id, e);
+                proxy_metrics.record_latency(latency, false);
+            },
+        }
     }
 
     /// Perform a health check on a proxy
@@ -322,7 +380,11 @@ impl SmartRoutingManager {
 
     /// Get current metrics for all proxies
     pub async fn get_all_metrics(&self) -> HashMap<String, ProxyMetrics> {
-        self.metrics.read().await.clone()
+        let proxies = self.proxies();
+        self.metrics.read().await.iter()
+            .filter(|(id, _)| proxies.contains_key(*id))
+            .map(|(id, metrics)| (id.clone(), metrics.clone()))
+            .collect()
     }
 
     /// Get metrics for a specific proxy
@@ -332,6 +394,7 @@ impl SmartRoutingManager {
 
     /// Get health status summary
     pub async fn get_health_summary(&self) -> HealthSummary {
+        let proxies = self.proxies();
         let metrics_guard = self.metrics.read().await;
         
         let mut healthy = 0;
@@ -339,17 +402,17 @@ impl SmartRoutingManager {
         let mut unhealthy = 0;
         let mut unknown = 0;
         
-        for metrics in metrics_guard.values() {
-            match metrics.health_status {
-                HealthStatus::Healthy => healthy += 1,
-                HealthStatus::Degraded => degraded += 1,
-                HealthStatus::Unhealthy => unhealthy += 1,
-                HealthStatus::Unknown => unknown += 1,
+        for id in proxies.keys() {
+            match metrics_guard.get(id).map(|metrics| &metrics.health_status) {
+                Some(HealthStatus::Healthy) => healthy += 1,
+                Some(HealthStatus::Degraded) => degraded += 1,
+                Some(HealthStatus::Unhealthy) => unhealthy += 1,
+                Some(HealthStatus::Unknown) | None => unknown += 1,
             }
         }
         
         HealthSummary {
-            total_proxies: metrics_guard.len(),
+            total_proxies: proxies.len(),
             healthy,
             degraded,
             unhealthy,
@@ -361,33 +424,8 @@ impl SmartRoutingManager {
     pub async fn force_health_check(&self) {
         info!("Forcing health check for all proxies");
         
-        for (id, proxy) in &self.upstream_proxies {
-            let proxy_id = id.clone();
-            let proxy_addr = proxy.addr;
-            let timeout_duration = self.config.health_check_timeout;
-            let metrics = Arc::clone(&self.metrics);
-            
-            tokio::spawn(async move {
-                let start_time = Instant::now();
-                let result = Self::health_check_proxy(proxy_addr, timeout_duration).await;
-                let latency = start_time.elapsed();
-                
-                let mut metrics_guard = metrics.write().await;
-                if let Some(proxy_metrics) = metrics_guard.get_mut(&proxy_id) {
-                    proxy_metrics.last_health_check = Instant::now();
-                    
-                    match result {
-                        Ok(()) => {
-                            info!("Forced health check passed for '{}': {:?}", proxy_id, latency);
-                            proxy_metrics.record_latency(latency, true);
-                        },
-                        Err(e) => {
-                            warn!("Forced health check failed for '{}': {}", proxy_id, e);
-                            proxy_metrics.record_latency(latency, false);
-                        },
-                    }
-                }
-            });
+        for (id, proxy) in self.proxies() {
+            tokio::spawn(Self::check_and_record(Arc::clone(&self.metrics), id, proxy.addr, self.config.health_check_timeout, true));
         }
     }
 }
@@ -478,7 +516,7 @@ mod tests {
     #[tokio::test]
     async fn test_smart_routing_manager() {
         let config = SmartRoutingConfig::default();
-        let mut manager = SmartRoutingManager::new(config);
+        let manager = SmartRoutingManager::new(config);
         
         // Add some test proxies
         manager.add_upstream_proxy(
@@ -0,0 +1,259 @@
+//! Sticky Upstream Sessions
+//!
+//! Pins a client (or user, or user and destination host) to the upstream
+//! proxy its first connection went through, so later connections leave from
+//! the same exit address. A pin
+//! lasts for `ttl` after the last connection that used it and can be listed,
+//! dropped, or moved to another upstream through the management API. Pins are
+//! persisted to a JSON state file so long-lived customer sessions keep their
+//! exit across a restart.
+
+use crate::config::{StickyKey, StickySessionConfig};
+use crate::expiring::ExpiringMap;
+use crate::protocol::TargetAddr;
+use crate::Result;
+use anyhow::{anyhow, Context};
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Instant, SystemTime};
+use tracing::{debug, info, warn};
+
+#[derive(Debug, Clone)]
+struct Pin {
+    upstream: String,
+    pinned_at: SystemTime,
+}
+
+/// A pinned session as reported by the API and written to the state file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StickySession {
+    /// `client:<ip>` or `user:<name>`, followed by `@<host>` when keyed by destination
+    pub key: String,
+    /// Name of the upstream proxy the session is pinned to
+    pub upstream: String,
+    pub pinned_at: SystemTime,
+    /// When the pin lapses unless another connection uses it
+    pub expires_at: SystemTime,
+}
+
+/// Table of client/user to upstream pins
+pub struct StickySessionTable {
+    config: StickySessionConfig,
+    pins: Mutex<ExpiringMap<String, Pin>>,
+    dirty: AtomicBool,
+}
+
+impl StickySessionTable {
+    /// Create a table, restoring persisted pins if a state file exists
+    pub fn new(config: StickySessionConfig) -> Self {
+        let mut pins = ExpiringMap::new(config.ttl);
+        if let (Some(path), true) = (&config.state_path, config.enabled) {
+            if path.exists() {
+                match Self::load(path) {
+                    Ok(sessions) => {
+                        let (now, wall_now) = (Instant::now(), SystemTime::now());
+                        let mut restored = 0;
+                        for session in sessions {
+                            let Ok(remaining) = session.expires_at.duration_since(wall_now) else {
+                                continue;
+                            };
+                            let pin = Pin { upstream: session.upstream, pinned_at: session.pinned_at };
+                            pins.insert_until(session.key, pin, now + remaining.min(config.ttl));
+                            restored += 1;
+                        }
+                        info!("Restored {} sticky session(s) from {}", restored, path.display());
+                    }
+                    Err(e) => warn!("Ignoring unreadable sticky session state {}: {:#}", path.display(), e),
+                }
+            }
+        }
+
+        Self {
+            config,
+            pins: Mutex::new(pins),
+            dirty: AtomicBool::new(false),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.config.enabled
+    }
+
+    /// Table key for a connection
+    pub fn key_for(&self, client_ip: IpAddr, user: Option<&str>, target: &TargetAddr) -> String {
+        let owner = match (self.config.key, user) {
+            (StickyKey::User | StickyKey::UserDestination, Some(user)) => format!("user:{}", user),
+            _ => format!("client:{}", client_ip),
+        };
+        if self.config.key != StickyKey::UserDestination {
+            return owner;
+        }
+        // The port is left out so e.g. http and https of a site share an exit
+        let host = match target {
+            TargetAddr::Ipv4(ip) => ip.to_string(),
+            TargetAddr::Ipv6(ip) => ip.to_string(),
+            TargetAddr::Domain(domain) => domain.trim_end_matches('.').to_ascii_lowercase(),
+        };
+        format!("{}@{}", owner, host)
+    }
+
+    /// Upstream a session is pinned to, refreshing the pin
+    pub fn lookup(&self, key: &str) -> Option<String> {
+        let mut pins = self.pins.lock().unwrap();
+        pins.expire(Instant::now(), |_, _| false);
+        let upstream = pins.get_mut(&key.to_string())?.upstream.clone();
+        self.dirty.store(true, Ordering::Relaxed);
+        Some(upstream)
+    }
+
+    /// Pin a session to an upstream, replacing any existing pin
+    pub fn pin(&self, key: String, upstream: &str) {
+        debug!("Pinning sticky session {} to upstream {}", key, upstream);
+        let pin = Pin { upstream: upstream.to_string(), pinned_at: SystemTime::now() };
+        self.pins.lock().unwrap().insert(key, pin);
+        self.dirty.store(true, Ordering::Relaxed);
+    }
+
+    /// Drop a pin, returning whether there was one
+    pub fn remove(&self, key: &str) -> bool {
+        let removed = self.pins.lock().unwrap().remove(&key.to_string()).is_some();
+        if removed {
+            self.dirty.store(true, Ordering::Relaxed);
+        }
+        removed
+    }
+
+    /// Move a session to the upstream after its current one in `upstreams`
+    pub fn rotate(&self, key: &str, upstreams: &[&str]) -> Result<StickySession> {
+        let current = self.sessions().into_iter()
+            .find(|session| session.key == key)
+            .ok_or_else(|| anyhow!("No sticky session '{}'", key))?;
+        let next = upstreams.iter()
+            .position(|name| *name == current.upstream)
+            .map_or(0, |index| index + 1);
+        let upstream = upstreams.get(next % upstreams.len().max(1))
+            .filter(|name| **name != current.upstream)
+            .ok_or_else(|| anyhow!("No other upstream proxy to rotate '{}' to", key))?;
+
+        info!("Rotating sticky session {} from upstream {} to {}", key, current.upstream, upstream);
+        self.pin(key.to_string(), upstream);
+        self.sessions().into_iter()
+            .find(|session| session.key == key)
+            .ok_or_else(|| anyhow!("No sticky session '{}'", key))
+    }
+
+    /// Current pins, without refreshing them
+    pub fn sessions(&self) -> Vec<StickySession> {
+        let mut pins = self.pins.lock().unwrap();
+        let (now, wall_now) = (Instant::now(), SystemTime::now());
+        pins.expire(now, |_, _| false);
+        let mut sessions: Vec<StickySession> = pins.iter()
+            .map(|(key, pin)| StickySession {
+                key: key.clone(),
+                upstream: pin.upstream.clone(),
+                pinned_at: pin.pinned_at,
+                expires_at: wall_now + pins.expires_at(key).unwrap_or(now).saturating_duration_since(now),
+            })
+            .collect();
+        sessions.sort_by(|a, b| a.key.cmp(&b.key));
+        sessions
+    }
+
+    /// Persist pins if they changed since the last save
+    pub fn save(&self) -> Result<()> {
+        let Some(path) = &self.config.state_path else {
+            return Ok(());
+        };
+        if !self.config.enabled || !self.dirty.swap(false, Ordering::Relaxed) {
+            return Ok(());
+        }
+
+        let content = serde_json::to_string_pretty(&self.sessions())?;
+        let result = crate::security::quota::write_atomically(path, &content);
+        if result.is_err() {
+            // Try again on the next save
+            self.dirty.store(true, Ordering::Relaxed);
+        } else {
+            debug!("Saved sticky sessions to {}", path.display());
+        }
+        result
+    }
+
+    fn load(path: &Path) -> Result<Vec<StickySession>> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        serde_json::from_str(&content)
+            .with_context(|| format!("Failed to parse {}", path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn test_config(key: StickyKey) -> StickySessionConfig {
+        StickySessionConfig { enabled: true, key, ttl: Duration::from_secs(60), state_path: None }
+    }
+
+    #[test]
+    fn test_pins_rotate_through_upstreams() {
+        let table = StickySessionTable::new(test_config(StickyKey::User));
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let target = TargetAddr::Domain("example.com".to_string());
+        assert_eq!(table.key_for(ip, Some("alice"), &target), "user:alice");
+        assert_eq!(table.key_for(ip, None, &target), "client:192.0.2.1");
+
+        table.pin("user:alice".to_string(), "eu-1");
+        assert_eq!(table.lookup("user:alice").as_deref(), Some("eu-1"));
+
+        let upstreams = ["eu-1", "eu-2"];
+        assert_eq!(table.rotate("user:alice", &upstreams).unwrap().upstream, "eu-2");
+        assert_eq!(table.rotate("user:alice", &upstreams).unwrap().upstream, "eu-1");
+        assert!(table.rotate("user:alice", &["eu-1"]).is_err());
+        assert!(table.rotate("user:bob", &upstreams).is_err());
+
+        assert!(table.remove("user:alice"));
+        assert_eq!(table.lookup("user:alice"), None);
+    }
+
+    #[test]
+    fn test_pins_survive_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = StickySessionConfig {
+            state_path: Some(dir.path().join("sticky.json")),
+            ..test_config(StickyKey::Client)
+        };
+
+        let table = StickySessionTable::new(config.clone());
+        table.pin("client:192.0.2.1".to_string(), "eu-1");
+        table.save().unwrap();
+
+        let restored = StickySessionTable::new(config);
+        let sessions = restored.sessions();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].upstream, "eu-1");
+        assert!(sessions[0].expires_at > SystemTime::now());
+        assert_eq!(restored.lookup("client:192.0.2.1").as_deref(), Some("eu-1"));
+    }
+
+    #[test]
+    fn test_user_destination_keys() {
+        let table = StickySessionTable::new(test_config(StickyKey::UserDestination));
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let shop = TargetAddr::Domain("Shop.Example.com.".to_string());
+        assert_eq!(table.key_for(ip, Some("alice"), &shop), "user:alice@shop.example.com");
+        assert_eq!(table.key_for(ip, None, &shop), "client:192.0.2.1@shop.example.com");
+        assert_eq!(table.key_for(ip, Some("alice"), &TargetAddr::Ipv4("198.51.100.7".parse().unwrap())),
+                   "user:alice@198.51.100.7");
+
+        // Each destination of a user keeps its own pin
+        table.pin(table.key_for(ip, Some("alice"), &shop), "eu-1");
+        let bank = TargetAddr::Domain("bank.example.com".to_string());
+        assert_eq!(table.lookup(&table.key_for(ip, Some("alice"), &bank)), None);
+        assert_eq!(table.lookup(&table.key_for(ip, Some("alice"), &shop)).as_deref(), Some("eu-1"));
+    }
+}
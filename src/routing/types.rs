@@ -1,18 +1,56 @@
 //! Routing Types
 
 use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use crate::config::DnsResolution;
+use crate::connection::EgressBind;
 use crate::protocol::TargetAddr;
+use crate::relay::{BandwidthClass, BandwidthLimit, TrafficClass};
+use crate::security::BlockReason;
+use super::ports::{ports_contain, PortRange};
+
+/// Highest valid DSCP codepoint (6 bits)
+pub const MAX_DSCP: u8 = 63;
 
 /// Routing decision for a connection request
 #[derive(Debug, Clone)]
 pub enum RouteDecision {
-    Allow { upstream: Option<UpstreamProxy> },
-    Block { reason: String },
-    Redirect { target: SocketAddr },
+    /// Allow the connection, optionally via an upstream (and any further `chain` hops
+    /// dialed through it, in order), with DSCP marking on the outbound socket,
+    /// per-direction rate caps on the relay, a bandwidth class whose caps it
+    /// shares with other connections, and named relay transformers; `rule` is
+    /// the routing rule that allowed it, if one matched, and `dns_resolution`
+    /// its override of where domain targets are resolved, `allow_private`
+    /// whether it may reach private and internal addresses, `traffic_class`
+    /// the QoS class the rule places it in, `egress_bind_addr` the local
+    /// address or interface a direct connection leaves from, and `fwmark` the
+    /// firewall mark set on it
+    Allow {
+        rule: Option<String>,
+        upstream: Option<UpstreamProxy>,
+        chain: Vec<UpstreamProxy>,
+        dscp: Option<u8>,
+        bandwidth: BandwidthLimit,
+        bandwidth_class: Option<Arc<BandwidthClass>>,
+        transformers: Vec<String>,
+        dns_resolution: Option<DnsResolution>,
+        allow_private: bool,
+        traffic_class: Option<TrafficClass>,
+        egress_bind_addr: Option<EgressBind>,
+        fwmark: Option<u32>,
+    },
+    Block { reason: String, code: BlockReason },
+    /// Connect to `target` instead of the requested destination, keeping the
+    /// requested port when `target`'s is 0; `rule` is the rule that redirected
+    Redirect { target: SocketAddr, rule: Option<String> },
+    /// Connect to `target` on the requested port instead of the requested
+    /// host; `rule` is the rule that rewrote it, and `allow_private` whether
+    /// the new host may be a private or internal address
+    Rewrite { target: TargetAddr, rule: Option<String>, allow_private: bool },
 }
 
 /// Upstream proxy configuration
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct UpstreamProxy {
     pub addr: SocketAddr,
     pub auth: Option<ProxyAuth>,
@@ -20,17 +58,19 @@ pub struct UpstreamProxy {
 }
 
 /// Proxy authentication
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct ProxyAuth {
     pub username: String,
     pub password: String,
 }
 
 /// Proxy protocol type
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum ProxyProtocol {
     Socks5,
     Http,
+    /// Another RustProxy node, reached over a shared tunnel
+    Tunnel(Box<crate::tunnel::TunnelClientConfig>),
 }
 
 /// Access control policy
@@ -73,8 +113,10 @@ impl From<&str> for Action {
 pub struct AccessControlRule {
     pub pattern: String,
     pub action: Action,
-    pub ports: Option<Vec<u16>>,
+    pub ports: Option<Vec<PortRange>>,
     pub countries: Option<Vec<String>>,
+    /// Groups the rule is limited to (`None` applies it to everyone)
+    pub groups: Option<Vec<String>>,
 }
 
 /// Access control list for managing rules and policies
@@ -100,9 +142,14 @@ impl AccessControlList {
 
     /// Evaluate access for a target address, port, and source IP
     pub fn evaluate_access(&self, target: &TargetAddr, port: u16, source_ip: IpAddr) -> (bool, String) {
+        self.evaluate_access_for_groups(target, port, source_ip, &[])
+    }
+
+    /// Evaluate access for a user belonging to the given groups
+    pub fn evaluate_access_for_groups(&self, target: &TargetAddr, port: u16, source_ip: IpAddr, groups: &[String]) -> (bool, String) {
         // Check each rule in order
         for rule in &self.rules {
-            if self.matches_rule(rule, target, port, source_ip) {
+            if self.applies_to_groups(rule, groups) && self.matches_rule(rule, target, port, source_ip) {
                 match &rule.action {
                     Action::Allow => return (true, "Allowed by rule".to_string()),
                     Action::Block => return (false, format!("Blocked by rule: {}", rule.pattern)),
@@ -122,7 +169,7 @@ impl AccessControlList {
     pub fn matches_rule(&self, rule: &AccessControlRule, target: &TargetAddr, port: u16, source_ip: IpAddr) -> bool {
         // Check port restriction
         if let Some(allowed_ports) = &rule.ports {
-            if !allowed_ports.contains(&port) {
+            if !ports_contain(allowed_ports, port) {
                 return false;
             }
         }
@@ -131,6 +178,14 @@ impl AccessControlList {
         self.matches_pattern(&rule.pattern, target, source_ip)
     }
 
+    /// Check if a rule applies to a user in the given groups
+    pub fn applies_to_groups(&self, rule: &AccessControlRule, groups: &[String]) -> bool {
+        match &rule.groups {
+            Some(rule_groups) => rule_groups.iter().any(|group| groups.contains(group)),
+            None => true,
+        }
+    }
+
     /// Check if a pattern matches the target address or source IP
     fn matches_pattern(&self, pattern: &str, target: &TargetAddr, source_ip: IpAddr) -> bool {
         // Handle wildcard
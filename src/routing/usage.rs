@@ -0,0 +1,313 @@
+//! Upstream Usage Caps and Budgets
+//!
+//! Counts the bytes relayed through each upstream proxy per calendar month
+//! (UTC) and compares them with the upstream's `monthly_cap_bytes`, and with
+//! its `monthly_budget` once priced at `cost_per_gb`. Upstreams past
+//! `warn_ratio` of a limit are only picked when no other upstream has room,
+//! and exhausted ones are not routed through at all until the month rolls
+//! over. Crossing either threshold is logged once per month as an alert.
+//! Usage is persisted to a JSON state file like user quotas.
+
+use crate::config::RoutingConfig;
+use crate::relay::{RelayControl, RelayObserver, RelaySession};
+use crate::security::quota::{current_period, write_atomically};
+use crate::Result;
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use tracing::{debug, info, warn};
+
+const BYTES_PER_GB: f64 = 1_000_000_000.0;
+
+/// How much room an upstream has left this month
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UpstreamBudget {
+    Available,
+    /// Past `warn_ratio` of a cap or budget
+    Approaching,
+    /// A cap or budget is used up
+    Exhausted,
+}
+
+impl fmt::Display for UpstreamBudget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            UpstreamBudget::Available => "available",
+            UpstreamBudget::Approaching => "approaching its limit",
+            UpstreamBudget::Exhausted => "exhausted",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct UpstreamLimits {
+    cap_bytes: Option<u64>,
+    cost_per_gb: Option<f64>,
+    budget: Option<f64>,
+}
+
+/// Bytes relayed through an upstream in the current month
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct UpstreamMonthUsage {
+    pub month: u64,
+    pub bytes: u64,
+    /// Highest threshold already alerted on this month
+    #[serde(default)]
+    alerted: Option<UpstreamBudget>,
+}
+
+/// Per-upstream monthly usage, checked against caps and budgets
+pub struct UpstreamUsageTracker {
+    warn_ratio: f64,
+    state_path: Option<PathBuf>,
+    limits: HashMap<String, UpstreamLimits>,
+    names: HashMap<SocketAddr, String>,
+    usage: Mutex<HashMap<String, UpstreamMonthUsage>>,
+    dirty: AtomicBool,
+}
+
+impl UpstreamUsageTracker {
+    /// Create a tracker for the configured upstreams, restoring persisted usage
+    pub fn new(config: &RoutingConfig) -> Self {
+        let limits = config.upstream_proxies.iter()
+            .map(|upstream| (upstream.name.clone(), UpstreamLimits {
+                cap_bytes: upstream.monthly_cap_bytes,
+                cost_per_gb: upstream.cost_per_gb,
+                budget: upstream.monthly_budget,
+            }))
+            .collect();
+        let names = config.upstream_proxies.iter()
+            .map(|upstream| (upstream.addr, upstream.name.clone()))
+            .collect();
+
+        let state_path = config.upstream_usage.state_path.clone();
+        let usage = match &state_path {
+            Some(path) if path.exists() => match Self::load(path) {
+                Ok(usage) => {
+                    info!("Restored usage for {} upstream proxy(ies) from {}", usage.len(), path.display());
+                    usage
+                }
+                Err(e) => {
+                    warn!("Ignoring unreadable upstream usage state {}: {:#}", path.display(), e);
+                    HashMap::new()
+                }
+            },
+            _ => HashMap::new(),
+        };
+
+        Self {
+            warn_ratio: config.upstream_usage.warn_ratio,
+            state_path,
+            limits,
+            names,
+            usage: Mutex::new(usage),
+            dirty: AtomicBool::new(false),
+        }
+    }
+
+    /// Whether any upstream has a cap or a price to track
+    pub fn is_enabled(&self) -> bool {
+        self.limits.values().any(|limits| limits.cap_bytes.is_some() || limits.cost_per_gb.is_some())
+    }
+
+    /// Name of the configured upstream listening at `addr`
+    pub fn name_at(&self, addr: SocketAddr) -> Option<&str> {
+        self.names.get(&addr).map(String::as_str)
+    }
+
+    /// Room the named upstream has left this month
+    pub fn budget(&self, name: &str) -> UpstreamBudget {
+        let limits = self.limits.get(name).copied().unwrap_or_default();
+        let bytes = self.usage(name).map_or(0, |usage| usage.bytes);
+        self.budget_for(&limits, bytes)
+    }
+
+    /// Bytes relayed through the named upstream this month
+    pub fn usage(&self, name: &str) -> Option<UpstreamMonthUsage> {
+        let (_, month) = current_period();
+        let mut usage = self.usage.lock().unwrap();
+        let entry = usage.get_mut(name)?;
+        Self::roll(entry, month);
+        Some(entry.clone())
+    }
+
+    /// Cost of the named upstream's usage this month
+    pub fn cost(&self, name: &str) -> f64 {
+        let cost_per_gb = self.limits.get(name).and_then(|limits| limits.cost_per_gb).unwrap_or(0.0);
+        let bytes = self.usage(name).map_or(0, |usage| usage.bytes);
+        bytes as f64 / BYTES_PER_GB * cost_per_gb
+    }
+
+    /// Add relayed bytes to an upstream's month, alerting when a threshold is crossed
+    pub fn record(&self, name: &str, bytes: u64) {
+        if bytes == 0 {
+            return;
+        }
+        let Some(limits) = self.limits.get(name).copied() else {
+            return;
+        };
+        let (_, month) = current_period();
+        let mut usage = self.usage.lock().unwrap();
+        let entry = usage.entry(name.to_string()).or_default();
+        Self::roll(entry, month);
+        entry.bytes += bytes;
+        self.dirty.store(true, Ordering::Relaxed);
+
+        let budget = self.budget_for(&limits, entry.bytes);
+        if budget > UpstreamBudget::Available && entry.alerted.is_none_or(|alerted| alerted < budget) {
+            entry.alerted = Some(budget);
+            warn!("Upstream proxy '{}' is {} for this month: {} bytes relayed{}",
+                  name, budget, entry.bytes, Self::describe_limits(&limits, entry.bytes));
+        }
+    }
+
+    /// Persist usage if it changed since the last save
+    pub fn save(&self) -> Result<()> {
+        let Some(path) = &self.state_path else {
+            return Ok(());
+        };
+        if !self.dirty.swap(false, Ordering::Relaxed) {
+            return Ok(());
+        }
+
+        let content = {
+            let usage = self.usage.lock().unwrap();
+            serde_json::to_string_pretty(&*usage)?
+        };
+        let result = write_atomically(path, &content);
+        if result.is_err() {
+            // Try again on the next save
+            self.dirty.store(true, Ordering::Relaxed);
+        } else {
+            debug!("Saved upstream usage to {}", path.display());
+        }
+        result
+    }
+
+    fn budget_for(&self, limits: &UpstreamLimits, bytes: u64) -> UpstreamBudget {
+        let mut used: f64 = 0.0;
+        if let Some(cap) = limits.cap_bytes {
+            used = used.max(bytes as f64 / cap as f64);
+        }
+        if let (Some(cost_per_gb), Some(budget)) = (limits.cost_per_gb, limits.budget) {
+            used = used.max(bytes as f64 / BYTES_PER_GB * cost_per_gb / budget);
+        }
+
+        if used >= 1.0 {
+            UpstreamBudget::Exhausted
+        } else if used >= self.warn_ratio {
+            UpstreamBudget::Approaching
+        } else {
+            UpstreamBudget::Available
+        }
+    }
+
+    fn describe_limits(limits: &UpstreamLimits, bytes: u64) -> String {
+        let mut description = String::new();
+        if let Some(cap) = limits.cap_bytes {
+            description.push_str(&format!(" of a {} byte cap", cap));
+        }
+        if let (Some(cost_per_gb), Some(budget)) = (limits.cost_per_gb, limits.budget) {
+            description.push_str(&format!(", costing {:.2} of a {:.2} budget", bytes as f64 / BYTES_PER_GB * cost_per_gb, budget));
+        }
+        description
+    }
+
+    fn roll(entry: &mut UpstreamMonthUsage, month: u64) {
+        if entry.month != month {
+            *entry = UpstreamMonthUsage { month, ..Default::default() };
+        }
+    }
+
+    fn load(path: &Path) -> Result<HashMap<String, UpstreamMonthUsage>> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        serde_json::from_str(&content)
+            .with_context(|| format!("Failed to parse {}", path.display()))
+    }
+}
+
+impl RelayObserver for UpstreamUsageTracker {
+    fn on_progress(&self, session: &RelaySession, _user_id: Option<&str>, bytes_up: u64, bytes_down: u64) -> RelayControl {
+        // Every hop of a chain carries (and may bill) the full traffic
+        for name in session.upstreams.iter().filter_map(|addr| self.name_at(*addr)) {
+            self.record(name, bytes_up + bytes_down);
+        }
+        RelayControl::Continue
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Config, UpstreamProxyConfig};
+
+    fn routing_config() -> RoutingConfig {
+        let mut routing = Config::default().routing;
+        let upstream = |name: &str, port: u16| UpstreamProxyConfig {
+            name: name.to_string(),
+            addr: SocketAddr::from(([192, 0, 2, 10], port)),
+            protocol: "socks5".to_string(),
+            auth: None,
+            monthly_cap_bytes: None,
+            cost_per_gb: None,
+            monthly_budget: None,
+            weight: 1,
+            tunnel: None,
+        };
+        routing.upstream_proxies = vec![
+            UpstreamProxyConfig { monthly_cap_bytes: Some(1000), ..upstream("capped", 1080) },
+            UpstreamProxyConfig { cost_per_gb: Some(2.0), monthly_budget: Some(0.000_001), ..upstream("metered", 1081) },
+            upstream("unlimited", 1082),
+        ];
+        routing
+    }
+
+    #[test]
+    fn test_caps_and_budgets() {
+        let tracker = UpstreamUsageTracker::new(&routing_config());
+        assert!(tracker.is_enabled());
+
+        tracker.record("capped", 700);
+        assert_eq!(tracker.budget("capped"), UpstreamBudget::Available);
+        tracker.record("capped", 100);
+        assert_eq!(tracker.budget("capped"), UpstreamBudget::Approaching);
+        tracker.record("capped", 200);
+        assert_eq!(tracker.budget("capped"), UpstreamBudget::Exhausted);
+
+        // 1000 bytes at 2 per GB costs twice the budget
+        tracker.record("metered", 1000);
+        assert_eq!(tracker.budget("metered"), UpstreamBudget::Exhausted);
+        assert!((tracker.cost("metered") - 0.000_002).abs() < 1e-12);
+
+        tracker.record("unlimited", u64::MAX / 2);
+        assert_eq!(tracker.budget("unlimited"), UpstreamBudget::Available);
+    }
+
+    #[test]
+    fn test_observer_counts_upstream_sessions_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = routing_config();
+        config.upstream_usage.state_path = Some(dir.path().join("upstreams.json"));
+        let tracker = UpstreamUsageTracker::new(&config);
+
+        let client = SocketAddr::from(([198, 51, 100, 1], 5000));
+        let proxied = RelaySession::new("s1".to_string(), client, SocketAddr::from(([192, 0, 2, 10], 1080)))
+            .with_upstreams(vec![SocketAddr::from(([192, 0, 2, 10], 1080))]);
+        let direct = RelaySession::new("s2".to_string(), client, SocketAddr::from(([192, 0, 2, 10], 1080)));
+        tracker.on_progress(&proxied, None, 300, 500);
+        tracker.on_progress(&direct, None, 300, 500);
+        assert_eq!(tracker.usage("capped").unwrap().bytes, 800);
+
+        tracker.save().unwrap();
+        let restored = UpstreamUsageTracker::new(&config);
+        assert_eq!(restored.usage("capped").unwrap().bytes, 800);
+        assert_eq!(restored.budget("capped"), UpstreamBudget::Approaching);
+    }
+}
@@ -0,0 +1,532 @@
+//! Weekly Schedules
+//!
+//! Recurring time windows such as "Monday to Friday, 09:00 to 17:30" used to
+//! restrict when something is allowed. Times are wall-clock times at a fixed
+//! UTC offset; daylight saving changes are only applied through a calendar.
+//!
+//! A window whose end is earlier than its start runs past midnight and belongs
+//! to the day it starts on, so `days = ["fri"]`, `start = "22:00"`,
+//! `end = "02:00"` covers Friday night into early Saturday.
+//!
+//! Named [`Calendar`]s group windows with a time zone that can follow
+//! daylight saving rules and a list of holidays, so rules can share e.g.
+//! "German business hours" instead of repeating the same windows.
+
+use crate::Result;
+use anyhow::{anyhow, bail};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const MINUTES_PER_DAY: i64 = 24 * 60;
+
+/// Day of the week
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Weekday {
+    #[serde(alias = "monday")]
+    Mon,
+    #[serde(alias = "tuesday")]
+    Tue,
+    #[serde(alias = "wednesday")]
+    Wed,
+    #[serde(alias = "thursday")]
+    Thu,
+    #[serde(alias = "friday")]
+    Fri,
+    #[serde(alias = "saturday")]
+    Sat,
+    #[serde(alias = "sunday")]
+    Sun,
+}
+
+impl Weekday {
+    const ALL: [Weekday; 7] = [
+        Weekday::Mon, Weekday::Tue, Weekday::Wed, Weekday::Thu,
+        Weekday::Fri, Weekday::Sat, Weekday::Sun,
+    ];
+
+    /// Weekday for a count of days since the Unix epoch (a Thursday)
+    fn from_days(days: i64) -> Self {
+        Self::ALL[(days + 3).rem_euclid(7) as usize]
+    }
+}
+
+/// Wall-clock time in minutes after midnight, written as `HH:MM`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct TimeOfDay(u16);
+
+impl TimeOfDay {
+    /// Create a time from hours and minutes
+    pub fn new(hour: u16, minute: u16) -> Result<Self> {
+        if hour > 24 || minute > 59 || (hour == 24 && minute != 0) {
+            bail!("Invalid time of day {:02}:{:02}", hour, minute);
+        }
+        Ok(Self(hour * 60 + minute))
+    }
+
+    fn minutes(self) -> i64 {
+        self.0 as i64
+    }
+}
+
+impl TryFrom<String> for TimeOfDay {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self> {
+        let (hour, minute) = value.split_once(':')
+            .ok_or_else(|| anyhow!("Invalid time of day '{}', expected HH:MM", value))?;
+        let parse = |part: &str| part.parse::<u16>()
+            .map_err(|_| anyhow!("Invalid time of day '{}', expected HH:MM", value));
+        Self::new(parse(hour)?, parse(minute)?)
+    }
+}
+
+impl From<TimeOfDay> for String {
+    fn from(time: TimeOfDay) -> Self {
+        time.to_string()
+    }
+}
+
+impl fmt::Display for TimeOfDay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02}:{:02}", self.0 / 60, self.0 % 60)
+    }
+}
+
+/// Fixed offset from UTC, written as `+HH:MM`, `-HH:MM`, or `UTC`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct UtcOffset(i16);
+
+impl TryFrom<String> for UtcOffset {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self> {
+        if value.eq_ignore_ascii_case("utc") || value == "Z" {
+            return Ok(Self(0));
+        }
+        let invalid = || anyhow!("Invalid UTC offset '{}', expected +HH:MM or -HH:MM", value);
+        let (sign, rest) = match value.as_bytes().first() {
+            Some(b'+') => (1, &value[1..]),
+            Some(b'-') => (-1, &value[1..]),
+            _ => return Err(invalid()),
+        };
+        let (hours, minutes) = rest.split_once(':').ok_or_else(invalid)?;
+        let hours: i16 = hours.parse().map_err(|_| invalid())?;
+        let minutes: i16 = minutes.parse().map_err(|_| invalid())?;
+        if hours > 14 || minutes > 59 {
+            return Err(invalid());
+        }
+        Ok(Self(sign * (hours * 60 + minutes)))
+    }
+}
+
+impl From<UtcOffset> for String {
+    fn from(offset: UtcOffset) -> Self {
+        let sign = if offset.0 < 0 { '-' } else { '+' };
+        let minutes = offset.0.unsigned_abs();
+        format!("{}{:02}:{:02}", sign, minutes / 60, minutes % 60)
+    }
+}
+
+/// Daylight saving rule applied on top of a standard UTC offset
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DstRule {
+    /// No daylight saving time
+    #[default]
+    None,
+    /// European Union: last Sunday of March to last Sunday of October, 01:00 UTC
+    Eu,
+    /// United States and Canada: second Sunday of March to first Sunday of
+    /// November, 02:00 local time
+    Us,
+}
+
+impl DstRule {
+    /// Minutes to add to the standard offset at a given minute since the epoch
+    fn shift(self, standard: i64, unix_minute: i64) -> i64 {
+        let (year, _, _) = civil_from_days((unix_minute + standard).div_euclid(MINUTES_PER_DAY));
+        let (start, end) = match self {
+            DstRule::None => return 0,
+            DstRule::Eu => (
+                last_sunday(year, 3) * MINUTES_PER_DAY + 60,
+                last_sunday(year, 10) * MINUTES_PER_DAY + 60,
+            ),
+            DstRule::Us => (
+                nth_sunday(year, 3, 2) * MINUTES_PER_DAY + 120 - standard,
+                nth_sunday(year, 11, 1) * MINUTES_PER_DAY + 120 - (standard + 60),
+            ),
+        };
+        if (start..end).contains(&unix_minute) { 60 } else { 0 }
+    }
+}
+
+/// Time zone given as a standard offset and a daylight saving rule
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct TimeZone {
+    /// Standard (winter) offset
+    #[serde(default)]
+    pub utc_offset: UtcOffset,
+    #[serde(default)]
+    pub dst: DstRule,
+}
+
+impl TimeZone {
+    /// A calendar in this time zone that is open during `hours`
+    pub fn calendar(self, hours: Vec<Schedule>) -> Calendar {
+        Calendar { utc_offset: self.utc_offset, dst: self.dst, hours, holidays: Vec::new() }
+    }
+}
+
+/// Holiday date, written as `YYYY-MM-DD` or `MM-DD` for every year
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Holiday {
+    year: Option<i64>,
+    month: u32,
+    day: u32,
+}
+
+impl Holiday {
+    fn falls_on(&self, (year, month, day): (i64, u32, u32)) -> bool {
+        self.month == month && self.day == day && self.year.is_none_or(|y| y == year)
+    }
+}
+
+impl TryFrom<String> for Holiday {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self> {
+        let invalid = || anyhow!("Invalid holiday '{}', expected YYYY-MM-DD or MM-DD", value);
+        let parts: Vec<&str> = value.split('-').collect();
+        let (year, month, day) = match parts.as_slice() {
+            [year, month, day] => (Some(year.parse().map_err(|_| invalid())?), month, day),
+            [month, day] => (None, month, day),
+            _ => return Err(invalid()),
+        };
+        let month: u32 = month.parse().map_err(|_| invalid())?;
+        let day: u32 = day.parse().map_err(|_| invalid())?;
+        if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+            return Err(invalid());
+        }
+        Ok(Self { year, month, day })
+    }
+}
+
+impl From<Holiday> for String {
+    fn from(holiday: Holiday) -> Self {
+        match holiday.year {
+            Some(year) => format!("{:04}-{:02}-{:02}", year, holiday.month, holiday.day),
+            None => format!("{:02}-{:02}", holiday.month, holiday.day),
+        }
+    }
+}
+
+/// Named set of opening hours in one time zone, closed on holidays
+///
+/// The calendar is open when the local time falls in one of `hours` (or at
+/// any time if there are none), unless the local date is a holiday. Windows
+/// use the calendar's time zone, so they must not set their own `utc_offset`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Calendar {
+    /// Standard (winter) offset of the calendar's wall clock
+    #[serde(default)]
+    pub utc_offset: UtcOffset,
+    /// Daylight saving rule of the calendar's region
+    #[serde(default)]
+    pub dst: DstRule,
+    #[serde(default)]
+    pub hours: Vec<Schedule>,
+    /// Dates the calendar stays closed all day
+    #[serde(default)]
+    pub holidays: Vec<Holiday>,
+}
+
+impl Calendar {
+    /// Whether the calendar is open at the given time
+    pub fn is_open(&self, time: SystemTime) -> bool {
+        let local = self.local_minutes(time);
+        let date = civil_from_days(local.div_euclid(MINUTES_PER_DAY));
+        if self.holidays.iter().any(|holiday| holiday.falls_on(date)) {
+            return false;
+        }
+        self.hours.is_empty() || self.hours.iter().any(|window| window.closes_after_local(local).is_some())
+    }
+
+    /// Offset from UTC in effect at the given time, in minutes
+    pub fn offset_at(&self, time: SystemTime) -> i64 {
+        let standard = self.utc_offset.0 as i64;
+        standard + self.dst.shift(standard, unix_minutes(time))
+    }
+
+    /// Check that the hours do not carry their own offsets
+    pub fn validate(&self) -> Result<()> {
+        if self.hours.iter().any(|window| window.utc_offset != UtcOffset::default()) {
+            bail!("Calendar hours use the calendar's utc_offset and cannot set their own");
+        }
+        Ok(())
+    }
+
+    fn local_minutes(&self, time: SystemTime) -> i64 {
+        unix_minutes(time) + self.offset_at(time)
+    }
+}
+
+/// A recurring weekly time window
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Schedule {
+    /// Days the window opens on (empty means every day)
+    #[serde(default)]
+    pub days: Vec<Weekday>,
+    /// Opening time
+    pub start: TimeOfDay,
+    /// Closing time; earlier than `start` for windows that run past midnight
+    pub end: TimeOfDay,
+    /// Offset of the wall clock the times refer to
+    #[serde(default)]
+    pub utc_offset: UtcOffset,
+}
+
+impl Schedule {
+    /// Whether the window is open at the given time
+    pub fn contains(&self, time: SystemTime) -> bool {
+        self.closes_after(time).is_some()
+    }
+
+    /// Minutes from `time` until the window closes, if it is open
+    fn closes_after(&self, time: SystemTime) -> Option<i64> {
+        self.closes_after_local(unix_minutes(time) + self.utc_offset.0 as i64)
+    }
+
+    /// Like `closes_after`, for a wall-clock time in minutes since the epoch
+    fn closes_after_local(&self, local: i64) -> Option<i64> {
+        let day = local.div_euclid(MINUTES_PER_DAY);
+        let minute = local.rem_euclid(MINUTES_PER_DAY);
+        let (start, end) = (self.start.minutes(), self.end.minutes());
+
+        if start < end {
+            (self.opens_on(day) && (start..end).contains(&minute)).then(|| end - minute)
+        } else if self.opens_on(day) && minute >= start {
+            // Opened today and runs past midnight (or all day when start == end)
+            Some(MINUTES_PER_DAY - minute + end)
+        } else if self.opens_on(day - 1) && minute < end {
+            // Opened yesterday and has not closed yet
+            Some(end - minute)
+        } else {
+            None
+        }
+    }
+
+    fn opens_on(&self, day: i64) -> bool {
+        self.days.is_empty() || self.days.contains(&Weekday::from_days(day))
+    }
+}
+
+/// Whether any window is open (no windows means no restriction)
+pub fn is_open(windows: &[Schedule], time: SystemTime) -> bool {
+    windows.is_empty() || windows.iter().any(|window| window.contains(time))
+}
+
+/// How long until the windows stop covering `time`
+///
+/// Back-to-back and overlapping windows are followed through, so a user with
+/// a day shift and an evening shift is only cut off when both have ended.
+/// Returns `None` when there are no windows or they never close.
+pub fn remaining(windows: &[Schedule], time: SystemTime) -> Option<Duration> {
+    if windows.is_empty() {
+        return None;
+    }
+    let mut elapsed = 0i64;
+    // Windows that stay open for two full weeks never close
+    while elapsed < 14 * MINUTES_PER_DAY {
+        let at = time + Duration::from_secs(elapsed as u64 * 60);
+        let Some(open_for) = windows.iter().filter_map(|w| w.closes_after(at)).max() else {
+            break;
+        };
+        elapsed += open_for;
+    }
+    if elapsed >= 14 * MINUTES_PER_DAY {
+        return None;
+    }
+    // Windows close on a minute boundary; drop the seconds already spent in this minute
+    let seconds_into_minute = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() % 60;
+    Some(Duration::from_secs((elapsed as u64 * 60).saturating_sub(seconds_into_minute)))
+}
+
+/// Calendar date for a count of days since the Unix epoch
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    // Howard Hinnant's days-to-civil algorithm
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    (yoe + era * 400 + i64::from(month <= 2), month, day)
+}
+
+/// Days since the Unix epoch for a calendar date
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = (month as i64 + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+/// Day (since the epoch) of the `n`th Sunday of a month
+fn nth_sunday(year: i64, month: u32, n: i64) -> i64 {
+    let first = days_from_civil(year, month, 1);
+    // Day 0 was a Thursday, so Sundays are the days with (days + 4) % 7 == 0
+    first + (7 - (first + 4).rem_euclid(7)) % 7 + 7 * (n - 1)
+}
+
+/// Day (since the epoch) of the last Sunday of a month
+fn last_sunday(year: i64, month: u32) -> i64 {
+    let (next_year, next_month) = if month == 12 { (year + 1, 1) } else { (year, month + 1) };
+    let last = days_from_civil(next_year, next_month, 1) - 1;
+    last - (last + 4).rem_euclid(7)
+}
+
+fn unix_minutes(time: SystemTime) -> i64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(since) => (since.as_secs() / 60) as i64,
+        Err(before) => -(before.duration().as_secs().div_ceil(60) as i64),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2024-01-05 was a Friday
+    fn at(day_of_month: u64, hour: u64, minute: u64) -> SystemTime {
+        let january_first = 1_704_067_200;
+        UNIX_EPOCH + Duration::from_secs(january_first + (day_of_month - 1) * 86_400 + hour * 3600 + minute * 60)
+    }
+
+    fn schedule(toml: &str) -> Schedule {
+        toml::from_str(toml).unwrap()
+    }
+
+    #[test]
+    fn test_business_hours() {
+        let hours = schedule(r#"days = ["mon", "tue", "wed", "thu", "fri"]
+start = "09:00"
+end = "17:30""#);
+
+        assert!(hours.contains(at(5, 9, 0)));
+        assert!(hours.contains(at(5, 17, 29)));
+        assert!(!hours.contains(at(5, 17, 30)));
+        assert!(!hours.contains(at(6, 12, 0))); // Saturday
+        assert!(hours.contains(at(8, 12, 0))); // Monday
+    }
+
+    #[test]
+    fn test_overnight_window_and_offset() {
+        let night = schedule(r#"days = ["friday"]
+start = "22:00"
+end = "02:00"
+utc_offset = "+02:00""#);
+
+        // 22:00 local on Friday is 20:00 UTC
+        assert!(!night.contains(at(5, 19, 59)));
+        assert!(night.contains(at(5, 20, 0)));
+        // 01:00 local on Saturday still belongs to Friday's window
+        assert!(night.contains(at(5, 23, 0)));
+        assert!(!night.contains(at(6, 0, 0)));
+        // Thursday night does not open
+        assert!(!night.contains(at(4, 21, 0)));
+    }
+
+    #[test]
+    fn test_remaining_follows_adjacent_windows() {
+        let shifts = vec![
+            schedule("start = \"08:00\"\nend = \"16:00\""),
+            schedule("start = \"16:00\"\nend = \"20:00\""),
+        ];
+
+        assert_eq!(remaining(&shifts, at(5, 15, 0)), Some(Duration::from_secs(5 * 3600)));
+        assert_eq!(remaining(&shifts, at(5, 21, 0)), Some(Duration::ZERO));
+        assert!(is_open(&shifts, at(5, 19, 0)));
+        assert!(!is_open(&shifts, at(5, 21, 0)));
+
+        let always = vec![schedule("start = \"00:00\"\nend = \"00:00\"")];
+        assert_eq!(remaining(&always, at(5, 12, 0)), None);
+        assert_eq!(remaining(&[], at(5, 12, 0)), None);
+    }
+
+    #[test]
+    fn test_invalid_times_rejected() {
+        assert!(toml::from_str::<Schedule>("start = \"25:00\"\nend = \"10:00\"").is_err());
+        assert!(toml::from_str::<Schedule>("start = \"9\"\nend = \"10:00\"").is_err());
+        assert!(toml::from_str::<Schedule>("start = \"09:00\"\nend = \"10:00\"\nutc_offset = \"2\"").is_err());
+        assert!(toml::from_str::<Schedule>("start = \"09:00\"\nend = \"10:00\"\ndays = [\"funday\"]").is_err());
+    }
+
+    fn utc(year: i64, month: u32, day: u32, hour: u64, minute: u64) -> SystemTime {
+        let days = days_from_civil(year, month, day) as u64;
+        UNIX_EPOCH + Duration::from_secs(days * 86_400 + hour * 3600 + minute * 60)
+    }
+
+    #[test]
+    fn test_civil_dates() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(days_from_civil(2024, 2, 29)), (2024, 2, 29));
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+        assert_eq!(civil_from_days(last_sunday(2024, 3)), (2024, 3, 31));
+        assert_eq!(civil_from_days(nth_sunday(2024, 3, 2)), (2024, 3, 10));
+        assert_eq!(civil_from_days(nth_sunday(2024, 9, 1)), (2024, 9, 1));
+    }
+
+    #[test]
+    fn test_daylight_saving_rules() {
+        let berlin: Calendar = toml::from_str("utc_offset = \"+01:00\"\ndst = \"eu\"").unwrap();
+        assert_eq!(berlin.offset_at(utc(2024, 3, 31, 0, 59)), 60);
+        assert_eq!(berlin.offset_at(utc(2024, 3, 31, 1, 0)), 120);
+        assert_eq!(berlin.offset_at(utc(2024, 10, 27, 0, 59)), 120);
+        assert_eq!(berlin.offset_at(utc(2024, 10, 27, 1, 0)), 60);
+
+        // 02:00 EST is 07:00 UTC; 02:00 EDT is 06:00 UTC
+        let new_york: Calendar = toml::from_str("utc_offset = \"-05:00\"\ndst = \"us\"").unwrap();
+        assert_eq!(new_york.offset_at(utc(2024, 3, 10, 6, 59)), -300);
+        assert_eq!(new_york.offset_at(utc(2024, 3, 10, 7, 0)), -240);
+        assert_eq!(new_york.offset_at(utc(2024, 11, 3, 5, 59)), -240);
+        assert_eq!(new_york.offset_at(utc(2024, 11, 3, 6, 0)), -300);
+    }
+
+    #[test]
+    fn test_calendar_hours_follow_dst_and_skip_holidays() {
+        let business: Calendar = toml::from_str(r#"utc_offset = "+01:00"
+dst = "eu"
+holidays = ["12-25", "2024-05-01"]
+
+[[hours]]
+days = ["mon", "tue", "wed", "thu", "fri"]
+start = "09:00"
+end = "17:00""#).unwrap();
+        business.validate().unwrap();
+
+        // 09:00 local is 08:00 UTC in winter and 07:00 UTC in summer
+        assert!(business.is_open(utc(2024, 1, 8, 8, 0)));
+        assert!(!business.is_open(utc(2024, 7, 8, 6, 59)));
+        assert!(business.is_open(utc(2024, 7, 8, 7, 0)));
+        assert!(!business.is_open(utc(2024, 7, 8, 15, 0)));
+
+        // Holidays close the whole day, recurring or for one year only
+        assert!(!business.is_open(utc(2024, 12, 25, 10, 0)));
+        assert!(!business.is_open(utc(2024, 5, 1, 10, 0)));
+        assert!(business.is_open(utc(2025, 4, 30, 10, 0)));
+
+        let own_offset: Calendar = toml::from_str("[[hours]]\nstart = \"09:00\"\nend = \"17:00\"\nutc_offset = \"+02:00\"").unwrap();
+        assert!(own_offset.validate().is_err());
+        assert!(toml::from_str::<Calendar>("holidays = [\"13-01\"]").is_err());
+    }
+}
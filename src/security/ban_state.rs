@@ -0,0 +1,326 @@
+//! Ban Persistence
+//!
+//! Rate-limit blocks, DDoS blocks, and fail2ban bans live in the shared
+//! `IpSecurityTable`, and manual network blocks and bans in the DDoS and
+//! fail2ban modules, all of which a restart would empty. `BanStore` writes
+//! the ones still in force to `security.ban_state_path` and puts them back
+//! when the proxy starts. Deadlines are saved as wall-clock times, so time spent down
+//! counts against them, and ban counts are kept so repeat offenders still get
+//! the longer progressive bans.
+
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info};
+
+use crate::Result;
+use super::ip_table::IpSecurityTable;
+use super::networks::NetworkBlocks;
+use super::{DdosProtection, Fail2BanManager, RateLimiter};
+
+/// Blocks in force against one IP, as written to the state file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SavedBlocks {
+    pub ip: IpAddr,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rate_limited_until: Option<SystemTime>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub flood_blocked_until: Option<SystemTime>,
+    /// DDoS violations so far; each one doubles the next block
+    #[serde(default)]
+    pub flood_violations: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub banned_until: Option<SystemTime>,
+    /// Fail2ban bans so far; each one multiplies the next ban
+    #[serde(default)]
+    pub ban_count: u32,
+}
+
+/// Manual blocks in force against one network, as written to the state file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SavedNetworkBlocks {
+    pub network: IpNet,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub flood_blocked_until: Option<SystemTime>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub banned_until: Option<SystemTime>,
+}
+
+/// Everything the state file holds
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SavedState {
+    #[serde(default)]
+    pub blocks: Vec<SavedBlocks>,
+    #[serde(default)]
+    pub networks: Vec<SavedNetworkBlocks>,
+}
+
+/// State file contents, including files from before network blocks were saved
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StateFile {
+    Current(SavedState),
+    BlocksOnly(Vec<SavedBlocks>),
+}
+
+/// Saves and restores the blocks held in an `IpSecurityTable` and the
+/// network blocks of the DDoS and fail2ban modules
+pub struct BanStore {
+    path: Option<PathBuf>,
+    ip_table: Arc<IpSecurityTable>,
+    /// DDoS network blocks and fail2ban network bans
+    network_blocks: Option<(Arc<NetworkBlocks>, Arc<NetworkBlocks>)>,
+    /// Content of the last successful save, to skip rewriting an unchanged file
+    last_saved: Mutex<Option<String>>,
+}
+
+impl BanStore {
+    /// Create a store for `ip_table`; without a path it saves and restores nothing
+    pub fn new(path: Option<PathBuf>, ip_table: Arc<IpSecurityTable>) -> Self {
+        Self { path, ip_table, network_blocks: None, last_saved: Mutex::new(None) }
+    }
+
+    /// Save the network blocks of these modules as well
+    pub fn with_network_blocks(mut self, ddos: &DdosProtection, fail2ban: &Fail2BanManager) -> Self {
+        self.network_blocks = Some((ddos.network_blocks().clone(), fail2ban.network_bans().clone()));
+        self
+    }
+
+    /// Put back the blocks saved by a previous run through the modules that
+    /// own them, returning how many IPs and networks still had one in force
+    pub fn restore(&self, rate_limiter: &RateLimiter, ddos: &DdosProtection, fail2ban: &Fail2BanManager) -> Result<usize> {
+        let Some(path) = self.path.as_deref().filter(|path| path.exists()) else {
+            return Ok(0);
+        };
+        let saved = Self::load(path)?;
+        let (now, wall_now) = (Instant::now(), SystemTime::now());
+        let remaining = |until: Option<SystemTime>| {
+            until
+                .and_then(|until| until.duration_since(wall_now).ok())
+                .filter(|remaining| !remaining.is_zero())
+                .map(|remaining| now + remaining)
+        };
+
+        let mut restored = 0;
+        for blocks in saved.blocks {
+            let mut any = false;
+            if let Some(until) = remaining(blocks.rate_limited_until) {
+                rate_limiter.restore_block(blocks.ip, until);
+                any = true;
+            }
+            if let Some(until) = remaining(blocks.flood_blocked_until) {
+                ddos.restore_block(blocks.ip, until, blocks.flood_violations);
+                any = true;
+            }
+            if let Some(until) = remaining(blocks.banned_until) {
+                any |= fail2ban.restore_ban(blocks.ip, until, blocks.ban_count);
+            }
+            restored += usize::from(any);
+        }
+        for blocks in saved.networks {
+            let mut any = false;
+            if let Some(until) = remaining(blocks.flood_blocked_until) {
+                ddos.network_blocks().block_until(blocks.network, until);
+                any = true;
+            }
+            if let Some(until) = remaining(blocks.banned_until) {
+                any |= fail2ban.restore_network_ban(blocks.network, until);
+            }
+            restored += usize::from(any);
+        }
+        info!("Restored blocks for {} IP(s) and network(s) from {}", restored, path.display());
+        Ok(restored)
+    }
+
+    /// Write the blocks in force now, unless they are the same as last time
+    pub fn save(&self) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let content = serde_json::to_string_pretty(&self.snapshot())?;
+        let mut last_saved = self.last_saved.lock().unwrap();
+        if last_saved.as_deref() == Some(content.as_str()) {
+            return Ok(());
+        }
+        super::quota::write_atomically(path, &content)?;
+        debug!("Saved blocks to {}", path.display());
+        *last_saved = Some(content);
+        Ok(())
+    }
+
+    /// Blocks in force now, ordered by IP and network
+    pub fn snapshot(&self) -> SavedState {
+        let (now, wall_now) = (Instant::now(), SystemTime::now());
+        // Whole seconds keep the file unchanged between saves while nothing
+        // is blocked or lifted. Rounded to the nearest second, since the
+        // monotonic and wall clocks drift apart by a few microseconds between
+        // a restore and the next save, and rounding up would then move a
+        // restored deadline a second later.
+        let wall = |until: Instant| {
+            let at = wall_now + until.saturating_duration_since(now);
+            let since_epoch = at.duration_since(UNIX_EPOCH).unwrap_or_default();
+            let secs = since_epoch.as_secs() + u64::from(since_epoch.subsec_nanos() >= 500_000_000);
+            UNIX_EPOCH + Duration::from_secs(secs)
+        };
+
+        let records = self.ip_table.lock();
+        let mut blocks: Vec<SavedBlocks> = records.iter()
+            .filter_map(|(ip, record)| {
+                let rate = record.rate.as_ref().and_then(|rate| rate.blocked_until());
+                let flood = record.flood.as_ref().and_then(|flood| flood.block_state());
+                let ban = record.brute_force.as_ref().and_then(|detector| detector.ban_state());
+                if rate.is_none() && flood.is_none() && ban.is_none() {
+                    return None;
+                }
+                Some(SavedBlocks {
+                    ip: *ip,
+                    rate_limited_until: rate.map(wall),
+                    flood_blocked_until: flood.map(|(until, _)| wall(until)),
+                    flood_violations: flood.map_or(0, |(_, violations)| violations),
+                    banned_until: ban.map(|(until, _)| wall(until)),
+                    ban_count: ban.map_or(0, |(_, count)| count),
+                })
+            })
+            .collect();
+        drop(records);
+        blocks.sort_by_key(|blocks| blocks.ip);
+
+        let mut networks: Vec<SavedNetworkBlocks> = Vec::new();
+        if let Some((flood, bans)) = &self.network_blocks {
+            let entry = |networks: &mut Vec<SavedNetworkBlocks>, network: IpNet| {
+                match networks.iter().position(|saved| saved.network == network) {
+                    Some(index) => index,
+                    None => {
+                        networks.push(SavedNetworkBlocks { network, flood_blocked_until: None, banned_until: None });
+                        networks.len() - 1
+                    }
+                }
+            };
+            for (network, left) in flood.list() {
+                let index = entry(&mut networks, network);
+                networks[index].flood_blocked_until = Some(wall(now + left));
+            }
+            for (network, left) in bans.list() {
+                let index = entry(&mut networks, network);
+                networks[index].banned_until = Some(wall(now + left));
+            }
+        }
+        networks.sort_by_key(|blocks| blocks.network);
+        SavedState { blocks, networks }
+    }
+
+    fn load(path: &Path) -> Result<SavedState> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let state = serde_json::from_str(&content)
+            .with_context(|| format!("Failed to parse {}", path.display()))?;
+        Ok(match state {
+            StateFile::Current(state) => state,
+            StateFile::BlocksOnly(blocks) => SavedState { blocks, networks: Vec::new() },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::security::{DdosConfig, Fail2BanConfig, RateLimitConfig};
+
+    struct Modules {
+        table: Arc<IpSecurityTable>,
+        rate_limiter: RateLimiter,
+        ddos: DdosProtection,
+        fail2ban: Fail2BanManager,
+    }
+
+    fn modules(whitelist: &[&str]) -> Modules {
+        let table = Arc::new(IpSecurityTable::default());
+        Modules {
+            rate_limiter: RateLimiter::new(RateLimitConfig::default()).with_ip_table(table.clone()),
+            ddos: DdosProtection::new(DdosConfig::default()).with_ip_table(table.clone()),
+            fail2ban: Fail2BanManager::new(Fail2BanConfig {
+                whitelist_ips: whitelist.iter().map(|ip| ip.to_string()).collect(),
+                ..Fail2BanConfig::default()
+            })
+            .with_ip_table(table.clone()),
+            table,
+        }
+    }
+
+    #[test]
+    fn test_blocks_survive_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bans.json");
+        let (limited, flooding, banned): (IpAddr, IpAddr, IpAddr) =
+            ("192.0.2.1".parse().unwrap(), "192.0.2.2".parse().unwrap(), "2001:db8::3".parse().unwrap());
+
+        let before = modules(&[]);
+        before.rate_limiter.block_ip(limited, Duration::from_secs(600), "test");
+        before.ddos.block_ip(flooding, Duration::from_secs(600), "test");
+        before.fail2ban.ban_ip(banned, Duration::from_secs(3600), "test");
+        before.fail2ban.ban_ip(banned, Duration::from_secs(3600), "test");
+        before.ddos.block_network("198.51.100.0/24".parse().unwrap(), Duration::from_secs(600), "test");
+        before.fail2ban.ban_network("198.51.100.0/24".parse().unwrap(), Duration::from_secs(3600), "test");
+        before.fail2ban.ban_network("2001:db8:1::/48".parse().unwrap(), Duration::from_secs(3600), "test");
+        let store = BanStore::new(Some(path.clone()), before.table.clone())
+            .with_network_blocks(&before.ddos, &before.fail2ban);
+        store.save().unwrap();
+        let saved = BanStore::load(&path).unwrap();
+        assert_eq!(saved.blocks.iter().map(|blocks| blocks.ip).collect::<Vec<_>>(), [limited, flooding, banned]);
+        assert_eq!(saved.blocks[2].ban_count, 2);
+        assert_eq!(saved.networks.len(), 2);
+        assert!(saved.networks[0].flood_blocked_until.is_some() && saved.networks[0].banned_until.is_some());
+
+        let after = modules(&[]);
+        let store = BanStore::new(Some(path.clone()), after.table.clone())
+            .with_network_blocks(&after.ddos, &after.fail2ban);
+        assert_eq!(store.restore(&after.rate_limiter, &after.ddos, &after.fail2ban).unwrap(), 5);
+        assert!(after.rate_limiter.is_ip_blocked(limited));
+        assert!(after.ddos.is_ip_blocked(flooding));
+        assert!(after.fail2ban.is_ip_banned(banned));
+        assert!(!after.fail2ban.is_ip_banned(limited));
+        assert_eq!(after.fail2ban.get_ip_stats(banned).unwrap().ban_count, 2);
+        assert!(after.ddos.is_ip_blocked("198.51.100.7".parse().unwrap()));
+        assert!(after.fail2ban.is_ip_banned("2001:db8:1::7".parse().unwrap()));
+        // Nothing was lifted, so the deadlines come back unchanged
+        assert_eq!(store.snapshot(), saved);
+    }
+
+    #[test]
+    fn test_expired_and_whitelisted_blocks_are_dropped() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bans.json");
+        let past = SystemTime::now() - Duration::from_secs(60);
+        let future = SystemTime::now() + Duration::from_secs(600);
+        let saved = vec![
+            SavedBlocks {
+                ip: "192.0.2.1".parse().unwrap(),
+                rate_limited_until: Some(past),
+                flood_blocked_until: None,
+                flood_violations: 0,
+                banned_until: Some(past),
+                ban_count: 1,
+            },
+            SavedBlocks {
+                ip: "192.0.2.2".parse().unwrap(),
+                rate_limited_until: None,
+                flood_blocked_until: None,
+                flood_violations: 0,
+                banned_until: Some(future),
+                ban_count: 1,
+            },
+        ];
+        // A file written before network blocks were saved still loads
+        std::fs::write(&path, serde_json::to_string(&saved).unwrap()).unwrap();
+
+        let after = modules(&["192.0.2.2"]);
+        let store = BanStore::new(Some(path), after.table.clone());
+        assert_eq!(store.restore(&after.rate_limiter, &after.ddos, &after.fail2ban).unwrap(), 0);
+        assert!(after.table.is_empty());
+    }
+}
@@ -0,0 +1,226 @@
+//! Client Country Policy
+//!
+//! GeoIP rules on where clients connect from, checked at accept time before
+//! a single handshake byte is read. The access control GeoIP filter only
+//! looks at destinations; this one looks at the client's own address, so a
+//! deployment serving one region can turn the rest of the world away for
+//! the cost of a database lookup. Every decision is counted by country.
+//!
+//! If the database cannot be loaded, or GeoIP support is not compiled in,
+//! `security.failure_policies.geoip` decides whether clients are let in.
+
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+
+use super::{FailurePolicy, NetworkList};
+use crate::metrics::Metrics;
+use crate::routing::{GeoIpFilter, GeoIpReader};
+
+/// Country label for clients whose country is not known
+pub const UNKNOWN_COUNTRY: &str = "unknown";
+
+/// Client country policy configuration
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ClientGeoConfig {
+    /// Check client countries before the handshake
+    #[serde(default)]
+    pub enabled: bool,
+    /// MaxMind country database, such as GeoLite2-Country.mmdb
+    #[serde(default)]
+    pub database: Option<PathBuf>,
+    /// ISO country codes allowed to connect; empty allows every country not denied
+    #[serde(default)]
+    pub allow_countries: Vec<String>,
+    /// ISO country codes refused, whatever `allow_countries` says
+    #[serde(default)]
+    pub deny_countries: Vec<String>,
+    /// Let in clients the database has no country for; refusing them also
+    /// refuses private addresses unless they are exempt
+    #[serde(default = "default_allow_unknown")]
+    pub allow_unknown: bool,
+    /// Sources that are never refused, whatever their country
+    #[serde(default)]
+    pub exempt_cidrs: Vec<String>,
+}
+
+fn default_allow_unknown() -> bool {
+    true
+}
+
+impl Default for ClientGeoConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            database: None,
+            allow_countries: Vec::new(),
+            deny_countries: Vec::new(),
+            allow_unknown: default_allow_unknown(),
+            exempt_cidrs: Vec::new(),
+        }
+    }
+}
+
+/// Connections from one country let in and refused
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct CountryCounts {
+    pub allowed: u64,
+    pub denied: u64,
+}
+
+/// Accept-time check of the client's country
+pub struct ClientGeoPolicy {
+    filter: Option<GeoIpFilter>,
+    failure_policy: FailurePolicy,
+    allow: HashSet<String>,
+    deny: HashSet<String>,
+    allow_unknown: bool,
+    exempt: NetworkList,
+    counts: Mutex<HashMap<String, CountryCounts>>,
+    metrics: Option<Arc<Metrics>>,
+}
+
+impl ClientGeoPolicy {
+    /// Load the database; if it cannot be used, `failure_policy` decides every connection
+    pub fn new(config: &ClientGeoConfig, failure_policy: FailurePolicy) -> Self {
+        let filter = match &config.database {
+            Some(path) => match GeoIpReader::new(path) {
+                Ok(reader) if reader.is_available() => {
+                    info!("Loaded client GeoIP database {}", path.display());
+                    Some(GeoIpFilter::new(reader))
+                }
+                Ok(_) => {
+                    warn!("GeoIP support is not compiled in, client countries cannot be checked (failure policy: {})",
+                          failure_policy);
+                    None
+                }
+                Err(e) => {
+                    warn!("Failed to load client GeoIP database {} (failure policy: {}): {}",
+                          path.display(), failure_policy, e);
+                    None
+                }
+            },
+            None => None,
+        };
+        let codes = |countries: &[String]| countries.iter().map(|code| code.trim().to_ascii_uppercase()).collect();
+        Self {
+            filter,
+            failure_policy,
+            allow: codes(&config.allow_countries),
+            deny: codes(&config.deny_countries),
+            allow_unknown: config.allow_unknown,
+            exempt: NetworkList::parse(&config.exempt_cidrs, "security.client_geo.exempt_cidrs"),
+            counts: Mutex::new(HashMap::new()),
+            metrics: None,
+        }
+    }
+
+    /// Count decisions in the Prometheus metrics as well
+    pub fn with_metrics(mut self, metrics: Option<Arc<Metrics>>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Why a client from `ip` is refused, or `None` to let it connect
+    pub fn check(&self, ip: IpAddr) -> Option<String> {
+        let ip = ip.to_canonical();
+        if self.exempt.contains(ip) {
+            return None;
+        }
+        let Some(filter) = &self.filter else {
+            let allowed = self.failure_policy.allows();
+            self.record(UNKNOWN_COUNTRY, allowed);
+            return (!allowed).then(|| "client GeoIP database unavailable".to_string());
+        };
+
+        let country = filter.get_country(ip).map(|code| code.to_ascii_uppercase());
+        let allowed = self.allows(country.as_deref());
+        debug!("Client {} country {:?}: allowed={}", ip, country, allowed);
+        self.record(country.as_deref().unwrap_or(UNKNOWN_COUNTRY), allowed);
+        if allowed {
+            return None;
+        }
+        Some(match country {
+            Some(country) => format!("client country {} is not allowed", country),
+            None => "client country unknown".to_string(),
+        })
+    }
+
+    /// Whether clients from `country` may connect
+    fn allows(&self, country: Option<&str>) -> bool {
+        match country {
+            Some(country) => !self.deny.contains(country) && (self.allow.is_empty() || self.allow.contains(country)),
+            None => self.allow_unknown,
+        }
+    }
+
+    fn record(&self, country: &str, allowed: bool) {
+        let mut counts = self.counts.lock().unwrap();
+        let entry = counts.entry(country.to_string()).or_default();
+        if allowed {
+            entry.allowed += 1;
+        } else {
+            entry.denied += 1;
+        }
+        drop(counts);
+        if let Some(metrics) = &self.metrics {
+            metrics.record_client_geo_decision(country, allowed);
+        }
+    }
+
+    /// Connections let in and refused per country, by country code
+    pub fn counts(&self) -> Vec<(String, CountryCounts)> {
+        let mut counts: Vec<_> = self.counts.lock().unwrap()
+            .iter()
+            .map(|(country, counts)| (country.clone(), *counts))
+            .collect();
+        counts.sort_by(|a, b| a.0.cmp(&b.0));
+        counts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(allow: &[&str], deny: &[&str]) -> ClientGeoConfig {
+        ClientGeoConfig {
+            enabled: true,
+            allow_countries: allow.iter().map(|code| code.to_string()).collect(),
+            deny_countries: deny.iter().map(|code| code.to_string()).collect(),
+            allow_unknown: false,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_allow_and_deny_lists() {
+        let policy = ClientGeoPolicy::new(&config(&["de", "FR"], &["FR"]), FailurePolicy::Open);
+        assert!(policy.allows(Some("DE")));
+        assert!(!policy.allows(Some("FR")));
+        assert!(!policy.allows(Some("US")));
+        assert!(!policy.allows(None));
+
+        let deny_only = ClientGeoPolicy::new(&config(&[], &["RU"]), FailurePolicy::Open);
+        assert!(deny_only.allows(Some("US")));
+        assert!(!deny_only.allows(Some("RU")));
+    }
+
+    #[test]
+    fn test_unavailable_database_follows_failure_policy() {
+        let ip = "203.0.113.9".parse().unwrap();
+        let open = ClientGeoPolicy::new(&config(&["DE"], &[]), FailurePolicy::Open);
+        assert_eq!(open.check(ip), None);
+
+        let mut closed_config = config(&["DE"], &[]);
+        closed_config.exempt_cidrs = vec!["10.0.0.0/8".to_string()];
+        let closed = ClientGeoPolicy::new(&closed_config, FailurePolicy::Closed);
+        assert!(closed.check(ip).is_some());
+        assert_eq!(closed.check("10.1.2.3".parse().unwrap()), None);
+        assert_eq!(closed.counts(), vec![(UNKNOWN_COUNTRY.to_string(), CountryCounts { allowed: 0, denied: 1 })]);
+    }
+}
@@ -3,12 +3,19 @@
 //! Provides connection flood detection and mitigation to protect against
 //! distributed denial of service attacks.
 
-use std::collections::{HashMap, VecDeque};
+use std::collections::VecDeque;
 use std::net::IpAddr;
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
 use tracing::{debug, warn, info};
+use super::{BlockReason, SecurityEvent};
+use super::fail2ban_log::Fail2BanLog;
+use super::siem::SiemExporter;
+use super::ip_table::IpSecurityTable;
+use super::networks::{self, NetworkBlocks, NetworkList};
+use ipnet::IpNet;
 
 /// DDoS protection configuration
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -23,6 +30,27 @@ pub struct DdosConfig {
     pub base_delay_ms: u64,
     pub max_delay_ms: u64,
     pub cleanup_interval_seconds: u64,
+    /// Queue connections over `max_connections_per_ip` instead of rejecting them
+    #[serde(default)]
+    pub queue_over_ip_limit: bool,
+    /// Maximum number of queued connections per IP
+    #[serde(default = "default_per_ip_queue_size")]
+    pub per_ip_queue_size: u32,
+    /// How long a queued connection waits for a free slot before being dropped
+    #[serde(default = "default_per_ip_queue_timeout_ms")]
+    pub per_ip_queue_timeout_ms: u64,
+    /// Addresses or CIDR ranges exempt from flood detection and per-IP
+    /// limits; they still count towards the global connection limit
+    #[serde(default)]
+    pub whitelist_ips: Vec<String>,
+}
+
+fn default_per_ip_queue_size() -> u32 {
+    16
+}
+
+fn default_per_ip_queue_timeout_ms() -> u64 {
+    5000
 }
 
 impl Default for DdosConfig {
@@ -38,19 +66,25 @@ impl Default for DdosConfig {
             base_delay_ms: 100,
             max_delay_ms: 5000,
             cleanup_interval_seconds: 300, // 5 minutes
+            queue_over_ip_limit: false,
+            per_ip_queue_size: default_per_ip_queue_size(),
+            per_ip_queue_timeout_ms: default_per_ip_queue_timeout_ms(),
+            whitelist_ips: Vec::new(),
         }
     }
 }
 
 /// Connection flood detector for tracking connection patterns
 #[derive(Debug)]
-struct ConnectionFloodDetector {
+pub(super) struct ConnectionFloodDetector {
     connection_times: VecDeque<Instant>,
     total_connections: u64,
     blocked_until: Option<Instant>,
     last_activity: Instant,
     current_connections: u32,
     violation_count: u32,
+    queued_connections: u32,
+    slot_released: Arc<Notify>,
 }
 
 impl ConnectionFloodDetector {
@@ -62,6 +96,8 @@ impl ConnectionFloodDetector {
             last_activity: Instant::now(),
             current_connections: 0,
             violation_count: 0,
+            queued_connections: 0,
+            slot_released: Arc::new(Notify::new()),
         }
     }
 
@@ -128,10 +164,30 @@ impl ConnectionFloodDetector {
         if self.current_connections > 0 {
             self.current_connections -= 1;
         }
+        self.slot_released.notify_one();
+    }
+
+    /// Whether to keep this state once the IP has gone idle
+    pub(super) fn should_retain(&self) -> bool {
+        self.is_blocked() || self.current_connections > 0 || self.queued_connections > 0
+    }
+
+    /// End of the current block and the violations that led to it, if one is in force
+    pub(super) fn block_state(&self) -> Option<(Instant, u32)> {
+        self.blocked_until
+            .filter(|&blocked_until| Instant::now() < blocked_until)
+            .map(|blocked_until| (blocked_until, self.violation_count))
+    }
+
+    /// Time left on the current block, zero if there is none
+    fn time_until_unblock(&self) -> Duration {
+        self.blocked_until
+            .map(|blocked_until| blocked_until.saturating_duration_since(Instant::now()))
+            .unwrap_or_default()
     }
 
     /// Check if IP is currently blocked
-    fn is_blocked(&self) -> bool {
+    pub(super) fn is_blocked(&self) -> bool {
         if let Some(blocked_until) = self.blocked_until {
             Instant::now() < blocked_until
         } else {
@@ -164,8 +220,13 @@ impl ConnectionFloodDetector {
 /// Main DDoS protection implementation
 pub struct DdosProtection {
     config: DdosConfig,
-    ip_detectors: Arc<Mutex<HashMap<IpAddr, ConnectionFloodDetector>>>,
+    ip_table: Arc<IpSecurityTable>,
+    whitelist: NetworkList,
+    /// Manual blocks on whole networks
+    network_blocks: Arc<NetworkBlocks>,
     global_stats: Arc<Mutex<GlobalDdosStats>>,
+    event_log: Option<Arc<Fail2BanLog>>,
+    siem: Option<Arc<SiemExporter>>,
 }
 
 #[derive(Debug, Default)]
@@ -174,17 +235,52 @@ struct GlobalDdosStats {
     total_connections_blocked: u64,
     total_ddos_events: u64,
     current_global_connections: u32,
-    currently_blocked_ips: usize,
     peak_global_connections: u32,
+    total_connections_queued: u64,
+    total_queue_timeouts: u64,
 }
 
 impl DdosProtection {
     /// Create a new DDoS protection instance
     pub fn new(config: DdosConfig) -> Self {
+        let idle_timeout = Duration::from_secs(config.cleanup_interval_seconds * 2);
         Self {
+            whitelist: NetworkList::parse(&config.whitelist_ips, "DDoS whitelist"),
+            network_blocks: Arc::new(NetworkBlocks::default()),
             config,
-            ip_detectors: Arc::new(Mutex::new(HashMap::new())),
+            ip_table: Arc::new(IpSecurityTable::new(idle_timeout)),
             global_stats: Arc::new(Mutex::new(GlobalDdosStats::default())),
+            event_log: None,
+            siem: None,
+        }
+    }
+
+    /// Keep per-IP state in a table shared with the other security modules
+    pub fn with_ip_table(mut self, ip_table: Arc<IpSecurityTable>) -> Self {
+        self.ip_table = ip_table;
+        self
+    }
+
+    /// Write blocks to the fail2ban log so the system fail2ban can extend them
+    pub fn with_event_log(mut self, event_log: Arc<Fail2BanLog>) -> Self {
+        self.event_log = Some(event_log);
+        self
+    }
+
+    /// Forward blocks to a SIEM
+    pub fn with_siem(mut self, siem: Arc<SiemExporter>) -> Self {
+        self.siem = Some(siem);
+        self
+    }
+
+    fn log_block(&self, ip: IpAddr, duration: Duration, detail: &str) {
+        if let Some(event_log) = &self.event_log {
+            if let Err(e) = event_log.ban(ip, BlockReason::Ddos, duration) {
+                warn!("Failed to write fail2ban log line: {:#}", e);
+            }
+        }
+        if let Some(siem) = &self.siem {
+            siem.record(&SecurityEvent::IpBlocked { ip, reason: BlockReason::Ddos, detail: detail.to_string(), duration });
         }
     }
 
@@ -210,8 +306,22 @@ impl DdosProtection {
             };
         }
 
-        let mut ip_detectors = self.ip_detectors.lock().unwrap();
-        let detector = ip_detectors.entry(ip).or_insert_with(ConnectionFloodDetector::new);
+        if self.whitelist.contains(ip) {
+            debug!("IP {} is whitelisted, skipping DDoS checks", ip);
+            return DdosDecision::Allow;
+        }
+
+        if self.network_blocks.time_left(ip).is_some() {
+            debug!("Connection from {} blocked, its network is blocked", ip);
+            self.increment_blocked_connections();
+            return DdosDecision::Block {
+                reason: "Network temporarily blocked by DDoS protection".to_string(),
+                delay: Duration::from_millis(self.config.base_delay_ms),
+            };
+        }
+
+        let mut records = self.ip_table.lock();
+        let detector = records.get_or_insert_with(ip, Default::default).flood.get_or_insert_with(ConnectionFloodDetector::new);
 
         // Check if IP is currently blocked
         if detector.is_blocked() {
@@ -223,6 +333,31 @@ impl DdosProtection {
             };
         }
 
+        // Over the concurrent limit: queue if there is room, otherwise reject
+        if detector.exceeds_concurrent_limit(&self.config)
+            && self.config.queue_over_ip_limit
+            && detector.queued_connections < self.config.per_ip_queue_size
+        {
+            if !detector.record_connection(&self.config) {
+                let delay = detector.get_progressive_delay(&self.config);
+                let duration = detector.time_until_unblock();
+                drop(records);
+                self.increment_blocked_connections();
+                self.log_block(ip, duration, "DDoS attack pattern detected");
+                return DdosDecision::Block {
+                    reason: "DDoS attack pattern detected".to_string(),
+                    delay,
+                };
+            }
+            detector.queued_connections += 1;
+            debug!("Queueing connection from {} ({} concurrent, {} queued)",
+                   ip, detector.current_connections, detector.queued_connections);
+            self.global_stats.lock().unwrap().total_connections_queued += 1;
+            return DdosDecision::Queue {
+                timeout: Duration::from_millis(self.config.per_ip_queue_timeout_ms),
+            };
+        }
+
         // Check concurrent connection limit
         if detector.exceeds_concurrent_limit(&self.config) {
             warn!("Concurrent connection limit exceeded for IP {}: {} connections", 
@@ -240,6 +375,9 @@ impl DdosProtection {
             DdosDecision::Allow
         } else {
             info!("DDoS attack detected from {}, blocking connection", ip);
+            let delay = detector.get_progressive_delay(&self.config);
+            let duration = detector.time_until_unblock();
+            drop(records);
             
             // Update global DDoS event counter
             {
@@ -248,31 +386,83 @@ impl DdosProtection {
             }
             
             self.increment_blocked_connections();
+            self.log_block(ip, duration, "DDoS attack pattern detected");
             DdosDecision::Block {
                 reason: "DDoS attack pattern detected".to_string(),
-                delay: detector.get_progressive_delay(&self.config),
+                delay,
             }
         }
     }
 
+    /// Wait for a queued connection to get a slot under the per-IP limit
+    ///
+    /// Must follow a `DdosDecision::Queue` for the same IP. The slot is
+    /// claimed under the same lock that finds it free, so two waiters never
+    /// take one slot; on success the connection counts as started and
+    /// `connection_started` must not be called for it. Returns `false` if no
+    /// slot became free within the timeout.
+    pub async fn wait_for_slot(&self, ip: IpAddr, wait: Duration) -> bool {
+        let deadline = tokio::time::Instant::now() + wait;
+        loop {
+            let notify = {
+                let mut records = self.ip_table.lock();
+                match records.get_mut(&ip).and_then(|record| record.flood.as_mut()) {
+                    Some(detector) if detector.exceeds_concurrent_limit(&self.config) => {
+                        detector.slot_released.clone()
+                    }
+                    Some(detector) => {
+                        detector.queued_connections = detector.queued_connections.saturating_sub(1);
+                        detector.connection_started();
+                        drop(records);
+                        self.count_global_connection();
+                        return true;
+                    }
+                    None => {
+                        drop(records);
+                        self.count_global_connection();
+                        return true;
+                    }
+                }
+            };
+
+            if tokio::time::timeout_at(deadline, notify.notified()).await.is_err() {
+                break;
+            }
+        }
+
+        let mut records = self.ip_table.lock();
+        if let Some(detector) = records.get_mut(&ip).and_then(|record| record.flood.as_mut()) {
+            detector.queued_connections = detector.queued_connections.saturating_sub(1);
+        }
+        drop(records);
+
+        warn!("Queued connection from {} timed out after {:?}", ip, wait);
+        let mut stats = self.global_stats.lock().unwrap();
+        stats.total_queue_timeouts += 1;
+        stats.total_connections_blocked += 1;
+        false
+    }
+
     /// Record that a connection has started (for concurrent tracking)
     pub fn connection_started(&self, ip: IpAddr) {
         if !self.config.enabled {
             return;
         }
 
-        let mut ip_detectors = self.ip_detectors.lock().unwrap();
-        if let Some(detector) = ip_detectors.get_mut(&ip) {
+        let mut records = self.ip_table.lock();
+        if let Some(detector) = records.get_mut(&ip).and_then(|record| record.flood.as_mut()) {
             detector.connection_started();
         }
+        drop(records);
+        self.count_global_connection();
+    }
 
-        // Update global connection count
-        {
-            let mut stats = self.global_stats.lock().unwrap();
-            stats.current_global_connections += 1;
-            if stats.current_global_connections > stats.peak_global_connections {
-                stats.peak_global_connections = stats.current_global_connections;
-            }
+    /// Update the global connection count for a connection that started
+    fn count_global_connection(&self) {
+        let mut stats = self.global_stats.lock().unwrap();
+        stats.current_global_connections += 1;
+        if stats.current_global_connections > stats.peak_global_connections {
+            stats.peak_global_connections = stats.current_global_connections;
         }
     }
 
@@ -282,8 +472,8 @@ impl DdosProtection {
             return;
         }
 
-        let mut ip_detectors = self.ip_detectors.lock().unwrap();
-        if let Some(detector) = ip_detectors.get_mut(&ip) {
+        let mut records = self.ip_table.lock();
+        if let Some(detector) = records.get_mut(&ip).and_then(|record| record.flood.as_mut()) {
             detector.connection_ended();
         }
 
@@ -298,19 +488,63 @@ impl DdosProtection {
 
     /// Manually block an IP address
     pub fn block_ip(&self, ip: IpAddr, duration: Duration, reason: &str) {
-        let mut ip_detectors = self.ip_detectors.lock().unwrap();
-        let detector = ip_detectors.entry(ip).or_insert_with(ConnectionFloodDetector::new);
+        let mut records = self.ip_table.lock();
+        let detector = records.get_or_insert_with(ip, Default::default).flood.get_or_insert_with(ConnectionFloodDetector::new);
         
         detector.blocked_until = Some(Instant::now() + duration);
         detector.violation_count += 1;
         
         info!("Manually blocked IP {} for {:?}: {}", ip, duration, reason);
+        drop(records);
+        self.log_block(ip, duration, reason);
+    }
+
+    /// Manually block a network, or a single address given as `/32` or `/128`
+    ///
+    /// Whitelisted addresses inside the network stay allowed.
+    pub fn block_network(&self, network: IpNet, duration: Duration, reason: &str) {
+        if let Some(ip) = networks::single_address(&network) {
+            return self.block_ip(ip, duration, reason);
+        }
+        self.network_blocks.block(network, duration);
+        info!("Manually blocked network {} for {:?}: {}", network, duration, reason);
+    }
+
+    /// Lift a network block, or the block on a single address given as `/32` or `/128`
+    pub fn unblock_network(&self, network: IpNet) -> bool {
+        if let Some(ip) = networks::single_address(&network) {
+            return self.unblock_ip(ip);
+        }
+        let lifted = self.network_blocks.unblock(&network);
+        if lifted {
+            info!("Unblocked network {} from DDoS protection", network);
+        }
+        lifted
+    }
+
+    /// Networks blocked now and the time left on each
+    pub fn get_blocked_networks(&self) -> Vec<(IpNet, Duration)> {
+        self.network_blocks.list()
+    }
+
+    /// Network blocks, for saving them across a restart
+    pub(super) fn network_blocks(&self) -> &Arc<NetworkBlocks> {
+        &self.network_blocks
+    }
+
+    /// Put back a block saved before a restart, keeping its violation count
+    /// so the next block escalates as it would have
+    pub(super) fn restore_block(&self, ip: IpAddr, blocked_until: Instant, violations: u32) {
+        let mut records = self.ip_table.lock();
+        let detector = records.get_or_insert_with(ip, Default::default).flood.get_or_insert_with(ConnectionFloodDetector::new);
+        detector.blocked_until = Some(blocked_until);
+        detector.violation_count = violations;
     }
 
     /// Unblock an IP address
     pub fn unblock_ip(&self, ip: IpAddr) -> bool {
-        let mut ip_detectors = self.ip_detectors.lock().unwrap();
-        if let Some(detector) = ip_detectors.get_mut(&ip) {
+        let mut records = self.ip_table.lock();
+        if let Some(detector) = records.get_mut(&ip).and_then(|record| record.flood.as_mut()) {
             if detector.is_blocked() {
                 detector.blocked_until = None;
                 detector.violation_count = 0;
@@ -321,10 +555,13 @@ impl DdosProtection {
         false
     }
 
-    /// Check if an IP is currently blocked
+    /// Check if an IP is currently blocked, on its own or as part of a network
     pub fn is_ip_blocked(&self, ip: IpAddr) -> bool {
-        let ip_detectors = self.ip_detectors.lock().unwrap();
-        if let Some(detector) = ip_detectors.get(&ip) {
+        if self.network_blocks.time_left(ip).is_some() && !self.whitelist.contains(ip) {
+            return true;
+        }
+        let records = self.ip_table.lock();
+        if let Some(detector) = records.get(&ip).and_then(|record| record.flood.as_ref()) {
             detector.is_blocked()
         } else {
             false
@@ -333,58 +570,41 @@ impl DdosProtection {
 
     /// Get list of currently blocked IPs
     pub fn get_blocked_ips(&self) -> Vec<IpAddr> {
-        let ip_detectors = self.ip_detectors.lock().unwrap();
-        ip_detectors.iter()
-            .filter(|(_, detector)| detector.is_blocked())
+        let records = self.ip_table.lock();
+        records.iter()
+            .filter(|(_, record)| record.is_flood_blocked())
             .map(|(ip, _)| *ip)
             .collect()
     }
 
-    /// Clean up old detector entries
+    /// Clean up IPs that have gone idle
     pub fn cleanup_old_entries(&self) {
-        let cleanup_threshold = Duration::from_secs(self.config.cleanup_interval_seconds * 2);
-        let cutoff_time = Instant::now() - cleanup_threshold;
-        
-        let mut ip_detectors = self.ip_detectors.lock().unwrap();
-        let initial_count = ip_detectors.len();
-        
-        ip_detectors.retain(|_, detector| {
-            // Keep if recently active, currently blocked, or has active connections
-            detector.last_activity > cutoff_time || 
-            detector.is_blocked() || 
-            detector.current_connections > 0
-        });
-        
-        let removed_count = initial_count - ip_detectors.len();
+        let removed_count = self.ip_table.expire();
         if removed_count > 0 {
-            debug!("Cleaned up {} old DDoS detector entries", removed_count);
-        }
-
-        // Update blocked IP count in stats
-        let blocked_count = ip_detectors.iter().filter(|(_, detector)| detector.is_blocked()).count();
-        {
-            let mut stats = self.global_stats.lock().unwrap();
-            stats.currently_blocked_ips = blocked_count;
+            debug!("Cleaned up {} idle IP entries", removed_count);
         }
     }
 
     /// Get DDoS protection statistics
     pub fn get_stats(&self) -> DdosStats {
+        let currently_blocked_ips = self.ip_table.lock().values().filter(|record| record.is_flood_blocked()).count();
         let stats = self.global_stats.lock().unwrap();
         DdosStats {
             total_connections_checked: stats.total_connections_checked,
             total_connections_blocked: stats.total_connections_blocked,
             total_ddos_events: stats.total_ddos_events,
             current_global_connections: stats.current_global_connections,
-            currently_blocked_ips: stats.currently_blocked_ips,
+            currently_blocked_ips,
             peak_global_connections: stats.peak_global_connections,
+            total_connections_queued: stats.total_connections_queued,
+            total_queue_timeouts: stats.total_queue_timeouts,
         }
     }
 
     /// Get detailed IP statistics
     pub fn get_ip_stats(&self, ip: IpAddr) -> Option<IpDdosStats> {
-        let ip_detectors = self.ip_detectors.lock().unwrap();
-        ip_detectors.get(&ip).map(|detector| IpDdosStats {
+        let records = self.ip_table.lock();
+        records.get(&ip).and_then(|record| record.flood.as_ref()).map(|detector| IpDdosStats {
             ip,
             total_connections: detector.total_connections,
             current_connections: detector.current_connections,
@@ -398,8 +618,8 @@ impl DdosProtection {
 
     /// Get all IP statistics
     pub fn get_all_ip_stats(&self) -> Vec<IpDdosStats> {
-        let ip_detectors = self.ip_detectors.lock().unwrap();
-        ip_detectors.iter().map(|(ip, detector)| IpDdosStats {
+        let records = self.ip_table.lock();
+        records.iter().filter_map(|(ip, record)| record.flood.as_ref().map(|detector| (ip, detector))).map(|(ip, detector)| IpDdosStats {
             ip: *ip,
             total_connections: detector.total_connections,
             current_connections: detector.current_connections,
@@ -430,6 +650,20 @@ pub enum DdosDecision {
         reason: String,
         delay: Duration,
     },
+    /// Over the per-IP concurrent limit; wait for a slot with `wait_for_slot`
+    Queue {
+        timeout: Duration,
+    },
+}
+
+impl DdosDecision {
+    /// Get the reason code if this decision blocks the connection
+    pub fn reason_code(&self) -> Option<BlockReason> {
+        match self {
+            DdosDecision::Allow | DdosDecision::Queue { .. } => None,
+            DdosDecision::Block { .. } => Some(BlockReason::Ddos),
+        }
+    }
 }
 
 /// DDoS protection statistics
@@ -441,6 +675,8 @@ pub struct DdosStats {
     pub current_global_connections: u32,
     pub currently_blocked_ips: usize,
     pub peak_global_connections: u32,
+    pub total_connections_queued: u64,
+    pub total_queue_timeouts: u64,
 }
 
 /// Statistics for a specific IP address
@@ -528,6 +764,30 @@ mod tests {
         assert!(matches!(protection.check_connection(ip), DdosDecision::Allow));
     }
 
+    #[test]
+    fn test_network_whitelist_and_block() {
+        let config = DdosConfig {
+            enabled: true,
+            connection_threshold: 2,
+            whitelist_ips: vec!["10.20.0.0/16".to_string()],
+            ..Default::default()
+        };
+        let protection = DdosProtection::new(config);
+        let office: IpAddr = "10.20.4.5".parse().unwrap();
+
+        // Whitelisted sources are never flood-blocked
+        for _ in 0..10 {
+            assert!(matches!(protection.check_connection(office), DdosDecision::Allow));
+        }
+
+        protection.block_network("2001:db8::/32".parse().unwrap(), Duration::from_secs(60), "test");
+        assert!(protection.is_ip_blocked("2001:db8:5::1".parse().unwrap()));
+        assert!(matches!(protection.check_connection("2001:db8:5::1".parse().unwrap()), DdosDecision::Block { .. }));
+        assert!(matches!(protection.check_connection("2001:db9::1".parse().unwrap()), DdosDecision::Allow));
+        assert!(protection.unblock_network("2001:db8::/32".parse().unwrap()));
+        assert!(!protection.is_ip_blocked("2001:db8:5::1".parse().unwrap()));
+    }
+
     #[test]
     fn test_ddos_protection_disabled() {
         let config = DdosConfig {
@@ -563,4 +823,79 @@ mod tests {
         assert!(!protection.is_ip_blocked(ip));
         assert!(matches!(protection.check_connection(ip), DdosDecision::Allow));
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_queue_over_ip_limit() {
+        let config = DdosConfig {
+            enabled: true,
+            max_connections_per_ip: 1,
+            connection_threshold: 100,
+            queue_over_ip_limit: true,
+            per_ip_queue_size: 1,
+            ..Default::default()
+        };
+        
+        let protection = Arc::new(DdosProtection::new(config));
+        let ip: IpAddr = "127.0.0.1".parse().unwrap();
+        
+        assert!(matches!(protection.check_connection(ip), DdosDecision::Allow));
+        protection.connection_started(ip);
+        
+        // Over the limit: first connection queues, the next overflows the queue
+        let wait = match protection.check_connection(ip) {
+            DdosDecision::Queue { timeout } => timeout,
+            other => panic!("Expected queue decision, got {:?}", other),
+        };
+        assert!(matches!(protection.check_connection(ip), DdosDecision::Block { .. }));
+        
+        // Freeing a slot releases the queued connection
+        let waiter = {
+            let protection = Arc::clone(&protection);
+            tokio::spawn(async move { protection.wait_for_slot(ip, wait).await })
+        };
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        protection.connection_ended(ip);
+        assert!(waiter.await.unwrap());
+        
+        // The released connection holds the slot, so the next one waits in vain
+        assert!(matches!(protection.check_connection(ip), DdosDecision::Queue { .. }));
+        assert!(!protection.wait_for_slot(ip, Duration::from_millis(20)).await);
+        assert_eq!(protection.get_stats().total_queue_timeouts, 1);
+    }
+
+    #[tokio::test]
+    async fn test_freed_slot_goes_to_one_waiter() {
+        let config = DdosConfig {
+            enabled: true,
+            max_connections_per_ip: 1,
+            connection_threshold: 100,
+            queue_over_ip_limit: true,
+            per_ip_queue_size: 4,
+            ..Default::default()
+        };
+        let protection = Arc::new(DdosProtection::new(config));
+        let ip: IpAddr = "127.0.0.1".parse().unwrap();
+        assert!(matches!(protection.check_connection(ip), DdosDecision::Allow));
+        protection.connection_started(ip);
+
+        let waiters: Vec<_> = (0..4).map(|_| {
+            assert!(matches!(protection.check_connection(ip), DdosDecision::Queue { .. }));
+            let protection = Arc::clone(&protection);
+            tokio::spawn(async move { protection.wait_for_slot(ip, Duration::from_millis(200)).await })
+        }).collect();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        // Releasing the slot twice over wakes waiters, but only one may take it
+        protection.connection_ended(ip);
+        protection.ip_table.lock().get(&ip).unwrap().flood.as_ref().unwrap().slot_released.notify_waiters();
+
+        let mut acquired = 0;
+        for waiter in waiters {
+            acquired += usize::from(waiter.await.unwrap());
+        }
+        assert_eq!(acquired, 1);
+        let records = protection.ip_table.lock();
+        let detector = records.get(&ip).unwrap().flood.as_ref().unwrap();
+        assert_eq!((detector.current_connections, detector.queued_connections), (1, 0));
+    }
+}
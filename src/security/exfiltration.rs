@@ -0,0 +1,340 @@
+//! Exfiltration Alerts
+//!
+//! A SOCKS proxy is a convenient way to move data out of a network, and the
+//! tell is volume going the wrong way: far more bytes uploaded than a user or
+//! a single connection normally sends. This observer counts upload bytes per
+//! connection and per user over a fixed window and raises an alert the first
+//! time either crosses its threshold in that window. Relays are never slowed
+//! or stopped; alerts go to the log, the SIEM exporter, and optionally a
+//! webhook.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tracing::{debug, warn};
+
+use super::{SecurityEvent, SiemExporter};
+use crate::relay::{RelayControl, RelayObserver, RelaySession};
+use crate::Result;
+
+/// Alerts waiting for webhook delivery; more are dropped
+const WEBHOOK_QUEUE_CAPACITY: usize = 256;
+
+/// Exfiltration alert configuration
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ExfiltrationConfig {
+    /// Watch upload volumes
+    #[serde(default)]
+    pub enabled: bool,
+    /// Period the thresholds apply to; counts start again after it
+    #[serde(default = "default_window", with = "humantime_serde")]
+    pub window: Duration,
+    /// Upload bytes one connection may send in a window before an alert
+    #[serde(default)]
+    pub connection_upload_bytes: Option<u64>,
+    /// Upload bytes one user may send in a window, over all connections, before an alert
+    #[serde(default)]
+    pub user_upload_bytes: Option<u64>,
+    /// Endpoint each alert is posted to as JSON
+    #[serde(default)]
+    pub webhook: Option<ExfiltrationWebhookConfig>,
+}
+
+fn default_window() -> Duration {
+    Duration::from_secs(600)
+}
+
+impl Default for ExfiltrationConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            window: default_window(),
+            connection_upload_bytes: None,
+            user_upload_bytes: None,
+            webhook: None,
+        }
+    }
+}
+
+/// Where exfiltration alerts are posted
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ExfiltrationWebhookConfig {
+    pub url: String,
+    /// Sent as `Authorization: Bearer <token>`
+    #[serde(default)]
+    pub bearer_token: Option<String>,
+    #[serde(default = "default_webhook_timeout", with = "humantime_serde")]
+    pub timeout: Duration,
+}
+
+fn default_webhook_timeout() -> Duration {
+    Duration::from_secs(10)
+}
+
+/// What crossed its threshold
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExfiltrationScope {
+    Connection,
+    User,
+}
+
+impl ExfiltrationScope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ExfiltrationScope::Connection => "connection",
+            ExfiltrationScope::User => "user",
+        }
+    }
+}
+
+/// One alert as posted to the webhook
+#[derive(Debug, Clone, Serialize)]
+pub struct ExfiltrationAlert {
+    pub scope: ExfiltrationScope,
+    pub session_id: String,
+    /// Left out, like `user` and `destination`, for connections excluded by
+    /// the privacy settings
+    pub client_ip: Option<IpAddr>,
+    pub user: Option<String>,
+    pub destination: Option<SocketAddr>,
+    /// Bytes uploaded in the current window when the threshold was crossed
+    pub bytes_up: u64,
+    pub threshold: u64,
+    pub window_secs: u64,
+    /// RFC 3339 time of the alert
+    pub timestamp: String,
+}
+
+/// Upload bytes counted in the current window
+struct WindowCount {
+    started: Instant,
+    bytes: u64,
+    alerted: bool,
+}
+
+impl WindowCount {
+    fn new() -> Self {
+        Self { started: Instant::now(), bytes: 0, alerted: false }
+    }
+
+    /// Add `bytes`, starting a new window if the current one is over, and
+    /// return the total if this crossed `threshold` for the first time in the window
+    fn add(&mut self, bytes: u64, threshold: u64, window: Duration) -> Option<u64> {
+        if self.started.elapsed() >= window {
+            *self = Self::new();
+        }
+        self.bytes += bytes;
+        if self.alerted || self.bytes <= threshold {
+            return None;
+        }
+        self.alerted = true;
+        Some(self.bytes)
+    }
+}
+
+/// Relay observer that raises alerts on large upload volumes
+pub struct ExfiltrationMonitor {
+    config: ExfiltrationConfig,
+    connections: Mutex<HashMap<String, (Option<String>, WindowCount)>>,
+    users: Mutex<HashMap<String, WindowCount>>,
+    siem: Option<Arc<SiemExporter>>,
+    webhook: Option<mpsc::Sender<ExfiltrationAlert>>,
+}
+
+impl ExfiltrationMonitor {
+    /// Start watching, delivering alerts to the configured webhook in the background
+    pub fn new(config: ExfiltrationConfig, siem: Option<Arc<SiemExporter>>) -> Result<Self> {
+        let webhook = match &config.webhook {
+            Some(webhook) => {
+                let client = reqwest::Client::builder()
+                    .timeout(webhook.timeout)
+                    .build()
+                    .context("Failed to build exfiltration webhook HTTP client")?;
+                let (queue, alerts) = mpsc::channel(WEBHOOK_QUEUE_CAPACITY);
+                tokio::spawn(deliver(client, webhook.clone(), alerts));
+                Some(queue)
+            }
+            None => None,
+        };
+        Ok(Self {
+            config,
+            connections: Mutex::new(HashMap::new()),
+            users: Mutex::new(HashMap::new()),
+            siem,
+            webhook,
+        })
+    }
+
+    fn alert(&self, scope: ExfiltrationScope, session: &RelaySession, user: Option<&str>, bytes_up: u64, threshold: u64) {
+        let (ip, user, destination) = if session.redacted {
+            (None, None, None)
+        } else {
+            (Some(session.client_addr.ip()), user, Some(session.target_addr))
+        };
+        warn!("Possible exfiltration: {} upload of {} bytes in {:?} exceeds {} bytes (connection {}, client {}, user {:?}, destination {:?})",
+              scope.as_str(), bytes_up, self.config.window, threshold, session.session_id,
+              ip.map_or("[redacted]".to_string(), |ip| ip.to_string()), user, destination);
+        if let Some(siem) = &self.siem {
+            siem.record(&SecurityEvent::ExfiltrationSuspected {
+                ip,
+                user: user.map(str::to_string),
+                scope,
+                bytes_up,
+                threshold,
+                window: self.config.window,
+            });
+        }
+        if let Some(queue) = &self.webhook {
+            let alert = ExfiltrationAlert {
+                scope,
+                session_id: session.session_id.clone(),
+                client_ip: ip,
+                user: user.map(str::to_string),
+                destination,
+                bytes_up,
+                threshold,
+                window_secs: self.config.window.as_secs(),
+                timestamp: humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+            };
+            if queue.try_send(alert).is_err() {
+                warn!("Exfiltration webhook queue full, alert dropped");
+            }
+        }
+    }
+}
+
+impl RelayObserver for ExfiltrationMonitor {
+    fn on_start(&self, session: &RelaySession, user_id: Option<&str>) {
+        self.connections.lock().unwrap()
+            .insert(session.session_id.clone(), (user_id.map(str::to_string), WindowCount::new()));
+    }
+
+    fn on_progress(&self, session: &RelaySession, user_id: Option<&str>, bytes_up: u64, _bytes_down: u64) -> RelayControl {
+        if bytes_up == 0 {
+            return RelayControl::Continue;
+        }
+        let window = self.config.window;
+        if let Some(threshold) = self.config.connection_upload_bytes {
+            let crossed = self.connections.lock().unwrap()
+                .get_mut(&session.session_id)
+                .and_then(|(_, count)| count.add(bytes_up, threshold, window));
+            if let Some(total) = crossed {
+                self.alert(ExfiltrationScope::Connection, session, user_id, total, threshold);
+            }
+        }
+        if let (Some(threshold), Some(user)) = (self.config.user_upload_bytes, user_id) {
+            let crossed = self.users.lock().unwrap()
+                .entry(user.to_string())
+                .or_insert_with(WindowCount::new)
+                .add(bytes_up, threshold, window);
+            if let Some(total) = crossed {
+                self.alert(ExfiltrationScope::User, session, user_id, total, threshold);
+            }
+        }
+        RelayControl::Continue
+    }
+
+    fn on_end(&self, session: &RelaySession) {
+        let removed = self.connections.lock().unwrap().remove(&session.session_id);
+        // Forget users whose window is over and who have no other connection open
+        if let Some((Some(user), _)) = removed {
+            let still_connected = self.connections.lock().unwrap()
+                .values()
+                .any(|(other, _)| other.as_deref() == Some(user.as_str()));
+            let mut users = self.users.lock().unwrap();
+            if !still_connected && users.get(&user).is_some_and(|count| count.started.elapsed() >= self.config.window) {
+                users.remove(&user);
+            }
+        }
+    }
+}
+
+/// Post alerts one by one until every monitor handle is gone
+async fn deliver(client: reqwest::Client, config: ExfiltrationWebhookConfig, mut alerts: mpsc::Receiver<ExfiltrationAlert>) {
+    while let Some(alert) = alerts.recv().await {
+        let mut request = client.post(&config.url).json(&alert);
+        if let Some(token) = &config.bearer_token {
+            request = request.bearer_auth(token);
+        }
+        match request.send().await.and_then(|response| response.error_for_status()) {
+            Ok(_) => debug!("Posted exfiltration alert for connection {}", alert.session_id),
+            Err(e) => warn!("Failed to post exfiltration alert: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session(id: &str) -> RelaySession {
+        RelaySession::new(
+            id.to_string(),
+            "192.0.2.10:40000".parse().unwrap(),
+            "198.51.100.1:443".parse().unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_window_alerts_once_and_restarts() {
+        let mut count = WindowCount::new();
+        assert_eq!(count.add(60, 100, Duration::from_secs(60)), None);
+        assert_eq!(count.add(60, 100, Duration::from_secs(60)), Some(120));
+        assert_eq!(count.add(500, 100, Duration::from_secs(60)), None);
+
+        // A window that is already over starts again from zero
+        assert_eq!(count.add(50, 100, Duration::ZERO), None);
+        assert_eq!(count.bytes, 50);
+    }
+
+    #[test]
+    fn test_user_volume_adds_up_over_connections() {
+        let monitor = ExfiltrationMonitor::new(ExfiltrationConfig {
+            enabled: true,
+            connection_upload_bytes: Some(1_000),
+            user_upload_bytes: Some(1_500),
+            ..Default::default()
+        }, None).unwrap();
+        let (a, b) = (session("a"), session("b"));
+        monitor.on_start(&a, Some("alice"));
+        monitor.on_start(&b, Some("alice"));
+
+        monitor.on_progress(&a, Some("alice"), 900, 0);
+        monitor.on_progress(&b, Some("alice"), 900, 0);
+        assert!(monitor.users.lock().unwrap()["alice"].alerted);
+        assert!(!monitor.connections.lock().unwrap()["a"].1.alerted);
+
+        monitor.on_progress(&a, Some("alice"), 200, 0);
+        assert!(monitor.connections.lock().unwrap()["a"].1.alerted);
+
+        monitor.on_end(&a);
+        monitor.on_end(&b);
+        assert!(monitor.connections.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_redacted_alerts_leave_out_the_client() {
+        let (queue, mut alerts) = mpsc::channel(4);
+        let monitor = ExfiltrationMonitor {
+            webhook: Some(queue),
+            ..ExfiltrationMonitor::new(ExfiltrationConfig {
+                enabled: true,
+                connection_upload_bytes: Some(100),
+                ..Default::default()
+            }, None).unwrap()
+        };
+        let mut redacted = session("a");
+        redacted.redacted = true;
+        monitor.on_start(&redacted, Some("alice"));
+        monitor.on_progress(&redacted, Some("alice"), 200, 0);
+
+        let alert = alerts.try_recv().unwrap();
+        assert_eq!((alert.client_ip, alert.user, alert.destination), (None, None, None));
+    }
+}
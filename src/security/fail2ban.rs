@@ -3,12 +3,18 @@
 //! Implements brute force attack detection, progressive authentication delays,
 //! and IP blacklist management similar to fail2ban functionality.
 
-use std::collections::{HashMap, VecDeque};
+use std::collections::VecDeque;
 use std::net::IpAddr;
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 use serde::{Deserialize, Serialize};
 use tracing::{debug, warn, info};
+use super::{BlockReason, SecurityEvent};
+use super::fail2ban_log::Fail2BanLog;
+use super::siem::SiemExporter;
+use super::ip_table::IpSecurityTable;
+use super::networks::{self, NetworkBlocks, NetworkList};
+use ipnet::IpNet;
 
 /// Fail2Ban configuration
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -22,8 +28,12 @@ pub struct Fail2BanConfig {
     pub enable_progressive_delays: bool,
     pub base_delay_ms: u64,
     pub max_delay_ms: u64,
+    /// Addresses or CIDR ranges that are never banned
     pub whitelist_ips: Vec<String>,
     pub cleanup_interval_seconds: u64,
+    /// Count an extra failure for IPs the rate limiter has already blocked
+    #[serde(default)]
+    pub escalate_rate_limited: bool,
 }
 
 impl Default for Fail2BanConfig {
@@ -43,13 +53,14 @@ impl Default for Fail2BanConfig {
                 "::1".to_string(),
             ],
             cleanup_interval_seconds: 300, // 5 minutes
+            escalate_rate_limited: false,
         }
     }
 }
 
 /// Brute force detector for tracking authentication failures
 #[derive(Debug)]
-struct BruteForceDetector {
+pub(super) struct BruteForceDetector {
     failure_times: VecDeque<Instant>,
     total_failures: u64,
     total_successes: u64,
@@ -139,8 +150,13 @@ impl BruteForceDetector {
         self.last_failure_time = None;
     }
 
+    /// Whether to keep this state once the IP has gone idle
+    pub(super) fn should_retain(&self) -> bool {
+        self.is_banned() || !self.failure_times.is_empty()
+    }
+
     /// Check if IP is currently banned
-    fn is_banned(&self) -> bool {
+    pub(super) fn is_banned(&self) -> bool {
         if let Some(banned_until) = self.banned_until {
             Instant::now() < banned_until
         } else {
@@ -148,6 +164,13 @@ impl BruteForceDetector {
         }
     }
 
+    /// End of the current ban and how many bans led up to it, if one is in force
+    pub(super) fn ban_state(&self) -> Option<(Instant, u32)> {
+        self.banned_until
+            .filter(|&banned_until| Instant::now() < banned_until)
+            .map(|banned_until| (banned_until, self.ban_count))
+    }
+
     /// Get progressive delay based on recent failures
     fn get_progressive_delay(&self, config: &Fail2BanConfig) -> Duration {
         if !config.enable_progressive_delays {
@@ -187,9 +210,13 @@ impl BruteForceDetector {
 /// Main Fail2Ban manager implementation
 pub struct Fail2BanManager {
     config: Fail2BanConfig,
-    ip_detectors: Arc<Mutex<HashMap<IpAddr, BruteForceDetector>>>,
-    whitelist: Arc<Vec<IpAddr>>,
+    ip_table: Arc<IpSecurityTable>,
+    whitelist: Arc<NetworkList>,
+    /// Manual bans on whole networks
+    network_bans: Arc<NetworkBlocks>,
     stats: Arc<Mutex<InternalFail2BanStats>>,
+    event_log: Option<Arc<Fail2BanLog>>,
+    siem: Option<Arc<SiemExporter>>,
 }
 
 #[derive(Debug, Default)]
@@ -197,25 +224,70 @@ struct InternalFail2BanStats {
     total_auth_attempts: u64,
     total_auth_failures: u64,
     total_bans_issued: u64,
-    currently_banned_ips: usize,
     total_brute_force_events: u64,
+    total_source_rejections: u64,
 }
 
 impl Fail2BanManager {
     /// Create a new Fail2Ban manager
     pub fn new(config: Fail2BanConfig) -> Self {
-        // Parse whitelist IPs
-        let whitelist: Vec<IpAddr> = config.whitelist_ips.iter()
-            .filter_map(|ip_str| ip_str.parse().ok())
-            .collect();
+        let whitelist = NetworkList::parse(&config.whitelist_ips, "fail2ban whitelist");
         
-        info!("Fail2Ban initialized with {} whitelisted IPs", whitelist.len());
+        info!("Fail2Ban initialized with {} whitelisted networks", whitelist.len());
         
+        let idle_timeout = Duration::from_secs(config.cleanup_interval_seconds * 2);
         Self {
             config,
-            ip_detectors: Arc::new(Mutex::new(HashMap::new())),
+            ip_table: Arc::new(IpSecurityTable::new(idle_timeout)),
             whitelist: Arc::new(whitelist),
+            network_bans: Arc::new(NetworkBlocks::default()),
             stats: Arc::new(Mutex::new(InternalFail2BanStats::default())),
+            event_log: None,
+            siem: None,
+        }
+    }
+
+    /// Keep per-IP state in a table shared with the other security modules
+    pub fn with_ip_table(mut self, ip_table: Arc<IpSecurityTable>) -> Self {
+        self.ip_table = ip_table;
+        self
+    }
+
+    /// Write failed logins and bans where the system fail2ban can read them
+    pub fn with_event_log(mut self, event_log: Arc<Fail2BanLog>) -> Self {
+        self.event_log = Some(event_log);
+        self
+    }
+
+    /// Forward failed logins and bans to a SIEM
+    pub fn with_siem(mut self, siem: Arc<SiemExporter>) -> Self {
+        self.siem = Some(siem);
+        self
+    }
+
+    /// Write a failed login to the fail2ban log and the SIEM, if configured
+    ///
+    /// Done whether or not the built-in protection is enabled, since the
+    /// system fail2ban may be the one acting on it.
+    pub fn log_auth_failure(&self, ip: IpAddr, user: Option<&str>) {
+        if let Some(event_log) = &self.event_log {
+            if let Err(e) = event_log.auth_failure(ip, user) {
+                warn!("Failed to write fail2ban log line: {:#}", e);
+            }
+        }
+        if let Some(siem) = &self.siem {
+            siem.record(&SecurityEvent::AuthenticationFailed { ip, user: user.map(str::to_string) });
+        }
+    }
+
+    fn log_ban(&self, ip: IpAddr, duration: Duration, detail: String) {
+        if let Some(event_log) = &self.event_log {
+            if let Err(e) = event_log.ban(ip, BlockReason::BruteForce, duration) {
+                warn!("Failed to write fail2ban log line: {:#}", e);
+            }
+        }
+        if let Some(siem) = &self.siem {
+            siem.record(&SecurityEvent::IpBlocked { ip, reason: BlockReason::BruteForce, detail, duration });
         }
     }
 
@@ -226,7 +298,7 @@ impl Fail2BanManager {
         }
 
         // Check whitelist
-        if self.whitelist.contains(&ip) {
+        if self.whitelist.contains(ip) {
             debug!("IP {} is whitelisted, allowing authentication", ip);
             return Fail2BanDecision::Allow;
         }
@@ -237,8 +309,17 @@ impl Fail2BanManager {
             stats.total_auth_attempts += 1;
         }
 
-        let ip_detectors = self.ip_detectors.lock().unwrap();
-        if let Some(detector) = ip_detectors.get(&ip) {
+        if let Some(time_left) = self.network_bans.time_left(ip) {
+            debug!("Authentication attempt from banned network, IP {}", ip);
+            return Fail2BanDecision::Block {
+                reason: "Network is currently banned".to_string(),
+                delay: Duration::from_millis(self.config.max_delay_ms),
+                time_until_unban: Some(time_left),
+            };
+        }
+
+        let records = self.ip_table.lock();
+        if let Some(detector) = records.get(&ip).and_then(|record| record.brute_force.as_ref()) {
             if detector.is_banned() {
                 debug!("Authentication attempt from banned IP {}", ip);
                 return Fail2BanDecision::Block {
@@ -270,7 +351,7 @@ impl Fail2BanManager {
         }
 
         // Check whitelist
-        if self.whitelist.contains(&ip) {
+        if self.whitelist.contains(ip) {
             debug!("Not recording failure for whitelisted IP {}", ip);
             return;
         }
@@ -281,32 +362,57 @@ impl Fail2BanManager {
             stats.total_auth_failures += 1;
         }
 
-        let mut ip_detectors = self.ip_detectors.lock().unwrap();
-        let detector = ip_detectors.entry(ip).or_insert_with(BruteForceDetector::new);
-        
+        let mut records = self.ip_table.lock();
+        let record = records.get_or_insert_with(ip, Default::default);
+        let escalate = self.config.escalate_rate_limited && record.is_rate_limited();
+        let detector = record.brute_force.get_or_insert_with(BruteForceDetector::new);
+
         let was_banned_before = detector.is_banned();
+        if escalate && !was_banned_before {
+            debug!("IP {} is rate limited, counting an extra failure", ip);
+            detector.failure_times.push_back(Instant::now());
+        }
         let allowed = detector.record_failure(&self.config);
         
         if !allowed && !was_banned_before {
             // New ban issued
             info!("Issued fail2ban for IP {} after {} failures", ip, detector.total_failures);
+            let duration = detector.time_until_unban().unwrap_or_default();
+            let detail = format!("{} failed logins within {}m", detector.failure_times.len(), self.config.failure_window_minutes);
+            drop(records);
             
             {
                 let mut stats = self.stats.lock().unwrap();
                 stats.total_bans_issued += 1;
                 stats.total_brute_force_events += 1;
             }
+            self.log_ban(ip, duration, detail);
         }
     }
 
+    /// Record valid credentials used from a network the user is not bound to
+    ///
+    /// Counts as an authentication failure, since the credentials may have leaked.
+    pub fn record_source_rejection(&self, ip: IpAddr) {
+        if !self.config.enabled {
+            return;
+        }
+
+        {
+            let mut stats = self.stats.lock().unwrap();
+            stats.total_source_rejections += 1;
+        }
+        self.record_auth_failure(ip);
+    }
+
     /// Record a successful authentication
     pub fn record_auth_success(&self, ip: IpAddr) {
         if !self.config.enabled {
             return;
         }
 
-        let mut ip_detectors = self.ip_detectors.lock().unwrap();
-        if let Some(detector) = ip_detectors.get_mut(&ip) {
+        let mut records = self.ip_table.lock();
+        if let Some(detector) = records.get_mut(&ip).and_then(|record| record.brute_force.as_mut()) {
             detector.record_success();
             debug!("Recorded successful authentication for IP {}", ip);
         }
@@ -315,29 +421,96 @@ impl Fail2BanManager {
     /// Manually ban an IP address
     pub fn ban_ip(&self, ip: IpAddr, duration: Duration, reason: &str) {
         // Don't ban whitelisted IPs
-        if self.whitelist.contains(&ip) {
+        if self.whitelist.contains(ip) {
             warn!("Attempted to ban whitelisted IP {}: {}", ip, reason);
             return;
         }
 
-        let mut ip_detectors = self.ip_detectors.lock().unwrap();
-        let detector = ip_detectors.entry(ip).or_insert_with(BruteForceDetector::new);
+        let mut records = self.ip_table.lock();
+        let detector = records.get_or_insert_with(ip, Default::default).brute_force.get_or_insert_with(BruteForceDetector::new);
         
         detector.banned_until = Some(Instant::now() + duration);
         detector.ban_count += 1;
         
         info!("Manually banned IP {} for {:?}: {}", ip, duration, reason);
+        drop(records);
         
         {
             let mut stats = self.stats.lock().unwrap();
             stats.total_bans_issued += 1;
         }
+        self.log_ban(ip, duration, reason.to_string());
+    }
+
+    /// Manually ban a network, or a single address given as `/32` or `/128`
+    ///
+    /// Whitelisted addresses inside the network stay allowed. Network bans
+    /// are not written to the fail2ban log, whose lines name one address.
+    pub fn ban_network(&self, network: IpNet, duration: Duration, reason: &str) {
+        if let Some(ip) = networks::single_address(&network) {
+            return self.ban_ip(ip, duration, reason);
+        }
+        if self.whitelist.covers(&network) {
+            warn!("Attempted to ban whitelisted network {}: {}", network, reason);
+            return;
+        }
+        self.network_bans.block(network, duration);
+        info!("Manually banned network {} for {:?}: {}", network, duration, reason);
+        self.stats.lock().unwrap().total_bans_issued += 1;
+    }
+
+    /// Lift a network ban, or the ban on a single address given as `/32` or `/128`
+    pub fn unban_network(&self, network: IpNet) -> bool {
+        if let Some(ip) = networks::single_address(&network) {
+            return self.unban_ip(ip);
+        }
+        let lifted = self.network_bans.unblock(&network);
+        if lifted {
+            info!("Unbanned network {}", network);
+        }
+        lifted
+    }
+
+    /// Networks banned now and the time left on each
+    pub fn get_banned_networks(&self) -> Vec<(IpNet, Duration)> {
+        self.network_bans.list()
+    }
+
+    /// Network bans, for saving them across a restart
+    pub(super) fn network_bans(&self) -> &Arc<NetworkBlocks> {
+        &self.network_bans
+    }
+
+    /// Put back a network ban saved before a restart
+    ///
+    /// Returns false for networks whitelisted since the ban was saved.
+    pub(super) fn restore_network_ban(&self, network: IpNet, banned_until: Instant) -> bool {
+        if self.whitelist.covers(&network) {
+            return false;
+        }
+        self.network_bans.block_until(network, banned_until);
+        true
+    }
+
+    /// Put back a ban saved before a restart, keeping its ban count so the
+    /// next ban is as long as it would have been
+    ///
+    /// Returns false for IPs whitelisted since the ban was saved.
+    pub(super) fn restore_ban(&self, ip: IpAddr, banned_until: Instant, ban_count: u32) -> bool {
+        if self.whitelist.contains(ip) {
+            return false;
+        }
+        let mut records = self.ip_table.lock();
+        let detector = records.get_or_insert_with(ip, Default::default).brute_force.get_or_insert_with(BruteForceDetector::new);
+        detector.banned_until = Some(banned_until);
+        detector.ban_count = ban_count;
+        true
     }
 
     /// Unban an IP address
     pub fn unban_ip(&self, ip: IpAddr) -> bool {
-        let mut ip_detectors = self.ip_detectors.lock().unwrap();
-        if let Some(detector) = ip_detectors.get_mut(&ip) {
+        let mut records = self.ip_table.lock();
+        if let Some(detector) = records.get_mut(&ip).and_then(|record| record.brute_force.as_mut()) {
             if detector.is_banned() {
                 detector.banned_until = None;
                 // Reset failure count but keep history
@@ -349,10 +522,13 @@ impl Fail2BanManager {
         false
     }
 
-    /// Check if an IP is currently banned
+    /// Check if an IP is currently banned, on its own or as part of a network
     pub fn is_ip_banned(&self, ip: IpAddr) -> bool {
-        let ip_detectors = self.ip_detectors.lock().unwrap();
-        if let Some(detector) = ip_detectors.get(&ip) {
+        if self.network_bans.time_left(ip).is_some() && !self.whitelist.contains(ip) {
+            return true;
+        }
+        let records = self.ip_table.lock();
+        if let Some(detector) = records.get(&ip).and_then(|record| record.brute_force.as_ref()) {
             detector.is_banned()
         } else {
             false
@@ -361,72 +537,54 @@ impl Fail2BanManager {
 
     /// Get list of currently banned IPs
     pub fn get_banned_ips(&self) -> Vec<IpAddr> {
-        let ip_detectors = self.ip_detectors.lock().unwrap();
-        ip_detectors.iter()
-            .filter(|(_, detector)| detector.is_banned())
+        let records = self.ip_table.lock();
+        records.iter()
+            .filter(|(_, record)| record.is_banned())
             .map(|(ip, _)| *ip)
             .collect()
     }
 
     /// Get list of IPs with recent failures (potential threats)
     pub fn get_suspicious_ips(&self) -> Vec<IpAddr> {
-        let ip_detectors = self.ip_detectors.lock().unwrap();
+        let records = self.ip_table.lock();
         let threshold = Duration::from_secs(self.config.failure_window_minutes * 60);
         let cutoff = Instant::now() - threshold;
         
-        ip_detectors.iter()
-            .filter(|(_, detector)| {
+        records.iter()
+            .filter(|(_, record)| record.brute_force.as_ref().is_some_and(|detector| {
                 !detector.failure_times.is_empty() && 
                 detector.last_failure_time.map_or(false, |t| t > cutoff)
-            })
+            }))
             .map(|(ip, _)| *ip)
             .collect()
     }
 
-    /// Clean up old detector entries
+    /// Clean up IPs that have gone idle
     pub fn cleanup_old_entries(&self) {
-        let cleanup_threshold = Duration::from_secs(self.config.cleanup_interval_seconds * 2);
-        let cutoff_time = Instant::now() - cleanup_threshold;
-        
-        let mut ip_detectors = self.ip_detectors.lock().unwrap();
-        let initial_count = ip_detectors.len();
-        
-        ip_detectors.retain(|_, detector| {
-            // Keep if recently active, currently banned, or has recent failures
-            detector.last_activity > cutoff_time || 
-            detector.is_banned() || 
-            !detector.failure_times.is_empty()
-        });
-        
-        let removed_count = initial_count - ip_detectors.len();
+        let removed_count = self.ip_table.expire();
         if removed_count > 0 {
-            debug!("Cleaned up {} old fail2ban detector entries", removed_count);
-        }
-
-        // Update banned IP count in stats
-        let banned_count = ip_detectors.iter().filter(|(_, detector)| detector.is_banned()).count();
-        {
-            let mut stats = self.stats.lock().unwrap();
-            stats.currently_banned_ips = banned_count;
+            debug!("Cleaned up {} idle IP entries", removed_count);
         }
     }
 
     /// Get fail2ban statistics
     pub fn get_stats(&self) -> Fail2BanStats {
+        let currently_banned_ips = self.ip_table.lock().values().filter(|record| record.is_banned()).count();
         let stats = self.stats.lock().unwrap();
         Fail2BanStats {
             total_auth_attempts: stats.total_auth_attempts,
             total_auth_failures: stats.total_auth_failures,
             total_bans_issued: stats.total_bans_issued,
-            currently_banned_ips: stats.currently_banned_ips,
+            currently_banned_ips,
             total_brute_force_events: stats.total_brute_force_events,
+            total_source_rejections: stats.total_source_rejections,
         }
     }
 
     /// Get detailed IP statistics
     pub fn get_ip_stats(&self, ip: IpAddr) -> Option<IpFail2BanStats> {
-        let ip_detectors = self.ip_detectors.lock().unwrap();
-        ip_detectors.get(&ip).map(|detector| IpFail2BanStats {
+        let records = self.ip_table.lock();
+        records.get(&ip).and_then(|record| record.brute_force.as_ref()).map(|detector| IpFail2BanStats {
             ip,
             total_failures: detector.total_failures,
             total_successes: detector.total_successes,
@@ -442,8 +600,8 @@ impl Fail2BanManager {
 
     /// Get all IP statistics
     pub fn get_all_ip_stats(&self) -> Vec<IpFail2BanStats> {
-        let ip_detectors = self.ip_detectors.lock().unwrap();
-        ip_detectors.iter().map(|(ip, detector)| IpFail2BanStats {
+        let records = self.ip_table.lock();
+        records.iter().filter_map(|(ip, record)| record.brute_force.as_ref().map(|detector| (ip, detector))).map(|(ip, detector)| IpFail2BanStats {
             ip: *ip,
             total_failures: detector.total_failures,
             total_successes: detector.total_successes,
@@ -457,30 +615,25 @@ impl Fail2BanManager {
         }).collect()
     }
 
-    /// Add IP to whitelist
-    pub fn add_to_whitelist(&mut self, ip: IpAddr) {
-        let whitelist = Arc::make_mut(&mut self.whitelist);
-        if !whitelist.contains(&ip) {
-            whitelist.push(ip);
-            info!("Added IP {} to fail2ban whitelist", ip);
+    /// Add an address or network to the whitelist
+    pub fn add_to_whitelist(&mut self, network: IpNet) {
+        if Arc::make_mut(&mut self.whitelist).insert(network) {
+            info!("Added {} to fail2ban whitelist", network);
         }
     }
 
-    /// Remove IP from whitelist
-    pub fn remove_from_whitelist(&mut self, ip: IpAddr) -> bool {
-        let whitelist = Arc::make_mut(&mut self.whitelist);
-        if let Some(pos) = whitelist.iter().position(|&x| x == ip) {
-            whitelist.remove(pos);
-            info!("Removed IP {} from fail2ban whitelist", ip);
-            true
-        } else {
-            false
+    /// Remove an address or network from the whitelist
+    pub fn remove_from_whitelist(&mut self, network: IpNet) -> bool {
+        let removed = Arc::make_mut(&mut self.whitelist).remove(&network);
+        if removed {
+            info!("Removed {} from fail2ban whitelist", network);
         }
+        removed
     }
 
     /// Get current whitelist
-    pub fn get_whitelist(&self) -> Vec<IpAddr> {
-        self.whitelist.as_ref().clone()
+    pub fn get_whitelist(&self) -> Vec<IpNet> {
+        self.whitelist.to_vec()
     }
 }
 
@@ -499,6 +652,16 @@ pub enum Fail2BanDecision {
     },
 }
 
+impl Fail2BanDecision {
+    /// Get the reason code if this decision blocks the connection
+    pub fn reason_code(&self) -> Option<BlockReason> {
+        match self {
+            Fail2BanDecision::Block { .. } => Some(BlockReason::BruteForce),
+            Fail2BanDecision::Allow | Fail2BanDecision::Delay { .. } => None,
+        }
+    }
+}
+
 /// Fail2Ban statistics
 #[derive(Debug, Clone)]
 pub struct Fail2BanStats {
@@ -507,6 +670,8 @@ pub struct Fail2BanStats {
     pub total_bans_issued: u64,
     pub currently_banned_ips: usize,
     pub total_brute_force_events: u64,
+    /// Valid credentials rejected because of the client's network
+    pub total_source_rejections: u64,
 }
 
 /// Statistics for a specific IP address
@@ -574,6 +739,32 @@ mod tests {
         assert!(manager.is_ip_banned(ip));
     }
 
+    #[test]
+    fn test_failures_and_bans_reach_event_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("fail2ban.log");
+        let event_log = Fail2BanLog::open(&crate::security::Fail2BanLogConfig::File { path: path.clone() }).unwrap();
+        let config = Fail2BanConfig {
+            enabled: true,
+            max_auth_failures: 2,
+            ban_duration_minutes: 30,
+            ..Default::default()
+        };
+        let manager = Fail2BanManager::new(config).with_event_log(Arc::new(event_log));
+        let ip = "192.0.2.7".parse().unwrap();
+
+        for _ in 0..3 {
+            manager.log_auth_failure(ip, Some("mallory"));
+            manager.record_auth_failure(ip);
+        }
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(contents.matches("auth_failure ip=192.0.2.7 user=\"mallory\"").count(), 3);
+        // Only the failure that crossed the threshold issues a ban
+        assert_eq!(contents.matches("ban ip=192.0.2.7 reason=BRUTE_FORCE duration=").count(), 1);
+        assert!(contents.contains("duration=1799s") || contents.contains("duration=1800s"));
+    }
+
     #[test]
     fn test_whitelist_protection() {
         let config = Fail2BanConfig {
@@ -636,6 +827,36 @@ mod tests {
         assert!(matches!(manager.check_auth_attempt(ip), Fail2BanDecision::Allow));
     }
 
+    #[test]
+    fn test_network_whitelist_and_ban() {
+        let config = Fail2BanConfig {
+            whitelist_ips: vec!["10.20.0.0/16".to_string(), "2001:db8:1::/48".to_string()],
+            ..Default::default()
+        };
+        let manager = Fail2BanManager::new(config);
+        let office: IpAddr = "10.20.4.5".parse().unwrap();
+        let outsider: IpAddr = "10.30.4.5".parse().unwrap();
+
+        manager.ban_network("10.0.0.0/8".parse().unwrap(), Duration::from_secs(60), "test");
+        assert!(matches!(manager.check_auth_attempt(office), Fail2BanDecision::Allow));
+        assert!(!manager.is_ip_banned(office));
+        assert!(matches!(manager.check_auth_attempt(outsider), Fail2BanDecision::Block { .. }));
+        assert!(manager.is_ip_banned("::ffff:10.30.4.5".parse().unwrap()));
+        assert_eq!(manager.get_banned_networks().len(), 1);
+
+        // A network inside the whitelist cannot be banned
+        manager.ban_network("2001:db8:1:2::/64".parse().unwrap(), Duration::from_secs(60), "test");
+        assert!(!manager.is_ip_banned("2001:db8:1:2::1".parse().unwrap()));
+
+        assert!(manager.unban_network("10.0.0.0/8".parse().unwrap()));
+        assert!(matches!(manager.check_auth_attempt(outsider), Fail2BanDecision::Allow));
+
+        // A single address goes through the per-IP ban
+        manager.ban_network("192.0.2.1/32".parse().unwrap(), Duration::from_secs(60), "test");
+        assert_eq!(manager.get_banned_ips(), ["192.0.2.1".parse::<IpAddr>().unwrap()]);
+        assert!(manager.get_banned_networks().is_empty());
+    }
+
     #[test]
     fn test_success_clears_failures() {
         let config = Fail2BanConfig {
@@ -659,4 +880,48 @@ mod tests {
         manager.record_auth_failure(ip);
         assert!(!manager.is_ip_banned(ip));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_source_rejection_counts_as_failure() {
+        let config = Fail2BanConfig {
+            enabled: true,
+            max_auth_failures: 2,
+            ..Default::default()
+        };
+
+        let manager = Fail2BanManager::new(config);
+        let ip = "203.0.113.7".parse().unwrap();
+
+        manager.record_source_rejection(ip);
+        manager.record_source_rejection(ip);
+
+        let stats = manager.get_stats();
+        assert_eq!(stats.total_source_rejections, 2);
+        assert_eq!(stats.total_auth_failures, 2);
+        assert!(manager.is_ip_banned(ip));
+    }
+
+    #[test]
+    fn test_rate_limited_ip_escalates() {
+        let table = Arc::new(IpSecurityTable::default());
+        let limiter = crate::security::RateLimiter::new(crate::security::RateLimitConfig::default())
+            .with_ip_table(table.clone());
+        let config = Fail2BanConfig {
+            enabled: true,
+            max_auth_failures: 4,
+            escalate_rate_limited: true,
+            ..Default::default()
+        };
+        let manager = Fail2BanManager::new(config).with_ip_table(table);
+        let limited: IpAddr = "203.0.113.8".parse().unwrap();
+        let other: IpAddr = "203.0.113.9".parse().unwrap();
+        limiter.block_ip(limited, Duration::from_secs(60), "test");
+
+        for _ in 0..2 {
+            manager.record_auth_failure(limited);
+            manager.record_auth_failure(other);
+        }
+        assert!(manager.is_ip_banned(limited));
+        assert!(!manager.is_ip_banned(other));
+    }
+}
@@ -0,0 +1,260 @@
+//! Fail2Ban-Compatible Event Log
+//!
+//! Writes one fixed-format line per failed login and per ban, either to a
+//! dedicated file or to the local syslog daemon, so that the system fail2ban
+//! can act on them with the filter from `rustproxy fail2ban-filter`. Lines
+//! look like
+//!
+//! ```text
+//! 2025-06-01T12:00:00.000Z rustproxy[4242]: auth_failure ip=192.0.2.1 user="alice"
+//! 2025-06-01T12:00:05.000Z rustproxy[4242]: ban ip=192.0.2.1 reason=BRUTE_FORCE duration=1800s
+//! ```
+//!
+//! The address always directly follows the event name, and usernames are
+//! quoted and escaped, so a client cannot shape a line the filter would
+//! attribute to another address.
+
+use crate::Result;
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::fmt::Write as _;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use super::BlockReason;
+
+/// Program name the filter's `_daemon` matches
+const DAEMON: &str = "rustproxy";
+
+/// Where fail2ban-compatible lines are written
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(tag = "target", rename_all = "lowercase")]
+pub enum Fail2BanLogConfig {
+    /// Append to a file, each line prefixed with an RFC 3339 timestamp
+    File { path: PathBuf },
+    /// Send to the local syslog daemon through `/dev/log`
+    Syslog {
+        #[serde(default)]
+        facility: SyslogFacility,
+    },
+}
+
+/// Syslog facility for `target = "syslog"`, also used by the SIEM exporter
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SyslogFacility {
+    Auth,
+    #[default]
+    Authpriv,
+    Daemon,
+    Local0,
+    Local1,
+    Local2,
+    Local3,
+    Local4,
+    Local5,
+    Local6,
+    Local7,
+}
+
+impl SyslogFacility {
+    pub(super) fn code(self) -> u8 {
+        match self {
+            SyslogFacility::Auth => 4,
+            SyslogFacility::Authpriv => 10,
+            SyslogFacility::Daemon => 3,
+            SyslogFacility::Local0 => 16,
+            SyslogFacility::Local1 => 17,
+            SyslogFacility::Local2 => 18,
+            SyslogFacility::Local3 => 19,
+            SyslogFacility::Local4 => 20,
+            SyslogFacility::Local5 => 21,
+            SyslogFacility::Local6 => 22,
+            SyslogFacility::Local7 => 23,
+        }
+    }
+}
+
+/// Syslog severities used for the two kinds of event
+const SEVERITY_WARNING: u8 = 4;
+const SEVERITY_NOTICE: u8 = 5;
+
+enum Sink {
+    File(Mutex<File>),
+    #[cfg(unix)]
+    Syslog { socket: std::os::unix::net::UnixDatagram, facility: SyslogFacility },
+}
+
+/// Writer for fail2ban-compatible lines
+pub struct Fail2BanLog {
+    sink: Sink,
+    pid: u32,
+}
+
+impl Fail2BanLog {
+    /// Open the file or syslog connection named in `config`
+    pub fn open(config: &Fail2BanLogConfig) -> Result<Self> {
+        let sink = match config {
+            Fail2BanLogConfig::File { path } => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .with_context(|| format!("Failed to open fail2ban log {}", path.display()))?;
+                Sink::File(Mutex::new(file))
+            }
+            #[cfg(unix)]
+            Fail2BanLogConfig::Syslog { facility } => {
+                let socket = std::os::unix::net::UnixDatagram::unbound()?;
+                socket.connect("/dev/log").context("Failed to connect to syslog at /dev/log")?;
+                Sink::Syslog { socket, facility: *facility }
+            }
+            #[cfg(not(unix))]
+            Fail2BanLogConfig::Syslog { .. } => anyhow::bail!("Syslog output is only available on Unix"),
+        };
+        Ok(Self { sink, pid: std::process::id() })
+    }
+
+    /// Record a failed login from `ip`, with the username the client gave if any
+    pub fn auth_failure(&self, ip: IpAddr, user: Option<&str>) -> Result<()> {
+        let mut message = format!("auth_failure ip={}", ip);
+        if let Some(user) = user {
+            let _ = write!(message, " user={}", quote(user));
+        }
+        self.write(SEVERITY_NOTICE, &message)
+    }
+
+    /// Record that the proxy itself banned `ip` for `duration`
+    pub fn ban(&self, ip: IpAddr, reason: BlockReason, duration: Duration) -> Result<()> {
+        let message = format!("ban ip={} reason={} duration={}s", ip, reason, duration.as_secs());
+        self.write(SEVERITY_WARNING, &message)
+    }
+
+    #[cfg_attr(not(unix), allow(unused_variables))]
+    fn write(&self, severity: u8, message: &str) -> Result<()> {
+        match &self.sink {
+            Sink::File(file) => {
+                let timestamp = humantime::format_rfc3339_millis(SystemTime::now());
+                let line = format!("{} {}[{}]: {}\n", timestamp, DAEMON, self.pid, message);
+                file.lock().unwrap().write_all(line.as_bytes()).context("Failed to write fail2ban log line")
+            }
+            #[cfg(unix)]
+            Sink::Syslog { socket, facility } => {
+                // The daemon stamps the time and host itself
+                let priority = facility.code() * 8 + severity;
+                let line = format!("<{}>{}[{}]: {}", priority, DAEMON, self.pid, message);
+                socket.send(line.as_bytes()).context("Failed to send fail2ban log line to syslog")?;
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Quote a client-supplied value, escaping anything that could end the line or the quotes
+fn quote(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' | '\\' => {
+                quoted.push('\\');
+                quoted.push(c);
+            }
+            c if c.is_control() => {
+                let _ = write!(quoted, "\\u{{{:x}}}", c as u32);
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Filter definition for `/etc/fail2ban/filter.d/rustproxy.conf`
+///
+/// Matches the lines above whether they were written to a file or went
+/// through syslog or the journal.
+pub fn filter_definition() -> String {
+    format!(
+        "\
+# Fail2Ban filter for RustProxy, generated by `rustproxy fail2ban-filter` (v{version})
+#
+# Matches the lines RustProxy writes to `security.fail2ban_log`.
+#   mode = normal      every failed login counts
+#   mode = bans        only bans RustProxy issued itself (use maxretry = 1)
+#   mode = aggressive  both
+
+[INCLUDES]
+before = common.conf
+
+[Definition]
+_daemon = {daemon}
+
+mode = normal
+
+mdre-normal = ^%(__prefix_line)sauth_failure ip=<HOST>(?: |$)
+mdre-bans = ^%(__prefix_line)sban ip=<HOST> reason=\\S+ duration=\\d+s$
+mdre-aggressive = %(mdre-normal)s
+                  %(mdre-bans)s
+
+failregex = %(mdre-<mode>)s
+
+ignoreregex =
+
+datepattern = {{^LN-BEG}}
+",
+        version = env!("CARGO_PKG_VERSION"),
+        daemon = DAEMON,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_file_lines_are_stable() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("fail2ban.log");
+        let log = Fail2BanLog::open(&Fail2BanLogConfig::File { path: path.clone() }).unwrap();
+
+        log.auth_failure("192.0.2.1".parse().unwrap(), Some("alice")).unwrap();
+        log.auth_failure("2001:db8::1".parse().unwrap(), None).unwrap();
+        log.ban("192.0.2.1".parse().unwrap(), BlockReason::BruteForce, Duration::from_secs(1800)).unwrap();
+
+        let contents = fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        let prefix = format!(" rustproxy[{}]: ", std::process::id());
+        let messages: Vec<&str> = lines.iter().map(|line| line.split_once(&prefix).unwrap().1).collect();
+        assert_eq!(messages, [
+            "auth_failure ip=192.0.2.1 user=\"alice\"",
+            "auth_failure ip=2001:db8::1",
+            "ban ip=192.0.2.1 reason=BRUTE_FORCE duration=1800s",
+        ]);
+        // Each line starts with the timestamp fail2ban reads
+        assert!(humantime::parse_rfc3339(lines[0].split(' ').next().unwrap()).is_ok());
+    }
+
+    #[test]
+    fn test_usernames_cannot_forge_lines() {
+        assert_eq!(quote("alice"), "\"alice\"");
+        assert_eq!(quote("a\"b\\c"), "\"a\\\"b\\\\c\"");
+        let forged = quote("x\n2025-06-01T00:00:00Z rustproxy[1]: auth_failure ip=203.0.113.9");
+        assert!(!forged.contains('\n'));
+        assert!(forged.starts_with("\"x\\u{a}2025"));
+    }
+
+    #[test]
+    fn test_config_targets() {
+        let file: Fail2BanLogConfig = toml::from_str("target = \"file\"\npath = \"/var/log/rustproxy-f2b.log\"").unwrap();
+        assert_eq!(file, Fail2BanLogConfig::File { path: PathBuf::from("/var/log/rustproxy-f2b.log") });
+        let syslog: Fail2BanLogConfig = toml::from_str("target = \"syslog\"").unwrap();
+        assert_eq!(syslog, Fail2BanLogConfig::Syslog { facility: SyslogFacility::Authpriv });
+        assert_eq!(SyslogFacility::Local3.code() * 8 + SEVERITY_WARNING, 156);
+    }
+}
@@ -0,0 +1,97 @@
+//! Failure Policies
+//!
+//! Controls what happens when a security dependency (GeoIP database, auth
+//! backend) is unavailable: let traffic through with a warning, or deny it.
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Behavior when a security dependency cannot be consulted
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FailurePolicy {
+    /// Allow the request and log a warning
+    Open,
+    /// Deny the request
+    Closed,
+}
+
+impl FailurePolicy {
+    /// Whether requests should be allowed while the dependency is unavailable
+    pub fn allows(&self) -> bool {
+        matches!(self, FailurePolicy::Open)
+    }
+}
+
+impl fmt::Display for FailurePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FailurePolicy::Open => f.write_str("open"),
+            FailurePolicy::Closed => f.write_str("closed"),
+        }
+    }
+}
+
+/// Failure policy for each security subsystem with an external dependency
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct FailurePolicyConfig {
+    /// Applied to country-restricted ACL rules when no GeoIP database is loaded
+    #[serde(default = "default_geoip_policy")]
+    pub geoip: FailurePolicy,
+    /// Applied when the auth backend errors while verifying credentials
+    #[serde(default = "default_auth_backend_policy")]
+    pub auth_backend: FailurePolicy,
+}
+
+fn default_geoip_policy() -> FailurePolicy {
+    FailurePolicy::Open
+}
+
+fn default_auth_backend_policy() -> FailurePolicy {
+    FailurePolicy::Closed
+}
+
+impl Default for FailurePolicyConfig {
+    fn default() -> Self {
+        Self {
+            geoip: default_geoip_policy(),
+            auth_backend: default_auth_backend_policy(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_policies() {
+        let config = FailurePolicyConfig::default();
+        assert!(config.geoip.allows());
+        assert!(!config.auth_backend.allows());
+    }
+
+    #[test]
+    fn test_policy_deserialization() {
+        let config: FailurePolicyConfig = toml::from_str(
+            "geoip = \"closed\"\nauth_backend = \"open\"\n"
+        ).unwrap();
+        assert_eq!(config.geoip, FailurePolicy::Closed);
+        assert_eq!(config.auth_backend, FailurePolicy::Open);
+    }
+
+    #[test]
+    fn test_partial_table_keeps_other_defaults() {
+        let config: FailurePolicyConfig = toml::from_str("geoip = \"closed\"\n").unwrap();
+        assert_eq!(config.geoip, FailurePolicy::Closed);
+        assert_eq!(config.auth_backend, FailurePolicy::Closed);
+
+        let config: FailurePolicyConfig = toml::from_str("auth_backend = \"open\"\n").unwrap();
+        assert_eq!(config.geoip, FailurePolicy::Open);
+        assert_eq!(config.auth_backend, FailurePolicy::Open);
+
+        let config: FailurePolicyConfig = toml::from_str("").unwrap();
+        assert!(config.geoip.allows());
+        assert!(!config.auth_backend.allows());
+    }
+}
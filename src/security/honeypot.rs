@@ -0,0 +1,365 @@
+//! Honeypot Sessions
+//!
+//! Instead of dropping a connection the accept-time checks refused, the
+//! honeypot can play along: it accepts whatever authentication the client
+//! offers, answers the request as if the target had been reached, and then
+//! swallows everything sent to it. Nothing is ever relayed. What the client
+//! asked for (the username it tried, the destination, the size of each
+//! payload) is kept for a report of what banned and suspicious sources were
+//! trying to do.
+//!
+//! Sessions are capped in number and length so the honeypot cannot be used
+//! to tie up the proxy.
+
+use std::collections::{HashMap, VecDeque};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpStream;
+use tracing::{debug, info};
+
+use super::BlockReason;
+use crate::protocol::constants::SOCKS5_REPLY_COMMAND_NOT_SUPPORTED;
+use crate::protocol::{AuthMethod, Socks5Command, Socks5Handler, Socks5Response, TargetAddr};
+use crate::Result;
+
+/// Payload sizes kept per session; later ones only add to the byte count
+const MAX_PAYLOADS_PER_SESSION: usize = 64;
+
+/// Distinct sources, targets, and usernames tallied for the report
+const MAX_TALLIED: usize = 10_000;
+
+/// Honeypot configuration
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct HoneypotConfig {
+    /// Divert refused connections into honeypot sessions
+    #[serde(default)]
+    pub enabled: bool,
+    /// Block reasons whose connections are diverted instead of dropped
+    #[serde(default = "default_divert")]
+    pub divert: Vec<BlockReason>,
+    /// Honeypot sessions open at once; connections beyond this are dropped as usual
+    #[serde(default = "default_max_sessions")]
+    pub max_sessions: usize,
+    /// Longest a session is kept open
+    #[serde(default = "default_session_timeout", with = "humantime_serde")]
+    pub session_timeout: Duration,
+    /// Finished sessions kept for the report
+    #[serde(default = "default_history")]
+    pub history: usize,
+}
+
+fn default_divert() -> Vec<BlockReason> {
+    vec![BlockReason::BruteForce, BlockReason::Reputation]
+}
+
+fn default_max_sessions() -> usize {
+    100
+}
+
+fn default_session_timeout() -> Duration {
+    Duration::from_secs(300)
+}
+
+fn default_history() -> usize {
+    500
+}
+
+impl Default for HoneypotConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            divert: default_divert(),
+            max_sessions: default_max_sessions(),
+            session_timeout: default_session_timeout(),
+            history: default_history(),
+        }
+    }
+}
+
+/// What one diverted client did
+#[derive(Debug, Clone, Serialize)]
+pub struct HoneypotSession {
+    pub client: SocketAddr,
+    /// Why the connection was refused
+    pub reason: BlockReason,
+    pub started_at: SystemTime,
+    pub duration_ms: u64,
+    /// Username tried, if the client chose username/password authentication
+    pub username: Option<String>,
+    /// `CONNECT`, `BIND`, or `UDP_ASSOCIATE`
+    pub command: Option<String>,
+    /// Destination asked for, as `host:port`
+    pub target: Option<String>,
+    /// Bytes sent after the request was answered
+    pub bytes_sent: u64,
+    /// Size of each read from the client, in order, up to a fixed number
+    pub payload_sizes: Vec<usize>,
+}
+
+/// Sessions seen for one source, target, or username
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct HoneypotTally {
+    pub value: String,
+    pub sessions: u64,
+}
+
+/// Attacker behaviour seen by the honeypot
+#[derive(Debug, Clone, Serialize)]
+pub struct HoneypotReport {
+    pub total_sessions: u64,
+    pub active_sessions: usize,
+    pub total_bytes: u64,
+    pub sessions_by_reason: HashMap<BlockReason, u64>,
+    /// Most frequent sources, targets, and usernames, busiest first
+    pub top_sources: Vec<HoneypotTally>,
+    pub top_targets: Vec<HoneypotTally>,
+    pub top_usernames: Vec<HoneypotTally>,
+    /// Latest finished sessions, newest first
+    pub recent_sessions: Vec<HoneypotSession>,
+}
+
+#[derive(Default)]
+struct HoneypotState {
+    total_sessions: u64,
+    total_bytes: u64,
+    by_reason: HashMap<BlockReason, u64>,
+    sources: HashMap<String, u64>,
+    targets: HashMap<String, u64>,
+    usernames: HashMap<String, u64>,
+    recent: VecDeque<HoneypotSession>,
+}
+
+/// Fake SOCKS5 server for refused clients
+pub struct Honeypot {
+    config: HoneypotConfig,
+    active: AtomicUsize,
+    state: Mutex<HoneypotState>,
+}
+
+/// Releases a session slot when the session ends
+struct SessionSlot<'a>(&'a AtomicUsize);
+
+impl Drop for SessionSlot<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Honeypot {
+    pub fn new(config: HoneypotConfig) -> Self {
+        Self {
+            config,
+            active: AtomicUsize::new(0),
+            state: Mutex::new(HoneypotState::default()),
+        }
+    }
+
+    /// Whether connections refused for `reason` go to the honeypot
+    pub fn diverts(&self, reason: BlockReason) -> bool {
+        self.config.enabled && self.config.divert.contains(&reason)
+    }
+
+    /// Take a session slot, released by the `SessionSlot` of the session
+    fn reserve_slot(&self) -> bool {
+        let max = self.config.max_sessions;
+        self.active
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |active| (active < max).then_some(active + 1))
+            .is_ok()
+    }
+
+    /// Run a session for a connection refused for `reason`, or hand the
+    /// stream back if every session slot is taken
+    pub fn divert(self: &Arc<Self>, stream: TcpStream, client: SocketAddr, reason: BlockReason) -> std::result::Result<(), TcpStream> {
+        if !self.reserve_slot() {
+            return Err(stream);
+        }
+        let honeypot = self.clone();
+        tokio::spawn(async move {
+            let _slot = SessionSlot(&honeypot.active);
+            honeypot.serve(stream, client, reason).await;
+        });
+        Ok(())
+    }
+
+    async fn serve(&self, stream: TcpStream, client: SocketAddr, reason: BlockReason) {
+        let start = Instant::now();
+        let mut session = HoneypotSession {
+            client,
+            reason,
+            started_at: SystemTime::now(),
+            duration_ms: 0,
+            username: None,
+            command: None,
+            target: None,
+            bytes_sent: 0,
+            payload_sizes: Vec::new(),
+        };
+        match tokio::time::timeout(self.config.session_timeout, Self::play_along(stream, &mut session)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => debug!("Honeypot session from {} ended: {}", client, e),
+            Err(_) => debug!("Honeypot session from {} timed out", client),
+        }
+        session.duration_ms = start.elapsed().as_millis() as u64;
+        info!("Honeypot session from {} [{}]: user {:?}, target {:?}, {} bytes",
+              client, reason, session.username, session.target, session.bytes_sent);
+        self.record(session);
+    }
+
+    /// Accept any authentication, answer the request, and swallow the payload
+    async fn play_along(stream: TcpStream, session: &mut HoneypotSession) -> Result<()> {
+        let mut handler = Socks5Handler::new(stream);
+        if handler.handle_handshake().await? == AuthMethod::UserPass {
+            let credentials = handler.handle_userpass_auth().await?;
+            let username_len = credentials[1] as usize;
+            session.username = Some(String::from_utf8_lossy(&credentials[2..2 + username_len]).into_owned());
+            handler.send_userpass_auth_response(true).await?;
+        }
+
+        let command = handler.handle_request().await?;
+        let (target, port) = command.target();
+        session.target = Some(format!("{}:{}", target.to_string(), port));
+        session.command = Some(match command {
+            Socks5Command::Connect { .. } => "CONNECT",
+            Socks5Command::Bind { .. } => "BIND",
+            Socks5Command::UdpAssociate { .. } => "UDP_ASSOCIATE",
+        }.to_string());
+        if !matches!(command, Socks5Command::Connect { .. }) {
+            handler.send_response(Socks5Response::error(SOCKS5_REPLY_COMMAND_NOT_SUPPORTED)).await?;
+            return Ok(());
+        }
+        handler.send_response(Socks5Response::success(TargetAddr::Ipv4(Ipv4Addr::UNSPECIFIED), 0)).await?;
+
+        let mut stream = handler.into_stream();
+        let mut buf = vec![0u8; 16 * 1024];
+        loop {
+            let n = stream.read(&mut buf).await?;
+            if n == 0 {
+                return Ok(());
+            }
+            session.bytes_sent += n as u64;
+            if session.payload_sizes.len() < MAX_PAYLOADS_PER_SESSION {
+                session.payload_sizes.push(n);
+            }
+        }
+    }
+
+    fn record(&self, session: HoneypotSession) {
+        fn tally(counts: &mut HashMap<String, u64>, value: String) {
+            if counts.len() < MAX_TALLIED || counts.contains_key(&value) {
+                *counts.entry(value).or_default() += 1;
+            }
+        }
+
+        let mut state = self.state.lock().unwrap();
+        state.total_sessions += 1;
+        state.total_bytes += session.bytes_sent;
+        *state.by_reason.entry(session.reason).or_default() += 1;
+        tally(&mut state.sources, session.client.ip().to_string());
+        if let Some(target) = &session.target {
+            tally(&mut state.targets, target.clone());
+        }
+        if let Some(username) = &session.username {
+            tally(&mut state.usernames, username.clone());
+        }
+        if self.config.history > 0 {
+            if state.recent.len() == self.config.history {
+                state.recent.pop_back();
+            }
+            state.recent.push_front(session);
+        }
+    }
+
+    /// Totals, the `limit` most frequent sources, targets, and usernames, and recent sessions
+    pub fn report(&self, limit: usize) -> HoneypotReport {
+        fn top(counts: &HashMap<String, u64>, limit: usize) -> Vec<HoneypotTally> {
+            let mut tallies: Vec<_> = counts.iter()
+                .map(|(value, sessions)| HoneypotTally { value: value.clone(), sessions: *sessions })
+                .collect();
+            tallies.sort_by(|a, b| b.sessions.cmp(&a.sessions).then_with(|| a.value.cmp(&b.value)));
+            tallies.truncate(limit);
+            tallies
+        }
+
+        let state = self.state.lock().unwrap();
+        HoneypotReport {
+            total_sessions: state.total_sessions,
+            active_sessions: self.active.load(Ordering::Relaxed),
+            total_bytes: state.total_bytes,
+            sessions_by_reason: state.by_reason.clone(),
+            top_sources: top(&state.sources, limit),
+            top_targets: top(&state.targets, limit),
+            top_usernames: top(&state.usernames, limit),
+            recent_sessions: state.recent.iter().take(limit).cloned().collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_session_records_destination_and_payloads() {
+        let honeypot = Arc::new(Honeypot::new(HoneypotConfig { enabled: true, ..Default::default() }));
+        assert!(honeypot.diverts(BlockReason::BruteForce));
+        assert!(!honeypot.diverts(BlockReason::RateLimit));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = {
+            let honeypot = honeypot.clone();
+            tokio::spawn(async move {
+                let (stream, client) = listener.accept().await.unwrap();
+                honeypot.serve(stream, client, BlockReason::BruteForce).await;
+            })
+        };
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let mut reply = [0u8; 10];
+        client.write_all(&[5, 1, 2]).await.unwrap();
+        client.read_exact(&mut reply[..2]).await.unwrap();
+        assert_eq!(&reply[..2], &[5, 2]);
+        client.write_all(&[1, 5]).await.unwrap();
+        client.write_all(b"admin").await.unwrap();
+        client.write_all(&[2, b'p', b'w']).await.unwrap();
+        client.read_exact(&mut reply[..2]).await.unwrap();
+        assert_eq!(&reply[..2], &[1, 0]);
+        client.write_all(&[5, 1, 0, 3, 11]).await.unwrap();
+        client.write_all(b"example.com").await.unwrap();
+        client.write_all(&443u16.to_be_bytes()).await.unwrap();
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[1], 0);
+        client.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+        client.flush().await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        drop(client);
+        server.await.unwrap();
+
+        let report = honeypot.report(10);
+        assert_eq!(report.total_sessions, 1);
+        assert_eq!(report.active_sessions, 0);
+        assert_eq!(report.total_bytes, 18);
+        assert_eq!(report.top_targets, vec![HoneypotTally { value: "example.com:443".to_string(), sessions: 1 }]);
+        assert_eq!(report.top_usernames[0].value, "admin");
+        let session = &report.recent_sessions[0];
+        assert_eq!(session.command.as_deref(), Some("CONNECT"));
+        assert_eq!(session.payload_sizes, vec![18]);
+        assert_eq!(report.top_sources[0].value, "127.0.0.1");
+    }
+
+    #[test]
+    fn test_session_slots_are_capped() {
+        let honeypot = Honeypot::new(HoneypotConfig { enabled: true, max_sessions: 1, ..Default::default() });
+        assert!(honeypot.reserve_slot());
+        assert!(!honeypot.reserve_slot());
+        drop(SessionSlot(&honeypot.active));
+        assert!(honeypot.reserve_slot());
+    }
+}
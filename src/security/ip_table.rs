@@ -0,0 +1,169 @@
+//! Shared Per-IP Security State
+//!
+//! The rate limiter, DDoS protection, and fail2ban all track state per client
+//! IP. Instead of three maps keyed by the same addresses, they share one table
+//! whose records hold each module's state side by side. An IP seen by all three
+//! costs a single entry, and a module can look at what the others know about
+//! an IP when it makes its own decision. Records expire through an
+//! `ExpiringMap` once the IP has been idle and no module has anything left to
+//! enforce against it.
+
+use std::net::IpAddr;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use crate::expiring::ExpiringMap;
+
+use super::ddos_protection::ConnectionFloodDetector;
+use super::fail2ban::BruteForceDetector;
+use super::rate_limiter::IpRateLimit;
+
+/// Everything the security modules track about one IP
+#[derive(Debug, Default)]
+pub struct IpSecurityRecord {
+    pub(super) rate: Option<IpRateLimit>,
+    pub(super) flood: Option<ConnectionFloodDetector>,
+    pub(super) brute_force: Option<BruteForceDetector>,
+}
+
+impl IpSecurityRecord {
+    /// Whether the rate limiter currently blocks this IP
+    pub fn is_rate_limited(&self) -> bool {
+        self.rate.as_ref().is_some_and(|rate| rate.is_blocked())
+    }
+
+    /// Whether DDoS protection currently blocks this IP
+    pub fn is_flood_blocked(&self) -> bool {
+        self.flood.as_ref().is_some_and(|flood| flood.is_blocked())
+    }
+
+    /// Whether fail2ban currently bans this IP
+    pub fn is_banned(&self) -> bool {
+        self.brute_force.as_ref().is_some_and(|detector| detector.is_banned())
+    }
+
+    /// Drop the state of modules with nothing left to enforce, returning
+    /// whether any state remains
+    fn retain_active(&mut self) -> bool {
+        if self.rate.as_ref().is_some_and(|rate| !rate.should_retain()) {
+            self.rate = None;
+        }
+        if self.flood.as_ref().is_some_and(|flood| !flood.should_retain()) {
+            self.flood = None;
+        }
+        if self.brute_force.as_ref().is_some_and(|detector| !detector.should_retain()) {
+            self.brute_force = None;
+        }
+        self.rate.is_some() || self.flood.is_some() || self.brute_force.is_some()
+    }
+}
+
+pub(super) type IpRecords = ExpiringMap<IpAddr, IpSecurityRecord>;
+
+/// How long an IP stays in the table without activity, unless configured
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// Table of per-IP security records shared by the security modules
+#[derive(Debug)]
+pub struct IpSecurityTable {
+    records: Mutex<IpRecords>,
+}
+
+impl Default for IpSecurityTable {
+    fn default() -> Self {
+        Self::new(DEFAULT_IDLE_TIMEOUT)
+    }
+}
+
+/// Combined view of one IP across the security modules
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpSecurityStatus {
+    pub rate_limited: bool,
+    pub flood_blocked: bool,
+    pub banned: bool,
+}
+
+impl IpSecurityTable {
+    /// Create a table that forgets an IP after `idle_timeout` without activity
+    pub fn new(idle_timeout: Duration) -> Self {
+        Self {
+            records: Mutex::new(ExpiringMap::new(idle_timeout)),
+        }
+    }
+
+    pub(super) fn lock(&self) -> MutexGuard<'_, IpRecords> {
+        self.records.lock().unwrap()
+    }
+
+    /// Current status of an IP, or `None` if no module is tracking it
+    pub fn status(&self, ip: IpAddr) -> Option<IpSecurityStatus> {
+        self.lock().get(&ip).map(|record| IpSecurityStatus {
+            rate_limited: record.is_rate_limited(),
+            flood_blocked: record.is_flood_blocked(),
+            banned: record.is_banned(),
+        })
+    }
+
+    /// Number of IPs tracked by at least one module
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Remove IPs that went idle, returning how many were removed
+    ///
+    /// Only records whose idle timeout has passed are looked at. An idle IP
+    /// that is still blocked, banned, or connected keeps the state that
+    /// matters and is checked again after another timeout.
+    pub fn expire(&self) -> usize {
+        self.lock().expire(Instant::now(), |_, record| record.retain_active())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::security::{DdosConfig, DdosProtection, Fail2BanConfig, Fail2BanManager, RateLimitConfig, RateLimiter};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_modules_share_one_record_per_ip() {
+        let table = Arc::new(IpSecurityTable::default());
+        let limiter = RateLimiter::new(RateLimitConfig::default()).with_ip_table(table.clone());
+        let ddos = DdosProtection::new(DdosConfig::default()).with_ip_table(table.clone());
+        let fail2ban = Fail2BanManager::new(Fail2BanConfig::default()).with_ip_table(table.clone());
+        let ip: IpAddr = "203.0.113.5".parse().unwrap();
+
+        assert!(limiter.check_connection_rate(ip));
+        ddos.check_connection(ip);
+        fail2ban.ban_ip(ip, Duration::from_secs(60), "test");
+
+        assert_eq!(table.len(), 1);
+        assert_eq!(
+            table.status(ip),
+            Some(IpSecurityStatus { rate_limited: false, flood_blocked: false, banned: true })
+        );
+    }
+
+    #[test]
+    fn test_idle_ips_expire_unless_still_enforced() {
+        let table = Arc::new(IpSecurityTable::new(Duration::from_millis(20)));
+        let limiter = RateLimiter::new(RateLimitConfig::default()).with_ip_table(table.clone());
+        let fail2ban = Fail2BanManager::new(Fail2BanConfig::default()).with_ip_table(table.clone());
+        let idle: IpAddr = "203.0.113.6".parse().unwrap();
+        let banned: IpAddr = "203.0.113.7".parse().unwrap();
+
+        limiter.check_connection_rate(idle);
+        limiter.check_connection_rate(banned);
+        fail2ban.ban_ip(banned, Duration::from_secs(60), "test");
+        assert_eq!(table.expire(), 0);
+
+        std::thread::sleep(Duration::from_millis(40));
+        assert_eq!(table.expire(), 1);
+        assert_eq!(table.status(idle), None);
+        assert!(table.lock().get(&banned).is_some_and(|record| record.rate.is_none() && record.is_banned()));
+    }
+}
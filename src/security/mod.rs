@@ -6,12 +6,44 @@
 pub mod rate_limiter;
 pub mod ddos_protection;
 pub mod fail2ban;
+pub mod fail2ban_log;
 pub mod secrets;
+pub mod reason;
+pub mod failure_policy;
+pub mod quota;
+pub mod prefilter;
+pub mod ip_table;
+pub mod udp_guard;
+pub mod private_ranges;
+pub mod ban_state;
+pub mod reputation;
+pub mod networks;
+pub mod siem;
+pub mod client_geo;
+pub mod honeypot;
+pub mod exfiltration;
+pub mod rejection;
 
-pub use rate_limiter::{RateLimiter, TokenBucket, RateLimitConfig};
+pub use rate_limiter::{RateLimiter, TokenBucket, RateLimitConfig, UdpAssociationLimit, UdpRateLimitConfig, UserRateLimitConfig};
 pub use ddos_protection::{DdosProtection, DdosConfig};
 pub use fail2ban::{Fail2BanManager, Fail2BanConfig};
-pub use secrets::{SecretsManager, SecureConfig};
+pub use fail2ban_log::{Fail2BanLog, Fail2BanLogConfig};
+pub use secrets::{MigrationOutcome, SecretsManager, SecureConfig};
+pub use reason::BlockReason;
+pub use failure_policy::{FailurePolicy, FailurePolicyConfig};
+pub use quota::{QuotaManager, QuotaConfig};
+pub use prefilter::{SecurityPrefilter, PrefilterDecision};
+pub use ip_table::{IpSecurityTable, IpSecurityRecord, IpSecurityStatus};
+pub use udp_guard::{UdpAssociationGuard, UdpGuardConfig, UdpGuardCounters, UdpGuardStats};
+pub use private_ranges::{PrivateAddress, PrivateRangesConfig};
+pub use ban_state::{BanStore, SavedBlocks, SavedNetworkBlocks, SavedState};
+pub use reputation::{FeedStatus, ReputationConfig, ReputationFilter};
+pub use networks::{NetworkBlocks, NetworkList};
+pub use siem::{SiemConfig, SiemExporter};
+pub use client_geo::{ClientGeoConfig, ClientGeoPolicy, CountryCounts};
+pub use honeypot::{Honeypot, HoneypotConfig, HoneypotReport};
+pub use exfiltration::{ExfiltrationConfig, ExfiltrationMonitor, ExfiltrationScope};
+pub use rejection::{RejectAction, RejectionConfig, Rejector};
 
 use std::net::IpAddr;
 use std::time::Duration;
@@ -24,6 +56,40 @@ pub struct SecurityConfig {
     pub ddos_protection: DdosConfig,
     pub fail2ban: Fail2BanConfig,
     pub secrets: SecureConfigSettings,
+    #[serde(default)]
+    pub failure_policies: FailurePolicyConfig,
+    #[serde(default)]
+    pub quotas: QuotaConfig,
+    /// Spoofing and replay protection for UDP ASSOCIATE relays
+    #[serde(default)]
+    pub udp_relay: UdpGuardConfig,
+    /// Refusal of direct connections to private, loopback, link-local and cloud metadata addresses
+    #[serde(default)]
+    pub private_ranges: PrivateRangesConfig,
+    /// Failed logins and bans written for the system fail2ban to read
+    #[serde(default)]
+    pub fail2ban_log: Option<Fail2BanLogConfig>,
+    /// Where blocks and bans in force are saved so a restart keeps them (in-memory only if unset)
+    #[serde(default)]
+    pub ban_state_path: Option<std::path::PathBuf>,
+    /// Downloaded lists of known-bad source networks refused at accept time
+    #[serde(default)]
+    pub reputation: ReputationConfig,
+    /// Allowed and refused client countries, checked before the handshake
+    #[serde(default)]
+    pub client_geo: ClientGeoConfig,
+    /// Fake sessions for refused clients, recorded instead of dropped
+    #[serde(default)]
+    pub honeypot: HoneypotConfig,
+    /// Alerts on upload volumes that look like data leaving through the proxy
+    #[serde(default)]
+    pub exfiltration: ExfiltrationConfig,
+    /// How connections refused at accept time are closed, per block reason
+    #[serde(default)]
+    pub rejection: RejectionConfig,
+    /// Security events forwarded to a remote syslog server or HTTP collector
+    #[serde(default)]
+    pub siem: Option<SiemConfig>,
 }
 
 /// Secure configuration settings
@@ -56,13 +122,51 @@ pub enum SecurityEvent {
     },
     IpBlocked {
         ip: IpAddr,
-        reason: String,
+        reason: BlockReason,
+        detail: String,
         duration: Duration,
     },
     IpUnblocked {
         ip: IpAddr,
         reason: String,
     },
+    /// A login failed; `user` is the name the client gave, unless privacy settings exclude it
+    AuthenticationFailed {
+        ip: IpAddr,
+        user: Option<String>,
+    },
+    /// A connection was refused at accept time
+    ConnectionRejected {
+        ip: IpAddr,
+        reason: BlockReason,
+        detail: String,
+    },
+    /// A connection or user uploaded more than its threshold within the window
+    ExfiltrationSuspected {
+        /// Left out for connections excluded by the privacy settings
+        ip: Option<IpAddr>,
+        user: Option<String>,
+        scope: ExfiltrationScope,
+        bytes_up: u64,
+        threshold: u64,
+        window: Duration,
+    },
+}
+
+impl SecurityEvent {
+    /// Get the reason code for events that block or ban an IP
+    pub fn reason_code(&self) -> Option<BlockReason> {
+        match self {
+            SecurityEvent::RateLimitExceeded { .. } => Some(BlockReason::RateLimit),
+            SecurityEvent::DdosAttackDetected { .. } => Some(BlockReason::Ddos),
+            SecurityEvent::BruteForceDetected { .. } => Some(BlockReason::BruteForce),
+            SecurityEvent::IpBlocked { reason, .. } => Some(*reason),
+            SecurityEvent::ConnectionRejected { reason, .. } => Some(*reason),
+            SecurityEvent::IpUnblocked { .. }
+            | SecurityEvent::AuthenticationFailed { .. }
+            | SecurityEvent::ExfiltrationSuspected { .. } => None,
+        }
+    }
 }
 
 /// Security statistics
@@ -87,6 +191,18 @@ impl Default for SecurityConfig {
                 secret_key_env: "SOCKS5_SECRET_KEY".to_string(),
                 config_encryption_key_env: "SOCKS5_CONFIG_KEY".to_string(),
             },
+            failure_policies: FailurePolicyConfig::default(),
+            quotas: QuotaConfig::default(),
+            udp_relay: UdpGuardConfig::default(),
+            private_ranges: PrivateRangesConfig::default(),
+            fail2ban_log: None,
+            ban_state_path: None,
+            reputation: ReputationConfig::default(),
+            client_geo: ClientGeoConfig::default(),
+            honeypot: HoneypotConfig::default(),
+            exfiltration: ExfiltrationConfig::default(),
+            rejection: RejectionConfig::default(),
+            siem: None,
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,172 @@
+//! Network Whitelists and Blocks
+//!
+//! Whitelists and manual blocks that cover whole networks rather than single
+//! addresses, so an office range can be exempted or a hostile subnet banned
+//! with one entry. Addresses are matched in canonical form, so an IPv4 client
+//! arriving as `::ffff:192.0.2.1` matches `192.0.2.0/24`.
+
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use ipnet::IpNet;
+use tracing::warn;
+
+/// Networks matched by address, parsed from config entries such as
+/// `192.0.2.10`, `10.20.0.0/16`, or `2001:db8::/32`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NetworkList(Vec<IpNet>);
+
+impl NetworkList {
+    /// Parse config entries, skipping (and warning about) any that are not
+    /// an address or CIDR; `what` names the setting in the warning
+    pub fn parse(entries: &[String], what: &str) -> Self {
+        let networks = entries.iter()
+            .filter_map(|entry| match crate::config::parse_source_cidr(entry) {
+                Ok(network) => Some(network.trunc()),
+                Err(e) => {
+                    warn!("Ignoring {} entry '{}': {:#}", what, entry, e);
+                    None
+                }
+            })
+            .collect();
+        Self(networks)
+    }
+
+    /// Whether `ip` is inside any of the networks
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        self.0.iter().any(|network| network.contains(&ip))
+    }
+
+    /// Whether all of `network` is inside one of the networks
+    pub fn covers(&self, network: &IpNet) -> bool {
+        self.0.iter().any(|listed| listed.contains(network))
+    }
+
+    /// Add a network, returning false if it was already listed
+    pub fn insert(&mut self, network: IpNet) -> bool {
+        let network = network.trunc();
+        if self.0.contains(&network) {
+            return false;
+        }
+        self.0.push(network);
+        true
+    }
+
+    /// Remove a network, returning false if it was not listed
+    pub fn remove(&mut self, network: &IpNet) -> bool {
+        let before = self.0.len();
+        self.0.retain(|listed| listed != &network.trunc());
+        self.0.len() != before
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn to_vec(&self) -> Vec<IpNet> {
+        self.0.clone()
+    }
+}
+
+/// Whether `network` names a single address rather than a range
+pub fn single_address(network: &IpNet) -> Option<IpAddr> {
+    (network.prefix_len() == network.max_prefix_len()).then(|| network.addr())
+}
+
+/// Manual blocks on whole networks, each lifted at its own deadline
+#[derive(Debug, Default)]
+pub struct NetworkBlocks {
+    blocks: Mutex<Vec<(IpNet, Instant)>>,
+}
+
+impl NetworkBlocks {
+    /// Block `network` for `duration`, replacing any block already on it
+    pub fn block(&self, network: IpNet, duration: Duration) {
+        self.block_until(network, Instant::now() + duration);
+    }
+
+    /// Block `network` until `until`, replacing any block already on it
+    pub fn block_until(&self, network: IpNet, until: Instant) {
+        let network = network.trunc();
+        let mut blocks = self.blocks.lock().unwrap();
+        match blocks.iter_mut().find(|(blocked, _)| *blocked == network) {
+            Some(block) => block.1 = until,
+            None => blocks.push((network, until)),
+        }
+    }
+
+    /// Lift the block on exactly `network`, returning false if there was none
+    pub fn unblock(&self, network: &IpNet) -> bool {
+        let network = network.trunc();
+        let now = Instant::now();
+        let mut blocks = self.blocks.lock().unwrap();
+        let before = blocks.len();
+        blocks.retain(|(blocked, until)| *blocked != network && now < *until);
+        blocks.len() != before
+    }
+
+    /// Time left on the longest block covering `ip`, if any
+    pub fn time_left(&self, ip: IpAddr) -> Option<Duration> {
+        let ip = ip.to_canonical();
+        let now = Instant::now();
+        let mut blocks = self.blocks.lock().unwrap();
+        blocks.retain(|(_, until)| now < *until);
+        blocks.iter()
+            .filter(|(network, _)| network.contains(&ip))
+            .map(|(_, until)| *until - now)
+            .max()
+    }
+
+    /// Networks blocked now and the time left on each
+    pub fn list(&self) -> Vec<(IpNet, Duration)> {
+        let now = Instant::now();
+        let mut blocks = self.blocks.lock().unwrap();
+        blocks.retain(|(_, until)| now < *until);
+        blocks.iter().map(|(network, until)| (*network, *until - now)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_network_list_matching() {
+        let entries = ["127.0.0.1", "10.20.0.0/16", "2001:db8::/32", "not-an-ip"].map(String::from);
+        let mut list = NetworkList::parse(&entries, "test");
+        assert_eq!(list.len(), 3);
+        assert!(list.contains("10.20.255.1".parse().unwrap()));
+        assert!(list.contains("::ffff:10.20.0.1".parse().unwrap()));
+        assert!(list.contains("2001:db8:1::5".parse().unwrap()));
+        assert!(!list.contains("10.21.0.1".parse().unwrap()));
+        assert!(list.covers(&"10.20.3.0/24".parse().unwrap()));
+        assert!(!list.covers(&"10.0.0.0/8".parse().unwrap()));
+
+        assert!(!list.insert("10.20.1.1/16".parse().unwrap()));
+        assert!(list.remove(&"10.20.0.0/16".parse().unwrap()));
+        assert!(!list.contains("10.20.255.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_network_blocks_expire_and_lift() {
+        let blocks = NetworkBlocks::default();
+        blocks.block("198.51.100.0/24".parse().unwrap(), Duration::from_secs(60));
+        blocks.block("203.0.113.0/24".parse().unwrap(), Duration::ZERO);
+
+        assert!(blocks.time_left("198.51.100.77".parse().unwrap()).is_some());
+        assert!(blocks.time_left("203.0.113.1".parse().unwrap()).is_none());
+        assert_eq!(blocks.list().len(), 1);
+
+        assert!(!blocks.unblock(&"198.51.0.0/16".parse().unwrap()));
+        assert!(blocks.unblock(&"198.51.100.0/24".parse().unwrap()));
+        assert!(blocks.time_left("198.51.100.77".parse().unwrap()).is_none());
+        assert_eq!(single_address(&"192.0.2.1/32".parse().unwrap()), Some("192.0.2.1".parse().unwrap()));
+        assert_eq!(single_address(&"192.0.2.0/31".parse().unwrap()), None);
+    }
+}
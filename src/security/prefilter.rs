@@ -0,0 +1,217 @@
+//! Accept-Time Prefilter
+//!
+//! Runs the per-connection security checks in one pass, cheapest and most
+//! decisive first, and stops at the first rejection. A source listed in a
+//! reputation feed or from a refused country is turned away before anything
+//! else looks at it, a banned IP
+//! is turned away before it can consume rate limiter tokens or count towards
+//! DDoS flood detection, and a rate-limited IP never reaches the DDoS
+//! detector.
+
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::ddos_protection::DdosDecision;
+use super::fail2ban::Fail2BanDecision;
+use super::{BlockReason, ClientGeoPolicy, DdosProtection, Fail2BanManager, RateLimiter, ReputationFilter};
+
+/// Outcome of the accept-time checks for one connection
+#[derive(Debug, Clone, PartialEq)]
+pub enum PrefilterDecision {
+    /// Accept the connection, after `delay` and, if set, waiting for a per-IP slot
+    Allow {
+        delay: Duration,
+        queue_timeout: Option<Duration>,
+    },
+    /// Drop the connection, after `delay`
+    Reject {
+        reason: BlockReason,
+        detail: String,
+        delay: Duration,
+    },
+}
+
+/// Combined rate limiter, DDoS, and fail2ban check for accepted connections
+#[derive(Clone)]
+pub struct SecurityPrefilter {
+    rate_limiter: Arc<RateLimiter>,
+    ddos_protection: Arc<DdosProtection>,
+    fail2ban: Arc<Fail2BanManager>,
+    reputation: Option<Arc<ReputationFilter>>,
+    client_geo: Option<Arc<ClientGeoPolicy>>,
+}
+
+impl SecurityPrefilter {
+    pub fn new(
+        rate_limiter: Arc<RateLimiter>,
+        ddos_protection: Arc<DdosProtection>,
+        fail2ban: Arc<Fail2BanManager>,
+    ) -> Self {
+        Self {
+            rate_limiter,
+            ddos_protection,
+            fail2ban,
+            reputation: None,
+            client_geo: None,
+        }
+    }
+
+    /// Refuse sources listed in the reputation feeds before the other checks
+    pub fn with_reputation(mut self, reputation: Option<Arc<ReputationFilter>>) -> Self {
+        self.reputation = reputation;
+        self
+    }
+
+    /// Refuse clients from countries the client country policy does not allow
+    pub fn with_client_geo(mut self, client_geo: Option<Arc<ClientGeoPolicy>>) -> Self {
+        self.client_geo = client_geo;
+        self
+    }
+
+    /// Decide whether to accept a connection from `ip`
+    pub fn check(&self, ip: IpAddr) -> PrefilterDecision {
+        // 0. Reputation feeds: a lookup in lists that only change on refresh
+        if let Some(feed) = self.reputation.as_ref().and_then(|reputation| reputation.check(ip)) {
+            return PrefilterDecision::Reject {
+                reason: BlockReason::Reputation,
+                detail: format!("listed in reputation feed '{}'", feed),
+                delay: Duration::ZERO,
+            };
+        }
+
+        // 0b. Client country: a database lookup, no per-IP state
+        if let Some(detail) = self.client_geo.as_ref().and_then(|client_geo| client_geo.check(ip)) {
+            return PrefilterDecision::Reject { reason: BlockReason::Geo, detail, delay: Duration::ZERO };
+        }
+
+        // 1. Fail2ban: a read-only lookup that settles banned IPs outright
+        let delay = match self.fail2ban.check_auth_attempt(ip) {
+            Fail2BanDecision::Allow => Duration::ZERO,
+            Fail2BanDecision::Delay { delay, .. } => delay,
+            Fail2BanDecision::Block { reason, delay, .. } => {
+                return PrefilterDecision::Reject { reason: BlockReason::BruteForce, detail: reason, delay };
+            }
+        };
+
+        // 2. Rate limiter: consumes a token, so only for IPs that are not banned
+        if !self.rate_limiter.check_connection_rate(ip) {
+            return PrefilterDecision::Reject {
+                reason: BlockReason::RateLimit,
+                detail: "connection rate limit exceeded".to_string(),
+                delay: Duration::ZERO,
+            };
+        }
+
+        // 3. DDoS protection: records the connection for flood detection
+        match self.ddos_protection.check_connection(ip) {
+            DdosDecision::Allow => PrefilterDecision::Allow { delay, queue_timeout: None },
+            DdosDecision::Queue { timeout } => PrefilterDecision::Allow { delay, queue_timeout: Some(timeout) },
+            DdosDecision::Block { reason, delay } => {
+                PrefilterDecision::Reject { reason: BlockReason::Ddos, detail: reason, delay }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::security::{DdosConfig, Fail2BanConfig, RateLimitConfig};
+
+    fn prefilter() -> SecurityPrefilter {
+        let rate_config = RateLimitConfig {
+            enabled: true,
+            connections_per_ip_burst: 2,
+            ..RateLimitConfig::default()
+        };
+        let fail2ban_config = Fail2BanConfig {
+            enabled: true,
+            whitelist_ips: Vec::new(),
+            ..Fail2BanConfig::default()
+        };
+        SecurityPrefilter::new(
+            Arc::new(RateLimiter::new(rate_config)),
+            Arc::new(DdosProtection::new(DdosConfig { enabled: false, ..DdosConfig::default() })),
+            Arc::new(Fail2BanManager::new(fail2ban_config)),
+        )
+    }
+
+    #[test]
+    fn test_banned_ip_does_not_consume_rate_tokens() {
+        let filter = prefilter();
+        let banned: IpAddr = "198.51.100.1".parse().unwrap();
+        filter.fail2ban.ban_ip(banned, Duration::from_secs(60), "test");
+
+        for _ in 0..5 {
+            assert!(matches!(
+                filter.check(banned),
+                PrefilterDecision::Reject { reason: BlockReason::BruteForce, .. }
+            ));
+        }
+        assert_eq!(filter.rate_limiter.get_stats().total_connections_checked, 0);
+    }
+
+    #[test]
+    fn test_rate_limit_short_circuits() {
+        let filter = prefilter();
+        let ip: IpAddr = "198.51.100.2".parse().unwrap();
+
+        assert!(matches!(filter.check(ip), PrefilterDecision::Allow { queue_timeout: None, .. }));
+        assert!(matches!(filter.check(ip), PrefilterDecision::Allow { .. }));
+        assert!(matches!(
+            filter.check(ip),
+            PrefilterDecision::Reject { reason: BlockReason::RateLimit, .. }
+        ));
+    }
+
+    #[test]
+    fn test_listed_source_rejected_before_other_checks() {
+        let cache = tempfile::tempdir().unwrap();
+        std::fs::write(cache.path().join("drop.txt"), "203.0.113.0/24\n").unwrap();
+        let reputation = crate::security::ReputationFilter::new(crate::security::ReputationConfig {
+            enabled: true,
+            feeds: vec![crate::security::reputation::FeedConfig {
+                name: Some("drop".to_string()),
+                preset: None,
+                url: Some("https://example.com/drop.txt".to_string()),
+            }],
+            cache_dir: Some(cache.path().to_path_buf()),
+            ..Default::default()
+        })
+        .unwrap();
+        let filter = prefilter().with_reputation(Some(Arc::new(reputation)));
+
+        match filter.check("203.0.113.9".parse().unwrap()) {
+            PrefilterDecision::Reject { reason, detail, .. } => {
+                assert_eq!(reason, BlockReason::Reputation);
+                assert!(detail.contains("'drop'"));
+            }
+            decision => panic!("unexpected {:?}", decision),
+        }
+        assert_eq!(filter.rate_limiter.get_stats().total_connections_checked, 0);
+        assert!(matches!(filter.check("198.51.100.3".parse().unwrap()), PrefilterDecision::Allow { .. }));
+    }
+
+    #[test]
+    fn test_refused_country_rejected_before_other_checks() {
+        // Without a usable database, the closed failure policy refuses every non-exempt client
+        let client_geo = ClientGeoPolicy::new(
+            &crate::security::ClientGeoConfig {
+                enabled: true,
+                allow_countries: vec!["DE".to_string()],
+                exempt_cidrs: vec!["198.51.100.0/24".to_string()],
+                ..Default::default()
+            },
+            crate::security::FailurePolicy::Closed,
+        );
+        let filter = prefilter().with_client_geo(Some(Arc::new(client_geo)));
+
+        assert!(matches!(
+            filter.check("203.0.113.9".parse().unwrap()),
+            PrefilterDecision::Reject { reason: BlockReason::Geo, .. }
+        ));
+        assert_eq!(filter.rate_limiter.get_stats().total_connections_checked, 0);
+        assert!(matches!(filter.check("198.51.100.3".parse().unwrap()), PrefilterDecision::Allow { .. }));
+    }
+}
@@ -0,0 +1,115 @@
+//! Private Range (SSRF) Protection
+//!
+//! A proxy reachable by clients outside its network would otherwise let them
+//! reach services inside it: databases on RFC 1918 addresses, admin ports on
+//! loopback, or a cloud provider's metadata endpoint handing out credentials.
+//! Direct CONNECTs to such addresses are refused unless the routing rule that
+//! allowed the connection sets `allow_private`. Resolved addresses are
+//! checked, not names, so a public name that resolves to an internal address
+//! (including by DNS rebinding between checks) is caught as well.
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+/// Metadata endpoints of cloud providers, checked before the wider ranges
+/// they sit in so they are reported by name
+const METADATA_ADDRESSES: &[IpAddr] = &[
+    // AWS, GCP, Azure, OpenStack and most others
+    IpAddr::V4(Ipv4Addr::new(169, 254, 169, 254)),
+    // Alibaba Cloud
+    IpAddr::V4(Ipv4Addr::new(100, 100, 100, 200)),
+    // AWS over IPv6
+    IpAddr::V6(Ipv6Addr::new(0xfd00, 0x0ec2, 0, 0, 0, 0, 0, 0x0254)),
+];
+
+/// Private range protection configuration
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PrivateRangesConfig {
+    /// Refuse direct connections to private and internal addresses
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+impl Default for PrivateRangesConfig {
+    fn default() -> Self {
+        Self { enabled: default_enabled() }
+    }
+}
+
+/// A connection would reach a private or internal address
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrivateAddress {
+    pub addr: SocketAddr,
+    /// Name of the range the address is in
+    pub range: &'static str,
+}
+
+impl fmt::Display for PrivateAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} is a {} address, which no rule allows reaching", self.addr, self.range)
+    }
+}
+
+impl std::error::Error for PrivateAddress {}
+
+/// The private or internal range `ip` is in, if any
+pub fn private_range(ip: IpAddr) -> Option<&'static str> {
+    let ip = ip.to_canonical();
+    if METADATA_ADDRESSES.contains(&ip) {
+        return Some("cloud metadata");
+    }
+    match ip {
+        IpAddr::V4(ip) if ip.is_loopback() => Some("loopback"),
+        IpAddr::V4(ip) if ip.is_private() => Some("private (RFC 1918)"),
+        IpAddr::V4(ip) if ip.is_link_local() => Some("link-local"),
+        // 0.0.0.0/8 reaches the proxy host itself
+        IpAddr::V4(ip) if ip.octets()[0] == 0 => Some("unspecified"),
+        IpAddr::V6(ip) if ip.is_loopback() => Some("loopback"),
+        IpAddr::V6(ip) if ip.is_unspecified() => Some("unspecified"),
+        IpAddr::V6(ip) if ip.is_unicast_link_local() => Some("link-local"),
+        IpAddr::V6(ip) if ip.is_unique_local() => Some("unique local"),
+        _ => None,
+    }
+}
+
+/// Fail if `addr` is a private or internal address
+pub fn check(addr: SocketAddr) -> Result<(), PrivateAddress> {
+    match private_range(addr.ip()) {
+        Some(range) => Err(PrivateAddress { addr, range }),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(ip: &str) -> Option<&'static str> {
+        private_range(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn test_classifies_internal_addresses() {
+        assert_eq!(range("169.254.169.254"), Some("cloud metadata"));
+        assert_eq!(range("fd00:ec2::254"), Some("cloud metadata"));
+        assert_eq!(range("169.254.1.1"), Some("link-local"));
+        assert_eq!(range("127.0.0.53"), Some("loopback"));
+        assert_eq!(range("::ffff:10.1.2.3"), Some("private (RFC 1918)"));
+        assert_eq!(range("172.31.255.255"), Some("private (RFC 1918)"));
+        assert_eq!(range("192.168.0.1"), Some("private (RFC 1918)"));
+        assert_eq!(range("0.0.0.0"), Some("unspecified"));
+        assert_eq!(range("::"), Some("unspecified"));
+        assert_eq!(range("fe80::1"), Some("link-local"));
+        assert_eq!(range("fd12:3456::1"), Some("unique local"));
+
+        assert_eq!(range("172.32.0.1"), None);
+        assert_eq!(range("100.64.0.1"), None);
+        assert_eq!(range("93.184.216.34"), None);
+        assert_eq!(range("2606:2800:220:1::1"), None);
+    }
+}
@@ -0,0 +1,530 @@
+//! Per-User Data Quotas
+//!
+//! Tracks bytes relayed per user against daily and monthly caps. Once a user
+//! is over a cap their new connections are refused. Their active relays
+//! share `grace_bytes` of slack past the cap, counted against the user's
+//! usage rather than per relay, so a nearly finished transfer is not cut off;
+//! once it is used up they are terminated or throttled at the next progress
+//! report.
+//! Periods roll over at midnight UTC and on the first of the month, and usage
+//! is persisted to a JSON state file so a restart does not reset anyone's
+//! counters. Besides the total, uploads and downloads can be capped on their
+//! own, since operators often want a much tighter limit on what leaves.
+
+use crate::config::UserConfig;
+use crate::relay::{RelayControl, RelayObserver, RelaySession};
+use crate::Result;
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{debug, info, warn};
+
+/// Quota configuration
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct QuotaConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Daily cap for users without their own `daily_quota_bytes`
+    #[serde(default)]
+    pub default_daily_bytes: Option<u64>,
+    /// Monthly cap for users without their own `monthly_quota_bytes`
+    #[serde(default)]
+    pub default_monthly_bytes: Option<u64>,
+    /// Daily cap on client-to-target bytes
+    #[serde(default)]
+    pub default_daily_upload_bytes: Option<u64>,
+    /// Monthly cap on client-to-target bytes
+    #[serde(default)]
+    pub default_monthly_upload_bytes: Option<u64>,
+    /// Daily cap on target-to-client bytes
+    #[serde(default)]
+    pub default_daily_download_bytes: Option<u64>,
+    /// Monthly cap on target-to-client bytes
+    #[serde(default)]
+    pub default_monthly_download_bytes: Option<u64>,
+    /// Where usage counters are persisted (in-memory only if unset)
+    #[serde(default)]
+    pub state_path: Option<PathBuf>,
+    /// Bytes a user's active relays may transfer past the cap, all together, before they are acted on
+    #[serde(default)]
+    pub grace_bytes: u64,
+    /// What happens to active relays once the cap plus grace is crossed
+    #[serde(default)]
+    pub on_exceeded: QuotaAction,
+    /// Per-direction rate for throttled relays
+    #[serde(default = "default_throttle_bytes_per_second")]
+    pub throttle_bytes_per_second: u64,
+}
+
+fn default_throttle_bytes_per_second() -> u64 {
+    64 * 1024
+}
+
+impl Default for QuotaConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            default_daily_bytes: None,
+            default_monthly_bytes: None,
+            default_daily_upload_bytes: None,
+            default_monthly_upload_bytes: None,
+            default_daily_download_bytes: None,
+            default_monthly_download_bytes: None,
+            state_path: None,
+            grace_bytes: 0,
+            on_exceeded: QuotaAction::default(),
+            throttle_bytes_per_second: default_throttle_bytes_per_second(),
+        }
+    }
+}
+
+/// Action taken on an active relay whose user runs out of quota
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QuotaAction {
+    /// Close the relay
+    #[default]
+    Terminate,
+    /// Keep the relay open at `throttle_bytes_per_second`
+    Throttle,
+}
+
+/// Quota a user has exhausted
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QuotaExceeded {
+    Daily,
+    Monthly,
+    DailyUpload,
+    MonthlyUpload,
+    DailyDownload,
+    MonthlyDownload,
+}
+
+impl fmt::Display for QuotaExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QuotaExceeded::Daily => f.write_str("daily"),
+            QuotaExceeded::Monthly => f.write_str("monthly"),
+            QuotaExceeded::DailyUpload => f.write_str("daily upload"),
+            QuotaExceeded::MonthlyUpload => f.write_str("monthly upload"),
+            QuotaExceeded::DailyDownload => f.write_str("daily download"),
+            QuotaExceeded::MonthlyDownload => f.write_str("monthly download"),
+        }
+    }
+}
+
+/// Bytes used by one user in the current periods
+///
+/// Totals cover both directions; downloads are the total minus uploads.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct QuotaUsage {
+    /// Days since the Unix epoch (UTC)
+    pub day: u64,
+    pub daily_bytes: u64,
+    #[serde(default)]
+    pub daily_upload_bytes: u64,
+    /// Months since January 1970
+    pub month: u64,
+    pub monthly_bytes: u64,
+    #[serde(default)]
+    pub monthly_upload_bytes: u64,
+}
+
+impl QuotaUsage {
+    /// Reset counters whose period has ended
+    fn roll(&mut self, day: u64, month: u64) {
+        if self.day != day {
+            self.day = day;
+            self.daily_bytes = 0;
+            self.daily_upload_bytes = 0;
+        }
+        if self.month != month {
+            self.month = month;
+            self.monthly_bytes = 0;
+            self.monthly_upload_bytes = 0;
+        }
+    }
+
+    fn add(&mut self, bytes_up: u64, bytes_down: u64) {
+        let total = bytes_up.saturating_add(bytes_down);
+        self.daily_bytes = self.daily_bytes.saturating_add(total);
+        self.monthly_bytes = self.monthly_bytes.saturating_add(total);
+        self.daily_upload_bytes = self.daily_upload_bytes.saturating_add(bytes_up);
+        self.monthly_upload_bytes = self.monthly_upload_bytes.saturating_add(bytes_up);
+    }
+
+    pub fn daily_download_bytes(&self) -> u64 {
+        self.daily_bytes.saturating_sub(self.daily_upload_bytes)
+    }
+
+    pub fn monthly_download_bytes(&self) -> u64 {
+        self.monthly_bytes.saturating_sub(self.monthly_upload_bytes)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct QuotaLimits {
+    daily: Option<u64>,
+    monthly: Option<u64>,
+    daily_upload: Option<u64>,
+    monthly_upload: Option<u64>,
+    daily_download: Option<u64>,
+    monthly_download: Option<u64>,
+}
+
+impl QuotaLimits {
+    fn defaults(config: &QuotaConfig) -> Self {
+        Self {
+            daily: config.default_daily_bytes,
+            monthly: config.default_monthly_bytes,
+            daily_upload: config.default_daily_upload_bytes,
+            monthly_upload: config.default_monthly_upload_bytes,
+            daily_download: config.default_daily_download_bytes,
+            monthly_download: config.default_monthly_download_bytes,
+        }
+    }
+
+    fn for_user(user: &UserConfig, config: &QuotaConfig) -> Self {
+        let defaults = Self::defaults(config);
+        Self {
+            daily: user.daily_quota_bytes.or(defaults.daily),
+            monthly: user.monthly_quota_bytes.or(defaults.monthly),
+            daily_upload: user.daily_upload_quota_bytes.or(defaults.daily_upload),
+            monthly_upload: user.monthly_upload_quota_bytes.or(defaults.monthly_upload),
+            daily_download: user.daily_download_quota_bytes.or(defaults.daily_download),
+            monthly_download: user.monthly_download_quota_bytes.or(defaults.monthly_download),
+        }
+    }
+}
+
+/// Tracks and enforces per-user data quotas
+pub struct QuotaManager {
+    config: QuotaConfig,
+    limits: HashMap<String, QuotaLimits>,
+    /// Monthly caps from the `quota_bytes` of a user's latest token or webhook login
+    claimed: Mutex<HashMap<String, u64>>,
+    usage: Arc<Mutex<HashMap<String, QuotaUsage>>>,
+    dirty: AtomicBool,
+}
+
+impl QuotaManager {
+    /// Create a quota manager, restoring persisted usage if a state file exists
+    pub fn new(config: QuotaConfig, users: &[UserConfig]) -> Self {
+        let limits = users.iter()
+            .map(|user| (user.username.clone(), QuotaLimits::for_user(user, &config)))
+            .collect();
+
+        let usage = match (&config.state_path, config.enabled) {
+            (Some(path), true) if path.exists() => match Self::load(path) {
+                Ok(usage) => {
+                    info!("Restored quota usage for {} user(s) from {}", usage.len(), path.display());
+                    usage
+                }
+                Err(e) => {
+                    warn!("Ignoring unreadable quota state {}: {:#}", path.display(), e);
+                    HashMap::new()
+                }
+            },
+            _ => HashMap::new(),
+        };
+
+        Self {
+            config,
+            limits,
+            claimed: Mutex::new(HashMap::new()),
+            usage: Arc::new(Mutex::new(usage)),
+            dirty: AtomicBool::new(false),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.config.enabled
+    }
+
+    /// Return the exhausted period if the user is over quota
+    pub fn check(&self, user: &str) -> Option<QuotaExceeded> {
+        if !self.config.enabled {
+            return None;
+        }
+        let (day, month) = current_period();
+        let mut usage = self.usage.lock().unwrap();
+        let entry = usage.get_mut(user)?;
+        entry.roll(day, month);
+        self.exceeded(user, entry, 0)
+    }
+
+    /// Cap a user's monthly total at `bytes`, as granted by their identity
+    /// provider at login; replaces any configured monthly cap for the user
+    pub fn set_claimed_quota(&self, user: &str, bytes: u64) {
+        if self.config.enabled {
+            self.claimed.lock().unwrap().insert(user.to_string(), bytes);
+        }
+    }
+
+    /// Add relayed bytes to a user's counters, returning the exhausted quota if now over one
+    pub fn record_usage(&self, user: &str, bytes_up: u64, bytes_down: u64) -> Option<QuotaExceeded> {
+        self.record(user, bytes_up, bytes_down, 0)
+    }
+
+    /// Add relayed bytes, returning the exhausted quota if over it by more than `slack`
+    fn record(&self, user: &str, bytes_up: u64, bytes_down: u64, slack: u64) -> Option<QuotaExceeded> {
+        if !self.config.enabled || (bytes_up == 0 && bytes_down == 0) {
+            return None;
+        }
+        let (day, month) = current_period();
+        let mut usage = self.usage.lock().unwrap();
+        let entry = usage.entry(user.to_string()).or_default();
+        entry.roll(day, month);
+        entry.add(bytes_up, bytes_down);
+        self.dirty.store(true, Ordering::Relaxed);
+        self.exceeded(user, entry, slack)
+    }
+
+    /// Current usage for a user
+    pub fn usage(&self, user: &str) -> Option<QuotaUsage> {
+        let (day, month) = current_period();
+        let mut usage = self.usage.lock().unwrap();
+        let entry = usage.get_mut(user)?;
+        entry.roll(day, month);
+        Some(entry.clone())
+    }
+
+    /// Persist usage counters if they changed since the last save
+    pub fn save(&self) -> Result<()> {
+        let Some(path) = &self.config.state_path else {
+            return Ok(());
+        };
+        if !self.config.enabled || !self.dirty.swap(false, Ordering::Relaxed) {
+            return Ok(());
+        }
+
+        let content = {
+            let usage = self.usage.lock().unwrap();
+            serde_json::to_string_pretty(&*usage)?
+        };
+        let result = write_atomically(path, &content);
+        if result.is_err() {
+            // Try again on the next save
+            self.dirty.store(true, Ordering::Relaxed);
+        } else {
+            debug!("Saved quota usage to {}", path.display());
+        }
+        result
+    }
+
+    fn limits_for(&self, user: &str) -> QuotaLimits {
+        let mut limits = self.limits.get(user).copied().unwrap_or_else(|| QuotaLimits::defaults(&self.config));
+        if let Some(bytes) = self.claimed.lock().unwrap().get(user) {
+            limits.monthly = Some(*bytes);
+        }
+        limits
+    }
+
+    fn exceeded(&self, user: &str, usage: &QuotaUsage, slack: u64) -> Option<QuotaExceeded> {
+        let limits = self.limits_for(user);
+        let over = |used: u64, limit: Option<u64>| {
+            limit.is_some_and(|limit| used >= limit.saturating_add(slack))
+        };
+        [
+            (usage.daily_bytes, limits.daily, QuotaExceeded::Daily),
+            (usage.monthly_bytes, limits.monthly, QuotaExceeded::Monthly),
+            (usage.daily_upload_bytes, limits.daily_upload, QuotaExceeded::DailyUpload),
+            (usage.monthly_upload_bytes, limits.monthly_upload, QuotaExceeded::MonthlyUpload),
+            (usage.daily_download_bytes(), limits.daily_download, QuotaExceeded::DailyDownload),
+            (usage.monthly_download_bytes(), limits.monthly_download, QuotaExceeded::MonthlyDownload),
+        ]
+        .into_iter()
+        .find(|(used, limit, _)| over(*used, *limit))
+        .map(|(_, _, exceeded)| exceeded)
+    }
+
+    fn load(path: &Path) -> Result<HashMap<String, QuotaUsage>> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        serde_json::from_str(&content)
+            .with_context(|| format!("Failed to parse {}", path.display()))
+    }
+}
+
+impl RelayObserver for QuotaManager {
+    fn on_progress(&self, session: &RelaySession, user_id: Option<&str>, bytes_up: u64, bytes_down: u64) -> RelayControl {
+        let Some(user) = user_id else {
+            return RelayControl::Continue;
+        };
+        let Some(exceeded) = self.record(user, bytes_up, bytes_down, self.config.grace_bytes) else {
+            return RelayControl::Continue;
+        };
+        match self.config.on_exceeded {
+            QuotaAction::Terminate => {
+                warn!("User '{}' exceeded {} quota, terminating relay {}", user, exceeded, session.session_id);
+                RelayControl::Terminate(format!("{} quota exceeded for user '{}'", exceeded, user))
+            }
+            QuotaAction::Throttle => RelayControl::Throttle(self.config.throttle_bytes_per_second),
+        }
+    }
+}
+
+/// Replace a file's contents without leaving a partial file behind on failure
+pub(crate) fn write_atomically(path: &Path, content: &str) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, content)
+        .with_context(|| format!("Failed to write {}", tmp.display()))?;
+    std::fs::rename(&tmp, path)
+        .with_context(|| format!("Failed to replace {}", path.display()))?;
+    Ok(())
+}
+
+/// Current UTC day and month indexes
+pub(crate) fn current_period() -> (u64, u64) {
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let day = secs / 86_400;
+    (day, month_index(day))
+}
+
+/// Months since January 1970 for a day index (civil calendar, UTC)
+fn month_index(day: u64) -> u64 {
+    // Howard Hinnant's days-to-civil algorithm, shifted so the year starts in March
+    let z = day + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    (year - 1970) * 12 + (month - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_user(username: &str, daily: Option<u64>) -> UserConfig {
+        UserConfig {
+            daily_quota_bytes: daily,
+            ..UserConfig::new(username, "password")
+        }
+    }
+
+    fn test_config() -> QuotaConfig {
+        QuotaConfig {
+            enabled: true,
+            default_monthly_bytes: Some(1000),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_month_index() {
+        assert_eq!(month_index(0), 0); // 1970-01-01
+        assert_eq!(month_index(31), 1); // 1970-02-01
+        assert_eq!(month_index(59), 2); // 1970-03-01
+        assert_eq!(month_index(19_783), 650); // 2024-03-01
+        assert_eq!(month_index(19_782), 649); // 2024-02-29
+    }
+
+    #[test]
+    fn test_user_and_default_limits() {
+        let manager = QuotaManager::new(test_config(), &[test_user("alice", Some(100))]);
+
+        assert_eq!(manager.record_usage("alice", 30, 30), None);
+        assert_eq!(manager.record_usage("alice", 30, 30), Some(QuotaExceeded::Daily));
+        assert_eq!(manager.check("alice"), Some(QuotaExceeded::Daily));
+
+        // Users without an override fall back to the defaults
+        assert_eq!(manager.record_usage("bob", 0, 999), None);
+        assert_eq!(manager.record_usage("bob", 1, 0), Some(QuotaExceeded::Monthly));
+        assert_eq!(manager.check("carol"), None);
+    }
+
+    #[test]
+    fn test_claimed_quota_replaces_monthly_cap() {
+        let manager = QuotaManager::new(test_config(), &[]);
+        manager.set_claimed_quota("token-user", 50);
+
+        assert_eq!(manager.record_usage("token-user", 40, 0), None);
+        assert_eq!(manager.record_usage("token-user", 10, 0), Some(QuotaExceeded::Monthly));
+        // Others keep the default monthly cap of 1000
+        assert_eq!(manager.record_usage("bob", 50, 0), None);
+    }
+
+    #[test]
+    fn test_usage_rolls_over() {
+        let mut usage = QuotaUsage { day: 10, daily_bytes: 500, month: 0, monthly_bytes: 800, ..Default::default() };
+        usage.roll(11, 0);
+        assert_eq!((usage.daily_bytes, usage.monthly_bytes), (0, 800));
+        usage.roll(40, 1);
+        assert_eq!((usage.daily_bytes, usage.monthly_bytes), (0, 0));
+    }
+
+    #[test]
+    fn test_usage_survives_restart() {
+        let path = std::env::temp_dir().join(format!("rustproxy-quota-{}.json", std::process::id()));
+        let config = QuotaConfig { state_path: Some(path.clone()), ..test_config() };
+
+        let manager = QuotaManager::new(config.clone(), &[]);
+        manager.record_usage("alice", 400, 600);
+        manager.save().unwrap();
+
+        let restored = QuotaManager::new(config, &[]);
+        let usage = restored.usage("alice").unwrap();
+        assert_eq!((usage.monthly_bytes, usage.monthly_download_bytes()), (1000, 600));
+        assert_eq!(restored.check("alice"), Some(QuotaExceeded::Monthly));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_observer_terminates_over_quota() {
+        let manager = QuotaManager::new(test_config(), &[]);
+        let addr = "127.0.0.1:1080".parse().unwrap();
+        let session = RelaySession::new("test".to_string(), addr, addr);
+
+        assert_eq!(manager.on_progress(&session, None, 5000, 0), RelayControl::Continue);
+        assert_eq!(manager.on_progress(&session, Some("alice"), 400, 400), RelayControl::Continue);
+        assert!(matches!(
+            manager.on_progress(&session, Some("alice"), 100, 100),
+            RelayControl::Terminate(_)
+        ));
+    }
+
+    #[test]
+    fn test_upload_and_download_limits() {
+        let config = QuotaConfig {
+            default_daily_upload_bytes: Some(100),
+            default_daily_download_bytes: Some(10_000),
+            default_monthly_bytes: None,
+            ..test_config()
+        };
+        let manager = QuotaManager::new(config, &[]);
+
+        assert_eq!(manager.record_usage("alice", 50, 5000), None);
+        assert_eq!(manager.record_usage("alice", 60, 0), Some(QuotaExceeded::DailyUpload));
+        assert_eq!(manager.record_usage("bob", 0, 10_000), Some(QuotaExceeded::DailyDownload));
+    }
+
+    #[test]
+    fn test_grace_then_throttle() {
+        let config = QuotaConfig {
+            grace_bytes: 100,
+            on_exceeded: QuotaAction::Throttle,
+            throttle_bytes_per_second: 512,
+            ..test_config()
+        };
+        let manager = QuotaManager::new(config, &[]);
+        let addr = "127.0.0.1:1080".parse().unwrap();
+        let session = RelaySession::new("test".to_string(), addr, addr);
+
+        // Over the cap: new connections are refused but the active relay keeps going
+        assert_eq!(manager.on_progress(&session, Some("alice"), 1050, 0), RelayControl::Continue);
+        assert_eq!(manager.check("alice"), Some(QuotaExceeded::Monthly));
+
+        assert_eq!(manager.on_progress(&session, Some("alice"), 50, 0), RelayControl::Throttle(512));
+    }
+}
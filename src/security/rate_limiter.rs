@@ -1,7 +1,10 @@
 //! Rate Limiting Implementation
 //! 
 //! Implements token bucket rate limiting per IP address to prevent abuse
-//! and connection flooding attacks.
+//! and connection flooding attacks, and optionally per user, so users
+//! sharing an address behind NAT are limited on their own. UDP relays can
+//! additionally be held to packet and byte rates per association and per
+//! source IP, since a flood of datagrams costs a client nothing.
 
 use std::collections::HashMap;
 use std::net::IpAddr;
@@ -9,6 +12,7 @@ use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 use serde::{Deserialize, Serialize};
 use tracing::{debug, warn, info};
+use super::ip_table::IpSecurityTable;
 
 /// Rate limiting configuration
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -21,6 +25,59 @@ pub struct RateLimitConfig {
     pub global_connections_per_second: u32,
     pub cleanup_interval_seconds: u64,
     pub block_duration_minutes: u64,
+    /// Limits for each user on top of the per-IP ones; off when unset
+    #[serde(default)]
+    pub per_user: Option<UserRateLimitConfig>,
+    /// Packet and byte ceilings for UDP relays; off when unset
+    #[serde(default)]
+    pub udp: Option<UdpRateLimitConfig>,
+}
+
+/// Token buckets kept for each user
+///
+/// Failed logins are counted against the configured user a client names;
+/// connections against the user once authenticated. A user over a limit is
+/// refused until their bucket refills, without blocking the address they
+/// came from.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct UserRateLimitConfig {
+    pub connections_per_minute: u32,
+    pub connections_burst: u32,
+    pub auth_attempts_per_minute: u32,
+    pub auth_attempts_burst: u32,
+    /// Users tracked at once; beyond this, new users go unlimited until
+    /// idle ones are dropped
+    pub max_tracked_users: usize,
+}
+
+/// Packet and byte ceilings on the datagrams UDP clients send, each
+/// allowing a second's worth of burst; unset ceilings do not apply
+///
+/// Datagrams over a ceiling are dropped rather than the source blocked.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct UdpRateLimitConfig {
+    /// Datagrams per second from the client of one association
+    pub association_packets_per_second: Option<u32>,
+    /// Bytes per second from the client of one association
+    pub association_bytes_per_second: Option<u32>,
+    /// Datagrams per second across all associations from one IP address
+    pub ip_packets_per_second: Option<u32>,
+    /// Bytes per second across all associations from one IP address
+    pub ip_bytes_per_second: Option<u32>,
+}
+
+impl Default for UserRateLimitConfig {
+    fn default() -> Self {
+        Self {
+            connections_per_minute: 60,
+            connections_burst: 10,
+            auth_attempts_per_minute: 10,
+            auth_attempts_burst: 3,
+            max_tracked_users: 10_000,
+        }
+    }
 }
 
 impl Default for RateLimitConfig {
@@ -34,6 +91,8 @@ impl Default for RateLimitConfig {
             global_connections_per_second: 1000,
             cleanup_interval_seconds: 300, // 5 minutes
             block_duration_minutes: 15,
+            per_user: None,
+            udp: None,
         }
     }
 }
@@ -83,6 +142,16 @@ impl TokenBucket {
         }
     }
 
+    /// Create a token bucket refilled every second rather than every minute
+    pub fn per_second(capacity: u32, refill_rate_per_second: u32) -> Self {
+        Self {
+            capacity,
+            tokens: capacity as f64,
+            refill_rate: refill_rate_per_second as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
     /// Get current token count
     pub fn current_tokens(&mut self) -> f64 {
         self.refill();
@@ -98,13 +167,15 @@ impl TokenBucket {
 
 /// Rate limiter for tracking per-IP limits
 #[derive(Debug)]
-struct IpRateLimit {
+pub(super) struct IpRateLimit {
     connection_bucket: TokenBucket,
     auth_bucket: TokenBucket,
     last_activity: Instant,
     total_connections: u64,
     total_auth_attempts: u64,
     blocked_until: Option<Instant>,
+    /// Shared by all of the IP's UDP associations, created on its first datagram
+    udp: Option<UdpBuckets>,
 }
 
 impl IpRateLimit {
@@ -122,10 +193,11 @@ impl IpRateLimit {
             total_connections: 0,
             total_auth_attempts: 0,
             blocked_until: None,
+            udp: None,
         }
     }
 
-    fn is_blocked(&self) -> bool {
+    pub(super) fn is_blocked(&self) -> bool {
         if let Some(blocked_until) = self.blocked_until {
             Instant::now() < blocked_until
         } else {
@@ -133,6 +205,16 @@ impl IpRateLimit {
         }
     }
 
+    /// Whether to keep this state once the IP has gone idle
+    pub(super) fn should_retain(&self) -> bool {
+        self.is_blocked()
+    }
+
+    /// End of the current block, if one is in force
+    pub(super) fn blocked_until(&self) -> Option<Instant> {
+        self.blocked_until.filter(|&blocked_until| Instant::now() < blocked_until)
+    }
+
     fn block_for_duration(&mut self, duration: Duration) {
         self.blocked_until = Some(Instant::now() + duration);
     }
@@ -142,11 +224,114 @@ impl IpRateLimit {
     }
 }
 
+/// Packet and byte buckets for UDP datagrams
+#[derive(Debug, Clone)]
+struct UdpBuckets {
+    packets: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+}
+
+impl UdpBuckets {
+    fn new(packets_per_second: Option<u32>, bytes_per_second: Option<u32>) -> Self {
+        Self {
+            packets: packets_per_second.map(|rate| TokenBucket::per_second(rate, rate)),
+            // Room for at least one datagram of the largest size, or it could never pass
+            bytes: bytes_per_second.map(|rate| TokenBucket::per_second(rate.max(u16::MAX as u32), rate)),
+        }
+    }
+
+    fn is_unlimited(&self) -> bool {
+        self.packets.is_none() && self.bytes.is_none()
+    }
+
+    /// Whether both buckets hold enough for a datagram of `len` bytes
+    fn fits(&mut self, len: usize) -> bool {
+        let len = u32::try_from(len).unwrap_or(u32::MAX);
+        self.packets.as_mut().is_none_or(|bucket| bucket.current_tokens() >= 1.0)
+            && self.bytes.as_mut().is_none_or(|bucket| bucket.current_tokens() >= len as f64)
+    }
+
+    /// Take a datagram of `len` bytes from both buckets; call after `fits`
+    fn consume(&mut self, len: usize) {
+        if let Some(bucket) = &mut self.packets {
+            bucket.try_consume(1);
+        }
+        if let Some(bucket) = &mut self.bytes {
+            bucket.try_consume(u32::try_from(len).unwrap_or(u32::MAX));
+        }
+    }
+}
+
+/// UDP ceilings for one association, checked for every datagram its client sends
+#[derive(Debug)]
+pub struct UdpAssociationLimit {
+    ip: IpAddr,
+    buckets: UdpBuckets,
+    limiter: Arc<RateLimiter>,
+}
+
+impl UdpAssociationLimit {
+    /// Whether a client datagram of `len` bytes may be relayed. It is taken
+    /// from the association's and the IP's budgets only if both have room,
+    /// so a datagram refused by one limit does not use up the other.
+    pub fn allow(&mut self, len: usize) -> bool {
+        self.limiter.consume_udp(self.ip, len, &mut self.buckets)
+    }
+}
+
+/// Rate limiter for tracking per-user limits
+#[derive(Debug)]
+struct UserRateLimit {
+    connection_bucket: TokenBucket,
+    auth_bucket: TokenBucket,
+    last_activity: Instant,
+    total_connections: u64,
+    total_auth_attempts: u64,
+    connections_blocked: u64,
+    auth_attempts_blocked: u64,
+}
+
+impl UserRateLimit {
+    fn new(config: &UserRateLimitConfig) -> Self {
+        Self {
+            connection_bucket: TokenBucket::new(config.connections_burst, config.connections_per_minute),
+            auth_bucket: TokenBucket::new(config.auth_attempts_burst, config.auth_attempts_per_minute),
+            last_activity: Instant::now(),
+            total_connections: 0,
+            total_auth_attempts: 0,
+            connections_blocked: 0,
+            auth_attempts_blocked: 0,
+        }
+    }
+
+    /// Whether dropping this state would lose nothing but its totals
+    fn is_idle(&mut self, idle_timeout: Duration) -> bool {
+        self.last_activity.elapsed() >= idle_timeout
+            && self.connection_bucket.current_tokens() >= self.connection_bucket.capacity as f64
+            && self.auth_bucket.current_tokens() >= self.auth_bucket.capacity as f64
+    }
+
+    fn stats(&self, username: &str) -> UserStats {
+        UserStats {
+            username: username.to_string(),
+            total_connections: self.total_connections,
+            total_auth_attempts: self.total_auth_attempts,
+            connections_blocked: self.connections_blocked,
+            auth_attempts_blocked: self.auth_attempts_blocked,
+            connection_tokens_remaining: self.connection_bucket.clone().current_tokens(),
+            auth_tokens_remaining: self.auth_bucket.clone().current_tokens(),
+            last_activity: self.last_activity,
+        }
+    }
+}
+
 /// Main rate limiter implementation
+#[derive(Debug)]
 pub struct RateLimiter {
     config: RateLimitConfig,
-    ip_limits: Arc<Mutex<HashMap<IpAddr, IpRateLimit>>>,
+    ip_table: Arc<IpSecurityTable>,
     global_bucket: Arc<Mutex<TokenBucket>>,
+    users: Mutex<HashMap<String, UserRateLimit>>,
     stats: Arc<Mutex<InternalRateLimiterStats>>,
 }
 
@@ -156,7 +341,10 @@ struct InternalRateLimiterStats {
     total_connections_blocked: u64,
     total_auth_attempts_checked: u64,
     total_auth_attempts_blocked: u64,
-    currently_blocked_ips: usize,
+    user_connections_checked: u64,
+    user_connections_blocked: u64,
+    user_auth_attempts_checked: u64,
+    user_auth_attempts_blocked: u64,
 }
 
 impl RateLimiter {
@@ -167,14 +355,22 @@ impl RateLimiter {
             config.global_connections_per_second * 60, // Convert to per minute
         );
 
+        let idle_timeout = Duration::from_secs(config.cleanup_interval_seconds * 2);
         Self {
             config,
-            ip_limits: Arc::new(Mutex::new(HashMap::new())),
+            ip_table: Arc::new(IpSecurityTable::new(idle_timeout)),
             global_bucket: Arc::new(Mutex::new(global_bucket)),
+            users: Mutex::new(HashMap::new()),
             stats: Arc::new(Mutex::new(InternalRateLimiterStats::default())),
         }
     }
 
+    /// Keep per-IP state in a table shared with the other security modules
+    pub fn with_ip_table(mut self, ip_table: Arc<IpSecurityTable>) -> Self {
+        self.ip_table = ip_table;
+        self
+    }
+
     /// Check if a connection from the given IP should be allowed
     pub fn check_connection_rate(&self, ip: IpAddr) -> bool {
         if !self.config.enabled {
@@ -187,27 +383,23 @@ impl RateLimiter {
             stats.total_connections_checked += 1;
         }
 
-        // Check global rate limit first
-        {
-            let mut global_bucket = self.global_bucket.lock().unwrap();
-            if !global_bucket.try_consume(1) {
-                warn!("Global connection rate limit exceeded");
-                self.increment_blocked_connections();
-                return false;
-            }
-        }
-
-        // Check per-IP rate limit
-        let mut ip_limits = self.ip_limits.lock().unwrap();
-        let ip_limit = ip_limits.entry(ip).or_insert_with(|| IpRateLimit::new(&self.config));
+        let mut records = self.ip_table.lock();
+        let ip_limit = records.get_or_insert_with(ip, Default::default).rate.get_or_insert_with(|| IpRateLimit::new(&self.config));
 
-        // Check if IP is currently blocked
+        // Blocked IPs are turned away before they can drain the global bucket
         if ip_limit.is_blocked() {
             debug!("Connection from {} blocked due to temporary ban", ip);
             self.increment_blocked_connections();
             return false;
         }
 
+        // Check global rate limit
+        if !self.global_bucket.lock().unwrap().try_consume(1) {
+            warn!("Global connection rate limit exceeded");
+            self.increment_blocked_connections();
+            return false;
+        }
+
         // Try to consume connection token
         if ip_limit.connection_bucket.try_consume(1) {
             ip_limit.last_activity = Instant::now();
@@ -241,8 +433,8 @@ impl RateLimiter {
             stats.total_auth_attempts_checked += 1;
         }
 
-        let mut ip_limits = self.ip_limits.lock().unwrap();
-        let ip_limit = ip_limits.entry(ip).or_insert_with(|| IpRateLimit::new(&self.config));
+        let mut records = self.ip_table.lock();
+        let ip_limit = records.get_or_insert_with(ip, Default::default).rate.get_or_insert_with(|| IpRateLimit::new(&self.config));
 
         // Check if IP is currently blocked
         if ip_limit.is_blocked() {
@@ -272,19 +464,160 @@ impl RateLimiter {
         }
     }
 
+    /// Check if a connection by an authenticated user should be allowed
+    pub fn check_user_connection_rate(&self, username: &str) -> bool {
+        let Some(per_user) = self.config.per_user.as_ref().filter(|_| self.config.enabled) else {
+            return true;
+        };
+
+        let mut users = self.users.lock().unwrap();
+        let Some(user_limit) = self.user_limit(&mut users, username, per_user) else {
+            return true;
+        };
+        user_limit.last_activity = Instant::now();
+        let allowed = user_limit.connection_bucket.try_consume(1);
+        if allowed {
+            user_limit.total_connections += 1;
+        } else {
+            user_limit.connections_blocked += 1;
+            debug!("Connection rate limit exceeded for user '{}'", username);
+        }
+        drop(users);
+
+        let mut stats = self.stats.lock().unwrap();
+        stats.user_connections_checked += 1;
+        if !allowed {
+            stats.user_connections_blocked += 1;
+        }
+        allowed
+    }
+
+    /// Check if an authentication attempt naming the given user should be
+    /// allowed, i.e. the user has not failed too many logins lately
+    ///
+    /// Only failures use up the budget, see [`Self::record_user_auth_failure`],
+    /// so a user who logs in correctly is never locked out by their own logins.
+    pub fn check_user_auth_rate(&self, username: &str) -> bool {
+        if self.config.per_user.is_none() || !self.config.enabled {
+            return true;
+        }
+
+        let mut users = self.users.lock().unwrap();
+        let allowed = match users.get_mut(username) {
+            Some(user_limit) => {
+                let allowed = user_limit.auth_bucket.current_tokens() >= 1.0;
+                if !allowed {
+                    user_limit.auth_attempts_blocked += 1;
+                    debug!("Authentication rate limit exceeded for user '{}'", username);
+                }
+                allowed
+            }
+            None => true,
+        };
+        drop(users);
+
+        let mut stats = self.stats.lock().unwrap();
+        stats.user_auth_attempts_checked += 1;
+        if !allowed {
+            stats.user_auth_attempts_blocked += 1;
+        }
+        allowed
+    }
+
+    /// Charge a failed login against a configured user's budget
+    ///
+    /// Callers only pass users that exist, so guessed usernames cannot grow
+    /// the table; those are left to the per-IP limits.
+    pub fn record_user_auth_failure(&self, username: &str) {
+        let Some(per_user) = self.config.per_user.as_ref().filter(|_| self.config.enabled) else {
+            return;
+        };
+
+        let mut users = self.users.lock().unwrap();
+        if let Some(user_limit) = self.user_limit(&mut users, username, per_user) {
+            user_limit.last_activity = Instant::now();
+            user_limit.total_auth_attempts += 1;
+            user_limit.auth_bucket.try_consume(1);
+        }
+    }
+
+    /// State for `username`, created unless the table is full of active users
+    fn user_limit<'a>(
+        &self,
+        users: &'a mut HashMap<String, UserRateLimit>,
+        username: &str,
+        per_user: &UserRateLimitConfig,
+    ) -> Option<&'a mut UserRateLimit> {
+        if !users.contains_key(username) && users.len() >= per_user.max_tracked_users {
+            let idle_timeout = Duration::from_secs(self.config.cleanup_interval_seconds * 2);
+            users.retain(|_, user_limit| !user_limit.is_idle(idle_timeout));
+            if users.len() >= per_user.max_tracked_users {
+                debug!("Per-user rate limits track {} users already, not tracking '{}'", users.len(), username);
+                return None;
+            }
+        }
+        Some(users.entry(username.to_string()).or_insert_with(|| UserRateLimit::new(per_user)))
+    }
+
+    /// Ceilings for a new UDP association from `ip`, or `None` when UDP
+    /// traffic is not limited
+    pub fn udp_association_limit(self: &Arc<Self>, ip: IpAddr) -> Option<UdpAssociationLimit> {
+        let udp = self.config.udp.as_ref().filter(|_| self.config.enabled)?;
+        let buckets = UdpBuckets::new(udp.association_packets_per_second, udp.association_bytes_per_second);
+        let per_ip = udp.ip_packets_per_second.is_some() || udp.ip_bytes_per_second.is_some();
+        (per_ip || !buckets.is_unlimited()).then(|| UdpAssociationLimit {
+            ip,
+            buckets,
+            limiter: self.clone(),
+        })
+    }
+
+    /// Take a UDP datagram of `len` bytes from the IP's budget and an
+    /// association's, or from neither if either is short
+    fn consume_udp(&self, ip: IpAddr, len: usize, association: &mut UdpBuckets) -> bool {
+        let per_ip = self.config.udp.as_ref()
+            .filter(|udp| self.config.enabled && (udp.ip_packets_per_second.is_some() || udp.ip_bytes_per_second.is_some()));
+        let Some(udp) = per_ip else {
+            if !association.fits(len) {
+                return false;
+            }
+            association.consume(len);
+            return true;
+        };
+
+        let mut records = self.ip_table.lock();
+        let ip_limit = records.get_or_insert_with(ip, Default::default).rate.get_or_insert_with(|| IpRateLimit::new(&self.config));
+        ip_limit.last_activity = Instant::now();
+        let ip_buckets = ip_limit.udp
+            .get_or_insert_with(|| UdpBuckets::new(udp.ip_packets_per_second, udp.ip_bytes_per_second));
+        if !association.fits(len) || !ip_buckets.fits(len) {
+            return false;
+        }
+        association.consume(len);
+        ip_buckets.consume(len);
+        true
+    }
+
     /// Manually block an IP address
     pub fn block_ip(&self, ip: IpAddr, duration: Duration, reason: &str) {
-        let mut ip_limits = self.ip_limits.lock().unwrap();
-        let ip_limit = ip_limits.entry(ip).or_insert_with(|| IpRateLimit::new(&self.config));
+        let mut records = self.ip_table.lock();
+        let ip_limit = records.get_or_insert_with(ip, Default::default).rate.get_or_insert_with(|| IpRateLimit::new(&self.config));
         
         ip_limit.block_for_duration(duration);
         info!("Manually blocked IP {} for {:?}: {}", ip, duration, reason);
     }
 
+    /// Put back a block saved before a restart, without logging it as new
+    pub(super) fn restore_block(&self, ip: IpAddr, blocked_until: Instant) {
+        let mut records = self.ip_table.lock();
+        let ip_limit = records.get_or_insert_with(ip, Default::default).rate.get_or_insert_with(|| IpRateLimit::new(&self.config));
+        ip_limit.blocked_until = Some(blocked_until);
+    }
+
     /// Unblock an IP address
     pub fn unblock_ip(&self, ip: IpAddr) -> bool {
-        let mut ip_limits = self.ip_limits.lock().unwrap();
-        if let Some(ip_limit) = ip_limits.get_mut(&ip) {
+        let mut records = self.ip_table.lock();
+        if let Some(ip_limit) = records.get_mut(&ip).and_then(|record| record.rate.as_mut()) {
             if ip_limit.is_blocked() {
                 ip_limit.unblock();
                 info!("Unblocked IP {}", ip);
@@ -296,8 +629,8 @@ impl RateLimiter {
 
     /// Check if an IP is currently blocked
     pub fn is_ip_blocked(&self, ip: IpAddr) -> bool {
-        let ip_limits = self.ip_limits.lock().unwrap();
-        if let Some(ip_limit) = ip_limits.get(&ip) {
+        let records = self.ip_table.lock();
+        if let Some(ip_limit) = records.get(&ip).and_then(|record| record.rate.as_ref()) {
             ip_limit.is_blocked()
         } else {
             false
@@ -306,55 +639,57 @@ impl RateLimiter {
 
     /// Get list of currently blocked IPs
     pub fn get_blocked_ips(&self) -> Vec<IpAddr> {
-        let ip_limits = self.ip_limits.lock().unwrap();
-        ip_limits.iter()
-            .filter(|(_, limit)| limit.is_blocked())
+        let records = self.ip_table.lock();
+        records.iter()
+            .filter(|(_, record)| record.is_rate_limited())
             .map(|(ip, _)| *ip)
             .collect()
     }
 
-    /// Clean up old rate limit entries
+    /// Clean up IPs that have gone idle
+    ///
+    /// Only IPs whose idle timeout has passed are visited, so this is cheap
+    /// however many IPs are tracked.
     pub fn cleanup_old_entries(&self) {
-        let cleanup_threshold = Duration::from_secs(self.config.cleanup_interval_seconds * 2);
-        let cutoff_time = Instant::now() - cleanup_threshold;
-        
-        let mut ip_limits = self.ip_limits.lock().unwrap();
-        let initial_count = ip_limits.len();
-        
-        ip_limits.retain(|_, limit| {
-            // Keep if recently active or currently blocked
-            limit.last_activity > cutoff_time || limit.is_blocked()
-        });
-        
-        let removed_count = initial_count - ip_limits.len();
+        let removed_count = self.ip_table.expire();
         if removed_count > 0 {
-            debug!("Cleaned up {} old rate limit entries", removed_count);
+            debug!("Cleaned up {} idle IP entries", removed_count);
         }
 
-        // Update blocked IP count in stats
-        let blocked_count = ip_limits.iter().filter(|(_, limit)| limit.is_blocked()).count();
-        {
-            let mut stats = self.stats.lock().unwrap();
-            stats.currently_blocked_ips = blocked_count;
+        let idle_timeout = Duration::from_secs(self.config.cleanup_interval_seconds * 2);
+        let mut users = self.users.lock().unwrap();
+        let before = users.len();
+        users.retain(|_, user_limit| !user_limit.is_idle(idle_timeout));
+        if users.len() != before {
+            debug!("Cleaned up {} idle user entries", before - users.len());
         }
     }
 
     /// Get rate limiter statistics
     pub fn get_stats(&self) -> RateLimiterStats {
+        let currently_blocked_ips = self.ip_table.lock().values().filter(|record| record.is_rate_limited()).count();
+        let tracked_users = self.users.lock().unwrap().len();
         let stats = self.stats.lock().unwrap();
         RateLimiterStats {
             total_connections_checked: stats.total_connections_checked,
             total_connections_blocked: stats.total_connections_blocked,
             total_auth_attempts_checked: stats.total_auth_attempts_checked,
             total_auth_attempts_blocked: stats.total_auth_attempts_blocked,
-            currently_blocked_ips: stats.currently_blocked_ips,
+            currently_blocked_ips,
+            users: UserRateLimiterStats {
+                total_connections_checked: stats.user_connections_checked,
+                total_connections_blocked: stats.user_connections_blocked,
+                total_auth_attempts_checked: stats.user_auth_attempts_checked,
+                total_auth_attempts_blocked: stats.user_auth_attempts_blocked,
+                tracked_users,
+            },
         }
     }
 
     /// Get detailed IP statistics
     pub fn get_ip_stats(&self, ip: IpAddr) -> Option<IpStats> {
-        let ip_limits = self.ip_limits.lock().unwrap();
-        ip_limits.get(&ip).map(|limit| IpStats {
+        let records = self.ip_table.lock();
+        records.get(&ip).and_then(|record| record.rate.as_ref()).map(|limit| IpStats {
             ip,
             total_connections: limit.total_connections,
             total_auth_attempts: limit.total_auth_attempts,
@@ -368,8 +703,8 @@ impl RateLimiter {
 
     /// Get all IP statistics
     pub fn get_all_ip_stats(&self) -> Vec<IpStats> {
-        let ip_limits = self.ip_limits.lock().unwrap();
-        ip_limits.iter().map(|(ip, limit)| IpStats {
+        let records = self.ip_table.lock();
+        records.iter().filter_map(|(ip, record)| record.rate.as_ref().map(|limit| (ip, limit))).map(|(ip, limit)| IpStats {
             ip: *ip,
             total_connections: limit.total_connections,
             total_auth_attempts: limit.total_auth_attempts,
@@ -381,6 +716,16 @@ impl RateLimiter {
         }).collect()
     }
 
+    /// Get statistics for a specific user
+    pub fn get_user_stats(&self, username: &str) -> Option<UserStats> {
+        self.users.lock().unwrap().get(username).map(|user_limit| user_limit.stats(username))
+    }
+
+    /// Get statistics for every user being tracked
+    pub fn get_all_user_stats(&self) -> Vec<UserStats> {
+        self.users.lock().unwrap().iter().map(|(username, user_limit)| user_limit.stats(username)).collect()
+    }
+
     fn increment_blocked_connections(&self) {
         let mut stats = self.stats.lock().unwrap();
         stats.total_connections_blocked += 1;
@@ -405,6 +750,19 @@ pub struct IpStats {
     pub last_activity: Instant,
 }
 
+/// Statistics for a specific user
+#[derive(Debug, Clone)]
+pub struct UserStats {
+    pub username: String,
+    pub total_connections: u64,
+    pub total_auth_attempts: u64,
+    pub connections_blocked: u64,
+    pub auth_attempts_blocked: u64,
+    pub connection_tokens_remaining: f64,
+    pub auth_tokens_remaining: f64,
+    pub last_activity: Instant,
+}
+
 /// Rate limiter statistics
 #[derive(Debug, Clone)]
 pub struct RateLimiterStats {
@@ -413,6 +771,18 @@ pub struct RateLimiterStats {
     pub total_auth_attempts_checked: u64,
     pub total_auth_attempts_blocked: u64,
     pub currently_blocked_ips: usize,
+    /// Checks made against the per-user limits, counted apart from the per-IP ones
+    pub users: UserRateLimiterStats,
+}
+
+/// Per-user rate limiter statistics
+#[derive(Debug, Clone, Default)]
+pub struct UserRateLimiterStats {
+    pub total_connections_checked: u64,
+    pub total_connections_blocked: u64,
+    pub total_auth_attempts_checked: u64,
+    pub total_auth_attempts_blocked: u64,
+    pub tracked_users: usize,
 }
 
 #[cfg(test)]
@@ -500,4 +870,113 @@ mod tests {
         assert!(!limiter.is_ip_blocked(ip));
         assert!(limiter.check_connection_rate(ip));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_user_limits_are_separate_from_ip_limits() {
+        let config = RateLimitConfig {
+            per_user: Some(UserRateLimitConfig {
+                connections_burst: 2,
+                auth_attempts_burst: 1,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let limiter = RateLimiter::new(config);
+
+        assert!(limiter.check_user_connection_rate("alice"));
+        assert!(limiter.check_user_connection_rate("alice"));
+        assert!(!limiter.check_user_connection_rate("alice"));
+        assert!(limiter.check_user_connection_rate("bob"));
+        assert!(limiter.check_user_auth_rate("alice"));
+        limiter.record_user_auth_failure("alice");
+        assert!(!limiter.check_user_auth_rate("alice"));
+
+        // Users over their limit leave the address they share unblocked
+        let ip = "127.0.0.1".parse().unwrap();
+        assert!(limiter.check_connection_rate(ip));
+
+        let stats = limiter.get_stats();
+        assert_eq!(stats.users.total_connections_checked, 4);
+        assert_eq!(stats.users.total_connections_blocked, 1);
+        assert_eq!(stats.users.total_auth_attempts_blocked, 1);
+        assert_eq!(stats.users.tracked_users, 2);
+        assert_eq!(stats.total_connections_checked, 1);
+        assert_eq!(stats.currently_blocked_ips, 0);
+        let alice = limiter.get_user_stats("alice").unwrap();
+        assert_eq!((alice.total_connections, alice.connections_blocked), (2, 1));
+
+        // Without per-user limits nothing is tracked
+        let limiter = RateLimiter::new(RateLimitConfig::default());
+        for _ in 0..100 {
+            assert!(limiter.check_user_connection_rate("alice"));
+        }
+        assert!(limiter.get_all_user_stats().is_empty());
+    }
+
+    #[test]
+    fn test_only_failed_logins_count_against_users() {
+        let config = RateLimitConfig {
+            per_user: Some(UserRateLimitConfig {
+                auth_attempts_burst: 2,
+                max_tracked_users: 2,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let limiter = RateLimiter::new(config);
+
+        // Successful logins are checked but never charged or tracked
+        for _ in 0..10 {
+            assert!(limiter.check_user_auth_rate("alice"));
+        }
+        assert!(limiter.get_user_stats("alice").is_none());
+
+        limiter.record_user_auth_failure("alice");
+        assert!(limiter.check_user_auth_rate("alice"));
+        limiter.record_user_auth_failure("alice");
+        assert!(!limiter.check_user_auth_rate("alice"));
+
+        // The table stops growing at its cap while its users are active
+        limiter.record_user_auth_failure("bob");
+        limiter.record_user_auth_failure("carol");
+        assert!(limiter.get_user_stats("carol").is_none());
+        assert!(limiter.check_user_connection_rate("carol"));
+        assert_eq!(limiter.get_stats().users.tracked_users, 2);
+    }
+
+    #[test]
+    fn test_udp_ceilings_per_association_and_ip() {
+        let config = RateLimitConfig {
+            udp: Some(UdpRateLimitConfig {
+                association_packets_per_second: Some(3),
+                ip_bytes_per_second: Some(100_000),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let limiter = Arc::new(RateLimiter::new(config));
+        let ip = "192.0.2.7".parse().unwrap();
+
+        let mut first = limiter.udp_association_limit(ip).unwrap();
+        assert!((0..3).all(|_| first.allow(100)));
+        assert!(!first.allow(100));
+
+        // A second association has its own packet budget but shares the IP's bytes
+        let mut second = limiter.udp_association_limit(ip).unwrap();
+        assert!(second.allow(65_000));
+        assert!(!second.allow(65_000));
+        assert!(second.allow(1_000));
+
+        // A datagram the IP budget refuses leaves the association's packets alone
+        assert!(second.allow(1_000));
+        assert!(!second.allow(65_000));
+        assert!(!second.allow(1_000));
+        let mut third = limiter.udp_association_limit(ip).unwrap();
+        assert!(!third.allow(65_000));
+        assert_eq!(third.buckets.packets.as_mut().unwrap().current_tokens().floor(), 3.0);
+
+        // Without UDP ceilings associations are not limited at all
+        let limiter = Arc::new(RateLimiter::new(RateLimitConfig::default()));
+        assert!(limiter.udp_association_limit(ip).is_none());
+    }
+}
@@ -0,0 +1,93 @@
+//! Block Reason Taxonomy
+//!
+//! Enumerated reason codes attached to every ban or block decision so that
+//! logs, metrics labels, and API responses can be parsed by automation.
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Machine-readable reason code for a rejected or banned connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum BlockReason {
+    /// Connection or authentication rate limit exceeded
+    RateLimit,
+    /// DDoS protection triggered
+    Ddos,
+    /// Brute force protection (fail2ban) triggered
+    BruteForce,
+    /// Denied by an access control or routing rule
+    Acl,
+    /// Denied by a GeoIP country restriction
+    Geo,
+    /// User or connection quota exhausted
+    Quota,
+    /// Target leads back into the proxy
+    Loop,
+    /// Target is a private or internal address no rule allows reaching
+    PrivateRange,
+    /// Source is listed in an IP reputation feed
+    Reputation,
+}
+
+impl BlockReason {
+    /// All reason codes, in a stable order
+    pub const ALL: [BlockReason; 9] = [
+        BlockReason::RateLimit,
+        BlockReason::Ddos,
+        BlockReason::BruteForce,
+        BlockReason::Acl,
+        BlockReason::Geo,
+        BlockReason::Quota,
+        BlockReason::Loop,
+        BlockReason::PrivateRange,
+        BlockReason::Reputation,
+    ];
+
+    /// Get the stable string code used in logs, metrics labels, and API responses
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BlockReason::RateLimit => "RATE_LIMIT",
+            BlockReason::Ddos => "DDOS",
+            BlockReason::BruteForce => "BRUTE_FORCE",
+            BlockReason::Acl => "ACL",
+            BlockReason::Geo => "GEO",
+            BlockReason::Quota => "QUOTA",
+            BlockReason::Loop => "LOOP",
+            BlockReason::PrivateRange => "PRIVATE_RANGE",
+            BlockReason::Reputation => "REPUTATION",
+        }
+    }
+
+    /// Parse a reason code from its string form
+    pub fn from_code(code: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|reason| reason.as_str() == code)
+    }
+}
+
+impl fmt::Display for BlockReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_codes_round_trip() {
+        for reason in BlockReason::ALL {
+            assert_eq!(BlockReason::from_code(reason.as_str()), Some(reason));
+        }
+        assert_eq!(BlockReason::from_code("UNKNOWN"), None);
+    }
+
+    #[test]
+    fn test_serde_matches_display() {
+        for reason in BlockReason::ALL {
+            let json = serde_json::to_string(&reason).unwrap();
+            assert_eq!(json, format!("\"{}\"", reason));
+        }
+    }
+}
@@ -0,0 +1,192 @@
+//! Rejection Behavior
+//!
+//! How a connection refused by the accept-time checks is turned away. Each
+//! choice looks different to the client: a plain close (`drop`) reads as a
+//! proxy that hung up, a TCP reset (`reset`) as a closed or filtered port, and
+//! a SOCKS reply (`reply`) as a working proxy that refuses the request, which
+//! well-behaved clients report to their user instead of retrying. The choice
+//! can be made per block reason, so scanners can be given nothing while
+//! rate-limited users get a clear answer.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpStream;
+use tokio::sync::Semaphore;
+use tracing::debug;
+
+use super::BlockReason;
+use crate::protocol::constants::SOCKS5_REPLY_CONNECTION_NOT_ALLOWED;
+use crate::protocol::{AuthMethod, Socks5Handler, Socks5Response};
+use crate::Result;
+
+/// How a refused connection is closed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RejectAction {
+    /// Close the socket without a word
+    #[default]
+    Drop,
+    /// Abort the connection with a TCP reset
+    Reset,
+    /// Complete the SOCKS handshake and answer the request with "connection not allowed"
+    Reply,
+}
+
+/// Rejection behavior configuration
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RejectionConfig {
+    /// Action for block reasons not listed in `by_reason`
+    #[serde(default)]
+    pub default: RejectAction,
+    /// Action per block reason, such as `RATE_LIMIT = "reply"`
+    #[serde(default)]
+    pub by_reason: HashMap<BlockReason, RejectAction>,
+    /// Longest a `reply` rejection waits for the client's handshake
+    #[serde(default = "default_reply_timeout", with = "humantime_serde")]
+    pub reply_timeout: Duration,
+    /// `reply` rejections in progress at once; beyond this, connections are dropped
+    #[serde(default = "default_max_replies")]
+    pub max_replies: usize,
+}
+
+fn default_reply_timeout() -> Duration {
+    Duration::from_secs(5)
+}
+
+fn default_max_replies() -> usize {
+    256
+}
+
+impl Default for RejectionConfig {
+    fn default() -> Self {
+        Self {
+            default: RejectAction::Drop,
+            by_reason: HashMap::new(),
+            reply_timeout: default_reply_timeout(),
+            max_replies: default_max_replies(),
+        }
+    }
+}
+
+impl RejectionConfig {
+    /// Action for connections refused for `reason`
+    pub fn action(&self, reason: BlockReason) -> RejectAction {
+        self.by_reason.get(&reason).copied().unwrap_or(self.default)
+    }
+}
+
+/// Turns refused connections away as configured
+pub struct Rejector {
+    config: RejectionConfig,
+    replies: Arc<Semaphore>,
+}
+
+impl Rejector {
+    pub fn new(config: RejectionConfig) -> Self {
+        let replies = Arc::new(Semaphore::new(config.max_replies));
+        Self { config, replies }
+    }
+
+    /// Close a connection refused for `reason`; `reply` rejections finish in the background
+    pub fn reject(&self, stream: TcpStream, reason: BlockReason) {
+        match self.config.action(reason) {
+            RejectAction::Drop => drop(stream),
+            RejectAction::Reset => reset(stream),
+            RejectAction::Reply => {
+                // A flood of refused clients must not become a flood of tasks
+                let Ok(permit) = self.replies.clone().try_acquire_owned() else {
+                    return drop(stream);
+                };
+                let timeout = self.config.reply_timeout;
+                tokio::spawn(async move {
+                    let _permit = permit;
+                    match tokio::time::timeout(timeout, refuse_request(stream)).await {
+                        Ok(Ok(())) => {}
+                        Ok(Err(e)) => debug!("Refusal reply not delivered: {}", e),
+                        Err(_) => debug!("Refusal reply timed out after {:?}", timeout),
+                    }
+                });
+            }
+        }
+    }
+}
+
+/// Close with a reset rather than the usual FIN
+fn reset(stream: TcpStream) {
+    if let Err(e) = socket2::SockRef::from(&stream).set_linger(Some(Duration::ZERO)) {
+        debug!("Failed to arm TCP reset: {}", e);
+    }
+}
+
+/// Complete the handshake and refuse whatever the client asks for. Credentials
+/// are read but not checked, since nothing is granted either way.
+async fn refuse_request(stream: TcpStream) -> Result<()> {
+    let mut handler = Socks5Handler::new(stream);
+    match handler.handle_handshake().await? {
+        AuthMethod::UserPass => {
+            handler.handle_userpass_auth().await?;
+            handler.send_userpass_auth_response(true).await?;
+        }
+        AuthMethod::NoAuth => {}
+        AuthMethod::Unsupported => return Ok(()),
+    }
+    handler.handle_request().await?;
+    handler.send_response(Socks5Response::error(SOCKS5_REPLY_CONNECTION_NOT_ALLOWED)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    async fn rejected(action: RejectAction) -> TcpStream {
+        let rejector = Rejector::new(RejectionConfig {
+            by_reason: HashMap::from([(BlockReason::RateLimit, action)]),
+            ..Default::default()
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        rejector.reject(stream, BlockReason::RateLimit);
+        client
+    }
+
+    #[test]
+    fn test_action_per_reason() {
+        let config: RejectionConfig = toml::from_str(r#"
+            default = "reset"
+            by_reason = { RATE_LIMIT = "reply" }
+        "#).unwrap();
+        assert_eq!(config.action(BlockReason::RateLimit), RejectAction::Reply);
+        assert_eq!(config.action(BlockReason::Ddos), RejectAction::Reset);
+        assert_eq!(RejectionConfig::default().action(BlockReason::Geo), RejectAction::Drop);
+    }
+
+    #[tokio::test]
+    async fn test_reply_refuses_request() {
+        let mut client = rejected(RejectAction::Reply).await;
+        client.write_all(&[5, 1, 0]).await.unwrap();
+        let mut method = [0u8; 2];
+        client.read_exact(&mut method).await.unwrap();
+        assert_eq!(method, [5, 0]);
+        client.write_all(&[5, 1, 0, 1, 192, 0, 2, 1, 0, 80]).await.unwrap();
+        let mut reply = [0u8; 10];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[1], SOCKS5_REPLY_CONNECTION_NOT_ALLOWED);
+    }
+
+    #[tokio::test]
+    async fn test_reset_and_drop() {
+        let mut buf = [0u8; 1];
+        let mut client = rejected(RejectAction::Reset).await;
+        let err = client.read(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::ConnectionReset);
+
+        let mut client = rejected(RejectAction::Drop).await;
+        assert_eq!(client.read(&mut buf).await.unwrap(), 0);
+    }
+}
@@ -0,0 +1,547 @@
+//! IP Reputation Feeds
+//!
+//! Downloads published lists of known-bad networks (Spamhaus DROP, FireHOL,
+//! abuse.ch, or any URL serving the same kind of list) on a schedule and
+//! refuses connections from listed sources at accept time, before any other
+//! check spends work on them. A feed that fails to download keeps its last
+//! good list, and with `cache_dir` set the lists survive a restart, so the
+//! proxy never drops back to blocking nothing because a feed is unreachable.
+//!
+//! Feeds are plain text with one address or CIDR per line. Anything after
+//! the first whitespace, `#` or `;` is ignored, which covers the comment
+//! styles the common lists use:
+//!
+//! ```text
+//! # FireHOL / abuse.ch style comment
+//! 192.0.2.0/24 ; SBL123456
+//! 198.51.100.7
+//! ```
+
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{anyhow, bail, Context};
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+
+use crate::metrics::Metrics;
+use crate::Result;
+
+/// Largest feed body accepted, far above any of the public lists
+const MAX_FEED_BYTES: usize = 32 * 1024 * 1024;
+
+/// Reputation feed configuration
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ReputationConfig {
+    /// Block sources listed in the configured feeds
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default)]
+    pub feeds: Vec<FeedConfig>,
+    /// How often every feed is downloaded again
+    #[serde(default = "default_refresh_interval", with = "humantime_serde")]
+    pub refresh_interval: Duration,
+    /// Time allowed for one download
+    #[serde(default = "default_timeout", with = "humantime_serde")]
+    pub timeout: Duration,
+    /// Where the last good copy of each feed is kept, so lists apply from
+    /// startup instead of after the first download
+    #[serde(default)]
+    pub cache_dir: Option<PathBuf>,
+    /// Never block clients on private, loopback or link-local addresses,
+    /// which some feeds list as bogons
+    #[serde(default = "default_exempt_private")]
+    pub exempt_private: bool,
+    /// Sources that are never blocked, whatever the feeds say
+    #[serde(default)]
+    pub exempt_cidrs: Vec<String>,
+}
+
+fn default_refresh_interval() -> Duration {
+    Duration::from_secs(3600)
+}
+
+fn default_timeout() -> Duration {
+    Duration::from_secs(30)
+}
+
+fn default_exempt_private() -> bool {
+    true
+}
+
+impl Default for ReputationConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            feeds: Vec::new(),
+            refresh_interval: default_refresh_interval(),
+            timeout: default_timeout(),
+            cache_dir: None,
+            exempt_private: default_exempt_private(),
+            exempt_cidrs: Vec::new(),
+        }
+    }
+}
+
+/// One feed: a well-known list by preset, or any URL
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct FeedConfig {
+    /// Label used in logs, metrics, and the cache file name; defaults to the preset name
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub preset: Option<FeedPreset>,
+    #[serde(default)]
+    pub url: Option<String>,
+}
+
+/// Well-known public feeds
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FeedPreset {
+    /// Spamhaus Don't Route Or Peer list (IPv4)
+    SpamhausDrop,
+    /// Spamhaus Don't Route Or Peer list (IPv6)
+    SpamhausDropv6,
+    /// FireHOL level 1: attacks, malware, and bogons, safe for most servers
+    FireholLevel1,
+    /// abuse.ch Feodo Tracker botnet command-and-control servers
+    AbuseChFeodo,
+}
+
+impl FeedPreset {
+    fn name(self) -> &'static str {
+        match self {
+            FeedPreset::SpamhausDrop => "spamhaus_drop",
+            FeedPreset::SpamhausDropv6 => "spamhaus_dropv6",
+            FeedPreset::FireholLevel1 => "firehol_level1",
+            FeedPreset::AbuseChFeodo => "abuse_ch_feodo",
+        }
+    }
+
+    fn url(self) -> &'static str {
+        match self {
+            FeedPreset::SpamhausDrop => "https://www.spamhaus.org/drop/drop.txt",
+            FeedPreset::SpamhausDropv6 => "https://www.spamhaus.org/drop/dropv6.txt",
+            FeedPreset::FireholLevel1 => "https://iplists.firehol.org/files/firehol_level1.netset",
+            FeedPreset::AbuseChFeodo => "https://feodotracker.abuse.ch/downloads/ipblocklist.txt",
+        }
+    }
+}
+
+impl FeedConfig {
+    /// Name and URL of the feed, or why the entry is unusable
+    pub fn source(&self) -> Result<(String, String)> {
+        let (name, url) = match (&self.preset, &self.url) {
+            (Some(preset), None) => (self.name.clone().unwrap_or_else(|| preset.name().to_string()), preset.url().to_string()),
+            (None, Some(url)) => {
+                let name = self.name.clone().ok_or_else(|| anyhow!("Feed {} needs a name", url))?;
+                (name, url.clone())
+            }
+            (Some(_), Some(_)) => bail!("Feed sets both preset and url"),
+            (None, None) => bail!("Feed needs a preset or a url"),
+        };
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+            bail!("Feed name '{}' may only contain letters, digits, '_' and '-'", name);
+        }
+        if !url.starts_with("http://") && !url.starts_with("https://") {
+            bail!("Feed '{}' url must be an http:// or https:// URL", name);
+        }
+        Ok((name, url))
+    }
+}
+
+/// Sorted, merged address ranges of one feed
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+struct RangeSet {
+    v4: Vec<(u32, u32)>,
+    v6: Vec<(u128, u128)>,
+}
+
+impl RangeSet {
+    fn from_networks(networks: impl IntoIterator<Item = IpNet>) -> Self {
+        let mut set = Self::default();
+        for network in networks {
+            match network {
+                IpNet::V4(net) => set.v4.push((net.network().into(), net.broadcast().into())),
+                IpNet::V6(net) => set.v6.push((net.network().into(), net.broadcast().into())),
+            }
+        }
+        merge(&mut set.v4);
+        merge(&mut set.v6);
+        set
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        match ip {
+            IpAddr::V4(ip) => covers(&self.v4, u32::from(ip)),
+            IpAddr::V6(ip) => covers(&self.v6, u128::from(ip)),
+        }
+    }
+}
+
+/// Sort ranges and join overlapping or adjacent ones
+fn merge<T: Ord + Copy + Into<u128>>(ranges: &mut Vec<(T, T)>) {
+    ranges.sort_unstable();
+    let mut merged: Vec<(T, T)> = Vec::with_capacity(ranges.len());
+    for &(start, end) in ranges.iter() {
+        match merged.last_mut() {
+            Some(last) if start.into() <= last.1.into().saturating_add(1) => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    *ranges = merged;
+}
+
+fn covers<T: Ord + Copy>(ranges: &[(T, T)], value: T) -> bool {
+    let index = ranges.partition_point(|&(start, _)| start <= value);
+    index > 0 && value <= ranges[index - 1].1
+}
+
+/// Parse a feed body, returning its networks and how many lines were not understood
+pub fn parse_feed(body: &str) -> (Vec<IpNet>, usize) {
+    let mut networks = Vec::new();
+    let mut skipped = 0;
+    for line in body.lines() {
+        let entry = line.split(['#', ';']).next().unwrap_or_default();
+        let Some(entry) = entry.split_whitespace().next() else {
+            continue;
+        };
+        match crate::config::parse_source_cidr(entry) {
+            Ok(network) => networks.push(network.trunc()),
+            Err(_) => skipped += 1,
+        }
+    }
+    (networks, skipped)
+}
+
+struct Feed {
+    name: String,
+    url: String,
+    ranges: RwLock<RangeSet>,
+    entries: AtomicU64,
+    hits: AtomicU64,
+    status: RwLock<FeedUpdate>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct FeedUpdate {
+    updated_at: Option<SystemTime>,
+    error: Option<String>,
+}
+
+/// State of one feed, for logs and the API
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FeedStatus {
+    pub name: String,
+    pub url: String,
+    /// Networks on the list in use
+    pub entries: u64,
+    /// Connections refused because of this feed
+    pub hits: u64,
+    /// When the list in use was downloaded
+    pub updated_at: Option<SystemTime>,
+    /// Why the last download failed, if it did
+    pub error: Option<String>,
+}
+
+/// Blocks sources listed in the configured reputation feeds
+pub struct ReputationFilter {
+    config: ReputationConfig,
+    feeds: Vec<Feed>,
+    exempt: RangeSet,
+    client: reqwest::Client,
+    metrics: Option<Arc<Metrics>>,
+}
+
+impl ReputationFilter {
+    /// Create a filter for the configured feeds, loading cached lists if there are any
+    pub fn new(config: ReputationConfig) -> Result<Self> {
+        let mut feeds = Vec::with_capacity(config.feeds.len());
+        for feed in &config.feeds {
+            let (name, url) = feed.source()?;
+            feeds.push(Feed {
+                name,
+                url,
+                ranges: RwLock::new(RangeSet::default()),
+                entries: AtomicU64::new(0),
+                hits: AtomicU64::new(0),
+                status: RwLock::new(FeedUpdate::default()),
+            });
+        }
+        let exempt = config.exempt_cidrs.iter()
+            .map(|cidr| crate::config::parse_source_cidr(cidr))
+            .collect::<Result<Vec<_>>>()?;
+        let client = reqwest::Client::builder()
+            .timeout(config.timeout)
+            .user_agent(format!("rustproxy/{}", env!("CARGO_PKG_VERSION")))
+            .build()
+            .context("Failed to build reputation feed HTTP client")?;
+        let filter = Self {
+            exempt: RangeSet::from_networks(exempt),
+            config,
+            feeds,
+            client,
+            metrics: None,
+        };
+        filter.load_cached();
+        Ok(filter)
+    }
+
+    /// Report feed sizes and hits to the shared metrics collector
+    pub fn with_metrics(mut self, metrics: Option<Arc<Metrics>>) -> Self {
+        self.metrics = metrics;
+        if let Some(metrics) = &self.metrics {
+            for feed in &self.feeds {
+                metrics.set_reputation_feed_entries(&feed.name, feed.entries.load(Ordering::Relaxed));
+            }
+        }
+        self
+    }
+
+    /// Name of the first feed listing `ip`, counting the hit
+    pub fn check(&self, ip: IpAddr) -> Option<&str> {
+        let ip = ip.to_canonical();
+        if self.exempt.contains(ip) || (self.config.exempt_private && super::private_ranges::private_range(ip).is_some()) {
+            return None;
+        }
+        let feed = self.feeds.iter().find(|feed| feed.ranges.read().unwrap().contains(ip))?;
+        feed.hits.fetch_add(1, Ordering::Relaxed);
+        if let Some(metrics) = &self.metrics {
+            metrics.record_reputation_hit(&feed.name);
+        }
+        Some(&feed.name)
+    }
+
+    /// State of every feed, in configuration order
+    pub fn feeds(&self) -> Vec<FeedStatus> {
+        self.feeds.iter()
+            .map(|feed| {
+                let status = feed.status.read().unwrap().clone();
+                FeedStatus {
+                    name: feed.name.clone(),
+                    url: feed.url.clone(),
+                    entries: feed.entries.load(Ordering::Relaxed),
+                    hits: feed.hits.load(Ordering::Relaxed),
+                    updated_at: status.updated_at,
+                    error: status.error,
+                }
+            })
+            .collect()
+    }
+
+    /// Download every feed now and every `refresh_interval` after
+    pub fn spawn(self: &Arc<Self>) -> JoinHandle<()> {
+        let filter = Arc::clone(self);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(filter.config.refresh_interval);
+            loop {
+                interval.tick().await;
+                filter.refresh().await;
+            }
+        })
+    }
+
+    /// Download every feed once, keeping the previous list of any that fail
+    pub async fn refresh(&self) {
+        for feed in &self.feeds {
+            let installed = match self.fetch(&feed.url).await {
+                Ok(body) => self.install(feed, &body).map(|count| (count, body)),
+                Err(e) => Err(e),
+            };
+            match installed {
+                Ok((count, body)) => {
+                    feed.status.write().unwrap().updated_at = Some(SystemTime::now());
+                    info!("Reputation feed '{}' lists {} networks", feed.name, count);
+                    if let Some(dir) = &self.config.cache_dir {
+                        let path = dir.join(format!("{}.txt", feed.name));
+                        let written = crate::blocking::pool()
+                            .run(move || super::quota::write_atomically(&path, &body))
+                            .await
+                            .and_then(|written| written);
+                        if let Err(e) = written {
+                            warn!("Failed to cache reputation feed '{}': {:#}", feed.name, e);
+                        }
+                    }
+                }
+                Err(e) => {
+                    warn!("Reputation feed '{}' not updated: {:#}", feed.name, e);
+                    feed.status.write().unwrap().error = Some(format!("{:#}", e));
+                }
+            }
+        }
+    }
+
+    async fn fetch(&self, url: &str) -> Result<String> {
+        let response = self.client.get(url).send().await.context("Cannot reach feed")?;
+        if !response.status().is_success() {
+            bail!("Feed returned {}", response.status());
+        }
+        if response.content_length().is_some_and(|length| length > MAX_FEED_BYTES as u64) {
+            bail!("Feed is larger than {} bytes", MAX_FEED_BYTES);
+        }
+        let body = response.bytes().await.context("Failed to read feed")?;
+        if body.len() > MAX_FEED_BYTES {
+            bail!("Feed is larger than {} bytes", MAX_FEED_BYTES);
+        }
+        String::from_utf8(body.to_vec()).context("Feed is not UTF-8 text")
+    }
+
+    /// Replace a feed's list with the one in `body`, returning its size. A
+    /// body with no networks, or with more unparsable lines than networks,
+    /// is taken for an error page or a broken download and leaves the list alone.
+    fn install(&self, feed: &Feed, body: &str) -> Result<u64> {
+        let (networks, skipped) = parse_feed(body);
+        if networks.is_empty() {
+            bail!("Feed lists no networks");
+        }
+        if skipped > networks.len() {
+            bail!("Feed has {} unparsable lines and only {} networks", skipped, networks.len());
+        }
+        if skipped > 0 {
+            debug!("Reputation feed '{}': skipped {} unparsable lines", feed.name, skipped);
+        }
+        let count = networks.len() as u64;
+        *feed.ranges.write().unwrap() = RangeSet::from_networks(networks);
+        feed.entries.store(count, Ordering::Relaxed);
+        feed.status.write().unwrap().error = None;
+        if let Some(metrics) = &self.metrics {
+            metrics.set_reputation_feed_entries(&feed.name, count);
+        }
+        Ok(count)
+    }
+
+    fn load_cached(&self) {
+        let Some(dir) = &self.config.cache_dir else {
+            return;
+        };
+        for feed in &self.feeds {
+            let path = dir.join(format!("{}.txt", feed.name));
+            let Ok(body) = std::fs::read_to_string(&path) else {
+                continue;
+            };
+            let count = match self.install(feed, &body) {
+                Ok(count) => count,
+                Err(e) => {
+                    warn!("Ignoring cached reputation feed {}: {:#}", path.display(), e);
+                    continue;
+                }
+            };
+            feed.status.write().unwrap().updated_at = std::fs::metadata(&path).and_then(|meta| meta.modified()).ok();
+            info!("Loaded {} networks for reputation feed '{}' from {}", count, feed.name, path.display());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    fn url_feed(name: &str, url: &str) -> FeedConfig {
+        FeedConfig { name: Some(name.to_string()), preset: None, url: Some(url.to_string()) }
+    }
+
+    #[test]
+    fn test_common_feed_formats() {
+        let spamhaus = "; Spamhaus DROP List 2025/06/01\n192.0.2.0/24 ; SBL1\n198.51.100.0/25 ; SBL2\n";
+        let firehol = "#\n# firehol_level1\n#\n203.0.113.0/24\n203.0.113.128/25\n";
+        let feodo = "# abuse.ch Feodo Tracker\n198.51.100.200\nnot-an-address\n2001:db8::1/48\n";
+
+        let (networks, skipped) = parse_feed(spamhaus);
+        assert_eq!(networks, ["192.0.2.0/24".parse::<IpNet>().unwrap(), "198.51.100.0/25".parse().unwrap()]);
+        assert_eq!(skipped, 0);
+        assert_eq!(parse_feed(firehol).0.len(), 2);
+        let (networks, skipped) = parse_feed(feodo);
+        assert_eq!(networks, ["198.51.100.200/32".parse::<IpNet>().unwrap(), "2001:db8::/48".parse().unwrap()]);
+        assert_eq!(skipped, 1);
+    }
+
+    #[test]
+    fn test_range_lookup() {
+        let (networks, _) = parse_feed("10.0.0.0/25\n10.0.0.128/25\n10.0.2.0/24\n10.0.1.5\n2001:db8::/32\n");
+        let ranges = RangeSet::from_networks(networks);
+        // The two /25s join into one range; 10.0.1.5 stays on its own
+        assert_eq!(ranges.v4.len(), 3);
+        for listed in ["10.0.0.0", "10.0.0.200", "10.0.1.5", "10.0.2.255", "2001:db8:ffff::1"] {
+            assert!(ranges.contains(listed.parse().unwrap()), "{} should be listed", listed);
+        }
+        for clear in ["9.255.255.255", "10.0.1.4", "10.0.1.6", "10.0.3.0", "2001:db9::1"] {
+            assert!(!ranges.contains(clear.parse().unwrap()), "{} should not be listed", clear);
+        }
+    }
+
+    #[test]
+    fn test_feed_config_rules() {
+        let preset = FeedConfig { name: None, preset: Some(FeedPreset::SpamhausDrop), url: None };
+        assert_eq!(preset.source().unwrap(), ("spamhaus_drop".to_string(), "https://www.spamhaus.org/drop/drop.txt".to_string()));
+        assert!(FeedConfig { name: None, preset: None, url: Some("https://example.com/list".to_string()) }.source().is_err());
+        assert!(url_feed("../etc", "https://example.com/list").source().is_err());
+        assert!(url_feed("local", "file:///etc/list").source().is_err());
+        assert!(FeedConfig { url: Some("https://example.com".to_string()), ..preset }.source().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_refresh_blocks_listed_sources_and_keeps_list_on_failure() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        // 0 serves the list, 1 fails, 2 serves an error page with a 200
+        let mode = Arc::new(AtomicUsize::new(0));
+        let mode_route = mode.clone();
+        let router = axum::Router::new().route(
+            "/drop.txt",
+            axum::routing::get(move || {
+                let mode = mode_route.clone();
+                async move {
+                    match mode.load(Ordering::Relaxed) {
+                        0 => Ok("; test list\n192.0.2.0/24 ; SBL1\n10.1.0.0/16\n"),
+                        1 => Err(axum::http::StatusCode::SERVICE_UNAVAILABLE),
+                        _ => Ok("<html>\n<body>Rate limited</body>\n10.9.0.0/16\n</html>\n"),
+                    }
+                }
+            }),
+        );
+        tokio::spawn(async move { axum::serve(listener, router).await });
+
+        let cache = tempfile::tempdir().unwrap();
+        let config = ReputationConfig {
+            enabled: true,
+            feeds: vec![url_feed("test", &format!("http://{}/drop.txt", addr))],
+            cache_dir: Some(cache.path().to_path_buf()),
+            exempt_cidrs: vec!["192.0.2.7".to_string()],
+            ..ReputationConfig::default()
+        };
+        let filter = ReputationFilter::new(config.clone()).unwrap();
+        assert_eq!(filter.check("192.0.2.1".parse().unwrap()), None);
+
+        filter.refresh().await;
+        assert_eq!(filter.check("192.0.2.1".parse().unwrap()), Some("test"));
+        assert_eq!(filter.check("::ffff:192.0.2.1".parse().unwrap()), Some("test"));
+        assert_eq!(filter.check("192.0.2.7".parse().unwrap()), None);
+        // Private addresses are exempt by default, even when listed
+        assert_eq!(filter.check("10.1.2.3".parse().unwrap()), None);
+        assert_eq!(filter.check("198.51.100.1".parse().unwrap()), None);
+
+        mode.store(1, Ordering::Relaxed);
+        filter.refresh().await;
+        assert_eq!(filter.check("192.0.2.1".parse().unwrap()), Some("test"));
+        let status = &filter.feeds()[0];
+        assert_eq!((status.entries, status.hits), (2, 3));
+        assert!(status.error.as_deref().unwrap().contains("503"));
+
+        // A mostly unparsable body fails the same way and is not cached
+        mode.store(2, Ordering::Relaxed);
+        filter.refresh().await;
+        assert_eq!(filter.check("192.0.2.1".parse().unwrap()), Some("test"));
+        assert!(filter.feeds()[0].error.as_deref().unwrap().contains("unparsable"));
+
+        // A new filter starts from the cached copy
+        let restarted = ReputationFilter::new(config).unwrap();
+        assert_eq!(restarted.check("192.0.2.1".parse().unwrap()), Some("test"));
+    }
+}
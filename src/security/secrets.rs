@@ -2,19 +2,45 @@
 //! 
 //! Provides encrypted configuration storage and environment variable support
 //! for sensitive data like passwords and API keys.
+//!
+//! Encrypted files are sealed with AES-256-GCM under a key derived from the
+//! key material (an environment variable or a key file) with PBKDF2-SHA256
+//! and a random per-file salt. The header in front of the ciphertext names
+//! the format version and iteration count and is authenticated along with
+//! the contents, so a file cannot be altered or downgraded unnoticed. Files
+//! from older releases, XORed with the key, are only read by
+//! [`SecretsManager::migrate_file`], which re-encrypts them.
 
 use std::collections::HashMap;
 use std::env;
 use std::fs;
-use std::path::Path;
+use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
+use anyhow::{anyhow, bail, Context};
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::{Algorithm, Argon2, Params, Version};
+use base64::{Engine as _, engine::general_purpose};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::pbkdf2;
+use ring::rand::{SecureRandom, SystemRandom};
 use serde::{Deserialize, Serialize};
 use tracing::{debug, warn, info};
 use crate::Result;
 
+/// Start of every file encrypted with the current format
+const HEADER_PREFIX: &str = "rustproxy-secrets:v1";
+/// PBKDF2 rounds for newly encrypted files; files record their own count
+const KDF_ITERATIONS: u32 = 210_000;
+const SALT_LEN: usize = 16;
+
 /// Secure configuration manager
 pub struct SecretsManager {
     config: SecureConfigSettings,
     secrets_cache: HashMap<String, String>,
+    /// PBKDF2 rounds for files this manager encrypts
+    kdf_iterations: u32,
+    /// Argon2id cost for passwords this manager hashes; hashes record their own
+    password_hash_params: Params,
 }
 
 /// Secure configuration settings
@@ -24,6 +50,9 @@ pub struct SecureConfigSettings {
     pub use_env_secrets: bool,
     pub secret_key_env: String,
     pub config_encryption_key_env: String,
+    /// File holding the key material, used instead of the environment variable
+    #[serde(default)]
+    pub config_encryption_key_file: Option<PathBuf>,
     pub env_prefix: String,
 }
 
@@ -34,11 +63,21 @@ impl Default for SecureConfigSettings {
             use_env_secrets: true,
             secret_key_env: "SOCKS5_SECRET_KEY".to_string(),
             config_encryption_key_env: "SOCKS5_CONFIG_KEY".to_string(),
+            config_encryption_key_file: None,
             env_prefix: "SOCKS5_".to_string(),
         }
     }
 }
 
+/// What [`SecretsManager::migrate_file`] did with a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MigrationOutcome {
+    /// Re-encrypted from the old XOR format; the original is kept next to it with a `.bak` suffix
+    Migrated,
+    /// Already in the current format, left alone
+    AlreadyCurrent,
+}
+
 /// Secure configuration wrapper
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct SecureConfig {
@@ -93,6 +132,8 @@ impl SecretsManager {
         Self {
             config,
             secrets_cache: HashMap::new(),
+            kdf_iterations: KDF_ITERATIONS,
+            password_hash_params: Params::default(),
         }
     }
 
@@ -273,57 +314,108 @@ impl SecretsManager {
         Ok(())
     }
 
+    /// Key material from the key file if one is set, else from the environment
+    fn key_material(&self) -> Result<String> {
+        if let Some(path) = &self.config.config_encryption_key_file {
+            let material = fs::read_to_string(path)
+                .with_context(|| format!("Failed to read encryption key file {}", path.display()))?;
+            let material = material.trim().to_string();
+            if material.is_empty() {
+                bail!("Encryption key file {} is empty", path.display());
+            }
+            return Ok(material);
+        }
+        let material = env::var(&self.config.config_encryption_key_env)
+            .map_err(|_| anyhow!("Encryption key not found in environment variable: {}",
+                                 self.config.config_encryption_key_env))?;
+        if material.trim().is_empty() {
+            bail!("Encryption key environment variable {} is empty", self.config.config_encryption_key_env);
+        }
+        Ok(material)
+    }
+
     /// Encrypt configuration content
-    fn encrypt_content(&self, content: &str) -> Result<String> {
-        // Get encryption key from environment
-        let key = env::var(&self.config.config_encryption_key_env)
-            .map_err(|_| anyhow::anyhow!("Encryption key not found in environment variable: {}", 
-                                       self.config.config_encryption_key_env))?;
-
-        // Simple XOR encryption (in production, use proper encryption like AES)
-        let encrypted = self.simple_encrypt(content, &key);
-        use base64::{Engine as _, engine::general_purpose};
-        let encoded = general_purpose::STANDARD.encode(encrypted);
-        
-        Ok(encoded)
+    pub fn encrypt_content(&self, content: &str) -> Result<String> {
+        let material = self.key_material()?;
+        let rng = SystemRandom::new();
+        let mut salt = [0u8; SALT_LEN];
+        let mut nonce = [0u8; NONCE_LEN];
+        rng.fill(&mut salt).map_err(|_| anyhow!("System random number generator failed"))?;
+        rng.fill(&mut nonce).map_err(|_| anyhow!("System random number generator failed"))?;
+
+        let header = format!("{}:{}:{}", HEADER_PREFIX, self.kdf_iterations, general_purpose::STANDARD.encode(salt));
+        let key = derive_key(&material, &salt, self.kdf_iterations)?;
+        let mut sealed = content.as_bytes().to_vec();
+        key.seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::from(header.as_bytes()), &mut sealed)
+            .map_err(|_| anyhow!("Failed to encrypt configuration"))?;
+
+        let mut payload = nonce.to_vec();
+        payload.extend_from_slice(&sealed);
+        Ok(format!("{}:{}\n", header, general_purpose::STANDARD.encode(payload)))
     }
 
     /// Decrypt configuration content
-    fn decrypt_content(&self, encrypted_content: &str) -> Result<String> {
-        // Get encryption key from environment
-        let key = env::var(&self.config.config_encryption_key_env)
-            .map_err(|_| anyhow::anyhow!("Encryption key not found in environment variable: {}", 
-                                       self.config.config_encryption_key_env))?;
-
-        // Decode and decrypt
-        use base64::{Engine as _, engine::general_purpose};
-        let encrypted_bytes = general_purpose::STANDARD.decode(encrypted_content)
-            .map_err(|e| anyhow::anyhow!("Failed to decode encrypted content: {}", e))?;
-        
-        let decrypted = self.simple_decrypt(&encrypted_bytes, &key);
-        
-        Ok(decrypted)
-    }
+    pub fn decrypt_content(&self, encrypted_content: &str) -> Result<String> {
+        let encrypted_content = encrypted_content.trim();
+        if !encrypted_content.starts_with(HEADER_PREFIX) {
+            bail!("Encrypted configuration is in the old XOR format; run `rustproxy migrate-secrets` to re-encrypt it");
+        }
+        let (header, payload) = encrypted_content.rsplit_once(':')
+            .ok_or_else(|| anyhow!("Malformed encrypted configuration"))?;
+        let mut fields = header[HEADER_PREFIX.len()..].split(':').skip(1);
+        let (Some(iterations), Some(salt), None) = (fields.next(), fields.next(), fields.next()) else {
+            bail!("Malformed encrypted configuration header");
+        };
+        let iterations: u32 = iterations.parse().context("Invalid iteration count in encrypted configuration")?;
+        let salt = general_purpose::STANDARD.decode(salt)
+            .map_err(|e| anyhow!("Invalid salt in encrypted configuration: {}", e))?;
+        let payload = general_purpose::STANDARD.decode(payload)
+            .map_err(|e| anyhow!("Failed to decode encrypted content: {}", e))?;
+        if payload.len() < NONCE_LEN + AES_256_GCM.tag_len() {
+            bail!("Encrypted configuration is truncated");
+        }
 
-    /// Simple XOR encryption (replace with proper encryption in production)
-    fn simple_encrypt(&self, data: &str, key: &str) -> Vec<u8> {
-        let key_bytes = key.as_bytes();
-        data.bytes()
-            .enumerate()
-            .map(|(i, byte)| byte ^ key_bytes[i % key_bytes.len()])
-            .collect()
+        let (nonce, sealed) = payload.split_at(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(nonce).map_err(|_| anyhow!("Invalid nonce"))?;
+        let key = derive_key(&self.key_material()?, &salt, iterations)?;
+        let mut sealed = sealed.to_vec();
+        let plain = key.open_in_place(nonce, Aad::from(header.as_bytes()), &mut sealed)
+            .map_err(|_| anyhow!("Failed to decrypt configuration: wrong key or the file was modified"))?;
+        String::from_utf8(plain.to_vec()).context("Decrypted configuration is not valid UTF-8")
     }
 
-    /// Simple XOR decryption (replace with proper decryption in production)
-    fn simple_decrypt(&self, data: &[u8], key: &str) -> String {
-        let key_bytes = key.as_bytes();
-        let decrypted_bytes: Vec<u8> = data
-            .iter()
-            .enumerate()
-            .map(|(i, &byte)| byte ^ key_bytes[i % key_bytes.len()])
-            .collect();
-        
-        String::from_utf8_lossy(&decrypted_bytes).to_string()
+    /// Re-encrypt a file written in the old XOR format with the current one
+    ///
+    /// The old format carries no integrity check, so the decrypted contents
+    /// must parse as a secure configuration before anything is written. The
+    /// new file replaces the old one atomically, and the original is kept
+    /// with a `.bak` suffix.
+    pub fn migrate_file(&self, path: &Path) -> Result<MigrationOutcome> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        if is_encrypted(&content) {
+            // Confirm the key opens it, so a rotated key is noticed now rather than at startup
+            self.decrypt_content(&content)?;
+            return Ok(MigrationOutcome::AlreadyCurrent);
+        }
+
+        let legacy = general_purpose::STANDARD.decode(content.trim())
+            .map_err(|e| anyhow!("{} is neither encrypted format: {}", path.display(), e))?;
+        let plain = legacy_xor(&legacy, self.key_material()?.as_bytes());
+        let plain = String::from_utf8(plain)
+            .map_err(|_| anyhow!("Decrypting {} gave invalid text; is the key right?", path.display()))?;
+        // The parse error would quote the decrypted text, so it is left out
+        toml::from_str::<SecureConfig>(&plain)
+            .map_err(|_| anyhow!("Decrypting {} did not give a valid configuration; is the key right?", path.display()))?;
+
+        let encrypted = self.encrypt_content(&plain)?;
+        let backup = append_suffix(path, ".bak");
+        let staged = append_suffix(path, ".tmp");
+        fs::copy(path, &backup).with_context(|| format!("Failed to back up {}", path.display()))?;
+        fs::write(&staged, encrypted).with_context(|| format!("Failed to write {}", staged.display()))?;
+        fs::rename(&staged, path).with_context(|| format!("Failed to replace {}", path.display()))?;
+        info!("Re-encrypted {} with AES-256-GCM (original kept as {})", path.display(), backup.display());
+        Ok(MigrationOutcome::Migrated)
     }
 
     /// Save secure configuration to encrypted file
@@ -392,17 +484,33 @@ impl SecretsManager {
         result == 0
     }
 
-    /// Verify password against hash (placeholder implementation)
-    fn verify_password_hash(&self, _password: &str, _hash: &str) -> bool {
-        // TODO: Implement proper password hashing verification (bcrypt, argon2, etc.)
-        warn!("Password hash verification not implemented - using plaintext comparison");
-        false
+    /// Verify a password against an Argon2 PHC string (`$argon2id$v=19$...`)
+    ///
+    /// The hash carries its own variant, cost and salt, so hashes made with
+    /// older or cheaper parameters keep verifying.
+    fn verify_password_hash(&self, password: &str, hash: &str) -> bool {
+        let parsed = match PasswordHash::new(hash) {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                warn!("Ignoring unparsable password hash: {}", e);
+                return false;
+            }
+        };
+        Argon2::default().verify_password(password.as_bytes(), &parsed).is_ok()
     }
 
-    /// Generate password hash (placeholder implementation)
-    pub fn hash_password(&self, _password: &str) -> Result<String> {
-        // TODO: Implement proper password hashing (bcrypt, argon2, etc.)
-        Err(anyhow::anyhow!("Password hashing not implemented"))
+    /// Hash a password with Argon2id and a random salt, as a PHC string for `password_hash`
+    pub fn hash_password(&self, password: &str) -> Result<String> {
+        if password.is_empty() {
+            bail!("Password must not be empty");
+        }
+        let mut salt = [0u8; SALT_LEN];
+        SystemRandom::new().fill(&mut salt).map_err(|_| anyhow!("Failed to generate salt"))?;
+        let salt = SaltString::encode_b64(&salt).map_err(|e| anyhow!("Failed to encode salt: {}", e))?;
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, self.password_hash_params.clone())
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .map_err(|e| anyhow!("Failed to hash password: {}", e))
     }
 
     /// Get proxy credentials by name
@@ -426,9 +534,7 @@ impl SecretsManager {
     pub fn validate_secrets_config(&self) -> Result<()> {
         if self.config.encrypt_config {
             // Check if encryption key is available
-            env::var(&self.config.config_encryption_key_env)
-                .map_err(|_| anyhow::anyhow!("Encryption key not found in environment: {}", 
-                                           self.config.config_encryption_key_env))?;
+            self.key_material()?;
         }
 
         if self.config.use_env_secrets {
@@ -439,21 +545,120 @@ impl SecretsManager {
     }
 }
 
+/// Whether a secrets file's contents are in the current encrypted format
+pub fn is_encrypted(content: &str) -> bool {
+    content.trim_start().starts_with(HEADER_PREFIX)
+}
+
+/// AES-256 key for a file's salt and iteration count
+fn derive_key(material: &str, salt: &[u8], iterations: u32) -> Result<LessSafeKey> {
+    let iterations = NonZeroU32::new(iterations).ok_or_else(|| anyhow!("Iteration count must be positive"))?;
+    let mut key = [0u8; 32];
+    pbkdf2::derive(pbkdf2::PBKDF2_HMAC_SHA256, iterations, salt, material.as_bytes(), &mut key);
+    let key = UnboundKey::new(&AES_256_GCM, &key).map_err(|_| anyhow!("Invalid encryption key"))?;
+    Ok(LessSafeKey::new(key))
+}
+
+/// Undo the XOR "encryption" of older releases
+fn legacy_xor(data: &[u8], key: &[u8]) -> Vec<u8> {
+    data.iter()
+        .enumerate()
+        .map(|(i, &byte)| byte ^ key[i % key.len()])
+        .collect()
+}
+
+fn append_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use std::env;
 
+    fn manager_with_key(dir: &tempfile::TempDir, key: &str) -> SecretsManager {
+        let key_file = dir.path().join(format!("{}.key", key));
+        fs::write(&key_file, format!("{}\n", key)).unwrap();
+        let mut manager = SecretsManager::new(SecureConfigSettings {
+            encrypt_config: true,
+            config_encryption_key_file: Some(key_file),
+            ..SecureConfigSettings::default()
+        });
+        // Files record their own count, so a cheap one keeps the tests fast
+        manager.kdf_iterations = 1_000;
+        manager
+    }
+
+    /// A manager whose password hashes are cheap enough for debug builds
+    fn cheap_hashing_manager() -> SecretsManager {
+        let mut manager = SecretsManager::new(SecureConfigSettings::default());
+        manager.password_hash_params = Params::new(64, 1, 1, None).unwrap();
+        manager
+    }
+
     #[test]
-    fn test_simple_encryption_decryption() {
-        let manager = SecretsManager::new(SecureConfigSettings::default());
+    fn test_encryption_round_trips_and_detects_tampering() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = manager_with_key(&dir, "encryption_key_123");
         let original = "test secret data";
-        let key = "encryption_key_123";
-        
-        let encrypted = manager.simple_encrypt(original, key);
-        let decrypted = manager.simple_decrypt(&encrypted, key);
-        
-        assert_eq!(original, decrypted);
+
+        let encrypted = manager.encrypt_content(original).unwrap();
+        assert!(encrypted.starts_with("rustproxy-secrets:v1:1000:"));
+        assert!(!encrypted.contains(original));
+        assert_eq!(manager.decrypt_content(&encrypted).unwrap(), original);
+        // Every file gets its own salt and nonce
+        assert_ne!(manager.encrypt_content(original).unwrap(), encrypted);
+
+        let other = manager_with_key(&dir, "another_key");
+        assert!(other.decrypt_content(&encrypted).is_err());
+
+        // A weaker iteration count in the header fails authentication
+        let downgraded = encrypted.replacen(":1000:", ":1:", 1);
+        assert!(manager.decrypt_content(&downgraded).is_err());
+    }
+
+    #[test]
+    fn test_migrates_xor_encrypted_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = manager_with_key(&dir, "old_key");
+        let config = SecureConfig {
+            auth_users: Vec::new(),
+            proxy_credentials: Vec::new(),
+            tls_certificates: Vec::new(),
+            api_keys: HashMap::from([("billing".to_string(), "s3cret".to_string())]),
+        };
+        let plain = toml::to_string_pretty(&config).unwrap();
+        let path = dir.path().join("secrets.enc");
+        fs::write(&path, general_purpose::STANDARD.encode(legacy_xor(plain.as_bytes(), b"old_key"))).unwrap();
+
+        // Startup refuses the old format rather than trusting it
+        assert!(manager.load_from_file(&path).is_err());
+        // A wrong key yields garbage, which is caught before anything is written
+        assert!(manager_with_key(&dir, "wrong_key").migrate_file(&path).is_err());
+
+        assert_eq!(manager.migrate_file(&path).unwrap(), MigrationOutcome::Migrated);
+        assert_eq!(manager.load_from_file(&path).unwrap().api_keys.get("billing"), Some(&"s3cret".to_string()));
+        assert!(append_suffix(&path, ".bak").exists());
+        assert_eq!(manager.migrate_file(&path).unwrap(), MigrationOutcome::AlreadyCurrent);
+    }
+
+    #[test]
+    fn test_rejects_empty_key_material() {
+        let dir = tempfile::tempdir().unwrap();
+        let var = "SOCKS5_TEST_EMPTY_CONFIG_KEY";
+        let manager = SecretsManager::new(SecureConfigSettings {
+            config_encryption_key_env: var.to_string(),
+            ..SecureConfigSettings::default()
+        });
+        env::set_var(var, " ");
+        let err = manager.encrypt_content("data").unwrap_err();
+        assert!(err.to_string().contains("is empty"));
+        let path = dir.path().join("secrets.enc");
+        fs::write(&path, general_purpose::STANDARD.encode(b"garbage")).unwrap();
+        assert!(manager.migrate_file(&path).is_err());
+        env::remove_var(var);
     }
 
     #[test]
@@ -524,4 +729,35 @@ mod tests {
         assert!(!manager.validate_user_credentials(&config, "user2", "pass2")); // disabled
         assert!(!manager.validate_user_credentials(&config, "nonexistent", "pass"));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_hashed_passwords_verify() {
+        let manager = cheap_hashing_manager();
+        let hash = manager.hash_password("correct horse").unwrap();
+        assert!(hash.starts_with("$argon2id$v=19$m=64,t=1,p=1$"));
+        assert!(!hash.contains("correct horse"));
+        // Every hash gets its own salt
+        assert_ne!(manager.hash_password("correct horse").unwrap(), hash);
+        assert!(manager.hash_password("").is_err());
+
+        let config = SecureConfig {
+            auth_users: vec![SecureUserConfig {
+                username: "hashed".to_string(),
+                password: None,
+                password_env: None,
+                password_hash: Some(hash),
+                enabled: true,
+                roles: Vec::new(),
+            }],
+            proxy_credentials: Vec::new(),
+            tls_certificates: Vec::new(),
+            api_keys: HashMap::new(),
+        };
+        // Verification takes the cost from the hash, not from the manager
+        let verifier = SecretsManager::new(SecureConfigSettings::default());
+        assert!(verifier.validate_user_credentials(&config, "hashed", "correct horse"));
+        assert!(!verifier.validate_user_credentials(&config, "hashed", "correct horse "));
+        assert!(!verifier.validate_user_credentials(&config, "hashed", ""));
+        assert!(!verifier.verify_password_hash("correct horse", "not-a-hash"));
+    }
+}
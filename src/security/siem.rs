@@ -0,0 +1,568 @@
+//! SIEM Export
+//!
+//! Forwards security events (failed logins, connections refused at accept
+//! time, and bans) to a remote syslog server or HTTP collector, as CEF or
+//! JSON, so a SOC can correlate proxy abuse with the rest of its data. Events
+//! are queued without blocking the connection path and delivered from a
+//! background task in batches of up to `batch_size`, or whatever has arrived
+//! after `flush_interval`. A failed batch is retried with exponential backoff
+//! and dropped after `max_retries`; when the queue is full, new events are
+//! dropped and counted.
+//!
+//! Over syslog every event is one RFC 5424 message, one datagram each over
+//! UDP and octet-counted (RFC 6587) over TCP. Over HTTP a batch is posted as
+//! one event per line: `application/x-ndjson` for JSON, `text/plain` for CEF.
+
+use std::fmt::Write as _;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Context};
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use tracing::{debug, warn};
+
+use super::fail2ban_log::SyslogFacility;
+use super::{BlockReason, SecurityEvent};
+use crate::Result;
+
+/// Longest wait between retries that doubling the backoff can reach
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(300);
+
+/// SIEM exporter configuration
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SiemConfig {
+    #[serde(default)]
+    pub format: SiemFormat,
+    /// Where events are sent
+    #[serde(flatten)]
+    pub target: SiemTarget,
+    /// Host name reported in syslog headers and events; defaults to the
+    /// machine's host name
+    #[serde(default)]
+    pub hostname: Option<String>,
+    /// Most events sent in one delivery
+    #[serde(default = "default_batch_size")]
+    pub batch_size: usize,
+    /// Longest an event waits for its batch to fill
+    #[serde(default = "default_flush_interval", with = "humantime_serde")]
+    pub flush_interval: Duration,
+    /// Further attempts after a failed delivery before the batch is dropped
+    #[serde(default = "default_max_retries")]
+    pub max_retries: u32,
+    /// Wait before the first retry, doubled for each one after up to five
+    /// minutes, or this wait if it is longer
+    #[serde(default = "default_retry_backoff", with = "humantime_serde")]
+    pub retry_backoff: Duration,
+    #[serde(default = "default_timeout", with = "humantime_serde")]
+    pub timeout: Duration,
+    /// Events held while deliveries are slow; new events are dropped beyond this
+    #[serde(default = "default_queue_capacity")]
+    pub queue_capacity: usize,
+}
+
+fn default_batch_size() -> usize {
+    100
+}
+
+fn default_flush_interval() -> Duration {
+    Duration::from_secs(5)
+}
+
+fn default_max_retries() -> u32 {
+    3
+}
+
+fn default_retry_backoff() -> Duration {
+    Duration::from_secs(1)
+}
+
+fn default_timeout() -> Duration {
+    Duration::from_secs(10)
+}
+
+fn default_queue_capacity() -> usize {
+    10_000
+}
+
+/// Encoding of each event
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SiemFormat {
+    /// ArcSight Common Event Format
+    Cef,
+    #[default]
+    Json,
+}
+
+/// Remote collector for SIEM events
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(tag = "transport", rename_all = "lowercase")]
+pub enum SiemTarget {
+    /// Syslog server at `address` (`host:port`)
+    Syslog {
+        address: String,
+        #[serde(default)]
+        protocol: SyslogProtocol,
+        #[serde(default)]
+        facility: SyslogFacility,
+    },
+    /// HTTP collector that accepts POSTed batches
+    Http {
+        url: String,
+        /// Sent as `Authorization: Bearer <token>`
+        #[serde(default)]
+        bearer_token: Option<String>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SyslogProtocol {
+    #[default]
+    Udp,
+    Tcp,
+}
+
+/// One security event as exported
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SiemRecord {
+    /// RFC 3339 time of the event
+    pub timestamp: String,
+    #[serde(skip)]
+    time: SystemTime,
+    pub host: String,
+    /// Event type, such as `auth_failure` or `ip_blocked`
+    pub event: &'static str,
+    /// CEF severity, 0 (lowest) to 10
+    pub severity: u8,
+    /// Left out for events about connections excluded by the privacy settings
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub src_ip: Option<IpAddr>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<BlockReason>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration_secs: Option<u64>,
+}
+
+impl SiemRecord {
+    fn new(event: &SecurityEvent, host: &str) -> Self {
+        let (name, severity, ip, detail) = match event {
+            SecurityEvent::RateLimitExceeded { ip, limit_type, current_rate, limit } => {
+                ("rate_limit_exceeded", 5, Some(*ip), Some(format!("{} rate {} over limit {}", limit_type, current_rate, limit)))
+            }
+            SecurityEvent::DdosAttackDetected { ip, connection_count, time_window } => {
+                ("ddos_detected", 8, Some(*ip), Some(format!("{} connections in {}s", connection_count, time_window.as_secs())))
+            }
+            SecurityEvent::BruteForceDetected { ip, failed_attempts, time_window } => {
+                ("brute_force_detected", 8, Some(*ip), Some(format!("{} failed logins in {}s", failed_attempts, time_window.as_secs())))
+            }
+            SecurityEvent::IpBlocked { ip, detail, .. } => ("ip_blocked", 7, Some(*ip), Some(detail.clone())),
+            SecurityEvent::IpUnblocked { ip, reason } => ("ip_unblocked", 2, Some(*ip), Some(reason.clone())),
+            SecurityEvent::AuthenticationFailed { ip, .. } => ("auth_failure", 5, Some(*ip), None),
+            SecurityEvent::ConnectionRejected { ip, detail, .. } => ("connection_rejected", 4, Some(*ip), Some(detail.clone())),
+            SecurityEvent::ExfiltrationSuspected { ip, scope, bytes_up, threshold, window, .. } => {
+                ("exfiltration_suspected", 7, *ip, Some(format!("{} uploaded {} bytes in {}s, over {}",
+                    scope.as_str(), bytes_up, window.as_secs(), threshold)))
+            }
+        };
+        let time = SystemTime::now();
+        Self {
+            timestamp: humantime::format_rfc3339_millis(time).to_string(),
+            time,
+            host: host.to_string(),
+            event: name,
+            severity,
+            src_ip: ip.map(|ip| ip.to_canonical()),
+            user: match event {
+                SecurityEvent::AuthenticationFailed { user, .. }
+                | SecurityEvent::ExfiltrationSuspected { user, .. } => user.clone(),
+                _ => None,
+            },
+            reason: event.reason_code(),
+            detail,
+            duration_secs: match event {
+                SecurityEvent::IpBlocked { duration, .. } => Some(duration.as_secs()),
+                _ => None,
+            },
+        }
+    }
+
+    fn title(&self) -> &'static str {
+        match self.event {
+            "rate_limit_exceeded" => "Rate limit exceeded",
+            "ddos_detected" => "Connection flood detected",
+            "brute_force_detected" => "Brute force detected",
+            "ip_blocked" => "Source blocked",
+            "ip_unblocked" => "Source unblocked",
+            "auth_failure" => "Authentication failed",
+            "connection_rejected" => "Connection rejected",
+            "exfiltration_suspected" => "Possible data exfiltration",
+            _ => "Security event",
+        }
+    }
+
+    /// The event as one CEF line
+    pub fn to_cef(&self) -> String {
+        let millis = self.time.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+        let mut line = format!(
+            "CEF:0|RustProxy|rustproxy|{}|{}|{}|{}|rt={} dvchost={}",
+            cef_header(env!("CARGO_PKG_VERSION")),
+            cef_header(self.event),
+            cef_header(self.title()),
+            self.severity,
+            millis,
+            cef_value(&self.host),
+        );
+        if let Some(ip) = self.src_ip {
+            let _ = write!(line, " src={}", ip);
+        }
+        if let Some(user) = &self.user {
+            let _ = write!(line, " suser={}", cef_value(user));
+        }
+        if let Some(reason) = self.reason {
+            let _ = write!(line, " reason={}", reason);
+        }
+        if let Some(duration) = self.duration_secs {
+            let _ = write!(line, " cn1={} cn1Label=durationSeconds", duration);
+        }
+        if let Some(detail) = &self.detail {
+            let _ = write!(line, " msg={}", cef_value(detail));
+        }
+        line
+    }
+
+    /// The event in the configured format
+    fn encode(&self, format: SiemFormat) -> String {
+        match format {
+            SiemFormat::Cef => self.to_cef(),
+            SiemFormat::Json => serde_json::to_string(self).unwrap_or_default(),
+        }
+    }
+}
+
+/// Escape a CEF header field
+fn cef_header(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('|', "\\|").replace(['\r', '\n'], " ")
+}
+
+/// Escape a CEF extension value
+fn cef_value(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('=', "\\=").replace('\n', "\\n").replace('\r', "\\r")
+}
+
+/// Queues security events for delivery to a SIEM
+pub struct SiemExporter {
+    host: String,
+    /// Taken on shutdown so the delivery task drains the queue and ends
+    queue: Mutex<Option<mpsc::Sender<SiemRecord>>>,
+    delivery: tokio::sync::Mutex<Option<JoinHandle<()>>>,
+    dropped: AtomicU64,
+}
+
+impl SiemExporter {
+    /// Start delivering events to the configured collector
+    pub fn spawn(config: SiemConfig) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(config.timeout)
+            .user_agent(format!("rustproxy/{}", env!("CARGO_PKG_VERSION")))
+            .build()
+            .context("Failed to build SIEM HTTP client")?;
+        let host = config.hostname.clone().unwrap_or_else(local_hostname);
+        let (queue, events) = mpsc::channel(config.queue_capacity.max(1));
+        let delivery = tokio::spawn(deliver(client, config, host.clone(), events));
+        Ok(Self {
+            host,
+            queue: Mutex::new(Some(queue)),
+            delivery: tokio::sync::Mutex::new(Some(delivery)),
+            dropped: AtomicU64::new(0),
+        })
+    }
+
+    /// Queue an event for export, dropping it if the queue is full
+    pub fn record(&self, event: &SecurityEvent) {
+        let record = SiemRecord::new(event, &self.host);
+        let queue = self.queue.lock().unwrap();
+        let Some(queue) = queue.as_ref() else {
+            return;
+        };
+        if queue.try_send(record).is_err() {
+            let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+            if dropped.is_power_of_two() {
+                warn!("SIEM queue full, {} security event(s) dropped so far", dropped);
+            }
+        }
+    }
+
+    /// Events dropped because the queue was full
+    pub fn dropped_events(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Stop taking events and wait until those queued have been delivered or dropped
+    pub async fn close(&self) -> Result<()> {
+        self.queue.lock().unwrap().take();
+        if let Some(delivery) = self.delivery.lock().await.take() {
+            delivery.await.context("SIEM delivery task failed")?;
+        }
+        Ok(())
+    }
+}
+
+/// Host name for syslog headers, or the RFC 5424 nil value if unknown
+fn local_hostname() -> String {
+    std::env::var("HOSTNAME").ok()
+        .or_else(|| std::fs::read_to_string("/proc/sys/kernel/hostname").ok())
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "-".to_string())
+}
+
+/// Send batches until the exporter closes and the queue is drained
+async fn deliver(client: reqwest::Client, config: SiemConfig, host: String, mut events: mpsc::Receiver<SiemRecord>) {
+    while let Some(batch) = next_batch(&mut events, &config).await {
+        if let Err(e) = send_with_retries(&client, &config, &host, &batch).await {
+            warn!("Dropping {} SIEM event(s): {:#}", batch.len(), e);
+        }
+    }
+}
+
+/// Wait for an event, then gather more until the batch is full or
+/// `flush_interval` has passed since the first
+async fn next_batch(events: &mut mpsc::Receiver<SiemRecord>, config: &SiemConfig) -> Option<Vec<SiemRecord>> {
+    let first = events.recv().await?;
+    let flush_at = Instant::now() + config.flush_interval;
+    let mut batch = vec![first];
+    while batch.len() < config.batch_size {
+        match tokio::time::timeout_at(flush_at, events.recv()).await {
+            Ok(Some(event)) => batch.push(event),
+            Ok(None) | Err(_) => break,
+        }
+    }
+    Some(batch)
+}
+
+async fn send_with_retries(client: &reqwest::Client, config: &SiemConfig, host: &str, batch: &[SiemRecord]) -> Result<()> {
+    let mut backoff = config.retry_backoff;
+    let max_backoff = MAX_RETRY_BACKOFF.max(config.retry_backoff);
+    let mut attempt = 0;
+    loop {
+        match send(client, config, host, batch).await {
+            Ok(()) => {
+                debug!("Delivered {} SIEM event(s)", batch.len());
+                return Ok(());
+            }
+            Err(e) if attempt >= config.max_retries => return Err(e),
+            Err(e) => {
+                attempt += 1;
+                warn!("SIEM delivery failed (retry {} of {} in {:?}): {:#}", attempt, config.max_retries, backoff, e);
+                tokio::time::sleep(backoff).await;
+                backoff = backoff.saturating_mul(2).min(max_backoff);
+            }
+        }
+    }
+}
+
+async fn send(client: &reqwest::Client, config: &SiemConfig, host: &str, batch: &[SiemRecord]) -> Result<()> {
+    match &config.target {
+        SiemTarget::Http { url, bearer_token } => {
+            let mut body = String::new();
+            for record in batch {
+                body.push_str(&record.encode(config.format));
+                body.push('\n');
+            }
+            let content_type = match config.format {
+                SiemFormat::Cef => "text/plain",
+                SiemFormat::Json => "application/x-ndjson",
+            };
+            let mut request = client.post(url).header(reqwest::header::CONTENT_TYPE, content_type).body(body);
+            if let Some(token) = bearer_token {
+                request = request.bearer_auth(token);
+            }
+            let response = request.send().await
+                .with_context(|| format!("SIEM request to {} failed", url))?;
+            if !response.status().is_success() {
+                return Err(anyhow!("SIEM collector returned {}", response.status()));
+            }
+            Ok(())
+        }
+        SiemTarget::Syslog { address, protocol, facility } => {
+            let messages: Vec<String> = batch.iter()
+                .map(|record| syslog_message(record, *facility, host, config.format))
+                .collect();
+            let send = async {
+                match protocol {
+                    SyslogProtocol::Udp => {
+                        let target = tokio::net::lookup_host(address.as_str()).await?.next()
+                            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "address did not resolve"))?;
+                        let local = if target.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+                        let socket = tokio::net::UdpSocket::bind(local).await?;
+                        socket.connect(target).await?;
+                        for message in &messages {
+                            socket.send(message.as_bytes()).await?;
+                        }
+                    }
+                    SyslogProtocol::Tcp => {
+                        let mut stream = tokio::net::TcpStream::connect(address).await?;
+                        let mut framed = String::new();
+                        for message in &messages {
+                            let _ = write!(framed, "{} {}", message.len(), message);
+                        }
+                        stream.write_all(framed.as_bytes()).await?;
+                        stream.shutdown().await?;
+                    }
+                }
+                Ok::<_, std::io::Error>(())
+            };
+            tokio::time::timeout(config.timeout, send).await
+                .map_err(|_| anyhow!("Timed out sending to syslog server {}", address))?
+                .with_context(|| format!("Failed to send to syslog server {}", address))
+        }
+    }
+}
+
+/// RFC 5424 message carrying one event
+fn syslog_message(record: &SiemRecord, facility: SyslogFacility, host: &str, format: SiemFormat) -> String {
+    // CEF severities 0-10 onto syslog's 7 (debug) to 1 (alert)
+    let severity = match record.severity {
+        0..=3 => 6,
+        4..=6 => 5,
+        7..=8 => 4,
+        _ => 3,
+    };
+    let priority = facility.code() * 8 + severity;
+    format!(
+        "<{}>1 {} {} rustproxy {} {} - {}",
+        priority,
+        record.timestamp,
+        host,
+        std::process::id(),
+        record.event,
+        record.encode(format),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    fn blocked() -> SecurityEvent {
+        SecurityEvent::IpBlocked {
+            ip: "::ffff:192.0.2.1".parse().unwrap(),
+            reason: BlockReason::BruteForce,
+            detail: "5 failures | key=value".to_string(),
+            duration: Duration::from_secs(1800),
+        }
+    }
+
+    #[test]
+    fn test_cef_and_json_encoding() {
+        let record = SiemRecord::new(&blocked(), "proxy-1");
+        let cef = record.to_cef();
+        let version = env!("CARGO_PKG_VERSION");
+        assert!(cef.starts_with(&format!("CEF:0|RustProxy|rustproxy|{}|ip_blocked|Source blocked|7|rt=", version)), "{}", cef);
+        assert!(cef.contains(" dvchost=proxy-1 src=192.0.2.1 reason=BRUTE_FORCE cn1=1800 cn1Label=durationSeconds"));
+        assert!(cef.ends_with(" msg=5 failures | key\\=value"));
+
+        let json: serde_json::Value = serde_json::from_str(&record.encode(SiemFormat::Json)).unwrap();
+        assert_eq!(json["event"], "ip_blocked");
+        assert_eq!(json["src_ip"], "192.0.2.1");
+        assert_eq!(json["reason"], "BRUTE_FORCE");
+        assert_eq!(json["duration_secs"], 1800);
+        assert!(json.get("user").is_none());
+
+        // Events about redacted connections name no source
+        let redacted = SiemRecord::new(&SecurityEvent::ExfiltrationSuspected {
+            ip: None,
+            user: None,
+            scope: crate::security::ExfiltrationScope::Connection,
+            bytes_up: 200,
+            threshold: 100,
+            window: Duration::from_secs(600),
+        }, "proxy-1");
+        assert!(!redacted.to_cef().contains(" src="));
+        assert!(serde_json::to_value(&redacted).unwrap().get("src_ip").is_none());
+
+        let failure = SiemRecord::new(&SecurityEvent::AuthenticationFailed {
+            ip: "2001:db8::1".parse().unwrap(),
+            user: Some("a=b\nc".to_string()),
+        }, "proxy-1");
+        assert!(failure.to_cef().contains(" src=2001:db8::1 suser=a\\=b\\nc"));
+        let message = syslog_message(&failure, SyslogFacility::Local3, "proxy-1", SiemFormat::Cef);
+        assert!(message.starts_with(&format!("<157>1 {} proxy-1 rustproxy {} auth_failure - CEF:0|", failure.timestamp, std::process::id())));
+    }
+
+    #[test]
+    fn test_config_targets() {
+        let config: SiemConfig = toml::from_str("format = \"cef\"\ntransport = \"syslog\"\naddress = \"siem.example.com:514\"\nprotocol = \"tcp\"").unwrap();
+        assert_eq!(config.format, SiemFormat::Cef);
+        assert_eq!(config.target, SiemTarget::Syslog {
+            address: "siem.example.com:514".to_string(),
+            protocol: SyslogProtocol::Tcp,
+            facility: SyslogFacility::Authpriv,
+        });
+        let config: SiemConfig = toml::from_str("transport = \"http\"\nurl = \"https://siem.example.com/ingest\"").unwrap();
+        assert_eq!(config.format, SiemFormat::Json);
+        assert_eq!(config.batch_size, 100);
+        assert!(matches!(config.target, SiemTarget::Http { bearer_token: None, .. }));
+    }
+
+    #[tokio::test]
+    async fn test_tcp_syslog_delivery_is_retried() {
+        // Reserve a port, then start listening on it only after the first attempt has failed
+        let address = {
+            let probe = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            probe.local_addr().unwrap()
+        };
+        let exporter = SiemExporter::spawn(SiemConfig {
+            format: SiemFormat::Json,
+            target: SiemTarget::Syslog {
+                address: address.to_string(),
+                protocol: SyslogProtocol::Tcp,
+                facility: SyslogFacility::Authpriv,
+            },
+            hostname: Some("proxy-1".to_string()),
+            batch_size: 10,
+            flush_interval: Duration::from_millis(20),
+            max_retries: 3,
+            retry_backoff: Duration::from_millis(100),
+            timeout: Duration::from_secs(2),
+            queue_capacity: 16,
+        })
+        .unwrap();
+
+        exporter.record(&blocked());
+        exporter.record(&SecurityEvent::ConnectionRejected {
+            ip: "198.51.100.7".parse().unwrap(),
+            reason: BlockReason::Reputation,
+            detail: "listed in reputation feed 'drop'".to_string(),
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let listener = tokio::net::TcpListener::bind(address).await.unwrap();
+        let receive = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut received = String::new();
+            stream.read_to_string(&mut received).await.unwrap();
+            received
+        });
+        exporter.close().await.unwrap();
+        exporter.record(&blocked());
+
+        let received = receive.await.unwrap();
+        let (length, rest) = received.split_once(' ').unwrap();
+        let first = &rest[..length.parse::<usize>().unwrap()];
+        assert!(first.starts_with("<84>1 ") && first.contains(" proxy-1 rustproxy ") && first.contains(" ip_blocked - {"));
+        assert!(received.contains("\"reason\":\"REPUTATION\""));
+        assert_eq!(received.matches("<8").count(), 2);
+    }
+}
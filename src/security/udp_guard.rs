@@ -0,0 +1,299 @@
+//! UDP Relay Spoofing and Replay Protection
+//!
+//! A UDP ASSOCIATE relay socket accepts datagrams from anyone who can reach
+//! it, so an off-path attacker who learns the port could inject traffic into
+//! an association. Each association is therefore pinned to its client: the
+//! address the client announced in the UDP ASSOCIATE request, or else the
+//! first datagram's source, which must come from the IP address of the
+//! controlling TCP connection. Datagrams from any other address are only
+//! accepted as replies from targets the client has already sent to.
+//!
+//! Cooperating clients can additionally prefix every datagram with an
+//! 8-byte big-endian sequence number (`sequence_validation`). Sequence
+//! numbers must increase, although reordering within `replay_window` is
+//! tolerated; repeated or older ones are dropped as replays.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Length of the optional sequence number prefix
+pub const SEQUENCE_LEN: usize = 8;
+
+/// Largest supported replay window
+pub const MAX_REPLAY_WINDOW: u64 = 64;
+
+/// UDP relay protection configuration
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct UdpGuardConfig {
+    /// Only accept client datagrams from the association's pinned address,
+    /// rather than from any port on the client's IP address
+    #[serde(default = "default_pin_source")]
+    pub pin_source: bool,
+    /// Require an 8-byte sequence number in front of each client datagram
+    #[serde(default)]
+    pub sequence_validation: bool,
+    /// How far behind the highest sequence number a datagram may arrive
+    #[serde(default = "default_replay_window")]
+    pub replay_window: u64,
+}
+
+fn default_pin_source() -> bool {
+    true
+}
+
+fn default_replay_window() -> u64 {
+    MAX_REPLAY_WINDOW
+}
+
+impl Default for UdpGuardConfig {
+    fn default() -> Self {
+        Self {
+            pin_source: default_pin_source(),
+            sequence_validation: false,
+            replay_window: default_replay_window(),
+        }
+    }
+}
+
+/// Why a datagram was dropped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UdpRejection {
+    /// From neither the pinned client nor a target it has sent to
+    Spoofed,
+    /// Sequence number already seen or too old
+    Replayed,
+    /// Too short to carry a sequence number
+    Malformed,
+    /// Over the association's or its source IP's packet or byte rate
+    RateLimited,
+    /// To a target the routing rules or private range protection refuse
+    Blocked,
+}
+
+impl UdpRejection {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UdpRejection::Spoofed => "spoofed",
+            UdpRejection::Replayed => "replayed",
+            UdpRejection::Malformed => "malformed",
+            UdpRejection::RateLimited => "rate_limited",
+            UdpRejection::Blocked => "blocked",
+        }
+    }
+}
+
+impl fmt::Display for UdpRejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Rejected datagram counters shared by all associations
+#[derive(Debug, Default)]
+pub struct UdpGuardCounters {
+    spoofed: AtomicU64,
+    replayed: AtomicU64,
+    malformed: AtomicU64,
+    rate_limited: AtomicU64,
+    blocked: AtomicU64,
+}
+
+/// Snapshot of [`UdpGuardCounters`]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct UdpGuardStats {
+    pub spoofed: u64,
+    pub replayed: u64,
+    pub malformed: u64,
+    pub rate_limited: u64,
+    pub blocked: u64,
+}
+
+impl UdpGuardCounters {
+    pub fn record(&self, rejection: UdpRejection) {
+        let counter = match rejection {
+            UdpRejection::Spoofed => &self.spoofed,
+            UdpRejection::Replayed => &self.replayed,
+            UdpRejection::Malformed => &self.malformed,
+            UdpRejection::RateLimited => &self.rate_limited,
+            UdpRejection::Blocked => &self.blocked,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> UdpGuardStats {
+        UdpGuardStats {
+            spoofed: self.spoofed.load(Ordering::Relaxed),
+            replayed: self.replayed.load(Ordering::Relaxed),
+            malformed: self.malformed.load(Ordering::Relaxed),
+            rate_limited: self.rate_limited.load(Ordering::Relaxed),
+            blocked: self.blocked.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Where an accepted datagram came from
+#[derive(Debug, PartialEq, Eq)]
+pub enum UdpOrigin<'a> {
+    /// The client, with the sequence prefix (if any) removed
+    Client(&'a [u8]),
+    /// A target the client has sent to
+    Target,
+}
+
+/// Sliding window over recently seen sequence numbers
+#[derive(Debug)]
+struct ReplayWindow {
+    size: u64,
+    highest: Option<u64>,
+    /// Bit `n` set means `highest - n` was seen
+    seen: u64,
+}
+
+impl ReplayWindow {
+    fn new(size: u64) -> Self {
+        Self { size: size.clamp(1, MAX_REPLAY_WINDOW), highest: None, seen: 0 }
+    }
+
+    fn accept(&mut self, sequence: u64) -> bool {
+        let Some(highest) = self.highest else {
+            self.highest = Some(sequence);
+            self.seen = 1;
+            return true;
+        };
+        if sequence > highest {
+            let shift = sequence - highest;
+            self.seen = if shift >= 64 { 0 } else { self.seen << shift };
+            self.seen |= 1;
+            self.highest = Some(sequence);
+            return true;
+        }
+        let behind = highest - sequence;
+        if behind >= self.size || self.seen & (1 << behind) != 0 {
+            return false;
+        }
+        self.seen |= 1 << behind;
+        true
+    }
+}
+
+/// Spoofing and replay checks for one UDP association
+#[derive(Debug)]
+pub struct UdpAssociationGuard {
+    pin_source: bool,
+    client_ip: IpAddr,
+    client: Option<SocketAddr>,
+    destinations: HashSet<SocketAddr>,
+    window: Option<ReplayWindow>,
+}
+
+impl UdpAssociationGuard {
+    /// Guard an association controlled from `client_ip`; `announced` is the
+    /// address from the UDP ASSOCIATE request, used when it is fully specified
+    pub fn new(config: &UdpGuardConfig, client_ip: IpAddr, announced: Option<SocketAddr>) -> Self {
+        Self {
+            pin_source: config.pin_source,
+            client_ip,
+            client: announced.filter(|addr| !addr.ip().is_unspecified() && addr.port() != 0),
+            destinations: HashSet::new(),
+            window: config.sequence_validation.then(|| ReplayWindow::new(config.replay_window)),
+        }
+    }
+
+    /// The address client datagrams are pinned to, once known
+    pub fn client(&self) -> Option<SocketAddr> {
+        self.client
+    }
+
+    /// Classify a datagram arriving on the relay socket
+    pub fn check<'a>(&mut self, source: SocketAddr, datagram: &'a [u8]) -> Result<UdpOrigin<'a>, UdpRejection> {
+        let from_client = match self.client {
+            Some(client) if source == client => true,
+            _ if self.destinations.contains(&source) => return Ok(UdpOrigin::Target),
+            Some(_) => !self.pin_source && source.ip() == self.client_ip,
+            None => source.ip() == self.client_ip,
+        };
+        if !from_client {
+            return Err(UdpRejection::Spoofed);
+        }
+
+        let payload = match &mut self.window {
+            Some(window) => {
+                if datagram.len() < SEQUENCE_LEN {
+                    return Err(UdpRejection::Malformed);
+                }
+                let (sequence, payload) = datagram.split_at(SEQUENCE_LEN);
+                let sequence = u64::from_be_bytes(sequence.try_into().expect("8-byte prefix"));
+                if !window.accept(sequence) {
+                    return Err(UdpRejection::Replayed);
+                }
+                payload
+            }
+            None => datagram,
+        };
+        if self.client.is_none() {
+            self.client = Some(source);
+        }
+        Ok(UdpOrigin::Client(payload))
+    }
+
+    /// Accept replies from a target the client sent a datagram to
+    pub fn record_destination(&mut self, target: SocketAddr) {
+        self.destinations.insert(target);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(text: &str) -> SocketAddr {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn test_pins_client_and_known_targets() {
+        let mut guard = UdpAssociationGuard::new(&UdpGuardConfig::default(), addr("198.51.100.7:1").ip(), None);
+        assert_eq!(guard.check(addr("203.0.113.9:4000"), b"x"), Err(UdpRejection::Spoofed));
+        assert_eq!(guard.check(addr("198.51.100.7:5000"), b"x"), Ok(UdpOrigin::Client(b"x")));
+        assert_eq!(guard.client(), Some(addr("198.51.100.7:5000")));
+
+        // Same host, different port is not the pinned client
+        assert_eq!(guard.check(addr("198.51.100.7:5001"), b"x"), Err(UdpRejection::Spoofed));
+        let config = UdpGuardConfig { pin_source: false, ..Default::default() };
+        let mut unpinned = UdpAssociationGuard::new(&config, addr("198.51.100.7:1").ip(), Some(addr("198.51.100.7:5000")));
+        assert!(unpinned.check(addr("198.51.100.7:5001"), b"x").is_ok());
+        assert_eq!(unpinned.check(addr("203.0.113.9:5000"), b"x"), Err(UdpRejection::Spoofed));
+
+        guard.record_destination(addr("192.0.2.53:53"));
+        assert_eq!(guard.check(addr("192.0.2.53:53"), b"reply"), Ok(UdpOrigin::Target));
+        assert_eq!(guard.check(addr("192.0.2.53:54"), b"reply"), Err(UdpRejection::Spoofed));
+
+        let announced = UdpAssociationGuard::new(&UdpGuardConfig::default(), addr("198.51.100.7:1").ip(), Some(addr("198.51.100.7:6000")));
+        assert_eq!(announced.client(), Some(addr("198.51.100.7:6000")));
+        let unspecified = UdpAssociationGuard::new(&UdpGuardConfig::default(), addr("198.51.100.7:1").ip(), Some(addr("0.0.0.0:0")));
+        assert_eq!(unspecified.client(), None);
+    }
+
+    #[test]
+    fn test_sequence_numbers_reject_replays() {
+        let config = UdpGuardConfig { sequence_validation: true, replay_window: 4, ..Default::default() };
+        let mut guard = UdpAssociationGuard::new(&config, addr("198.51.100.7:1").ip(), None);
+        let client = addr("198.51.100.7:5000");
+        let datagram = |sequence: u64| [&sequence.to_be_bytes()[..], b"data"].concat();
+
+        assert_eq!(guard.check(client, &datagram(10)), Ok(UdpOrigin::Client(&b"data"[..])));
+        assert_eq!(guard.check(client, &datagram(10)), Err(UdpRejection::Replayed));
+        assert!(guard.check(client, &datagram(12)).is_ok());
+        // Reordered within the window
+        assert!(guard.check(client, &datagram(11)).is_ok());
+        assert_eq!(guard.check(client, &datagram(11)), Err(UdpRejection::Replayed));
+        // Too far behind
+        assert_eq!(guard.check(client, &datagram(8)), Err(UdpRejection::Replayed));
+        assert!(guard.check(client, &datagram(500)).is_ok());
+        assert_eq!(guard.check(client, &datagram(12)), Err(UdpRejection::Replayed));
+        assert_eq!(guard.check(client, b"short"), Err(UdpRejection::Malformed));
+    }
+}
@@ -2,8 +2,15 @@
 //! 
 //! This module provides utilities for handling graceful shutdown of the SOCKS5 proxy server.
 //! It supports SIGTERM and SIGINT signals and ensures active connections are closed cleanly.
+//!
+//! Shutdown runs in ordered phases (stop accepting, drain relays, flush,
+//! persist, exit). Subsystems register hooks for the phase their cleanup
+//! belongs to, and `run_phases` runs them in that order.
 
-use std::sync::Arc;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 use tokio::sync::{broadcast, Notify};
 use tokio::signal;
@@ -11,6 +18,60 @@ use tracing::{info, warn, error, debug};
 use crate::connection::ConnectionManager;
 use crate::Result;
 
+/// Extra time a hook gets beyond the shutdown timeout, for cleanup after draining
+const HOOK_GRACE: Duration = Duration::from_secs(5);
+
+/// Ordered stages of a graceful shutdown
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ShutdownPhase {
+    /// Close listeners so no new connections arrive
+    StopAccepting,
+    /// Let active relays finish, up to the shutdown timeout
+    DrainRelays,
+    /// Deliver queued metrics, reports and notifications
+    Flush,
+    /// Write state that should survive a restart
+    Persist,
+    /// Stop remaining services before the process exits
+    Exit,
+}
+
+impl ShutdownPhase {
+    /// Every phase, in the order they run
+    pub const ALL: [ShutdownPhase; 5] = [
+        ShutdownPhase::StopAccepting,
+        ShutdownPhase::DrainRelays,
+        ShutdownPhase::Flush,
+        ShutdownPhase::Persist,
+        ShutdownPhase::Exit,
+    ];
+
+    /// Stable name used in logs
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ShutdownPhase::StopAccepting => "stop_accepting",
+            ShutdownPhase::DrainRelays => "drain_relays",
+            ShutdownPhase::Flush => "flush",
+            ShutdownPhase::Persist => "persist",
+            ShutdownPhase::Exit => "exit",
+        }
+    }
+}
+
+impl fmt::Display for ShutdownPhase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+type ShutdownHook = Box<dyn FnOnce() -> Pin<Box<dyn Future<Output = Result<()>> + Send>> + Send>;
+
+struct RegisteredHook {
+    phase: ShutdownPhase,
+    name: String,
+    hook: ShutdownHook,
+}
+
 /// Shutdown coordinator that manages graceful shutdown process
 pub struct ShutdownCoordinator {
     /// Broadcast sender for shutdown signal
@@ -19,6 +80,8 @@ pub struct ShutdownCoordinator {
     shutdown_complete: Arc<Notify>,
     /// Shutdown timeout duration
     timeout: Duration,
+    /// Cleanup registered for each phase, in registration order
+    hooks: Mutex<Vec<RegisteredHook>>,
 }
 
 impl ShutdownCoordinator {
@@ -31,9 +94,63 @@ impl ShutdownCoordinator {
             shutdown_tx,
             shutdown_complete,
             timeout,
+            hooks: Mutex::new(Vec::new()),
         }
     }
 
+    /// Run `hook` during `phase`
+    ///
+    /// Hooks of one phase run one after another in registration order, each
+    /// bounded by the shutdown timeout. A failing hook is logged and does not
+    /// stop the hooks after it.
+    pub fn register<F, Fut>(&self, phase: ShutdownPhase, name: impl Into<String>, hook: F)
+    where
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        let hook: ShutdownHook = Box::new(move || Box::pin(hook()));
+        self.hooks.lock().unwrap().push(RegisteredHook { phase, name: name.into(), hook });
+    }
+
+    /// Run every registered hook, phase by phase, then signal completion
+    ///
+    /// Returns the phase and name of each hook that failed or timed out.
+    pub async fn run_phases(&self) -> Vec<(ShutdownPhase, String)> {
+        let mut hooks = std::mem::take(&mut *self.hooks.lock().unwrap());
+        // Stable, so hooks of a phase keep their registration order
+        hooks.sort_by_key(|registered| registered.phase);
+        let mut hooks = hooks.into_iter().peekable();
+        let mut failed = Vec::new();
+        let start_time = Instant::now();
+
+        for phase in ShutdownPhase::ALL {
+            let phase_start = Instant::now();
+            let mut ran = 0;
+            while let Some(registered) = hooks.next_if(|registered| registered.phase == phase) {
+                debug!("Running shutdown hook '{}' ({})", registered.name, phase);
+                match tokio::time::timeout(self.timeout + HOOK_GRACE, (registered.hook)()).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => {
+                        warn!("Shutdown hook '{}' failed during {}: {:#}", registered.name, phase, e);
+                        failed.push((phase, registered.name));
+                    }
+                    Err(_) => {
+                        warn!("Shutdown hook '{}' timed out during {}", registered.name, phase);
+                        failed.push((phase, registered.name));
+                    }
+                }
+                ran += 1;
+            }
+            if ran > 0 {
+                info!("Shutdown phase {} finished {} hook(s) in {:?}", phase, ran, phase_start.elapsed());
+            }
+        }
+
+        info!("Shutdown phases completed in {:?} ({} hook(s) failed)", start_time.elapsed(), failed.len());
+        self.shutdown_complete.notify_waiters();
+        failed
+    }
+
     /// Get a shutdown receiver for components to listen for shutdown signals
     pub fn subscribe(&self) -> broadcast::Receiver<()> {
         self.shutdown_tx.subscribe()
@@ -219,6 +336,30 @@ mod tests {
         assert!(receiver.recv().await.is_ok());
     }
 
+    #[tokio::test]
+    async fn test_hooks_run_in_phase_order() {
+        let coordinator = ShutdownCoordinator::new(Duration::from_millis(50));
+        let ran = Arc::new(Mutex::new(Vec::new()));
+        let record = |name: &'static str, ran: &Arc<Mutex<Vec<&'static str>>>| {
+            let ran = ran.clone();
+            move || async move {
+                ran.lock().unwrap().push(name);
+                Ok(())
+            }
+        };
+
+        // Registered out of order on purpose
+        coordinator.register(ShutdownPhase::Persist, "bans", record("persist", &ran));
+        coordinator.register(ShutdownPhase::Flush, "broken", || async { Err(anyhow::anyhow!("disk full")) });
+        coordinator.register(ShutdownPhase::StopAccepting, "listener", record("stop", &ran));
+        coordinator.register(ShutdownPhase::Flush, "metrics", record("flush", &ran));
+        coordinator.register(ShutdownPhase::Exit, "api", record("exit", &ran));
+
+        let failed = coordinator.run_phases().await;
+        assert_eq!(*ran.lock().unwrap(), ["stop", "flush", "persist", "exit"]);
+        assert_eq!(failed, [(ShutdownPhase::Flush, "broken".to_string())]);
+    }
+
     #[tokio::test]
     async fn test_shutdown_aware_task() {
         let coordinator = ShutdownCoordinator::new(Duration::from_secs(5));
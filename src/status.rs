@@ -0,0 +1,403 @@
+//! Status Dashboard
+//!
+//! Backs `rustproxy status`: pulls server status, statistics, and live
+//! connections from the running proxy's management API, probes the configured
+//! upstream proxies, and renders a compact terminal summary that is readable
+//! over an SSH session without curl or jq.
+
+use crate::config::Config;
+use crate::management::types::{ApiAuthConfig, ApiResponse, ConnectionInfo, ServerStatus, StatsSummary};
+use crate::preflight::{self, CheckStatus, PreflightCheck};
+use crate::update::VersionReport;
+use crate::Result;
+use anyhow::{anyhow, Context};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// How many entries to show in each top-N section
+const TOP_N: usize = 5;
+
+/// Everything shown on the dashboard
+#[derive(Debug, Serialize)]
+pub struct StatusSnapshot {
+    pub api_url: String,
+    pub listeners: Vec<Listener>,
+    pub status: ServerStatus,
+    pub stats: StatsSummary,
+    pub connections: Vec<ConnectionInfo>,
+    pub upstreams: Vec<PreflightCheck>,
+    /// Release check results; missing from servers that predate them
+    pub version: Option<VersionReport>,
+}
+
+/// A socket the proxy is configured to listen on
+#[derive(Debug, Serialize)]
+pub struct Listener {
+    pub name: String,
+    pub addr: SocketAddr,
+}
+
+/// Client for the local management API
+pub struct StatusClient {
+    client: reqwest::Client,
+    base_url: String,
+    auth: ApiAuthConfig,
+}
+
+impl StatusClient {
+    /// Create a client for the management API described by the configuration
+    ///
+    /// Wildcard bind addresses are contacted over loopback. When the API is
+    /// served over TLS, its certificate is trusted; pass `url` with a name
+    /// the certificate covers if it is not issued for the address.
+    pub fn from_config(config: &Config, url: Option<&str>) -> Result<Self> {
+        let api = &config.monitoring.management_api;
+        let base_url = match url {
+            Some(url) => url.trim_end_matches('/').to_string(),
+            None => {
+                let mut addr = api.bind_addr;
+                if addr.ip().is_unspecified() {
+                    addr.set_ip(if addr.is_ipv4() {
+                        std::net::Ipv4Addr::LOCALHOST.into()
+                    } else {
+                        std::net::Ipv6Addr::LOCALHOST.into()
+                    });
+                }
+                let scheme = if api.tls_cert.is_some() { "https" } else { "http" };
+                format!("{}://{}/api/v1", scheme, addr)
+            }
+        };
+        let mut builder = reqwest::Client::builder().timeout(Duration::from_secs(5));
+        // Trust the API's own certificate, which is often self-signed
+        if let Some(cert) = &api.tls_cert {
+            let pem = std::fs::read(cert)
+                .with_context(|| format!("Failed to read management API certificate {}", cert.display()))?;
+            for root in reqwest::Certificate::from_pem_bundle(&pem)
+                .with_context(|| format!("Invalid certificate in {}", cert.display()))?
+            {
+                builder = builder.add_root_certificate(root);
+            }
+        }
+        let client = builder.build().context("Failed to build management API client")?;
+
+        Ok(Self { client, base_url, auth: api.auth.clone() })
+    }
+
+    /// Collect a full snapshot from the management API and upstream probes
+    pub async fn snapshot(&self, config: &Config) -> Result<StatusSnapshot> {
+        let status = self.get::<ServerStatus>("/status").await?;
+        let stats = self.get::<StatsSummary>("/stats").await?;
+        let connections = self.get::<Vec<ConnectionInfo>>("/connections?limit=1000").await?;
+        let upstreams = preflight::check_upstreams(config).await;
+        let version = self.get::<VersionReport>("/version").await.ok();
+
+        Ok(StatusSnapshot {
+            api_url: self.base_url.clone(),
+            listeners: listeners(config),
+            status,
+            stats,
+            connections,
+            upstreams,
+            version,
+        })
+    }
+
+    async fn get<T: DeserializeOwned>(&self, path: &str) -> Result<T> {
+        let url = format!("{}{}", self.base_url, path);
+        let mut request = self.client.get(&url);
+        if self.auth.enabled {
+            if let Some(key) = &self.auth.api_key {
+                request = request.header("x-api-key", key);
+            } else if let Some(basic) = &self.auth.basic_auth {
+                request = request.basic_auth(&basic.username, Some(&basic.password));
+            }
+        }
+
+        let response = request.send().await
+            .with_context(|| format!("Cannot reach management API at {}", self.base_url))?;
+        if !response.status().is_success() {
+            return Err(anyhow!("Management API returned {} for {}", response.status(), path));
+        }
+        let body: ApiResponse<T> = response.json().await
+            .with_context(|| format!("Invalid response from {}", path))?;
+        match body.data {
+            Some(data) if body.success => Ok(data),
+            _ => Err(anyhow!("{} failed: {}", path, body.error.unwrap_or_else(|| "no data".to_string()))),
+        }
+    }
+}
+
+fn listeners(config: &Config) -> Vec<Listener> {
+    let mut listeners = vec![Listener { name: "socks5".to_string(), addr: config.server.bind_addr }];
+    if let Some(tunnel) = &config.server.tunnel {
+        listeners.push(Listener { name: "tunnel".to_string(), addr: tunnel.bind_addr });
+    }
+    if config.monitoring.management_api.enabled {
+        listeners.push(Listener {
+            name: "management".to_string(),
+            addr: config.monitoring.management_api.bind_addr,
+        });
+    }
+    if config.monitoring.enabled && config.monitoring.prometheus_enabled {
+        if let Some(addr) = config.monitoring.metrics_addr {
+            listeners.push(Listener { name: "metrics".to_string(), addr });
+        }
+    }
+    listeners
+}
+
+/// ANSI styling that can be switched off for pipes and `NO_COLOR`
+#[derive(Debug, Clone, Copy)]
+pub struct Palette {
+    enabled: bool,
+}
+
+impl Palette {
+    pub fn new(enabled: bool) -> Self {
+        Self { enabled }
+    }
+
+    /// Colors on when stdout is a terminal and `NO_COLOR` is unset
+    pub fn detect() -> Self {
+        use std::io::IsTerminal;
+        Self::new(std::io::stdout().is_terminal() && std::env::var_os("NO_COLOR").is_none())
+    }
+
+    fn paint(&self, code: &str, text: &str) -> String {
+        if self.enabled {
+            format!("\x1b[{}m{}\x1b[0m", code, text)
+        } else {
+            text.to_string()
+        }
+    }
+
+    fn bold(&self, text: &str) -> String {
+        self.paint("1", text)
+    }
+
+    fn dim(&self, text: &str) -> String {
+        self.paint("2", text)
+    }
+
+    fn green(&self, text: &str) -> String {
+        self.paint("32", text)
+    }
+
+    fn yellow(&self, text: &str) -> String {
+        self.paint("33", text)
+    }
+
+    fn red(&self, text: &str) -> String {
+        self.paint("31", text)
+    }
+}
+
+/// Render the dashboard as terminal text
+pub fn render(snapshot: &StatusSnapshot, palette: Palette) -> String {
+    let mut out = String::new();
+    let status = &snapshot.status;
+    let stats = &snapshot.stats;
+
+    let _ = writeln!(
+        out,
+        "{}  {}  up {}  {}",
+        palette.bold(&format!("RustProxy v{}", status.version)),
+        palette.green("● running"),
+        format_duration(status.uptime_seconds),
+        palette.dim(&snapshot.api_url),
+    );
+
+    if let Some(version) = &snapshot.version {
+        if let (true, Some(latest)) = (version.update_available, &version.latest) {
+            let _ = writeln!(out, "  {} {}", palette.yellow(&format!("Update available: v{}", latest)),
+                             palette.dim(version.release_url.as_deref().unwrap_or_default()));
+        }
+        for advisory in &version.advisories {
+            let _ = writeln!(out, "  {} {}: {}", palette.red("Security advisory"), advisory.id, advisory.summary);
+        }
+    }
+
+    section(&mut out, palette, "Listeners");
+    for listener in &snapshot.listeners {
+        let _ = writeln!(out, "  {:<12} {}", listener.name, listener.addr);
+    }
+
+    section(&mut out, palette, "Traffic");
+    let _ = writeln!(
+        out,
+        "  {} active / {} total connections, {} transferred",
+        palette.bold(&status.active_connections.to_string()),
+        status.total_connections,
+        format_bytes(status.bytes_transferred),
+    );
+    let _ = writeln!(
+        out,
+        "  {} auth attempts, {} failed",
+        stats.auth_attempts,
+        failures(palette, stats.auth_failures),
+    );
+
+    section(&mut out, palette, "Top talkers");
+    let talkers = top_talkers(&snapshot.connections);
+    if talkers.is_empty() && stats.top_users.is_empty() {
+        let _ = writeln!(out, "  {}", palette.dim("none"));
+    }
+    for (client, bytes) in talkers {
+        let _ = writeln!(out, "  {:<24} {:>10} {}", client, format_bytes(bytes), palette.dim("live"));
+    }
+    for user in stats.top_users.iter().take(TOP_N) {
+        let _ = writeln!(
+            out,
+            "  {:<24} {:>10} {}",
+            user.username,
+            format_bytes(user.bytes_transferred),
+            palette.dim(&format!("{} connections", user.connection_count)),
+        );
+    }
+
+    section(&mut out, palette, "Blocks");
+    let mut blocks: Vec<_> = stats.blocked_by_reason.iter().filter(|(_, count)| **count > 0).collect();
+    blocks.sort_by(|a, b| b.1.cmp(a.1).then(a.0.as_str().cmp(b.0.as_str())));
+    if blocks.is_empty() {
+        let _ = writeln!(out, "  {}", palette.dim("none"));
+    }
+    for (reason, count) in blocks {
+        let _ = writeln!(out, "  {:<12} {}", reason.as_str(), palette.yellow(&count.to_string()));
+    }
+
+    section(&mut out, palette, "Upstreams");
+    if snapshot.upstreams.is_empty() {
+        let _ = writeln!(out, "  {}", palette.dim("none configured"));
+    }
+    for check in &snapshot.upstreams {
+        let name = check.name.strip_prefix("upstream:").unwrap_or(&check.name);
+        let marker = match check.status {
+            CheckStatus::Pass => palette.green("✔"),
+            CheckStatus::Warn => palette.yellow("!"),
+            CheckStatus::Fail => palette.red("✘"),
+        };
+        let _ = writeln!(out, "  {} {:<16} {} {}", marker, name, check.message, palette.dim(&format!("({} ms)", check.duration_ms)));
+    }
+
+    out
+}
+
+fn section(out: &mut String, palette: Palette, title: &str) {
+    let _ = writeln!(out, "\n{}", palette.bold(title));
+}
+
+fn failures(palette: Palette, count: u64) -> String {
+    if count == 0 {
+        count.to_string()
+    } else {
+        palette.red(&count.to_string())
+    }
+}
+
+/// Clients moving the most bytes on currently open connections
+fn top_talkers(connections: &[ConnectionInfo]) -> Vec<(String, u64)> {
+    let mut totals: HashMap<String, u64> = HashMap::new();
+    for conn in connections {
+        let client = conn.user_id.clone().unwrap_or_else(|| conn.client_addr.ip().to_string());
+        *totals.entry(client).or_default() += conn.bytes_up + conn.bytes_down;
+    }
+    let mut talkers: Vec<_> = totals.into_iter().collect();
+    talkers.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    talkers.truncate(TOP_N);
+    talkers
+}
+
+/// Format seconds as e.g. "3d 4h 12m"
+pub fn format_duration(seconds: u64) -> String {
+    let (days, hours, minutes) = (seconds / 86_400, seconds / 3_600 % 24, seconds / 60 % 60);
+    match (days, hours) {
+        (0, 0) => format!("{}m {}s", minutes, seconds % 60),
+        (0, _) => format!("{}h {}m", hours, minutes),
+        _ => format!("{}d {}h {}m", days, hours, minutes),
+    }
+}
+
+/// Format a byte count with binary units
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::management::handlers::AppState;
+    use crate::management::ManagementApi;
+    use crate::metrics::Metrics;
+    use std::sync::Arc;
+    use std::time::SystemTime;
+    use tokio::sync::RwLock;
+
+    #[test]
+    fn test_formatting() {
+        assert_eq!(format_bytes(512), "512 B");
+        assert_eq!(format_bytes(1536), "1.5 KiB");
+        assert_eq!(format_bytes(3 * 1024 * 1024 * 1024), "3.0 GiB");
+        assert_eq!(format_duration(59), "0m 59s");
+        assert_eq!(format_duration(3_700), "1h 1m");
+        assert_eq!(format_duration(90_061), "1d 1h 1m");
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_from_management_api() {
+        let mut config = Config::default();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        config.monitoring.management_api.bind_addr = listener.local_addr().unwrap();
+
+        let state = AppState {
+            config: Arc::new(RwLock::new(config.clone())),
+            metrics: Arc::new(Metrics::new()),
+            start_time: SystemTime::now(),
+            auth_manager: None,
+            sticky_sessions: None,
+            router: None,
+            update_checker: None,
+            honeypot: None,
+            fail2ban: None,
+            ddos: None,
+        };
+        let router = ManagementApi::create_router(state, config.monitoring.management_api.auth.clone());
+        tokio::spawn(async move { axum::serve(listener, router).await });
+
+        let client = StatusClient::from_config(&config, None).unwrap();
+        let snapshot = client.snapshot(&config).await.unwrap();
+        assert_eq!(snapshot.status.version, env!("CARGO_PKG_VERSION"));
+        assert!(!snapshot.version.as_ref().unwrap().update_available);
+
+        let text = render(&snapshot, Palette::new(false));
+        assert!(text.contains("Listeners"));
+        assert!(text.contains(&config.server.bind_addr.to_string()));
+        assert!(!text.contains('\x1b'));
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_api() {
+        let mut config = Config::default();
+        // Bind and drop to get a port with nothing listening
+        let addr = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        config.monitoring.management_api.bind_addr = addr;
+
+        let client = StatusClient::from_config(&config, None).unwrap();
+        let error = client.snapshot(&config).await.unwrap_err();
+        assert!(error.to_string().contains("Cannot reach management API"));
+    }
+}
@@ -0,0 +1,287 @@
+//! TLS Certificates
+//!
+//! Certificates for the TLS listeners (the tunnel listener and the
+//! management API). Each listener has a default certificate and any number
+//! of extra ones picked by the name the client sends in SNI, so one listener
+//! can answer for several names. The files are watched the same way as the
+//! configuration file: when a certificate or key is replaced, the listener
+//! loads the new set and uses it for the next handshake. Connections that
+//! are already up keep the session they negotiated, and a set that fails to
+//! load (a key written before its certificate, say) leaves the old one in
+//! use until the files are consistent again.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use anyhow::{bail, Context};
+use notify::{Config as NotifyConfig, Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use serde::{Deserialize, Serialize};
+use tokio_rustls::rustls::crypto::CryptoProvider;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+use tokio_rustls::rustls::sign::CertifiedKey;
+use tokio_rustls::rustls::{self, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, error, info, warn};
+
+use crate::Result;
+
+/// Extra certificate served to clients asking for one of its names
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct SniCertConfig {
+    /// Names sent in SNI that select this certificate; `*.example.com`
+    /// matches any single label under `example.com`
+    pub server_names: Vec<String>,
+    /// PEM certificate chain
+    pub cert: PathBuf,
+    /// PEM private key for `cert`
+    pub key: PathBuf,
+}
+
+/// Certificate and key files of one TLS listener
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CertificateFiles {
+    /// Served when SNI is absent or matches none of `sni`
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    pub sni: Vec<SniCertConfig>,
+}
+
+impl CertificateFiles {
+    /// Files from a listener's `tls_cert`, `tls_key`, and `tls_sni_certs`
+    /// settings, or `None` when TLS is off; `what` names the listener in errors
+    pub fn from_settings(
+        cert: Option<&Path>,
+        key: Option<&Path>,
+        sni: &[SniCertConfig],
+        what: &str,
+    ) -> Result<Option<Self>> {
+        match (cert, key) {
+            (Some(cert), Some(key)) => Ok(Some(Self {
+                cert: cert.to_path_buf(),
+                key: key.to_path_buf(),
+                sni: sni.to_vec(),
+            })),
+            (None, None) if sni.is_empty() => Ok(None),
+            (None, None) => bail!("{} TLS needs tls_cert and tls_key to use tls_sni_certs", what),
+            _ => bail!("{} TLS needs both tls_cert and tls_key", what),
+        }
+    }
+
+    /// Every file the set is loaded from
+    fn paths(&self) -> Vec<&Path> {
+        let mut paths = vec![self.cert.as_path(), self.key.as_path()];
+        for entry in &self.sni {
+            paths.push(&entry.cert);
+            paths.push(&entry.key);
+        }
+        paths
+    }
+}
+
+/// Loaded certificates, keyed by lowercase server name
+#[derive(Debug)]
+struct CertificateStore {
+    default: Arc<CertifiedKey>,
+    by_name: HashMap<String, Arc<CertifiedKey>>,
+}
+
+impl CertificateStore {
+    fn load(files: &CertificateFiles, provider: &CryptoProvider) -> Result<Self> {
+        let default = load_certified_key(&files.cert, &files.key, provider)?;
+        let mut by_name = HashMap::new();
+        for entry in &files.sni {
+            if entry.server_names.is_empty() {
+                bail!("SNI certificate {} has no server_names", entry.cert.display());
+            }
+            let key = load_certified_key(&entry.cert, &entry.key, provider)?;
+            for name in &entry.server_names {
+                by_name.insert(name.trim_end_matches('.').to_ascii_lowercase(), key.clone());
+            }
+        }
+        Ok(Self { default, by_name })
+    }
+
+    /// Certificate for an exact name, then for a wildcard one label up, then the default
+    fn select(&self, server_name: Option<&str>) -> Arc<CertifiedKey> {
+        let Some(name) = server_name.map(|name| name.trim_end_matches('.').to_ascii_lowercase()) else {
+            return self.default.clone();
+        };
+        if let Some(key) = self.by_name.get(&name) {
+            return key.clone();
+        }
+        name.split_once('.')
+            .and_then(|(_, parent)| self.by_name.get(&format!("*.{}", parent)))
+            .unwrap_or(&self.default)
+            .clone()
+    }
+}
+
+fn load_certified_key(cert: &Path, key: &Path, provider: &CryptoProvider) -> Result<Arc<CertifiedKey>> {
+    let certs = CertificateDer::pem_file_iter(cert)
+        .with_context(|| format!("Failed to read certificate {}", cert.display()))?
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .with_context(|| format!("Invalid certificate in {}", cert.display()))?;
+    if certs.is_empty() {
+        bail!("No certificate in {}", cert.display());
+    }
+    let private_key = PrivateKeyDer::from_pem_file(key)
+        .with_context(|| format!("Failed to read private key {}", key.display()))?;
+    let certified = CertifiedKey::from_der(certs, private_key, provider)
+        .with_context(|| format!("Certificate {} does not match key {}", cert.display(), key.display()))?;
+    Ok(Arc::new(certified))
+}
+
+/// Picks a listener's certificate by SNI and swaps in new files when they change
+#[derive(Debug)]
+pub struct ReloadingCertResolver {
+    files: CertificateFiles,
+    provider: Arc<CryptoProvider>,
+    store: RwLock<Arc<CertificateStore>>,
+}
+
+impl ReloadingCertResolver {
+    /// Load the certificate set, failing if any file is missing or invalid
+    pub fn new(files: CertificateFiles) -> Result<Self> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let store = CertificateStore::load(&files, &provider)?;
+        Ok(Self {
+            files,
+            provider,
+            store: RwLock::new(Arc::new(store)),
+        })
+    }
+
+    /// Load the files again, keeping the current set if they do not load
+    pub fn reload(&self) -> Result<()> {
+        let store = CertificateStore::load(&self.files, &self.provider)?;
+        *self.store.write().unwrap() = Arc::new(store);
+        Ok(())
+    }
+
+    /// TLS acceptor whose handshakes ask this resolver for the certificate
+    pub fn acceptor(self: &Arc<Self>) -> Result<TlsAcceptor> {
+        let tls = ServerConfig::builder_with_provider(self.provider.clone())
+            .with_safe_default_protocol_versions()
+            .context("Failed to build TLS server")?
+            .with_no_client_auth()
+            .with_cert_resolver(self.clone());
+        Ok(TlsAcceptor::from(Arc::new(tls)))
+    }
+
+    /// Reload whenever one of the files is written or replaced, for as long
+    /// as the returned watcher is kept
+    pub fn watch(self: &Arc<Self>) -> Result<RecommendedWatcher> {
+        let resolver = self.clone();
+        let mut watcher = RecommendedWatcher::new(
+            move |res: notify::Result<Event>| match res {
+                Ok(event) => resolver.handle_file_event(event),
+                Err(e) => error!("Certificate watcher error: {}", e),
+            },
+            NotifyConfig::default(),
+        )
+        .context("Failed to create certificate watcher")?;
+
+        // Watch the directories rather than the files, which renewal tools replace
+        let mut dirs: Vec<&Path> = self.files.paths().into_iter()
+            .map(|path| path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new(".")))
+            .collect();
+        dirs.sort();
+        dirs.dedup();
+        for dir in dirs {
+            watcher
+                .watch(dir, RecursiveMode::NonRecursive)
+                .with_context(|| format!("Failed to watch directory: {}", dir.display()))?;
+            info!("Watching certificates in {}", dir.display());
+        }
+        Ok(watcher)
+    }
+
+    fn handle_file_event(&self, event: Event) {
+        debug!("Certificate file event: {:?}", event);
+        if !matches!(event.kind, EventKind::Modify(_) | EventKind::Create(_)) {
+            return;
+        }
+        let watched = self.files.paths();
+        let affects_certs = event.paths.iter().any(|path| {
+            watched.iter().any(|watched| path.file_name() == watched.file_name())
+        });
+        if !affects_certs {
+            return;
+        }
+
+        // Give the writer a moment to finish, as the config watcher does
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        match self.reload() {
+            Ok(()) => info!("Reloaded TLS certificates from {}", self.files.cert.display()),
+            Err(e) => warn!("Failed to reload TLS certificates, keeping current ones: {:#}", e),
+        }
+    }
+}
+
+impl ResolvesServerCert for ReloadingCertResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let store = self.store.read().unwrap().clone();
+        Some(store.select(client_hello.server_name()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CERT_A: &str = include_str!("../tests/data/tls/a.example.com.crt");
+    const KEY_A: &str = include_str!("../tests/data/tls/a.example.com.key");
+    const CERT_B: &str = include_str!("../tests/data/tls/b.example.com.crt");
+    const KEY_B: &str = include_str!("../tests/data/tls/b.example.com.key");
+
+    fn write(dir: &Path, name: &str, contents: &str) -> PathBuf {
+        let path = dir.join(name);
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_select_by_sni_and_reload() {
+        let dir = tempfile::tempdir().unwrap();
+        let files = CertificateFiles {
+            cert: write(dir.path(), "default.crt", CERT_A),
+            key: write(dir.path(), "default.key", KEY_A),
+            sni: vec![SniCertConfig {
+                server_names: vec!["b.example.com".to_string(), "*.b.example.com".to_string()],
+                cert: write(dir.path(), "b.crt", CERT_B),
+                key: write(dir.path(), "b.key", KEY_B),
+            }],
+        };
+        let resolver = ReloadingCertResolver::new(files.clone()).unwrap();
+        let store = resolver.store.read().unwrap().clone();
+        let cert_of = |name: Option<&str>| store.select(name).cert[0].clone();
+        let (a, b) = (cert_of(None), cert_of(Some("b.example.com")));
+        assert_ne!(a, b);
+        assert_eq!(cert_of(Some("B.Example.Com.")), b);
+        assert_eq!(cert_of(Some("www.b.example.com")), b);
+        assert_eq!(cert_of(Some("x.www.b.example.com")), a);
+        assert_eq!(cert_of(Some("other.example.com")), a);
+
+        // A key that does not match its certificate keeps the old set
+        write(dir.path(), "default.key", KEY_B);
+        assert!(resolver.reload().is_err());
+        assert_eq!(resolver.store.read().unwrap().select(None).cert[0], a);
+
+        write(dir.path(), "default.crt", CERT_B);
+        resolver.reload().unwrap();
+        assert_eq!(resolver.store.read().unwrap().select(None).cert[0], b);
+    }
+
+    #[test]
+    fn test_settings_need_cert_and_key() {
+        let path = Path::new("x.pem");
+        assert!(CertificateFiles::from_settings(None, None, &[], "Test").unwrap().is_none());
+        assert!(CertificateFiles::from_settings(Some(path), None, &[], "Test").is_err());
+        let sni = [SniCertConfig { server_names: vec!["a".into()], cert: path.into(), key: path.into() }];
+        assert!(CertificateFiles::from_settings(None, None, &sni, "Test").is_err());
+        assert!(CertificateFiles::from_settings(Some(path), Some(path), &sni, "Test").unwrap().is_some());
+    }
+}
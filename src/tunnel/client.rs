@@ -0,0 +1,160 @@
+//! Tunnel Client
+//!
+//! Keeps one tunnel open to each far node and opens streams over it,
+//! dialling afresh when the tunnel has gone away.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use anyhow::Context;
+use socket2::SockRef;
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName};
+use tokio_rustls::rustls::{self, ClientConfig, RootCertStore};
+use tokio_rustls::TlsConnector;
+use tracing::{debug, info};
+
+use super::frame::{client_handshake, HandshakeError};
+use super::mux::{MuxSession, TunnelStream};
+use super::TunnelClientConfig;
+use crate::connection::{Keepalive, SocketOptions};
+use crate::protocol::TargetAddr;
+use crate::routing::UpstreamError;
+use crate::Result;
+
+type SessionSlot = Arc<tokio::sync::Mutex<Option<Arc<MuxSession>>>>;
+
+/// Opens streams to far nodes over shared tunnels
+#[derive(Default)]
+pub struct TunnelConnector {
+    sessions: Mutex<HashMap<(SocketAddr, TunnelClientConfig), SessionSlot>>,
+    socket_options: SocketOptions,
+    keepalive: Option<Keepalive>,
+}
+
+impl TunnelConnector {
+    /// Tunnel connections get the egress socket options and keepalive
+    pub fn new(socket_options: SocketOptions, keepalive: Option<Keepalive>) -> Self {
+        Self { sessions: Mutex::new(HashMap::new()), socket_options, keepalive }
+    }
+
+    /// Ask the far node at `node` to connect a stream to `target:port`
+    ///
+    /// Dialling the tunnel, if there is none yet, and the far node's connect
+    /// each get `connect_timeout`.
+    pub async fn open(
+        &self,
+        node: SocketAddr,
+        config: &TunnelClientConfig,
+        target: &TargetAddr,
+        port: u16,
+        connect_timeout: Duration,
+    ) -> Result<TunnelStream> {
+        let session = self.session(node, config, connect_timeout).await?;
+        match timeout(connect_timeout, session.open(target, port)).await {
+            Ok(result) => result,
+            Err(_) => Err(UpstreamError::Timeout { proxy: node }.into()),
+        }
+    }
+
+    /// Tunnels currently open, with the streams each carries
+    pub fn tunnels(&self) -> Vec<(SocketAddr, usize)> {
+        let sessions = self.sessions.lock().unwrap();
+        sessions.iter()
+            .filter_map(|((node, _), slot)| {
+                let slot = slot.try_lock().ok()?;
+                let session = slot.as_ref().filter(|session| !session.is_closed())?;
+                Some((*node, session.stream_count()))
+            })
+            .collect()
+    }
+
+    /// The open tunnel to `node`, dialling one if needed
+    async fn session(&self, node: SocketAddr, config: &TunnelClientConfig, connect_timeout: Duration) -> Result<Arc<MuxSession>> {
+        let slot = self.sessions.lock().unwrap()
+            .entry((node, config.clone()))
+            .or_default()
+            .clone();
+        // Streams opened while the tunnel is being dialled wait for it rather than dialling their own
+        let mut slot = slot.lock().await;
+        if let Some(session) = slot.as_ref().filter(|session| !session.is_closed()) {
+            return Ok(session.clone());
+        }
+        let session = match timeout(connect_timeout, self.dial(node, config)).await {
+            Ok(result) => Arc::new(result?),
+            Err(_) => return Err(UpstreamError::Timeout { proxy: node }.into()),
+        };
+        *slot = Some(session.clone());
+        Ok(session)
+    }
+
+    async fn dial(&self, node: SocketAddr, config: &TunnelClientConfig) -> Result<MuxSession> {
+        debug!("Opening tunnel to {}", node);
+        let stream = TcpStream::connect(node).await
+            .map_err(|e| UpstreamError::Unreachable { proxy: node, reason: e.to_string() })?;
+        if let Err(e) = self.socket_options.apply(SockRef::from(&stream)) {
+            debug!("Failed to set socket options on tunnel to {}: {}", node, e);
+        }
+        if let Some(keepalive) = self.keepalive {
+            if let Err(e) = keepalive.apply(&stream) {
+                debug!("Failed to enable keepalive on tunnel to {}: {}", node, e);
+            }
+        }
+
+        let (session, compressed) = if config.tls {
+            let connector = tls_connector(config)?;
+            let name = match &config.server_name {
+                Some(name) => ServerName::try_from(name.clone())
+                    .with_context(|| format!("Invalid tunnel server name {}", name))?,
+                None => ServerName::IpAddress(node.ip().into()),
+            };
+            let mut stream = connector.connect(name, stream).await
+                .map_err(|e| UpstreamError::Unreachable { proxy: node, reason: format!("TLS handshake failed: {}", e) })?;
+            let compressed = handshake(&mut stream, node, config).await?;
+            (MuxSession::new(stream, node, compressed).0, compressed)
+        } else {
+            let mut stream = stream;
+            let compressed = handshake(&mut stream, node, config).await?;
+            (MuxSession::new(stream, node, compressed).0, compressed)
+        };
+        info!("Opened tunnel to {} (tls: {}, compression: {})", node, config.tls, compressed);
+        Ok(session)
+    }
+}
+
+/// Present the token, turning a rejection into an upstream authentication failure
+async fn handshake<S>(stream: &mut S, node: SocketAddr, config: &TunnelClientConfig) -> Result<bool>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    client_handshake(stream, &config.token, config.compression).await.map_err(|e| {
+        match e.downcast_ref::<HandshakeError>() {
+            Some(HandshakeError::BadToken) => UpstreamError::AuthFailed { proxy: node }.into(),
+            _ => e.context(format!("Tunnel handshake with {} failed", node)),
+        }
+    })
+}
+
+fn tls_connector(config: &TunnelClientConfig) -> Result<TlsConnector> {
+    let roots = match &config.ca_cert {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in CertificateDer::pem_file_iter(path)
+                .with_context(|| format!("Failed to read tunnel CA certificates from {}", path.display()))?
+            {
+                roots.add(cert.with_context(|| format!("Invalid certificate in {}", path.display()))?)?;
+            }
+            roots
+        }
+        None => RootCertStore { roots: webpki_roots::TLS_SERVER_ROOTS.to_vec() },
+    };
+    let tls = ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .context("Failed to build tunnel TLS client")?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(TlsConnector::from(Arc::new(tls)))
+}
@@ -0,0 +1,323 @@
+//! Tunnel Frames
+//!
+//! A tunnel connection opens with a handshake and then carries frames, each
+//! `TYPE(1) FLAGS(1) STREAM(4) LENGTH(4) PAYLOAD`. Data payloads are
+//! LZ4-compressed when both nodes agreed to it during the handshake and
+//! compression actually makes them smaller; `FLAG_LZ4` marks those.
+
+use std::net::{Ipv4Addr, Ipv6Addr};
+use anyhow::{anyhow, bail};
+use bytes::Bytes;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::protocol::constants::{SOCKS5_ADDR_DOMAIN, SOCKS5_ADDR_IPV4, SOCKS5_ADDR_IPV6};
+use crate::protocol::TargetAddr;
+use crate::Result;
+
+/// Sent by a node opening a tunnel, ahead of its version
+const MAGIC: &[u8; 4] = b"RPTN";
+const VERSION: u8 = 1;
+
+/// Largest payload one frame carries, before compression
+pub const MAX_PAYLOAD: usize = 64 * 1024;
+
+const HEADER_LEN: usize = 10;
+const FLAG_LZ4: u8 = 0x01;
+const HELLO_LZ4: u8 = 0x01;
+
+const TYPE_OPEN: u8 = 1;
+const TYPE_OPENED: u8 = 2;
+const TYPE_REFUSED: u8 = 3;
+const TYPE_DATA: u8 = 4;
+const TYPE_FIN: u8 = 5;
+const TYPE_RESET: u8 = 6;
+const TYPE_WINDOW: u8 = 7;
+
+/// Handshake status codes
+const STATUS_OK: u8 = 0;
+const STATUS_BAD_TOKEN: u8 = 1;
+const STATUS_BAD_VERSION: u8 = 2;
+
+/// One message on a tunnel connection
+#[derive(Debug, Clone, PartialEq)]
+pub enum Frame {
+    /// Ask the far node to connect stream `id` to `target:port`
+    Open { id: u32, target: TargetAddr, port: u16 },
+    /// The far node connected the stream
+    Opened { id: u32 },
+    /// The far node could not connect the stream, with a SOCKS5 reply code saying why
+    Refused { id: u32, reply_code: u8 },
+    Data { id: u32, payload: Bytes },
+    /// The sender will write no more on the stream
+    Fin { id: u32 },
+    /// The stream is gone in both directions
+    Reset { id: u32 },
+    /// The receiver consumed `increment` more bytes, which the sender may now write
+    Window { id: u32, increment: u32 },
+}
+
+impl Frame {
+    /// The stream the frame belongs to
+    pub fn stream_id(&self) -> u32 {
+        match self {
+            Frame::Open { id, .. }
+            | Frame::Opened { id }
+            | Frame::Refused { id, .. }
+            | Frame::Data { id, .. }
+            | Frame::Fin { id }
+            | Frame::Reset { id }
+            | Frame::Window { id, .. } => *id,
+        }
+    }
+
+    /// Append the frame's wire form to `out`, compressing data if `compress` is set
+    pub fn encode(&self, compress: bool, out: &mut Vec<u8>) {
+        let (kind, mut flags, payload): (u8, u8, std::borrow::Cow<'_, [u8]>) = match self {
+            Frame::Open { target, port, .. } => (TYPE_OPEN, 0, encode_target(target, *port).into()),
+            Frame::Opened { .. } => (TYPE_OPENED, 0, (&[][..]).into()),
+            Frame::Refused { reply_code, .. } => (TYPE_REFUSED, 0, vec![*reply_code].into()),
+            Frame::Data { payload, .. } => (TYPE_DATA, 0, payload.as_ref().into()),
+            Frame::Fin { .. } => (TYPE_FIN, 0, (&[][..]).into()),
+            Frame::Reset { .. } => (TYPE_RESET, 0, (&[][..]).into()),
+            Frame::Window { increment, .. } => (TYPE_WINDOW, 0, increment.to_be_bytes().to_vec().into()),
+        };
+        let payload = match self {
+            Frame::Data { .. } if compress => {
+                let compressed = lz4_flex::compress_prepend_size(&payload);
+                if compressed.len() < payload.len() {
+                    flags |= FLAG_LZ4;
+                    compressed.into()
+                } else {
+                    payload
+                }
+            }
+            _ => payload,
+        };
+        out.push(kind);
+        out.push(flags);
+        out.extend_from_slice(&self.stream_id().to_be_bytes());
+        out.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        out.extend_from_slice(&payload);
+    }
+
+    /// Read the next frame, or `None` if the connection closed between frames
+    pub async fn read<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Option<Frame>> {
+        let mut header = [0u8; HEADER_LEN];
+        match reader.read_exact(&mut header).await {
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        let (kind, flags) = (header[0], header[1]);
+        let id = u32::from_be_bytes(header[2..6].try_into()?);
+        let len = u32::from_be_bytes(header[6..10].try_into()?) as usize;
+        if len > MAX_PAYLOAD {
+            bail!("Tunnel frame of {} bytes exceeds the limit", len);
+        }
+        let mut payload = vec![0u8; len];
+        reader.read_exact(&mut payload).await?;
+
+        let frame = match kind {
+            TYPE_OPEN => {
+                let (target, port) = decode_target(&payload)?;
+                Frame::Open { id, target, port }
+            }
+            TYPE_OPENED => Frame::Opened { id },
+            TYPE_REFUSED => Frame::Refused {
+                id,
+                reply_code: *payload.first().ok_or_else(|| anyhow!("Tunnel refusal without a reply code"))?,
+            },
+            TYPE_DATA if flags & FLAG_LZ4 != 0 => {
+                // Check the size the sender claims before allocating for it
+                let claimed = payload.get(..4)
+                    .map(|size| u32::from_le_bytes(size.try_into().unwrap()) as usize)
+                    .ok_or_else(|| anyhow!("Truncated compressed tunnel frame"))?;
+                if claimed > MAX_PAYLOAD {
+                    bail!("Compressed tunnel frame expands to {} bytes, over the limit", claimed);
+                }
+                let data = lz4_flex::decompress_size_prepended(&payload)
+                    .map_err(|e| anyhow!("Invalid compressed tunnel frame: {}", e))?;
+                Frame::Data { id, payload: data.into() }
+            }
+            TYPE_DATA => Frame::Data { id, payload: payload.into() },
+            TYPE_FIN => Frame::Fin { id },
+            TYPE_RESET => Frame::Reset { id },
+            TYPE_WINDOW => Frame::Window {
+                id,
+                increment: u32::from_be_bytes(payload.get(..4)
+                    .ok_or_else(|| anyhow!("Truncated tunnel window update"))?
+                    .try_into()?),
+            },
+            other => bail!("Unknown tunnel frame type {}", other),
+        };
+        Ok(Some(frame))
+    }
+}
+
+/// `ATYP(1) ADDR PORT(2)`, as in a SOCKS5 request
+fn encode_target(target: &TargetAddr, port: u16) -> Vec<u8> {
+    let mut out = vec![target.address_type()];
+    match target {
+        TargetAddr::Ipv4(ip) => out.extend_from_slice(&ip.octets()),
+        TargetAddr::Ipv6(ip) => out.extend_from_slice(&ip.octets()),
+        TargetAddr::Domain(domain) => {
+            out.push(domain.len() as u8);
+            out.extend_from_slice(domain.as_bytes());
+        }
+    }
+    out.extend_from_slice(&port.to_be_bytes());
+    out
+}
+
+fn decode_target(buf: &[u8]) -> Result<(TargetAddr, u16)> {
+    let truncated = || anyhow!("Truncated target in tunnel open");
+    let (target, rest) = match *buf.first().ok_or_else(truncated)? {
+        SOCKS5_ADDR_IPV4 => {
+            let octets: [u8; 4] = buf.get(1..5).ok_or_else(truncated)?.try_into()?;
+            (TargetAddr::Ipv4(Ipv4Addr::from(octets)), &buf[5..])
+        }
+        SOCKS5_ADDR_IPV6 => {
+            let octets: [u8; 16] = buf.get(1..17).ok_or_else(truncated)?.try_into()?;
+            (TargetAddr::Ipv6(Ipv6Addr::from(octets)), &buf[17..])
+        }
+        SOCKS5_ADDR_DOMAIN => {
+            let len = *buf.get(1).ok_or_else(truncated)? as usize;
+            let name = buf.get(2..2 + len).ok_or_else(truncated)?;
+            let domain = String::from_utf8(name.to_vec()).map_err(|_| anyhow!("Invalid domain in tunnel open"))?;
+            (TargetAddr::Domain(domain), &buf[2 + len..])
+        }
+        other => bail!("Unsupported address type {} in tunnel open", other),
+    };
+    let port: [u8; 2] = rest.get(..2).ok_or_else(truncated)?.try_into()?;
+    Ok((target, u16::from_be_bytes(port)))
+}
+
+/// Why a node refused a tunnel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakeError {
+    BadToken,
+    BadVersion,
+}
+
+impl std::fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HandshakeError::BadToken => write!(f, "tunnel token rejected"),
+            HandshakeError::BadVersion => write!(f, "tunnel protocol version not supported"),
+        }
+    }
+}
+
+impl std::error::Error for HandshakeError {}
+
+/// Open a tunnel: `MAGIC(4) VERSION(1) FLAGS(1) TOKEN_LEN(2) TOKEN`, answered
+/// by `VERSION(1) STATUS(1) FLAGS(1)`
+///
+/// Returns whether data frames are compressed.
+pub async fn client_handshake<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S, token: &str, compress: bool) -> Result<bool> {
+    let mut hello = Vec::with_capacity(8 + token.len());
+    hello.extend_from_slice(MAGIC);
+    hello.push(VERSION);
+    hello.push(if compress { HELLO_LZ4 } else { 0 });
+    hello.extend_from_slice(&(token.len() as u16).to_be_bytes());
+    hello.extend_from_slice(token.as_bytes());
+    stream.write_all(&hello).await?;
+    stream.flush().await?;
+
+    let mut reply = [0u8; 3];
+    stream.read_exact(&mut reply).await?;
+    match reply[1] {
+        STATUS_OK => Ok(reply[2] & HELLO_LZ4 != 0),
+        STATUS_BAD_TOKEN => Err(HandshakeError::BadToken.into()),
+        STATUS_BAD_VERSION => Err(HandshakeError::BadVersion.into()),
+        other => bail!("Unknown tunnel handshake status {}", other),
+    }
+}
+
+/// Accept a tunnel if it presents `token`, returning whether data frames are compressed
+pub async fn server_handshake<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S, token: &str, allow_compression: bool) -> Result<bool> {
+    let mut hello = [0u8; 8];
+    stream.read_exact(&mut hello).await?;
+    if &hello[..4] != MAGIC {
+        bail!("Not a tunnel connection");
+    }
+    let mut presented = vec![0u8; u16::from_be_bytes([hello[6], hello[7]]) as usize];
+    stream.read_exact(&mut presented).await?;
+
+    let (status, compress) = if hello[4] != VERSION {
+        (STATUS_BAD_VERSION, false)
+    } else if !tokens_match(&presented, token.as_bytes()) {
+        (STATUS_BAD_TOKEN, false)
+    } else {
+        (STATUS_OK, allow_compression && hello[5] & HELLO_LZ4 != 0)
+    };
+    stream.write_all(&[VERSION, status, if compress { HELLO_LZ4 } else { 0 }]).await?;
+    stream.flush().await?;
+    match status {
+        STATUS_OK => Ok(compress),
+        STATUS_BAD_TOKEN => Err(HandshakeError::BadToken.into()),
+        _ => Err(HandshakeError::BadVersion.into()),
+    }
+}
+
+/// Compare tokens without revealing how much of a wrong one matched
+fn tokens_match(presented: &[u8], expected: &[u8]) -> bool {
+    presented.len() == expected.len()
+        && presented.iter().zip(expected).fold(0u8, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn round_trip(frame: &Frame, compress: bool) -> (Frame, usize) {
+        let mut wire = Vec::new();
+        frame.encode(compress, &mut wire);
+        let decoded = Frame::read(&mut wire.as_slice()).await.unwrap().unwrap();
+        (decoded, wire.len())
+    }
+
+    #[tokio::test]
+    async fn test_frames_round_trip() {
+        let frames = [
+            Frame::Open { id: 1, target: TargetAddr::Domain("example.com".to_string()), port: 443 },
+            Frame::Open { id: 3, target: TargetAddr::Ipv6(Ipv6Addr::LOCALHOST), port: 80 },
+            Frame::Opened { id: 1 },
+            Frame::Refused { id: 3, reply_code: 5 },
+            Frame::Data { id: 1, payload: Bytes::from_static(b"hello") },
+            Frame::Fin { id: 1 },
+            Frame::Reset { id: 3 },
+            Frame::Window { id: 1, increment: 65536 },
+        ];
+        for frame in &frames {
+            assert_eq!(&round_trip(frame, true).await.0, frame);
+        }
+        assert!(Frame::read(&mut &[][..]).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_compresses_only_when_it_helps() {
+        let repetitive = Frame::Data { id: 1, payload: Bytes::from(vec![b'a'; 4096]) };
+        let (decoded, compressed_len) = round_trip(&repetitive, true).await;
+        assert_eq!(decoded, repetitive);
+        assert!(compressed_len < 200);
+        assert_eq!(round_trip(&repetitive, false).await.1, HEADER_LEN + 4096);
+
+        let tiny = Frame::Data { id: 1, payload: Bytes::from_static(b"x") };
+        assert_eq!(round_trip(&tiny, true).await.1, HEADER_LEN + 1);
+    }
+
+    #[tokio::test]
+    async fn test_handshake_checks_the_token() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let accepted = tokio::spawn(async move { server_handshake(&mut server, "secret", true).await });
+        assert!(client_handshake(&mut client, "secret", true).await.unwrap());
+        assert!(accepted.await.unwrap().unwrap());
+
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let refused = tokio::spawn(async move { server_handshake(&mut server, "secret", true).await });
+        let error = client_handshake(&mut client, "guess", false).await.unwrap_err();
+        assert_eq!(error.downcast_ref::<HandshakeError>(), Some(&HandshakeError::BadToken));
+        assert!(refused.await.unwrap().is_err());
+    }
+}
@@ -0,0 +1,70 @@
+//! Node-to-Node Tunnels
+//!
+//! Two RustProxy nodes can be linked by a tunnel: the near node, facing the
+//! clients, carries every connection routed to a `tunnel` upstream over a
+//! single long-lived connection to the far node, which connects to the
+//! targets. Streams are multiplexed with per-stream flow control, frames are
+//! LZ4-compressed when both nodes agree to it and it makes them smaller, and
+//! the link can be wrapped in TLS. Sharing one connection saves a TCP (and
+//! TLS) handshake per client connection across the high-latency hop, and
+//! compression pays off on the text-heavy traffic most links carry.
+//!
+//! Nodes authenticate each other with a shared token sent in the opening
+//! handshake; use TLS when the link crosses a network you do not trust.
+
+pub mod client;
+pub mod frame;
+pub mod mux;
+pub mod server;
+
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use serde::{Deserialize, Serialize};
+
+pub use client::TunnelConnector;
+pub use frame::HandshakeError;
+pub use mux::{IncomingStream, MuxSession, TunnelStream};
+pub use server::TunnelServer;
+
+/// How a near node reaches a far node, set on a `tunnel` upstream
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub struct TunnelClientConfig {
+    /// Shared secret the far node expects
+    pub token: String,
+    /// Offer LZ4 compression; used only if the far node allows it
+    #[serde(default = "default_compression")]
+    pub compression: bool,
+    /// Wrap the tunnel in TLS
+    #[serde(default)]
+    pub tls: bool,
+    /// Name to verify the far node's certificate against; defaults to its IP address
+    #[serde(default)]
+    pub server_name: Option<String>,
+    /// PEM file of CA certificates to trust instead of the public roots
+    #[serde(default)]
+    pub ca_cert: Option<PathBuf>,
+}
+
+/// Tunnel listener of a far node
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TunnelServerConfig {
+    pub bind_addr: SocketAddr,
+    /// Shared secret near nodes must present
+    pub token: String,
+    /// Allow near nodes to compress the tunnel
+    #[serde(default = "default_compression")]
+    pub compression: bool,
+    /// PEM certificate chain; with `tls_key`, the listener only accepts TLS
+    #[serde(default)]
+    pub tls_cert: Option<PathBuf>,
+    /// PEM private key for `tls_cert`
+    #[serde(default)]
+    pub tls_key: Option<PathBuf>,
+    /// Further certificates, served to near nodes that ask for one of their names
+    #[serde(default)]
+    pub tls_sni_certs: Vec<crate::tls::SniCertConfig>,
+}
+
+fn default_compression() -> bool {
+    true
+}
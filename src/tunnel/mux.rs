@@ -0,0 +1,545 @@
+//! Stream Multiplexing
+//!
+//! A [`MuxSession`] carries many [`TunnelStream`]s over one connection
+//! between two nodes. Each direction of a stream has a window: the sender
+//! may have at most [`WINDOW`] bytes in flight that the receiver has not yet
+//! read, and the receiver hands credit back as it reads. A slow client thus
+//! holds up only its own stream, never the rest of the tunnel.
+
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, Weak};
+use std::task::{Context, Poll, Waker};
+use anyhow::bail;
+use bytes::{Buf, Bytes};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::sync::{mpsc, oneshot, Notify};
+use tracing::debug;
+
+use super::frame::{Frame, MAX_PAYLOAD};
+use crate::protocol::constants::SOCKS5_REPLY_GENERAL_FAILURE;
+use crate::protocol::TargetAddr;
+use crate::routing::UpstreamError;
+use crate::Result;
+
+/// Bytes a stream may have in flight in each direction
+pub const WINDOW: u32 = 256 * 1024;
+
+/// Most bytes of queued frames written to the connection in one go
+const WRITE_BATCH: usize = 256 * 1024;
+
+struct StreamState {
+    received: VecDeque<Bytes>,
+    buffered: usize,
+    /// Bytes read but not yet handed back to the sender as credit
+    unacked: u32,
+    /// Bytes this side may still send
+    credit: u32,
+    /// The far side sent its FIN
+    read_closed: bool,
+    /// This side sent its FIN
+    write_closed: bool,
+    reset: bool,
+    read_waker: Option<Waker>,
+    write_waker: Option<Waker>,
+    /// Answers an open this side is waiting on, with a reply code if refused
+    opened: Option<oneshot::Sender<std::result::Result<(), u8>>>,
+}
+
+impl StreamState {
+    fn new() -> Self {
+        Self {
+            received: VecDeque::new(),
+            buffered: 0,
+            unacked: 0,
+            credit: WINDOW,
+            read_closed: false,
+            write_closed: false,
+            reset: false,
+            read_waker: None,
+            write_waker: None,
+            opened: None,
+        }
+    }
+
+    fn wake(&mut self) {
+        if let Some(waker) = self.read_waker.take() {
+            waker.wake();
+        }
+        if let Some(waker) = self.write_waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+struct Inner {
+    streams: HashMap<u32, StreamState>,
+    next_id: u32,
+    /// Why the connection ended, once it has
+    closed: Option<String>,
+}
+
+struct Shared {
+    inner: Mutex<Inner>,
+    frames: mpsc::UnboundedSender<Frame>,
+    peer: SocketAddr,
+    shutdown: Notify,
+}
+
+impl Shared {
+    fn send(&self, frame: Frame) {
+        let _ = self.frames.send(frame);
+    }
+
+    /// Fail every stream and pending open once the connection is gone
+    fn close(&self, reason: String) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.closed.get_or_insert(reason);
+        for state in inner.streams.values_mut() {
+            state.opened = None;
+            state.wake();
+        }
+    }
+
+    /// Apply a frame from the far side
+    fn dispatch(self: &Arc<Self>, frame: Frame, incoming: &mpsc::UnboundedSender<IncomingStream>) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        match frame {
+            Frame::Open { id, target, port } => {
+                if inner.streams.contains_key(&id) {
+                    bail!("stream {} opened twice", id);
+                }
+                inner.streams.insert(id, StreamState::new());
+                drop(inner);
+                let stream = TunnelStream { id, shared: Arc::clone(self) };
+                // Nobody accepting drops the stream, which resets it
+                let _ = incoming.send(IncomingStream { target, port, stream });
+            }
+            Frame::Opened { id } => {
+                if let Some(opened) = inner.streams.get_mut(&id).and_then(|state| state.opened.take()) {
+                    let _ = opened.send(Ok(()));
+                }
+            }
+            Frame::Refused { id, reply_code } => {
+                if let Some(opened) = inner.streams.remove(&id).and_then(|state| state.opened) {
+                    let _ = opened.send(Err(reply_code));
+                }
+            }
+            Frame::Data { id, payload } => {
+                let Some(state) = inner.streams.get_mut(&id) else {
+                    return Ok(());
+                };
+                if state.reset || state.read_closed {
+                    return Ok(());
+                }
+                state.buffered += payload.len();
+                if state.buffered > WINDOW as usize {
+                    // The sender ignored its window; drop the stream rather than buffer without bound
+                    debug!("Tunnel stream {} to {} overran its window, resetting", id, self.peer);
+                    state.reset = true;
+                    state.wake();
+                    self.send(Frame::Reset { id });
+                } else {
+                    state.received.push_back(payload);
+                    if let Some(waker) = state.read_waker.take() {
+                        waker.wake();
+                    }
+                }
+            }
+            Frame::Fin { id } => {
+                if let Some(state) = inner.streams.get_mut(&id) {
+                    state.read_closed = true;
+                    if let Some(waker) = state.read_waker.take() {
+                        waker.wake();
+                    }
+                }
+            }
+            Frame::Reset { id } => {
+                if let Some(state) = inner.streams.get_mut(&id) {
+                    state.reset = true;
+                    if let Some(opened) = state.opened.take() {
+                        let _ = opened.send(Err(SOCKS5_REPLY_GENERAL_FAILURE));
+                    }
+                    state.wake();
+                }
+            }
+            Frame::Window { id, increment } => {
+                if let Some(state) = inner.streams.get_mut(&id) {
+                    state.credit = state.credit.saturating_add(increment);
+                    if let Some(waker) = state.write_waker.take() {
+                        waker.wake();
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Many streams over one connection to another node
+///
+/// Dropping the session closes the connection once its streams are done with it.
+pub struct MuxSession {
+    shared: Arc<Shared>,
+}
+
+impl MuxSession {
+    /// Multiplex streams over `io`, a connection that finished its handshake
+    ///
+    /// Streams the far side opens arrive on the returned receiver.
+    pub fn new<S>(io: S, peer: SocketAddr, compress: bool) -> (Self, mpsc::UnboundedReceiver<IncomingStream>)
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (reader, writer) = tokio::io::split(io);
+        let (frames, queued) = mpsc::unbounded_channel();
+        let (incoming, accepted) = mpsc::unbounded_channel();
+        let shared = Arc::new(Shared {
+            inner: Mutex::new(Inner { streams: HashMap::new(), next_id: 1, closed: None }),
+            frames,
+            peer,
+            shutdown: Notify::new(),
+        });
+        tokio::spawn(write_frames(writer, queued, compress, Arc::downgrade(&shared)));
+        tokio::spawn(read_frames(reader, Arc::clone(&shared), incoming));
+        (Self { shared }, accepted)
+    }
+
+    /// Ask the far node to connect a new stream to `target:port`
+    pub async fn open(&self, target: &TargetAddr, port: u16) -> Result<TunnelStream> {
+        let peer = self.shared.peer;
+        let (opened, answer) = oneshot::channel();
+        let id = {
+            let mut inner = self.shared.inner.lock().unwrap();
+            if let Some(reason) = &inner.closed {
+                return Err(UpstreamError::Unreachable { proxy: peer, reason: reason.clone() }.into());
+            }
+            let id = inner.next_id;
+            inner.next_id = inner.next_id.wrapping_add(2);
+            let mut state = StreamState::new();
+            state.opened = Some(opened);
+            inner.streams.insert(id, state);
+            id
+        };
+        // From here on, giving up on the open resets the stream
+        let stream = TunnelStream { id, shared: Arc::clone(&self.shared) };
+        self.shared.send(Frame::Open { id, target: target.clone(), port });
+        match answer.await {
+            Ok(Ok(())) => Ok(stream),
+            Ok(Err(reply_code)) => Err(UpstreamError::Refused { proxy: peer, reply_code }.into()),
+            Err(_) => Err(UpstreamError::Unreachable { proxy: peer, reason: "tunnel closed".to_string() }.into()),
+        }
+    }
+
+    /// Whether the connection is gone, so no more streams can be opened
+    pub fn is_closed(&self) -> bool {
+        self.shared.inner.lock().unwrap().closed.is_some()
+    }
+
+    /// Streams currently open on the session
+    pub fn stream_count(&self) -> usize {
+        self.shared.inner.lock().unwrap().streams.len()
+    }
+}
+
+impl Drop for MuxSession {
+    fn drop(&mut self) {
+        self.shared.shutdown.notify_one();
+    }
+}
+
+/// A stream the far node asked for, to be accepted once its target is reached or refused
+pub struct IncomingStream {
+    pub target: TargetAddr,
+    pub port: u16,
+    stream: TunnelStream,
+}
+
+impl IncomingStream {
+    /// Tell the far node the stream is connected
+    pub fn accept(self) -> TunnelStream {
+        self.stream.shared.send(Frame::Opened { id: self.stream.id });
+        self.stream
+    }
+
+    /// Tell the far node the target could not be reached, with a SOCKS5 reply code saying why
+    pub fn refuse(self, reply_code: u8) {
+        let id = self.stream.id;
+        self.stream.shared.inner.lock().unwrap().streams.remove(&id);
+        self.stream.shared.send(Frame::Refused { id, reply_code });
+    }
+}
+
+/// One stream of a [`MuxSession`]; dropping it before both sides finished resets it
+pub struct TunnelStream {
+    id: u32,
+    shared: Arc<Shared>,
+}
+
+impl TunnelStream {
+    /// The node at the other end of the tunnel
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.shared.peer
+    }
+}
+
+impl AsyncRead for TunnelStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let mut inner = self.shared.inner.lock().unwrap();
+        let closed = inner.closed.is_some();
+        let Some(state) = inner.streams.get_mut(&self.id) else {
+            return Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()));
+        };
+        if !state.received.is_empty() {
+            let mut copied = 0;
+            while buf.remaining() > 0 {
+                let Some(chunk) = state.received.front_mut() else {
+                    break;
+                };
+                let n = chunk.len().min(buf.remaining());
+                buf.put_slice(&chunk[..n]);
+                chunk.advance(n);
+                if chunk.is_empty() {
+                    state.received.pop_front();
+                }
+                copied += n;
+            }
+            state.buffered -= copied;
+            state.unacked += copied as u32;
+            // Hand credit back in batches rather than for every read
+            if state.unacked >= WINDOW / 2 && !state.read_closed && !state.reset {
+                let increment = std::mem::take(&mut state.unacked);
+                self.shared.send(Frame::Window { id: self.id, increment });
+            }
+            return Poll::Ready(Ok(()));
+        }
+        if state.reset {
+            return Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()));
+        }
+        if state.read_closed {
+            return Poll::Ready(Ok(()));
+        }
+        if closed {
+            return Poll::Ready(Err(io::ErrorKind::ConnectionAborted.into()));
+        }
+        state.read_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl AsyncWrite for TunnelStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let mut inner = self.shared.inner.lock().unwrap();
+        let closed = inner.closed.is_some();
+        let Some(state) = inner.streams.get_mut(&self.id) else {
+            return Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()));
+        };
+        if state.reset {
+            return Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()));
+        }
+        if closed || state.write_closed {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        if state.credit == 0 {
+            state.write_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let n = buf.len().min(state.credit as usize).min(MAX_PAYLOAD);
+        state.credit -= n as u32;
+        self.shared.send(Frame::Data { id: self.id, payload: Bytes::copy_from_slice(&buf[..n]) });
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut inner = self.shared.inner.lock().unwrap();
+        if let Some(state) = inner.streams.get_mut(&self.id) {
+            if !state.write_closed && !state.reset {
+                state.write_closed = true;
+                self.shared.send(Frame::Fin { id: self.id });
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for TunnelStream {
+    fn drop(&mut self) {
+        let Ok(mut inner) = self.shared.inner.lock() else {
+            return;
+        };
+        let connected = inner.closed.is_none();
+        if let Some(state) = inner.streams.remove(&self.id) {
+            if connected && !state.reset && !(state.read_closed && state.write_closed) {
+                self.shared.send(Frame::Reset { id: self.id });
+            }
+        }
+    }
+}
+
+/// Write queued frames until every sender is gone or the connection fails
+async fn write_frames<W: AsyncWrite + Unpin>(
+    mut writer: W,
+    mut queued: mpsc::UnboundedReceiver<Frame>,
+    compress: bool,
+    shared: Weak<Shared>,
+) {
+    let mut out = Vec::new();
+    while let Some(frame) = queued.recv().await {
+        out.clear();
+        frame.encode(compress, &mut out);
+        // Gather whatever else is queued into the same write
+        while out.len() < WRITE_BATCH {
+            match queued.try_recv() {
+                Ok(frame) => frame.encode(compress, &mut out),
+                Err(_) => break,
+            }
+        }
+        if let Err(e) = writer.write_all(&out).await {
+            if let Some(shared) = shared.upgrade() {
+                shared.close(format!("tunnel write failed: {}", e));
+                shared.shutdown.notify_one();
+            }
+            return;
+        }
+    }
+    let _ = writer.shutdown().await;
+}
+
+/// Dispatch frames from the far node until the connection ends
+async fn read_frames<R: AsyncRead + Unpin>(
+    mut reader: R,
+    shared: Arc<Shared>,
+    incoming: mpsc::UnboundedSender<IncomingStream>,
+) {
+    let reason = loop {
+        let frame = tokio::select! {
+            frame = Frame::read(&mut reader) => frame,
+            _ = shared.shutdown.notified() => break "tunnel closed".to_string(),
+        };
+        match frame {
+            Ok(Some(frame)) => {
+                if let Err(e) = shared.dispatch(frame, &incoming) {
+                    break format!("tunnel protocol error: {}", e);
+                }
+            }
+            Ok(None) => break "tunnel closed by peer".to_string(),
+            Err(e) => break format!("tunnel read failed: {}", e),
+        }
+    };
+    debug!("Tunnel to {} ended: {}", shared.peer, reason);
+    shared.close(reason);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    fn pair() -> (MuxSession, MuxSession, mpsc::UnboundedReceiver<IncomingStream>) {
+        let (near, far) = tokio::io::duplex(64 * 1024);
+        let peer: SocketAddr = "127.0.0.1:1443".parse().unwrap();
+        let (client, _) = MuxSession::new(near, peer, true);
+        let (server, incoming) = MuxSession::new(far, peer, true);
+        (client, server, incoming)
+    }
+
+    #[tokio::test]
+    async fn test_streams_carry_data_both_ways_and_half_close() {
+        let (client, _server, mut incoming) = pair();
+        let target = TargetAddr::Domain("example.com".to_string());
+
+        let opening = tokio::spawn(async move {
+            let request = incoming.recv().await.unwrap();
+            assert_eq!((request.target.clone(), request.port), (TargetAddr::Domain("example.com".to_string()), 443));
+            let mut stream = request.accept();
+            let mut received = Vec::new();
+            stream.read_to_end(&mut received).await.unwrap();
+            stream.write_all(&received).await.unwrap();
+            stream.shutdown().await.unwrap();
+        });
+
+        let mut stream = client.open(&target, 443).await.unwrap();
+        stream.write_all(b"ping").await.unwrap();
+        stream.shutdown().await.unwrap();
+        let mut echoed = Vec::new();
+        stream.read_to_end(&mut echoed).await.unwrap();
+        assert_eq!(echoed, b"ping");
+        opening.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_refused_open_reports_the_reply_code() {
+        let (client, _server, mut incoming) = pair();
+        tokio::spawn(async move {
+            incoming.recv().await.unwrap().refuse(5);
+        });
+        let error = client.open(&TargetAddr::Domain("example.com".to_string()), 443).await.err().unwrap();
+        assert!(matches!(error.downcast_ref::<UpstreamError>(), Some(UpstreamError::Refused { reply_code: 5, .. })));
+        assert_eq!(client.stream_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_a_stalled_stream_does_not_hold_up_the_others() {
+        let (client, _server, mut incoming) = pair();
+        let accepting = tokio::spawn(async move {
+            let stalled = incoming.recv().await.unwrap().accept();
+            let mut active = incoming.recv().await.unwrap().accept();
+            let mut buf = [0u8; 4];
+            active.read_exact(&mut buf).await.unwrap();
+            active.write_all(&buf).await.unwrap();
+            // Only now read the stalled stream, which must have stopped at its window
+            (stalled, buf)
+        });
+
+        let target = TargetAddr::Domain("example.com".to_string());
+        let mut stalled = client.open(&target, 80).await.unwrap();
+        let writing = tokio::time::timeout(std::time::Duration::from_millis(200), async {
+            let chunk = vec![7u8; 64 * 1024];
+            loop {
+                stalled.write_all(&chunk).await.unwrap();
+            }
+        }).await;
+        assert!(writing.is_err(), "writes past the window should wait for credit");
+
+        let mut active = client.open(&target, 443).await.unwrap();
+        active.write_all(b"pong").await.unwrap();
+        let mut buf = [0u8; 4];
+        active.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"pong");
+
+        let (mut stalled_far, _) = accepting.await.unwrap();
+        let mut first = vec![0u8; WINDOW as usize];
+        stalled_far.read_exact(&mut first).await.unwrap();
+        assert!(first.iter().all(|&b| b == 7));
+    }
+
+    #[tokio::test]
+    async fn test_streams_fail_when_the_connection_drops() {
+        let (client, server, mut incoming) = pair();
+        let accepting = tokio::spawn(async move { incoming.recv().await.unwrap().accept() });
+        let mut stream = client.open(&TargetAddr::Domain("example.com".to_string()), 443).await.unwrap();
+        let far = accepting.await.unwrap();
+
+        drop(far);
+        drop(server);
+        let mut buf = [0u8; 1];
+        assert!(stream.read(&mut buf).await.is_err());
+        for _ in 0..100 {
+            if client.is_closed() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert!(client.is_closed());
+        assert!(client.open(&TargetAddr::Domain("example.com".to_string()), 443).await.is_err());
+    }
+}
@@ -0,0 +1,148 @@
+//! Tunnel Server
+//!
+//! Accepts tunnels from near nodes and connects each stream they open to
+//! its target. Near nodes that present the token are trusted to have
+//! applied their own access control; this node still refuses targets that
+//! lead back into itself and, if private range protection is on, into the
+//! internal network.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use anyhow::{bail, Context};
+use notify::RecommendedWatcher;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, info, warn};
+
+use super::frame::server_handshake;
+use super::mux::{IncomingStream, MuxSession};
+use super::TunnelServerConfig;
+use crate::relay::RelayEngine;
+use crate::tls::{CertificateFiles, ReloadingCertResolver};
+use crate::{Config, Result};
+
+/// Listener for tunnels from near nodes
+pub struct TunnelServer {
+    settings: TunnelServerConfig,
+    engine: Arc<RelayEngine>,
+    tls: Option<TlsAcceptor>,
+    /// Reloads the certificates while the listener runs
+    _cert_watcher: Option<RecommendedWatcher>,
+    handshake_timeout: std::time::Duration,
+}
+
+impl TunnelServer {
+    /// Server for `config.server.tunnel`
+    pub fn new(config: &Config) -> Result<Self> {
+        let Some(settings) = config.server.tunnel.clone() else {
+            bail!("No tunnel listener configured");
+        };
+        let files = CertificateFiles::from_settings(
+            settings.tls_cert.as_deref(),
+            settings.tls_key.as_deref(),
+            &settings.tls_sni_certs,
+            "Tunnel",
+        )?;
+        let (tls, cert_watcher) = match files {
+            Some(files) => {
+                let resolver = Arc::new(
+                    ReloadingCertResolver::new(files).context("Failed to load tunnel certificates")?,
+                );
+                let watcher = match resolver.watch() {
+                    Ok(watcher) => Some(watcher),
+                    Err(e) => {
+                        warn!("Tunnel certificates will not reload on change: {:#}", e);
+                        None
+                    }
+                };
+                (Some(resolver.acceptor()?), watcher)
+            }
+            None => (None, None),
+        };
+        Ok(Self {
+            settings,
+            engine: Arc::new(RelayEngine::from_config(config)),
+            tls,
+            _cert_watcher: cert_watcher,
+            handshake_timeout: config.server.handshake_timeout,
+        })
+    }
+
+    /// Bind the configured address and serve tunnels on it
+    pub async fn start(self) -> Result<()> {
+        let listener = TcpListener::bind(self.settings.bind_addr)
+            .await
+            .with_context(|| format!("Failed to bind tunnel listener to {}", self.settings.bind_addr))?;
+        self.serve(listener).await
+    }
+
+    /// Serve tunnels accepted from `listener`
+    pub async fn serve(self, listener: TcpListener) -> Result<()> {
+        info!("Tunnel listener on {} (tls: {})", listener.local_addr()?, self.tls.is_some());
+        let server = Arc::new(self);
+        loop {
+            let (stream, peer) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!("Failed to accept tunnel connection: {}", e);
+                    continue;
+                }
+            };
+            let server = server.clone();
+            tokio::spawn(async move {
+                let result = match &server.tls {
+                    Some(acceptor) => match tokio::time::timeout(server.handshake_timeout, acceptor.accept(stream)).await {
+                        Ok(Ok(stream)) => server.run_tunnel(stream, peer).await,
+                        Ok(Err(e)) => Err(anyhow::Error::new(e).context("TLS handshake failed")),
+                        Err(_) => Err(anyhow::anyhow!("TLS handshake timed out")),
+                    },
+                    None => server.run_tunnel(stream, peer).await,
+                };
+                if let Err(e) = result {
+                    warn!("Tunnel from {} failed: {:#}", peer, e);
+                }
+            });
+        }
+    }
+
+    /// Authenticate a near node, then connect the streams it opens until it goes away
+    async fn run_tunnel<S>(self: &Arc<Self>, mut stream: S, peer: SocketAddr) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let handshake = server_handshake(&mut stream, &self.settings.token, self.settings.compression);
+        let compressed = match tokio::time::timeout(self.handshake_timeout, handshake).await {
+            Ok(result) => result?,
+            Err(_) => bail!("Tunnel handshake timed out"),
+        };
+        info!("Accepted tunnel from {} (compression: {})", peer, compressed);
+
+        let (_session, mut incoming) = MuxSession::new(stream, peer, compressed);
+        while let Some(request) = incoming.recv().await {
+            let server = self.clone();
+            tokio::spawn(async move { server.connect_stream(request, peer).await });
+        }
+        info!("Tunnel from {} closed", peer);
+        Ok(())
+    }
+
+    /// Connect a stream to its target and relay it, or refuse it with the reason
+    async fn connect_stream(&self, request: IncomingStream, peer: SocketAddr) {
+        let (target, port) = (request.target.clone(), request.port);
+        let (mut target_stream, resolved) = match self.engine.connect_to_target(&target, port).await {
+            Ok(connected) => connected,
+            Err(e) => {
+                debug!("Tunnel from {} could not reach {:?}:{}: {:#}", peer, target, port, e);
+                request.refuse(self.engine.connection_error_to_socks5_code(&e));
+                return;
+            }
+        };
+        debug!("Tunnel from {} connected to {:?}:{} ({})", peer, target, port, resolved);
+        let mut stream = request.accept();
+        match tokio::io::copy_bidirectional(&mut stream, &mut target_stream).await {
+            Ok((up, down)) => debug!("Tunnel stream to {} closed after {} bytes up, {} bytes down", resolved, up, down),
+            Err(e) => debug!("Tunnel stream to {} failed: {}", resolved, e),
+        }
+    }
+}
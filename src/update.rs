@@ -0,0 +1,288 @@
+//! Release Update Checks
+//!
+//! Optionally fetches a release manifest from `monitoring.update_check` in
+//! the background and compares it with the running version. A newer release
+//! or a security advisory that applies to this build is logged, served from
+//! `GET /api/v1/version` and shown by `rustproxy status`. Nothing is ever
+//! downloaded or installed; operators decide when to upgrade.
+//!
+//! The manifest is JSON:
+//!
+//! ```json
+//! {
+//!   "latest": "1.4.0",
+//!   "url": "https://example.com/rustproxy/releases/1.4.0",
+//!   "advisories": [
+//!     { "id": "RP-2026-01", "severity": "critical", "summary": "...", "fixed_in": "1.3.2" }
+//!   ]
+//! }
+//! ```
+
+use crate::config::UpdateCheckConfig;
+use crate::Result;
+use anyhow::{anyhow, Context};
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::fmt;
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+use tokio::task::JoinHandle;
+use tracing::{debug, error, warn};
+
+/// Version of this build
+pub const CURRENT_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Release manifest published by the maintainers
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ReleaseManifest {
+    /// Newest released version
+    pub latest: String,
+    /// Release notes or download page
+    #[serde(default)]
+    pub url: Option<String>,
+    #[serde(default)]
+    pub advisories: Vec<Advisory>,
+}
+
+/// Security advisory listed in the manifest
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Advisory {
+    pub id: String,
+    #[serde(default)]
+    pub severity: Option<String>,
+    pub summary: String,
+    /// First version the issue appeared in; all earlier ones when unset
+    #[serde(default)]
+    pub introduced_in: Option<String>,
+    /// First version with the fix; every later version when unset
+    #[serde(default)]
+    pub fixed_in: Option<String>,
+    #[serde(default)]
+    pub url: Option<String>,
+}
+
+impl Advisory {
+    /// Whether the advisory applies to `version`
+    pub fn affects(&self, version: &Version) -> bool {
+        let introduced = self.introduced_in.as_deref().and_then(Version::parse);
+        let fixed = self.fixed_in.as_deref().and_then(Version::parse);
+        introduced.is_none_or(|introduced| *version >= introduced) && fixed.is_none_or(|fixed| *version < fixed)
+    }
+}
+
+/// Dotted numeric release version; pre-release and build suffixes are ignored
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Version {
+    major: u64,
+    minor: u64,
+    patch: u64,
+}
+
+impl Version {
+    /// Parse versions like `1.4`, `1.4.0` or `v1.4.0-rc1`
+    pub fn parse(text: &str) -> Option<Self> {
+        let text = text.trim().trim_start_matches('v');
+        let core = text.split(['-', '+']).next()?;
+        let mut parts = core.split('.').map(|part| part.parse::<u64>());
+        let major = parts.next()?.ok()?;
+        let minor = parts.next().transpose().ok()?.unwrap_or(0);
+        let patch = parts.next().transpose().ok()?.unwrap_or(0);
+        if parts.next().is_some() {
+            return None;
+        }
+        Some(Self { major, minor, patch })
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+/// What the last update check found
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct VersionReport {
+    pub current: String,
+    /// Newest version in the manifest, once a check succeeded
+    pub latest: Option<String>,
+    pub update_available: bool,
+    pub release_url: Option<String>,
+    /// Advisories that apply to the running version
+    pub advisories: Vec<Advisory>,
+    /// RFC 3339 time of the last successful check
+    pub checked_at: Option<String>,
+    /// Why the last check failed
+    pub error: Option<String>,
+}
+
+impl VersionReport {
+    /// Report for a server that does not check for updates
+    pub fn unchecked() -> Self {
+        Self { current: CURRENT_VERSION.to_string(), ..Default::default() }
+    }
+
+    /// Compare a manifest with the `current` version
+    pub fn evaluate(current: &str, manifest: &ReleaseManifest) -> Result<Self> {
+        let running = Version::parse(current)
+            .ok_or_else(|| anyhow!("Cannot parse running version '{}'", current))?;
+        let latest = Version::parse(&manifest.latest)
+            .ok_or_else(|| anyhow!("Cannot parse manifest version '{}'", manifest.latest))?;
+
+        Ok(Self {
+            current: current.to_string(),
+            latest: Some(manifest.latest.clone()),
+            update_available: latest.cmp(&running) == Ordering::Greater,
+            release_url: manifest.url.clone(),
+            advisories: manifest.advisories.iter()
+                .filter(|advisory| advisory.affects(&running))
+                .cloned()
+                .collect(),
+            checked_at: Some(humantime::format_rfc3339_seconds(SystemTime::now()).to_string()),
+            error: None,
+        })
+    }
+}
+
+/// Periodically checks the release manifest
+pub struct UpdateChecker {
+    config: UpdateCheckConfig,
+    client: reqwest::Client,
+    report: RwLock<VersionReport>,
+}
+
+impl UpdateChecker {
+    /// Create a checker for the configured manifest
+    pub fn new(config: UpdateCheckConfig) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(config.timeout)
+            .user_agent(format!("rustproxy/{}", CURRENT_VERSION))
+            .build()
+            .context("Failed to build update check HTTP client")?;
+        Ok(Self { config, client, report: RwLock::new(VersionReport::unchecked()) })
+    }
+
+    /// Result of the most recent check
+    pub fn report(&self) -> VersionReport {
+        self.report.read().unwrap().clone()
+    }
+
+    /// Check now and every `interval` after
+    pub fn spawn(self: &Arc<Self>) -> JoinHandle<()> {
+        let checker = Arc::clone(self);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(checker.config.interval);
+            loop {
+                interval.tick().await;
+                checker.check().await;
+            }
+        })
+    }
+
+    /// Fetch the manifest once and log anything new
+    pub async fn check(&self) {
+        match self.fetch().await.and_then(|manifest| VersionReport::evaluate(CURRENT_VERSION, &manifest)) {
+            Ok(report) => {
+                let previous = self.report();
+                if report.update_available && report.latest != previous.latest {
+                    warn!("RustProxy {} is available (running {}){}",
+                          report.latest.as_deref().unwrap_or_default(), report.current,
+                          report.release_url.as_deref().map(|url| format!(": {}", url)).unwrap_or_default());
+                }
+                for advisory in report.advisories.iter().filter(|advisory| !previous.advisories.contains(advisory)) {
+                    error!("Security advisory {} ({}) affects RustProxy {}: {}{}",
+                           advisory.id, advisory.severity.as_deref().unwrap_or("unrated"), report.current, advisory.summary,
+                           advisory.fixed_in.as_deref().map(|fixed| format!(", fixed in {}", fixed)).unwrap_or_default());
+                }
+                debug!("Update check found latest version {:?}", report.latest);
+                *self.report.write().unwrap() = report;
+            }
+            Err(e) => {
+                warn!("Update check against {} failed: {:#}", self.config.manifest_url, e);
+                // Keep what the last good check found
+                self.report.write().unwrap().error = Some(format!("{:#}", e));
+            }
+        }
+    }
+
+    async fn fetch(&self) -> Result<ReleaseManifest> {
+        let response = self.client.get(&self.config.manifest_url).send().await
+            .context("Cannot reach release manifest")?;
+        if !response.status().is_success() {
+            return Err(anyhow!("Release manifest returned {}", response.status()));
+        }
+        response.json().await.context("Invalid release manifest")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn advisory(introduced_in: Option<&str>, fixed_in: Option<&str>) -> Advisory {
+        Advisory {
+            id: "RP-1".to_string(),
+            severity: Some("critical".to_string()),
+            summary: "Auth bypass".to_string(),
+            introduced_in: introduced_in.map(str::to_string),
+            fixed_in: fixed_in.map(str::to_string),
+            url: None,
+        }
+    }
+
+    #[test]
+    fn test_version_parsing() {
+        assert_eq!(Version::parse("v1.4"), Version::parse("1.4.0"));
+        assert_eq!(Version::parse("1.4.0-rc1").unwrap().to_string(), "1.4.0");
+        assert!(Version::parse("1.10.0") > Version::parse("1.9.3"));
+        assert!(Version::parse("1.x").is_none());
+        assert!(Version::parse("1.2.3.4").is_none());
+    }
+
+    #[test]
+    fn test_manifest_evaluation() {
+        let manifest = ReleaseManifest {
+            latest: "1.4.0".to_string(),
+            url: Some("https://example.com/1.4.0".to_string()),
+            advisories: vec![
+                advisory(None, Some("1.3.2")),
+                advisory(Some("1.1.0"), Some("1.2.0")),
+                advisory(Some("1.3.0"), None),
+            ],
+        };
+
+        let report = VersionReport::evaluate("1.3.0", &manifest).unwrap();
+        assert!(report.update_available);
+        assert_eq!(report.advisories, [manifest.advisories[0].clone(), manifest.advisories[2].clone()]);
+
+        let report = VersionReport::evaluate("1.4.0", &manifest).unwrap();
+        assert!(!report.update_available);
+        assert_eq!(report.advisories, [manifest.advisories[2].clone()]);
+    }
+
+    #[tokio::test]
+    async fn test_check_fetches_manifest() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let manifest = serde_json::json!({
+            "latest": "999.0.0",
+            "advisories": [{ "id": "RP-1", "summary": "Auth bypass", "fixed_in": "999.0.0" }],
+        });
+        let router = axum::Router::new()
+            .route("/manifest.json", axum::routing::get(move || async move { axum::Json(manifest) }));
+        tokio::spawn(async move { axum::serve(listener, router).await });
+
+        let checker = UpdateChecker::new(UpdateCheckConfig {
+            manifest_url: format!("http://{}/manifest.json", addr),
+            interval: std::time::Duration::from_secs(3600),
+            timeout: std::time::Duration::from_secs(5),
+        }).unwrap();
+        assert!(checker.report().latest.is_none());
+
+        checker.check().await;
+        let report = checker.report();
+        assert!(report.update_available);
+        assert_eq!(report.advisories.len(), 1);
+        assert!(report.error.is_none());
+    }
+}
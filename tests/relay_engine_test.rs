@@ -125,4 +125,274 @@ async fn test_connection_stats() {
     assert_eq!(stats.bytes_down, 2048);
     assert_eq!(stats.total_bytes, 3072);
     assert_eq!(stats.user_id, Some("test_user".to_string()));
-}
\ No newline at end of file
+}
+#[tokio::test]
+async fn test_connection_with_dscp_marking() {
+    let relay_engine = RelayEngine::new().with_dscp(Some(46));
+    
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let server_addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let _ = listener.accept().await;
+    });
+    
+    let target_addr = TargetAddr::Ipv4(Ipv4Addr::new(127, 0, 0, 1));
+    let result = relay_engine.connect_to_target(&target_addr, server_addr.port()).await;
+    assert!(result.is_ok(), "DSCP marking should not prevent connecting");
+}
+
+#[tokio::test]
+async fn test_relay_ends_at_max_lifetime() {
+    use rustproxy::config::SessionExpiryAction;
+    use std::time::Duration;
+
+    // Client side of the relay
+    let client_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let client_addr = client_listener.local_addr().unwrap();
+    let _client_peer = TcpStream::connect(client_addr).await.unwrap();
+    let (client, _) = client_listener.accept().await.unwrap();
+
+    // Target side of the relay
+    let target_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target_listener.local_addr().unwrap();
+    let target = TcpStream::connect(target_addr).await.unwrap();
+    let (_target_peer, _) = target_listener.accept().await.unwrap();
+
+    let relay_engine = RelayEngine::new()
+        .with_max_lifetime(Duration::from_millis(100), SessionExpiryAction::Terminate);
+
+    // Both ends stay idle, so only the lifetime limit can end the relay
+    let result = tokio::time::timeout(
+        Duration::from_secs(5),
+        relay_engine.start_complete_relay_with_user(client, target, Some("test_user".to_string())),
+    ).await.expect("relay should end at its maximum lifetime");
+    assert!(result.is_err());
+}
+
+#[tokio::test]
+async fn test_half_close_still_delivers_the_response() {
+    use rustproxy::relay::BandwidthLimit;
+
+    let limit = BandwidthLimit { download_bytes_per_second: Some(1_000_000), ..Default::default() };
+    for relay_engine in [RelayEngine::new(), RelayEngine::new().with_bandwidth_limit(limit)] {
+        let client_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client_peer = TcpStream::connect(client_listener.local_addr().unwrap()).await.unwrap();
+        let (client, _) = client_listener.accept().await.unwrap();
+        let target_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = TcpStream::connect(target_listener.local_addr().unwrap()).await.unwrap();
+        let (mut target_peer, _) = target_listener.accept().await.unwrap();
+        let relay = tokio::spawn(async move { relay_engine.start_complete_relay_with_user(client, target, None).await });
+
+        // The client sends its request and shuts down writing, as HTTP/1.0 clients do
+        client_peer.write_all(b"GET / HTTP/1.0\r\n\r\n").await.unwrap();
+        client_peer.shutdown().await.unwrap();
+
+        // The target sees the end of the request, then answers
+        let mut request = Vec::new();
+        target_peer.read_to_end(&mut request).await.unwrap();
+        assert_eq!(request, b"GET / HTTP/1.0\r\n\r\n");
+        target_peer.write_all(&vec![b'x'; 100_000]).await.unwrap();
+        drop(target_peer);
+
+        let mut response = Vec::new();
+        client_peer.read_to_end(&mut response).await.unwrap();
+        assert_eq!(response.len(), 100_000);
+        let stats = relay.await.unwrap().unwrap();
+        assert_eq!((stats.bytes_up, stats.bytes_down), (18, 100_000));
+    }
+}
+
+#[tokio::test]
+async fn test_cancelled_relay_sends_both_ends_a_fin() {
+    use rustproxy::relay::RelayHandle;
+    use std::time::Duration;
+
+    let client_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mut client_peer = TcpStream::connect(client_listener.local_addr().unwrap()).await.unwrap();
+    let (client, _) = client_listener.accept().await.unwrap();
+    let target_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target = TcpStream::connect(target_listener.local_addr().unwrap()).await.unwrap();
+    let (mut target_peer, _) = target_listener.accept().await.unwrap();
+
+    let handle = RelayHandle::new();
+    let relay_engine = RelayEngine::new().with_handle(handle.clone());
+    let relay = tokio::spawn(async move { relay_engine.start_complete_relay_with_user(client, target, None).await });
+
+    client_peer.write_all(b"ping").await.unwrap();
+    let mut buf = [0u8; 4];
+    target_peer.read_exact(&mut buf).await.unwrap();
+    assert!(handle.idle_for() < Duration::from_secs(1));
+
+    assert!(handle.cancel("idle"));
+    assert!(relay.await.unwrap().is_err());
+
+    // Both ends read a clean end of stream rather than a reset
+    assert_eq!(client_peer.read(&mut buf).await.unwrap(), 0);
+    assert_eq!(target_peer.read(&mut buf).await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn test_busy_relay_closes_at_the_connection_lifetime() {
+    use rustproxy::metrics::Metrics;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    let client_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mut client_peer = TcpStream::connect(client_listener.local_addr().unwrap()).await.unwrap();
+    let (client, _) = client_listener.accept().await.unwrap();
+    let target_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target = TcpStream::connect(target_listener.local_addr().unwrap()).await.unwrap();
+    let (mut target_peer, _) = target_listener.accept().await.unwrap();
+
+    let started = Instant::now();
+    let metrics = Arc::new(Metrics::new());
+    let relay_engine = RelayEngine::new()
+        .with_observer(metrics.clone())
+        .with_connection_lifetime(Some(Duration::from_millis(400)), Duration::from_millis(300));
+    let relay = tokio::spawn(async move { relay_engine.start_complete_relay_with_user(client, target, None).await });
+
+    // Traffic keeps flowing until the lifetime is up
+    let mut buf = [0u8; 4];
+    while started.elapsed() < Duration::from_millis(250) {
+        client_peer.write_all(b"ping").await.unwrap();
+        target_peer.read_exact(&mut buf).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert!(metrics.export_prometheus().contains("socks5_connection_lifetime_warnings_total 1"));
+
+    assert!(relay.await.unwrap().is_err());
+    assert!(started.elapsed() >= Duration::from_millis(400));
+    assert_eq!(client_peer.read(&mut buf).await.unwrap(), 0);
+    assert_eq!(target_peer.read(&mut buf).await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn test_active_connections_show_live_byte_counts() {
+    use rustproxy::metrics::Metrics;
+    use rustproxy::relay::ProgressSettings;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    let client_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mut client_peer = TcpStream::connect(client_listener.local_addr().unwrap()).await.unwrap();
+    let (client, _) = client_listener.accept().await.unwrap();
+    let target_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target = TcpStream::connect(target_listener.local_addr().unwrap()).await.unwrap();
+    let (mut target_peer, _) = target_listener.accept().await.unwrap();
+
+    let metrics = Arc::new(Metrics::new());
+    let relay_engine = RelayEngine::new()
+        .with_observer(metrics.clone())
+        .with_progress_settings(ProgressSettings { interval: Duration::from_millis(20), threshold_bytes: 0 });
+    let relay = tokio::spawn(async move { relay_engine.start_complete_relay_with_user(client, target, None).await });
+
+    client_peer.write_all(b"request").await.unwrap();
+    let mut buf = [0u8; 7];
+    target_peer.read_exact(&mut buf).await.unwrap();
+    target_peer.write_all(b"response").await.unwrap();
+    let mut buf = [0u8; 8];
+    client_peer.read_exact(&mut buf).await.unwrap();
+
+    // The counts reach the registry while the relay is still open
+    let mut live = None;
+    for _ in 0..100 {
+        live = metrics.get_active_connection_info().into_iter().next().filter(|conn| conn.bytes_down == 8);
+        if live.is_some() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    let live = live.expect("active connection should show the bytes relayed so far");
+    assert_eq!(live.bytes_up, 7);
+    assert!(!relay.is_finished());
+
+    drop(client_peer);
+    drop(target_peer);
+    relay.await.unwrap().unwrap();
+    assert!(metrics.get_active_connection_info().is_empty());
+}
+
+#[tokio::test]
+async fn test_active_connections_show_live_throughput() {
+    use rustproxy::metrics::Metrics;
+    use rustproxy::relay::RelayHandle;
+    use std::sync::Arc;
+
+    let client_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mut client_peer = TcpStream::connect(client_listener.local_addr().unwrap()).await.unwrap();
+    let (client, _) = client_listener.accept().await.unwrap();
+    let target_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target = TcpStream::connect(target_listener.local_addr().unwrap()).await.unwrap();
+    let (mut target_peer, _) = target_listener.accept().await.unwrap();
+
+    let handle = RelayHandle::new();
+    let metrics = Arc::new(Metrics::new());
+    let relay_engine = RelayEngine::new().with_observer(metrics.clone()).with_handle(handle.clone());
+    let relay = tokio::spawn(async move { relay_engine.start_complete_relay_with_user(client, target, None).await });
+
+    // A download of 1 MB with a small request shows up as mostly downstream
+    client_peer.write_all(b"GET").await.unwrap();
+    let mut request = [0u8; 3];
+    target_peer.read_exact(&mut request).await.unwrap();
+    let download = tokio::spawn(async move {
+        target_peer.write_all(&vec![0u8; 1_000_000]).await.unwrap();
+        target_peer
+    });
+    let mut received = vec![0u8; 1_000_000];
+    client_peer.read_exact(&mut received).await.unwrap();
+    let target_peer = download.await.unwrap();
+
+    let rates = handle.throughput().rates();
+    assert!(rates.down_1s > rates.up_1s, "{:?}", rates);
+    assert!(rates.down_10s > 0, "{:?}", rates);
+    let live = metrics.get_active_connection_info().into_iter().next().unwrap();
+    assert!(live.throughput.down_1s > 0, "{:?}", live.throughput);
+
+    drop(client_peer);
+    drop(target_peer);
+    relay.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn test_refuses_targets_that_loop_back_into_the_proxy() {
+    use rustproxy::connection::{LoopGuard, ProxyLoop};
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let proxy_addr = listener.local_addr().unwrap();
+    let relay_engine = RelayEngine::new().with_loop_guard(LoopGuard::new(vec![proxy_addr]));
+
+    // "localhost" resolves to the proxy's own listener too
+    for target in [TargetAddr::Ipv4(Ipv4Addr::LOCALHOST), TargetAddr::Domain("localhost".to_string())] {
+        let err = relay_engine.connect_to_target(&target, proxy_addr.port()).await.unwrap_err();
+        assert!(err.downcast_ref::<ProxyLoop>().is_some(), "{:#}", err);
+        assert_eq!(relay_engine.connection_error_to_socks5_code(&err), 0x06);
+    }
+
+    // A target reached through an upstream must not be one of its hops
+    let upstream = "192.0.2.1:1080".parse().unwrap();
+    let relay_engine = relay_engine.with_upstreams(vec![upstream]);
+    assert!(relay_engine.check_loop(upstream).is_err());
+    assert!(relay_engine.check_chain_loop().is_ok());
+    assert!(relay_engine.with_upstreams(vec![upstream, proxy_addr]).check_chain_loop().is_err());
+}
+
+#[tokio::test]
+async fn test_refuses_private_addresses_unless_allowed() {
+    use rustproxy::security::PrivateAddress;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    // Protection is on by default, and checks what names resolve to
+    let relay_engine = RelayEngine::from_config(&rustproxy::Config::default());
+    for target in [TargetAddr::Ipv4(Ipv4Addr::LOCALHOST), TargetAddr::Domain("localhost".to_string())] {
+        let err = relay_engine.connect_to_target(&target, port).await.unwrap_err();
+        let private = err.downcast_ref::<PrivateAddress>().expect("private address refused");
+        assert_eq!(private.range, "loopback");
+        assert_eq!(relay_engine.connection_error_to_socks5_code(&err), 0x02);
+    }
+
+    // A rule with `allow_private` turns it off for its connections
+    let relay_engine = relay_engine.with_private_range_protection(false);
+    assert!(relay_engine.connect_to_target(&TargetAddr::Ipv4(Ipv4Addr::LOCALHOST), port).await.is_ok());
+}
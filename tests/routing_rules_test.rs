@@ -3,6 +3,7 @@
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use rustproxy::routing::{RoutingRulesEngine, RoutingRule, RoutingAction, RouteDecision};
 use rustproxy::protocol::TargetAddr;
+use rustproxy::relay::BandwidthLimit;
 
 #[tokio::test]
 async fn test_routing_rules_priority_order() {
@@ -17,8 +18,18 @@ async fn test_routing_rules_priority_order() {
         ports: None,
         source_ips: None,
         users: None,
+        groups: None,
+        countries: None,
+        dns_resolution: None,
+        allow_private: false,
+        traffic_class: None,
+        egress_bind_addr: None,
+        fwmark: None,
         time_restrictions: None,
         enabled: true,
+        dscp: None,
+        bandwidth: BandwidthLimit::default(),
+        transformers: Vec::new(),
     };
     
     // Add a high priority rule that blocks specific domain
@@ -32,8 +43,18 @@ async fn test_routing_rules_priority_order() {
         ports: None,
         source_ips: None,
         users: None,
+        groups: None,
+        countries: None,
+        dns_resolution: None,
+        allow_private: false,
+        traffic_class: None,
+        egress_bind_addr: None,
+        fwmark: None,
         time_restrictions: None,
         enabled: true,
+        dscp: None,
+        bandwidth: BandwidthLimit::default(),
+        transformers: Vec::new(),
     };
     
     engine.add_rule(allow_all_rule).unwrap();
@@ -49,7 +70,7 @@ async fn test_routing_rules_priority_order() {
     );
     
     match decision {
-        RouteDecision::Block { reason } => {
+        RouteDecision::Block { reason, .. } => {
             assert_eq!(reason, "Malware domain blocked");
         },
         _ => panic!("Expected block decision for malware domain"),
@@ -84,8 +105,18 @@ async fn test_wildcard_domain_matching() {
         ports: None,
         source_ips: None,
         users: None,
+        groups: None,
+        countries: None,
+        dns_resolution: None,
+        allow_private: false,
+        traffic_class: None,
+        egress_bind_addr: None,
+        fwmark: None,
         time_restrictions: None,
         enabled: true,
+        dscp: None,
+        bandwidth: BandwidthLimit::default(),
+        transformers: Vec::new(),
     };
     
     engine.add_rule(wildcard_rule).unwrap();
@@ -100,7 +131,7 @@ async fn test_wildcard_domain_matching() {
     );
     
     match decision {
-        RouteDecision::Block { reason } => {
+        RouteDecision::Block { reason, .. } => {
             assert_eq!(reason, "Advertisement blocked");
         },
         _ => panic!("Expected block decision for ad subdomain"),
@@ -132,11 +163,21 @@ async fn test_port_restrictions() {
         action: RoutingAction::Block { 
             reason: Some("SSH blocked".to_string()) 
         },
-        ports: Some(vec![22]),
+        ports: Some(vec![22.into()]),
         source_ips: None,
         users: None,
+        groups: None,
+        countries: None,
+        dns_resolution: None,
+        allow_private: false,
+        traffic_class: None,
+        egress_bind_addr: None,
+        fwmark: None,
         time_restrictions: None,
         enabled: true,
+        dscp: None,
+        bandwidth: BandwidthLimit::default(),
+        transformers: Vec::new(),
     };
     
     engine.add_rule(port_restricted_rule).unwrap();
@@ -151,7 +192,7 @@ async fn test_port_restrictions() {
     );
     
     match ssh_decision {
-        RouteDecision::Block { reason } => {
+        RouteDecision::Block { reason, .. } => {
             assert_eq!(reason, "SSH blocked");
         },
         _ => panic!("Expected block decision for SSH port"),
@@ -183,8 +224,18 @@ async fn test_source_ip_restrictions() {
         ports: None,
         source_ips: Some(vec!["192.168.1.0/24".to_string()]),
         users: None,
+        groups: None,
+        countries: None,
+        dns_resolution: None,
+        allow_private: false,
+        traffic_class: None,
+        egress_bind_addr: None,
+        fwmark: None,
         time_restrictions: None,
         enabled: true,
+        dscp: None,
+        bandwidth: BandwidthLimit::default(),
+        transformers: Vec::new(),
     };
     
     engine.add_rule(ip_restricted_rule).unwrap();
@@ -231,8 +282,18 @@ async fn test_redirect_action() {
         ports: None,
         source_ips: None,
         users: None,
+        groups: None,
+        countries: None,
+        dns_resolution: None,
+        allow_private: false,
+        traffic_class: None,
+        egress_bind_addr: None,
+        fwmark: None,
         time_restrictions: None,
         enabled: true,
+        dscp: None,
+        bandwidth: BandwidthLimit::default(),
+        transformers: Vec::new(),
     };
     
     engine.add_rule(redirect_rule).unwrap();
@@ -246,8 +307,9 @@ async fn test_redirect_action() {
     );
     
     match decision {
-        RouteDecision::Redirect { target } => {
+        RouteDecision::Redirect { target, rule } => {
             assert_eq!(target, "8.8.8.8:53".parse::<SocketAddr>().unwrap());
+            assert_eq!(rule.as_deref(), Some("redirect_dns"));
         },
         _ => panic!("Expected redirect decision"),
     }
@@ -267,8 +329,18 @@ async fn test_disabled_rules() {
         ports: None,
         source_ips: None,
         users: None,
+        groups: None,
+        countries: None,
+        dns_resolution: None,
+        allow_private: false,
+        traffic_class: None,
+        egress_bind_addr: None,
+        fwmark: None,
         time_restrictions: None,
         enabled: false, // Rule is disabled
+        dscp: None,
+        bandwidth: BandwidthLimit::default(),
+        transformers: Vec::new(),
     };
     
     engine.add_rule(disabled_rule).unwrap();
@@ -286,4 +358,462 @@ async fn test_disabled_rules() {
         RouteDecision::Allow { .. } => {},
         _ => panic!("Expected allow decision since rule is disabled"),
     }
-}
\ No newline at end of file
+}
+#[tokio::test]
+async fn test_router_applies_group_policy() {
+    use rustproxy::config::{AccessRule, Config, GroupConfig, RoutingActionConfig, RoutingRuleConfig, UserConfig};
+    use rustproxy::routing::Router;
+    use std::sync::Arc;
+
+    let mut config = Config::default();
+    config.auth.users.push(UserConfig {
+        groups: vec!["contractors".to_string()],
+        ..UserConfig::new("carol", "secret")
+    });
+    config.auth.users.push(UserConfig {
+        groups: vec!["staff".to_string()],
+        ..UserConfig::new("alice", "secret")
+    });
+    config.auth.groups.insert("contractors".to_string(), GroupConfig {
+        bandwidth: BandwidthLimit { upload_bytes_per_second: Some(4096), download_bytes_per_second: None },
+        ..Default::default()
+    });
+    config.access_control.enabled = true;
+    config.access_control.rules.push(AccessRule {
+        pattern: "10.0.0.0/8".to_string(),
+        action: "block".to_string(),
+        ports: None,
+        countries: None,
+        groups: Some(vec!["contractors".to_string()]),
+    });
+    config.routing.enabled = true;
+    config.routing.rules.push(RoutingRuleConfig {
+        id: "staff-only".to_string(),
+        priority: 100,
+        pattern: "*.corp.example.com".to_string(),
+        action: RoutingActionConfig::Block { reason: Some("Staff only".to_string()) },
+        ports: None,
+        source_ips: None,
+        users: None,
+        groups: Some(vec!["contractors".to_string()]),
+        countries: None,
+        dns_resolution: None,
+        allow_private: false,
+        traffic_class: None,
+        egress_bind_addr: None,
+        fwmark: None,
+        time_restrictions: None,
+        enabled: true,
+        dscp: None,
+        dscp_class: None,
+        bandwidth: BandwidthLimit::default(),
+        transformers: Vec::new(),
+    });
+
+    let carol_groups = config.auth.resolve_groups(Some("carol"), &[]);
+    let alice_groups = config.auth.resolve_groups(Some("alice"), &["admin".to_string()]);
+    assert_eq!(alice_groups, vec!["staff".to_string(), "admin".to_string()]);
+    assert_eq!(config.auth.bandwidth_for(Some("carol"), &carol_groups).upload_bytes_per_second, Some(4096));
+    assert!(config.auth.bandwidth_for(Some("alice"), &alice_groups).is_unlimited());
+
+    let router = Router::new(Arc::new(config));
+    let source = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 100));
+    let internal = TargetAddr::Ipv4(Ipv4Addr::new(10, 1, 2, 3));
+    let intranet = TargetAddr::Domain("wiki.corp.example.com".to_string());
+
+    assert!(matches!(router.route_request(&internal, 22, source, Some("carol"), &carol_groups).await, RouteDecision::Block { .. }));
+    assert!(matches!(router.route_request(&intranet, 443, source, Some("carol"), &carol_groups).await, RouteDecision::Block { .. }));
+    assert!(matches!(router.route_request(&internal, 22, source, Some("alice"), &alice_groups).await, RouteDecision::Allow { .. }));
+    assert!(matches!(router.route_request(&intranet, 443, source, Some("alice"), &alice_groups).await, RouteDecision::Allow { .. }));
+}
+
+#[tokio::test]
+async fn test_dns_resolution_follows_rule_then_global_setting() {
+    use rustproxy::config::{Config, DnsResolution, RoutingRuleConfig};
+    use rustproxy::routing::Router;
+    use std::sync::Arc;
+
+    let mut config = Config::default();
+    config.routing.enabled = true;
+    config.routing.dns_resolution = DnsResolution::Local;
+    let rule: RoutingRuleConfig = toml::from_str(r#"
+        id = "private"
+        priority = 100
+        pattern = "*.onion.example"
+        action = { type = "Allow" }
+        dns_resolution = "remote"
+        enabled = true
+    "#).unwrap();
+    assert_eq!(rule.dns_resolution, Some(DnsResolution::Remote));
+    config.routing.rules.push(rule);
+
+    let router = Router::new(Arc::new(config));
+    let source = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 100));
+    let dns_override = |decision: RouteDecision| match decision {
+        RouteDecision::Allow { dns_resolution, .. } => dns_resolution,
+        other => panic!("Expected an allow decision, got {:?}", other),
+    };
+
+    let private = TargetAddr::Domain("hidden.onion.example".to_string());
+    assert_eq!(dns_override(router.route_request(&private, 443, source, None, &[]).await), Some(DnsResolution::Remote));
+    // Without a rule override the connection manager applies routing.dns_resolution
+    let other = TargetAddr::Domain("example.com".to_string());
+    assert_eq!(dns_override(router.route_request(&other, 443, source, None, &[]).await), None);
+}
+
+#[tokio::test]
+async fn test_rules_opt_in_to_private_addresses() {
+    use rustproxy::config::{Config, RoutingRuleConfig};
+    use rustproxy::routing::Router;
+    use std::sync::Arc;
+
+    let mut config = Config::default();
+    assert!(config.security.private_ranges.enabled);
+    config.routing.enabled = true;
+    for rule in [
+        r#"
+            id = "intranet"
+            priority = 100
+            pattern = "*.corp.example"
+            action = { type = "Allow" }
+            allow_private = true
+            enabled = true
+        "#,
+        r#"
+            id = "staging"
+            priority = 100
+            pattern = '^(\w+)\.staging\.example$'
+            action = { type = "Rewrite", config = { host = "$1.internal.example" } }
+            allow_private = true
+            enabled = true
+        "#,
+    ] {
+        config.routing.rules.push(toml::from_str::<RoutingRuleConfig>(rule).unwrap());
+    }
+
+    let router = Router::new(Arc::new(config));
+    let source = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 100));
+    let allows_private = |decision: RouteDecision| match decision {
+        RouteDecision::Allow { allow_private, .. } | RouteDecision::Rewrite { allow_private, .. } => allow_private,
+        other => panic!("Expected an allow or rewrite decision, got {:?}", other),
+    };
+
+    let intranet = TargetAddr::Domain("wiki.corp.example".to_string());
+    assert!(allows_private(router.route_request(&intranet, 443, source, None, &[]).await));
+    let staging = TargetAddr::Domain("api.staging.example".to_string());
+    assert!(allows_private(router.route_request(&staging, 443, source, None, &[]).await));
+    let other = TargetAddr::Domain("example.com".to_string());
+    assert!(!allows_private(router.route_request(&other, 443, source, None, &[]).await));
+}
+
+#[tokio::test]
+async fn test_router_skips_upstreams_over_their_cap() {
+    use rustproxy::config::{Config, UpstreamProxyConfig};
+    use rustproxy::routing::{Router, UpstreamUsageTracker};
+    use std::sync::Arc;
+
+    let mut config = Config::default();
+    config.routing.enabled = true;
+    for (name, port) in [("primary", 1080), ("backup", 1081)] {
+        config.routing.upstream_proxies.push(UpstreamProxyConfig {
+            name: name.to_string(),
+            addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 10)), port),
+            protocol: "socks5".to_string(),
+            auth: None,
+            monthly_cap_bytes: Some(1000),
+            cost_per_gb: None,
+            monthly_budget: None,
+            weight: 1,
+            tunnel: None,
+        });
+    }
+    let config = Arc::new(config);
+    let usage = Arc::new(UpstreamUsageTracker::new(&config.routing));
+    let router = Router::new(config.clone()).with_upstream_usage(usage.clone());
+    let source = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 100));
+    let target = TargetAddr::Domain("example.com".to_string());
+    let upstream_port = |decision: RouteDecision| match decision {
+        RouteDecision::Allow { upstream: Some(proxy), .. } => proxy.addr.port(),
+        other => panic!("Expected an upstream, got {:?}", other),
+    };
+
+    assert_eq!(upstream_port(router.route_request(&target, 443, source, None, &[]).await), 1080);
+
+    // Near its cap, the primary is passed over while the backup has room
+    usage.record("primary", 900);
+    assert_eq!(upstream_port(router.route_request(&target, 443, source, None, &[]).await), 1081);
+    usage.record("backup", 950);
+    assert_eq!(upstream_port(router.route_request(&target, 443, source, None, &[]).await), 1080);
+
+    usage.record("primary", 100);
+    usage.record("backup", 50);
+    assert!(matches!(router.route_request(&target, 443, source, None, &[]).await, RouteDecision::Block { .. }));
+}
+
+#[tokio::test]
+async fn test_router_round_robins_upstreams() {
+    use rustproxy::config::{Config, LoadBalancingStrategy, UpstreamProxyConfig};
+    use rustproxy::routing::{Router, UpstreamBalancer};
+    use std::sync::Arc;
+
+    let mut config = Config::default();
+    config.routing.enabled = true;
+    config.routing.load_balancing = LoadBalancingStrategy::RoundRobin;
+    for (name, port) in [("first", 1080), ("second", 1081), ("third", 1082)] {
+        config.routing.upstream_proxies.push(UpstreamProxyConfig {
+            name: name.to_string(),
+            addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 10)), port),
+            protocol: "socks5".to_string(),
+            auth: None,
+            monthly_cap_bytes: None,
+            cost_per_gb: None,
+            monthly_budget: None,
+            weight: 1,
+            tunnel: None,
+        });
+    }
+    let config = Arc::new(config);
+    let balancer = Arc::new(UpstreamBalancer::new(&config.routing));
+    let source = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 100));
+    let target = TargetAddr::Domain("example.com".to_string());
+
+    // The rotation lives in the balancer, so it carries over between routers
+    let mut ports = Vec::new();
+    for _ in 0..4 {
+        let router = Router::new(config.clone()).with_load_balancer(balancer.clone());
+        match router.route_request(&target, 443, source, None, &[]).await {
+            RouteDecision::Allow { upstream: Some(proxy), .. } => ports.push(proxy.addr.port()),
+            other => panic!("Expected an upstream, got {:?}", other),
+        }
+    }
+    assert_eq!(ports, [1080, 1081, 1082, 1080]);
+}
+
+#[tokio::test]
+async fn test_router_reloads_rules_from_config_changes() {
+    use rustproxy::config::{Config, ConfigChangeEvent, RoutingRuleConfig};
+    use rustproxy::routing::Router;
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime};
+    use tokio_stream::wrappers::BroadcastStream;
+
+    let mut config = Config::default();
+    config.routing.enabled = true;
+    let router = Arc::new(Router::new(Arc::new(config.clone())));
+    let source = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 100));
+    let target = TargetAddr::Domain("ads.example.com".to_string());
+    assert!(matches!(router.route_request(&target, 443, source, None, &[]).await, RouteDecision::Allow { .. }));
+
+    let (changes, _) = tokio::sync::broadcast::channel(4);
+    let watch = router.watch(BroadcastStream::new(changes.subscribe()));
+    config.routing.rules.push(toml::from_str::<RoutingRuleConfig>(r#"
+        id = "no-ads"
+        priority = 100
+        pattern = "ads.*"
+        action = { type = "Block", config = { reason = "Ads" } }
+        enabled = true
+    "#).unwrap());
+    changes.send(ConfigChangeEvent {
+        config: Arc::new(config),
+        timestamp: SystemTime::now(),
+        file_path: "config.toml".into(),
+    }).unwrap();
+
+    for _ in 0..50 {
+        if !router.get_routing_rules().is_empty() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(router.get_routing_rules()[0].id, "no-ads");
+    assert!(matches!(router.route_request(&target, 443, source, None, &[]).await, RouteDecision::Block { .. }));
+
+    // The watch ends with the router
+    drop(router);
+    drop(changes);
+    tokio::time::timeout(Duration::from_secs(1), watch).await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn test_router_blocks_domains_on_blocklists() {
+    use rustproxy::config::{BlocklistConfig, BlocklistFormat, Config, RoutingRuleConfig};
+    use rustproxy::routing::Router;
+    use std::sync::Arc;
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("ads.txt");
+    std::fs::write(&path, "||ads.example^\n||tracker.example^\n").unwrap();
+
+    let mut config = Config::default();
+    config.routing.enabled = true;
+    config.routing.blocklists.push(BlocklistConfig { path, format: BlocklistFormat::Adblock });
+    // Blocklists are checked before rules, even rules allowing the domain
+    config.routing.rules.push(toml::from_str::<RoutingRuleConfig>(r#"
+        id = "allow-all"
+        priority = 100
+        pattern = "*"
+        action = { type = "Allow" }
+        enabled = true
+    "#).unwrap());
+    config.validate().unwrap();
+
+    let router = Router::new(Arc::new(config));
+    let source = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 100));
+    let blocked = TargetAddr::Domain("cdn.ads.example".to_string());
+    assert!(matches!(router.route_request(&blocked, 443, source, None, &[]).await, RouteDecision::Block { .. }));
+    let allowed = TargetAddr::Domain("example.com".to_string());
+    assert!(matches!(router.route_request(&allowed, 443, source, None, &[]).await, RouteDecision::Allow { .. }));
+
+    let explanation = router.explain_request(&blocked, 443, source, None, &[]).await;
+    assert!(explanation.blocklist.unwrap().ends_with("ads.txt"));
+    assert_eq!(explanation.matched_rule, None);
+}
+
+#[tokio::test]
+async fn test_router_throttles_into_bandwidth_classes() {
+    use rustproxy::config::{Config, RoutingRuleConfig};
+    use rustproxy::relay::BandwidthLimit;
+    use rustproxy::routing::Router;
+    use std::sync::Arc;
+
+    let mut config = Config::default();
+    config.routing.enabled = true;
+    config.routing.bandwidth_classes.insert("bulk".to_string(), BandwidthLimit {
+        upload_bytes_per_second: None,
+        download_bytes_per_second: Some(625_000),
+    });
+    config.routing.rules.push(toml::from_str::<RoutingRuleConfig>(r#"
+        id = "video"
+        priority = 100
+        pattern = "*.video.example"
+        action = { type = "Throttle", config = { class = "bulk" } }
+        enabled = true
+    "#).unwrap());
+    config.validate().unwrap();
+
+    let router = Router::new(Arc::new(config.clone()));
+    let source = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 100));
+    let class_of = |decision: RouteDecision| match decision {
+        RouteDecision::Allow { bandwidth_class, .. } => bandwidth_class,
+        decision => panic!("Expected allow, got {:?}", decision),
+    };
+    let video = TargetAddr::Domain("cdn.video.example".to_string());
+    let bulk = class_of(router.route_request(&video, 443, source, None, &[]).await).unwrap();
+    assert_eq!(bulk.name(), "bulk");
+    assert_eq!(bulk.limit().download_bytes_per_second, Some(625_000));
+    let other = TargetAddr::Domain("example.com".to_string());
+    assert!(class_of(router.route_request(&other, 443, source, None, &[]).await).is_none());
+
+    // Connections before and after a reload share the same class
+    router.reload(Arc::new(config.clone()));
+    let reloaded = class_of(router.route_request(&video, 443, source, None, &[]).await).unwrap();
+    assert!(Arc::ptr_eq(&bulk, &reloaded));
+
+    config.routing.bandwidth_classes.clear();
+    assert!(format!("{:#}", config.validate().unwrap_err()).contains("unknown bandwidth class"));
+}
+
+#[tokio::test]
+async fn test_rules_and_groups_assign_traffic_classes() {
+    use rustproxy::config::{Config, GroupConfig, RoutingRuleConfig};
+    use rustproxy::relay::TrafficClass;
+    use rustproxy::routing::Router;
+    use std::sync::Arc;
+
+    let mut config = Config::default();
+    config.routing.enabled = true;
+    config.routing.rules.push(toml::from_str::<RoutingRuleConfig>(r#"
+        id = "ssh"
+        priority = 100
+        pattern = "*"
+        ports = ["ssh"]
+        action = { type = "Allow" }
+        traffic_class = "interactive"
+        enabled = true
+    "#).unwrap());
+    config.auth.groups.insert("backup".to_string(), GroupConfig { traffic_class: Some(TrafficClass::Bulk), ..Default::default() });
+    config.auth.groups.insert("ops".to_string(), GroupConfig { traffic_class: Some(TrafficClass::Default), ..Default::default() });
+
+    let router = Router::new(Arc::new(config.clone()));
+    let source = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 100));
+    let target = TargetAddr::Domain("host.example".to_string());
+    let class = |decision: RouteDecision| match decision {
+        RouteDecision::Allow { traffic_class, .. } => traffic_class,
+        other => panic!("Expected an allow decision, got {:?}", other),
+    };
+    assert_eq!(class(router.route_request(&target, 22, source, None, &[]).await), Some(TrafficClass::Interactive));
+    assert_eq!(class(router.route_request(&target, 443, source, None, &[]).await), None);
+
+    // Without a rule's class, the highest class among the user's groups applies
+    assert_eq!(config.auth.traffic_class_for(&["backup".to_string()]), Some(TrafficClass::Bulk));
+    assert_eq!(config.auth.traffic_class_for(&["backup".to_string(), "ops".to_string()]), Some(TrafficClass::Default));
+    assert_eq!(config.auth.traffic_class_for(&["staff".to_string()]), None);
+}
+
+#[tokio::test]
+async fn test_rules_and_users_pick_egress_addresses() {
+    use rustproxy::config::{Config, RoutingRuleConfig, UserConfig};
+    use rustproxy::connection::EgressBind;
+    use rustproxy::routing::Router;
+    use std::sync::Arc;
+
+    let mut config = Config::default();
+    config.routing.enabled = true;
+    config.routing.egress_bind_addr = Some(EgressBind::Address("203.0.113.1".parse().unwrap()));
+    config.routing.rules.push(toml::from_str::<RoutingRuleConfig>(r#"
+        id = "mail"
+        priority = 100
+        pattern = "*"
+        ports = [25]
+        action = { type = "Allow" }
+        egress_bind_addr = "eth1"
+        enabled = true
+    "#).unwrap());
+    config.auth.users.push(UserConfig {
+        egress_bind_addr: Some(EgressBind::Address("203.0.113.7".parse().unwrap())),
+        ..UserConfig::new("alice", "secret")
+    });
+
+    let router = Router::new(Arc::new(config.clone()));
+    let source = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 100));
+    let target = TargetAddr::Domain("host.example".to_string());
+    let egress = |decision: RouteDecision| match decision {
+        RouteDecision::Allow { egress_bind_addr, .. } => egress_bind_addr,
+        other => panic!("Expected an allow decision, got {:?}", other),
+    };
+    assert_eq!(egress(router.route_request(&target, 25, source, None, &[]).await), Some(EgressBind::Interface("eth1".to_string())));
+    assert_eq!(egress(router.route_request(&target, 443, source, None, &[]).await), None);
+
+    // Without a rule's choice, the user's address applies before the global one
+    assert_eq!(config.auth.egress_bind_addr_for(Some("alice")), Some(&EgressBind::Address("203.0.113.7".parse().unwrap())));
+    assert_eq!(config.auth.egress_bind_addr_for(Some("bob")), None);
+}
+
+#[tokio::test]
+async fn test_rules_carry_their_fwmark() {
+    use rustproxy::config::{Config, RoutingRuleConfig};
+    use rustproxy::routing::Router;
+    use std::sync::Arc;
+
+    let mut config = Config::default();
+    config.routing.enabled = true;
+    config.routing.rules.push(toml::from_str::<RoutingRuleConfig>(r#"
+        id = "via-vpn"
+        priority = 100
+        pattern = "*.example.org"
+        action = { type = "Allow" }
+        fwmark = 51820
+        enabled = true
+    "#).unwrap());
+
+    let router = Router::new(Arc::new(config));
+    let source = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 100));
+    let fwmark = |decision: RouteDecision| match decision {
+        RouteDecision::Allow { fwmark, .. } => fwmark,
+        other => panic!("Expected an allow decision, got {:?}", other),
+    };
+    let marked = TargetAddr::Domain("www.example.org".to_string());
+    let unmarked = TargetAddr::Domain("host.example".to_string());
+    assert_eq!(fwmark(router.route_request(&marked, 443, source, None, &[]).await), Some(51820));
+    assert_eq!(fwmark(router.route_request(&unmarked, 443, source, None, &[]).await), None);
+}
@@ -0,0 +1,202 @@
+//! Tests for tunnels between RustProxy nodes
+
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use rustproxy::protocol::TargetAddr;
+use rustproxy::routing::UpstreamError;
+use rustproxy::tls::SniCertConfig;
+use rustproxy::tunnel::{TunnelClientConfig, TunnelConnector, TunnelServer, TunnelServerConfig, TunnelStream};
+use rustproxy::Config;
+
+/// Start a far node whose tunnel listener expects `token`
+async fn far_node(token: &str) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mut config = Config::default();
+    config.security.private_ranges.enabled = false;
+    config.server.tunnel = Some(TunnelServerConfig {
+        bind_addr: addr,
+        token: token.to_string(),
+        compression: true,
+        tls_cert: None,
+        tls_key: None,
+        tls_sni_certs: Vec::new(),
+    });
+    let server = TunnelServer::new(&config).unwrap();
+    tokio::spawn(server.serve(listener));
+    addr
+}
+
+/// Start a target that echoes whatever each connection sends
+async fn echo_target() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let (mut reader, mut writer) = stream.split();
+                let _ = tokio::io::copy(&mut reader, &mut writer).await;
+            });
+        }
+    });
+    addr
+}
+
+fn settings(token: &str) -> TunnelClientConfig {
+    TunnelClientConfig {
+        token: token.to_string(),
+        compression: true,
+        tls: false,
+        server_name: None,
+        ca_cert: None,
+    }
+}
+
+#[tokio::test]
+async fn test_streams_share_one_tunnel_to_the_far_node() {
+    let node = far_node("secret").await;
+    let target = echo_target().await;
+    let connector = TunnelConnector::default();
+    let target_addr = TargetAddr::from_socket_addr(&target);
+
+    let mut streams = Vec::new();
+    for _ in 0..3 {
+        streams.push(connector.open(node, &settings("secret"), &target_addr, target.port(), Duration::from_secs(5)).await.unwrap());
+    }
+    assert_eq!(connector.tunnels(), vec![(node, 3)]);
+
+    // Compressible and incompressible payloads both come back intact
+    let text = "GET / HTTP/1.1\r\nHost: example.com\r\n\r\n".repeat(4096).into_bytes();
+    let noise: Vec<u8> = (0..200_000u32).map(|i| (i.wrapping_mul(2654435761) >> 13) as u8).collect();
+    for (stream, payload) in streams.iter_mut().zip([&text, &noise, &text]) {
+        let (mut reader, mut writer) = tokio::io::split(stream);
+        let sent = payload.clone();
+        let (_, echoed) = tokio::join!(
+            async move { writer.write_all(&sent).await.unwrap() },
+            async move {
+                let mut echoed = vec![0u8; payload.len()];
+                reader.read_exact(&mut echoed).await.unwrap();
+                echoed
+            },
+        );
+        assert_eq!(&echoed, payload);
+    }
+}
+
+#[tokio::test]
+async fn test_wrong_token_is_an_authentication_failure() {
+    let node = far_node("secret").await;
+    let connector = TunnelConnector::default();
+    let error = connector
+        .open(node, &settings("guess"), &TargetAddr::Domain("example.com".to_string()), 443, Duration::from_secs(5))
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(error.downcast_ref::<UpstreamError>(), Some(&UpstreamError::AuthFailed { proxy: node }));
+}
+
+#[tokio::test]
+async fn test_unreachable_target_is_refused_by_the_far_node() {
+    let node = far_node("secret").await;
+    let closed = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+    let connector = TunnelConnector::default();
+    let error = connector
+        .open(node, &settings("secret"), &TargetAddr::from_socket_addr(&closed), closed.port(), Duration::from_secs(5))
+        .await
+        .err()
+        .unwrap();
+    assert!(matches!(error.downcast_ref::<UpstreamError>(), Some(UpstreamError::Refused { .. })));
+
+    // The tunnel itself survives a refused stream
+    let target = echo_target().await;
+    let mut stream = connector
+        .open(node, &settings("secret"), &TargetAddr::from_socket_addr(&target), target.port(), Duration::from_secs(5))
+        .await
+        .unwrap();
+    stream.write_all(b"still up").await.unwrap();
+    let mut echoed = [0u8; 8];
+    stream.read_exact(&mut echoed).await.unwrap();
+    assert_eq!(&echoed, b"still up");
+}
+
+/// Start a far node serving TLS with `cert`/`key`, plus `sni_certs`
+async fn tls_far_node(token: &str, cert: PathBuf, key: PathBuf, sni_certs: Vec<SniCertConfig>) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mut config = Config::default();
+    config.security.private_ranges.enabled = false;
+    config.server.tunnel = Some(TunnelServerConfig {
+        bind_addr: addr,
+        token: token.to_string(),
+        compression: true,
+        tls_cert: Some(cert),
+        tls_key: Some(key),
+        tls_sni_certs: sni_certs,
+    });
+    let server = TunnelServer::new(&config).unwrap();
+    tokio::spawn(server.serve(listener));
+    addr
+}
+
+/// Open a stream over a TLS tunnel that trusts only `ca_cert` and asks for `server_name`
+async fn open_tls(
+    connector: &TunnelConnector,
+    node: SocketAddr,
+    server_name: &str,
+    ca_cert: &Path,
+    target: SocketAddr,
+) -> rustproxy::Result<TunnelStream> {
+    let settings = TunnelClientConfig {
+        tls: true,
+        server_name: Some(server_name.to_string()),
+        ca_cert: Some(ca_cert.to_path_buf()),
+        ..settings("secret")
+    };
+    connector
+        .open(node, &settings, &TargetAddr::from_socket_addr(&target), target.port(), Duration::from_secs(5))
+        .await
+}
+
+#[tokio::test]
+async fn test_tls_certificate_is_picked_by_sni_and_reloaded() {
+    let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data/tls");
+    let (ca_a, ca_b) = (fixtures.join("a.example.com.crt"), fixtures.join("b.example.com.crt"));
+    let dir = tempfile::tempdir().unwrap();
+    let (cert, key) = (dir.path().join("tunnel.crt"), dir.path().join("tunnel.key"));
+    std::fs::copy(&ca_a, &cert).unwrap();
+    std::fs::copy(fixtures.join("a.example.com.key"), &key).unwrap();
+    let sni = SniCertConfig {
+        server_names: vec!["b.example.com".to_string()],
+        cert: ca_b.clone(),
+        key: fixtures.join("b.example.com.key"),
+    };
+    let node = tls_far_node("secret", cert.clone(), key.clone(), vec![sni]).await;
+    let target = echo_target().await;
+
+    let connector = TunnelConnector::default();
+    let mut open_stream = open_tls(&connector, node, "a.example.com", &ca_a, target).await.unwrap();
+    assert!(open_tls(&TunnelConnector::default(), node, "b.example.com", &ca_b, target).await.is_ok());
+    assert!(open_tls(&TunnelConnector::default(), node, "x.b.example.com", &ca_b, target).await.is_err());
+
+    // Replace the default certificate; new handshakes pick it up
+    std::fs::copy(&ca_b, &cert).unwrap();
+    std::fs::copy(fixtures.join("b.example.com.key"), &key).unwrap();
+    let mut reloaded = false;
+    for _ in 0..50 {
+        if open_tls(&TunnelConnector::default(), node, "x.b.example.com", &ca_b, target).await.is_ok() {
+            reloaded = true;
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert!(reloaded, "tunnel listener did not reload its certificate");
+
+    // A tunnel set up before the reload carries on
+    open_stream.write_all(b"still up").await.unwrap();
+    let mut echoed = [0u8; 8];
+    open_stream.read_exact(&mut echoed).await.unwrap();
+    assert_eq!(&echoed, b"still up");
+}